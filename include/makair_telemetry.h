@@ -0,0 +1,172 @@
+/*
+ * MakAir telemetry and control frame constants
+ *
+ * Generated by the makair-telemetry crate (`makair_telemetry_cli c-header`); do not edit.
+ */
+
+#ifndef MAKAIR_TELEMETRY_H
+#define MAKAIR_TELEMETRY_H
+
+/* Telemetry frames (MCU to UI) */
+#define MAKAIR_TELEMETRY_MAX_PROTOCOL_VERSION 3
+#define MAKAIR_TELEMETRY_FRAME_HEADER_0 0x03
+#define MAKAIR_TELEMETRY_FRAME_HEADER_1 0x0C
+#define MAKAIR_TELEMETRY_FRAME_FOOTER_0 0x30
+#define MAKAIR_TELEMETRY_FRAME_FOOTER_1 0xC0
+
+/* Control frames (UI to MCU) */
+#define MAKAIR_CONTROL_FRAME_HEADER_0 0x05
+#define MAKAIR_CONTROL_FRAME_HEADER_1 0x0A
+#define MAKAIR_PROTECTED_CONTROL_FRAME_HEADER_0 0x05
+#define MAKAIR_PROTECTED_CONTROL_FRAME_HEADER_1 0x0B
+#define MAKAIR_CONTROL_FRAME_FOOTER_0 0x50
+#define MAKAIR_CONTROL_FRAME_FOOTER_1 0xA0
+#define MAKAIR_DISABLE_RPI_WATCHDOG 43690
+
+/* Control settings: ID, bounds and default value */
+#define MAKAIR_SETTING_HEARTBEAT 0
+#define MAKAIR_SETTING_HEARTBEAT_MIN 0
+#define MAKAIR_SETTING_HEARTBEAT_MAX 255
+#define MAKAIR_SETTING_HEARTBEAT_DEFAULT 0
+#define MAKAIR_SETTING_VENTILATION_MODE 1
+#define MAKAIR_SETTING_VENTILATION_MODE_MIN 1
+#define MAKAIR_SETTING_VENTILATION_MODE_MAX 5
+#define MAKAIR_SETTING_VENTILATION_MODE_DEFAULT 1
+#define MAKAIR_SETTING_PLATEAU_PRESSURE 2
+#define MAKAIR_SETTING_PLATEAU_PRESSURE_MIN 50
+#define MAKAIR_SETTING_PLATEAU_PRESSURE_MAX 400
+#define MAKAIR_SETTING_PLATEAU_PRESSURE_DEFAULT 0
+#define MAKAIR_SETTING_PEEP 3
+#define MAKAIR_SETTING_PEEP_MIN 0
+#define MAKAIR_SETTING_PEEP_MAX 300
+#define MAKAIR_SETTING_PEEP_DEFAULT 0
+#define MAKAIR_SETTING_CYCLES_PER_MINUTE 4
+#define MAKAIR_SETTING_CYCLES_PER_MINUTE_MIN 5
+#define MAKAIR_SETTING_CYCLES_PER_MINUTE_MAX 35
+#define MAKAIR_SETTING_CYCLES_PER_MINUTE_DEFAULT 20
+#define MAKAIR_SETTING_EXPIRATORY_TERM 5
+#define MAKAIR_SETTING_EXPIRATORY_TERM_MIN 10
+#define MAKAIR_SETTING_EXPIRATORY_TERM_MAX 60
+#define MAKAIR_SETTING_EXPIRATORY_TERM_DEFAULT 20
+#define MAKAIR_SETTING_TRIGGER_ENABLED 6
+#define MAKAIR_SETTING_TRIGGER_ENABLED_MIN 0
+#define MAKAIR_SETTING_TRIGGER_ENABLED_MAX 1
+#define MAKAIR_SETTING_TRIGGER_ENABLED_DEFAULT 0
+#define MAKAIR_SETTING_TRIGGER_OFFSET 7
+#define MAKAIR_SETTING_TRIGGER_OFFSET_MIN 0
+#define MAKAIR_SETTING_TRIGGER_OFFSET_MAX 100
+#define MAKAIR_SETTING_TRIGGER_OFFSET_DEFAULT 20
+#define MAKAIR_SETTING_RESPIRATION_ENABLED 8
+#define MAKAIR_SETTING_RESPIRATION_ENABLED_MIN 0
+#define MAKAIR_SETTING_RESPIRATION_ENABLED_MAX 1
+#define MAKAIR_SETTING_RESPIRATION_ENABLED_DEFAULT 0
+#define MAKAIR_SETTING_ALARM_SNOOZE 9
+#define MAKAIR_SETTING_ALARM_SNOOZE_MIN 0
+#define MAKAIR_SETTING_ALARM_SNOOZE_MAX 1
+#define MAKAIR_SETTING_ALARM_SNOOZE_DEFAULT 0
+#define MAKAIR_SETTING_INSPIRATORY_TRIGGER_FLOW 10
+#define MAKAIR_SETTING_INSPIRATORY_TRIGGER_FLOW_MIN 0
+#define MAKAIR_SETTING_INSPIRATORY_TRIGGER_FLOW_MAX 100
+#define MAKAIR_SETTING_INSPIRATORY_TRIGGER_FLOW_DEFAULT 10
+#define MAKAIR_SETTING_EXPIRATORY_TRIGGER_FLOW 11
+#define MAKAIR_SETTING_EXPIRATORY_TRIGGER_FLOW_MIN 0
+#define MAKAIR_SETTING_EXPIRATORY_TRIGGER_FLOW_MAX 100
+#define MAKAIR_SETTING_EXPIRATORY_TRIGGER_FLOW_DEFAULT 30
+#define MAKAIR_SETTING_TI_MIN 12
+#define MAKAIR_SETTING_TI_MIN_MIN 100
+#define MAKAIR_SETTING_TI_MIN_MAX 3000
+#define MAKAIR_SETTING_TI_MIN_DEFAULT 200
+#define MAKAIR_SETTING_TI_MAX 13
+#define MAKAIR_SETTING_TI_MAX_MIN 200
+#define MAKAIR_SETTING_TI_MAX_MAX 5000
+#define MAKAIR_SETTING_TI_MAX_DEFAULT 1000
+#define MAKAIR_SETTING_LOW_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD 14
+#define MAKAIR_SETTING_LOW_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MIN 0
+#define MAKAIR_SETTING_LOW_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MAX 20
+#define MAKAIR_SETTING_LOW_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_DEFAULT 3
+#define MAKAIR_SETTING_HIGH_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD 15
+#define MAKAIR_SETTING_HIGH_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MIN 1
+#define MAKAIR_SETTING_HIGH_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MAX 40
+#define MAKAIR_SETTING_HIGH_INSPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_DEFAULT 20
+#define MAKAIR_SETTING_LOW_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD 16
+#define MAKAIR_SETTING_LOW_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MIN 0
+#define MAKAIR_SETTING_LOW_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MAX 20
+#define MAKAIR_SETTING_LOW_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_DEFAULT 3
+#define MAKAIR_SETTING_HIGH_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD 17
+#define MAKAIR_SETTING_HIGH_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MIN 1
+#define MAKAIR_SETTING_HIGH_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_MAX 40
+#define MAKAIR_SETTING_HIGH_EXPIRATORY_MINUTE_VOLUME_ALARM_THRESHOLD_DEFAULT 20
+#define MAKAIR_SETTING_LOW_RESPIRATORY_RATE_ALARM_THRESHOLD 18
+#define MAKAIR_SETTING_LOW_RESPIRATORY_RATE_ALARM_THRESHOLD_MIN 5
+#define MAKAIR_SETTING_LOW_RESPIRATORY_RATE_ALARM_THRESHOLD_MAX 25
+#define MAKAIR_SETTING_LOW_RESPIRATORY_RATE_ALARM_THRESHOLD_DEFAULT 16
+#define MAKAIR_SETTING_HIGH_RESPIRATORY_RATE_ALARM_THRESHOLD 19
+#define MAKAIR_SETTING_HIGH_RESPIRATORY_RATE_ALARM_THRESHOLD_MIN 15
+#define MAKAIR_SETTING_HIGH_RESPIRATORY_RATE_ALARM_THRESHOLD_MAX 35
+#define MAKAIR_SETTING_HIGH_RESPIRATORY_RATE_ALARM_THRESHOLD_DEFAULT 24
+#define MAKAIR_SETTING_TARGET_TIDAL_VOLUME 20
+#define MAKAIR_SETTING_TARGET_TIDAL_VOLUME_MIN 50
+#define MAKAIR_SETTING_TARGET_TIDAL_VOLUME_MAX 2000
+#define MAKAIR_SETTING_TARGET_TIDAL_VOLUME_DEFAULT 400
+#define MAKAIR_SETTING_LOW_TIDAL_VOLUME_ALARM_THRESHOLD 21
+#define MAKAIR_SETTING_LOW_TIDAL_VOLUME_ALARM_THRESHOLD_MIN 0
+#define MAKAIR_SETTING_LOW_TIDAL_VOLUME_ALARM_THRESHOLD_MAX 1000
+#define MAKAIR_SETTING_LOW_TIDAL_VOLUME_ALARM_THRESHOLD_DEFAULT 200
+#define MAKAIR_SETTING_HIGH_TIDAL_VOLUME_ALARM_THRESHOLD 22
+#define MAKAIR_SETTING_HIGH_TIDAL_VOLUME_ALARM_THRESHOLD_MIN 50
+#define MAKAIR_SETTING_HIGH_TIDAL_VOLUME_ALARM_THRESHOLD_MAX 2000
+#define MAKAIR_SETTING_HIGH_TIDAL_VOLUME_ALARM_THRESHOLD_DEFAULT 1000
+#define MAKAIR_SETTING_PLATEAU_DURATION 23
+#define MAKAIR_SETTING_PLATEAU_DURATION_MIN 100
+#define MAKAIR_SETTING_PLATEAU_DURATION_MAX 2000
+#define MAKAIR_SETTING_PLATEAU_DURATION_DEFAULT 200
+#define MAKAIR_SETTING_LEAK_ALARM_THRESHOLD 24
+#define MAKAIR_SETTING_LEAK_ALARM_THRESHOLD_MIN 0
+#define MAKAIR_SETTING_LEAK_ALARM_THRESHOLD_MAX 10000
+#define MAKAIR_SETTING_LEAK_ALARM_THRESHOLD_DEFAULT 200
+#define MAKAIR_SETTING_TARGET_INSPIRATORY_FLOW 25
+#define MAKAIR_SETTING_TARGET_INSPIRATORY_FLOW_MIN 5
+#define MAKAIR_SETTING_TARGET_INSPIRATORY_FLOW_MAX 80
+#define MAKAIR_SETTING_TARGET_INSPIRATORY_FLOW_DEFAULT 40
+#define MAKAIR_SETTING_INSPIRATORY_DURATION 26
+#define MAKAIR_SETTING_INSPIRATORY_DURATION_MIN 200
+#define MAKAIR_SETTING_INSPIRATORY_DURATION_MAX 3000
+#define MAKAIR_SETTING_INSPIRATORY_DURATION_DEFAULT 800
+#define MAKAIR_SETTING_LOCALE 27
+#define MAKAIR_SETTING_LOCALE_MIN 24929
+#define MAKAIR_SETTING_LOCALE_MAX 31354
+#define MAKAIR_SETTING_LOCALE_DEFAULT 25966
+#define MAKAIR_SETTING_PATIENT_HEIGHT 28
+#define MAKAIR_SETTING_PATIENT_HEIGHT_MIN 30
+#define MAKAIR_SETTING_PATIENT_HEIGHT_MAX 250
+#define MAKAIR_SETTING_PATIENT_HEIGHT_DEFAULT 160
+#define MAKAIR_SETTING_PATIENT_GENDER 29
+#define MAKAIR_SETTING_PATIENT_GENDER_MIN 0
+#define MAKAIR_SETTING_PATIENT_GENDER_MAX 1
+#define MAKAIR_SETTING_PATIENT_GENDER_DEFAULT 0
+#define MAKAIR_SETTING_PEAK_PRESSURE_ALARM_THRESHOLD 30
+#define MAKAIR_SETTING_PEAK_PRESSURE_ALARM_THRESHOLD_MIN 50
+#define MAKAIR_SETTING_PEAK_PRESSURE_ALARM_THRESHOLD_MAX 700
+#define MAKAIR_SETTING_PEAK_PRESSURE_ALARM_THRESHOLD_DEFAULT 500
+#define MAKAIR_SETTING_EOL_CONFIRM 31
+#define MAKAIR_SETTING_EOL_CONFIRM_MIN 0
+#define MAKAIR_SETTING_EOL_CONFIRM_MAX 0
+#define MAKAIR_SETTING_EOL_CONFIRM_DEFAULT 0
+#define MAKAIR_SETTING_TIME_SYNC 32
+#define MAKAIR_SETTING_TIME_SYNC_MIN 0
+#define MAKAIR_SETTING_TIME_SYNC_MAX 65535
+#define MAKAIR_SETTING_TIME_SYNC_DEFAULT 0
+#define MAKAIR_SETTING_DATA_SNAPSHOT_DECIMATION 33
+#define MAKAIR_SETTING_DATA_SNAPSHOT_DECIMATION_MIN 1
+#define MAKAIR_SETTING_DATA_SNAPSHOT_DECIMATION_MAX 5
+#define MAKAIR_SETTING_DATA_SNAPSHOT_DECIMATION_DEFAULT 1
+#define MAKAIR_SETTING_WALL_CLOCK_HIGH 34
+#define MAKAIR_SETTING_WALL_CLOCK_HIGH_MIN 0
+#define MAKAIR_SETTING_WALL_CLOCK_HIGH_MAX 65535
+#define MAKAIR_SETTING_WALL_CLOCK_HIGH_DEFAULT 0
+#define MAKAIR_SETTING_WALL_CLOCK_LOW 35
+#define MAKAIR_SETTING_WALL_CLOCK_LOW_MIN 0
+#define MAKAIR_SETTING_WALL_CLOCK_LOW_MAX 65535
+#define MAKAIR_SETTING_WALL_CLOCK_LOW_DEFAULT 0
+
+#endif /* MAKAIR_TELEMETRY_H */
@@ -0,0 +1,40 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::session::{ControlEvent, ControlSession};
+use crate::structures::TelemetryMessage;
+
+/// Something that turns telemetry messages into higher-level outputs (events, derived values, UI state, etc.)
+///
+/// This is typically implemented by state machines that follow the MCU state; see `testing::replay()` to test them against recordings.
+pub trait MessageAdapter {
+    /// What this adapter produces
+    type Output;
+
+    /// Handle a telemetry message and return the outputs it produced (possibly none)
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<Self::Output>;
+}
+
+impl<F, O> MessageAdapter for F
+where
+    F: FnMut(&TelemetryMessage) -> Vec<O>,
+{
+    type Output = O;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<O> {
+        self(message)
+    }
+}
+
+impl MessageAdapter for ControlSession {
+    type Output = ControlEvent;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<ControlEvent> {
+        match message {
+            TelemetryMessage::ControlAck(ack) => self.handle_ack(ack).into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
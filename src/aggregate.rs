@@ -0,0 +1,310 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{BTreeMap, HashMap};
+
+use crate::anomaly::{CycleMetrics, Metric};
+use crate::structures::TelemetryMessage;
+
+/// Default minimum number of devices an hour must have to be exported
+pub const DEFAULT_MIN_DEVICES: usize = 5;
+
+/// Length of the periods statistics are aggregated over, in microseconds
+pub const HOUR: u64 = 3_600_000_000;
+
+/// Metrics that are aggregated, in the order of the columns of the CSV export
+pub const AGGREGATED_METRICS: [Metric; 5] = [
+    Metric::PeakPressure,
+    Metric::PlateauPressure,
+    Metric::Peep,
+    Metric::TidalVolume,
+    Metric::RespiratoryRate,
+];
+
+#[derive(Debug, Clone, Copy, Default)]
+struct Sum {
+    count: u64,
+    total: f64,
+}
+
+impl Sum {
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.total += value;
+    }
+
+    fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.total / self.count as f64)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+struct DeviceHour {
+    cycles: u64,
+    alarms: u64,
+    metrics: [Sum; AGGREGATED_METRICS.len()],
+}
+
+/// Statistics of one hour of ventilation, across devices
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct HourlyAggregate {
+    /// Hour of ventilation, counted from the first message of each device
+    pub hour: u64,
+    /// Number of devices that ventilated during this hour
+    pub devices: usize,
+    /// Total number of cycles
+    pub cycles: u64,
+    /// Mean number of alarms triggered per device
+    pub alarms_per_device: f64,
+    /// Mean and standard deviation of the mean of each device, for every metric of `AGGREGATED_METRICS` (`None` if no device measured it)
+    pub metrics: Vec<Option<(f64, f64)>>,
+}
+
+/// Aggregated statistics that can be shared without exposing any waveform or any single device
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct AggregateExport {
+    /// Hours that have enough devices
+    pub hours: Vec<HourlyAggregate>,
+    /// Number of hours left out because they had too few devices
+    pub suppressed_hours: usize,
+}
+
+impl AggregateExport {
+    /// Render as CSV, with a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("hour,devices,cycles,alarms_per_device");
+        for metric in AGGREGATED_METRICS {
+            let name = metric.name();
+            csv.push_str(&format!(",{}_mean,{}_std_dev", name, name));
+        }
+        csv.push('\n');
+        for hour in &self.hours {
+            csv.push_str(&format!(
+                "{},{},{},{:.2}",
+                hour.hour, hour.devices, hour.cycles, hour.alarms_per_device
+            ));
+            for metric in &hour.metrics {
+                match metric {
+                    Some((mean, std_dev)) => csv.push_str(&format!(",{:.1},{:.1}", mean, std_dev)),
+                    None => csv.push_str(",,"),
+                }
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Builds per-hour statistics across devices, and only exports hours shared by enough devices (k-anonymity)
+///
+/// Only cycle metrics and alarm counts are kept: raw waveforms never reach the export, and an hour is exported only when at least `min_devices` distinct devices contributed to it.
+/// Hours are counted from the first message of each device, so that sessions which started at different times can be compared.
+#[derive(Debug, Clone)]
+pub struct Aggregator {
+    min_devices: usize,
+    hours: BTreeMap<u64, HashMap<String, DeviceHour>>,
+    anonymous_devices: usize,
+}
+
+impl Default for Aggregator {
+    fn default() -> Self {
+        Self::new(DEFAULT_MIN_DEVICES)
+    }
+}
+
+impl Aggregator {
+    /// Create an aggregator exporting hours that have at least `min_devices` devices (at least 1)
+    pub fn new(min_devices: usize) -> Self {
+        Self {
+            min_devices: min_devices.max(1),
+            hours: BTreeMap::new(),
+            anonymous_devices: 0,
+        }
+    }
+
+    /// Add the messages of a session of a device, e.g. a recording
+    ///
+    /// Sessions of the same device ID count as one device; sessions without a device ID (protocol v1) count as distinct devices.
+    pub fn add_session<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(&mut self, messages: I) {
+        let mut device = None;
+        let mut elapsed = 0;
+        let mut last_systick = None;
+        for message in messages {
+            let device = device.get_or_insert_with(|| {
+                if message.device_id().is_empty() {
+                    self.anonymous_devices += 1;
+                    format!("anonymous-{}", self.anonymous_devices)
+                } else {
+                    message.device_id()
+                }
+            });
+            let systick = message.systick();
+            // Systick goes back when the device reboots: the session goes on from where it was
+            elapsed += last_systick.map_or(0, |last| systick.saturating_sub(last));
+            last_systick = Some(systick);
+
+            let hour = self
+                .hours
+                .entry(elapsed / HOUR)
+                .or_default()
+                .entry(device.clone())
+                .or_default();
+            match message {
+                TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                    let metrics = CycleMetrics::new(snapshot, None);
+                    hour.cycles += 1;
+                    for (sum, metric) in hour.metrics.iter_mut().zip(AGGREGATED_METRICS) {
+                        if let Some(value) = metric.value(&metrics) {
+                            sum.add(value);
+                        }
+                    }
+                }
+                TelemetryMessage::AlarmTrap(alarm) if alarm.triggered => hour.alarms += 1,
+                _ => (),
+            }
+        }
+    }
+
+    /// Aggregate every hour, leaving out those with too few devices
+    pub fn export(&self) -> AggregateExport {
+        let mut export = AggregateExport::default();
+        for (hour, devices) in &self.hours {
+            if devices.len() < self.min_devices {
+                export.suppressed_hours += 1;
+                continue;
+            }
+            let count = devices.len() as f64;
+            let metrics = (0..AGGREGATED_METRICS.len())
+                .map(|index| {
+                    let means: Vec<f64> = devices
+                        .values()
+                        .filter_map(|device| device.metrics[index].mean())
+                        .collect();
+                    if means.is_empty() {
+                        return None;
+                    }
+                    let mean = means.iter().sum::<f64>() / means.len() as f64;
+                    let variance =
+                        means.iter().map(|m| (m - mean).powi(2)).sum::<f64>() / means.len() as f64;
+                    Some((mean, variance.sqrt()))
+                })
+                .collect();
+            export.hours.push(HourlyAggregate {
+                hour: *hour,
+                devices: devices.len(),
+                cycles: devices.values().map(|device| device.cycles).sum(),
+                alarms_per_device: devices.values().map(|device| device.alarms).sum::<u64>() as f64
+                    / count,
+                metrics,
+            });
+        }
+        export
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{AlarmPriority, AlarmTrap, MachineStateSnapshot, Phase};
+
+    fn session(device_id: &str, peak: u16, hours: u64) -> Vec<TelemetryMessage> {
+        (0..hours * 60)
+            .map(|minute| {
+                TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+                    telemetry_version: 2,
+                    device_id: device_id.to_owned(),
+                    systick: minute * 60_000_000,
+                    previous_peak_pressure: peak,
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn suppresses_hours_with_few_devices() {
+        let mut aggregator = Aggregator::new(3);
+        aggregator.add_session(&session("a", 200, 2));
+        aggregator.add_session(&session("b", 250, 2));
+        aggregator.add_session(&session("c", 300, 1));
+        // Same device as before: it does not make the second hour shareable
+        aggregator.add_session(&session("c", 300, 1));
+
+        let export = aggregator.export();
+        assert_eq!(export.suppressed_hours, 1);
+        assert_eq!(export.hours.len(), 1);
+        let hour = &export.hours[0];
+        assert_eq!((hour.hour, hour.devices, hour.cycles), (0, 3, 240));
+        let (mean, std_dev) = hour.metrics[0].unwrap();
+        assert_eq!(mean, 250.0);
+        assert!((std_dev - 40.82).abs() < 0.01);
+
+        let csv = export.to_csv();
+        assert!(csv.starts_with("hour,devices,cycles,alarms_per_device,peak_pressure_mean,"));
+        assert_eq!(csv.lines().count(), 2);
+    }
+
+    fn alarm(device_id: &str, systick: u64, triggered: bool) -> TelemetryMessage {
+        TelemetryMessage::AlarmTrap(AlarmTrap {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: device_id.to_owned(),
+            systick,
+            centile: 0,
+            pressure: 0,
+            phase: Phase::Inhalation,
+            subphase: None,
+            cycle: 0,
+            alarm_code: 12,
+            alarm_priority: AlarmPriority::High,
+            triggered,
+            expected: 0,
+            measured: 0,
+            cycles_since_trigger: 0,
+        })
+    }
+
+    #[test]
+    fn handles_anonymous_devices_reboots_and_alarms() {
+        let export = Aggregator::new(0).export();
+        assert_eq!(export, AggregateExport::default());
+        assert_eq!(export.to_csv().lines().count(), 1);
+
+        let mut aggregator = Aggregator::new(1);
+        // Protocol v1 sessions cannot be told apart: each one counts as a device
+        aggregator.add_session(&session("", 200, 1));
+        aggregator.add_session(&session("", 300, 1));
+        // The device rebooted after 50 minutes: the session goes on in the second hour
+        let mut rebooted = session("a", 250, 1);
+        rebooted.truncate(50);
+        rebooted.extend(session("a", 250, 1));
+        rebooted.push(alarm("a", 3_540_000_000, true));
+        rebooted.push(alarm("a", 3_541_000_000, false));
+        aggregator.add_session(&rebooted);
+
+        let export = aggregator.export();
+        assert_eq!(export.suppressed_hours, 0);
+        assert_eq!(export.hours.len(), 2);
+        assert_eq!(export.hours[0].devices, 3);
+        // The first snapshot after the reboot is at the same point of the session as the last one before it
+        assert_eq!(export.hours[0].cycles, 60 + 60 + 61);
+        assert_eq!(export.hours[0].alarms_per_device, 0.0);
+        let hour = &export.hours[1];
+        assert_eq!((hour.hour, hour.devices, hour.cycles), (1, 1, 49));
+        // Only triggered alarms are counted
+        assert_eq!(hour.alarms_per_device, 1.0);
+        // Metrics that were never measured are left empty
+        assert_eq!(hour.metrics[3], None);
+        assert!(export.to_csv().lines().nth(2).unwrap().ends_with(",,"));
+    }
+}
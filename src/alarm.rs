@@ -3,6 +3,9 @@
 // Copyright: 2020, Makers For Life
 // License: Public Domain License
 
+use crate::control::ControlSetting;
+use crate::structures::{AlarmTrap, MachineStateSnapshot, StoppedMessage, TelemetryMessage};
+
 /// Error code of RMC SW 1
 pub const RMC_SW_1: u8 = 12;
 /// Error code of RMC SW 2
@@ -148,6 +151,11 @@ impl AlarmCode {
     pub fn code(self) -> u8 {
         self.code
     }
+
+    /// Whether the alarm code is in the catalog (its description is not `Unknown`)
+    pub fn is_known(self) -> bool {
+        !matches!(self.description(), AlarmCodeDescription::Unknown(_))
+    }
 }
 
 impl From<u8> for AlarmCode {
@@ -156,3 +164,239 @@ impl From<u8> for AlarmCode {
         AlarmCode { code }
     }
 }
+
+impl MachineStateSnapshot {
+    /// Typed codes of the alarms that are currently triggered
+    pub fn alarm_codes(&self) -> Vec<AlarmCode> {
+        self.current_alarm_codes
+            .iter()
+            .copied()
+            .map(AlarmCode::from)
+            .collect()
+    }
+}
+
+impl StoppedMessage {
+    /// Typed codes of the alarms that are currently triggered, if the MCU reported them
+    pub fn alarm_codes(&self) -> Option<Vec<AlarmCode>> {
+        self.current_alarm_codes
+            .as_ref()
+            .map(|codes| codes.iter().copied().map(AlarmCode::from).collect())
+    }
+}
+
+impl AlarmTrap {
+    /// Typed code of the alarm
+    pub fn alarm(&self) -> AlarmCode {
+        AlarmCode::from(self.alarm_code)
+    }
+}
+
+impl TelemetryMessage {
+    /// Typed alarm codes carried by the message: triggered alarms of snapshots and stopped messages, or the alarm of an alarm trap
+    pub fn alarm_codes(&self) -> Vec<AlarmCode> {
+        match self {
+            Self::MachineStateSnapshot(snapshot) => snapshot.alarm_codes(),
+            Self::StoppedMessage(stopped) => stopped.alarm_codes().unwrap_or_default(),
+            Self::AlarmTrap(trap) => vec![trap.alarm()],
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Thresholds of every configurable alarm, as reported by the MCU
+///
+/// Thresholds are `None` when the MCU did not report them (e.g. older firmware versions).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct AlarmThresholds {
+    /// Threshold for low inspiratory minute volume alarm in L/min
+    pub low_inspiratory_minute_volume: Option<u8>,
+    /// Threshold for high inspiratory minute volume alarm in L/min
+    pub high_inspiratory_minute_volume: Option<u8>,
+    /// Threshold for low expiratory minute volume alarm in L/min
+    pub low_expiratory_minute_volume: Option<u8>,
+    /// Threshold for high expiratory minute volume alarm in L/min
+    pub high_expiratory_minute_volume: Option<u8>,
+    /// Threshold for low respiratory rate alarm in cycle per minute
+    pub low_respiratory_rate: Option<u8>,
+    /// Threshold for high respiratory rate alarm in cycle per minute
+    pub high_respiratory_rate: Option<u8>,
+    /// Threshold for low tidal volume in mL
+    pub low_tidal_volume: Option<u16>,
+    /// Threshold for high tidal volume in mL
+    pub high_tidal_volume: Option<u16>,
+    /// Threshold for leak alarm in cL/min
+    pub leak: Option<u16>,
+    /// Threshold for peak pressure alarm in mmH2O
+    pub peak_pressure: Option<u16>,
+}
+
+/// A threshold that differs between two `AlarmThresholds`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct AlarmThresholdChange {
+    /// Control setting of the threshold
+    pub setting: ControlSetting,
+    /// Value in the first `AlarmThresholds`
+    pub previous: Option<u16>,
+    /// Value in the second `AlarmThresholds`
+    pub current: Option<u16>,
+}
+
+impl AlarmThresholds {
+    /// Collect alarm thresholds from a machine state snapshot
+    pub fn from_machine_state(snapshot: &MachineStateSnapshot) -> Self {
+        Self {
+            low_inspiratory_minute_volume: snapshot.low_inspiratory_minute_volume_alarm_threshold,
+            high_inspiratory_minute_volume: snapshot.high_inspiratory_minute_volume_alarm_threshold,
+            low_expiratory_minute_volume: snapshot.low_expiratory_minute_volume_alarm_threshold,
+            high_expiratory_minute_volume: snapshot.high_expiratory_minute_volume_alarm_threshold,
+            low_respiratory_rate: snapshot.low_respiratory_rate_alarm_threshold,
+            high_respiratory_rate: snapshot.high_respiratory_rate_alarm_threshold,
+            low_tidal_volume: snapshot.low_tidal_volume_alarm_threshold,
+            high_tidal_volume: snapshot.high_tidal_volume_alarm_threshold,
+            leak: snapshot.leak_alarm_threshold,
+            peak_pressure: snapshot.peak_pressure_alarm_threshold,
+        }
+    }
+
+    /// Collect alarm thresholds from a stopped message
+    pub fn from_stopped_message(message: &StoppedMessage) -> Self {
+        Self {
+            low_inspiratory_minute_volume: message.low_inspiratory_minute_volume_alarm_threshold,
+            high_inspiratory_minute_volume: message.high_inspiratory_minute_volume_alarm_threshold,
+            low_expiratory_minute_volume: message.low_expiratory_minute_volume_alarm_threshold,
+            high_expiratory_minute_volume: message.high_expiratory_minute_volume_alarm_threshold,
+            low_respiratory_rate: message.low_respiratory_rate_alarm_threshold,
+            high_respiratory_rate: message.high_respiratory_rate_alarm_threshold,
+            low_tidal_volume: message.low_tidal_volume_alarm_threshold,
+            high_tidal_volume: message.high_tidal_volume_alarm_threshold,
+            leak: message.leak_alarm_threshold,
+            peak_pressure: message.peak_pressure_alarm_threshold,
+        }
+    }
+
+    /// Get every threshold along with its control setting
+    pub fn values(&self) -> [(ControlSetting, Option<u16>); 10] {
+        [
+            (
+                ControlSetting::LowInspiratoryMinuteVolumeAlarmThreshold,
+                self.low_inspiratory_minute_volume.map(u16::from),
+            ),
+            (
+                ControlSetting::HighInspiratoryMinuteVolumeAlarmThreshold,
+                self.high_inspiratory_minute_volume.map(u16::from),
+            ),
+            (
+                ControlSetting::LowExpiratoryMinuteVolumeAlarmThreshold,
+                self.low_expiratory_minute_volume.map(u16::from),
+            ),
+            (
+                ControlSetting::HighExpiratoryMinuteVolumeAlarmThreshold,
+                self.high_expiratory_minute_volume.map(u16::from),
+            ),
+            (
+                ControlSetting::LowRespiratoryRateAlarmThreshold,
+                self.low_respiratory_rate.map(u16::from),
+            ),
+            (
+                ControlSetting::HighRespiratoryRateAlarmThreshold,
+                self.high_respiratory_rate.map(u16::from),
+            ),
+            (
+                ControlSetting::LowTidalVolumeAlarmThreshold,
+                self.low_tidal_volume,
+            ),
+            (
+                ControlSetting::HighTidalVolumeAlarmThreshold,
+                self.high_tidal_volume,
+            ),
+            (ControlSetting::LeakAlarmThreshold, self.leak),
+            (
+                ControlSetting::PeakPressureAlarmThreshold,
+                self.peak_pressure,
+            ),
+        ]
+    }
+
+    /// List thresholds that changed between `self` and `other`
+    pub fn diff(&self, other: &Self) -> Vec<AlarmThresholdChange> {
+        self.values()
+            .iter()
+            .zip(other.values().iter())
+            .filter(|((_, previous), (_, current))| previous != current)
+            .map(|((setting, previous), (_, current))| AlarmThresholdChange {
+                setting: *setting,
+                previous: *previous,
+                current: *current,
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn diff_alarm_thresholds() {
+        let previous = AlarmThresholds {
+            leak: Some(200),
+            low_respiratory_rate: Some(16),
+            ..AlarmThresholds::default()
+        };
+        let current = AlarmThresholds {
+            leak: Some(300),
+            low_respiratory_rate: Some(16),
+            peak_pressure: Some(500),
+            ..AlarmThresholds::default()
+        };
+
+        assert_eq!(
+            previous.diff(&current),
+            vec![
+                AlarmThresholdChange {
+                    setting: ControlSetting::LeakAlarmThreshold,
+                    previous: Some(200),
+                    current: Some(300),
+                },
+                AlarmThresholdChange {
+                    setting: ControlSetting::PeakPressureAlarmThreshold,
+                    previous: None,
+                    current: Some(500),
+                },
+            ]
+        );
+        assert!(current.diff(&current).is_empty());
+    }
+
+    #[test]
+    fn types_alarm_codes_of_messages() {
+        let message = TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+            current_alarm_codes: vec![RMC_SW_2, 200],
+            ..MachineStateSnapshot::default()
+        });
+        let codes = message.alarm_codes();
+        assert_eq!(
+            codes
+                .iter()
+                .map(|code| code.description())
+                .collect::<Vec<_>>(),
+            vec![
+                AlarmCodeDescription::PatientUnplugged,
+                AlarmCodeDescription::Unknown(200)
+            ]
+        );
+        assert!(codes[0].is_known());
+        assert!(!codes[1].is_known());
+
+        let stopped = StoppedMessage::default();
+        assert_eq!(stopped.alarm_codes(), None);
+        assert!(TelemetryMessage::StoppedMessage(stopped)
+            .alarm_codes()
+            .is_empty());
+    }
+}
@@ -0,0 +1,310 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use polars::prelude::*;
+
+use crate::structures::{AlarmTrap, DataSnapshot, MachineStateSnapshot, TelemetryMessage};
+
+fn column<T, P: ?Sized>(name: &str, values: T) -> Column
+where
+    Series: NamedFrom<T, P>,
+{
+    Series::new(name.into(), values).into()
+}
+
+/// Build one column per field, taking the value of the field from each item
+///
+/// Fields must be `Copy`; others are built explicitly.
+macro_rules! field_columns {
+    ($items:expr; $($field:ident),* $(,)?) => {
+        vec![$(column(
+            stringify!($field),
+            $items.iter().map(|item| item.$field).collect::<Vec<_>>(),
+        )),*]
+    };
+}
+
+/// Build a DataFrame with one row per data snapshot (other messages are ignored)
+///
+/// Columns are named after the fields of `DataSnapshot`; enums are stored as strings, and flows are null for protocol v1 messages.
+pub fn to_dataframe<'a, I>(messages: I) -> PolarsResult<DataFrame>
+where
+    I: IntoIterator<Item = &'a TelemetryMessage>,
+{
+    let snapshots: Vec<&DataSnapshot> = messages
+        .into_iter()
+        .filter_map(|message| match message {
+            TelemetryMessage::DataSnapshot(snapshot) => Some(snapshot),
+            _ => None,
+        })
+        .collect();
+
+    let mut columns = vec![
+        column(
+            "device_id",
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.device_id.as_str())
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "phase",
+            snapshots
+                .iter()
+                .map(|snapshot| format!("{:?}", snapshot.phase))
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "subphase",
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.subphase.map(|subphase| format!("{:?}", subphase)))
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    columns.extend(field_columns!(snapshots;
+        telemetry_version,
+        systick,
+        centile,
+        pressure,
+        blower_valve_position,
+        patient_valve_position,
+        blower_rpm,
+        battery_level,
+        inspiratory_flow,
+        expiratory_flow,
+    ));
+
+    DataFrame::new(columns)
+}
+
+/// Build a DataFrame with one row per machine state snapshot (other messages are ignored)
+///
+/// Columns are named after the fields of `MachineStateSnapshot`; enums are stored as strings, alarm codes as a list, and settings that the firmware did not send are null.
+pub fn machine_states_to_dataframe<'a, I>(messages: I) -> PolarsResult<DataFrame>
+where
+    I: IntoIterator<Item = &'a TelemetryMessage>,
+{
+    let snapshots: Vec<&MachineStateSnapshot> = messages
+        .into_iter()
+        .filter_map(|message| match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => Some(snapshot),
+            _ => None,
+        })
+        .collect();
+
+    let mut columns = vec![
+        column(
+            "device_id",
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.device_id.as_str())
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "ventilation_mode",
+            snapshots
+                .iter()
+                .map(|snapshot| format!("{:?}", snapshot.ventilation_mode))
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "locale",
+            snapshots
+                .iter()
+                .map(|snapshot| snapshot.locale.map(|locale| locale.to_string()))
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "patient_gender",
+            snapshots
+                .iter()
+                .map(|snapshot| {
+                    snapshot
+                        .patient_gender
+                        .map(|gender| format!("{:?}", gender))
+                })
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "current_alarm_codes",
+            snapshots
+                .iter()
+                .map(|snapshot| Series::new("".into(), snapshot.current_alarm_codes.as_slice()))
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    columns.extend(field_columns!(snapshots;
+        telemetry_version,
+        systick,
+        cycle,
+        peak_command,
+        plateau_command,
+        peep_command,
+        cpm_command,
+        previous_peak_pressure,
+        previous_plateau_pressure,
+        previous_peep_pressure,
+        previous_volume,
+        expiratory_term,
+        trigger_enabled,
+        trigger_offset,
+        previous_cpm,
+        alarm_snoozed,
+        cpu_load,
+        inspiratory_trigger_flow,
+        expiratory_trigger_flow,
+        ti_min,
+        ti_max,
+        low_inspiratory_minute_volume_alarm_threshold,
+        high_inspiratory_minute_volume_alarm_threshold,
+        low_expiratory_minute_volume_alarm_threshold,
+        high_expiratory_minute_volume_alarm_threshold,
+        low_respiratory_rate_alarm_threshold,
+        high_respiratory_rate_alarm_threshold,
+        target_tidal_volume,
+        low_tidal_volume_alarm_threshold,
+        high_tidal_volume_alarm_threshold,
+        plateau_duration,
+        leak_alarm_threshold,
+        target_inspiratory_flow,
+        inspiratory_duration_command,
+        previous_inspiratory_duration,
+        battery_level,
+        patient_height,
+        peak_pressure_alarm_threshold,
+    ));
+
+    DataFrame::new(columns)
+}
+
+/// Build a DataFrame with one row per alarm trap (other messages are ignored)
+///
+/// Columns are named after the fields of `AlarmTrap`; enums are stored as strings.
+pub fn alarms_to_dataframe<'a, I>(messages: I) -> PolarsResult<DataFrame>
+where
+    I: IntoIterator<Item = &'a TelemetryMessage>,
+{
+    let traps: Vec<&AlarmTrap> = messages
+        .into_iter()
+        .filter_map(|message| match message {
+            TelemetryMessage::AlarmTrap(trap) => Some(trap),
+            _ => None,
+        })
+        .collect();
+
+    let mut columns = vec![
+        column(
+            "device_id",
+            traps
+                .iter()
+                .map(|trap| trap.device_id.as_str())
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "phase",
+            traps
+                .iter()
+                .map(|trap| format!("{:?}", trap.phase))
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "subphase",
+            traps
+                .iter()
+                .map(|trap| trap.subphase.map(|subphase| format!("{:?}", subphase)))
+                .collect::<Vec<_>>(),
+        ),
+        column(
+            "alarm_priority",
+            traps
+                .iter()
+                .map(|trap| format!("{:?}", trap.alarm_priority))
+                .collect::<Vec<_>>(),
+        ),
+    ];
+    columns.extend(field_columns!(traps;
+        telemetry_version,
+        systick,
+        centile,
+        pressure,
+        cycle,
+        alarm_code,
+        triggered,
+        expected,
+        measured,
+        cycles_since_trigger,
+    ));
+
+    DataFrame::new(columns)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{Phase, StoppedMessage};
+
+    fn snapshot(systick: u64, inspiratory_flow: Option<i16>) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: "device".to_string(),
+            systick,
+            centile: 0,
+            pressure: -12,
+            phase: Phase::Exhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow,
+            expiratory_flow: None,
+        })
+    }
+
+    #[test]
+    fn data_snapshots_with_nulls() {
+        let messages = vec![
+            snapshot(1, Some(300)),
+            TelemetryMessage::StoppedMessage(StoppedMessage::default()),
+            snapshot(2, None),
+        ];
+        let df = to_dataframe(&messages).unwrap();
+
+        assert_eq!(df.height(), 2);
+        assert_eq!(df.column("systick").unwrap().dtype(), &DataType::UInt64);
+        assert_eq!(df.column("pressure").unwrap().dtype(), &DataType::Int16);
+        assert_eq!(
+            df.column("phase").unwrap().str().unwrap().get(0),
+            Some("Exhalation")
+        );
+        let flow = df.column("inspiratory_flow").unwrap().i16().unwrap();
+        assert_eq!(flow.get(0), Some(300));
+        assert_eq!(flow.get(1), None);
+        assert_eq!(df.column("expiratory_flow").unwrap().null_count(), 2);
+    }
+
+    #[test]
+    fn machine_states_with_alarm_codes() {
+        let messages = vec![TelemetryMessage::MachineStateSnapshot(
+            MachineStateSnapshot {
+                cycle: 3,
+                current_alarm_codes: vec![12, 14],
+                ..Default::default()
+            },
+        )];
+        let df = machine_states_to_dataframe(&messages).unwrap();
+
+        assert_eq!(df.height(), 1);
+        assert_eq!(df.column("cycle").unwrap().u32().unwrap().get(0), Some(3));
+        assert_eq!(
+            df.column("current_alarm_codes").unwrap().dtype(),
+            &DataType::List(Box::new(DataType::UInt8))
+        );
+        assert_eq!(df.column("previous_volume").unwrap().null_count(), 1);
+        assert_eq!(alarms_to_dataframe(&messages).unwrap().height(), 0);
+    }
+}
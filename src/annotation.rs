@@ -0,0 +1,197 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::Read;
+
+use crate::recording::Base64Decoder;
+
+/// Header of annotation frames
+///
+/// It differs from the header of telemetry frames, so that readers that do not know annotations skip them like any other garbage.
+pub const ANNOTATION_HEADER: &[u8; 2] = b"\x03\x0A";
+const ANNOTATION_FOOTER: &[u8; 2] = b"\x30\xC0";
+const READ_CHUNK_SIZE: usize = 8 * 1024;
+
+/// A note written by an operator in a recording (e.g. "changed PEEP per protocol")
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct Annotation {
+    /// Systick of the MCU at which the annotation applies
+    pub systick: u64,
+    /// Free text (at most 65535 bytes are stored; longer texts are truncated)
+    pub text: String,
+}
+
+enum FrameParsing {
+    Complete(Annotation, usize),
+    Incomplete,
+    Invalid,
+}
+
+impl Annotation {
+    /// Create an annotation
+    pub fn new<S: Into<String>>(systick: u64, text: S) -> Self {
+        Self {
+            systick,
+            text: text.into(),
+        }
+    }
+
+    /// Build a frame containing this annotation, ready to be written in a recording
+    pub fn to_frame(&self) -> Vec<u8> {
+        let mut text_len = self.text.len().min(u16::MAX as usize);
+        while !self.text.is_char_boundary(text_len) {
+            text_len -= 1;
+        }
+
+        let mut body = Vec::with_capacity(10 + text_len);
+        body.extend_from_slice(&self.systick.to_be_bytes());
+        body.extend_from_slice(&(text_len as u16).to_be_bytes());
+        body.extend_from_slice(&self.text.as_bytes()[..text_len]);
+
+        let mut frame = Vec::with_capacity(body.len() + 8);
+        frame.extend_from_slice(ANNOTATION_HEADER);
+        frame.extend_from_slice(&body);
+        frame.extend_from_slice(&crc32fast::hash(&body).to_be_bytes());
+        frame.extend_from_slice(ANNOTATION_FOOTER);
+        frame
+    }
+
+    fn parse_frame(input: &[u8]) -> FrameParsing {
+        const FIXED_LEN: usize = 2 + 8 + 2 + 4 + 2;
+
+        if input.len() < ANNOTATION_HEADER.len() && ANNOTATION_HEADER.starts_with(input) {
+            return FrameParsing::Incomplete;
+        }
+        if !input.starts_with(ANNOTATION_HEADER) {
+            return FrameParsing::Invalid;
+        }
+        if input.len() < 12 {
+            return FrameParsing::Incomplete;
+        }
+        let text_len = u16::from_be_bytes([input[10], input[11]]) as usize;
+        let frame_len = FIXED_LEN + text_len;
+        if input.len() < frame_len {
+            return FrameParsing::Incomplete;
+        }
+
+        let body = &input[2..12 + text_len];
+        let crc = &input[12 + text_len..16 + text_len];
+        let footer = &input[16 + text_len..frame_len];
+        if footer != ANNOTATION_FOOTER || crc != crc32fast::hash(body).to_be_bytes() {
+            return FrameParsing::Invalid;
+        }
+        match std::str::from_utf8(&body[10..]) {
+            Ok(text) => {
+                let mut systick = [0; 8];
+                systick.copy_from_slice(&body[..8]);
+                FrameParsing::Complete(
+                    Annotation::new(u64::from_be_bytes(systick), text),
+                    frame_len,
+                )
+            }
+            Err(_) => FrameParsing::Invalid,
+        }
+    }
+}
+
+/// Read every annotation contained in a recording, sorted by systick
+///
+/// * `reader` - Recording (base64-encoded frames, usually a file).
+///
+/// Telemetry frames are ignored; annotations can be anywhere in the recording, they do not need to be sorted.
+pub fn read_annotations<R: Read>(reader: R) -> std::io::Result<Vec<Annotation>> {
+    let mut reader = Base64Decoder::new(reader);
+    let mut chunk = [0; READ_CHUNK_SIZE];
+    let mut buffer: Vec<u8> = Vec::new();
+    let mut annotations = Vec::new();
+
+    loop {
+        let read_bytes = match reader.read(&mut chunk) {
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let eof = read_bytes == 0;
+        buffer.extend_from_slice(&chunk[..read_bytes]);
+
+        let mut pos = 0;
+        while pos < buffer.len() {
+            match Annotation::parse_frame(&buffer[pos..]) {
+                FrameParsing::Complete(annotation, len) => {
+                    annotations.push(annotation);
+                    pos += len;
+                }
+                // At the end of the stream, this was not an annotation after all
+                FrameParsing::Incomplete if !eof => break,
+                _ => pos += 1,
+            }
+        }
+        buffer.drain(..pos);
+
+        if eof {
+            break;
+        }
+    }
+
+    annotations.sort_by_key(|annotation| annotation.systick);
+    Ok(annotations)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::structures::{StoppedMessage, TelemetryMessage};
+
+    #[test]
+    fn frame_roundtrip() {
+        let annotation = Annotation::new(42, "patient simulator disconnected");
+        match Annotation::parse_frame(&annotation.to_frame()) {
+            FrameParsing::Complete(parsed, len) => {
+                assert_eq!(parsed, annotation);
+                assert_eq!(len, annotation.to_frame().len());
+            }
+            _ => panic!("failed to parse annotation frame"),
+        }
+    }
+
+    #[test]
+    fn truncates_long_texts_on_char_boundaries() {
+        let annotation = Annotation::new(0, "é".repeat(40_000));
+        match Annotation::parse_frame(&annotation.to_frame()) {
+            FrameParsing::Complete(parsed, _) => assert_eq!(parsed.text.len(), 65534),
+            _ => panic!("failed to parse annotation frame"),
+        }
+    }
+
+    #[test]
+    fn reads_annotations_among_telemetry_frames() {
+        let stopped = TelemetryMessage::StoppedMessage(StoppedMessage::default()).to_bytes();
+        let frames = [
+            stopped.clone(),
+            Annotation::new(20, "changed PEEP per protocol").to_frame(),
+            stopped.clone(),
+            Annotation::new(10, "started").to_frame(),
+            stopped,
+        ];
+        let recording: String = frames
+            .iter()
+            .map(|frame| format!("{}\n", base64::encode(frame)))
+            .collect();
+
+        let annotations = read_annotations(recording.as_bytes()).unwrap();
+        assert_eq!(
+            annotations,
+            vec![
+                Annotation::new(10, "started"),
+                Annotation::new(20, "changed PEEP per protocol")
+            ]
+        );
+    }
+}
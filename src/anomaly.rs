@@ -0,0 +1,507 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::sync::mpsc::Sender;
+
+use crate::adapter::MessageAdapter;
+use crate::rolling::{RollingStats, Window};
+use crate::sink::TelemetrySink;
+use crate::structures::{DataSnapshot, MachineStateSnapshot, TelemetryMessage};
+use crate::volume::{BreathVolumes, VolumeIntegrator};
+use crate::TimedMessage;
+
+/// Default number of cycles used by `ZScoreDetector` to learn what is normal
+pub const DEFAULT_ZSCORE_WINDOW: u32 = 30;
+
+/// Default number of standard deviations from the mean above which `ZScoreDetector` reports a value
+pub const DEFAULT_ZSCORE_THRESHOLD: f64 = 3.0;
+
+/// Metrics of one breathing cycle, derived from its machine state snapshot and its data snapshots
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct CycleMetrics {
+    /// Systick of the machine state snapshot that ended the cycle
+    pub systick: u64,
+    /// Number of the cycle
+    pub cycle: u32,
+    /// Measured peak pressure in mmH2O
+    pub peak_pressure: u16,
+    /// Measured plateau pressure in mmH2O
+    pub plateau_pressure: u16,
+    /// Measured PEEP in mmH2O
+    pub peep: u16,
+    /// Tidal volume reported by the firmware in mL
+    pub tidal_volume: Option<u16>,
+    /// Measured number of cycles per minute
+    pub respiratory_rate: Option<u8>,
+    /// Mean leak flow in cL/min, integrated from the flows of data snapshots (protocol v2 only)
+    pub leak_flow: Option<f64>,
+}
+
+impl CycleMetrics {
+    /// Derive the metrics of a cycle
+    pub fn new(snapshot: &MachineStateSnapshot, volumes: Option<&BreathVolumes>) -> Self {
+        Self {
+            systick: snapshot.systick,
+            cycle: snapshot.cycle,
+            peak_pressure: snapshot.previous_peak_pressure,
+            plateau_pressure: snapshot.previous_plateau_pressure,
+            peep: snapshot.previous_peep_pressure,
+            tidal_volume: snapshot.previous_volume,
+            respiratory_rate: snapshot.previous_cpm,
+            leak_flow: volumes.map(BreathVolumes::leak_flow),
+        }
+    }
+}
+
+/// Pressure and flow samples of one breathing cycle, from its data snapshots (one every 10 ms)
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct CycleWaveform {
+    /// Pressures in mmH2O
+    pub pressure: Vec<f64>,
+    /// Inspiratory minus expiratory flows in cL/min (0 in protocol v1, which has no flow)
+    pub flow: Vec<f64>,
+}
+
+impl CycleWaveform {
+    /// Number of samples
+    pub fn len(&self) -> usize {
+        self.pressure.len()
+    }
+
+    /// Whether there is no sample
+    pub fn is_empty(&self) -> bool {
+        self.pressure.is_empty()
+    }
+
+    fn push(&mut self, snapshot: &DataSnapshot) {
+        self.pressure.push(f64::from(snapshot.pressure));
+        self.flow.push(
+            f64::from(snapshot.inspiratory_flow.unwrap_or(0))
+                - f64::from(snapshot.expiratory_flow.unwrap_or(0)),
+        );
+    }
+
+    fn clear(&mut self) {
+        self.pressure.clear();
+        self.flow.clear();
+    }
+}
+
+/// A metric of `CycleMetrics` that detectors can watch
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum Metric {
+    /// Peak pressure in mmH2O
+    PeakPressure,
+    /// Plateau pressure in mmH2O
+    PlateauPressure,
+    /// PEEP in mmH2O
+    Peep,
+    /// Tidal volume in mL
+    TidalVolume,
+    /// Respiratory rate in cycles per minute
+    RespiratoryRate,
+    /// Leak flow in cL/min
+    LeakFlow,
+    /// Shape of the pressure and flow curves (see `CycleWaveform`), for classifiers; it has no value
+    Waveform,
+}
+
+impl Metric {
+    /// Value of the metric in a cycle, if it was measured
+    pub fn value(&self, metrics: &CycleMetrics) -> Option<f64> {
+        match self {
+            Self::PeakPressure => Some(f64::from(metrics.peak_pressure)),
+            Self::PlateauPressure => Some(f64::from(metrics.plateau_pressure)),
+            Self::Peep => Some(f64::from(metrics.peep)),
+            Self::TidalVolume => metrics.tidal_volume.map(f64::from),
+            Self::RespiratoryRate => metrics.respiratory_rate.map(f64::from),
+            Self::LeakFlow => metrics.leak_flow,
+            Self::Waveform => None,
+        }
+    }
+
+    /// Name of the metric, e.g. `peak_pressure` (used as a CSV column)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::PeakPressure => "peak_pressure",
+            Self::PlateauPressure => "plateau_pressure",
+            Self::Peep => "peep",
+            Self::TidalVolume => "tidal_volume",
+            Self::RespiratoryRate => "respiratory_rate",
+            Self::LeakFlow => "leak_flow",
+            Self::Waveform => "waveform",
+        }
+    }
+}
+
+/// An unusual value found by an anomaly detector
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct AnomalyEvent {
+    /// Name of the detector
+    pub detector: String,
+    /// Systick of the machine state snapshot that ended the cycle
+    pub systick: u64,
+    /// Number of the cycle
+    pub cycle: u32,
+    /// Metric that is unusual
+    pub metric: Metric,
+    /// Value of the metric
+    pub value: f64,
+    /// How unusual the value is, in a unit that depends on the detector (e.g. number of standard deviations)
+    pub score: f64,
+    /// Class found by a classifier (e.g. an asynchrony type)
+    pub label: Option<String>,
+}
+
+impl std::fmt::Display for AnomalyEvent {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.label {
+            Some(label) => write!(
+                f,
+                "[{}] cycle {}: {:?} classified as {} (score {:.2})",
+                self.detector, self.cycle, self.metric, label, self.score
+            ),
+            None => write!(
+                f,
+                "[{}] cycle {}: {:?} = {:.1} (score {:.2})",
+                self.detector, self.cycle, self.metric, self.value, self.score
+            ),
+        }
+    }
+}
+
+/// Something that finds unusual cycles, e.g. a statistical test or a machine learning model
+pub trait AnomalyDetector {
+    /// Name of the detector, used in the events it produces
+    fn name(&self) -> &str;
+
+    /// Inspect the metrics of a cycle that just ended, and return the anomalies found (possibly none)
+    fn inspect(&mut self, metrics: &CycleMetrics) -> Vec<AnomalyEvent>;
+
+    /// Inspect a cycle with its waveform; detectors that only need metrics can keep the default, which calls `inspect`
+    fn inspect_cycle(
+        &mut self,
+        metrics: &CycleMetrics,
+        _waveform: &CycleWaveform,
+    ) -> Vec<AnomalyEvent> {
+        self.inspect(metrics)
+    }
+
+    /// Forget what was learnt, e.g. because the MCU rebooted
+    fn reset(&mut self) {}
+}
+
+fn event(
+    detector: &str,
+    metrics: &CycleMetrics,
+    metric: Metric,
+    value: f64,
+    score: f64,
+) -> AnomalyEvent {
+    AnomalyEvent {
+        detector: detector.to_owned(),
+        systick: metrics.systick,
+        cycle: metrics.cycle,
+        metric,
+        value,
+        score,
+        label: None,
+    }
+}
+
+/// Reports values of a metric outside of fixed bounds
+///
+/// The score is the distance to the nearest bound.
+#[derive(Debug, Clone)]
+pub struct ThresholdDetector {
+    name: String,
+    metric: Metric,
+    min: Option<f64>,
+    max: Option<f64>,
+}
+
+impl ThresholdDetector {
+    /// Create a detector for a metric, without any bound
+    pub fn new(name: &str, metric: Metric) -> Self {
+        Self {
+            name: name.to_owned(),
+            metric,
+            min: None,
+            max: None,
+        }
+    }
+
+    /// Report values below this bound
+    pub fn min(mut self, min: f64) -> Self {
+        self.min = Some(min);
+        self
+    }
+
+    /// Report values above this bound
+    pub fn max(mut self, max: f64) -> Self {
+        self.max = Some(max);
+        self
+    }
+}
+
+impl AnomalyDetector for ThresholdDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn inspect(&mut self, metrics: &CycleMetrics) -> Vec<AnomalyEvent> {
+        let value = match self.metric.value(metrics) {
+            Some(value) => value,
+            None => return Vec::new(),
+        };
+        let score = match (self.min, self.max) {
+            (Some(min), _) if value < min => min - value,
+            (_, Some(max)) if value > max => value - max,
+            _ => return Vec::new(),
+        };
+        vec![event(&self.name, metrics, self.metric, value, score)]
+    }
+}
+
+/// Reports values of a metric that are far from its recent values (z-score)
+///
+/// Values are compared with the mean and standard deviation of the previous cycles; nothing is reported until the window is full.
+/// Reported values are still learnt, so that a lasting change (e.g. new settings) stops being reported after a while.
+#[derive(Debug, Clone)]
+pub struct ZScoreDetector {
+    name: String,
+    metric: Metric,
+    window: u32,
+    threshold: f64,
+    stats: RollingStats,
+}
+
+impl ZScoreDetector {
+    /// Create a detector for a metric, using `DEFAULT_ZSCORE_WINDOW` and `DEFAULT_ZSCORE_THRESHOLD`
+    pub fn new(name: &str, metric: Metric) -> Self {
+        Self {
+            name: name.to_owned(),
+            metric,
+            window: DEFAULT_ZSCORE_WINDOW,
+            threshold: DEFAULT_ZSCORE_THRESHOLD,
+            stats: RollingStats::new(Window::Samples(DEFAULT_ZSCORE_WINDOW as usize)),
+        }
+    }
+
+    /// Learn from this number of previous cycles
+    pub fn window(mut self, cycles: u32) -> Self {
+        self.window = cycles.max(2);
+        self.stats = RollingStats::new(Window::Samples(self.window as usize));
+        self
+    }
+
+    /// Report values that are more than this number of standard deviations from the mean
+    pub fn threshold(mut self, threshold: f64) -> Self {
+        self.threshold = threshold;
+        self
+    }
+}
+
+impl AnomalyDetector for ZScoreDetector {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn inspect(&mut self, metrics: &CycleMetrics) -> Vec<AnomalyEvent> {
+        let value = match self.metric.value(metrics) {
+            Some(value) => value,
+            None => return Vec::new(),
+        };
+        let mut events = Vec::new();
+        if self.stats.count() >= self.window as usize {
+            if let (Some(mean), Some(std_dev)) = (self.stats.mean(), self.stats.std_dev()) {
+                // A perfectly stable metric would make any change infinitely unusual
+                let score = (value - mean) / std_dev.max(1.0);
+                if score.abs() > self.threshold {
+                    events.push(event(&self.name, metrics, self.metric, value, score));
+                }
+            }
+        }
+        self.stats.push(metrics.systick, metrics.cycle, value);
+        events
+    }
+
+    fn reset(&mut self) {
+        self.stats.clear();
+    }
+}
+
+/// Derives the metrics and waveform of every cycle and runs anomaly detectors on them
+///
+/// Detectors are reset when the MCU boots.
+#[derive(Default)]
+pub struct AnomalyMonitor {
+    detectors: Vec<Box<dyn AnomalyDetector + Send>>,
+    volumes: VolumeIntegrator,
+    waveform: CycleWaveform,
+}
+
+impl AnomalyMonitor {
+    /// Create a monitor without any detector
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a detector
+    pub fn detector<D: AnomalyDetector + Send + 'static>(mut self, detector: D) -> Self {
+        self.detectors.push(Box::new(detector));
+        self
+    }
+}
+
+impl MessageAdapter for AnomalyMonitor {
+    type Output = AnomalyEvent;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<AnomalyEvent> {
+        let volumes = self.volumes.handle(message);
+        match message {
+            TelemetryMessage::BootMessage(_) => {
+                self.detectors
+                    .iter_mut()
+                    .for_each(|detector| detector.reset());
+                self.waveform.clear();
+                Vec::new()
+            }
+            TelemetryMessage::StoppedMessage(_) => {
+                self.waveform.clear();
+                Vec::new()
+            }
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                self.waveform.push(snapshot);
+                Vec::new()
+            }
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                let metrics = CycleMetrics::new(snapshot, volumes.last());
+                let waveform = std::mem::take(&mut self.waveform);
+                self.detectors
+                    .iter_mut()
+                    .flat_map(|detector| detector.inspect_cycle(&metrics, &waveform))
+                    .collect()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Sink that runs an anomaly monitor on messages and sends the events it produces through a channel
+///
+/// Events are also logged; they are sent until the receiver is dropped.
+pub struct AnomalySink {
+    monitor: AnomalyMonitor,
+    tx: Option<Sender<AnomalyEvent>>,
+}
+
+impl AnomalySink {
+    /// Create a sink sending events through a channel, or only logging them with `None`
+    pub fn new(monitor: AnomalyMonitor, tx: Option<Sender<AnomalyEvent>>) -> Self {
+        Self { monitor, tx }
+    }
+}
+
+impl TelemetrySink for AnomalySink {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(message) = &message.message {
+            for event in self.monitor.handle(message) {
+                log::warn!("[anomaly]\t{}", event);
+                if let Some(tx) = self.tx.as_ref() {
+                    if tx.send(event).is_err() {
+                        self.tx = None;
+                    }
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{BootMessage, Mode};
+
+    fn cycle(cycle: u32, peak: u16) -> TelemetryMessage {
+        TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+            cycle,
+            systick: u64::from(cycle) * 3_000_000,
+            previous_peak_pressure: peak,
+            previous_volume: Some(500),
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn threshold_detector() {
+        let mut detector = ThresholdDetector::new("volume", Metric::TidalVolume)
+            .min(300.0)
+            .max(450.0);
+        let metrics = CycleMetrics::new(
+            &MachineStateSnapshot {
+                previous_volume: Some(500),
+                ..Default::default()
+            },
+            None,
+        );
+        let events = detector.inspect(&metrics);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].score, 50.0);
+
+        assert!(ThresholdDetector::new("leak", Metric::LeakFlow)
+            .max(100.0)
+            .inspect(&metrics)
+            .is_empty());
+    }
+
+    #[test]
+    fn zscore_detector_through_monitor() {
+        let mut monitor = AnomalyMonitor::new()
+            .detector(ZScoreDetector::new("peak", Metric::PeakPressure).window(10));
+
+        let mut events = Vec::new();
+        for number in 0..20 {
+            let peak = if number == 15 {
+                400
+            } else {
+                200 + (number % 3) as u16 * 5
+            };
+            events.extend(monitor.handle(&cycle(number, peak)));
+        }
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].cycle, 15);
+        assert!(events[0].score > DEFAULT_ZSCORE_THRESHOLD);
+
+        // Nothing is reported while learning again after a reboot
+        monitor.handle(&TelemetryMessage::BootMessage(BootMessage {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick: 0,
+            mode: Mode::Production,
+            value128: 128,
+            control_capabilities: None,
+            session_nonce: None,
+        }));
+        assert!(monitor.handle(&cycle(0, 400)).is_empty());
+    }
+}
@@ -0,0 +1,259 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::HashMap;
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::control::{ControlMessage, ControlSetting};
+
+/// How to arbitrate between several controllers sending control messages to the same MCU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArbitrationPolicy {
+    /// Every message is accepted and the last one wins; who changed each setting is kept
+    LastWriterWins,
+    /// Only the controller holding the lock can change settings; the lock expires when it was not used for the given duration
+    Lockout {
+        /// Time after which an unused lock is released
+        lease: Duration,
+    },
+}
+
+/// Why a control message was rejected
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ArbitrationError {
+    /// Another controller holds the lock
+    #[error("settings are locked by {holder}")]
+    Locked {
+        /// Controller holding the lock
+        holder: String,
+    },
+    /// The channel to the MCU is closed
+    #[error("control channel is closed")]
+    Disconnected,
+}
+
+/// Who last changed a setting, and when
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Provenance {
+    /// Name of the controller
+    pub controller: String,
+    /// Value that was sent
+    pub value: u16,
+    /// When the message was accepted
+    pub at: Instant,
+}
+
+#[derive(Debug, Clone)]
+struct Lock {
+    holder: String,
+    expires_at: Instant,
+}
+
+/// Decides which control messages are sent to the MCU when several controllers (e.g. a local UI and a remote dashboard) share it
+///
+/// Heartbeats and time synchronization messages are always accepted, as they do not change any setting.
+#[derive(Debug, Clone)]
+pub struct Arbiter {
+    policy: ArbitrationPolicy,
+    lock: Option<Lock>,
+    last_writers: HashMap<ControlSetting, Provenance>,
+}
+
+impl Arbiter {
+    /// Create an arbiter without any lock held
+    pub fn new(policy: ArbitrationPolicy) -> Self {
+        Self {
+            policy,
+            lock: None,
+            last_writers: HashMap::new(),
+        }
+    }
+
+    /// Take the lock (see `ArbitrationPolicy::Lockout`), or renew it if the controller already holds it
+    ///
+    /// With `ArbitrationPolicy::LastWriterWins`, this always succeeds and has no effect.
+    pub fn acquire(&mut self, controller: &str) -> Result<(), ArbitrationError> {
+        self.acquire_at(controller, Instant::now())
+    }
+
+    fn acquire_at(&mut self, controller: &str, now: Instant) -> Result<(), ArbitrationError> {
+        let lease = match self.policy {
+            ArbitrationPolicy::LastWriterWins => return Ok(()),
+            ArbitrationPolicy::Lockout { lease } => lease,
+        };
+        match &self.lock {
+            Some(lock) if lock.holder != controller && lock.expires_at > now => {
+                Err(ArbitrationError::Locked {
+                    holder: lock.holder.clone(),
+                })
+            }
+            _ => {
+                self.lock = Some(Lock {
+                    holder: controller.to_owned(),
+                    expires_at: now + lease,
+                });
+                Ok(())
+            }
+        }
+    }
+
+    /// Give the lock back, if the controller holds it
+    pub fn release(&mut self, controller: &str) {
+        if self
+            .lock
+            .as_ref()
+            .is_some_and(|lock| lock.holder == controller)
+        {
+            self.lock = None;
+        }
+    }
+
+    /// Controller currently holding the lock, if any
+    pub fn holder(&self) -> Option<&str> {
+        self.holder_at(Instant::now())
+    }
+
+    fn holder_at(&self, now: Instant) -> Option<&str> {
+        self.lock
+            .as_ref()
+            .filter(|lock| lock.expires_at > now)
+            .map(|lock| lock.holder.as_str())
+    }
+
+    /// Decide whether a control message from a controller can be sent to the MCU
+    ///
+    /// With `ArbitrationPolicy::Lockout`, accepted messages take or renew the lock, so the first controller to send a setting gets it.
+    pub fn submit(
+        &mut self,
+        controller: &str,
+        message: &ControlMessage,
+    ) -> Result<(), ArbitrationError> {
+        self.submit_at(controller, message, Instant::now())
+    }
+
+    fn submit_at(
+        &mut self,
+        controller: &str,
+        message: &ControlMessage,
+        now: Instant,
+    ) -> Result<(), ArbitrationError> {
+        if matches!(
+            message.setting,
+            ControlSetting::Heartbeat
+                | ControlSetting::TimeSync
+                | ControlSetting::WallClockHigh
+                | ControlSetting::WallClockLow
+        ) {
+            return Ok(());
+        }
+        self.acquire_at(controller, now)?;
+        self.last_writers.insert(
+            message.setting,
+            Provenance {
+                controller: controller.to_owned(),
+                value: message.value,
+                at: now,
+            },
+        );
+        Ok(())
+    }
+
+    /// Who last changed a setting through this arbiter
+    pub fn last_writer(&self, setting: ControlSetting) -> Option<&Provenance> {
+        self.last_writers.get(&setting)
+    }
+}
+
+/// Sender of control messages for one controller, going through an arbiter shared with other controllers
+///
+/// Every controller gets its own `ArbitratedSender`, all sending to the channel given to a `gather_telemetry*` function.
+#[derive(Debug, Clone)]
+pub struct ArbitratedSender {
+    controller: String,
+    arbiter: Arc<Mutex<Arbiter>>,
+    tx: Sender<ControlMessage>,
+}
+
+impl ArbitratedSender {
+    /// Create a sender for a controller
+    pub fn new(controller: &str, arbiter: Arc<Mutex<Arbiter>>, tx: Sender<ControlMessage>) -> Self {
+        Self {
+            controller: controller.to_owned(),
+            arbiter,
+            tx,
+        }
+    }
+
+    /// Send a control message if the arbiter accepts it
+    pub fn send(&self, message: ControlMessage) -> Result<(), ArbitrationError> {
+        self.arbiter
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .submit(&self.controller, &message)?;
+        self.tx
+            .send(message)
+            .map_err(|_| ArbitrationError::Disconnected)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peep(value: u16) -> ControlMessage {
+        ControlMessage {
+            setting: ControlSetting::PEEP,
+            value,
+        }
+    }
+
+    #[test]
+    fn last_writer_wins_with_provenance() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let arbiter = Arc::new(Mutex::new(Arbiter::new(ArbitrationPolicy::LastWriterWins)));
+        let local = ArbitratedSender::new("local", Arc::clone(&arbiter), tx.clone());
+        let remote = ArbitratedSender::new("remote", Arc::clone(&arbiter), tx);
+
+        local.send(peep(50)).unwrap();
+        remote.send(peep(80)).unwrap();
+
+        assert_eq!(rx.try_iter().count(), 2);
+        let arbiter = arbiter.lock().unwrap();
+        let provenance = arbiter.last_writer(ControlSetting::PEEP).unwrap();
+        assert_eq!(provenance.controller, "remote");
+        assert_eq!(provenance.value, 80);
+    }
+
+    #[test]
+    fn lockout_until_released_or_expired() {
+        let start = Instant::now();
+        let lease = Duration::from_secs(60);
+        let mut arbiter = Arbiter::new(ArbitrationPolicy::Lockout { lease });
+
+        assert_eq!(arbiter.submit_at("local", &peep(50), start), Ok(()));
+        assert_eq!(
+            arbiter.submit_at("remote", &peep(80), start),
+            Err(ArbitrationError::Locked {
+                holder: "local".to_owned()
+            })
+        );
+        let heartbeat = ControlMessage {
+            setting: ControlSetting::Heartbeat,
+            value: 0,
+        };
+        assert_eq!(arbiter.submit_at("remote", &heartbeat, start), Ok(()));
+
+        assert_eq!(arbiter.holder_at(start + lease), None);
+        assert_eq!(
+            arbiter.submit_at("remote", &peep(80), start + lease),
+            Ok(())
+        );
+
+        arbiter.release("remote");
+        assert_eq!(arbiter.submit_at("local", &peep(60), start + lease), Ok(()));
+    }
+}
@@ -0,0 +1,205 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::pin::Pin;
+use std::sync::mpsc::{channel, Sender};
+use std::task::{Context, Poll};
+
+use futures_core::Stream;
+use futures_sink::Sink;
+use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+use crate::control::ControlMessage;
+#[cfg(any(feature = "serial", feature = "websocket"))]
+use crate::recording::RecordingWriter;
+use crate::{TelemetryChannelType, TimedMessage};
+
+/// Stream of the messages read by a transport
+///
+/// Transports still do blocking I/O, in a dedicated thread; only the delivery of messages is asynchronous, so the stream can be polled by any executor.
+/// The stream ends when the transport stops (e.g. at the end of a file).
+/// Dropping it makes the transport return cleanly once it fails to deliver its next message or error: a live source stays open until the device sends something (or the connection fails).
+#[derive(Debug)]
+pub struct TelemetryStream<T = TelemetryChannelType> {
+    rx: UnboundedReceiver<T>,
+}
+
+impl<T> TelemetryStream<T> {
+    /// Wait for the next message, or `None` once the transport stopped
+    pub async fn next(&mut self) -> Option<T> {
+        self.rx.recv().await
+    }
+}
+
+impl<T> Stream for TelemetryStream<T> {
+    type Item = T;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Option<T>> {
+        self.rx.poll_recv(cx)
+    }
+}
+
+/// Error returned when sending a control message to a transport that stopped
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[error("transport stopped, control message was not sent")]
+pub struct ControlSinkClosed;
+
+/// Sink of the control messages to send to the MCU through a transport
+///
+/// Messages are queued without bound, so the sink is always ready; they are sent in order, between the reads of the transport.
+#[derive(Debug, Clone)]
+pub struct ControlSink {
+    tx: Sender<ControlMessage>,
+}
+
+impl ControlSink {
+    /// Queue a control message without waiting
+    pub fn send(&self, message: ControlMessage) -> Result<(), ControlSinkClosed> {
+        self.tx.send(message).map_err(|_| ControlSinkClosed)
+    }
+}
+
+impl Sink<ControlMessage> for ControlSink {
+    type Error = ControlSinkClosed;
+
+    fn poll_ready(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn start_send(self: Pin<&mut Self>, message: ControlMessage) -> Result<(), Self::Error> {
+        self.send(message)
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_close(self: Pin<&mut Self>, _: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        Poll::Ready(Ok(()))
+    }
+}
+
+/// Run a blocking transport in its own thread and forward its messages to a stream
+///
+/// When the stream is dropped, the forwarding thread ends and drops the receiver of the transport, whose next send fails so that it returns.
+fn spawn_transport<T, F>(transport: F) -> TelemetryStream<T>
+where
+    T: Send + 'static,
+    F: FnOnce(Sender<T>) + Send + 'static,
+{
+    let (transport_tx, transport_rx) = channel();
+    let (tx, rx) = unbounded_channel();
+    std::thread::spawn(move || transport(transport_tx));
+    std::thread::spawn(move || {
+        for message in transport_rx {
+            if tx.send(message).is_err() {
+                break;
+            }
+        }
+    });
+    TelemetryStream { rx }
+}
+
+/// Same as `gather_telemetry_with_config`, but with a stream of messages and a sink of control messages
+///
+/// * `port_id` - Name of the serial port.
+/// * `recorder` - Optional writer to record the raw byte stream.
+/// * `config` - Settings of the serial port.
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub fn gather_telemetry_async<T: From<TimedMessage> + Send + 'static>(
+    port_id: &str,
+    recorder: Option<RecordingWriter>,
+    config: &crate::serial_config::SerialConfig,
+) -> (TelemetryStream<T>, ControlSink) {
+    let (control_tx, control_rx) = channel();
+    let port_id = port_id.to_owned();
+    let config = config.clone();
+    let stream = spawn_transport(move |tx| {
+        crate::gather_telemetry_with_config(&port_id, tx, recorder, Some(control_rx), &config, None)
+    });
+    (stream, ControlSink { tx: control_tx })
+}
+
+/// Same as `gather_telemetry_from_file`, but with a stream of messages
+///
+/// * `file` - Recording to read.
+/// * `enable_time_simulation` - Whether messages are delivered at the pace they were recorded.
+pub fn gather_telemetry_from_file_async<T: From<TimedMessage> + Send + 'static>(
+    file: File,
+    enable_time_simulation: bool,
+) -> TelemetryStream<T> {
+    spawn_transport(move |tx| {
+        crate::gather_telemetry_from_file(file, tx, enable_time_simulation, None)
+    })
+}
+
+/// Same as `gather_telemetry_from_ws_with_config`, but with a stream of messages and a sink of control messages
+///
+/// * `url` - URL of the WebSocket server.
+/// * `recorder` - Optional writer to record the raw byte stream.
+/// * `config` - How to connect to the server.
+#[cfg(feature = "websocket")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+pub fn gather_telemetry_from_ws_async<T: From<TimedMessage> + Send + 'static>(
+    url: &url::Url,
+    recorder: Option<RecordingWriter>,
+    config: &crate::websocket::WebSocketClientConfig,
+) -> (TelemetryStream<T>, ControlSink) {
+    let (control_tx, control_rx) = channel();
+    let url = url.clone();
+    let config = config.clone();
+    let stream = spawn_transport(move |tx| {
+        crate::gather_telemetry_from_ws_with_config(
+            &url,
+            tx,
+            recorder,
+            Some(control_rx),
+            &config,
+            None,
+        )
+    });
+    (stream, ControlSink { tx: control_tx })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn streams_a_recording() {
+        let (tx, rx) = channel();
+        crate::gather_telemetry_from_file(
+            File::open("records/v2/short.record").unwrap(),
+            tx,
+            false,
+            None,
+        );
+        let expected: Vec<TelemetryChannelType> = rx.into_iter().collect();
+
+        let runtime = tokio::runtime::Builder::new_current_thread()
+            .build()
+            .unwrap();
+        let streamed = runtime.block_on(async {
+            let mut stream: TelemetryStream = gather_telemetry_from_file_async(
+                File::open("records/v2/short.record").unwrap(),
+                false,
+            );
+            let mut streamed = Vec::new();
+            while let Some(message) = stream.next().await {
+                streamed.push(message);
+            }
+            streamed
+        });
+
+        assert!(!expected.is_empty());
+        assert_eq!(streamed.len(), expected.len());
+        assert_eq!(
+            streamed.last().unwrap().as_ref().ok(),
+            expected.last().unwrap().as_ref().ok()
+        );
+    }
+}
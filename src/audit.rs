@@ -0,0 +1,311 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::convert::TryFrom;
+use std::fmt::Write as _;
+use std::io::{BufRead, Write};
+use std::sync::Mutex;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+use crate::control::{ControlMessage, ControlSetting};
+
+/// Hash preceding the first entry of a log
+pub const GENESIS_HASH: [u8; 32] = [0; 32];
+
+/// Whether a control frame could be handed to the transport
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum AuditResult {
+    /// The frame was written
+    Sent,
+    /// The frame could not be written, for the given reason
+    Failed(String),
+}
+
+/// One control frame emitted by the library, chained to the previous entry by its hash
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct AuditEntry {
+    /// Position of the entry in the log, starting at 0
+    pub sequence: u64,
+    /// Host wall-clock time at which the frame was emitted, in microseconds since the Unix epoch
+    pub timestamp: u64,
+    /// Setting of the control message
+    pub setting: ControlSetting,
+    /// Value of the control message
+    pub value: u16,
+    /// Whether the frame was written
+    pub result: AuditResult,
+    /// Hash of the previous entry (`GENESIS_HASH` for the first one)
+    pub previous_hash: [u8; 32],
+    /// SHA-256 hash of the previous hash and of the content of this entry
+    pub hash: [u8; 32],
+}
+
+impl AuditEntry {
+    fn compute_hash(&self) -> [u8; 32] {
+        let mut hasher = Sha256::new();
+        hasher.update(self.previous_hash);
+        hasher.update(self.sequence.to_be_bytes());
+        hasher.update(self.timestamp.to_be_bytes());
+        hasher.update([self.setting as u8]);
+        hasher.update(self.value.to_be_bytes());
+        match &self.result {
+            AuditResult::Sent => hasher.update([0]),
+            AuditResult::Failed(reason) => {
+                hasher.update([1]);
+                hasher.update(reason.as_bytes());
+            }
+        }
+        hasher.finalize().into()
+    }
+
+    fn to_line(&self) -> String {
+        let result = match &self.result {
+            AuditResult::Sent => "sent".to_owned(),
+            AuditResult::Failed(reason) => format!("failed:{}", reason.replace(['\t', '\n'], " ")),
+        };
+        format!(
+            "{}\t{}\t{}\t{}\t{}\t{}\t{}\n",
+            self.sequence,
+            self.timestamp,
+            self.setting as u8,
+            self.value,
+            result,
+            to_hex(&self.previous_hash),
+            to_hex(&self.hash)
+        )
+    }
+
+    fn from_line(line: &str) -> Option<Self> {
+        let mut fields = line.trim_end_matches('\n').split('\t');
+        let mut next = || fields.next();
+        let entry = Self {
+            sequence: next()?.parse().ok()?,
+            timestamp: next()?.parse().ok()?,
+            setting: ControlSetting::try_from(next()?.parse::<u8>().ok()?).ok()?,
+            value: next()?.parse().ok()?,
+            result: match next()? {
+                "sent" => AuditResult::Sent,
+                result => AuditResult::Failed(result.strip_prefix("failed:")?.to_owned()),
+            },
+            previous_hash: from_hex(next()?)?,
+            hash: from_hex(next()?)?,
+        };
+        next().is_none().then_some(entry)
+    }
+}
+
+/// Why an audit log could not be read
+#[derive(Debug, thiserror::Error)]
+pub enum AuditError {
+    /// The log could not be read
+    #[error("failed reading audit log: {0}")]
+    Io(#[from] std::io::Error),
+    /// A line is not a valid entry
+    #[error("line {line} of the audit log is not a valid entry")]
+    InvalidEntry {
+        /// Line number, starting at 1
+        line: usize,
+    },
+    /// An entry was modified, removed or inserted
+    #[error("audit log was tampered with at entry {sequence}")]
+    BrokenChain {
+        /// Sequence number of the first entry that does not match the chain
+        sequence: u64,
+    },
+}
+
+/// Append-only, hash-chained log of control frames
+///
+/// Each entry is written as a line of tab-separated fields and includes the hash of the previous one, so any modification of the file is detected by `read_audit_log()`.
+#[derive(Debug)]
+pub struct AuditLog<W: Write> {
+    writer: W,
+    next_sequence: u64,
+    last_hash: [u8; 32],
+}
+
+impl<W: Write> AuditLog<W> {
+    /// Start a new log
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            next_sequence: 0,
+            last_hash: GENESIS_HASH,
+        }
+    }
+
+    /// Continue a log after its last entry (e.g. read with `read_audit_log()` before opening the file in append mode)
+    pub fn resume(writer: W, last_entry: Option<&AuditEntry>) -> Self {
+        Self {
+            writer,
+            next_sequence: last_entry.map_or(0, |entry| entry.sequence + 1),
+            last_hash: last_entry.map_or(GENESIS_HASH, |entry| entry.hash),
+        }
+    }
+
+    /// Append an entry for a control frame that was just emitted, and flush it
+    pub fn record(
+        &mut self,
+        message: &ControlMessage,
+        result: AuditResult,
+    ) -> std::io::Result<AuditEntry> {
+        let timestamp = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map(|duration| duration.as_micros() as u64)
+            .unwrap_or_default();
+        let mut entry = AuditEntry {
+            sequence: self.next_sequence,
+            timestamp,
+            setting: message.setting,
+            value: message.value,
+            result,
+            previous_hash: self.last_hash,
+            hash: GENESIS_HASH,
+        };
+        entry.hash = entry.compute_hash();
+
+        self.writer.write_all(entry.to_line().as_bytes())?;
+        self.writer.flush()?;
+        self.next_sequence += 1;
+        self.last_hash = entry.hash;
+        Ok(entry)
+    }
+}
+
+/// Read every entry of an audit log, checking that the chain of hashes is intact
+pub fn read_audit_log<R: BufRead>(reader: R) -> Result<Vec<AuditEntry>, AuditError> {
+    let mut entries: Vec<AuditEntry> = Vec::new();
+    for (index, line) in reader.lines().enumerate() {
+        let entry =
+            AuditEntry::from_line(&line?).ok_or(AuditError::InvalidEntry { line: index + 1 })?;
+        let (expected_sequence, expected_previous) = entries
+            .last()
+            .map_or((0, GENESIS_HASH), |last| (last.sequence + 1, last.hash));
+        if entry.sequence != expected_sequence
+            || entry.previous_hash != expected_previous
+            || entry.hash != entry.compute_hash()
+        {
+            return Err(AuditError::BrokenChain {
+                sequence: entry.sequence,
+            });
+        }
+        entries.push(entry);
+    }
+    Ok(entries)
+}
+
+static AUDIT_LOG: Mutex<Option<AuditLog<Box<dyn Write + Send>>>> = Mutex::new(None);
+
+/// Record every control frame emitted by the `gather_telemetry*` functions in a log, or stop recording them with `None`
+pub fn set_audit_log(log: Option<AuditLog<Box<dyn Write + Send>>>) {
+    *AUDIT_LOG
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner()) = log;
+}
+
+/// Record a control frame in the installed audit log, if any
+pub(crate) fn record(message: &ControlMessage, result: AuditResult) {
+    let mut log = AUDIT_LOG
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner());
+    if let Some(log) = log.as_mut() {
+        if let Err(e) = log.record(message, result) {
+            log::error!(
+                "failed writing control message '{}' to audit log: {:?}",
+                message,
+                e
+            );
+        }
+    }
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut hex, byte| {
+        let _ = write!(hex, "{:02x}", byte);
+        hex
+    })
+}
+
+fn from_hex(hex: &str) -> Option<[u8; 32]> {
+    let mut bytes = [0; 32];
+    if hex.len() != 64 {
+        return None;
+    }
+    for (i, byte) in bytes.iter_mut().enumerate() {
+        *byte = u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn peep(value: u16) -> ControlMessage {
+        ControlMessage {
+            setting: ControlSetting::PEEP,
+            value,
+        }
+    }
+
+    fn sample_log() -> Vec<u8> {
+        let mut log = AuditLog::new(Vec::new());
+        log.record(&peep(50), AuditResult::Sent).unwrap();
+        log.record(&peep(80), AuditResult::Failed("timed out".to_owned()))
+            .unwrap();
+        log.writer
+    }
+
+    #[test]
+    fn reads_back_intact_logs() {
+        let bytes = sample_log();
+        let entries = read_audit_log(bytes.as_slice()).unwrap();
+
+        assert_eq!(entries.len(), 2);
+        assert_eq!(entries[0].previous_hash, GENESIS_HASH);
+        assert_eq!(entries[1].previous_hash, entries[0].hash);
+        assert_eq!(entries[1].value, 80);
+        assert_eq!(
+            entries[1].result,
+            AuditResult::Failed("timed out".to_owned())
+        );
+
+        let mut resumed = AuditLog::resume(Vec::new(), entries.last());
+        let entry = resumed.record(&peep(60), AuditResult::Sent).unwrap();
+        assert_eq!(entry.sequence, 2);
+        assert_eq!(entry.previous_hash, entries[1].hash);
+    }
+
+    #[test]
+    fn detects_tampering() {
+        let log = String::from_utf8(sample_log()).unwrap();
+
+        let modified = log.replacen("\t50\t", "\t40\t", 1);
+        assert!(matches!(
+            read_audit_log(modified.as_bytes()),
+            Err(AuditError::BrokenChain { sequence: 0 })
+        ));
+
+        let removed: String = log
+            .lines()
+            .skip(1)
+            .map(|line| format!("{}\n", line))
+            .collect();
+        assert!(matches!(
+            read_audit_log(removed.as_bytes()),
+            Err(AuditError::BrokenChain { sequence: 1 })
+        ));
+    }
+}
@@ -0,0 +1,405 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::path::Path;
+
+use crate::filter::MessageType;
+use crate::framing::FrameKind;
+use crate::reader::TelemetryFileReader;
+use crate::structures::TelemetryMessage;
+
+/// Baud rate of the serial link between the MCU and the control UI
+pub const DEFAULT_BAUD_RATE: u32 = 115_200;
+
+/// Bits sent on the UART for each byte (8N1: start bit, 8 data bits, stop bit)
+const BITS_PER_BYTE: u32 = 10;
+
+/// Bytes before the firmware version in a payload: message type (2), protocol version (1), firmware version length (1)
+const PAYLOAD_PREFIX_LENGTH: usize = 4;
+
+/// Bytes after the firmware version in the header of a payload: device ID (12), separator, systick (8), separator
+const PAYLOAD_SUFFIX_LENGTH: usize = 22;
+
+/// Bytes sent for the messages of one type, broken down by purpose
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct TypeBandwidth {
+    /// Type of the messages
+    pub message_type: MessageType,
+    /// Number of frames
+    pub frames: u64,
+    /// Bytes of frame headers, CRCs and frame footers
+    pub framing_bytes: u64,
+    /// Bytes of the header of messages: message type, protocol version, firmware version, device ID, systick and their separators
+    pub header_bytes: u64,
+    /// Bytes of the fields of messages, including their separators
+    pub field_bytes: u64,
+}
+
+impl TypeBandwidth {
+    /// Number of bytes sent for these messages
+    pub fn bytes(&self) -> u64 {
+        self.framing_bytes + self.header_bytes + self.field_bytes
+    }
+
+    /// Mean size of a frame, in bytes
+    pub fn mean_frame_size(&self) -> Option<f64> {
+        (self.frames > 0).then(|| self.bytes() as f64 / self.frames as f64)
+    }
+}
+
+/// Serial traffic of a recording, by message type, projected against the capacity of the UART
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct BandwidthReport {
+    /// Baud rate the traffic is projected against
+    pub baud_rate: u32,
+    /// Time covered by the messages according to their systicks, in µs (time between boots is not counted)
+    pub duration: u64,
+    /// Traffic of each message type, in the order types were first seen
+    pub types: Vec<TypeBandwidth>,
+}
+
+impl BandwidthReport {
+    /// Number of bytes the UART can carry per second
+    pub fn uart_capacity(&self) -> f64 {
+        f64::from(self.baud_rate) / f64::from(BITS_PER_BYTE)
+    }
+
+    /// Number of bytes sent
+    pub fn bytes(&self) -> u64 {
+        self.types.iter().map(TypeBandwidth::bytes).sum()
+    }
+
+    /// Number of bytes of framing and message headers, i.e. everything but the fields of messages
+    pub fn overhead_bytes(&self) -> u64 {
+        self.types
+            .iter()
+            .map(|bandwidth| bandwidth.framing_bytes + bandwidth.header_bytes)
+            .sum()
+    }
+
+    /// Number of bytes sent per second
+    pub fn bytes_per_second(&self) -> Option<f64> {
+        self.per_second(self.bytes())
+    }
+
+    /// Share of the UART capacity used by the whole traffic (above 1 means the link could not keep up)
+    pub fn load(&self) -> Option<f64> {
+        Some(self.bytes_per_second()? / self.uart_capacity())
+    }
+
+    /// Number of messages of a type sent per second
+    pub fn frequency(&self, message_type: MessageType) -> Option<f64> {
+        self.per_second(self.get(message_type)?.frames)
+    }
+
+    /// Highest number of messages of a type that could be sent per second with their current size, if the traffic of other types does not change
+    ///
+    /// This is what a higher data snapshot frequency (or a pruned layout, with a smaller frame size) can be weighed against.
+    pub fn max_frequency(&self, message_type: MessageType) -> Option<f64> {
+        let bandwidth = self.get(message_type)?;
+        let other_bytes = self.bytes() - bandwidth.bytes();
+        let available = self.uart_capacity() - self.per_second(other_bytes)?;
+        Some(available.max(0.0) / bandwidth.mean_frame_size()?)
+    }
+
+    fn get(&self, message_type: MessageType) -> Option<&TypeBandwidth> {
+        self.types
+            .iter()
+            .find(|bandwidth| bandwidth.message_type == message_type)
+    }
+
+    fn per_second(&self, count: u64) -> Option<f64> {
+        (self.duration > 0).then(|| count as f64 * 1_000_000.0 / self.duration as f64)
+    }
+}
+
+impl std::fmt::Display for BandwidthReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let bytes = self.bytes();
+        write!(
+            f,
+            "{} bytes in {:.1}s",
+            bytes,
+            self.duration as f64 / 1_000_000.0
+        )?;
+        match (self.bytes_per_second(), self.load()) {
+            (Some(rate), Some(load)) => writeln!(
+                f,
+                ": {:.1} B/s, {:.1}% of the UART capacity ({:.0} B/s at {} baud)",
+                rate,
+                load * 100.0,
+                self.uart_capacity(),
+                self.baud_rate
+            )?,
+            _ => writeln!(f)?,
+        }
+        if bytes > 0 {
+            writeln!(
+                f,
+                "overhead (framing and message headers): {} bytes, {:.1}% of the traffic",
+                self.overhead_bytes(),
+                self.overhead_bytes() as f64 * 100.0 / bytes as f64
+            )?;
+        }
+        for bandwidth in &self.types {
+            write!(
+                f,
+                "  {}: {} frame(s) of {:.1} bytes ({:.1} framing, {:.1} header, {:.1} fields)",
+                bandwidth.message_type.name(),
+                bandwidth.frames,
+                bandwidth.mean_frame_size().unwrap_or_default(),
+                bandwidth.framing_bytes as f64 / bandwidth.frames as f64,
+                bandwidth.header_bytes as f64 / bandwidth.frames as f64,
+                bandwidth.field_bytes as f64 / bandwidth.frames as f64
+            )?;
+            match (
+                self.frequency(bandwidth.message_type),
+                self.per_second(bandwidth.bytes()),
+                self.max_frequency(bandwidth.message_type),
+            ) {
+                (Some(frequency), Some(rate), Some(max_frequency)) => writeln!(
+                    f,
+                    ", {:.1}/s, {:.1} B/s ({:.1}% of the UART capacity), up to {:.1}/s",
+                    frequency,
+                    rate,
+                    rate * 100.0 / self.uart_capacity(),
+                    max_frequency
+                )?,
+                _ => writeln!(f)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Builder of a `BandwidthReport` from decoded frames
+#[derive(Debug, Clone)]
+pub struct BandwidthAnalyzer {
+    baud_rate: u32,
+    types: Vec<TypeBandwidth>,
+    duration: u64,
+    last_systick: Option<u64>,
+}
+
+impl Default for BandwidthAnalyzer {
+    fn default() -> Self {
+        Self::new(DEFAULT_BAUD_RATE)
+    }
+}
+
+impl BandwidthAnalyzer {
+    /// Create an analyzer projecting traffic against a UART running at `baud_rate`
+    pub fn new(baud_rate: u32) -> Self {
+        Self {
+            baud_rate,
+            types: Vec::new(),
+            duration: 0,
+            last_systick: None,
+        }
+    }
+
+    /// Add a message along with the whole frame it was decoded from
+    pub fn push(&mut self, message: &TelemetryMessage, frame: &[u8]) {
+        let framing = FrameKind::of(frame)
+            .map_or(0, |kind| kind.overhead())
+            .min(frame.len());
+        let payload = frame.len() - framing;
+        let header = frame
+            .get(FrameKind::Telemetry.header().len() + PAYLOAD_PREFIX_LENGTH - 1)
+            .map_or(payload, |version_length| {
+                PAYLOAD_PREFIX_LENGTH + usize::from(*version_length) + PAYLOAD_SUFFIX_LENGTH
+            })
+            .min(payload);
+
+        let message_type = MessageType::of(message);
+        let index = match self
+            .types
+            .iter()
+            .position(|bandwidth| bandwidth.message_type == message_type)
+        {
+            Some(index) => index,
+            None => {
+                self.types.push(TypeBandwidth {
+                    message_type,
+                    frames: 0,
+                    framing_bytes: 0,
+                    header_bytes: 0,
+                    field_bytes: 0,
+                });
+                self.types.len() - 1
+            }
+        };
+        let bandwidth = &mut self.types[index];
+        bandwidth.frames += 1;
+        bandwidth.framing_bytes += framing as u64;
+        bandwidth.header_bytes += header as u64;
+        bandwidth.field_bytes += (payload - header) as u64;
+
+        // Systicks restart at each boot: only count time between consecutive messages of a same run
+        let systick = message.systick();
+        if let Some(last_systick) = self.last_systick {
+            self.duration += systick.saturating_sub(last_systick);
+        }
+        self.last_systick = Some(systick);
+    }
+
+    /// Get the report of the frames added so far
+    pub fn finish(self) -> BandwidthReport {
+        BandwidthReport {
+            baud_rate: self.baud_rate,
+            duration: self.duration,
+            types: self.types,
+        }
+    }
+}
+
+/// Read a whole recording and report its serial traffic, projected against a UART running at `baud_rate`
+pub fn analyze_recording<P: AsRef<Path>>(
+    path: P,
+    baud_rate: u32,
+) -> std::io::Result<BandwidthReport> {
+    let mut analyzer = BandwidthAnalyzer::new(baud_rate);
+    let mut reader = TelemetryFileReader::open(path)?;
+    while let Some(frame) = reader.next_frame() {
+        let (message, frame) = frame?;
+        analyzer.push(&message, frame);
+    }
+    Ok(analyzer.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+
+    #[test]
+    fn breaks_down_traffic_by_message_type() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(1_000)
+                .collect();
+        let mut analyzer = BandwidthAnalyzer::default();
+        for message in &messages {
+            analyzer.push(message, &message.to_bytes());
+        }
+        let report = analyzer.finish();
+
+        let bytes: usize = messages
+            .iter()
+            .map(|message| message.to_bytes().len())
+            .sum();
+        assert_eq!(report.bytes(), bytes as u64);
+        assert_eq!(
+            report
+                .types
+                .iter()
+                .map(|bandwidth| bandwidth.frames)
+                .sum::<u64>(),
+            1_000
+        );
+
+        let snapshots = report
+            .types
+            .iter()
+            .find(|bandwidth| bandwidth.message_type == MessageType::DataSnapshot)
+            .unwrap();
+        let snapshot = messages
+            .iter()
+            .find_map(|message| match message {
+                TelemetryMessage::DataSnapshot(snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .unwrap();
+        assert_eq!(snapshots.framing_bytes, 8 * snapshots.frames);
+        assert_eq!(
+            snapshots.header_bytes,
+            (26 + snapshot.version.len() as u64) * snapshots.frames
+        );
+        // centile, pressure, phase, 2 valve positions, blower RPM, battery level, 2 flows and their separators
+        assert_eq!(snapshots.field_bytes, 22 * snapshots.frames);
+
+        let frequency = report.frequency(MessageType::DataSnapshot).unwrap();
+        let max_frequency = report.max_frequency(MessageType::DataSnapshot).unwrap();
+        assert!(frequency > 0.0);
+        assert!(max_frequency > frequency);
+        assert!(report.load().unwrap() < 1.0);
+    }
+
+    #[test]
+    fn reports_nothing_without_duration() {
+        let report = BandwidthAnalyzer::default().finish();
+        assert_eq!(report.bytes(), 0);
+        assert_eq!(report.bytes_per_second(), None);
+        assert_eq!(report.load(), None);
+        assert_eq!(report.frequency(MessageType::DataSnapshot), None);
+        assert_eq!(report.max_frequency(MessageType::DataSnapshot), None);
+        assert_eq!(report.to_string(), "0 bytes in 0.0s\n");
+
+        // A single message covers no time: its size is known, but not its rate
+        let message = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .next()
+            .unwrap();
+        let mut analyzer = BandwidthAnalyzer::default();
+        analyzer.push(&message, &message.to_bytes());
+        let report = analyzer.finish();
+        assert_eq!(report.duration, 0);
+        assert!(report.types[0].mean_frame_size().is_some());
+        assert_eq!(report.frequency(MessageType::Boot), None);
+        assert_eq!(report.max_frequency(MessageType::Boot), None);
+        assert_eq!(report.to_string().lines().count(), 3);
+    }
+
+    #[test]
+    fn counts_truncated_frames_without_overflowing() {
+        let message = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .next()
+            .unwrap();
+        let mut analyzer = BandwidthAnalyzer::default();
+        // Shorter than the framing
+        analyzer.push(&message, b"\x03\x0C\x00");
+        // Shorter than the header announced by the firmware version length
+        analyzer.push(&message, b"\x03\x0CB:\x02\xFF\x00\x00\x00\x00\x00\x00");
+        // Not even a frame header
+        analyzer.push(&message, b"B");
+        let report = analyzer.finish();
+
+        let bandwidth = &report.types[0];
+        assert_eq!(bandwidth.frames, 3);
+        assert_eq!(bandwidth.bytes(), 3 + 12 + 1);
+        assert_eq!(bandwidth.framing_bytes, 3 + 8);
+        assert_eq!(bandwidth.header_bytes, 4 + 1);
+        assert_eq!(bandwidth.field_bytes, 0);
+    }
+
+    #[test]
+    fn saturates_with_reboots_and_overloaded_links() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(100)
+                .collect();
+        // A UART too slow for the traffic, and a reboot in the middle of it
+        let mut analyzer = BandwidthAnalyzer::new(1_200);
+        for message in messages.iter().chain(messages.iter()) {
+            analyzer.push(message, &message.to_bytes());
+        }
+        let report = analyzer.finish();
+
+        // Systicks going back at the reboot are not counted
+        let last_systick = messages.last().unwrap().systick();
+        let first_systick = messages.first().unwrap().systick();
+        assert_eq!(report.duration, 2 * (last_systick - first_systick));
+        assert!(report.load().unwrap() > 1.0);
+        assert_eq!(report.max_frequency(MessageType::Boot), Some(0.0));
+    }
+}
@@ -0,0 +1,394 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::{BufWriter, Write};
+use std::path::Path;
+
+use crate::annotation::Annotation;
+use crate::structures::TelemetryMessage;
+
+/// Sampling frequency of exported signals, in Hz (the MCU sends a data snapshot every 10 ms)
+pub const SAMPLING_FREQUENCY: usize = 100;
+
+const EDF_HEADER_SIZE: usize = 256;
+const EDF_ANNOTATIONS_LABEL: &str = "EDF Annotations";
+const WFDB_NOTE: u16 = 22;
+const WFDB_SKIP: u16 = 59;
+const WFDB_AUX: u16 = 63;
+const WFDB_MAX_INTERVAL: usize = 1023;
+
+/// An event to export along with signals (alarm or operator annotation)
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BiosignalEvent {
+    /// Index of the sample at which the event happened
+    pub sample: usize,
+    /// Description of the event
+    pub text: String,
+}
+
+/// Pressure and flow signals extracted from data snapshots, ready to be exported to biosignal formats
+///
+/// Data snapshots are considered evenly spaced at `SAMPLING_FREQUENCY`; flow is always 0 with protocol v1.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Biosignals {
+    /// Systick of the first sample
+    pub start_systick: u64,
+    /// Pressure in mmH2O
+    pub pressure: Vec<i16>,
+    /// Net flow (inspiratory minus expiratory) in cL/min
+    pub flow: Vec<i16>,
+    /// Alarms and annotations, sorted by sample
+    pub events: Vec<BiosignalEvent>,
+    /// Free-text lines describing the export (e.g. its manifest), written as EDF+ annotations at the first sample and as WFDB header comments
+    pub comments: Vec<String>,
+}
+
+impl Biosignals {
+    /// Extract signals and events from messages
+    ///
+    /// * `messages` - Messages of the session, sorted by systick.
+    /// * `annotations` - Operator annotations, sorted by systick.
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(
+        messages: I,
+        annotations: &[Annotation],
+    ) -> Self {
+        let mut signals = Self::default();
+        let mut annotations = annotations.iter().peekable();
+
+        for message in messages {
+            match message {
+                TelemetryMessage::DataSnapshot(snapshot) => {
+                    if signals.pressure.is_empty() {
+                        signals.start_systick = snapshot.systick;
+                    }
+                    while let Some(annotation) =
+                        annotations.next_if(|annotation| annotation.systick <= snapshot.systick)
+                    {
+                        signals.push_event(annotation.text.clone());
+                    }
+
+                    let flow = i32::from(snapshot.inspiratory_flow.unwrap_or(0))
+                        - i32::from(snapshot.expiratory_flow.unwrap_or(0));
+                    signals.pressure.push(snapshot.pressure);
+                    signals
+                        .flow
+                        .push(flow.clamp(i16::MIN.into(), i16::MAX.into()) as i16);
+                }
+                TelemetryMessage::AlarmTrap(trap) => signals.push_event(format!(
+                    "Alarm {} {}",
+                    trap.alarm_code,
+                    if trap.triggered {
+                        "triggered"
+                    } else {
+                        "stopped"
+                    }
+                )),
+                _ => (),
+            }
+        }
+        for annotation in annotations {
+            signals.push_event(annotation.text.clone());
+        }
+
+        signals
+    }
+
+    fn push_event(&mut self, text: String) {
+        self.events.push(BiosignalEvent {
+            sample: self.pressure.len(),
+            text,
+        });
+    }
+
+    /// Number of samples of each signal
+    pub fn len(&self) -> usize {
+        self.pressure.len()
+    }
+
+    /// Whether there is no sample
+    pub fn is_empty(&self) -> bool {
+        self.pressure.is_empty()
+    }
+
+    /// Write signals and events as an EDF+ file, with one data record per second
+    ///
+    /// Start date and time are unknown, so they are set to `01.01.85 00.00.00`; event onsets are relative to the first sample.
+    pub fn write_edf<W: Write>(&self, writer: W) -> std::io::Result<()> {
+        let mut writer = BufWriter::new(writer);
+        let records = self.len().div_ceil(SAMPLING_FREQUENCY).max(1);
+
+        // Annotations of a record are stored in its "EDF Annotations" signal, which must be large enough for the busiest record
+        let tals: Vec<Vec<u8>> = (0..records)
+            .map(|record| {
+                let mut tal = format!("+{}\x14\x14\x00", record).into_bytes();
+                if record == 0 {
+                    for comment in &self.comments {
+                        let text = comment.replace(['\x14', '\x15', '\x00'], " ");
+                        tal.extend_from_slice(format!("+0\x14{}\x14\x00", text).as_bytes());
+                    }
+                }
+                for event in self
+                    .events
+                    .iter()
+                    .filter(|event| event.sample / SAMPLING_FREQUENCY == record)
+                {
+                    let onset = event.sample as f64 / SAMPLING_FREQUENCY as f64;
+                    let text = event.text.replace(['\x14', '\x15', '\x00'], " ");
+                    tal.extend_from_slice(format!("+{}\x14{}\x14\x00", onset, text).as_bytes());
+                }
+                tal
+            })
+            .collect();
+        let annotation_samples = tals
+            .iter()
+            .map(|tal| tal.len().div_ceil(2))
+            .max()
+            .unwrap_or(1);
+
+        let signals = [
+            ("Pressure", "cmH2O", "-3276.8", "3276.7", SAMPLING_FREQUENCY),
+            ("Flow", "L/min", "-327.68", "327.67", SAMPLING_FREQUENCY),
+            (EDF_ANNOTATIONS_LABEL, "", "-1", "1", annotation_samples),
+        ];
+
+        let mut header = String::with_capacity(EDF_HEADER_SIZE * (signals.len() + 1));
+        header.push_str(&edf_field("0", 8));
+        header.push_str(&edf_field("X X X X", 80));
+        header.push_str(&edf_field("Startdate X X X MakAir", 80));
+        header.push_str(&edf_field("01.01.85", 8));
+        header.push_str(&edf_field("00.00.00", 8));
+        header.push_str(&edf_field(
+            &(EDF_HEADER_SIZE * (signals.len() + 1)).to_string(),
+            8,
+        ));
+        header.push_str(&edf_field("EDF+C", 44));
+        header.push_str(&edf_field(&records.to_string(), 8));
+        header.push_str(&edf_field("1", 8));
+        header.push_str(&edf_field(&signals.len().to_string(), 4));
+        for (label, ..) in &signals {
+            header.push_str(&edf_field(label, 16));
+        }
+        for _ in &signals {
+            header.push_str(&edf_field("", 80));
+        }
+        for (_, dimension, ..) in &signals {
+            header.push_str(&edf_field(dimension, 8));
+        }
+        for (_, _, physical_min, ..) in &signals {
+            header.push_str(&edf_field(physical_min, 8));
+        }
+        for (_, _, _, physical_max, _) in &signals {
+            header.push_str(&edf_field(physical_max, 8));
+        }
+        for _ in &signals {
+            header.push_str(&edf_field("-32768", 8));
+        }
+        for _ in &signals {
+            header.push_str(&edf_field("32767", 8));
+        }
+        for _ in &signals {
+            header.push_str(&edf_field("", 80));
+        }
+        for (.., samples) in &signals {
+            header.push_str(&edf_field(&samples.to_string(), 8));
+        }
+        for _ in &signals {
+            header.push_str(&edf_field("", 32));
+        }
+        writer.write_all(header.as_bytes())?;
+
+        for (record, tal) in tals.iter().enumerate() {
+            for signal in [&self.pressure, &self.flow] {
+                for sample in 0..SAMPLING_FREQUENCY {
+                    // The last record is padded with zeros
+                    let value = signal
+                        .get(record * SAMPLING_FREQUENCY + sample)
+                        .copied()
+                        .unwrap_or(0);
+                    writer.write_all(&value.to_le_bytes())?;
+                }
+            }
+            writer.write_all(tal)?;
+            writer.write_all(&vec![0; annotation_samples * 2 - tal.len()])?;
+        }
+
+        writer.flush()
+    }
+
+    /// Write signals and events as a WFDB record (`<name>.hea`, `<name>.dat` and `<name>.atr` in the given directory)
+    ///
+    /// Signals use format 16 and events are `NOTE` annotations whose text is in the auxiliary information.
+    pub fn write_wfdb<P: AsRef<Path>>(&self, directory: P, name: &str) -> std::io::Result<()> {
+        let directory = directory.as_ref();
+        let data_file_name = format!("{}.dat", name);
+
+        let mut data = BufWriter::new(File::create(directory.join(&data_file_name))?);
+        for (pressure, flow) in self.pressure.iter().zip(&self.flow) {
+            data.write_all(&pressure.to_le_bytes())?;
+            data.write_all(&flow.to_le_bytes())?;
+        }
+        data.flush()?;
+
+        let checksum = |signal: &[i16]| {
+            signal
+                .iter()
+                .fold(0_i16, |sum, value| sum.wrapping_add(*value))
+        };
+        let mut header = BufWriter::new(File::create(directory.join(format!("{}.hea", name)))?);
+        writeln!(header, "{} 2 {} {}", name, SAMPLING_FREQUENCY, self.len())?;
+        writeln!(
+            header,
+            "{} 16 10/cmH2O 16 0 {} {} 0 Pressure",
+            data_file_name,
+            self.pressure.first().copied().unwrap_or(0),
+            checksum(&self.pressure)
+        )?;
+        writeln!(
+            header,
+            "{} 16 100/L/min 16 0 {} {} 0 Flow",
+            data_file_name,
+            self.flow.first().copied().unwrap_or(0),
+            checksum(&self.flow)
+        )?;
+        for comment in &self.comments {
+            writeln!(header, "# {}", comment)?;
+        }
+        header.flush()?;
+
+        let mut annotations =
+            BufWriter::new(File::create(directory.join(format!("{}.atr", name)))?);
+        annotations.write_all(&self.wfdb_annotations())?;
+        annotations.flush()
+    }
+
+    fn wfdb_annotations(&self) -> Vec<u8> {
+        let word = |code: u16, value: usize| ((code << 10) | value as u16).to_le_bytes();
+
+        let mut output = Vec::new();
+        let mut previous_sample = 0;
+        for event in &self.events {
+            let interval = event.sample - previous_sample;
+            previous_sample = event.sample;
+            if interval > WFDB_MAX_INTERVAL {
+                // Long intervals are written as a PDP-11 long integer (high word first)
+                output.extend_from_slice(&word(WFDB_SKIP, 0));
+                output.extend_from_slice(&((interval >> 16) as u16).to_le_bytes());
+                output.extend_from_slice(&(interval as u16).to_le_bytes());
+                output.extend_from_slice(&word(WFDB_NOTE, 0));
+            } else {
+                output.extend_from_slice(&word(WFDB_NOTE, interval));
+            }
+
+            let mut text_len = event.text.len().min(255);
+            while !event.text.is_char_boundary(text_len) {
+                text_len -= 1;
+            }
+            output.extend_from_slice(&word(WFDB_AUX, text_len));
+            output.extend_from_slice(&event.text.as_bytes()[..text_len]);
+            if text_len % 2 == 1 {
+                output.push(0);
+            }
+        }
+        output.extend_from_slice(&[0, 0]);
+        output
+    }
+}
+
+/// Left-align a value in a fixed-size ASCII field, as required by EDF headers
+fn edf_field(value: &str, size: usize) -> String {
+    let mut field: String = value
+        .chars()
+        .map(|c| {
+            if c.is_ascii() && !c.is_ascii_control() {
+                c
+            } else {
+                '_'
+            }
+        })
+        .take(size)
+        .collect();
+    while field.len() < size {
+        field.push(' ');
+    }
+    field
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{DataSnapshot, Phase};
+
+    fn snapshot(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick,
+            centile: 0,
+            pressure: 50,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow: Some(300),
+            expiratory_flow: Some(100),
+        })
+    }
+
+    fn signals() -> Biosignals {
+        let messages: Vec<TelemetryMessage> = (0..150).map(|i| snapshot(i * 10_000)).collect();
+        Biosignals::from_messages(&messages, &[Annotation::new(1_200_000, "changed PEEP")])
+    }
+
+    #[test]
+    fn extracts_signals_and_events() {
+        let signals = signals();
+        assert_eq!(signals.len(), 150);
+        assert_eq!(signals.flow[0], 200);
+        assert_eq!(
+            signals.events,
+            vec![BiosignalEvent {
+                sample: 120,
+                text: "changed PEEP".to_owned()
+            }]
+        );
+    }
+
+    #[test]
+    fn writes_edf() {
+        let mut output = Vec::new();
+        signals().write_edf(&mut output).unwrap();
+
+        let header = String::from_utf8_lossy(&output[..EDF_HEADER_SIZE * 4]);
+        assert_eq!(&header[184..192], "1024    ");
+        assert_eq!(&header[236..244], "2       ");
+        assert!(header.contains(EDF_ANNOTATIONS_LABEL));
+
+        // 2 records of 100 pressure samples, 100 flow samples and 12 annotation samples
+        assert_eq!(output.len(), EDF_HEADER_SIZE * 4 + 2 * (200 + 200 + 24));
+        let second_record = &output[EDF_HEADER_SIZE * 4 + 424..];
+        assert!(String::from_utf8_lossy(second_record).contains("+1.2\x14changed PEEP\x14"));
+    }
+
+    #[test]
+    fn writes_wfdb_annotations() {
+        let mut signals = signals();
+        signals.events.push(BiosignalEvent {
+            sample: 2000,
+            text: "late".to_owned(),
+        });
+        let annotations = signals.wfdb_annotations();
+
+        assert_eq!(&annotations[..2], &((22 << 10) | 120_u16).to_le_bytes());
+        assert_eq!(&annotations[2..4], &((63 << 10) | 12_u16).to_le_bytes());
+        assert_eq!(&annotations[4..16], b"changed PEEP");
+        assert_eq!(&annotations[16..18], &(59_u16 << 10).to_le_bytes());
+        assert_eq!(&annotations[18..22], &[0, 0, 0x58, 0x07]);
+        assert_eq!(&annotations[annotations.len() - 2..], &[0, 0]);
+    }
+}
@@ -0,0 +1,214 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::{self, Read, Write};
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::source::{SourceInfo, SourceKind, TelemetrySource};
+
+/// RFCOMM channel used by most serial port profile (SPP) bridges
+pub const DEFAULT_RFCOMM_CHANNEL: u8 = 1;
+
+/// Default time to wait for bytes before giving the hand back (e.g. to send control messages)
+pub const DEFAULT_READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Error returned when a Bluetooth address cannot be parsed
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid Bluetooth address {0} (expected 6 hexadecimal bytes separated by colons, e.g. 00:11:22:33:44:55)")]
+pub struct InvalidBluetoothAddress(pub String);
+
+/// Address of a Bluetooth device, most significant byte first (as written, e.g. `00:11:22:33:44:55`)
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct BluetoothAddress(pub [u8; 6]);
+
+impl FromStr for BluetoothAddress {
+    type Err = InvalidBluetoothAddress;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidBluetoothAddress(s.to_owned());
+        let mut bytes = [0; 6];
+        let mut parts = s.split(':');
+        for byte in bytes.iter_mut() {
+            let part = parts
+                .next()
+                .filter(|part| part.len() == 2)
+                .ok_or_else(invalid)?;
+            *byte = u8::from_str_radix(part, 16).map_err(|_| invalid())?;
+        }
+        if parts.next().is_some() {
+            return Err(invalid());
+        }
+        Ok(Self(bytes))
+    }
+}
+
+impl std::fmt::Display for BluetoothAddress {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02X}:{:02X}:{:02X}:{:02X}:{:02X}:{:02X}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// Telemetry relayed by a Bluetooth serial port profile (SPP) bridge, read through an RFCOMM socket
+///
+/// Use it with `gather_telemetry_from_source()`, which connects again when the link drops.
+/// RFCOMM sockets are only supported on Linux (through BlueZ); elsewhere, bind the bridge to a virtual serial port and use `gather_telemetry()`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RfcommSource {
+    /// Address of the bridge
+    pub address: BluetoothAddress,
+    /// RFCOMM channel of the serial port profile
+    pub channel: u8,
+    /// Time to wait for bytes before giving the hand back
+    pub read_timeout: Duration,
+}
+
+impl RfcommSource {
+    /// Create a source reading from an RFCOMM channel of a device
+    pub fn new(address: BluetoothAddress, channel: u8) -> Self {
+        Self {
+            address,
+            channel,
+            read_timeout: DEFAULT_READ_TIMEOUT,
+        }
+    }
+}
+
+/// An open RFCOMM connection
+#[derive(Debug)]
+pub struct RfcommStream {
+    socket: File,
+}
+
+impl Read for RfcommStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.socket.read(buf)
+    }
+}
+
+impl Write for RfcommStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.socket.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.socket.flush()
+    }
+}
+
+impl TelemetrySource for RfcommSource {
+    type Connection = RfcommStream;
+
+    fn info(&self) -> SourceInfo {
+        SourceInfo::new(
+            SourceKind::Bluetooth,
+            Some(format!("{}/{}", self.address, self.channel)),
+        )
+    }
+
+    #[cfg(target_os = "linux")]
+    fn connect(&mut self) -> io::Result<RfcommStream> {
+        use std::os::unix::io::{AsRawFd, FromRawFd};
+
+        const BTPROTO_RFCOMM: libc::c_int = 3;
+
+        #[repr(C)]
+        struct SockaddrRc {
+            rc_family: libc::sa_family_t,
+            rc_bdaddr: [u8; 6],
+            rc_channel: u8,
+        }
+
+        // SAFETY: the file descriptor is checked, then owned by `socket` which closes it when dropped
+        let socket = unsafe {
+            let fd = libc::socket(
+                libc::AF_BLUETOOTH,
+                libc::SOCK_STREAM | libc::SOCK_CLOEXEC,
+                BTPROTO_RFCOMM,
+            );
+            if fd < 0 {
+                return Err(io::Error::last_os_error());
+            }
+            File::from_raw_fd(fd)
+        };
+
+        // BlueZ expects addresses least significant byte first
+        let mut bdaddr = self.address.0;
+        bdaddr.reverse();
+        let address = SockaddrRc {
+            rc_family: libc::AF_BLUETOOTH as libc::sa_family_t,
+            rc_bdaddr: bdaddr,
+            rc_channel: self.channel,
+        };
+        let timeout = libc::timeval {
+            tv_sec: self.read_timeout.as_secs() as libc::time_t,
+            tv_usec: self.read_timeout.subsec_micros() as libc::suseconds_t,
+        };
+
+        // SAFETY: pointers are valid for the given lengths during the calls
+        unsafe {
+            if libc::connect(
+                socket.as_raw_fd(),
+                &address as *const SockaddrRc as *const libc::sockaddr,
+                std::mem::size_of::<SockaddrRc>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+            if libc::setsockopt(
+                socket.as_raw_fd(),
+                libc::SOL_SOCKET,
+                libc::SO_RCVTIMEO,
+                &timeout as *const libc::timeval as *const libc::c_void,
+                std::mem::size_of::<libc::timeval>() as libc::socklen_t,
+            ) < 0
+            {
+                return Err(io::Error::last_os_error());
+            }
+        }
+
+        Ok(RfcommStream { socket })
+    }
+
+    #[cfg(not(target_os = "linux"))]
+    fn connect(&mut self) -> io::Result<RfcommStream> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "RFCOMM sockets are only supported on Linux; bind the bridge to a serial port instead",
+        ))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_and_display_addresses() {
+        let address: BluetoothAddress = "00:1a:7D:da:71:13".parse().unwrap();
+        assert_eq!(address.0, [0x00, 0x1A, 0x7D, 0xDA, 0x71, 0x13]);
+        assert_eq!(address.to_string(), "00:1A:7D:DA:71:13");
+
+        for invalid in [
+            "",
+            "00:11:22:33:44",
+            "00:11:22:33:44:55:66",
+            "0:11:22:33:44:55",
+            "zz:11:22:33:44:55",
+        ] {
+            assert!(invalid.parse::<BluetoothAddress>().is_err(), "{}", invalid);
+        }
+        assert_eq!(
+            RfcommSource::new(address, 2).info().to_string(),
+            "bt:00:1A:7D:DA:71:13/2"
+        );
+    }
+}
@@ -0,0 +1,216 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::structures::TelemetryMessage;
+
+/// Minimum number of complete cycles needed to estimate anything
+const MIN_CYCLES: usize = 2;
+
+/// Respiratory rate and I:E ratio estimated from a waveform
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct BreathingEstimate {
+    /// Number of complete cycles detected
+    pub cycles: usize,
+    /// Respiratory rate in cycles per minute
+    pub respiratory_rate: f64,
+    /// Median duration of inspirations in microseconds
+    pub inspiratory_duration: u64,
+    /// Median duration of expirations in microseconds
+    pub expiratory_duration: u64,
+    /// Whether the flow was used (protocol v2) rather than the pressure
+    pub from_flow: bool,
+}
+
+impl BreathingEstimate {
+    /// Expiratory part of the I:E ratio when the inspiratory part is 1 (e.g. `2.0` for 1:2)
+    pub fn ie_ratio(&self) -> f64 {
+        self.expiratory_duration as f64 / self.inspiratory_duration.max(1) as f64
+    }
+
+    /// Estimate the breathing from the data snapshots of a sequence of messages (other messages are ignored)
+    ///
+    /// This does not rely on machine state snapshots, so it also works on partial captures or when they were dropped.
+    /// Cycles are detected with hysteresis on the flow when every snapshot has one, and on the pressure otherwise:
+    /// an inspiration starts when the signal rises above 60% of its range, and ends when it falls back below 30%.
+    /// Returns `None` if less than 2 complete cycles are found.
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(
+        messages: I,
+    ) -> Option<Self> {
+        let mut samples: Vec<(u64, f64, Option<f64>)> = Vec::new();
+        for message in messages {
+            if let TelemetryMessage::DataSnapshot(snapshot) = message {
+                let flow = snapshot.inspiratory_flow.map(|inspiratory| {
+                    f64::from(inspiratory) - f64::from(snapshot.expiratory_flow.unwrap_or(0))
+                });
+                samples.push((snapshot.systick, f64::from(snapshot.pressure), flow));
+            }
+        }
+
+        let from_flow = !samples.is_empty() && samples.iter().all(|(_, _, flow)| flow.is_some());
+        let signal: Vec<(u64, f64)> = samples
+            .iter()
+            .map(|(systick, pressure, flow)| match flow {
+                Some(flow) if from_flow => (*systick, *flow),
+                _ => (*systick, *pressure),
+            })
+            .collect();
+
+        let (starts, ends) = detect_phases(&signal)?;
+        let periods: Vec<u64> = starts.windows(2).map(|w| w[1] - w[0]).collect();
+        let inspirations: Vec<u64> = starts
+            .iter()
+            .zip(&ends)
+            .map(|(start, end)| end - start)
+            .collect();
+        let expirations: Vec<u64> = ends
+            .iter()
+            .zip(starts.iter().skip(1))
+            .map(|(end, next_start)| next_start - end)
+            .collect();
+
+        let period = median(periods)?;
+        Some(Self {
+            cycles: starts.len() - 1,
+            respiratory_rate: 60_000_000.0 / period.max(1) as f64,
+            inspiratory_duration: median(inspirations)?,
+            expiratory_duration: median(expirations)?,
+            from_flow,
+        })
+    }
+}
+
+/// Respiratory rate in cycles per minute, taken from machine state snapshots when there are some, and estimated from the waveform otherwise
+pub fn respiratory_rate<'a, I>(messages: I) -> Option<f64>
+where
+    I: IntoIterator<Item = &'a TelemetryMessage> + Clone,
+{
+    let reported: Vec<u64> = messages
+        .clone()
+        .into_iter()
+        .filter_map(|message| match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => snapshot.previous_cpm,
+            _ => None,
+        })
+        .filter(|cpm| *cpm > 0)
+        .map(u64::from)
+        .collect();
+
+    match median(reported) {
+        Some(cpm) => Some(cpm as f64),
+        None => {
+            BreathingEstimate::from_messages(messages).map(|estimate| estimate.respiratory_rate)
+        }
+    }
+}
+
+/// Systicks at which inspirations start, and at which they end; only complete cycles are kept, so there is one more start than ends
+fn detect_phases(signal: &[(u64, f64)]) -> Option<(Vec<u64>, Vec<u64>)> {
+    let (min, max) = signal
+        .iter()
+        .fold((f64::MAX, f64::MIN), |(min, max), (_, value)| {
+            (min.min(*value), max.max(*value))
+        });
+    if max <= min {
+        return None;
+    }
+    let rising = min + (max - min) * 0.6;
+    let falling = min + (max - min) * 0.3;
+
+    let mut starts = Vec::new();
+    let mut ends = Vec::new();
+    // Wait for a first expiration, so that a capture starting mid-inspiration is not counted
+    let mut inspiring = true;
+    for (systick, value) in signal {
+        if inspiring && *value <= falling {
+            inspiring = false;
+            if !starts.is_empty() {
+                ends.push(*systick);
+            }
+        } else if !inspiring && *value >= rising {
+            inspiring = true;
+            starts.push(*systick);
+        }
+    }
+
+    // Drop the last inspiration if it did not end, then the last cycle which has no next start to bound its expiration
+    starts.truncate(ends.len() + 1);
+    ends.truncate(starts.len().saturating_sub(1));
+    if ends.len() < MIN_CYCLES {
+        return None;
+    }
+    Some((starts, ends))
+}
+
+fn median(mut values: Vec<u64>) -> Option<u64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable();
+    Some(values[values.len() / 2])
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{DataSnapshot, MachineStateSnapshot, Phase};
+
+    /// 20 cycles per minute (3 s) with 1 s of inspiration, sampled every 100 ms
+    fn waveform(with_flow: bool) -> Vec<TelemetryMessage> {
+        (0..100)
+            .map(|i| {
+                let systick = i * 100_000;
+                let inspiring = (systick % 3_000_000) < 1_000_000;
+                TelemetryMessage::DataSnapshot(DataSnapshot {
+                    telemetry_version: 2,
+                    version: String::new(),
+                    device_id: String::new(),
+                    systick,
+                    centile: 0,
+                    pressure: if inspiring { 300 } else { 50 },
+                    phase: Phase::Inhalation,
+                    subphase: None,
+                    blower_valve_position: 0,
+                    patient_valve_position: 0,
+                    blower_rpm: 0,
+                    battery_level: 0,
+                    inspiratory_flow: with_flow.then_some(if inspiring { 3000 } else { 0 }),
+                    expiratory_flow: with_flow.then_some(if inspiring { 0 } else { 1500 }),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn estimates_from_pressure_and_flow() {
+        for with_flow in [false, true] {
+            let estimate = BreathingEstimate::from_messages(&waveform(with_flow)).unwrap();
+            assert_eq!(estimate.from_flow, with_flow);
+            assert_eq!(estimate.cycles, 2);
+            assert_eq!(estimate.respiratory_rate, 20.0);
+            assert_eq!(estimate.inspiratory_duration, 1_000_000);
+            assert_eq!(estimate.expiratory_duration, 2_000_000);
+            assert_eq!(estimate.ie_ratio(), 2.0);
+        }
+    }
+
+    #[test]
+    fn prefers_reported_rate() {
+        let mut messages = waveform(false);
+        assert_eq!(respiratory_rate(&messages), Some(20.0));
+
+        messages.push(TelemetryMessage::MachineStateSnapshot(
+            MachineStateSnapshot {
+                previous_cpm: Some(18),
+                ..Default::default()
+            },
+        ));
+        assert_eq!(respiratory_rate(&messages), Some(18.0));
+        assert_eq!(respiratory_rate(&messages[..20]), None);
+    }
+}
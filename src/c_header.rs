@@ -0,0 +1,128 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fmt::Write;
+
+use crate::control::{
+    ControlSetting, CONTROL_FRAME_FOOTER, CONTROL_FRAME_HEADER, DISABLE_RPI_WATCHDOG,
+    PROTECTED_CONTROL_FRAME_HEADER,
+};
+use crate::parsers::{FRAME_FOOTER, FRAME_HEADER, MAXIMUM_SUPPORTED_VERSION};
+
+/// Path of the generated header, relative to the root of the crate
+pub const HEADER_PATH: &str = "include/makair_telemetry.h";
+
+/// Name of a setting in C constants, e.g. `CYCLES_PER_MINUTE` for `CyclesPerMinute`
+fn constant_name(setting: ControlSetting) -> String {
+    let name = format!("{:?}", setting);
+    let chars: Vec<char> = name.chars().collect();
+    let mut constant = String::new();
+    for (index, c) in chars.iter().enumerate() {
+        let starts_word = index > 0
+            && c.is_ascii_uppercase()
+            && (!chars[index - 1].is_ascii_uppercase()
+                || chars
+                    .get(index + 1)
+                    .is_some_and(|next| next.is_ascii_lowercase()));
+        if starts_word {
+            constant.push('_');
+        }
+        constant.push(c.to_ascii_uppercase());
+    }
+    constant
+}
+
+fn define_bytes(output: &mut String, name: &str, bytes: &[u8]) {
+    for (index, byte) in bytes.iter().enumerate() {
+        let _ = writeln!(output, "#define {}_{} 0x{:02X}", name, index, byte);
+    }
+}
+
+/// Generate a C header with the constants of telemetry and control frames
+///
+/// It contains frame headers and footers, the latest supported protocol version, and the ID, bounds and default value of every control setting.
+/// The firmware's unit tests can include it so that both sides always agree on these numbers; a copy is kept at `HEADER_PATH` and checked by this crate's tests.
+pub fn generate() -> String {
+    let mut output = String::new();
+    output.push_str(
+        "/*\n * MakAir telemetry and control frame constants\n *\n * Generated by the makair-telemetry crate (`makair_telemetry_cli c-header`); do not edit.\n */\n\n",
+    );
+    output.push_str("#ifndef MAKAIR_TELEMETRY_H\n#define MAKAIR_TELEMETRY_H\n\n");
+
+    output.push_str("/* Telemetry frames (MCU to UI) */\n");
+    let _ = writeln!(
+        output,
+        "#define MAKAIR_TELEMETRY_MAX_PROTOCOL_VERSION {}",
+        MAXIMUM_SUPPORTED_VERSION
+    );
+    define_bytes(&mut output, "MAKAIR_TELEMETRY_FRAME_HEADER", FRAME_HEADER);
+    define_bytes(&mut output, "MAKAIR_TELEMETRY_FRAME_FOOTER", FRAME_FOOTER);
+
+    output.push_str("\n/* Control frames (UI to MCU) */\n");
+    define_bytes(
+        &mut output,
+        "MAKAIR_CONTROL_FRAME_HEADER",
+        CONTROL_FRAME_HEADER,
+    );
+    define_bytes(
+        &mut output,
+        "MAKAIR_PROTECTED_CONTROL_FRAME_HEADER",
+        PROTECTED_CONTROL_FRAME_HEADER,
+    );
+    define_bytes(
+        &mut output,
+        "MAKAIR_CONTROL_FRAME_FOOTER",
+        CONTROL_FRAME_FOOTER,
+    );
+    let _ = writeln!(
+        output,
+        "#define MAKAIR_DISABLE_RPI_WATCHDOG {}",
+        DISABLE_RPI_WATCHDOG
+    );
+
+    output.push_str("\n/* Control settings: ID, bounds and default value */\n");
+    for setting in ControlSetting::iter() {
+        let name = format!("MAKAIR_SETTING_{}", constant_name(setting));
+        let bounds = setting.bounds();
+        let _ = writeln!(output, "#define {} {}", name, setting as u8);
+        let _ = writeln!(output, "#define {}_MIN {}", name, bounds.start());
+        let _ = writeln!(output, "#define {}_MAX {}", name, bounds.end());
+        let _ = writeln!(output, "#define {}_DEFAULT {}", name, setting.default());
+    }
+
+    output.push_str("\n#endif /* MAKAIR_TELEMETRY_H */\n");
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn constant_names() {
+        assert_eq!(
+            constant_name(ControlSetting::CyclesPerMinute),
+            "CYCLES_PER_MINUTE"
+        );
+        assert_eq!(constant_name(ControlSetting::PEEP), "PEEP");
+        assert_eq!(constant_name(ControlSetting::TiMin), "TI_MIN");
+    }
+
+    /// Run with `MAKAIR_UPDATE_C_HEADER=1` to update the header after changing constants
+    #[test]
+    fn header_is_up_to_date() {
+        let path = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join(HEADER_PATH);
+        let generated = generate();
+        if std::env::var_os("MAKAIR_UPDATE_C_HEADER").is_some() {
+            std::fs::write(&path, &generated).unwrap();
+        }
+        let committed = std::fs::read_to_string(&path).unwrap_or_default();
+        assert!(
+            committed == generated,
+            "{} is outdated; run the tests with MAKAIR_UPDATE_C_HEADER=1 to update it",
+            HEADER_PATH
+        );
+    }
+}
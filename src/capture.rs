@@ -0,0 +1,211 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::SystemTime;
+
+use crate::source::SourceInfo;
+use crate::structures::HighLevelError;
+
+/// Number of frames kept by a default `FrameCapture`
+pub const DEFAULT_CAPACITY: usize = 64;
+
+/// Outcome of the parsing of a captured frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FrameOutcome {
+    /// Frame was successfully parsed
+    Parsed,
+    /// Frame was read but its CRC was wrong
+    CrcError,
+    /// Frame was built using an unsupported protocol version
+    UnsupportedProtocolVersion,
+}
+
+impl FrameOutcome {
+    /// Outcome of a frame, given the result of its decoding
+    pub(crate) fn of<T>(result: &Result<T, HighLevelError>) -> Self {
+        match result {
+            Ok(_) => Self::Parsed,
+            Err(HighLevelError::UnsupportedProtocolVersion { .. }) => {
+                Self::UnsupportedProtocolVersion
+            }
+            Err(_) => Self::CrcError,
+        }
+    }
+}
+
+/// Raw bytes of a frame that went through a `gather_telemetry*` function
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CapturedFrame {
+    /// Bytes of the frame, including header, CRC and footer
+    ///
+    /// In case of an error, this contains every byte that was in the parsing buffer.
+    pub bytes: Vec<u8>,
+    /// Host wall-clock time at which the frame was received
+    pub received_at: SystemTime,
+    /// Where the frame comes from
+    pub source: SourceInfo,
+    /// Outcome of the parsing
+    pub outcome: FrameOutcome,
+}
+
+#[derive(Debug)]
+struct FrameRing {
+    capacity: usize,
+    frames: VecDeque<CapturedFrame>,
+}
+
+impl FrameRing {
+    fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            frames: VecDeque::with_capacity(capacity),
+        }
+    }
+
+    fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.frames.len() > capacity {
+            self.frames.pop_front();
+        }
+    }
+
+    fn push(&mut self, frame: CapturedFrame) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.frames.len() >= self.capacity {
+            self.frames.pop_front();
+        }
+        self.frames.push_back(frame);
+    }
+}
+
+/// In-memory ring of the last raw frames read by a `gather_telemetry*` function
+///
+/// Capture is opt-in: give a `FrameCapture` to a gather function (see `decoder::DecodeConfig::frame_capture()`) and keep a clone of it to dump its frames, e.g. when a parse anomaly or a UI bug occurs.
+/// Clones share the same frames; use one capture per gather function so that sources are not mixed.
+#[derive(Debug, Clone)]
+pub struct FrameCapture {
+    ring: Arc<Mutex<FrameRing>>,
+}
+
+impl Default for FrameCapture {
+    fn default() -> Self {
+        Self::new(DEFAULT_CAPACITY)
+    }
+}
+
+/// Captures are equal if they share the same frames
+impl PartialEq for FrameCapture {
+    fn eq(&self, other: &Self) -> bool {
+        Arc::ptr_eq(&self.ring, &other.ring)
+    }
+}
+
+impl Eq for FrameCapture {}
+
+impl FrameCapture {
+    /// Create a capture keeping the last `capacity` frames; oldest frames are dropped first
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            ring: Arc::new(Mutex::new(FrameRing::new(capacity))),
+        }
+    }
+
+    fn ring(&self) -> MutexGuard<'_, FrameRing> {
+        // A panic while holding the lock cannot leave the ring in an inconsistent state
+        self.ring
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Change the number of frames that are kept (`0` stops the capture)
+    pub fn set_capacity(&self, capacity: usize) {
+        self.ring().set_capacity(capacity);
+    }
+
+    /// Get a copy of the last captured frames, from the oldest to the newest
+    pub fn dump_recent_frames(&self) -> Vec<CapturedFrame> {
+        self.ring().frames.iter().cloned().collect()
+    }
+
+    /// Write the last captured frames in the recording format (one base64-encoded frame per line)
+    ///
+    /// The output can be attached to a bug report and replayed with `gather_telemetry_from_file()`.
+    pub fn write_recent_frames<W: Write>(&self, writer: &mut W) -> std::io::Result<()> {
+        for frame in self.dump_recent_frames() {
+            writer.write_all(base64::encode(&frame.bytes).as_bytes())?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+
+    pub(crate) fn push(&self, bytes: &[u8], source: &SourceInfo, outcome: FrameOutcome) {
+        self.ring().push(CapturedFrame {
+            bytes: bytes.to_vec(),
+            received_at: SystemTime::now(),
+            source: source.clone(),
+            outcome,
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceKind;
+
+    fn frame(byte: u8) -> CapturedFrame {
+        CapturedFrame {
+            bytes: vec![byte],
+            received_at: SystemTime::now(),
+            source: SourceInfo::new(SourceKind::Simulator, None),
+            outcome: FrameOutcome::Parsed,
+        }
+    }
+
+    fn bytes(ring: &FrameRing) -> Vec<u8> {
+        ring.frames.iter().map(|frame| frame.bytes[0]).collect()
+    }
+
+    #[test]
+    fn keeps_last_frames() {
+        let mut ring = FrameRing::new(3);
+        for byte in 0..5 {
+            ring.push(frame(byte));
+        }
+        assert_eq!(bytes(&ring), vec![2, 3, 4]);
+
+        ring.set_capacity(2);
+        assert_eq!(bytes(&ring), vec![3, 4]);
+
+        ring.set_capacity(0);
+        ring.push(frame(5));
+        assert!(ring.frames.is_empty());
+    }
+
+    #[test]
+    fn clones_share_their_frames() {
+        let capture = FrameCapture::new(2);
+        let clone = capture.clone();
+        clone.push(
+            &[1],
+            &SourceInfo::new(SourceKind::Simulator, None),
+            FrameOutcome::CrcError,
+        );
+        assert_eq!(capture, clone);
+        assert_ne!(capture, FrameCapture::new(2));
+        assert_eq!(
+            capture.dump_recent_frames()[0].outcome,
+            FrameOutcome::CrcError
+        );
+
+        capture.set_capacity(0);
+        assert!(clone.dump_recent_frames().is_empty());
+    }
+}
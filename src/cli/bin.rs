@@ -7,30 +7,108 @@
 extern crate log;
 
 mod convert;
+mod daemon;
+mod progress;
 mod statistics;
-mod storm;
 
-use clap::{ArgGroup, Parser};
+use clap::{ArgGroup, Args, Parser};
 use std::fs::File;
 use std::fs::OpenOptions;
-use std::io::BufWriter;
+use std::io::{BufWriter, LineWriter, Write};
+use std::sync::atomic::{AtomicU64, Ordering};
 use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::sync::{Arc, Mutex};
+use std::time::SystemTime;
 use url::Url;
 
+use adapter::MessageAdapter;
+use annotation::*;
+use anomaly::*;
 use control::*;
 use convert::*;
+use fanout::*;
+use fault::*;
+use filter::*;
+use formatter::*;
+use jitter::*;
 use makair_telemetry::*;
+use progress::*;
+use query::*;
+use reader::*;
+use recording::*;
+use serial_config::*;
+use session::*;
+use simulator::*;
+use sink::*;
 use statistics::*;
-use storm::*;
 use structures::*;
+use time_sync::*;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum DisplayFormat {
+    Log,
+    Compact,
+    Color,
+}
+
+impl DisplayFormat {
+    fn formatter(self) -> Box<dyn MessageFormatter + Send> {
+        match self {
+            Self::Log => Box::new(LogFormatter),
+            Self::Compact => Box::new(CompactFormatter),
+            Self::Color => Box::new(ColorFormatter),
+        }
+    }
+}
+
+impl std::str::FromStr for DisplayFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "log" => Ok(Self::Log),
+            "compact" => Ok(Self::Compact),
+            "color" => Ok(Self::Color),
+            _ => Err("Supported formats are: log, compact, color"),
+        }
+    }
+}
 
 #[derive(Debug, Parser)]
 #[clap(name = "MakAir Telemetry CLI", author, about, version)]
 struct Opts {
+    /// How to handle unknown values sent by the firmware: strict (reject messages) or lenient (keep them); defaults to $MAKAIR_PARSER_MODE or strict
+    #[clap(long, global = true)]
+    parser_mode: Option<parsers::ParsingMode>,
+
+    /// Size in bytes beyond which an incomplete frame is considered garbage; defaults to $MAKAIR_PARSER_MAX_FRAME_SIZE or 1024
+    #[clap(long, global = true)]
+    max_frame_size: Option<usize>,
+
+    /// Number of bytes scanned at once for the next frame header after garbage; defaults to $MAKAIR_PARSER_RESYNC_WINDOW or 64
+    #[clap(long, global = true)]
+    resync_window: Option<usize>,
+
     #[clap(subcommand)]
     mode: Mode,
 }
 
+impl Opts {
+    fn parser_config(&self) -> parsers::ParserConfig {
+        let mut config = parsers::ParserConfig::from_env().expect("invalid parser configuration");
+        if let Some(mode) = self.parser_mode {
+            config = config.mode(mode);
+        }
+        if let Some(bytes) = self.max_frame_size {
+            config = config.max_frame_size(bytes);
+        }
+        if let Some(bytes) = self.resync_window {
+            config = config.resync_window(bytes);
+        }
+        config
+    }
+}
+
 #[derive(Debug, Parser)]
 enum Mode {
     /// Read telemetry from a serial port or a WebSocket server, parse it and stream result to stdout
@@ -39,12 +117,33 @@ enum Mode {
     /// Read telemetry from a serial port and save bytes to a file
     Record(Record),
 
+    /// Read telemetry from a serial port and give it to the sinks of a TOML configuration, applying its changes without dropping the serial connection
+    Daemon(Daemon),
+
     /// Read telemetry from a recorded file, parse it and stream result to stdout
     Play(Play),
 
+    /// Simulate a MakAir ventilating a patient model, and stream its telemetry to stdout
+    Simulate(Simulate),
+
+    /// Forward bytes between the MCU and a control UI connected to another serial port, and stream the telemetry to stdout
+    Sniff(Sniff),
+
+    /// Read telemetry from a serial port and serve it to WebSocket clients, forwarding their control messages to the MCU
+    Serve(Serve),
+
+    /// Read telemetry from a serial port and publish it to an MQTT broker, forwarding control messages published to its control topic to the MCU
+    Mqtt(Mqtt),
+
+    /// Read telemetry bytes from stdin and write parsed messages to stdout as JSON or raw frames, e.g. behind socat, inetd or systemd socket activation
+    Pipe(Pipe),
+
     /// Read telemetry from a recorded file, parse it and compute some statistics
     Stats(Stats),
 
+    /// Read telemetry from a recorded file and break its serial traffic down by message type and overhead, projected against the UART capacity
+    Bandwidth(Bandwidth),
+
     /// Send one specific control message to a serial port, then run debug mode
     Control(Control),
 
@@ -54,6 +153,59 @@ enum Mode {
     /// Read telemetry from a recorded file, parse it and convert it to another format
     Convert(Convert),
 
+    /// Read messages exported by convert in JSON format and write them back to a recorded file
+    ImportJson(ImportJson),
+
+    /// Read telemetry from a recorded file and merge its data snapshots with the nearest rows of an external sensor CSV, aligned by wall-clock
+    MergeCsv(MergeCsv),
+
+    /// Add an annotation to a recorded file, or list its annotations
+    Annotate(Annotate),
+
+    /// Play a recorded file through the parser, adapters and sinks, and report the latency of each stage
+    Latency(Latency),
+
+    /// Check a capture of a firmware's output for protocol compliance (framing, CRC, field ranges, ordering, cadence), e.g. in the firmware's CI
+    LintCapture(LintCapture),
+
+    /// Listen to a device for a few seconds and report its protocol version, firmware version, device ID, message cadence and compliance warnings
+    Probe(Probe),
+
+    /// List serial ports a MakAir could be connected to
+    ListPorts,
+
+    /// Read telemetry from a recorded file and render pressure, flow and volume curves to an image
+    #[cfg(feature = "plot")]
+    Plot(Plot),
+
+    /// Read telemetry from a recorded file and write a standalone HTML report (statistics, settings, alarms, waveforms)
+    Report(Report),
+
+    /// Read telemetry from a recorded file and write the step function of every commanded setting over time, as CSV or JSON
+    SettingsTimeline(SettingsTimeline),
+
+    /// Read telemetry from a recorded file and export its end of line test sessions to CSV and/or the REST endpoint of a manufacturing execution system
+    EolExport(EolExport),
+
+    /// Read telemetry from the recorded files of two devices, align their cycles and compute per-cycle deltas of their metrics
+    Compare(Compare),
+
+    /// Read telemetry from recorded files of several devices and write per-hour statistics, leaving out hours with too few devices
+    Aggregate(Aggregate),
+
+    /// Read telemetry from a recorded file and save the messages matching a query to another recording
+    Trim(Trim),
+
+    /// Delete recordings of a directory according to retention rules (age, total size, annotations)
+    Gc(Gc),
+
+    /// Upload completed recordings of a directory to a tus server, then optionally delete uploaded recordings
+    #[cfg(feature = "upload")]
+    Upload(Upload),
+
+    /// Generate a C header with the constants of telemetry and control frames, for the firmware's unit tests
+    CHeader(CHeader),
+
     /// Send a control message to disable the RPi watchdog (until MCU is restarted)
     DisableRpiWatchdog(DisableRpiWatchdog),
 }
@@ -69,9 +221,44 @@ struct Debug {
     #[clap(short = 'w', long, group = "source")]
     ws_url: Option<Url>,
 
+    /// Ask the WebSocket server to compress messages (permessage-deflate)
+    #[clap(long, requires = "ws-url")]
+    ws_deflate: bool,
+
+    /// Address of a Bluetooth serial bridge (e.g. 00:11:22:33:44:55)
+    #[clap(short = 'b', long, group = "source")]
+    bluetooth: Option<bluetooth::BluetoothAddress>,
+
+    /// RFCOMM channel of the Bluetooth serial bridge
+    #[clap(long, default_value_t = bluetooth::DEFAULT_RFCOMM_CHANNEL)]
+    rfcomm_channel: u8,
+
     /// Randomly send control messages at a normal pace
     #[clap(short = 'c', long)]
     random_control_messages: bool,
+
+    /// Periodically send the wall-clock to the MCU, and log the offset between systick and wall-clock
+    #[clap(short = 't', long)]
+    time_sync: bool,
+
+    /// Ask the MCU to send only one data snapshot out of this number (e.g. 4 for 25 Hz), and log whether its firmware supports it
+    #[clap(long)]
+    decimation: Option<u16>,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+
+    /// Periodically log histograms of intervals between data snapshots (by systick and by host receive time)
+    #[clap(long)]
+    jitter: bool,
+
+    /// Serve Prometheus metrics (message counters, last systick, active alarms, CRC errors) on this address, e.g. 0.0.0.0:9100
+    #[clap(long)]
+    metrics_listen: Option<String>,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
 }
 
 #[derive(Debug, Parser)]
@@ -83,6 +270,191 @@ struct Record {
     /// Path of the file to write to
     #[clap(short = 'o', long)]
     output: String,
+
+    /// When to flush recorded messages to the file: message, messages:<count>, ms:<milliseconds>, alarm (alarms are also synced to storage)
+    #[clap(long, default_value = "message")]
+    flush_policy: FlushPolicy,
+
+    /// Write to the file from a dedicated thread
+    #[clap(long)]
+    async_writer: bool,
+
+    /// Also write the recording to this file (e.g. on a USB stick); recording goes on as long as one of the copies can be written
+    #[clap(long, conflicts_with = "split-sessions")]
+    mirror: Option<String>,
+
+    /// Start a new file for each patient session (after a long stop, a reboot or a device change): the output path gets a "-session-<number>" suffix
+    #[clap(long)]
+    split_sessions: bool,
+
+    /// (with --split-sessions) Minutes the device must stay stopped for its session to end
+    #[clap(long, default_value = "5")]
+    session_stop_timeout: u64,
+
+    /// Also write a JSON transcript (one message per line) to this file
+    #[clap(long)]
+    also_json: Option<String>,
+
+    /// Also write a JSON summary of each cycle (settings, measures, alarms and derived metrics) to this file, one per line
+    #[clap(long)]
+    cycle_summaries: Option<String>,
+
+    /// Read annotations from stdin while recording: type a note and press Enter to store it in the recording
+    #[clap(long)]
+    annotate: bool,
+
+    /// Seconds without telemetry after which heartbeats (and systemd watchdog pings, when started with WatchdogSec=) stop, so that watchdogs restart the pipeline
+    #[clap(long, default_value = "60")]
+    stall_timeout: u64,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum PipeFormat {
+    Json,
+    Frames,
+}
+
+impl std::str::FromStr for PipeFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "json" => Ok(Self::Json),
+            "frames" => Ok(Self::Frames),
+            _ => Err("Supported formats are: json, frames"),
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+struct Pipe {
+    /// What to write to stdout: json (one message per line) or frames (raw telemetry frames, without garbage and corrupted frames)
+    #[clap(long, default_value = "json")]
+    format: PipeFormat,
+
+    /// Read control messages from this file descriptor, one per line (<setting>=<value>, setting being a number or a name like PEEP)
+    #[clap(long, requires = "control-out")]
+    control_in: Option<i32>,
+
+    /// Write control frames for the MCU to this file descriptor (e.g. the other direction of the link telemetry comes from)
+    #[clap(long, requires = "control-in")]
+    control_out: Option<i32>,
+
+    #[clap(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Sniff {
+    /// Address of the port the MCU is connected to
+    #[clap(short = 'p', long)]
+    mcu_port: String,
+
+    /// Address of the port the control UI is connected to
+    #[clap(short = 'u', long)]
+    ui_port: String,
+
+    /// Also record telemetry to this file
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+
+    /// When to flush recorded messages to the file: message, messages:<count>, ms:<milliseconds>, alarm
+    #[clap(long, default_value = "message")]
+    flush_policy: FlushPolicy,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Serve {
+    /// Address of the serial port
+    #[clap(short = 'p', long)]
+    port: String,
+
+    /// Address the WebSocket server listens on (e.g. 0.0.0.0:8080)
+    #[clap(short = 'l', long)]
+    listen: String,
+
+    /// Only let clients watch telemetry: control messages they send are not forwarded to the MCU
+    #[clap(long)]
+    read_only: bool,
+
+    /// Also record telemetry to this file
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+
+    /// When to flush recorded messages to the file: message, messages:<count>, ms:<milliseconds>, alarm
+    #[clap(long, default_value = "message")]
+    flush_policy: FlushPolicy,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Mqtt {
+    /// Address of the serial port
+    #[clap(short = 'p', long)]
+    port: String,
+
+    /// URL of the MQTT broker (mqtt://[user:password@]host[:port]; only plain MQTT is supported)
+    #[clap(short = 'b', long)]
+    broker: Url,
+
+    /// Prefix of topics; messages are published to <prefix>/<message type> and control messages are received on <prefix>/control
+    #[clap(short = 't', long, default_value = "makair")]
+    topic_prefix: String,
+
+    /// Client identifier given to the broker (unique among its clients); defaults to makair-telemetry-<process ID>
+    #[clap(long)]
+    client_id: Option<String>,
+
+    /// How messages are encoded: frame (telemetry and control frames, as on the serial port) or json
+    #[clap(long, default_value = "frame")]
+    payload: mqtt::MqttPayload,
+
+    /// Do not subscribe to the control topic: nothing is sent to the MCU
+    #[clap(long)]
+    read_only: bool,
+
+    /// Also record telemetry to this file
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+
+    /// When to flush recorded messages to the file: message, messages:<count>, ms:<milliseconds>, alarm
+    #[clap(long, default_value = "message")]
+    flush_policy: FlushPolicy,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Daemon {
+    /// Path of the TOML configuration (serial port, sinks and throttling); it is watched and sinks and throttling changes are applied while running
+    #[clap(short = 'c', long)]
+    config: String,
+
+    /// Seconds without telemetry after which heartbeats (and systemd watchdog pings, when started with WatchdogSec=) stop, so that watchdogs restart the pipeline
+    #[clap(long, default_value = "60")]
+    stall_timeout: u64,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
 }
 
 #[derive(Debug, Parser)]
@@ -94,6 +466,65 @@ struct Play {
     /// Parse and output data as fast as possible
     #[clap(long)]
     full_blast: bool,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+
+    #[clap(flatten)]
+    serve: ServeArgs,
+
+    /// Log unusual cycles (peak pressure, PEEP or tidal volume far from the previous cycles)
+    #[clap(long)]
+    detect_anomalies: bool,
+
+    /// Also write a JSON summary of each cycle (settings, measures, alarms and derived metrics) to this file, one per line
+    #[clap(long)]
+    cycle_summaries: Option<String>,
+
+    /// Inject a fault while playing (e.g. 30:battery-sag:2200:60, 10:pressure-noise:20:5, 60:mass-flow-meter-failure; times in seconds); can be repeated
+    #[clap(long = "fault")]
+    faults: Vec<TimedFault>,
+
+    #[clap(flatten)]
+    filter: FilterArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Simulate {
+    /// Patient model to ventilate: healthy, ards, copd, pediatric
+    #[clap(long, default_value = "healthy")]
+    patient: PatientPreset,
+
+    /// Number of devices to simulate, with distinct device IDs (simulator-1, simulator-2, etc.)
+    #[clap(long, default_value = "1")]
+    devices: usize,
+
+    /// Stop after this number of seconds of simulated time
+    #[clap(long)]
+    duration: Option<u64>,
+
+    /// Also save telemetry to this recording file, or to an S3 object (s3://<bucket>/<key>, configured with AWS_ENDPOINT_URL, AWS_REGION, AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY; requires the s3 feature)
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+
+    /// Generate data as fast as possible
+    #[clap(long)]
+    full_blast: bool,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+
+    #[clap(flatten)]
+    serve: ServeArgs,
+
+    /// Inject a fault while simulating (e.g. 30:battery-sag:2200:60, 10:pressure-noise:20:5, 60:mass-flow-meter-failure; times in seconds); can be repeated
+    #[clap(long = "fault")]
+    faults: Vec<TimedFault>,
+
+    #[clap(flatten)]
+    filter: FilterArgs,
 }
 
 #[derive(Debug, Parser)]
@@ -101,6 +532,28 @@ struct Stats {
     /// Path of the recorded file
     #[clap(short = 'i', long)]
     input: String,
+
+    #[clap(flatten)]
+    query: QueryArgs,
+
+    /// Width of the buckets of the histogram of intervals between data snapshots, in microseconds
+    #[clap(long, default_value_t = jitter::DEFAULT_BUCKET_WIDTH)]
+    jitter_bucket: u64,
+}
+
+#[derive(Debug, Parser)]
+struct Bandwidth {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Baud rate of the UART the traffic is projected against
+    #[clap(long, default_value_t = bandwidth::DEFAULT_BAUD_RATE)]
+    baud_rate: u32,
+
+    /// Write the report as JSON instead of text
+    #[clap(long)]
+    json: bool,
 }
 
 #[derive(Debug, Parser)]
@@ -116,6 +569,12 @@ struct Control {
     /// Value
     #[clap(name = "value")]
     value: u16,
+
+    /// How to display telemetry messages: log, compact, color
+    #[clap(long, default_value = "log")]
+    format: DisplayFormat,
+    #[clap(flatten)]
+    serial: SerialArgs,
 }
 
 #[derive(Debug, Parser)]
@@ -136,30 +595,69 @@ struct Storm {
     #[clap(short = 'c', long)]
     wrong_crc: bool,
 
+    /// Relative frequency of a generator, as <generator>=<weight> (e.g. valid=3); enables the generator unless the weight is 0
+    #[clap(long = "weight", multiple_occurrences = true)]
+    weights: Vec<GeneratorWeight>,
+
+    /// Relative frequency of a setting in control messages, as <setting>=<weight> (e.g. PEEP=20, or 0=0 to never send heartbeats)
+    #[clap(long = "setting-weight", multiple_occurrences = true)]
+    setting_weights: Vec<SettingWeight>,
+
+    /// Seed of the random generator, to replay the exact sequence of a previous storm (its seed is logged at start)
+    #[clap(long)]
+    seed: Option<u64>,
+
     /// Send data as fast as possible (MCU might not be able to read it, but it should not crash)
     #[clap(short = 'f', long)]
     full_blast: bool,
 }
 
+#[derive(Debug)]
+struct GeneratorWeight(storm::StormGenerator, u32);
+
+impl std::str::FromStr for GeneratorWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (generator, weight) = parse_weight(s)?;
+        Ok(Self(generator.parse()?, weight))
+    }
+}
+
+#[derive(Debug)]
+struct SettingWeight(ControlSetting, u32);
+
+impl std::str::FromStr for SettingWeight {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (setting, weight) = parse_weight(s)?;
+        Ok(Self(parse_setting(setting)?, weight))
+    }
+}
+
+fn parse_weight(s: &str) -> Result<(&str, u32), String> {
+    let (name, weight) = s
+        .split_once('=')
+        .ok_or_else(|| format!("expected <name>=<weight>, got {:?}", s))?;
+    let weight = weight
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid weight {:?}: {}", weight.trim(), e))?;
+    Ok((name, weight))
+}
+
 #[derive(Debug, Parser)]
 struct Convert {
     /// Path of the recorded file
     #[clap(short = 'i', long)]
     input: String,
 
-    /// Path of the converted file
+    /// Path of the converted file (for CSV, path of the files without extension; for WFDB, path of the record without extension)
     #[clap(short = 'o', long)]
     output: String,
 
-    /// If a systick value is specified, only messages with a greater or equal systick will be included
-    #[clap(long)]
-    from: Option<u64>,
-
-    /// If a systick value is specified, only messages with a smaller or equal systick will be included
-    #[clap(long)]
-    to: Option<u64>,
-
-    /// Output format
+    /// Output format: gts, json, influx (InfluxDB line protocol), csv (one file for data snapshots and one for machine state snapshots), edf (EDF+), wfdb
     #[clap(short = 'f', long)]
     format: Format,
 
@@ -170,73 +668,709 @@ struct Convert {
     /// (GTS) Do not put automatic or manual "source" label in every GTS line
     #[clap(long)]
     gts_disable_source_label: bool,
+
+    /// (GTS, InfluxDB) Offset in microseconds to add to systicks to get absolute timestamps (as logged by debug mode with time sync)
+    #[clap(long, allow_hyphen_values = true)]
+    gts_clock_offset: Option<i64>,
+
+    #[clap(flatten)]
+    query: QueryArgs,
 }
 
 #[derive(Debug, Parser)]
-struct DisableRpiWatchdog {
-    /// Address of the port to use
-    #[clap(short = 'p')]
-    port: String,
+struct Annotate {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Systick at which the annotation applies (required when adding an annotation)
+    #[clap(long, requires = "text")]
+    systick: Option<u64>,
+
+    /// Text of the annotation to add; if not specified, existing annotations are listed
+    #[clap(name = "text", requires = "systick")]
+    text: Option<String>,
 }
 
-const THREAD_SLEEP_THROTTLE: std::time::Duration = std::time::Duration::from_millis(10);
-const HEARTBEAT_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+#[cfg(feature = "plot")]
+#[derive(Debug, Parser)]
+struct Plot {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
 
-fn main() {
-    env_logger::init();
-    let opts: Opts = Opts::parse();
+    /// Path of the image to write (PNG or SVG, depending on the extension)
+    #[clap(short = 'o', long)]
+    output: String,
 
-    match opts.mode {
-        Mode::Debug(cfg) => debug(cfg),
-        Mode::Record(cfg) => record(cfg),
-        Mode::Play(cfg) => play(cfg),
-        Mode::Stats(cfg) => stats(cfg),
-        Mode::Control(cfg) => control(cfg),
-        Mode::Storm(cfg) => storm(cfg),
-        Mode::Convert(cfg) => convert(cfg),
-        Mode::DisableRpiWatchdog(cfg) => disable_rpi_watchdog(cfg),
-    }
+    /// Width of the image in pixels
+    #[clap(long, default_value = "1200")]
+    width: u32,
+
+    /// Height of the image in pixels
+    #[clap(long, default_value = "900")]
+    height: u32,
+
+    /// Title displayed above the curves
+    #[clap(long)]
+    title: Option<String>,
+
+    #[clap(flatten)]
+    query: QueryArgs,
 }
 
-fn debug(cfg: Debug) {
-    let (control_tx, control_rx): (Sender<ControlMessage>, Receiver<ControlMessage>) =
-        std::sync::mpsc::channel();
+#[derive(Debug, Parser)]
+struct Report {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
 
-    let heartbeat_tx = control_tx.clone();
-    std::thread::spawn(move || loop {
-        heartbeat_tx
-            .send(ControlMessage {
-                setting: ControlSetting::Heartbeat,
-                value: 0,
-            })
-            .expect("[heartbeat tx] failed to send heartbeat message");
-        std::thread::sleep(HEARTBEAT_PERIOD);
-    });
+    /// Path of the HTML file to write
+    #[clap(short = 'o', long)]
+    output: String,
 
-    if cfg.random_control_messages {
-        std::thread::spawn(move || loop {
-            std::thread::sleep(std::time::Duration::from_secs(3));
-            control_tx
-                .send(gen_random_message())
-                .expect("[control tx] failed to send control message");
-        });
-    };
+    /// Title of the report; uses the input filename if not specified
+    #[clap(long)]
+    title: Option<String>,
+}
 
-    let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
-        std::sync::mpsc::channel();
-    std::thread::spawn(move || {
-        if let Some(port) = &cfg.port {
-            gather_telemetry(port, tx, None, Some(control_rx));
-        } else if let Some(url) = &cfg.ws_url {
-            gather_telemetry_from_ws(url, tx, None, Some(control_rx))
-        } else {
-            unreachable!()
-        }
-    });
-    loop {
+#[derive(Debug, Parser)]
+struct SettingsTimeline {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Path of the file to write
+    #[clap(short = 'o', long)]
+    output: String,
+
+    /// Write a JSON array of steps instead of CSV
+    #[clap(long)]
+    json: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(group = ArgGroup::new("destination").required(true).multiple(true))]
+struct EolExport {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Path of the CSV file to write (one line per step)
+    #[clap(short = 'o', long, group = "destination")]
+    output: Option<String>,
+
+    /// URL of the MES endpoint each session is sent to as JSON; `{device_id}` is replaced by the device ID (only http is supported)
+    #[clap(long, group = "destination")]
+    endpoint: Option<String>,
+
+    /// Header added to requests sent to the endpoint, as `Name: value` (e.g. `Authorization: Bearer ...`)
+    #[clap(long = "header", requires = "endpoint")]
+    headers: Vec<String>,
+
+    /// Send sessions with PUT requests instead of POST
+    #[clap(long, requires = "endpoint")]
+    put: bool,
+}
+
+#[derive(Debug, Parser)]
+struct Aggregate {
+    /// Path of a recorded file (one per session, sessions of a device ID count as one device)
+    #[clap(short = 'i', long = "input", required = true)]
+    inputs: Vec<String>,
+
+    /// Path of the CSV file to write
+    #[clap(short = 'o', long)]
+    output: String,
+
+    /// Minimum number of devices an hour must have to be written
+    #[clap(short = 'k', long, default_value_t = aggregate::DEFAULT_MIN_DEVICES)]
+    min_devices: usize,
+}
+
+#[derive(Debug, Parser)]
+struct ImportJson {
+    /// Path of the JSON file (one message per line, as written by convert)
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Path of the recorded file to create
+    #[clap(short = 'o', long)]
+    output: String,
+
+    /// Version of the telemetry protocol to use for frames (1 to 3)
+    #[clap(long, default_value_t = parsers::MAXIMUM_SUPPORTED_VERSION)]
+    protocol_version: u8,
+}
+
+#[derive(Debug, Parser)]
+struct MergeCsv {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Path of the external CSV (with a header line, sorted by time)
+    #[clap(short = 'e', long)]
+    external: String,
+
+    /// Path of the merged CSV to create
+    #[clap(short = 'o', long)]
+    output: String,
+
+    /// Offset in microseconds to add to systicks to get wall-clock times (as logged by debug mode with time sync)
+    #[clap(long, allow_hyphen_values = true)]
+    clock_offset: i64,
+
+    /// Name of the column of the external CSV holding timestamps (seconds since UNIX epoch); the first column if not specified
+    #[clap(long)]
+    timestamp_column: Option<String>,
+
+    /// Character separating cells of the external CSV
+    #[clap(long, default_value = ",")]
+    delimiter: char,
+
+    /// Maximum distance between a data snapshot and the external row it is merged with, in microseconds
+    #[clap(long, default_value_t = merge::DEFAULT_TOLERANCE)]
+    tolerance: u64,
+}
+
+#[derive(Debug, Parser)]
+struct Compare {
+    /// Path of the recorded file of the first device (reference)
+    #[clap(short = 'a', long)]
+    first: String,
+
+    /// Path of the recorded file of the second device
+    #[clap(short = 'b', long)]
+    second: String,
+
+    /// Path of a CSV file to write per-cycle deltas to
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+
+    /// Time in microseconds to add to the times of the second recording (e.g. when it started that much later than the first one)
+    #[clap(long, default_value = "0", allow_hyphen_values = true)]
+    offset: i64,
+
+    /// Maximum distance between the ends of two cycles for them to be aligned, in microseconds
+    #[clap(long, default_value_t = compare::DEFAULT_ALIGNMENT_TOLERANCE)]
+    tolerance: u64,
+}
+
+#[derive(Debug, Parser)]
+struct CHeader {
+    /// Path of the header to write (stdout if not specified)
+    #[clap(short = 'o', long)]
+    output: Option<String>,
+}
+
+#[derive(Debug, Parser)]
+struct Gc {
+    /// Directory containing recordings (.record files)
+    #[clap(short = 'd', long)]
+    directory: String,
+
+    /// Delete recordings last modified more than this number of days ago
+    #[clap(long)]
+    max_age: Option<u64>,
+
+    /// Delete the oldest recordings until the directory holds at most this number of megabytes of recordings
+    #[clap(long)]
+    max_size: Option<u64>,
+
+    /// Never delete recordings containing annotations
+    #[clap(long)]
+    keep_annotated: bool,
+
+    /// Only list the recordings that would be deleted
+    #[clap(long)]
+    dry_run: bool,
+}
+
+#[cfg(feature = "upload")]
+#[derive(Debug, Parser)]
+struct Upload {
+    /// Directory containing recordings (.record files)
+    #[clap(short = 'd', long)]
+    directory: String,
+
+    /// URL of the tus endpoint creating uploads (only http is supported)
+    #[clap(short = 'u', long)]
+    url: Url,
+
+    /// Size of uploaded chunks in kilobytes
+    #[clap(long, default_value = "1024")]
+    chunk_size: usize,
+
+    /// Only upload recordings that were not modified for this number of seconds
+    #[clap(long, default_value = "60")]
+    min_idle: u64,
+
+    /// After uploading, delete uploaded recordings last modified more than this number of days ago
+    #[clap(long)]
+    max_age: Option<u64>,
+
+    /// After uploading, delete the oldest uploaded recordings until the directory holds at most this number of megabytes of recordings
+    #[clap(long)]
+    max_size: Option<u64>,
+
+    /// Never delete recordings containing annotations
+    #[clap(long)]
+    keep_annotated: bool,
+}
+
+#[derive(Debug, Parser)]
+struct Latency {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Maximum time a message may take to go through the whole pipeline, in microseconds
+    #[clap(long, default_value = "10000")]
+    budget: u64,
+}
+
+#[derive(Debug, Parser)]
+struct LintCapture {
+    /// Path of the capture
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// The capture contains raw bytes read from the serial port instead of a base64 recording
+    #[clap(long)]
+    raw: bool,
+}
+
+#[derive(Debug, Parser)]
+#[clap(group = ArgGroup::new("source").required(true))]
+struct Probe {
+    /// Address of the serial port
+    #[clap(short = 'p', long, group = "source")]
+    port: Option<String>,
+
+    /// URL of the WebSocket server
+    #[clap(short = 'w', long, group = "source")]
+    ws_url: Option<Url>,
+
+    /// Ask the WebSocket server to compress messages (permessage-deflate)
+    #[clap(long, requires = "ws-url")]
+    ws_deflate: bool,
+
+    /// Path of a capture to read instead of a device
+    #[clap(short = 'i', long, group = "source")]
+    input: Option<String>,
+
+    /// (with --input) The capture contains raw bytes read from the serial port instead of a base64 recording
+    #[clap(long, requires = "input")]
+    raw: bool,
+
+    /// Time to listen, in seconds
+    #[clap(short = 'd', long, default_value = "5")]
+    duration: u64,
+
+    /// Write the report as JSON instead of text
+    #[clap(long)]
+    json: bool,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+#[derive(Debug, Parser)]
+struct Trim {
+    /// Path of the recorded file
+    #[clap(short = 'i', long)]
+    input: String,
+
+    /// Path of the trimmed recording
+    #[clap(short = 'o', long)]
+    output: String,
+
+    #[clap(flatten)]
+    query: QueryArgs,
+}
+
+#[derive(Debug, Args)]
+struct QueryArgs {
+    /// If a systick value is specified, only messages with a greater or equal systick will be included
+    #[clap(long)]
+    from: Option<u64>,
+
+    /// If a systick value is specified, only messages with a smaller or equal systick will be included
+    #[clap(long)]
+    to: Option<u64>,
+
+    /// If a cycle number is specified, only messages of cycles with a greater or equal number will be included
+    #[clap(long)]
+    from_cycle: Option<u32>,
+
+    /// If a cycle number is specified, only messages of cycles with a smaller or equal number will be included
+    #[clap(long)]
+    to_cycle: Option<u32>,
+
+    /// Only include messages of cycles during which an alarm was active
+    #[clap(long)]
+    only_cycles_with_alarm: bool,
+
+    #[clap(flatten)]
+    filter: FilterArgs,
+}
+
+impl QueryArgs {
+    fn query(&self) -> Query {
+        let query = Query {
+            from_systick: self.from,
+            to_systick: self.to,
+            from_cycle: self.from_cycle,
+            to_cycle: self.to_cycle,
+            types: self.filter.message_filter(),
+            only_cycles_with_alarm: self.only_cycles_with_alarm,
+        };
+        if let Err(e) = query.validate() {
+            error!("invalid query: {}", e);
+            std::process::exit(1);
+        }
+        query
+    }
+}
+
+#[derive(Debug, Args)]
+struct FilterArgs {
+    /// Only include messages of these types (comma-separated, e.g. data-snapshot,alarm-trap)
+    #[clap(long, use_value_delimiter = true)]
+    only: Vec<MessageType>,
+
+    /// Exclude messages of these types (comma-separated, e.g. stopped)
+    #[clap(long, use_value_delimiter = true)]
+    exclude: Vec<MessageType>,
+}
+
+impl FilterArgs {
+    fn message_filter(&self) -> MessageFilter {
+        MessageFilter::new()
+            .only(self.only.iter().copied())
+            .exclude(self.exclude.iter().copied())
+    }
+}
+
+#[derive(Debug, Args)]
+struct ServeArgs {
+    /// Also serve telemetry messages to WebSocket clients on this address (e.g. 127.0.0.1:8080)
+    #[clap(long)]
+    serve: Option<String>,
+
+    #[clap(flatten)]
+    server: ServerArgs,
+}
+
+impl ServeArgs {
+    fn websocket_sink(&self) -> Option<WebSocketSink> {
+        let addr = self.serve.as_ref()?;
+        let ws_sink = WebSocketSink::bind_with_config(addr, self.server.server_config())
+            .expect("failed to start WebSocket server");
+        info!("serving telemetry messages on ws://{}", addr);
+        Some(ws_sink)
+    }
+}
+
+#[derive(Debug, Args)]
+struct ServerArgs {
+    /// Number of messages that can wait in the queue of each WebSocket client
+    #[clap(long, default_value = "512")]
+    serve_queue_size: usize,
+
+    /// What to do with new messages when the queue of a WebSocket client is full: drop-oldest, drop-newest, disconnect
+    #[clap(long, default_value = "drop-oldest")]
+    serve_drop_policy: DropPolicy,
+
+    /// Disconnect WebSocket clients whose queue stays full for this number of seconds (0 to never disconnect them)
+    #[clap(long, default_value = "10")]
+    serve_slow_client_timeout: u64,
+
+    /// Compress messages for WebSocket clients that support it (permessage-deflate)
+    #[clap(long)]
+    serve_deflate: bool,
+
+    /// How to send messages to WebSocket clients: binary (one telemetry frame per message, like the MakAir WebSocket relay) or json (one JSON text per message)
+    #[clap(long, default_value = "binary")]
+    serve_format: websocket::WebSocketMessageFormat,
+}
+
+impl ServerArgs {
+    fn server_config(&self) -> websocket::WebSocketServerConfig {
+        websocket::WebSocketServerConfig {
+            fan_out: FanOutConfig::new()
+                .queue_capacity(self.serve_queue_size)
+                .drop_policy(self.serve_drop_policy)
+                .slow_client_timeout(
+                    (self.serve_slow_client_timeout > 0)
+                        .then(|| std::time::Duration::from_secs(self.serve_slow_client_timeout)),
+                ),
+            permessage_deflate: self.serve_deflate,
+            format: self.serve_format,
+        }
+    }
+}
+
+#[derive(Debug, Args)]
+struct SerialArgs {
+    /// Time to wait for a byte before giving the hand back, in milliseconds
+    #[clap(long, default_value = "100")]
+    serial_timeout: u64,
+
+    /// Set the DTR line to this level once the port is open (true or false)
+    #[clap(long)]
+    dtr: Option<bool>,
+
+    /// Set the RTS line to this level once the port is open (true or false)
+    #[clap(long)]
+    rts: Option<bool>,
+
+    /// Drive DTR/RTS to the opposite level during this time (in milliseconds) before setting them, to reset bridges that need it
+    #[clap(long)]
+    toggle_lines: Option<u64>,
+
+    /// Report a break condition after this number of consecutive NUL bytes
+    #[clap(long)]
+    detect_break: Option<usize>,
+
+    /// Maximum number of control messages sent per second (others wait in a queue), or 0 to send them as soon as possible
+    #[clap(long, default_value = "10")]
+    max_control_rate: u32,
+}
+
+impl SerialArgs {
+    fn serial_config(&self) -> SerialConfig {
+        SerialConfig {
+            timeout: std::time::Duration::from_millis(self.serial_timeout),
+            dtr: self.dtr,
+            rts: self.rts,
+            toggle_duration: self.toggle_lines.map(std::time::Duration::from_millis),
+            break_threshold: self.detect_break,
+            control_rate_limit: (self.max_control_rate > 0).then(|| rate_limit::RateLimit {
+                frames_per_second: self.max_control_rate,
+                ..Default::default()
+            }),
+            ..Default::default()
+        }
+    }
+}
+
+#[derive(Debug, Parser)]
+struct DisableRpiWatchdog {
+    /// Address of the port to use
+    #[clap(short = 'p')]
+    port: String,
+
+    #[clap(flatten)]
+    serial: SerialArgs,
+}
+
+const THREAD_SLEEP_THROTTLE: std::time::Duration = std::time::Duration::from_millis(10);
+const HEARTBEAT_PERIOD: std::time::Duration = std::time::Duration::from_secs(30);
+const TIME_SYNC_PERIOD: std::time::Duration = std::time::Duration::from_secs(10);
+const JITTER_REPORT_PERIOD: std::time::Duration = std::time::Duration::from_secs(60);
+
+fn main() {
+    env_logger::init();
+    let opts: Opts = Opts::parse();
+    let decode = decoder::DecodeConfig::new().parser(opts.parser_config());
+
+    match opts.mode {
+        Mode::Debug(cfg) => debug(cfg, decode),
+        Mode::Record(cfg) => record(cfg, decode),
+        Mode::Daemon(cfg) => run_daemon(cfg, decode),
+        Mode::Play(cfg) => play(cfg, decode),
+        Mode::Simulate(cfg) => simulate(cfg),
+        Mode::Sniff(cfg) => sniff(cfg, decode),
+        Mode::Serve(cfg) => serve(cfg, decode),
+        Mode::Mqtt(cfg) => mqtt(cfg, decode),
+        Mode::Pipe(cfg) => pipe(cfg, decode),
+        Mode::Stats(cfg) => stats(cfg, decode),
+        Mode::Bandwidth(cfg) => bandwidth(cfg),
+        Mode::Control(cfg) => control(cfg, decode),
+        Mode::Storm(cfg) => storm(cfg),
+        Mode::Convert(cfg) => convert(cfg, decode),
+        Mode::ImportJson(cfg) => import_json(cfg),
+        Mode::MergeCsv(cfg) => merge_csv(cfg, decode),
+        Mode::Trim(cfg) => trim(cfg, decode),
+        Mode::Gc(cfg) => gc(cfg),
+        #[cfg(feature = "upload")]
+        Mode::Upload(cfg) => upload(cfg),
+        Mode::Annotate(cfg) => annotate(cfg),
+        Mode::Report(cfg) => report(cfg),
+        Mode::SettingsTimeline(cfg) => settings_timeline(cfg),
+        Mode::EolExport(cfg) => eol_export(cfg),
+        Mode::Aggregate(cfg) => aggregate(cfg),
+        Mode::Compare(cfg) => compare(cfg),
+        Mode::Latency(cfg) => latency(cfg),
+        Mode::LintCapture(cfg) => lint_capture(cfg),
+        Mode::Probe(cfg) => probe(cfg),
+        Mode::ListPorts => list_ports(),
+        #[cfg(feature = "plot")]
+        Mode::Plot(cfg) => plot(cfg, decode),
+        Mode::CHeader(cfg) => c_header(cfg),
+        Mode::DisableRpiWatchdog(cfg) => disable_rpi_watchdog(cfg, decode),
+    }
+}
+
+fn debug(cfg: Debug, decode: decoder::DecodeConfig) {
+    let formatter = cfg.format.formatter();
+    let (control_tx, control_rx): (Sender<ControlMessage>, Receiver<ControlMessage>) =
+        std::sync::mpsc::channel();
+
+    let heartbeat_tx = control_tx.clone();
+    std::thread::spawn(move || loop {
+        heartbeat_tx
+            .send(ControlMessage {
+                setting: ControlSetting::Heartbeat,
+                value: 0,
+            })
+            .expect("[heartbeat tx] failed to send heartbeat message");
+        std::thread::sleep(HEARTBEAT_PERIOD);
+    });
+
+    if cfg.random_control_messages {
+        let random_tx = control_tx.clone();
+        std::thread::spawn(move || loop {
+            std::thread::sleep(std::time::Duration::from_secs(3));
+            random_tx
+                .send(rand::random())
+                .expect("[control tx] failed to send control message");
+        });
+    };
+
+    let link_updates = link::subscribe_link_status();
+    std::thread::spawn(move || {
+        for update in link_updates {
+            info!("link to {}: {}", update.source, update.status);
+        }
+    });
+
+    let mut decimation = cfg.decimation.map(decimation::DecimationRequest::new);
+    if let Some(decimation) = decimation.as_mut() {
+        control_tx
+            .send(decimation.request())
+            .expect("[control tx] failed to send decimation request");
+    }
+    let decimation_tx = control_tx.clone();
+
+    let clock_synchronizer = Arc::new(Mutex::new(ClockSynchronizer::new()));
+    if cfg.time_sync {
+        let synchronizer = Arc::clone(&clock_synchronizer);
+        std::thread::spawn(move || loop {
+            let request = synchronizer
+                .lock()
+                .expect("[time sync] failed to lock clock synchronizer")
+                .request(SystemTime::now());
+            for message in request {
+                control_tx
+                    .send(message)
+                    .expect("[time sync] failed to send time sync message");
+            }
+            std::thread::sleep(TIME_SYNC_PERIOD);
+        });
+    };
+
+    let mut jitter = cfg.jitter.then(JitterAnalyzer::default);
+    let mut last_jitter_report = std::time::Instant::now();
+
+    let mut metrics = cfg.metrics_listen.as_ref().map(|address| {
+        let exporter = prometheus::PrometheusExporter::new();
+        exporter
+            .serve(address.as_str())
+            .expect("failed to serve metrics");
+        info!("serving metrics on http://{}/metrics", address);
+        exporter
+    });
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        if let Some(port) = &cfg.port {
+            gather_telemetry_with_config(
+                port,
+                tx,
+                None,
+                Some(control_rx),
+                &cfg.serial.serial_config().decode(decode.clone()),
+                None,
+            );
+        } else if let Some(url) = &cfg.ws_url {
+            let config = websocket::WebSocketClientConfig {
+                permessage_deflate: cfg.ws_deflate,
+                decode: decode.clone(),
+                ..websocket::WebSocketClientConfig::default()
+            };
+            gather_telemetry_from_ws_with_config(url, tx, None, Some(control_rx), &config, None)
+        } else if let Some(address) = cfg.bluetooth {
+            gather_telemetry_from_source(
+                bluetooth::RfcommSource::new(address, cfg.rfcomm_channel),
+                tx,
+                None,
+                Some(control_rx),
+                cfg.serial.serial_config().reconnect_delay,
+                &decode,
+                None,
+            )
+        } else {
+            unreachable!()
+        }
+    });
+    loop {
         match rx.try_recv() {
-            Ok(msg) => {
-                display_message(msg);
+            Ok(timed_message) => {
+                if let Some(metrics) = metrics.as_mut() {
+                    metrics.consume(&timed_message);
+                }
+                let TimedMessage {
+                    message: msg,
+                    received_at,
+                    received_instant,
+                    ..
+                } = timed_message;
+                if let (Some(jitter), Ok(message)) = (jitter.as_mut(), &msg) {
+                    jitter.push(message, Some(received_instant));
+                    if last_jitter_report.elapsed() >= JITTER_REPORT_PERIOD {
+                        info!(
+                            "intervals between data snapshots (systick): {}",
+                            jitter.systick
+                        );
+                        info!("intervals between data snapshots (host): {}", jitter.host);
+                        last_jitter_report = std::time::Instant::now();
+                    }
+                }
+                if let (Some(decimation), Ok(message)) = (decimation.as_mut(), &msg) {
+                    let support = decimation.support();
+                    if let Some(request) = decimation.push(message) {
+                        decimation_tx
+                            .send(request)
+                            .expect("[control tx] failed to send decimation request");
+                    }
+                    match decimation.support() {
+                        new_support if new_support == support => (),
+                        decimation::DecimationSupport::Supported { factor } => {
+                            info!("firmware sends one data snapshot out of {}", factor)
+                        }
+                        decimation::DecimationSupport::Unsupported => {
+                            warn!("firmware does not support data snapshot decimation")
+                        }
+                        decimation::DecimationSupport::Unknown => (),
+                    }
+                }
+                if let Ok(TelemetryMessage::ControlAck(ack)) = &msg {
+                    let new_mapping = clock_synchronizer
+                        .lock()
+                        .expect("failed to lock clock synchronizer")
+                        .handle_ack(ack, received_at);
+                    if let Some(mapping) = new_mapping {
+                        info!(
+                            "clock offset: {} µs (± {} µs)",
+                            mapping.offset_micros(),
+                            mapping.uncertainty.as_micros()
+                        );
+                    }
+                }
+                formatter.display(&msg);
             }
             Err(TryRecvError::Empty) => {
                 std::thread::sleep(THREAD_SLEEP_THROTTLE);
@@ -248,83 +1382,522 @@ fn debug(cfg: Debug) {
     }
 }
 
-fn record(cfg: Record) {
-    let file = OpenOptions::new()
-        .write(true)
-        .create_new(true)
-        .open(&cfg.output)
-        .expect("failed to create recording file");
-    let file_buffer = BufWriter::new(file);
+fn record(cfg: Record, decode: decoder::DecodeConfig) {
+    let mut sinks = SinkSet::new();
+    sinks.add("display", DisplaySink::new(cfg.format.formatter()));
+    if let Some(json_output) = &cfg.also_json {
+        let json_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(json_output)
+            .expect("failed to create JSON file");
+        sinks.add("json", JsonSink::new(LineWriter::new(json_file)));
+    }
+    if let Some(path) = &cfg.cycle_summaries {
+        sinks.add("cycle summaries", cycle_summary_sink(path));
+    }
+
+    let mut session_files = segmentation::SessionFiles::new(&cfg.output);
+    let file = if cfg.split_sessions {
+        session_files.create_next()
+    } else {
+        OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&cfg.output)
+    }
+    .expect("failed to create recording file");
+    let sync_file = file
+        .try_clone()
+        .expect("failed to open recording file for syncing");
+    let writer: Box<dyn Write + Send> = match &cfg.mirror {
+        Some(mirror_output) => {
+            let mirror_file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(mirror_output)
+                .expect("failed to create mirror recording file");
+            Box::new(mirror::MirrorWriter::new(
+                &cfg.output,
+                file,
+                mirror_output,
+                mirror_file,
+            ))
+        }
+        None => Box::new(file),
+    };
+    let mut recorder = if cfg.async_writer {
+        RecordingWriter::new_async(writer, cfg.flush_policy)
+    } else {
+        RecordingWriter::new(writer, cfg.flush_policy)
+    };
+    if cfg.flush_policy == FlushPolicy::OnAlarm {
+        recorder = recorder.sync_alarms(sync_file);
+    }
+    if cfg.split_sessions {
+        let detector = segmentation::SessionDetector::new(std::time::Duration::from_secs(
+            cfg.session_stop_timeout * 60,
+        ));
+        recorder = recorder.split_sessions(detector, session_files);
+    }
+    if cfg.annotate {
+        let annotator = recorder.annotator();
+        let last_systick = Arc::new(AtomicU64::new(0));
+        let last_systick_sink = Arc::clone(&last_systick);
+        sinks.add(
+            "systick",
+            FnSink::new(move |message: &TelemetryMessage| {
+                last_systick_sink.store(message.systick(), Ordering::Relaxed)
+            }),
+        );
+        std::thread::spawn(move || {
+            for line in std::io::stdin().lines() {
+                let text = line.expect("failed to read annotation from stdin");
+                if text.trim().is_empty() {
+                    continue;
+                }
+                let annotation = Annotation::new(last_systick.load(Ordering::Relaxed), text.trim());
+                match annotator.write(&annotation) {
+                    Ok(()) => info!("annotation recorded at systick {}", annotation.systick),
+                    Err(e) => error!("failed to record annotation: {:?}", e),
+                }
+            }
+        });
+    }
 
     let (heartbeat_tx, control_rx): (Sender<ControlMessage>, Receiver<ControlMessage>) =
         std::sync::mpsc::channel();
-    std::thread::spawn(move || loop {
-        heartbeat_tx
-            .send(ControlMessage {
-                setting: ControlSetting::Heartbeat,
-                value: 0,
-            })
-            .expect("[heartbeat tx] failed to send heartbeat message");
-        std::thread::sleep(HEARTBEAT_PERIOD);
+    let watchdog = watchdog::HeartbeatWatchdog::new(heartbeat_tx)
+        .heartbeat_period(HEARTBEAT_PERIOD)
+        .stall_timeout(std::time::Duration::from_secs(cfg.stall_timeout));
+    sinks.add("watchdog", watchdog.feeder());
+    let _watchdog = watchdog.spawn();
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        gather_telemetry_with_config(
+            &cfg.port,
+            tx,
+            Some(recorder),
+            Some(control_rx),
+            &cfg.serial.serial_config().decode(decode.clone()),
+            None,
+        );
     });
 
-    let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
+    dispatch(rx, &mut sinks);
+    panic!("channel to serial port thread was closed");
+}
+
+fn run_daemon(cfg: Daemon, decode: decoder::DecodeConfig) {
+    let config =
+        daemon::DaemonConfig::load(&cfg.config).expect("failed to load daemon configuration");
+    let mut sinks = SinkSet::new();
+    let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+    let empty = daemon::DaemonConfig::empty(&config.port);
+    let failed = daemon::apply_changes(empty.changes(&config), &commands_tx).unwrap_or_default();
+    let config = config.keeping_sinks(&empty, &failed);
+
+    let (heartbeat_tx, control_rx): (Sender<ControlMessage>, Receiver<ControlMessage>) =
         std::sync::mpsc::channel();
+    let watchdog = watchdog::HeartbeatWatchdog::new(heartbeat_tx)
+        .heartbeat_period(HEARTBEAT_PERIOD)
+        .stall_timeout(std::time::Duration::from_secs(cfg.stall_timeout));
+    sinks.add("watchdog", watchdog.feeder());
+    let _watchdog = watchdog.spawn();
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    let port = config.port.clone();
+    std::thread::spawn(move || {
+        gather_telemetry_with_config(
+            &port,
+            tx,
+            None,
+            Some(control_rx),
+            &cfg.serial.serial_config().decode(decode),
+            None,
+        );
+    });
+    let path = std::path::PathBuf::from(&cfg.config);
+    std::thread::spawn(move || daemon::watch_config(path, config, commands_tx));
+
+    dispatch_with_commands(rx, commands_rx, &mut sinks);
+    panic!("channel to serial port thread was closed");
+}
+
+fn sniff(cfg: Sniff, decode: decoder::DecodeConfig) {
+    let mut sinks = SinkSet::new();
+    sinks.add("display", DisplaySink::new(cfg.format.formatter()));
+
+    let recorder = cfg.output.as_ref().map(|output| {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(output)
+            .expect("failed to create recording file");
+        RecordingWriter::new(file, cfg.flush_policy)
+    });
+
+    // The UI sends heartbeats and control messages by itself: nothing is added to its traffic
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        sniff_telemetry(
+            &cfg.mcu_port,
+            &cfg.ui_port,
+            tx,
+            recorder,
+            &cfg.serial.serial_config().decode(decode.clone()),
+            None,
+        );
+    });
+
+    dispatch(rx, &mut sinks);
+    panic!("channel to serial port thread was closed");
+}
+
+fn serve(cfg: Serve, decode: decoder::DecodeConfig) {
+    let recorder = cfg.output.as_ref().map(|output| {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(output)
+            .expect("failed to create recording file");
+        RecordingWriter::new(file, cfg.flush_policy)
+    });
+
+    serve_telemetry_ws(
+        &cfg.port,
+        cfg.listen.as_str(),
+        cfg.server.server_config(),
+        recorder,
+        &cfg.serial.serial_config().decode(decode.clone()),
+        !cfg.read_only,
+    )
+    .expect("failed to start WebSocket server");
+    panic!("channel to serial port thread was closed");
+}
+
+fn mqtt(cfg: Mqtt, decode: decoder::DecodeConfig) {
+    let recorder = cfg.output.as_ref().map(|output| {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(output)
+            .expect("failed to create recording file");
+        RecordingWriter::new(file, cfg.flush_policy)
+    });
+    let mut config = mqtt::MqttConfig::new(cfg.broker, &cfg.topic_prefix).payload(cfg.payload);
+    if let Some(client_id) = &cfg.client_id {
+        config = config.client_id(client_id);
+    }
+
+    gather_telemetry_to_mqtt(
+        &cfg.port,
+        &config,
+        recorder,
+        &cfg.serial.serial_config().decode(decode.clone()),
+        !cfg.read_only,
+    );
+    panic!("channel to serial port thread was closed");
+}
+
+/// Parse a control message written as `<setting>=<value>`, the setting being a number or a name (e.g. `3=80` or `PEEP=80`)
+fn parse_control_line(line: &str) -> Result<ControlMessage, String> {
+    let (setting, value) = line
+        .split_once('=')
+        .ok_or_else(|| format!("expected <setting>=<value>, got {:?}", line))?;
+    let setting = parse_setting(setting)?;
+    let value = value
+        .trim()
+        .parse()
+        .map_err(|e| format!("invalid value {:?}: {}", value.trim(), e))?;
+    Ok(ControlMessage { setting, value })
+}
+
+/// Parse a setting written as a number or a name (e.g. `3` or `PEEP`)
+fn parse_setting(setting: &str) -> Result<ControlSetting, String> {
+    let setting = setting.trim();
+    match setting.parse::<u8>() {
+        Ok(number) => ControlSetting::try_from(number).map_err(|e| e.to_owned()),
+        Err(_) => ControlSetting::iter()
+            .find(|candidate| format!("{:?}", candidate).eq_ignore_ascii_case(setting))
+            .ok_or_else(|| format!("unknown setting {:?}", setting)),
+    }
+}
+
+#[cfg(unix)]
+fn open_fd(fd: i32) -> File {
+    use std::os::unix::io::FromRawFd;
+
+    // SAFETY: the file descriptor was handed over to this process by its parent, and is only owned by this `File`
+    unsafe { File::from_raw_fd(fd) }
+}
+
+#[cfg(not(unix))]
+fn open_fd(_fd: i32) -> File {
+    panic!("reading and writing file descriptors requires a Unix system");
+}
+
+/// Standard output of pipe mode, which ends the process when the reading side of the pipe is closed
+struct PipeStdout(std::io::Stdout);
+
+impl PipeStdout {
+    fn exit_if_closed<T>(result: std::io::Result<T>) -> std::io::Result<T> {
+        if let Err(e) = &result {
+            if e.kind() == std::io::ErrorKind::BrokenPipe {
+                info!("stdout was closed");
+                std::process::exit(0);
+            }
+        }
+        result
+    }
+}
+
+impl Write for PipeStdout {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        Self::exit_if_closed(self.0.write(buf))
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Self::exit_if_closed(self.0.flush())
+    }
+}
+
+fn pipe(cfg: Pipe, decode: decoder::DecodeConfig) {
+    use std::io::BufRead;
+
+    if let (Some(control_in), Some(control_out)) = (cfg.control_in, cfg.control_out) {
+        let control_in = std::io::BufReader::new(open_fd(control_in));
+        let mut control_out = open_fd(control_out);
+        std::thread::spawn(move || {
+            for line in control_in.lines() {
+                let line = line.expect("failed to read control messages");
+                if line.trim().is_empty() {
+                    continue;
+                }
+                match parse_control_line(&line) {
+                    Ok(message) => {
+                        info!("sending control message {}", message);
+                        control_out
+                            .write_all(&message.to_control_frame())
+                            .and_then(|_| control_out.flush())
+                            .expect("failed to write control frame");
+                    }
+                    Err(e) => error!("{}", e),
+                }
+            }
+        });
+    }
+
+    let mut sinks = SinkSet::new();
+    match cfg.format {
+        PipeFormat::Json => sinks.add(
+            "json",
+            JsonSink::new(LineWriter::new(PipeStdout(std::io::stdout()))),
+        ),
+        PipeFormat::Frames => sinks.add("frames", FrameSink::new(PipeStdout(std::io::stdout()))),
+    }
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        if let Err(e) = gather_telemetry_from_reader(
+            std::io::stdin(),
+            tx,
+            Some("stdin".to_owned()),
+            &decode,
+            None,
+        ) {
+            error!("failed to read stdin: {}", e);
+        }
+    });
+
+    dispatch(
+        rx,
+        &mut FilteredSink::new(cfg.filter.message_filter(), sinks),
+    );
+}
+
+fn play(cfg: Play, decode: decoder::DecodeConfig) {
+    let filter = cfg.filter.message_filter();
+    let mut sinks = SinkSet::new();
+    sinks.add("display", DisplaySink::new(cfg.format.formatter()));
+    if let Some(ws_sink) = cfg.serve.websocket_sink() {
+        sinks.add("websocket", ws_sink);
+    }
+    if cfg.detect_anomalies {
+        let monitor = AnomalyMonitor::new()
+            .detector(ZScoreDetector::new("peak-pressure", Metric::PeakPressure))
+            .detector(ZScoreDetector::new("peep", Metric::Peep))
+            .detector(ZScoreDetector::new("tidal-volume", Metric::TidalVolume));
+        sinks.add("anomalies", AnomalySink::new(monitor, None));
+    }
+    if let Some(path) = &cfg.cycle_summaries {
+        sinks.add("cycle summaries", cycle_summary_sink(path));
+    }
+
+    let file = File::open(cfg.input).expect("failed to play recorded file");
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    let enable_time_simulation = !cfg.full_blast;
+    std::thread::spawn(move || {
+        info!("start playing telemetry messages");
+        gather_telemetry_from_file_with_progress(
+            file,
+            tx,
+            enable_time_simulation,
+            makair_telemetry::progress::NoProgress,
+            &decode,
+            None,
+        );
+    });
+    let rx = if cfg.faults.is_empty() {
+        rx
+    } else {
+        inject_faults(rx, FaultScenario { faults: cfg.faults })
+    };
+
+    dispatch(rx, &mut FilteredSink::new(filter, sinks));
+    warn!("end of recording");
+}
+
+fn cycle_summary_sink(path: &str) -> summary::CycleSummarySink<LineWriter<File>> {
+    let file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)
+        .expect("failed to create cycle summaries file");
+    summary::CycleSummarySink::new(LineWriter::new(file))
+}
+
+#[cfg(feature = "s3")]
+fn create_s3_writer(location: &str) -> RecordingWriter {
+    use makair_telemetry::s3::{S3Config, S3Writer};
+
+    let config = S3Config::from_env().expect("failed to configure S3");
+    let writer = S3Writer::for_location(config, location).expect("failed to create S3 object");
+    RecordingWriter::new(writer, FlushPolicy::default())
+}
+
+#[cfg(not(feature = "s3"))]
+fn create_s3_writer(_location: &str) -> RecordingWriter {
+    panic!("recording to S3 requires the s3 feature");
+}
+
+fn simulate(cfg: Simulate) {
+    let filter = cfg.filter.message_filter();
+    let mut sinks = SinkSet::new();
+    sinks.add("display", DisplaySink::new(cfg.format.formatter()));
+    if let Some(output) = &cfg.output {
+        let writer = if output.starts_with("s3://") {
+            create_s3_writer(output)
+        } else {
+            let file = OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(output)
+                .expect("failed to create recording file");
+            RecordingWriter::new(file, FlushPolicy::default())
+        };
+        sinks.add("recording", RecordingSink::new(writer));
+    }
+    if let Some(ws_sink) = cfg.serve.websocket_sink() {
+        sinks.add("websocket", ws_sink);
+    }
+
+    let devices = if cfg.devices > 1 {
+        swarm("simulator", cfg.devices, cfg.patient)
+    } else {
+        vec![SimulatedDevice::with_preset("simulator", cfg.patient)]
+    };
+    let duration = cfg.duration.map(std::time::Duration::from_secs);
+    let enable_time_simulation = !cfg.full_blast;
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    info!(
+        "start simulating {} device(s) ventilating a {} patient",
+        devices.len(),
+        cfg.patient.name()
+    );
+    for device in devices {
+        let tx = tx.clone();
+        std::thread::spawn(move || {
+            simulate_telemetry(device, tx, duration, enable_time_simulation);
+        });
+    }
+    drop(tx);
+    let rx = if cfg.faults.is_empty() {
+        rx
+    } else {
+        inject_faults(rx, FaultScenario { faults: cfg.faults })
+    };
+
+    dispatch(rx, &mut FilteredSink::new(filter, sinks));
+}
+
+/// Inject faults into the telemetry of every device, each device following the scenario from its first message
+fn inject_faults(rx: Receiver<TimedMessage>, scenario: FaultScenario) -> Receiver<TimedMessage> {
+    let mut injectors: std::collections::HashMap<String, FaultInjector> =
+        std::collections::HashMap::new();
+    let (tx, faulty_rx) = std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        gather_telemetry(&cfg.port, tx, Some(file_buffer), Some(control_rx));
-    });
-    loop {
-        match rx.try_recv() {
-            Ok(msg) => {
-                display_message(msg);
-            }
-            Err(TryRecvError::Empty) => {
-                std::thread::sleep(THREAD_SLEEP_THROTTLE);
-            }
-            Err(TryRecvError::Disconnected) => {
-                panic!("channel to serial port thread was closed");
+        for timed_message in rx.iter() {
+            let messages = match &timed_message.message {
+                Ok(message) => injectors
+                    .entry(message.device_id())
+                    .or_insert_with(|| FaultInjector::new(scenario.clone()))
+                    .handle(message),
+                Err(_) => {
+                    if tx.send(timed_message).is_err() {
+                        break;
+                    }
+                    continue;
+                }
+            };
+            for message in messages {
+                let faulty_message = TimedMessage {
+                    message: Ok(message),
+                    received_at: timed_message.received_at,
+                    received_instant: timed_message.received_instant,
+                    source: timed_message.source.clone(),
+                };
+                if tx.send(faulty_message).is_err() {
+                    return;
+                }
             }
         }
-    }
+    });
+    faulty_rx
 }
 
-fn play(cfg: Play) {
-    let file = File::open(cfg.input).expect("failed to play recorded file");
+fn stats(cfg: Stats, decode: decoder::DecodeConfig) {
+    let mut runner = QueryRunner::new(cfg.query.query());
+    let file = File::open(cfg.input).expect("failed to open given recorded file");
+    let progress = ProgressBarCallback::new(file.metadata().ok().map(|m| m.len()));
+
     let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
         std::sync::mpsc::channel();
-    let enable_time_simulation = !cfg.full_blast;
     std::thread::spawn(move || {
-        info!("start playing telemetry messages");
-        gather_telemetry_from_file(file, tx, enable_time_simulation);
+        gather_telemetry_from_file_with_progress(file, tx, false, progress, &decode, None);
     });
 
+    let mut telemetry_messages: Vec<TelemetryMessage> = Vec::new();
+
     loop {
         match rx.try_recv() {
-            Ok(msg) => {
-                display_message(msg);
+            Ok(channel_message) => {
+                if let Ok(message) = channel_message {
+                    telemetry_messages.extend(runner.push(message));
+                }
             }
             Err(TryRecvError::Empty) => {
                 std::thread::sleep(THREAD_SLEEP_THROTTLE);
             }
             Err(TryRecvError::Disconnected) => {
-                warn!("end of recording");
-                std::process::exit(0);
+                telemetry_messages.extend(runner.finish());
+                break;
             }
         }
     }
-}
-
-fn stats(cfg: Stats) {
-    let file = File::open(cfg.input).expect("failed to open given recorded file");
-
-    let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
-        std::sync::mpsc::channel();
-    std::thread::spawn(move || {
-        gather_telemetry_from_file(file, tx, false);
-    });
-
-    let mut telemetry_messages: Vec<TelemetryMessage> = Vec::new();
 
+    let mut jitter = JitterAnalyzer::new(cfg.jitter_bucket);
     let mut nb_boot_messages: u32 = 0;
     let mut nb_alarm_traps: u32 = 0;
     let mut nb_data_snapshots: u32 = 0;
@@ -333,85 +1906,146 @@ fn stats(cfg: Stats) {
     let mut nb_control_ack: u32 = 0;
     let mut nb_fatal_error: u32 = 0;
     let mut nb_eol_test_snapshots: u32 = 0;
+    let mut nb_unknown_messages: u32 = 0;
 
-    loop {
-        match rx.try_recv() {
-            Ok(channel_message) => {
-                if let Ok(message) = channel_message {
-                    match message {
-                        TelemetryMessage::BootMessage(_) => {
-                            nb_boot_messages += 1;
-                        }
-                        TelemetryMessage::AlarmTrap(_) => {
-                            nb_alarm_traps += 1;
-                        }
-                        TelemetryMessage::DataSnapshot(_) => {
-                            nb_data_snapshots += 1;
-                        }
-                        TelemetryMessage::MachineStateSnapshot(_) => {
-                            nb_machine_state_snapshots += 1;
-                        }
-                        TelemetryMessage::StoppedMessage(_) => {
-                            nb_stopped_messages += 1;
-                        }
-                        TelemetryMessage::ControlAck(_) => {
-                            nb_control_ack += 1;
-                        }
-                        TelemetryMessage::FatalError(_) => {
-                            nb_fatal_error += 1;
-                        }
-                        TelemetryMessage::EolTestSnapshot(_) => {
-                            nb_eol_test_snapshots += 1;
-                        }
-                    }
-                    telemetry_messages.push(message);
-                }
+    for message in &telemetry_messages {
+        jitter.push(message, None);
+        match message {
+            TelemetryMessage::BootMessage(_) => {
+                nb_boot_messages += 1;
             }
-            Err(TryRecvError::Empty) => {
-                std::thread::sleep(THREAD_SLEEP_THROTTLE);
+            TelemetryMessage::AlarmTrap(_) => {
+                nb_alarm_traps += 1;
             }
-            Err(TryRecvError::Disconnected) => {
-                println!("Statistics");
-                println!("Nb BootMessages: {}", nb_boot_messages);
-                println!("Nb AlarmTraps: {}", nb_alarm_traps);
-                println!("Nb DataSnapshots: {}", nb_data_snapshots);
-                println!("Nb MachineStateSnapshot: {}", nb_machine_state_snapshots);
-                println!("Nb StoppedMessage: {}", nb_stopped_messages);
-                println!("Nb ControlAck: {}", nb_control_ack);
-                println!("Nb FatalError: {}", nb_fatal_error);
-                println!("Nb EolTestSnapshot: {}", nb_eol_test_snapshots);
-                println!(
-                    "Estimated duration: {:.3} seconds",
-                    compute_duration(telemetry_messages) as f32 / 1000_f32
-                );
-                std::process::exit(0);
+            TelemetryMessage::DataSnapshot(_) => {
+                nb_data_snapshots += 1;
+            }
+            TelemetryMessage::MachineStateSnapshot(_) => {
+                nb_machine_state_snapshots += 1;
+            }
+            TelemetryMessage::StoppedMessage(_) => {
+                nb_stopped_messages += 1;
+            }
+            TelemetryMessage::ControlAck(_) => {
+                nb_control_ack += 1;
+            }
+            TelemetryMessage::FatalError(_) => {
+                nb_fatal_error += 1;
+            }
+            TelemetryMessage::EolTestSnapshot(_) => {
+                nb_eol_test_snapshots += 1;
+            }
+            // Messages of types added after this tool was written are counted as unknown
+            _ => {
+                nb_unknown_messages += 1;
             }
         }
     }
+
+    println!("Statistics");
+    println!("Nb BootMessages: {}", nb_boot_messages);
+    println!("Nb AlarmTraps: {}", nb_alarm_traps);
+    println!("Nb DataSnapshots: {}", nb_data_snapshots);
+    println!("Nb MachineStateSnapshot: {}", nb_machine_state_snapshots);
+    println!("Nb StoppedMessage: {}", nb_stopped_messages);
+    println!("Nb ControlAck: {}", nb_control_ack);
+    println!("Nb FatalError: {}", nb_fatal_error);
+    println!("Nb EolTestSnapshot: {}", nb_eol_test_snapshots);
+    println!("Nb unknown messages: {}", nb_unknown_messages);
+    if runner.skipped() != 0 {
+        println!("Nb messages excluded by the query: {}", runner.skipped());
+    }
+    println!(
+        "Estimated duration: {:.3} seconds",
+        compute_duration(telemetry_messages) as f32 / 1000_f32
+    );
+    print!(
+        "Intervals between DataSnapshots (systick): {}",
+        jitter.systick
+    );
+}
+
+fn bandwidth(cfg: Bandwidth) {
+    let report = bandwidth::analyze_recording(&cfg.input, cfg.baud_rate)
+        .expect("failed to read recorded file");
+
+    if cfg.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("failed to serialize report")
+        );
+    } else {
+        print!("{}", report);
+    }
 }
 
-fn control(cfg: Control) {
+fn control(cfg: Control, decode: decoder::DecodeConfig) {
+    let formatter = cfg.format.formatter();
     let setting = ControlSetting::try_from(cfg.setting).expect("invalid control setting passed");
-    let value = cfg.value;
+    let message = ControlMessage {
+        setting,
+        value: cfg.value,
+    };
+
+    let expected_value = setting.clamp(message.value);
+    if expected_value != message.value {
+        warn!(
+            "{:?} will probably be set to {} instead of {} by the firmware",
+            setting, expected_value, message.value
+        );
+    }
+
+    let mut session = ControlSession::new();
+    session.sent(&message);
 
     let (control_tx, control_rx): (Sender<ControlMessage>, Receiver<ControlMessage>) =
         std::sync::mpsc::channel();
     std::thread::spawn(move || {
         std::thread::sleep(std::time::Duration::from_secs(3));
         control_tx
-            .send(ControlMessage { setting, value })
+            .send(message)
             .expect("[control tx] failed to send control message");
     });
 
     let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
         std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        gather_telemetry(&cfg.port, tx, None, Some(control_rx));
+        gather_telemetry_with_config(
+            &cfg.port,
+            tx,
+            None,
+            Some(control_rx),
+            &cfg.serial.serial_config().decode(decode.clone()),
+            None,
+        );
     });
     loop {
         match rx.try_recv() {
             Ok(msg) => {
-                display_message(msg);
+                if let Ok(TelemetryMessage::ControlAck(ack)) = &msg {
+                    match session.handle_ack(ack) {
+                        Some(ControlEvent::Acknowledged { setting, value }) => {
+                            info!("{:?} was set to {}", setting, value);
+                        }
+                        Some(ControlEvent::ValueClampedByFirmware {
+                            setting,
+                            sent,
+                            applied,
+                        }) => {
+                            warn!(
+                                "{:?} was set to {} instead of {} by the firmware",
+                                setting, applied, sent
+                            );
+                        }
+                        Some(ControlEvent::ExternalSettingChange { setting, value, .. }) => {
+                            info!("{:?} was changed to {} on the device", setting, value);
+                        }
+                        Some(ControlEvent::DuplicateAck { .. })
+                        | Some(ControlEvent::BatchCompleted { .. })
+                        | None => (),
+                    }
+                }
+                formatter.display(&msg);
             }
             Err(TryRecvError::Empty) => {
                 std::thread::sleep(THREAD_SLEEP_THROTTLE);
@@ -431,31 +2065,41 @@ fn storm(cfg: Storm) {
     let full_blast = cfg.full_blast;
     let (tx, rx): (Sender<Vec<u8>>, Receiver<Vec<u8>>) = std::sync::mpsc::channel();
 
-    let mut generators: Vec<&'static str> = vec![];
-    if cfg.valid {
-        generators.push("valid");
-    };
-    if cfg.bytes {
-        generators.push("bytes");
-    };
-    if cfg.wrong_crc {
-        generators.push("wrong_crc");
-    };
-    if generators.is_empty() {
-        panic!("You must specify at least one generator; use '-h' to see the list");
+    let mut config = storm::StormConfig::new();
+    for (enabled, generator) in [
+        (cfg.valid, storm::StormGenerator::Valid),
+        (cfg.bytes, storm::StormGenerator::Bytes),
+        (cfg.wrong_crc, storm::StormGenerator::WrongCrc),
+    ] {
+        if enabled {
+            config = config.generator(generator, 1);
+        }
+    }
+    for GeneratorWeight(generator, weight) in cfg.weights {
+        config = config.generator(generator, weight);
     }
+    for SettingWeight(setting, weight) in cfg.setting_weights {
+        config = config.setting_weight(setting, weight);
+    }
+    if let Some(seed) = cfg.seed {
+        config = config.seed(seed);
+    }
+    let storm = match storm::Storm::new(&config) {
+        Ok(storm) => storm,
+        Err(e) => {
+            error!("{}; use '-h' to see the list of generators", e);
+            std::process::exit(1);
+        }
+    };
+    info!(
+        "storm seed is {} (use --seed {} to replay this storm)",
+        storm.seed(),
+        storm.seed()
+    );
 
     std::thread::spawn(move || {
-        use rand::seq::SliceRandom;
-
         std::thread::sleep(std::time::Duration::from_secs(3));
-        loop {
-            let bytes = match generators.choose(&mut rand::thread_rng()) {
-                Some(&"valid") => gen_random_message_bytes(),
-                Some(&"bytes") => gen_random_bytes(),
-                Some(&"wrong_crc") => gen_random_message_with_wrong_crc(),
-                _ => unreachable!(),
-            };
+        for bytes in storm {
             tx.send(bytes).expect("[tx] failed to send bytes");
             if !full_blast {
                 std::thread::sleep(std::time::Duration::from_millis(15));
@@ -499,86 +2143,613 @@ fn storm(cfg: Storm) {
     }
 }
 
-fn convert(cfg: Convert) {
-    use std::io::Write;
+fn convert(cfg: Convert, decode: decoder::DecodeConfig) {
     use std::path::Path;
 
-    let from = cfg.from.unwrap_or(u64::MIN);
-    let to = cfg.to.unwrap_or(u64::MAX);
-    let mut skipped = 0u64;
+    let query = cfg.query.query();
+    let input_file_name = cfg.input;
+    let input_file = File::open(&input_file_name).expect("failed to open recorded file");
+    let progress = ProgressBarCallback::new(input_file.metadata().ok().map(|m| m.len()));
+
+    let annotations =
+        read_annotations(File::open(&input_file_name).expect("failed to open recorded file"))
+            .expect("failed to read annotations")
+            .into_iter()
+            .filter(|annotation| query.matches_systick(annotation.systick));
+
+    let mut manifest = manifest::ExportManifest::for_recording(&input_file_name)
+        .expect("failed to hash recorded file")
+        .parameter("format", format!("{:?}", cfg.format).to_lowercase())
+        .parameter("query", format!("{:?}", query))
+        .parameter("parser", format!("{:?}", decode.parser));
+
+    let export_sink: Box<dyn TelemetrySink> = if cfg.format.is_streamed() {
+        let output_file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&cfg.output)
+            .expect("failed to create recording file");
+
+        let gts_source_label = if cfg.format == Format::Gts && !cfg.gts_disable_source_label {
+            cfg.gts_source_label.or_else(|| {
+                Path::new(&input_file_name)
+                    .file_name()
+                    .map(|ostr| ostr.to_string_lossy().into_owned())
+            })
+        } else {
+            None
+        };
+        if let Some(label) = &gts_source_label {
+            manifest = manifest.parameter("gts_source_label", label);
+        }
+        if let Some(offset) = cfg.gts_clock_offset.filter(|_| cfg.format != Format::Json) {
+            manifest = manifest.parameter("gts_clock_offset", offset);
+        }
+
+        let mut writer = BufWriter::new(output_file);
+        writer
+            .write_all(manifest_header(&manifest, &cfg.format).as_bytes())
+            .expect("failed to write to output file");
+
+        Box::new(ExportSink {
+            writer,
+            format: cfg.format,
+            gts_source_label,
+            gts_clock_offset: cfg.gts_clock_offset,
+            annotations: annotations.collect(),
+        })
+    } else if cfg.format == Format::Csv {
+        let create = |path: &Path| {
+            OpenOptions::new()
+                .write(true)
+                .create_new(true)
+                .open(path)
+                .map(BufWriter::new)
+                .expect("failed to create output file")
+        };
+        let output = Path::new(&cfg.output);
+        // A comment line would get in the way of spreadsheets, so the manifest is written next to the CSV files
+        create(&csv_export_path(output, "manifest.json"))
+            .write_all(manifest.to_json_record().as_bytes())
+            .expect("failed to write manifest");
+        Box::new(CsvExportSink::new(
+            create(&csv_export_path(output, "data_snapshots.csv")),
+            create(&csv_export_path(output, "machine_states.csv")),
+        ))
+    } else {
+        Box::new(BiosignalExportSink {
+            output: cfg.output.into(),
+            format: cfg.format,
+            annotations: annotations.collect(),
+            messages: Vec::new(),
+            manifest,
+        })
+    };
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        info!("start playing telemetry messages");
+        gather_telemetry_from_file_with_progress(input_file, tx, false, progress, &decode, None);
+    });
+
+    let mut query_sink = QuerySink::new(query, export_sink);
+    dispatch(rx, &mut query_sink);
+    if query_sink.skipped() != 0 {
+        info!("{} records were skipped", query_sink.skipped());
+    }
+    warn!("end of recording");
+}
+
+fn annotate(cfg: Annotate) {
+    match (cfg.systick, cfg.text) {
+        (Some(systick), Some(text)) => {
+            let file = OpenOptions::new()
+                .append(true)
+                .open(&cfg.input)
+                .expect("failed to open recorded file");
+            RecordingWriter::new(file, FlushPolicy::EveryMessage)
+                .write_annotation(&Annotation::new(systick, text))
+                .expect("failed to write annotation");
+        }
+        _ => {
+            let file = File::open(&cfg.input).expect("failed to open recorded file");
+            for annotation in read_annotations(file).expect("failed to read recorded file") {
+                println!("{}: {}", annotation.systick, annotation.text);
+            }
+        }
+    }
+}
+
+#[cfg(feature = "plot")]
+fn plot(cfg: Plot, decode: decoder::DecodeConfig) {
+    use makair_telemetry::plot::{PlotOptions, Waveforms};
+
+    let mut runner = QueryRunner::new(cfg.query.query());
+    let reader = TelemetryFileReader::open(&cfg.input)
+        .expect("failed to open recorded file")
+        .parser_config(decode.parser);
+
+    let mut messages = Vec::new();
+    for message in reader.filter_map(Result::ok) {
+        messages.extend(runner.push(message));
+    }
+    messages.extend(runner.finish());
 
-    if from > to {
-        error!("systick in --from cannot be greater than systick in --to");
+    let options = PlotOptions {
+        width: cfg.width,
+        height: cfg.height,
+        title: cfg.title,
+    };
+    if let Err(e) = Waveforms::from_messages(&messages).render(&cfg.output, &options) {
+        error!("{}", e);
         std::process::exit(1);
     }
+}
 
-    let input_file_name = cfg.input;
-    let input_file = File::open(&input_file_name).expect("failed to open recorded file");
+fn report(cfg: Report) {
+    use makair_telemetry::report::SessionReport;
+    use std::path::Path;
+
+    let title = cfg.title.unwrap_or_else(|| {
+        Path::new(&cfg.input)
+            .file_name()
+            .map(|name| name.to_string_lossy().into_owned())
+            .unwrap_or_default()
+    });
+    let messages = makair_telemetry::testing::read_recording(&cfg.input)
+        .expect("failed to read recorded file");
+    let annotations =
+        read_annotations(File::open(&cfg.input).expect("failed to open recorded file"))
+            .expect("failed to read annotations");
+
+    let mut report = SessionReport::from_messages(&title, &messages);
+    report.annotations = annotations;
+    #[cfg(feature = "plot")]
+    if let Err(e) = report.add_thumbnails(&messages) {
+        warn!("failed to render waveform thumbnails: {}", e);
+    }
+
+    std::fs::write(&cfg.output, report.to_html()).expect("failed to write report");
+}
+
+fn settings_timeline(cfg: SettingsTimeline) {
+    let messages = makair_telemetry::testing::read_recording(&cfg.input)
+        .expect("failed to read recorded file");
+    let timeline = timeline::SettingsTimeline::from_messages(&messages);
+    let export = if cfg.json {
+        timeline.to_json()
+    } else {
+        timeline.to_csv()
+    };
+    std::fs::write(&cfg.output, export).expect("failed to write settings timeline");
+}
+
+fn eol_export(cfg: EolExport) {
+    let messages = makair_telemetry::testing::read_recording(&cfg.input)
+        .expect("failed to read recorded file");
+    let sessions = eol::EolSessions::from_messages(&messages);
+    info!("found {} end of line test session(s)", sessions.len());
+
+    if let Some(output) = &cfg.output {
+        let mut csv = eol::EolSession::csv_header().to_owned();
+        for session in &sessions {
+            csv.push_str(&session.to_csv_lines());
+        }
+        std::fs::write(output, csv).expect("failed to write end of line test sessions");
+    }
+
+    if let Some(url) = &cfg.endpoint {
+        let mut endpoint = mes::MesEndpoint::new(url);
+        if cfg.put {
+            endpoint = endpoint.put();
+        }
+        for header in &cfg.headers {
+            let (name, value) = header
+                .split_once(':')
+                .expect("headers must be formatted as `Name: value`");
+            endpoint = endpoint.header(name.trim(), value.trim());
+        }
+        let mut failed = false;
+        for session in &sessions {
+            match endpoint.send(session) {
+                Ok(()) => info!(
+                    "sent {} end of line test session of {}",
+                    session.outcome.name(),
+                    session.device_id
+                ),
+                Err(e) => {
+                    error!(
+                        "failed to send end of line test session of {}: {}",
+                        session.device_id, e
+                    );
+                    failed = true;
+                }
+            }
+        }
+        if failed {
+            std::process::exit(1);
+        }
+    }
+}
+
+fn aggregate(cfg: Aggregate) {
+    let mut aggregator = aggregate::Aggregator::new(cfg.min_devices);
+    for input in &cfg.inputs {
+        let messages =
+            makair_telemetry::testing::read_recording(input).expect("failed to read recorded file");
+        aggregator.add_session(&messages);
+    }
+
+    let export = aggregator.export();
+    if export.suppressed_hours > 0 {
+        warn!(
+            "{} hours had fewer than {} devices and were left out",
+            export.suppressed_hours, cfg.min_devices
+        );
+    }
+    std::fs::write(&cfg.output, export.to_csv()).expect("failed to write aggregated statistics");
+}
+
+fn gc(cfg: Gc) {
+    use makair_telemetry::retention::{apply_retention, RetentionPolicy};
+
+    let mut policy = RetentionPolicy::new().keep_annotated(cfg.keep_annotated);
+    if let Some(days) = cfg.max_age {
+        policy = policy.max_age(std::time::Duration::from_secs(days * 24 * 3600));
+    }
+    if let Some(megabytes) = cfg.max_size {
+        policy = policy.max_total_size(megabytes * 1_000_000);
+    }
+
+    let report =
+        apply_retention(&cfg.directory, &policy, cfg.dry_run).expect("failed to apply retention");
+    for path in &report.deleted {
+        println!(
+            "{}{}",
+            if cfg.dry_run {
+                "would delete "
+            } else {
+                "deleted "
+            },
+            path.display()
+        );
+    }
+    println!(
+        "{} recordings ({} bytes) {}, {} recordings ({} bytes) kept",
+        report.deleted.len(),
+        report.freed_bytes,
+        if cfg.dry_run { "to delete" } else { "deleted" },
+        report.kept,
+        report.kept_bytes
+    );
+}
+
+#[cfg(feature = "upload")]
+fn upload(cfg: Upload) {
+    use makair_telemetry::retention::{apply_retention, RetentionPolicy};
+    use makair_telemetry::upload::{TusTarget, Uploader};
+
+    let mut uploader = Uploader::new(TusTarget::new(cfg.url))
+        .chunk_size(cfg.chunk_size * 1024)
+        .min_idle(std::time::Duration::from_secs(cfg.min_idle));
+    match uploader.upload_directory(&cfg.directory) {
+        Ok(uploaded) => {
+            for path in uploaded {
+                println!("uploaded {}", path.display());
+            }
+        }
+        Err(e) => error!("upload stopped, it will resume on next run: {}", e),
+    }
+
+    if cfg.max_age.is_some() || cfg.max_size.is_some() {
+        let mut policy = RetentionPolicy::new()
+            .uploaded_only(true)
+            .keep_annotated(cfg.keep_annotated);
+        if let Some(days) = cfg.max_age {
+            policy = policy.max_age(std::time::Duration::from_secs(days * 24 * 3600));
+        }
+        if let Some(megabytes) = cfg.max_size {
+            policy = policy.max_total_size(megabytes * 1_000_000);
+        }
+        let report =
+            apply_retention(&cfg.directory, &policy, false).expect("failed to apply retention");
+        for path in &report.deleted {
+            println!("deleted {}", path.display());
+        }
+    }
+}
+
+fn import_json(cfg: ImportJson) {
+    if !(1..=parsers::MAXIMUM_SUPPORTED_VERSION).contains(&cfg.protocol_version) {
+        error!(
+            "protocol version must be between 1 and {}",
+            parsers::MAXIMUM_SUPPORTED_VERSION
+        );
+        std::process::exit(1);
+    }
+    let input_file = File::open(&cfg.input).expect("failed to open JSON file");
     let output_file = OpenOptions::new()
         .write(true)
         .create_new(true)
         .open(&cfg.output)
         .expect("failed to create recording file");
-    let mut output_buffer = BufWriter::new(output_file);
+    let mut recording_writer =
+        RecordingWriter::new(BufWriter::new(output_file), FlushPolicy::default());
+    let import = json_to_recording(
+        std::io::BufReader::new(input_file),
+        &mut recording_writer,
+        cfg.protocol_version,
+    )
+    .expect("failed to write recording file");
+    info!(
+        "{} messages and {} annotations were imported, {} lines were skipped",
+        import.messages, import.annotations, import.skipped
+    );
+}
 
-    let gts_source_label = if cfg.format == Format::Gts && !cfg.gts_disable_source_label {
-        cfg.gts_source_label.or_else(|| {
-            Path::new(&input_file_name)
-                .file_name()
-                .map(|ostr| ostr.to_string_lossy().into_owned())
-        })
+fn merge_csv(cfg: MergeCsv, decode: decoder::DecodeConfig) {
+    let reader = TelemetryFileReader::open(&cfg.input)
+        .expect("failed to open recorded file")
+        .parser_config(decode.parser);
+    let external_file = File::open(&cfg.external).expect("failed to open external CSV");
+    let output_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&cfg.output)
+        .expect("failed to create merged CSV");
+    let external = merge::ExternalCsv::new(
+        std::io::BufReader::new(external_file),
+        cfg.timestamp_column.as_deref(),
+        cfg.delimiter,
+    )
+    .expect("failed to read external CSV");
+    let mut merger = merge::CsvMerger::new(external, BufWriter::new(output_file), cfg.clock_offset)
+        .expect("failed to write merged CSV")
+        .tolerance(cfg.tolerance);
+
+    for message in reader.filter_map(Result::ok) {
+        if let Err(e) = merger.push(&message) {
+            error!("failed to merge telemetry: {}", e);
+            std::process::exit(1);
+        }
+    }
+    let summary = merger.finish().expect("failed to write merged CSV");
+    info!(
+        "{} data snapshots were written, {} of them matched an external row",
+        summary.rows, summary.matched
+    );
+}
+
+fn compare(cfg: Compare) {
+    let first = testing::read_recording(&cfg.first).expect("failed to read recorded file");
+    let second = testing::read_recording(&cfg.second).expect("failed to read recorded file");
+    let comparison = compare::CycleAligner::new()
+        .offset(cfg.offset)
+        .tolerance(cfg.tolerance)
+        .compare(&first, &second);
+
+    println!(
+        "{} aligned cycles ({} cycles of the first device and {} of the second one have no counterpart)",
+        comparison.pairs.len(),
+        comparison.unmatched_a,
+        comparison.unmatched_b
+    );
+    println!(
+        "{:<18}{:>8}{:>10}{:>10}{:>10}{:>10}{:>10}",
+        "metric (b - a)", "cycles", "mean", "std dev", "mean abs", "min", "max"
+    );
+    for summary in comparison.summaries().into_iter().flatten() {
+        println!(
+            "{:<18}{:>8}{:>10.1}{:>10.1}{:>10.1}{:>10.1}{:>10.1}",
+            summary.metric.name(),
+            summary.count,
+            summary.mean,
+            summary.std_dev,
+            summary.mean_absolute,
+            summary.min,
+            summary.max
+        );
+    }
+
+    if let Some(output) = &cfg.output {
+        std::fs::write(output, comparison.to_csv()).expect("failed to write CSV file");
+    }
+}
+
+fn trim(cfg: Trim, decode: decoder::DecodeConfig) {
+    let query = cfg.query.query();
+    let input_file = File::open(&cfg.input).expect("failed to open recorded file");
+    let progress = ProgressBarCallback::new(input_file.metadata().ok().map(|m| m.len()));
+    let output_file = OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(&cfg.output)
+        .expect("failed to create recording file");
+    let recording_writer =
+        RecordingWriter::new(BufWriter::new(output_file), FlushPolicy::default());
+    let annotator = recording_writer.annotator();
+    let recording_sink = RecordingSink::new(recording_writer);
+    let annotations =
+        read_annotations(File::open(&cfg.input).expect("failed to open recorded file"))
+            .expect("failed to read annotations");
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    std::thread::spawn(move || {
+        gather_telemetry_from_file_with_progress(input_file, tx, false, progress, &decode, None);
+    });
+
+    let mut query_sink = QuerySink::new(query.clone(), recording_sink);
+    dispatch(rx, &mut query_sink);
+    for annotation in annotations
+        .iter()
+        .filter(|a| query.matches_systick(a.systick))
+    {
+        annotator
+            .write(annotation)
+            .expect("failed to write annotation");
+    }
+    info!("{} records were skipped", query_sink.skipped());
+}
+
+fn latency(cfg: Latency) {
+    let messages = testing::read_recording(&cfg.input).expect("failed to read recorded file");
+    let mut harness = latency::LatencyHarness::new()
+        .adapter(
+            "rolling pressure",
+            rolling::RollingMetric::new(rolling::selectors::pressure, rolling::Window::Cycles(1)),
+        )
+        .adapter("volume integrator", volume::VolumeIntegrator::new())
+        .adapter("control session", ControlSession::new())
+        .sink(
+            "recording",
+            RecordingSink::new(RecordingWriter::new(
+                std::io::sink(),
+                FlushPolicy::EveryMessage,
+            )),
+        )
+        .sink("json", JsonSink::new(std::io::sink()));
+    let report = harness.run(&messages);
+    print!("{}", report);
+
+    let budget = std::time::Duration::from_micros(cfg.budget);
+    if report.within_budget(budget) {
+        println!(
+            "Every message went through the pipeline within {} µs",
+            cfg.budget
+        );
+    } else {
+        println!(
+            "Some messages took more than {} µs to go through the pipeline",
+            cfg.budget
+        );
+        std::process::exit(1);
+    }
+}
+
+fn lint_capture(cfg: LintCapture) {
+    let file = File::open(&cfg.input).expect("failed to open capture");
+    let result = if cfg.raw {
+        lint::lint_capture(std::io::BufReader::new(file))
     } else {
-        None
+        lint::lint_capture(Base64Decoder::new(file))
     };
+    let report = result.expect("failed to read capture");
 
-    let (tx, rx): (Sender<TelemetryChannelType>, Receiver<TelemetryChannelType>) =
-        std::sync::mpsc::channel();
+    for (category, issues) in report.by_category() {
+        println!("{}: {} issue(s)", category.name(), issues.len());
+        for issue in issues {
+            println!("  {}", issue);
+        }
+    }
+    if report.is_clean() {
+        println!("{} frames, no issue found", report.frames);
+    } else {
+        println!(
+            "{} frames, {} issues found",
+            report.frames,
+            report.issues.len()
+        );
+        std::process::exit(1);
+    }
+}
+
+fn probe(cfg: Probe) {
+    let duration = std::time::Duration::from_secs(cfg.duration);
+    let result = if let Some(port) = &cfg.port {
+        probe::probe_serial_port(port, &cfg.serial.serial_config(), duration)
+    } else if let Some(url) = &cfg.ws_url {
+        Ok(probe_ws(url, cfg.ws_deflate, duration))
+    } else if let Some(input) = &cfg.input {
+        let file = File::open(input).expect("failed to open capture");
+        if cfg.raw {
+            probe::probe_reader(std::io::BufReader::new(file), duration)
+        } else {
+            probe::probe_reader(Base64Decoder::new(file), duration)
+        }
+    } else {
+        unreachable!()
+    };
+    let report = match result {
+        Ok(report) => report,
+        Err(e) => {
+            error!("failed to probe device: {}", e);
+            std::process::exit(1);
+        }
+    };
+
+    if cfg.json {
+        println!(
+            "{}",
+            serde_json::to_string_pretty(&report).expect("failed to serialize report")
+        );
+    } else {
+        print!("{}", report);
+    }
+    if report.frames == 0 {
+        std::process::exit(1);
+    }
+}
+
+fn probe_ws(url: &Url, deflate: bool, duration: std::time::Duration) -> probe::ProbeReport {
+    let config = websocket::WebSocketClientConfig {
+        permessage_deflate: deflate,
+        ..websocket::WebSocketClientConfig::default()
+    };
+    let mut socket = match websocket::connect(url, &config) {
+        Ok(socket) => socket,
+        Err(e) => {
+            error!("failed to connect to {}: {}", url, e);
+            std::process::exit(1);
+        }
+    };
+
+    let (tx, rx) = std::sync::mpsc::channel();
     std::thread::spawn(move || {
-        info!("start playing telemetry messages");
-        gather_telemetry_from_file(input_file, tx, false);
+        while let Ok(message) = socket.read_message() {
+            if message.is_binary() && tx.send(message.into_data()).is_err() {
+                break;
+            }
+        }
     });
 
-    loop {
-        match rx.try_recv() {
-            Ok(Ok(msg)) => {
-                if msg.systick() >= from && msg.systick() <= to {
-                    let output_payload = match cfg.format {
-                        Format::Gts => telemetry_to_gts(&msg, &gts_source_label),
-                        Format::Json => {
-                            telemetry_to_json(&msg).expect("Failed to serialize a message to JSON")
-                        }
-                    };
-                    output_buffer
-                        .write_all(output_payload.as_bytes())
-                        .expect("failed to write to output file");
-                } else {
-                    skipped += 1;
-                }
-            }
-            Ok(msg) => {
-                display_message(msg);
-            }
-            Err(TryRecvError::Empty) => {
-                std::thread::sleep(THREAD_SLEEP_THROTTLE);
-            }
-            Err(TryRecvError::Disconnected) => {
-                warn!("end of recording");
-                if skipped != 0 {
-                    info!("{} records were skipped", &skipped);
-                }
-                output_buffer
-                    .flush()
-                    .expect("failed to write to output file");
-                std::process::exit(0);
-            }
+    let mut probe = probe::Probe::new();
+    let deadline = std::time::Instant::now() + duration;
+    while let Some(timeout) = deadline.checked_duration_since(std::time::Instant::now()) {
+        match rx.recv_timeout(timeout) {
+            Ok(bytes) => probe.push(&bytes),
+            Err(_) => break,
         }
     }
+    probe.finish()
+}
+
+fn list_ports() {
+    let ports = ports::list_ports().expect("failed to list serial ports");
+    if ports.is_empty() {
+        info!("no serial port found");
+    }
+    for port in ports {
+        println!("{}", port);
+    }
+}
+
+fn c_header(cfg: CHeader) {
+    let header = c_header::generate();
+    match &cfg.output {
+        Some(output) => std::fs::write(output, header).expect("failed to write C header"),
+        None => print!("{}", header),
+    }
 }
 
-fn disable_rpi_watchdog(cfg: DisableRpiWatchdog) {
-    control(Control {
-        port: cfg.port,
-        setting: ControlSetting::Heartbeat as u8,
-        value: DISABLE_RPI_WATCHDOG,
-    })
+fn disable_rpi_watchdog(cfg: DisableRpiWatchdog, decode: decoder::DecodeConfig) {
+    control(
+        Control {
+            port: cfg.port,
+            setting: ControlSetting::Heartbeat as u8,
+            value: DISABLE_RPI_WATCHDOG,
+            format: DisplayFormat::Log,
+            serial: cfg.serial,
+        },
+        decode,
+    )
 }
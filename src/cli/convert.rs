@@ -3,12 +3,36 @@
 // Copyright: 2020, Makers For Life
 // License: Public Domain License
 
+use std::collections::VecDeque;
+use std::fs::OpenOptions;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use crate::annotation::Annotation;
+use crate::biosignal::Biosignals;
+use crate::control::ControlSetting;
+use crate::formatter::{LogFormatter, MessageFormatter};
+use crate::manifest::{ExportManifest, MANIFEST_MESSAGE_TYPE};
+use crate::recording::RecordingWriter;
+use crate::sink::TelemetrySink;
 use crate::structures::*;
+use crate::TimedMessage;
 
 #[derive(Debug, PartialEq)]
 pub enum Format {
     Gts,
     Json,
+    InfluxLineProtocol,
+    Csv,
+    Edf,
+    Wfdb,
+}
+
+impl Format {
+    /// Whether messages are written to a single file as soon as they are read (otherwise they are collected and written at the end, or written to several files)
+    pub fn is_streamed(&self) -> bool {
+        matches!(self, Self::Gts | Self::Json | Self::InfluxLineProtocol)
+    }
 }
 
 impl std::str::FromStr for Format {
@@ -18,23 +42,513 @@ impl std::str::FromStr for Format {
         match s.trim().to_lowercase().as_str() {
             "gts" => Ok(Self::Gts),
             "json" => Ok(Self::Json),
-            _ => Err("Supported formats are: gts, json"),
+            "influx" => Ok(Self::InfluxLineProtocol),
+            "csv" => Ok(Self::Csv),
+            "edf" => Ok(Self::Edf),
+            "wfdb" => Ok(Self::Wfdb),
+            _ => Err("Supported formats are: gts, json, influx, csv, edf, wfdb"),
+        }
+    }
+}
+
+/// Sink that exports messages to a file, in GTS, JSON or InfluxDB line protocol format
+pub struct ExportSink<W: Write> {
+    pub writer: W,
+    pub format: Format,
+    pub gts_source_label: Option<String>,
+    pub gts_clock_offset: Option<i64>,
+    /// Annotations of the recording, sorted by systick; they are exported along with messages
+    pub annotations: VecDeque<Annotation>,
+}
+
+impl<W: Write> ExportSink<W> {
+    fn export_annotations_until(&mut self, systick: u64) {
+        while self
+            .annotations
+            .front()
+            .is_some_and(|annotation| annotation.systick <= systick)
+        {
+            if let Some(annotation) = self.annotations.pop_front() {
+                let output_payload = match self.format {
+                    Format::Gts => annotation_to_gts(
+                        &annotation,
+                        &self.gts_source_label,
+                        self.gts_clock_offset,
+                    ),
+                    Format::Json => annotation_to_json(&annotation),
+                    Format::InfluxLineProtocol => {
+                        annotation_to_influx(&annotation, self.gts_clock_offset)
+                    }
+                    Format::Csv | Format::Edf | Format::Wfdb => {
+                        unreachable!("{:?} is not streamed", self.format)
+                    }
+                };
+                self.writer
+                    .write_all(output_payload.as_bytes())
+                    .expect("failed to write to output file");
+            }
+        }
+    }
+}
+
+impl<W: Write> TelemetrySink for ExportSink<W> {
+    fn consume(&mut self, message: &TimedMessage) {
+        match &message.message {
+            Ok(msg) => {
+                self.export_annotations_until(msg.systick());
+                let output_payload = match self.format {
+                    Format::Gts => {
+                        telemetry_to_gts(msg, &self.gts_source_label, self.gts_clock_offset)
+                    }
+                    Format::Json => {
+                        telemetry_to_json(msg).expect("Failed to serialize a message to JSON")
+                    }
+                    Format::InfluxLineProtocol => telemetry_to_influx(msg, self.gts_clock_offset),
+                    Format::Csv | Format::Edf | Format::Wfdb => {
+                        unreachable!("{:?} is not streamed", self.format)
+                    }
+                };
+                self.writer
+                    .write_all(output_payload.as_bytes())
+                    .expect("failed to write to output file");
+            }
+            Err(_) => {
+                LogFormatter.display(&message.message);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        self.export_annotations_until(u64::MAX);
+        self.writer.flush().expect("failed to write to output file");
+    }
+}
+
+/// Sink that collects messages and exports their waveforms and events in EDF+ or WFDB format once there is no more message
+pub struct BiosignalExportSink {
+    /// Path of the EDF+ file, or path of the WFDB record without extension
+    pub output: PathBuf,
+    pub format: Format,
+    pub annotations: Vec<Annotation>,
+    pub messages: Vec<TelemetryMessage>,
+    pub manifest: ExportManifest,
+}
+
+impl TelemetrySink for BiosignalExportSink {
+    fn consume(&mut self, message: &TimedMessage) {
+        match &message.message {
+            Ok(msg) => self.messages.push(msg.clone()),
+            Err(_) => LogFormatter.display(&message.message),
+        }
+    }
+
+    fn flush(&mut self) {
+        let mut signals = Biosignals::from_messages(&self.messages, &self.annotations);
+        signals.comments = self.manifest.lines();
+        match self.format {
+            Format::Edf => {
+                let file = OpenOptions::new()
+                    .write(true)
+                    .create_new(true)
+                    .open(&self.output)
+                    .expect("failed to create output file");
+                signals.write_edf(file).expect("failed to write EDF+ file");
+            }
+            Format::Wfdb => {
+                let directory = self.output.parent().unwrap_or_else(|| Path::new("."));
+                let name = self
+                    .output
+                    .file_stem()
+                    .expect("output must be the path of a WFDB record")
+                    .to_string_lossy();
+                signals
+                    .write_wfdb(directory, &name)
+                    .expect("failed to write WFDB record");
+            }
+            Format::Gts | Format::Json | Format::InfluxLineProtocol | Format::Csv => {
+                unreachable!("{:?} is not a biosignal format", self.format)
+            }
+        }
+        info!("{} samples were exported", signals.len());
+    }
+}
+
+/// Header of a streamed export, describing where it comes from
+pub fn manifest_header(manifest: &ExportManifest, format: &Format) -> String {
+    match format {
+        Format::Gts | Format::InfluxLineProtocol => manifest.to_comment_lines(),
+        Format::Json => manifest.to_json_record(),
+        Format::Csv | Format::Edf | Format::Wfdb => unreachable!("{:?} is not streamed", format),
+    }
+}
+
+/// Path of one of the files of a CSV export (`<output>_<name>`), from the path given without extension
+pub fn csv_export_path(output: &Path, name: &str) -> PathBuf {
+    let mut path = output.as_os_str().to_owned();
+    path.push("_");
+    path.push(name);
+    PathBuf::from(path)
+}
+
+/// Sink that exports data snapshots and machine state snapshots to one CSV file each, one row per message and one column per field
+///
+/// Missing optional fields are empty cells, enums are written as their name and alarm codes are separated by spaces. Other messages are not exported.
+/// The header line of a file is written along with its first row.
+pub struct CsvExportSink<W: Write> {
+    data_snapshots: W,
+    machine_states: W,
+    data_snapshots_started: bool,
+    machine_states_started: bool,
+}
+
+impl<W: Write> CsvExportSink<W> {
+    pub fn new(data_snapshots: W, machine_states: W) -> Self {
+        Self {
+            data_snapshots,
+            machine_states,
+            data_snapshots_started: false,
+            machine_states_started: false,
         }
     }
 }
 
-pub fn telemetry_to_gts(message: &TelemetryMessage, source_label: &Option<String>) -> String {
+impl<W: Write> TelemetrySink for CsvExportSink<W> {
+    fn consume(&mut self, message: &TimedMessage) {
+        let (writer, started, fields) = match &message.message {
+            Ok(TelemetryMessage::DataSnapshot(snapshot)) => (
+                &mut self.data_snapshots,
+                &mut self.data_snapshots_started,
+                data_snapshot_fields(snapshot),
+            ),
+            Ok(TelemetryMessage::MachineStateSnapshot(snapshot)) => (
+                &mut self.machine_states,
+                &mut self.machine_states_started,
+                machine_state_fields(snapshot),
+            ),
+            Ok(_) => return,
+            Err(_) => {
+                LogFormatter.display(&message.message);
+                return;
+            }
+        };
+        if !*started {
+            *started = true;
+            let header: Vec<&str> = fields.iter().map(|(name, _)| *name).collect();
+            writeln!(writer, "{}", header.join(",")).expect("failed to write to output file");
+        }
+        let row: Vec<String> = fields.iter().map(|(_, value)| value.to_csv()).collect();
+        writeln!(writer, "{}", row.join(",")).expect("failed to write to output file");
+    }
+
+    fn flush(&mut self) {
+        self.data_snapshots
+            .flush()
+            .expect("failed to write to output file");
+        self.machine_states
+            .flush()
+            .expect("failed to write to output file");
+    }
+}
+
+/// Value of a field of a message, as written by the CSV and InfluxDB exports
+enum FieldValue {
+    Missing,
+    Integer(i64),
+    Boolean(bool),
+    Text(String),
+}
+
+impl FieldValue {
+    fn to_csv(&self) -> String {
+        match self {
+            Self::Missing => String::new(),
+            Self::Integer(value) => value.to_string(),
+            Self::Boolean(value) => value.to_string(),
+            Self::Text(text) if text.contains([',', '"', '\n', '\r']) => {
+                format!("\"{}\"", text.replace('"', "\"\""))
+            }
+            Self::Text(text) => text.clone(),
+        }
+    }
+
+    fn to_influx(&self) -> Option<String> {
+        match self {
+            Self::Missing => None,
+            Self::Integer(value) => Some(format!("{}i", value)),
+            Self::Boolean(value) => Some(value.to_string()),
+            Self::Text(text) => Some(format!(
+                "\"{}\"",
+                text.replace('\\', "\\\\").replace('"', "\\\"")
+            )),
+        }
+    }
+}
+
+trait ToFieldValue {
+    fn field_value(&self) -> FieldValue;
+}
+
+macro_rules! integer_field_values {
+    ($($type:ty),*) => {
+        $(impl ToFieldValue for $type {
+            fn field_value(&self) -> FieldValue {
+                FieldValue::Integer(*self as i64)
+            }
+        })*
+    };
+}
+
+macro_rules! debug_field_values {
+    ($($type:ty),*) => {
+        $(impl ToFieldValue for $type {
+            fn field_value(&self) -> FieldValue {
+                FieldValue::Text(format!("{:?}", self))
+            }
+        })*
+    };
+}
+
+integer_field_values!(u8, u16, u32, u64, i16);
+debug_field_values!(
+    AlarmPriority,
+    ControlSetting,
+    PatientGender,
+    Phase,
+    SubPhase,
+    VentilationMode
+);
+
+impl ToFieldValue for bool {
+    fn field_value(&self) -> FieldValue {
+        FieldValue::Boolean(*self)
+    }
+}
+
+impl ToFieldValue for String {
+    fn field_value(&self) -> FieldValue {
+        FieldValue::Text(self.clone())
+    }
+}
+
+impl ToFieldValue for crate::locale::Locale {
+    fn field_value(&self) -> FieldValue {
+        FieldValue::Text(self.to_string())
+    }
+}
+
+/// Alarm codes are separated by spaces
+impl ToFieldValue for Vec<u8> {
+    fn field_value(&self) -> FieldValue {
+        FieldValue::Text(self.iter().map(u8::to_string).collect::<Vec<_>>().join(" "))
+    }
+}
+
+impl<T: ToFieldValue> ToFieldValue for Option<T> {
+    fn field_value(&self) -> FieldValue {
+        self.as_ref()
+            .map(ToFieldValue::field_value)
+            .unwrap_or(FieldValue::Missing)
+    }
+}
+
+/// Name and value of each field, in order
+macro_rules! field_values {
+    ($item:expr; $($field:ident),* $(,)?) => {
+        vec![$((stringify!($field), $item.$field.field_value())),*]
+    };
+}
+
+fn data_snapshot_fields(snapshot: &DataSnapshot) -> Vec<(&'static str, FieldValue)> {
+    field_values!(snapshot;
+        telemetry_version,
+        version,
+        device_id,
+        systick,
+        centile,
+        pressure,
+        phase,
+        subphase,
+        blower_valve_position,
+        patient_valve_position,
+        blower_rpm,
+        battery_level,
+        inspiratory_flow,
+        expiratory_flow,
+    )
+}
+
+fn machine_state_fields(snapshot: &MachineStateSnapshot) -> Vec<(&'static str, FieldValue)> {
+    field_values!(snapshot;
+        telemetry_version,
+        version,
+        device_id,
+        systick,
+        cycle,
+        ventilation_mode,
+        peak_command,
+        plateau_command,
+        peep_command,
+        cpm_command,
+        previous_peak_pressure,
+        previous_plateau_pressure,
+        previous_peep_pressure,
+        current_alarm_codes,
+        previous_volume,
+        expiratory_term,
+        trigger_enabled,
+        trigger_offset,
+        previous_cpm,
+        alarm_snoozed,
+        cpu_load,
+        inspiratory_trigger_flow,
+        expiratory_trigger_flow,
+        ti_min,
+        ti_max,
+        low_inspiratory_minute_volume_alarm_threshold,
+        high_inspiratory_minute_volume_alarm_threshold,
+        low_expiratory_minute_volume_alarm_threshold,
+        high_expiratory_minute_volume_alarm_threshold,
+        low_respiratory_rate_alarm_threshold,
+        high_respiratory_rate_alarm_threshold,
+        target_tidal_volume,
+        low_tidal_volume_alarm_threshold,
+        high_tidal_volume_alarm_threshold,
+        plateau_duration,
+        leak_alarm_threshold,
+        target_inspiratory_flow,
+        inspiratory_duration_command,
+        previous_inspiratory_duration,
+        battery_level,
+        locale,
+        patient_height,
+        patient_gender,
+        peak_pressure_alarm_threshold,
+    )
+}
+
+fn alarm_trap_fields(trap: &AlarmTrap) -> Vec<(&'static str, FieldValue)> {
+    field_values!(trap;
+        telemetry_version,
+        version,
+        device_id,
+        systick,
+        centile,
+        pressure,
+        phase,
+        subphase,
+        cycle,
+        alarm_code,
+        alarm_priority,
+        triggered,
+        expected,
+        measured,
+        cycles_since_trigger,
+    )
+}
+
+fn control_ack_fields(ack: &ControlAck) -> Vec<(&'static str, FieldValue)> {
+    field_values!(ack;
+        telemetry_version,
+        version,
+        device_id,
+        systick,
+        setting,
+        value,
+    )
+}
+
+/// Name of the InfluxDB measurement of every exported point
+const INFLUX_MEASUREMENT: &str = "makair";
+
+/// Escape commas, equal signs and spaces of an InfluxDB tag value
+fn influx_tag(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        if matches!(c, ',' | '=' | ' ') {
+            escaped.push('\\');
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+/// InfluxDB timestamp (in nanoseconds) of a systick
+fn influx_timestamp(systick: u64, clock_offset: Option<i64>) -> i128 {
+    (i128::from(systick) + i128::from(clock_offset.unwrap_or(0))) * 1_000
+}
+
+/// Line of InfluxDB line protocol, tagged with the device ID (if any) and the message type; `systick` and `device_id` fields are left out
+fn influx_line(
+    message_type: &str,
+    device_id: Option<&str>,
+    fields: &[(&'static str, FieldValue)],
+    timestamp: i128,
+) -> String {
+    let fields: Vec<String> = fields
+        .iter()
+        .filter(|(name, _)| !matches!(*name, "device_id" | "systick"))
+        .filter_map(|(name, value)| Some(format!("{}={}", name, value.to_influx()?)))
+        .collect();
+    let device_tag = device_id
+        .map(|device_id| format!(",device_id={}", influx_tag(device_id)))
+        .unwrap_or_default();
+    format!(
+        "{}{},message_type={} {} {}\n",
+        INFLUX_MEASUREMENT,
+        device_tag,
+        message_type,
+        fields.join(","),
+        timestamp
+    )
+}
+
+/// Export data snapshots, machine state snapshots, alarm traps and ACKs as InfluxDB line protocol (other messages are left out)
+pub fn telemetry_to_influx(message: &TelemetryMessage, clock_offset: Option<i64>) -> String {
+    let (message_type, fields) = match message {
+        TelemetryMessage::DataSnapshot(snapshot) => {
+            ("DataSnapshot", data_snapshot_fields(snapshot))
+        }
+        TelemetryMessage::MachineStateSnapshot(snapshot) => {
+            ("MachineStateSnapshot", machine_state_fields(snapshot))
+        }
+        TelemetryMessage::AlarmTrap(trap) => ("AlarmTrap", alarm_trap_fields(trap)),
+        TelemetryMessage::ControlAck(ack) => ("ControlAck", control_ack_fields(ack)),
+        _ => return String::new(),
+    };
+    influx_line(
+        message_type,
+        Some(&message.device_id()),
+        &fields,
+        influx_timestamp(message.systick(), clock_offset),
+    )
+}
+
+pub fn annotation_to_influx(annotation: &Annotation, clock_offset: Option<i64>) -> String {
+    influx_line(
+        "Annotation",
+        None,
+        &[("text", FieldValue::Text(annotation.text.clone()))],
+        influx_timestamp(annotation.systick, clock_offset),
+    )
+}
+
+pub fn telemetry_to_gts(
+    message: &TelemetryMessage,
+    source_label: &Option<String>,
+    clock_offset: Option<i64>,
+) -> String {
+    let ts = i128::from(message.systick()) + i128::from(clock_offset.unwrap_or(0));
     let mut output = vec![];
     match message {
         TelemetryMessage::BootMessage(msg) => {
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "boot_version",
                 Value::Str(&msg.version),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "boot_mode",
                 Value::Str(format!("{:?}", msg.mode)),
                 source_label,
@@ -45,31 +559,31 @@ pub fn telemetry_to_gts(message: &TelemetryMessage, source_label: &Option<String
         }
         TelemetryMessage::DataSnapshot(msg) => {
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "pressure",
                 Value::Number(msg.pressure),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "blower_valve_position",
                 Value::Number(msg.blower_valve_position),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "patient_valve_position",
                 Value::Number(msg.patient_valve_position),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "blower_rpm",
                 Value::Number(msg.blower_rpm),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "battery_level",
                 Value::Number(msg.battery_level),
                 source_label,
@@ -77,75 +591,75 @@ pub fn telemetry_to_gts(message: &TelemetryMessage, source_label: &Option<String
         }
         TelemetryMessage::MachineStateSnapshot(msg) => {
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "cycle",
                 Value::Number(msg.cycle),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "peak_command",
                 Value::Number(msg.peak_command),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "plateau_command",
                 Value::Number(msg.plateau_command),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "peep_command",
                 Value::Number(msg.peep_command),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "cpm_command",
                 Value::Number(msg.cpm_command),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "previous_peak_pressure",
                 Value::Number(msg.previous_peak_pressure),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "previous_plateau_pressure",
                 Value::Number(msg.previous_plateau_pressure),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "previous_peep_pressure",
                 Value::Number(msg.previous_peep_pressure),
                 source_label,
             ));
             if let Some(previous_volume) = msg.previous_volume {
                 output.push(create_gts_line(
-                    msg.systick,
+                    ts,
                     "previous_volume",
                     Value::Number(previous_volume),
                     source_label,
                 ));
             }
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "expiratory_term",
                 Value::Number(msg.expiratory_term),
                 source_label,
             ));
             output.push(create_gts_line::<String>(
-                msg.systick,
+                ts,
                 "trigger_enabled",
                 Value::Bool(msg.trigger_enabled),
                 source_label,
             ));
             output.push(create_gts_line(
-                msg.systick,
+                ts,
                 "trigger_offset",
                 Value::Number(msg.trigger_offset),
                 source_label,
@@ -153,7 +667,7 @@ pub fn telemetry_to_gts(message: &TelemetryMessage, source_label: &Option<String
         }
         TelemetryMessage::AlarmTrap(msg) => {
             output.push(create_gts_line::<String>(
-                msg.systick,
+                ts,
                 format!("alarm_{}", msg.alarm_code).as_str(),
                 Value::Bool(msg.triggered),
                 source_label,
@@ -168,6 +682,9 @@ pub fn telemetry_to_gts(message: &TelemetryMessage, source_label: &Option<String
         TelemetryMessage::EolTestSnapshot(_) => {
             // Do nothing: we don't want this kind of messages
         }
+        _ => {
+            // Do nothing: unknown messages have no known fields to export
+        }
     };
     output.iter().fold(String::new(), |mut acc, cur| {
         acc.push_str(cur);
@@ -193,7 +710,7 @@ impl<N: std::string::ToString> std::fmt::Display for Value<N> {
 }
 
 fn create_gts_line<N: std::string::ToString>(
-    ts: u64,
+    ts: i128,
     name: &str,
     value: Value<N>,
     source_label: &Option<String>,
@@ -205,9 +722,251 @@ fn create_gts_line<N: std::string::ToString>(
     format!("{}// {}{} {}", ts, name, labels, value)
 }
 
+pub fn annotation_to_gts(
+    annotation: &Annotation,
+    source_label: &Option<String>,
+    clock_offset: Option<i64>,
+) -> String {
+    let ts = i128::from(annotation.systick) + i128::from(clock_offset.unwrap_or(0));
+    let mut line = create_gts_line(
+        ts,
+        "annotation",
+        Value::Str(gts_encode(&annotation.text)),
+        source_label,
+    );
+    line.push('\n');
+    line
+}
+
+/// Percent-encode characters that cannot appear as is in a GTS string value
+fn gts_encode(text: &str) -> String {
+    let mut encoded = String::with_capacity(text.len());
+    for c in text.chars() {
+        if c == '\'' || c == '%' || c.is_control() {
+            let mut bytes = [0; 4];
+            for byte in c.encode_utf8(&mut bytes).bytes() {
+                encoded.push_str(&format!("%{:02X}", byte));
+            }
+        } else {
+            encoded.push(c);
+        }
+    }
+    encoded
+}
+
+pub fn annotation_to_json(annotation: &Annotation) -> String {
+    let mut result = serde_json::json!({
+        "message_type": "Annotation",
+        "systick": annotation.systick,
+        "text": annotation.text,
+    })
+    .to_string();
+    result.push('\n');
+    result
+}
+
 pub fn telemetry_to_json(message: &TelemetryMessage) -> Result<String, serde_json::Error> {
     serde_json::to_string(&message).map(|mut result| {
         result.push('\n');
         result
     })
 }
+
+/// Counts of what a JSON export contained once re-serialized into a recording
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct JsonImport {
+    pub messages: usize,
+    pub annotations: usize,
+    /// Lines that could not be parsed, or messages that do not exist in the chosen protocol version
+    pub skipped: usize,
+}
+
+/// Re-serialize messages and annotations exported by `convert -f json` (one JSON object per line) into a recording
+///
+/// * `protocol_version` - Version of the telemetry protocol to use for frames (1 to 3); messages that did not exist in protocol v1 are skipped.
+pub fn json_to_recording<R: BufRead>(
+    reader: R,
+    writer: &mut RecordingWriter,
+    protocol_version: u8,
+) -> std::io::Result<JsonImport> {
+    let mut import = JsonImport::default();
+    for (index, line) in reader.lines().enumerate() {
+        let line = line?;
+        if line.trim().is_empty() {
+            continue;
+        }
+        let value: serde_json::Value = match serde_json::from_str(&line) {
+            Ok(value) => value,
+            Err(e) => {
+                warn!("line {} is not valid JSON: {}", index + 1, e);
+                import.skipped += 1;
+                continue;
+            }
+        };
+
+        // The manifest only describes where the export comes from
+        if value["message_type"] == MANIFEST_MESSAGE_TYPE {
+            continue;
+        }
+
+        if value["message_type"] == "Annotation" {
+            match serde_json::from_value::<Annotation>(value) {
+                Ok(annotation) => {
+                    writer.write_annotation(&annotation)?;
+                    import.annotations += 1;
+                }
+                Err(e) => {
+                    warn!("line {} is not a valid annotation: {}", index + 1, e);
+                    import.skipped += 1;
+                }
+            }
+            continue;
+        }
+
+        let message = match serde_json::from_value::<TelemetryMessage>(value) {
+            Ok(message) => message,
+            Err(e) => {
+                warn!("line {} is not a valid telemetry message: {}", index + 1, e);
+                import.skipped += 1;
+                continue;
+            }
+        };
+        let frame = match (protocol_version, &message) {
+            (1, TelemetryMessage::FatalError(_) | TelemetryMessage::EolTestSnapshot(_)) => {
+                warn!(
+                    "line {} contains a message that did not exist in telemetry protocol v1",
+                    index + 1
+                );
+                import.skipped += 1;
+                continue;
+            }
+            _ => message
+                .to_bytes_for_version(protocol_version)
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?,
+        };
+        writer.write_frame(&frame, Some(&message))?;
+        import.messages += 1;
+    }
+    writer.flush()?;
+    Ok(import)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::recording::FlushPolicy;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+    use crate::source::{SourceInfo, SourceKind};
+
+    #[test]
+    fn csv_export() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(1_000)
+                .collect();
+        let mut sink = CsvExportSink::new(Vec::new(), Vec::new());
+        for message in &messages {
+            sink.consume(&TimedMessage::now(
+                Ok(message.clone()),
+                SourceInfo::new(SourceKind::File, None),
+            ));
+        }
+        sink.flush();
+
+        let data_snapshots = String::from_utf8(sink.data_snapshots).unwrap();
+        let machine_states = String::from_utf8(sink.machine_states).unwrap();
+        let count = |predicate: fn(&TelemetryMessage) -> bool| {
+            messages.iter().filter(|message| predicate(message)).count()
+        };
+        assert_eq!(
+            data_snapshots.lines().count(),
+            count(|message| matches!(message, TelemetryMessage::DataSnapshot(_))) + 1
+        );
+        assert_eq!(
+            machine_states.lines().count(),
+            count(|message| matches!(message, TelemetryMessage::MachineStateSnapshot(_))) + 1
+        );
+
+        let header: Vec<&str> = machine_states.lines().next().unwrap().split(',').collect();
+        let row: Vec<&str> = machine_states.lines().nth(1).unwrap().split(',').collect();
+        assert_eq!(header.len(), row.len());
+        assert_eq!(header[2], "device_id");
+        assert_eq!(row[2], "1-2-3");
+        assert!(data_snapshots.starts_with("telemetry_version,version,device_id,systick,"));
+    }
+
+    #[test]
+    fn influx_export() {
+        let ack = TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "v 2".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 1_000,
+            setting: ControlSetting::PEEP,
+            value: 80,
+        });
+        assert_eq!(
+            telemetry_to_influx(&ack, Some(-500)),
+            "makair,device_id=1-2-3,message_type=ControlAck telemetry_version=2i,version=\"v 2\",setting=\"PEEP\",value=80i 500000\n"
+        );
+        assert_eq!(
+            annotation_to_influx(&Annotation::new(2, "a \"quote\""), None),
+            "makair,message_type=Annotation text=\"a \\\"quote\\\"\" 2000\n"
+        );
+
+        let stopped = TelemetryMessage::StoppedMessage(StoppedMessage::default());
+        assert!(telemetry_to_influx(&stopped, None).is_empty());
+        let snapshot = TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+            device_id: "a b".to_owned(),
+            ..MachineStateSnapshot::default()
+        });
+        let line = telemetry_to_influx(&snapshot, None);
+        assert!(line.starts_with("makair,device_id=a\\ b,message_type=MachineStateSnapshot "));
+        // Missing optional fields are left out
+        assert!(!line.contains("previous_volume"));
+    }
+
+    #[test]
+    fn json_export_round_trip() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(300)
+                .collect();
+        let manifest = ExportManifest::for_recording("records/v2/short.record").unwrap();
+        let mut export = manifest_header(&manifest, &Format::Json);
+        for message in &messages[..100] {
+            export.push_str(&telemetry_to_json(message).unwrap());
+        }
+        export.push_str(&annotation_to_json(&Annotation::new(
+            messages[99].systick(),
+            "changed PEEP",
+        )));
+        export.push_str("not json\n");
+        for message in &messages[100..] {
+            export.push_str(&telemetry_to_json(message).unwrap());
+        }
+
+        let path =
+            std::env::temp_dir().join(format!("makair-json-import-{}.record", std::process::id()));
+        let mut writer = RecordingWriter::new(
+            std::fs::File::create(&path).unwrap(),
+            FlushPolicy::default(),
+        );
+        let import = json_to_recording(export.as_bytes(), &mut writer, 2).unwrap();
+        drop(writer);
+
+        assert_eq!(
+            import,
+            JsonImport {
+                messages: 300,
+                annotations: 1,
+                skipped: 1,
+            }
+        );
+        assert_eq!(crate::testing::read_recording(&path).unwrap(), messages);
+        let annotations =
+            crate::annotation::read_annotations(std::fs::File::open(&path).unwrap()).unwrap();
+        assert_eq!(annotations[0].text, "changed PEEP");
+        std::fs::remove_file(&path).unwrap();
+    }
+}
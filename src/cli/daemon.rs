@@ -0,0 +1,402 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::BTreeMap;
+use std::fs::OpenOptions;
+use std::io::LineWriter;
+use std::path::{Path, PathBuf};
+use std::sync::mpsc::Sender;
+use std::time::{Duration, SystemTime};
+
+use serde::Deserialize;
+
+use crate::recording::{FlushPolicy, RecordingWriter};
+use crate::sink::{DisplaySink, JsonSink, RecordingSink, SinkCommand, TelemetrySink};
+use crate::DisplayFormat;
+
+/// How often the configuration file is checked for changes
+const CONFIG_CHECK_PERIOD: Duration = Duration::from_secs(1);
+
+/// Configuration of the daemon mode, read from a TOML file
+///
+/// ```toml
+/// port = "/dev/ttyUSB0"
+/// throttle_ms = 100
+///
+/// [sinks.display]
+/// kind = "display"
+/// format = "compact"
+///
+/// [sinks.recording]
+/// kind = "recording"
+/// path = "records/daemon.record"
+/// ```
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DaemonConfig {
+    /// Address of the serial port; changing it requires a restart
+    pub port: String,
+    /// Minimum interval between two data snapshots given to sinks, in milliseconds
+    #[serde(default)]
+    pub throttle_ms: Option<u64>,
+    /// Sinks, by name
+    #[serde(default)]
+    pub sinks: BTreeMap<String, SinkConfig>,
+}
+
+/// Configuration of a sink of the daemon mode
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize)]
+#[serde(tag = "kind", rename_all = "lowercase")]
+pub enum SinkConfig {
+    /// Display messages in the logs
+    Display {
+        /// How to display messages: log, compact, color
+        #[serde(default = "default_display_format")]
+        format: String,
+    },
+    /// Append messages to a recording
+    Recording {
+        /// Path of the recording
+        path: String,
+    },
+    /// Append messages to a JSON transcript (one message per line)
+    Json {
+        /// Path of the transcript
+        path: String,
+    },
+}
+
+fn default_display_format() -> String {
+    "log".to_owned()
+}
+
+/// Change between two configurations of the daemon mode
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ConfigChange {
+    /// Add a sink, or replace the sink with the same name
+    AddSink(String, SinkConfig),
+    /// Remove a sink
+    RemoveSink(String),
+    /// Change the minimum interval between two data snapshots
+    Throttle(Option<Duration>),
+    /// Change the serial port; this cannot be applied without dropping the serial connection
+    Port(String),
+}
+
+impl DaemonConfig {
+    /// Read a configuration from a TOML file
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path.as_ref())
+            .map_err(|e| format!("failed to read {}: {}", path.as_ref().display(), e))?;
+        basic_toml::from_str(&content)
+            .map_err(|e| format!("failed to parse {}: {}", path.as_ref().display(), e))
+    }
+
+    /// Configuration without any sink nor throttling, used as a starting point
+    pub fn empty(port: &str) -> Self {
+        Self {
+            port: port.to_owned(),
+            throttle_ms: None,
+            sinks: BTreeMap::new(),
+        }
+    }
+
+    /// Changes needed to go from this configuration to another one
+    pub fn changes(&self, new: &Self) -> Vec<ConfigChange> {
+        let mut changes = Vec::new();
+        if new.port != self.port {
+            changes.push(ConfigChange::Port(new.port.clone()));
+        }
+        if new.throttle_ms != self.throttle_ms {
+            changes.push(ConfigChange::Throttle(
+                new.throttle_ms.map(Duration::from_millis),
+            ));
+        }
+        for name in self.sinks.keys() {
+            if !new.sinks.contains_key(name) {
+                changes.push(ConfigChange::RemoveSink(name.clone()));
+            }
+        }
+        for (name, sink) in &new.sinks {
+            if self.sinks.get(name) != Some(sink) {
+                changes.push(ConfigChange::AddSink(name.clone(), sink.clone()));
+            }
+        }
+        changes
+    }
+
+    /// This configuration, with the given sinks kept as they are in a previous one (or absent if they were not in it)
+    ///
+    /// This is used to only commit the sinks that were actually applied, so that the others are retried on the next change.
+    pub fn keeping_sinks(mut self, previous: &Self, names: &[String]) -> Self {
+        for name in names {
+            match previous.sinks.get(name) {
+                Some(sink) => self.sinks.insert(name.clone(), sink.clone()),
+                None => self.sinks.remove(name),
+            };
+        }
+        self
+    }
+}
+
+impl SinkConfig {
+    /// Create the sink; files are appended to, so that reloading the configuration does not erase them
+    pub fn build(&self) -> Result<Box<dyn TelemetrySink + Send>, String> {
+        let append = |path: &str| {
+            OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(path)
+                .map_err(|e| format!("failed to open {}: {}", path, e))
+        };
+        match self {
+            Self::Display { format } => {
+                let format: DisplayFormat = format.parse()?;
+                Ok(Box::new(DisplaySink::new(format.formatter())))
+            }
+            Self::Recording { path } => Ok(Box::new(RecordingSink::new(RecordingWriter::new(
+                append(path)?,
+                FlushPolicy::default(),
+            )))),
+            Self::Json { path } => Ok(Box::new(JsonSink::new(LineWriter::new(append(path)?)))),
+        }
+    }
+}
+
+/// Send the commands applying changes of configuration to a running dispatcher
+///
+/// Returns the names of the sinks that could not be created, or `None` if the dispatcher is not running anymore.
+pub fn apply_changes(
+    changes: Vec<ConfigChange>,
+    commands_tx: &Sender<SinkCommand>,
+) -> Option<Vec<String>> {
+    let mut failed = Vec::new();
+    for change in changes {
+        let command = match change {
+            ConfigChange::AddSink(name, config) => match config.build() {
+                Ok(sink) => {
+                    info!("adding sink {}", name);
+                    SinkCommand::Add(name, sink)
+                }
+                Err(e) => {
+                    error!("failed to create sink {}: {}", name, e);
+                    failed.push(name);
+                    continue;
+                }
+            },
+            ConfigChange::RemoveSink(name) => {
+                info!("removing sink {}", name);
+                SinkCommand::Remove(name)
+            }
+            ConfigChange::Throttle(interval) => {
+                info!("throttling data snapshots to one every {:?}", interval);
+                SinkCommand::Throttle(interval)
+            }
+            ConfigChange::Port(port) => {
+                warn!(
+                    "serial port changed to {}, restart the daemon to apply it",
+                    port
+                );
+                continue;
+            }
+        };
+        if commands_tx.send(command).is_err() {
+            return None;
+        }
+    }
+    Some(failed)
+}
+
+/// Check the configuration file regularly and apply its changes, until the dispatcher stops
+///
+/// Invalid configurations are logged and ignored: the current one is kept until the file is fixed.
+/// Likewise, sinks that cannot be created keep their previous configuration, so they are retried on the next change.
+/// This is meant to be run in a dedicated thread.
+pub fn watch_config(path: PathBuf, mut config: DaemonConfig, commands_tx: Sender<SinkCommand>) {
+    let modified = |path: &Path| -> Option<SystemTime> { path.metadata().ok()?.modified().ok() };
+    let mut last_modified = modified(&path);
+
+    loop {
+        std::thread::sleep(CONFIG_CHECK_PERIOD);
+        let current_modified = modified(&path);
+        if current_modified == last_modified {
+            continue;
+        }
+        last_modified = current_modified;
+
+        match DaemonConfig::load(&path) {
+            Ok(new_config) => {
+                info!("configuration {} changed", path.display());
+                let failed = match apply_changes(config.changes(&new_config), &commands_tx) {
+                    Some(failed) => failed,
+                    None => break,
+                };
+                // The port cannot change while running
+                config = DaemonConfig {
+                    port: config.port.clone(),
+                    ..new_config.keeping_sinks(&config, &failed)
+                };
+            }
+            Err(e) => error!("{}, keeping the current configuration", e),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: &str = r#"
+port = "/dev/ttyUSB0"
+
+[sinks.display]
+kind = "display"
+
+[sinks.recording]
+kind = "recording"
+path = "daemon.record"
+"#;
+
+    #[test]
+    fn parses_config() {
+        let config: DaemonConfig = basic_toml::from_str(CONFIG).unwrap();
+
+        assert_eq!(config.port, "/dev/ttyUSB0");
+        assert_eq!(config.throttle_ms, None);
+        assert_eq!(
+            config.sinks.get("display"),
+            Some(&SinkConfig::Display {
+                format: "log".to_owned()
+            })
+        );
+        assert_eq!(
+            config.sinks.get("recording"),
+            Some(&SinkConfig::Recording {
+                path: "daemon.record".to_owned()
+            })
+        );
+        assert!(basic_toml::from_str::<DaemonConfig>("port = 1").is_err());
+    }
+
+    #[test]
+    fn computes_changes() {
+        let config: DaemonConfig = basic_toml::from_str(CONFIG).unwrap();
+        assert_eq!(
+            DaemonConfig::empty("/dev/ttyUSB0").changes(&config),
+            vec![
+                ConfigChange::AddSink(
+                    "display".to_owned(),
+                    SinkConfig::Display {
+                        format: "log".to_owned()
+                    }
+                ),
+                ConfigChange::AddSink(
+                    "recording".to_owned(),
+                    SinkConfig::Recording {
+                        path: "daemon.record".to_owned()
+                    }
+                ),
+            ]
+        );
+        assert_eq!(config.changes(&config), vec![]);
+
+        let mut new_config = config.clone();
+        new_config.port = "/dev/ttyUSB1".to_owned();
+        new_config.throttle_ms = Some(100);
+        new_config.sinks.remove("recording");
+        new_config.sinks.insert(
+            "display".to_owned(),
+            SinkConfig::Display {
+                format: "compact".to_owned(),
+            },
+        );
+        assert_eq!(
+            config.changes(&new_config),
+            vec![
+                ConfigChange::Port("/dev/ttyUSB1".to_owned()),
+                ConfigChange::Throttle(Some(Duration::from_millis(100))),
+                ConfigChange::RemoveSink("recording".to_owned()),
+                ConfigChange::AddSink(
+                    "display".to_owned(),
+                    SinkConfig::Display {
+                        format: "compact".to_owned()
+                    }
+                ),
+            ]
+        );
+    }
+
+    #[test]
+    fn skips_changes_that_cannot_be_applied() {
+        let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+        let changes = vec![
+            ConfigChange::Port("/dev/ttyUSB1".to_owned()),
+            ConfigChange::AddSink(
+                "display".to_owned(),
+                SinkConfig::Display {
+                    format: "fancy".to_owned(),
+                },
+            ),
+            ConfigChange::RemoveSink("recording".to_owned()),
+        ];
+
+        assert_eq!(
+            apply_changes(changes, &commands_tx),
+            Some(vec!["display".to_owned()])
+        );
+        let commands: Vec<SinkCommand> = commands_rx.try_iter().collect();
+        assert_eq!(commands.len(), 1);
+        assert!(matches!(&commands[0], SinkCommand::Remove(name) if name == "recording"));
+
+        drop(commands_rx);
+        assert_eq!(
+            apply_changes(vec![ConfigChange::Throttle(None)], &commands_tx),
+            None
+        );
+    }
+
+    #[test]
+    fn keeps_previous_configuration_of_failed_sinks() {
+        let config: DaemonConfig = basic_toml::from_str(CONFIG).unwrap();
+        let mut new_config = config.clone();
+        new_config.sinks.insert(
+            "display".to_owned(),
+            SinkConfig::Display {
+                format: "fancy".to_owned(),
+            },
+        );
+        new_config.sinks.insert(
+            "json".to_owned(),
+            SinkConfig::Json {
+                path: "daemon.json".to_owned(),
+            },
+        );
+        new_config.sinks.remove("recording");
+
+        let kept = new_config
+            .clone()
+            .keeping_sinks(&config, &["display".to_owned(), "json".to_owned()]);
+        assert_eq!(kept.sinks.get("display"), config.sinks.get("display"));
+        assert_eq!(kept.sinks.get("json"), None);
+        assert_eq!(kept.sinks.get("recording"), None);
+        assert_eq!(
+            kept.changes(&new_config),
+            vec![
+                ConfigChange::AddSink(
+                    "display".to_owned(),
+                    SinkConfig::Display {
+                        format: "fancy".to_owned()
+                    }
+                ),
+                ConfigChange::AddSink(
+                    "json".to_owned(),
+                    SinkConfig::Json {
+                        path: "daemon.json".to_owned()
+                    }
+                ),
+            ]
+        );
+    }
+}
@@ -0,0 +1,49 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+use makair_telemetry::progress::*;
+
+/// Progress callback that displays a progress bar on stderr
+pub struct ProgressBarCallback {
+    bar: ProgressBar,
+}
+
+impl ProgressBarCallback {
+    pub fn new(total_bytes: Option<u64>) -> Self {
+        let bar = match total_bytes {
+            Some(total) => ProgressBar::new(total).with_style(
+                ProgressStyle::default_bar()
+                    .template("[{elapsed_precise}] {bar:40} {percent:>3}% (ETA {eta}) {msg}")
+                    .expect("invalid progress bar template")
+                    .progress_chars("=> "),
+            ),
+            None => ProgressBar::new_spinner().with_style(
+                ProgressStyle::default_spinner()
+                    .template("{spinner} {msg}")
+                    .expect("invalid progress bar template"),
+            ),
+        };
+        Self { bar }
+    }
+}
+
+impl ProgressCallback for ProgressBarCallback {
+    fn on_progress(&mut self, progress: &Progress) {
+        self.bar.set_position(progress.processed_bytes);
+        self.bar
+            .set_message(format!("{} messages", progress.messages));
+    }
+
+    fn on_finish(&mut self, progress: &Progress) {
+        self.bar.set_position(progress.processed_bytes);
+        self.bar.finish_with_message(format!(
+            "{} messages in {:.1} s",
+            progress.messages,
+            progress.elapsed.as_secs_f32()
+        ));
+    }
+}
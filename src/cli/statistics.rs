@@ -42,6 +42,8 @@ mod tests {
             systick: 0,
             mode: Mode::Production,
             value128: 0,
+            control_capabilities: None,
+            session_nonce: None,
         })];
 
         assert_eq!(compute_duration(vect), 0);
@@ -201,6 +203,8 @@ mod tests {
                 systick: 0,
                 mode: Mode::Production,
                 value128: 0,
+                control_capabilities: None,
+                session_nonce: None,
             }),
             TelemetryMessage::AlarmTrap(AlarmTrap {
                 telemetry_version: 1,
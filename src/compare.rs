@@ -0,0 +1,340 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::anomaly::{CycleMetrics, Metric};
+use crate::structures::TelemetryMessage;
+
+/// Default maximum distance between the ends of two cycles for them to be aligned, in µs
+pub const DEFAULT_ALIGNMENT_TOLERANCE: u64 = 500_000;
+
+/// Metrics that are compared, in the order of `Comparison::summaries()` and of the columns of the CSV export
+pub const COMPARED_METRICS: [Metric; 5] = [
+    Metric::PeakPressure,
+    Metric::PlateauPressure,
+    Metric::Peep,
+    Metric::TidalVolume,
+    Metric::RespiratoryRate,
+];
+
+/// Two cycles, one of each device, that ended at the same time
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct CyclePair {
+    /// Time at which the cycle of the first device ended, in µs since the beginning of its recording
+    pub elapsed: u64,
+    /// Cycle of the first device
+    pub a: CycleMetrics,
+    /// Cycle of the second device
+    pub b: CycleMetrics,
+}
+
+impl CyclePair {
+    /// Value of a metric on the second device minus its value on the first device, if both measured it
+    pub fn delta(&self, metric: Metric) -> Option<f64> {
+        Some(metric.value(&self.b)? - metric.value(&self.a)?)
+    }
+}
+
+/// Statistics of the deltas of one metric over every aligned pair of cycles
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct DeltaSummary {
+    /// Compared metric
+    pub metric: Metric,
+    /// Number of pairs where both devices measured the metric
+    pub count: usize,
+    /// Mean delta (bias of the second device)
+    pub mean: f64,
+    /// Standard deviation of deltas
+    pub std_dev: f64,
+    /// Mean of absolute deltas
+    pub mean_absolute: f64,
+    /// Smallest delta
+    pub min: f64,
+    /// Largest delta
+    pub max: f64,
+}
+
+impl DeltaSummary {
+    fn new(metric: Metric, deltas: &[f64]) -> Option<Self> {
+        if deltas.is_empty() {
+            return None;
+        }
+        let count = deltas.len() as f64;
+        let mean = deltas.iter().sum::<f64>() / count;
+        let variance = deltas.iter().map(|d| (d - mean).powi(2)).sum::<f64>() / count;
+        Some(Self {
+            metric,
+            count: deltas.len(),
+            mean,
+            std_dev: variance.sqrt(),
+            mean_absolute: deltas.iter().map(|d| d.abs()).sum::<f64>() / count,
+            min: deltas.iter().copied().fold(f64::INFINITY, f64::min),
+            max: deltas.iter().copied().fold(f64::NEG_INFINITY, f64::max),
+        })
+    }
+}
+
+/// Cycles of two devices aligned by time
+#[derive(Debug, Clone, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct Comparison {
+    /// Aligned cycles, in chronological order
+    pub pairs: Vec<CyclePair>,
+    /// Number of cycles of the first device that have no counterpart
+    pub unmatched_a: usize,
+    /// Number of cycles of the second device that have no counterpart
+    pub unmatched_b: usize,
+}
+
+impl Comparison {
+    /// Statistics of the deltas of every metric of `COMPARED_METRICS` (`None` if no pair measured it)
+    pub fn summaries(&self) -> Vec<Option<DeltaSummary>> {
+        COMPARED_METRICS
+            .iter()
+            .map(|metric| {
+                let deltas: Vec<f64> = self
+                    .pairs
+                    .iter()
+                    .filter_map(|pair| pair.delta(*metric))
+                    .collect();
+                DeltaSummary::new(*metric, &deltas)
+            })
+            .collect()
+    }
+
+    /// Render per-cycle deltas as CSV, with a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("elapsed,cycle_a,cycle_b");
+        for metric in COMPARED_METRICS {
+            let name = metric.name();
+            csv.push_str(&format!(",{}_a,{}_b,{}_delta", name, name, name));
+        }
+        csv.push('\n');
+        let optional = |value: Option<f64>| value.map(|v| v.to_string()).unwrap_or_default();
+        for pair in &self.pairs {
+            csv.push_str(&format!(
+                "{},{},{}",
+                pair.elapsed, pair.a.cycle, pair.b.cycle
+            ));
+            for metric in COMPARED_METRICS {
+                csv.push_str(&format!(
+                    ",{},{},{}",
+                    optional(metric.value(&pair.a)),
+                    optional(metric.value(&pair.b)),
+                    optional(pair.delta(metric))
+                ));
+            }
+            csv.push('\n');
+        }
+        csv
+    }
+}
+
+/// Metrics of every cycle of a recording, with the time it ended in µs since the beginning of the recording
+fn timed_cycles<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(
+    messages: I,
+) -> Vec<(i64, CycleMetrics)> {
+    let mut cycles = Vec::new();
+    let mut elapsed: u64 = 0;
+    let mut last_systick = None;
+    for message in messages {
+        let systick = message.systick();
+        // Systick goes back when the device reboots: the recording goes on from where it was
+        elapsed += last_systick.map_or(0, |last| systick.saturating_sub(last));
+        last_systick = Some(systick);
+        if let TelemetryMessage::MachineStateSnapshot(snapshot) = message {
+            cycles.push((elapsed as i64, CycleMetrics::new(snapshot, None)));
+        }
+    }
+    cycles
+}
+
+/// Aligns the cycles of two recordings (e.g. two MakAirs on a splitter, or A/B firmware tests) to compare their metrics
+///
+/// Times are counted from the beginning of each recording; when recordings did not start at the same time, `offset()` shifts the second one.
+/// Each cycle is paired with the cycle of the other device that ended the nearest, as long as it is within the tolerance.
+#[derive(Debug, Clone)]
+pub struct CycleAligner {
+    offset: i64,
+    tolerance: u64,
+}
+
+impl Default for CycleAligner {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CycleAligner {
+    /// Create an aligner for recordings that started at the same time
+    pub fn new() -> Self {
+        Self {
+            offset: 0,
+            tolerance: DEFAULT_ALIGNMENT_TOLERANCE,
+        }
+    }
+
+    /// Choose the time in µs to add to the times of the second recording, e.g. when it started that much later than the first one
+    pub fn offset(mut self, offset: i64) -> Self {
+        self.offset = offset;
+        self
+    }
+
+    /// Choose the maximum distance between the ends of two cycles for them to be aligned, in µs
+    pub fn tolerance(mut self, tolerance: u64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Align the cycles of two recordings
+    pub fn compare<'a, A, B>(&self, a: A, b: B) -> Comparison
+    where
+        A: IntoIterator<Item = &'a TelemetryMessage>,
+        B: IntoIterator<Item = &'a TelemetryMessage>,
+    {
+        let a = timed_cycles(a);
+        let b: Vec<(i64, CycleMetrics)> = timed_cycles(b)
+            .into_iter()
+            .map(|(elapsed, metrics)| (elapsed + self.offset, metrics))
+            .collect();
+        let distance = |i: usize, j: usize| a[i].0.abs_diff(b[j].0);
+
+        let mut comparison = Comparison::default();
+        let (mut i, mut j) = (0, 0);
+        while i < a.len() && j < b.len() {
+            if distance(i, j) > self.tolerance {
+                if a[i].0 < b[j].0 {
+                    comparison.unmatched_a += 1;
+                    i += 1;
+                } else {
+                    comparison.unmatched_b += 1;
+                    j += 1;
+                }
+            } else if i + 1 < a.len() && distance(i + 1, j) < distance(i, j) {
+                // The next cycle of the first device is a better match
+                comparison.unmatched_a += 1;
+                i += 1;
+            } else if j + 1 < b.len() && distance(i, j + 1) < distance(i, j) {
+                comparison.unmatched_b += 1;
+                j += 1;
+            } else {
+                comparison.pairs.push(CyclePair {
+                    elapsed: a[i].0 as u64,
+                    a: a[i].1,
+                    b: b[j].1,
+                });
+                i += 1;
+                j += 1;
+            }
+        }
+        comparison.unmatched_a += a.len() - i;
+        comparison.unmatched_b += b.len() - j;
+        comparison
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::MachineStateSnapshot;
+
+    fn recording(start: u64, period: u64, cycles: u32, peak: u16) -> Vec<TelemetryMessage> {
+        (1..=cycles)
+            .map(|cycle| {
+                TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+                    telemetry_version: 2,
+                    device_id: "1-2-3".to_owned(),
+                    systick: start + u64::from(cycle) * period,
+                    cycle,
+                    previous_peak_pressure: peak + (cycle % 2) as u16,
+                    previous_plateau_pressure: 200,
+                    previous_peep_pressure: 50,
+                    previous_volume: Some(500),
+                    ..Default::default()
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn aligns_cycles_by_time() {
+        // The second device was recorded from 2 cycles later, and its cycles end 100 ms after those of the first one
+        let a = recording(0, 3_000_000, 20, 300);
+        let b = recording(6_100_000, 3_000_000, 18, 310);
+
+        let comparison = CycleAligner::new().offset(6_000_000).compare(&a, &b);
+        assert_eq!(comparison.pairs.len(), 18);
+        assert_eq!(comparison.unmatched_a, 2);
+        assert_eq!(comparison.unmatched_b, 0);
+        assert_eq!(comparison.pairs[0].a.cycle, 3);
+        assert_eq!(comparison.pairs[0].b.cycle, 1);
+
+        let summaries = comparison.summaries();
+        let peak = summaries[0].unwrap();
+        assert_eq!(peak.count, 18);
+        assert_eq!(peak.mean, 10.0);
+        assert_eq!(peak.std_dev, 0.0);
+        assert!(summaries[4].is_none());
+        assert!(comparison.to_csv().starts_with(
+            "elapsed,cycle_a,cycle_b,peak_pressure_a,peak_pressure_b,peak_pressure_delta,"
+        ));
+    }
+
+    #[test]
+    fn picks_the_nearest_cycle() {
+        let a = recording(0, 3_000_000, 3, 300);
+        // An extra cycle ended shortly before the one matching the second cycle of the first device
+        let mut b = recording(0, 3_000_000, 3, 300);
+        let mut extra = b[1].clone();
+        if let TelemetryMessage::MachineStateSnapshot(snapshot) = &mut extra {
+            snapshot.systick -= 300_000;
+        }
+        b.insert(1, extra);
+
+        let comparison = CycleAligner::new().compare(&a, &b);
+        assert_eq!(comparison.pairs.len(), 3);
+        assert_eq!(comparison.unmatched_a, 0);
+        assert_eq!(comparison.unmatched_b, 1);
+        assert!(comparison
+            .pairs
+            .iter()
+            .all(|pair| pair.a.systick == pair.b.systick));
+
+        // The second recording started before the first one
+        let comparison = CycleAligner::new().offset(-3_000_000).compare(&a, &a);
+        assert_eq!(comparison.pairs.len(), 2);
+        assert_eq!((comparison.unmatched_a, comparison.unmatched_b), (1, 1));
+        assert_eq!(comparison.pairs[0].a.cycle, 1);
+        assert_eq!(comparison.pairs[0].b.cycle, 2);
+    }
+
+    #[test]
+    fn reports_recordings_that_do_not_overlap() {
+        let a = recording(0, 3_000_000, 5, 300);
+
+        let comparison = CycleAligner::new().compare(&a, &[]);
+        assert!(comparison.pairs.is_empty());
+        assert_eq!((comparison.unmatched_a, comparison.unmatched_b), (5, 0));
+        assert!(comparison.summaries().iter().all(Option::is_none));
+        assert_eq!(comparison.to_csv().lines().count(), 1);
+
+        let comparison = CycleAligner::new()
+            .offset(60_000_000)
+            .tolerance(0)
+            .compare(&a, &a);
+        assert!(comparison.pairs.is_empty());
+        assert_eq!((comparison.unmatched_a, comparison.unmatched_b), (5, 5));
+    }
+}
@@ -7,13 +7,27 @@ use nom::IResult;
 use std::ops::RangeInclusive;
 
 use crate::locale::Locale;
-use crate::structures::{TelemetryError, TelemetryErrorKind};
+use crate::structures::{
+    TelemetryError, TelemetryErrorKind, VentilationMode, VentilationModeClass, VentilationModeKind,
+};
 
 /// Special value that can be used in a heartbeat control message to disable RPi watchdog
 pub const DISABLE_RPI_WATCHDOG: u16 = 43_690;
 
+/// Bytes starting a control frame
+pub const CONTROL_FRAME_HEADER: &[u8; 2] = b"\x05\x0A";
+
+/// Bytes starting a control frame protected against replays
+pub const PROTECTED_CONTROL_FRAME_HEADER: &[u8; 2] = b"\x05\x0B";
+
+/// Bytes ending a control frame (protected or not)
+pub const CONTROL_FRAME_FOOTER: &[u8; 2] = b"\x50\xA0";
+
+/// Highest setting ID of the control protocol v1 (ID 1 was the peak pressure, later replaced by the ventilation mode)
+const MAXIMUM_SETTING_ID_V1: u8 = 9;
+
 /// Available settings in the control protocol
-#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash)]
 #[cfg_attr(
     feature = "serde-messages",
     derive(serde::Serialize, serde::Deserialize)
@@ -89,6 +103,16 @@ pub enum ControlSetting {
     PeakPressureAlarmThreshold = 30,
     /// Confirm end-of-line test step (value bounds must be between 0 and 0)
     EolConfirm = 31,
+    /// Time synchronization request; value is a token chosen by the host, different for every pending request (the firmware echoes it in a `ControlAck`, whose systick marks when it was handled)
+    ///
+    /// It is sent right after `WallClockHigh` and `WallClockLow`, which give the host wall-clock at the time it was sent.
+    TimeSync = 32,
+    /// Send only one data snapshot out of this number, e.g. 4 for 25 Hz instead of 100 Hz (value bounds must be between 1 and 5); firmwares that predate it ignore it without sending any ACK (see `decimation::DecimationRequest`)
+    DataSnapshotDecimation = 33,
+    /// High 16 bits of the host wall-clock (number of seconds since UNIX epoch) of the next `TimeSync` request
+    WallClockHigh = 34,
+    /// Low 16 bits of the host wall-clock (number of seconds since UNIX epoch) of the next `TimeSync` request
+    WallClockLow = 35,
 }
 
 impl ControlSetting {
@@ -128,6 +152,10 @@ impl ControlSetting {
             Self::PatientGender => 0,
             Self::PeakPressureAlarmThreshold => 500,
             Self::EolConfirm => 0,
+            Self::TimeSync => 0,
+            Self::DataSnapshotDecimation => 1,
+            Self::WallClockHigh => 0,
+            Self::WallClockLow => 0,
         }
     }
 
@@ -167,6 +195,100 @@ impl ControlSetting {
             Self::PatientGender => RangeInclusive::new(0, 1),
             Self::PeakPressureAlarmThreshold => RangeInclusive::new(50, 700),
             Self::EolConfirm => RangeInclusive::new(0, 0),
+            Self::TimeSync => RangeInclusive::new(0, u16::MAX.into()),
+            Self::DataSnapshotDecimation => RangeInclusive::new(1, 5),
+            Self::WallClockHigh => RangeInclusive::new(0, u16::MAX.into()),
+            Self::WallClockLow => RangeInclusive::new(0, u16::MAX.into()),
+        }
+    }
+
+    /// Iterate over every setting of the control protocol
+    pub fn iter() -> impl Iterator<Item = Self> {
+        (0..=u8::MAX).filter_map(|number| Self::try_from(number).ok())
+    }
+
+    /// Whether the setting is relevant when the MCU is in the given ventilation mode
+    ///
+    /// Settings that are not ventilation parameters (heartbeat, end-of-line test confirmation, time synchronization and data snapshot decimation) and legacy settings superseded by ventilation modes (expiratory term, trigger state and offset) are not applicable in any mode.
+    pub fn applicable_in(&self, mode: VentilationMode) -> bool {
+        match self {
+            Self::Heartbeat
+            | Self::EolConfirm
+            | Self::TimeSync
+            | Self::WallClockHigh
+            | Self::WallClockLow
+            | Self::DataSnapshotDecimation
+            | Self::ExpiratoryTerm
+            | Self::TriggerEnabled
+            | Self::TriggerOffset => false,
+            Self::VentilationMode
+            | Self::PEEP
+            | Self::CyclesPerMinute
+            | Self::RespirationEnabled
+            | Self::AlarmSnooze
+            | Self::Locale
+            | Self::PatientHeight
+            | Self::PatientGender
+            | Self::LowInspiratoryMinuteVolumeAlarmThreshold
+            | Self::HighInspiratoryMinuteVolumeAlarmThreshold
+            | Self::LowExpiratoryMinuteVolumeAlarmThreshold
+            | Self::HighExpiratoryMinuteVolumeAlarmThreshold
+            | Self::LowRespiratoryRateAlarmThreshold
+            | Self::HighRespiratoryRateAlarmThreshold
+            | Self::LowTidalVolumeAlarmThreshold
+            | Self::HighTidalVolumeAlarmThreshold
+            | Self::LeakAlarmThreshold
+            | Self::PeakPressureAlarmThreshold => true,
+            Self::PlateauPressure => mode.class() == VentilationModeClass::Pressure,
+            Self::TargetTidalVolume | Self::TargetInspiratoryFlow | Self::PlateauDuration => {
+                mode.class() == VentilationModeClass::Volume
+            }
+            Self::InspiratoryDuration => {
+                matches!(mode, VentilationMode::PC_CMV | VentilationMode::PC_AC)
+            }
+            Self::InspiratoryTriggerFlow => matches!(
+                mode.kind(),
+                VentilationModeKind::Ac | VentilationModeKind::Vsai
+            ),
+            Self::ExpiratoryTriggerFlow | Self::TiMin | Self::TiMax => {
+                mode.kind() == VentilationModeKind::Vsai
+            }
+        }
+    }
+
+    /// Granularity of values per setting (the firmware rounds values to a multiple of this step)
+    pub fn step(&self) -> usize {
+        match self {
+            Self::PlateauPressure => 10,
+            Self::PEEP => 10,
+            Self::TriggerOffset => 5,
+            Self::TiMin => 10,
+            Self::TiMax => 10,
+            Self::TargetTidalVolume => 10,
+            Self::LowTidalVolumeAlarmThreshold => 10,
+            Self::HighTidalVolumeAlarmThreshold => 10,
+            Self::PlateauDuration => 10,
+            Self::LeakAlarmThreshold => 10,
+            Self::InspiratoryDuration => 10,
+            Self::PeakPressureAlarmThreshold => 10,
+            _ => 1,
+        }
+    }
+
+    /// Predict the value that the firmware will apply when receiving the given value
+    ///
+    /// Like the firmware, this rounds the value to the nearest step and then clamps it within bounds.
+    /// Values of settings that are not numbers (heartbeat, locale and time synchronization) are returned unchanged.
+    pub fn clamp(&self, value: u16) -> u16 {
+        match self {
+            Self::Heartbeat | Self::Locale | Self::TimeSync => value,
+            _ => {
+                let step = self.step();
+                let rounded = (usize::from(value) + step / 2) / step * step;
+                let bounds = self.bounds();
+                let clamped = rounded.clamp(*bounds.start(), *bounds.end());
+                u16::try_from(clamped).unwrap_or(u16::MAX)
+            }
         }
     }
 }
@@ -208,16 +330,89 @@ impl std::convert::TryFrom<u8> for ControlSetting {
             29 => Ok(ControlSetting::PatientGender),
             30 => Ok(ControlSetting::PeakPressureAlarmThreshold),
             31 => Ok(ControlSetting::EolConfirm),
+            32 => Ok(ControlSetting::TimeSync),
+            33 => Ok(ControlSetting::DataSnapshotDecimation),
+            34 => Ok(ControlSetting::WallClockHigh),
+            35 => Ok(ControlSetting::WallClockLow),
             _ => Err("Invalid setting number"),
         }
     }
 }
 
+#[cfg(feature = "rand")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "rand")))]
+impl ControlSetting {
+    /// Relative frequency of the setting in randomly generated control messages
+    ///
+    /// Ventilation parameters are the most frequent, then alarm thresholds; settings that do not change ventilation (heartbeat, end-of-line test confirmation, time synchronization, data snapshot decimation) are the rarest.
+    pub fn random_weight(&self) -> u32 {
+        match self {
+            Self::VentilationMode
+            | Self::PlateauPressure
+            | Self::PEEP
+            | Self::CyclesPerMinute
+            | Self::InspiratoryTriggerFlow
+            | Self::ExpiratoryTriggerFlow
+            | Self::TiMin
+            | Self::TiMax
+            | Self::TargetTidalVolume
+            | Self::PlateauDuration
+            | Self::TargetInspiratoryFlow
+            | Self::InspiratoryDuration => 10,
+            Self::LowInspiratoryMinuteVolumeAlarmThreshold
+            | Self::HighInspiratoryMinuteVolumeAlarmThreshold
+            | Self::LowExpiratoryMinuteVolumeAlarmThreshold
+            | Self::HighExpiratoryMinuteVolumeAlarmThreshold
+            | Self::LowRespiratoryRateAlarmThreshold
+            | Self::HighRespiratoryRateAlarmThreshold
+            | Self::LowTidalVolumeAlarmThreshold
+            | Self::HighTidalVolumeAlarmThreshold
+            | Self::LeakAlarmThreshold
+            | Self::PeakPressureAlarmThreshold => 5,
+            Self::ExpiratoryTerm
+            | Self::TriggerEnabled
+            | Self::TriggerOffset
+            | Self::RespirationEnabled
+            | Self::AlarmSnooze
+            | Self::Locale
+            | Self::PatientHeight
+            | Self::PatientGender => 3,
+            Self::Heartbeat
+            | Self::EolConfirm
+            | Self::TimeSync
+            | Self::WallClockHigh
+            | Self::WallClockLow
+            | Self::DataSnapshotDecimation => 1,
+        }
+    }
+
+    /// Generate a random value the firmware accepts as is for this setting (within bounds, multiple of the step, valid locale)
+    pub fn random_value<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> u16 {
+        match self {
+            Self::Locale => {
+                let letters = [rng.gen_range(b'a'..=b'z'), rng.gen_range(b'a'..=b'z')];
+                u16::from_be_bytes(letters)
+            }
+            _ => self.clamp(u16::try_from(rng.gen_range(self.bounds())).unwrap_or(u16::MAX)),
+        }
+    }
+}
+
+/// Sample every setting of the control protocol, according to its `random_weight()`
 #[cfg(feature = "rand")]
 impl rand::distributions::Distribution<ControlSetting> for rand::distributions::Standard {
     fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> ControlSetting {
-        let number = rng.gen_range(1..=5);
-        ControlSetting::try_from(number).unwrap()
+        let total: u32 = ControlSetting::iter()
+            .map(|setting| setting.random_weight())
+            .sum();
+        let mut pick = rng.gen_range(0..total);
+        for setting in ControlSetting::iter() {
+            match pick.checked_sub(setting.random_weight()) {
+                Some(rest) => pick = rest,
+                None => return setting,
+            }
+        }
+        unreachable!("pick is lower than the sum of weights")
     }
 }
 
@@ -230,11 +425,12 @@ pub struct ControlMessage {
     pub value: u16,
 }
 
+/// Sample a message for a random setting, with a value the firmware accepts as is (within bounds, multiple of the step, valid locale)
 #[cfg(feature = "rand")]
 impl rand::distributions::Distribution<ControlMessage> for rand::distributions::Standard {
     fn sample<R: rand::Rng + ?Sized>(&self, rng: &mut R) -> ControlMessage {
         let setting: ControlSetting = rng.gen();
-        let value = u16::try_from(rng.gen_range(setting.bounds())).unwrap_or(u16::MAX);
+        let value = setting.random_value(rng);
         ControlMessage { setting, value }
     }
 }
@@ -249,6 +445,17 @@ fn flat(v: &[&[u8]]) -> Vec<u8> {
     v.iter().flat_map(|a| a.iter()).copied().collect()
 }
 
+impl ControlSetting {
+    /// ID of the setting in the control protocol v1, if it existed
+    fn id_v1(self) -> Option<u8> {
+        match self {
+            Self::VentilationMode => None,
+            _ if self as u8 <= MAXIMUM_SETTING_ID_V1 => Some(self as u8),
+            _ => None,
+        }
+    }
+}
+
 impl ControlMessage {
     fn to_bytes(&self) -> Vec<u8> {
         flat(&[&[self.setting as u8], &self.value.to_be_bytes()])
@@ -267,10 +474,10 @@ impl ControlMessage {
     /// * `force_crc` - CRC value to be used; will be computed if not specified.
     pub fn to_control_frame_with(&self, force_crc: Option<u32>) -> Vec<u8> {
         flat(&[
-            b"\x05\x0A",
+            CONTROL_FRAME_HEADER,
             &self.to_bytes(),
             &force_crc.unwrap_or_else(|| self.crc()).to_be_bytes(),
-            b"\x50\xA0",
+            CONTROL_FRAME_FOOTER,
         ])
     }
 
@@ -278,7 +485,186 @@ impl ControlMessage {
     ///
     /// This converts message to binary and adds header, footer and CRC
     pub fn to_control_frame(&self) -> Vec<u8> {
-        self.to_control_frame_with(None)
+        self.to_control_frame_v2()
+    }
+
+    /// Create a frame for MCUs speaking the control protocol v1 (firmware 1.x), or `None` if the setting did not exist yet
+    pub fn to_control_frame_v1(&self) -> Option<Vec<u8>> {
+        let id = self.setting.id_v1()?;
+        Some(crate::framing::encode_with(
+            crate::framing::FrameKind::Control,
+            &flat(&[&[id], &self.value.to_be_bytes()]),
+        ))
+    }
+
+    /// Create a frame for MCUs speaking the control protocol v2 (also used with telemetry protocol v3)
+    pub fn to_control_frame_v2(&self) -> Vec<u8> {
+        crate::framing::encode_with(crate::framing::FrameKind::Control, &self.to_bytes())
+    }
+
+    /// Create a frame protected against replays, to be sent to firmwares supporting it (see `replay::ControlCapabilities`)
+    ///
+    /// Besides the message, the frame contains the nonce of the current session of the device and a sequence number, both covered by the CRC.
+    pub fn to_protected_control_frame(&self, nonce: u32, sequence: u32) -> Vec<u8> {
+        let protected_bytes = flat(&[
+            &self.to_bytes(),
+            &nonce.to_be_bytes(),
+            &sequence.to_be_bytes(),
+        ]);
+        crate::framing::encode_with(
+            crate::framing::FrameKind::ProtectedControl,
+            &protected_bytes,
+        )
+    }
+}
+
+/// A control message protected against replays
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ProtectedControlMessage {
+    /// The control message
+    pub message: ControlMessage,
+    /// Nonce of the session of the device the message was meant for
+    pub nonce: u32,
+    /// Sequence number of the message in the session
+    pub sequence: u32,
+}
+
+/// Control messages meant to be reviewed and sent together (e.g. a set of proposed alarm thresholds)
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ControlMessageGroup {
+    /// Description of the group, to be displayed to whoever reviews it
+    pub description: String,
+    /// Messages of the group, in the order they should be sent
+    pub messages: Vec<ControlMessage>,
+}
+
+impl ControlMessageGroup {
+    /// Create an empty group
+    pub fn new<S: Into<String>>(description: S) -> Self {
+        Self {
+            description: description.into(),
+            messages: Vec::new(),
+        }
+    }
+
+    /// Add a message to the group, replacing any previous message for the same setting
+    pub fn push(&mut self, message: ControlMessage) {
+        self.messages
+            .retain(|existing| existing.setting != message.setting);
+        self.messages.push(message);
+    }
+
+    /// Value proposed for a setting, if any
+    pub fn get(&self, setting: ControlSetting) -> Option<u16> {
+        self.messages
+            .iter()
+            .find(|message| message.setting == setting)
+            .map(|message| message.value)
+    }
+
+    /// Whether the group contains no message
+    pub fn is_empty(&self) -> bool {
+        self.messages.is_empty()
+    }
+
+    /// Create the frames of every message of the group, in order
+    pub fn to_control_frames(&self) -> Vec<Vec<u8>> {
+        self.messages
+            .iter()
+            .map(ControlMessage::to_control_frame)
+            .collect()
+    }
+}
+
+/// Error returned when a mode change cannot be planned
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum ModeChangeError {
+    /// The target mode is unknown to this version of the library
+    #[error("unknown ventilation mode {0}")]
+    UnknownMode(u8),
+    /// A setting is not relevant in the target mode
+    #[error("{setting:?} is not applicable in {mode:?}")]
+    NotApplicable {
+        /// The setting
+        setting: ControlSetting,
+        /// The target mode
+        mode: VentilationMode,
+    },
+    /// A setting is sent by the plan itself (ventilation mode and respiration state)
+    #[error("{0:?} is set by the mode change plan itself")]
+    Reserved(ControlSetting),
+}
+
+/// Safe sequence of control messages to switch the ventilation mode
+///
+/// Respiration is disabled first, then the mode is changed and its settings are sent, and finally respiration is enabled again, so that the MCU never ventilates with a mix of settings of both modes.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ModeChangePlan {
+    target_mode: VentilationMode,
+    settings: Vec<ControlMessage>,
+}
+
+impl ModeChangePlan {
+    /// Plan a switch to a ventilation mode
+    ///
+    /// * `target_mode` - Mode to switch to.
+    /// * `settings` - Settings to send once in the new mode; they must be applicable in this mode (see `ControlSetting::applicable_in()`).
+    pub fn new<I: IntoIterator<Item = ControlMessage>>(
+        target_mode: VentilationMode,
+        settings: I,
+    ) -> Result<Self, ModeChangeError> {
+        if let VentilationMode::Unknown(mode) = target_mode {
+            return Err(ModeChangeError::UnknownMode(mode));
+        }
+        let settings: Vec<ControlMessage> = settings.into_iter().collect();
+        for message in &settings {
+            match message.setting {
+                ControlSetting::VentilationMode | ControlSetting::RespirationEnabled => {
+                    return Err(ModeChangeError::Reserved(message.setting))
+                }
+                setting if !setting.applicable_in(target_mode) => {
+                    return Err(ModeChangeError::NotApplicable {
+                        setting,
+                        mode: target_mode,
+                    })
+                }
+                _ => (),
+            }
+        }
+        Ok(Self {
+            target_mode,
+            settings,
+        })
+    }
+
+    /// Mode to switch to
+    pub fn target_mode(&self) -> VentilationMode {
+        self.target_mode
+    }
+
+    /// Messages to send, in order
+    pub fn to_group(&self) -> ControlMessageGroup {
+        let mut group = ControlMessageGroup::new(format!(
+            "Switch to {}",
+            self.target_mode.display_name(&Locale::default())
+        ));
+        group.push(ControlMessage {
+            setting: ControlSetting::RespirationEnabled,
+            value: 0,
+        });
+        group.push(ControlMessage {
+            setting: ControlSetting::VentilationMode,
+            value: u8::from(&self.target_mode).into(),
+        });
+        for message in &self.settings {
+            group.push(message.clone());
+        }
+        // Not pushed, as it would replace the message disabling respiration
+        group.messages.push(ControlMessage {
+            setting: ControlSetting::RespirationEnabled,
+            value: 1,
+        });
+        group
     }
 }
 
@@ -289,11 +675,26 @@ fn parse_control_setting(input: &[u8]) -> IResult<&[u8], ControlSetting> {
     map_res(be_u8, ControlSetting::try_from)(input)
 }
 
+fn parse_control_setting_v1(input: &[u8]) -> IResult<&[u8], ControlSetting> {
+    use nom::combinator::{map_res, verify};
+    use nom::number::streaming::be_u8;
+
+    let id = verify(be_u8, |id| *id != 1 && *id <= MAXIMUM_SETTING_ID_V1);
+    map_res(id, ControlSetting::try_from)(input)
+}
+
 fn parse_inner_control_message(input: &[u8]) -> IResult<&[u8], ControlMessage> {
+    parse_inner_control_message_with(input, parse_control_setting)
+}
+
+fn parse_inner_control_message_with(
+    input: &[u8],
+    setting: fn(&[u8]) -> IResult<&[u8], ControlSetting>,
+) -> IResult<&[u8], ControlMessage> {
     use nom::number::streaming::be_u16;
     use nom::sequence::pair;
 
-    let mut parser = pair(parse_control_setting, be_u16);
+    let mut parser = pair(setting, be_u16);
     parser(input).map(|(rest, (setting, value))| (rest, ControlMessage { setting, value }))
 }
 
@@ -302,39 +703,123 @@ fn parse_inner_control_message(input: &[u8]) -> IResult<&[u8], ControlMessage> {
 /// * `input` - Bytes to parse.
 pub fn parse_control_message(
     input: &[u8],
+) -> IResult<&[u8], ControlMessage, TelemetryError<&[u8]>> {
+    parse_control_frame(input, parse_control_setting)
+}
+
+/// Same as `parse_control_message()`, for MCUs speaking an older or newer protocol
+///
+/// * `input` - Bytes to parse.
+/// * `version` - Version of the telemetry protocol of the MCU (see `link::protocol_version()`); protocol v3 uses the control frames of protocol v2.
+///
+/// Frames of the control protocol v1 setting the peak pressure, which was removed in v2, are rejected.
+/// Versions that do not exist (0, or newer than the latest one known by this library) are rejected with `TelemetryErrorKind::UnsupportedProtocolVersion`.
+pub fn parse_control_message_versioned(
+    input: &[u8],
+    version: u8,
+) -> IResult<&[u8], ControlMessage, TelemetryError<&[u8]>> {
+    use crate::parsers::MAXIMUM_SUPPORTED_VERSION;
+
+    match version {
+        1 => parse_control_frame(input, parse_control_setting_v1),
+        2 | 3 => parse_control_message(input),
+        _ => Err(nom::Err::Failure(TelemetryError(
+            input,
+            TelemetryErrorKind::UnsupportedProtocolVersion {
+                maximum_supported: MAXIMUM_SUPPORTED_VERSION,
+                found: version,
+            },
+        ))),
+    }
+}
+
+fn parse_control_frame(
+    input: &[u8],
+    setting: fn(&[u8]) -> IResult<&[u8], ControlSetting>,
 ) -> IResult<&[u8], ControlMessage, TelemetryError<&[u8]>> {
     use nom::bytes::streaming::tag;
     use nom::combinator::consumed;
     use nom::number::streaming::be_u32;
     use nom::sequence::{pair, preceded, terminated};
 
-    let header = tag(b"\x05\x0A");
-    let footer = tag(b"\x50\xA0");
+    let header = tag(CONTROL_FRAME_HEADER);
+    let footer = tag(CONTROL_FRAME_FOOTER);
     let mut parser = preceded(
         header,
-        terminated(pair(consumed(parse_inner_control_message), be_u32), footer),
+        terminated(
+            pair(
+                consumed(|i| parse_inner_control_message_with(i, setting)),
+                be_u32,
+            ),
+            footer,
+        ),
     );
 
     parser(input)
         .map_err(nom::Err::convert)
         .and_then(|(rest, ((msg_bytes, msg), expected_crc))| {
-            let mut crc = crc32fast::Hasher::new();
-            crc.update(msg_bytes);
-            let computed_crc = crc.finalize();
-            if expected_crc == computed_crc {
-                Ok((rest, msg))
-            } else {
-                Err(nom::Err::Failure(TelemetryError(
-                    input,
-                    TelemetryErrorKind::CrcError {
-                        expected: expected_crc,
-                        computed: computed_crc,
-                    },
-                )))
-            }
+            check_crc(input, msg_bytes, expected_crc).map(|_| (rest, msg))
         })
 }
 
+/// Transform bytes into a control message protected against replays
+///
+/// This only checks the CRC; use a `replay::ReplayValidator` to reject replayed frames.
+///
+/// * `input` - Bytes to parse.
+pub fn parse_protected_control_message(
+    input: &[u8],
+) -> IResult<&[u8], ProtectedControlMessage, TelemetryError<&[u8]>> {
+    use nom::bytes::streaming::tag;
+    use nom::combinator::consumed;
+    use nom::number::streaming::be_u32;
+    use nom::sequence::{pair, preceded, terminated, tuple};
+
+    let header = tag(PROTECTED_CONTROL_FRAME_HEADER);
+    let footer = tag(CONTROL_FRAME_FOOTER);
+    let mut parser = preceded(
+        header,
+        terminated(
+            pair(
+                consumed(tuple((parse_inner_control_message, be_u32, be_u32))),
+                be_u32,
+            ),
+            footer,
+        ),
+    );
+
+    parser(input).map_err(nom::Err::convert).and_then(
+        |(rest, ((msg_bytes, (message, nonce, sequence)), expected_crc))| {
+            check_crc(input, msg_bytes, expected_crc).map(|_| {
+                (
+                    rest,
+                    ProtectedControlMessage {
+                        message,
+                        nonce,
+                        sequence,
+                    },
+                )
+            })
+        },
+    )
+}
+
+fn check_crc<'a>(
+    input: &'a [u8],
+    msg_bytes: &[u8],
+    expected: u32,
+) -> Result<(), nom::Err<TelemetryError<&'a [u8]>>> {
+    let computed = crc32fast::hash(msg_bytes);
+    if expected == computed {
+        Ok(())
+    } else {
+        Err(nom::Err::Failure(TelemetryError(
+            input,
+            TelemetryErrorKind::CrcError { expected, computed },
+        )))
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -348,6 +833,166 @@ mod tests {
         })
     }
 
+    #[test]
+    fn settings_applicable_in_modes() {
+        assert_eq!(ControlSetting::iter().count(), 36);
+        assert!(ControlSetting::PlateauPressure.applicable_in(VentilationMode::PC_AC));
+        assert!(!ControlSetting::PlateauPressure.applicable_in(VentilationMode::VC_AC));
+        assert!(ControlSetting::TargetTidalVolume.applicable_in(VentilationMode::VC_CMV));
+        assert!(!ControlSetting::TargetTidalVolume.applicable_in(VentilationMode::PC_CMV));
+        assert!(!ControlSetting::InspiratoryTriggerFlow.applicable_in(VentilationMode::VC_CMV));
+        assert!(ControlSetting::TiMax.applicable_in(VentilationMode::PC_VSAI));
+        assert!(!ControlSetting::Heartbeat.applicable_in(VentilationMode::PC_AC));
+    }
+
+    #[test]
+    fn translates_control_frames_between_versions() {
+        let peep = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+        let frame = peep.to_control_frame_v1().unwrap();
+        assert_eq!(frame, peep.to_control_frame_v2());
+        assert_eq!(
+            parse_control_message_versioned(&frame, 1),
+            Ok((&[][..], peep))
+        );
+
+        let mode = ControlMessage {
+            setting: ControlSetting::VentilationMode,
+            value: 1,
+        };
+        assert_eq!(mode.to_control_frame_v1(), None);
+        let frame = mode.to_control_frame_v2();
+        // Setting 1 was the peak pressure in protocol v1
+        assert!(parse_control_message_versioned(&frame, 1).is_err());
+        assert_eq!(
+            parse_control_message_versioned(&frame, 3),
+            Ok((&[][..], mode))
+        );
+        for version in [0, 4] {
+            assert_eq!(
+                parse_control_message_versioned(&frame, version),
+                Err(nom::Err::Failure(TelemetryError(
+                    &frame[..],
+                    TelemetryErrorKind::UnsupportedProtocolVersion {
+                        maximum_supported: 3,
+                        found: version
+                    }
+                )))
+            );
+        }
+        assert_eq!(
+            ControlMessage {
+                setting: ControlSetting::TiMin,
+                value: 200
+            }
+            .to_control_frame_v1(),
+            None
+        );
+    }
+
+    #[test]
+    fn clamp_values() {
+        assert_eq!(ControlSetting::PEEP.clamp(84), 80);
+        assert_eq!(ControlSetting::PEEP.clamp(85), 90);
+        assert_eq!(ControlSetting::PEEP.clamp(1_000), 300);
+        assert_eq!(ControlSetting::TriggerOffset.clamp(12), 10);
+        assert_eq!(ControlSetting::CyclesPerMinute.clamp(2), 5);
+        assert_eq!(ControlSetting::CyclesPerMinute.clamp(21), 21);
+        assert_eq!(
+            ControlSetting::Heartbeat.clamp(DISABLE_RPI_WATCHDOG),
+            DISABLE_RPI_WATCHDOG
+        );
+    }
+
+    #[test]
+    fn mode_change_plan_order() {
+        let peep = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+        let volume = ControlMessage {
+            setting: ControlSetting::TargetTidalVolume,
+            value: 450,
+        };
+        let plan =
+            ModeChangePlan::new(VentilationMode::VC_AC, [peep.clone(), volume.clone()]).unwrap();
+        let settings: Vec<(ControlSetting, u16)> = plan
+            .to_group()
+            .messages
+            .iter()
+            .map(|message| (message.setting, message.value))
+            .collect();
+        assert_eq!(
+            settings,
+            [
+                (ControlSetting::RespirationEnabled, 0),
+                (ControlSetting::VentilationMode, 5),
+                (ControlSetting::PEEP, 80),
+                (ControlSetting::TargetTidalVolume, 450),
+                (ControlSetting::RespirationEnabled, 1),
+            ]
+        );
+
+        assert_eq!(
+            ModeChangePlan::new(VentilationMode::PC_CMV, [volume]),
+            Err(ModeChangeError::NotApplicable {
+                setting: ControlSetting::TargetTidalVolume,
+                mode: VentilationMode::PC_CMV
+            })
+        );
+        let enable = ControlMessage {
+            setting: ControlSetting::RespirationEnabled,
+            value: 1,
+        };
+        assert_eq!(
+            ModeChangePlan::new(VentilationMode::PC_AC, [peep, enable]),
+            Err(ModeChangeError::Reserved(
+                ControlSetting::RespirationEnabled
+            ))
+        );
+    }
+
+    #[cfg(feature = "rand")]
+    #[test]
+    fn random_messages_cover_every_setting() {
+        use rand::Rng;
+        use std::collections::HashSet;
+
+        let mut rng = rand::thread_rng();
+        let mut settings = HashSet::new();
+        for _ in 0..20_000 {
+            let message: ControlMessage = rng.gen();
+            settings.insert(message.setting);
+            if message.setting == ControlSetting::Locale {
+                assert!(crate::locale::Locale::try_from_u16(message.value).is_some());
+            } else {
+                assert_eq!(message.setting.clamp(message.value), message.value);
+                assert!(message
+                    .setting
+                    .bounds()
+                    .contains(&usize::from(message.value)));
+            }
+        }
+        assert_eq!(settings.len(), ControlSetting::iter().count());
+    }
+
+    proptest! {
+        #[test]
+        fn test_clamped_values_are_applicable(
+            setting in control_setting_strategy(),
+            value in num::u16::ANY,
+        ) {
+            let clamped = setting.clamp(value);
+
+            prop_assert_eq!(setting.clamp(clamped), clamped);
+            if !matches!(setting, ControlSetting::Heartbeat | ControlSetting::Locale | ControlSetting::TimeSync) {
+                prop_assert!(setting.bounds().contains(&usize::from(clamped)));
+            }
+        }
+    }
+
     proptest! {
         #[test]
         fn test_control_message_parser(
@@ -363,4 +1008,23 @@ mod tests {
             assert_eq!(nom::error::dbg_dmp(parse_control_message, "parse_control_message")(input), Ok((&[][..], msg)));
         }
     }
+
+    proptest! {
+        #[test]
+        fn test_protected_control_message_parser(
+            setting in control_setting_strategy(),
+            value in num::u16::ANY,
+            nonce in num::u32::ANY,
+            sequence in num::u32::ANY,
+        ) {
+            let message = ControlMessage {
+                setting,
+                value,
+            };
+            let input = &message.to_protected_control_frame(nonce, sequence);
+
+            prop_assert_eq!(parse_protected_control_message(input), Ok((&[][..], ProtectedControlMessage { message, nonce, sequence })));
+            prop_assert!(parse_control_message(input).is_err());
+        }
+    }
 }
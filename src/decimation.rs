@@ -0,0 +1,248 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::control::{ControlMessage, ControlSetting};
+use crate::replay::ControlCapabilities;
+use crate::structures::TelemetryMessage;
+
+/// Number of data snapshots received after a decimation request without its ACK, after which the firmware is considered not to support it (1 s at 100 Hz)
+pub const DEFAULT_ACK_TIMEOUT: u32 = 100;
+
+/// Whether the firmware decimates data snapshots as requested
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DecimationSupport {
+    /// The request was not answered yet
+    Unknown,
+    /// The firmware acknowledged the request
+    Supported {
+        /// Decimation factor applied by the firmware
+        factor: u16,
+    },
+    /// The firmware does not advertise the setting in its boot message, or kept sending data snapshots without acknowledging the request: it predates the setting and ignored it
+    Unsupported,
+}
+
+/// Asks the MCU to send fewer data snapshots (`DataSnapshotDecimation` setting), e.g. to save the battery of remote bridges, and detects whether its firmware supports it
+///
+/// Send the control message returned by `request()` to the MCU, then pass every telemetry message to `push()` and send the control messages it returns: firmwares start over with every data snapshot after a boot, so the request is sent again.
+/// Firmwares that do not know the setting ignore it without sending any ACK; data snapshots then keep their usual frequency.
+/// Firmwares using the telemetry protocol v3 advertise whether they support it in their boot messages (`ControlCapabilities::DATA_SNAPSHOT_DECIMATION`), so the request is not sent again to those that do not; older firmwares are detected once `ack_timeout` data snapshots were received without ACK.
+#[derive(Debug, Clone)]
+pub struct DecimationRequest {
+    factor: u16,
+    ack_timeout: u32,
+    support: DecimationSupport,
+    /// Number of data snapshots received since the unanswered request was sent
+    waiting: Option<u32>,
+}
+
+impl DecimationRequest {
+    /// Create a request to send only one data snapshot out of `factor` (clamped within the bounds of the setting)
+    pub fn new(factor: u16) -> Self {
+        Self {
+            factor: ControlSetting::DataSnapshotDecimation.clamp(factor),
+            ack_timeout: DEFAULT_ACK_TIMEOUT,
+            support: DecimationSupport::Unknown,
+            waiting: None,
+        }
+    }
+
+    /// Number of data snapshots to wait for the ACK of a request before considering the firmware does not support it
+    pub fn ack_timeout(mut self, ack_timeout: u32) -> Self {
+        self.ack_timeout = ack_timeout;
+        self
+    }
+
+    /// Requested decimation factor
+    pub fn factor(&self) -> u16 {
+        self.factor
+    }
+
+    /// Support of the firmware, as detected from its answers so far
+    pub fn support(&self) -> DecimationSupport {
+        self.support
+    }
+
+    /// Create the control message asking for decimation, to be sent to the MCU
+    pub fn request(&mut self) -> ControlMessage {
+        self.waiting = Some(0);
+        ControlMessage {
+            setting: ControlSetting::DataSnapshotDecimation,
+            value: self.factor,
+        }
+    }
+
+    /// Handle a telemetry message received from the MCU
+    ///
+    /// Returns the control message to send again if the MCU rebooted, unless its firmware advertised it does not support it.
+    pub fn push(&mut self, message: &TelemetryMessage) -> Option<ControlMessage> {
+        match message {
+            TelemetryMessage::BootMessage(boot) => {
+                let capabilities = boot
+                    .control_capabilities
+                    .map(ControlCapabilities::from_bits);
+                if capabilities.is_some_and(|c| !c.supports_data_snapshot_decimation()) {
+                    self.support = DecimationSupport::Unsupported;
+                    self.waiting = None;
+                    return None;
+                }
+                self.support = DecimationSupport::Unknown;
+                return Some(self.request());
+            }
+            TelemetryMessage::ControlAck(ack)
+                if ack.setting == ControlSetting::DataSnapshotDecimation =>
+            {
+                self.support = DecimationSupport::Supported { factor: ack.value };
+                self.waiting = None;
+            }
+            TelemetryMessage::DataSnapshot(_) => {
+                if let Some(waiting) = self.waiting.as_mut() {
+                    *waiting += 1;
+                    if *waiting >= self.ack_timeout {
+                        self.support = DecimationSupport::Unsupported;
+                        self.waiting = None;
+                    }
+                }
+            }
+            _ => (),
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+    use crate::structures::{BootMessage, ControlAck, Mode};
+
+    #[test]
+    fn detects_support_from_acks() {
+        let mut messages = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy);
+        let boot = messages.next().unwrap();
+        let mut request = DecimationRequest::new(40).ack_timeout(10);
+        assert_eq!(request.factor(), 5);
+
+        // The MCU rebooted: the request is sent again
+        assert_eq!(
+            request.push(&boot),
+            Some(ControlMessage {
+                setting: ControlSetting::DataSnapshotDecimation,
+                value: 5,
+            })
+        );
+        request.push(&TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 0,
+            setting: ControlSetting::DataSnapshotDecimation,
+            value: 5,
+        }));
+        assert_eq!(
+            request.support(),
+            DecimationSupport::Supported { factor: 5 }
+        );
+
+        // An older firmware ignores the request and keeps sending every data snapshot
+        request.request();
+        for message in messages.by_ref().take(100) {
+            assert_eq!(request.push(&message), None);
+        }
+        assert_eq!(request.support(), DecimationSupport::Unsupported);
+        assert_eq!(request.push(&boot).map(|message| message.value), Some(5));
+        assert_eq!(request.support(), DecimationSupport::Unknown);
+    }
+
+    #[test]
+    fn detects_support_from_capabilities() {
+        let boot = |control_capabilities| {
+            TelemetryMessage::BootMessage(BootMessage {
+                telemetry_version: 3,
+                version: "test".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 0,
+                mode: Mode::Production,
+                value128: 128,
+                control_capabilities,
+                session_nonce: Some(42),
+            })
+        };
+        let mut request = DecimationRequest::new(4);
+        request.request();
+
+        assert_eq!(request.push(&boot(Some(0))), None);
+        assert_eq!(request.support(), DecimationSupport::Unsupported);
+
+        let supported = boot(Some(ControlCapabilities::DATA_SNAPSHOT_DECIMATION));
+        assert!(request.push(&supported).is_some());
+        assert_eq!(request.support(), DecimationSupport::Unknown);
+    }
+
+    #[test]
+    fn handles_late_acks_and_reboots() {
+        let mut messages = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .filter(|message| matches!(message, TelemetryMessage::DataSnapshot(_)));
+        let ack = |setting, value| {
+            TelemetryMessage::ControlAck(ControlAck {
+                telemetry_version: 2,
+                version: "test".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 0,
+                setting,
+                value,
+            })
+        };
+        let mut request = DecimationRequest::new(4).ack_timeout(3);
+
+        // Nothing is waited for before the request is sent
+        for message in messages.by_ref().take(5) {
+            request.push(&message);
+        }
+        assert_eq!(request.support(), DecimationSupport::Unknown);
+
+        // The request times out, and ACKs of other settings do not answer it
+        request.request();
+        request.push(&ack(ControlSetting::PEEP, 4));
+        for message in messages.by_ref().take(3) {
+            request.push(&message);
+        }
+        assert_eq!(request.support(), DecimationSupport::Unsupported);
+
+        // The MCU reboots: support is unknown again until the request sent again is answered
+        let boot = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .next()
+            .unwrap();
+        assert!(request.push(&boot).is_some());
+        assert_eq!(request.support(), DecimationSupport::Unknown);
+
+        // A late ACK still proves support, with the factor actually applied by the firmware
+        request.push(&ack(ControlSetting::DataSnapshotDecimation, 2));
+        assert_eq!(
+            request.support(),
+            DecimationSupport::Supported { factor: 2 }
+        );
+        // Data snapshots do not time out an answered request
+        for message in messages.by_ref().take(10) {
+            assert_eq!(request.push(&message), None);
+        }
+        assert_eq!(
+            request.support(),
+            DecimationSupport::Supported { factor: 2 }
+        );
+
+        // An ACK received after the request timed out overrides the timeout
+        request.request();
+        for message in messages.by_ref().take(3) {
+            request.push(&message);
+        }
+        assert_eq!(request.support(), DecimationSupport::Unsupported);
+        request.push(&ack(ControlSetting::DataSnapshotDecimation, 4));
+        assert_eq!(
+            request.support(),
+            DecimationSupport::Supported { factor: 4 }
+        );
+    }
+}
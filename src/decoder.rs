@@ -0,0 +1,325 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::sync::mpsc::Sender;
+
+#[cfg(feature = "runtime")]
+use crate::capture::FrameCapture;
+use crate::diagnostics::DecodeDiagnostic;
+use crate::parsers::{parse_telemetry_message_with_warnings, resync_offset, ParserConfig};
+use crate::structures::{HighLevelError, TelemetryError, TelemetryErrorKind, TelemetryMessage};
+
+/// A frame found by a `TelemetryDecoder`
+#[derive(Debug, Clone, PartialEq)]
+pub struct DecodedFrame<'a> {
+    /// Bytes of the frame, including header, CRC and footer
+    pub frame: &'a [u8],
+    /// The decoded message, or why the frame could not be decoded
+    pub result: Result<TelemetryMessage, HighLevelError>,
+}
+
+/// How the `gather_telemetry*()` functions decode frames
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DecodeConfig {
+    /// Limits and strictness of the parser
+    pub parser: ParserConfig,
+    /// If set, the last frames are kept in this capture, to be attached to bug reports
+    #[cfg(feature = "runtime")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+    pub frame_capture: Option<FrameCapture>,
+}
+
+impl DecodeConfig {
+    /// Create the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Choose the limits and strictness of the parser
+    pub fn parser(mut self, parser: ParserConfig) -> Self {
+        self.parser = parser;
+        self
+    }
+
+    /// Keep the last frames in a capture; keep a clone of it to dump them
+    #[cfg(feature = "runtime")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+    pub fn frame_capture(mut self, capture: FrameCapture) -> Self {
+        self.frame_capture = Some(capture);
+        self
+    }
+}
+
+/// Streaming decoder of telemetry frames, independent of any I/O
+///
+/// Bytes are pushed as they are received, in chunks of any size, and messages are pulled once their frame is complete.
+/// Bytes that do not belong to a frame are skipped until the next frame header; frames with a CRC error or an unsupported protocol version are returned as errors.
+#[derive(Debug, Default)]
+pub struct TelemetryDecoder {
+    buffer: Vec<u8>,
+    position: usize,
+    frame_start: usize,
+    config: ParserConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+}
+
+impl TelemetryDecoder {
+    /// Create a decoder using the default parser configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create a decoder with its own parser configuration
+    pub fn with_config(config: ParserConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Also send non-fatal problems (CRC errors, dropped bytes, field warnings) through a channel, until its receiver is dropped
+    pub fn diagnostics(mut self, tx: Option<Sender<DecodeDiagnostic>>) -> Self {
+        self.diagnostics_tx = tx;
+        self
+    }
+
+    /// Choose the limits and strictness of the parser, keeping the diagnostics channel
+    #[cfg(feature = "runtime")]
+    pub(crate) fn parser_config(mut self, config: ParserConfig) -> Self {
+        self.config = config;
+        self
+    }
+
+    fn report(&mut self, diagnostic: DecodeDiagnostic) {
+        if let Some(tx) = self.diagnostics_tx.as_ref() {
+            if tx.send(diagnostic).is_err() {
+                self.diagnostics_tx = None;
+            }
+        }
+    }
+
+    /// Add received bytes
+    pub fn push_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.drain(..self.position);
+        self.position = 0;
+        self.frame_start = 0;
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Number of bytes received but not decoded yet
+    pub fn pending_bytes(&self) -> usize {
+        self.buffer.len() - self.position
+    }
+
+    /// Drop bytes received but not decoded yet, e.g. after reconnecting to a device
+    pub fn clear(&mut self) {
+        self.buffer.clear();
+        self.position = 0;
+        self.frame_start = 0;
+    }
+
+    /// Decode the next message, or `None` if more bytes are needed
+    pub fn next_message(&mut self) -> Option<Result<TelemetryMessage, HighLevelError>> {
+        let config = self.config;
+        let (result, length) = loop {
+            let input = &self.buffer[self.position..];
+            if input.is_empty() {
+                return None;
+            }
+            match parse_telemetry_message_with_warnings(input, &config) {
+                Ok((rest, (message, warnings))) => {
+                    let length = input.len() - rest.len();
+                    for warning in warnings {
+                        #[cfg(feature = "log")]
+                        log::warn!("[field warning]\t{}", warning);
+                        self.report(DecodeDiagnostic::Field(warning));
+                    }
+                    break (Ok(message), length);
+                }
+                Err(nom::Err::Failure(TelemetryError(
+                    frame,
+                    TelemetryErrorKind::CrcError { expected, computed },
+                ))) => {
+                    #[cfg(feature = "log")]
+                    log::warn!("[CRC error]\texpected={}\tcomputed={}", expected, computed);
+                    let length = frame.len();
+                    self.report(DecodeDiagnostic::CrcError { expected, computed });
+                    break (Err(HighLevelError::CrcError { expected, computed }), length);
+                }
+                Err(nom::Err::Failure(TelemetryError(
+                    _,
+                    TelemetryErrorKind::UnsupportedProtocolVersion {
+                        maximum_supported,
+                        found,
+                    },
+                ))) => {
+                    #[cfg(feature = "log")]
+                    log::warn!(
+                        "[unsupported protocol version]\tmaximum_supported={}\tfound={}",
+                        maximum_supported,
+                        found
+                    );
+                    let length = resync_offset(input, config.resync_window);
+                    self.report(DecodeDiagnostic::UnsupportedProtocolVersion {
+                        maximum_supported,
+                        found,
+                    });
+                    break (
+                        Err(HighLevelError::UnsupportedProtocolVersion {
+                            maximum_supported,
+                            found,
+                        }),
+                        length,
+                    );
+                }
+                // There are not enough bytes, let's wait until we get more
+                Err(nom::Err::Incomplete(_)) => return None,
+                // We can't do anything with the beginning of the buffer, let's drop bytes until the next header
+                Err(_e) => {
+                    #[cfg(feature = "log")]
+                    log::debug!("{:?}", &_e);
+                    let dropped_bytes = resync_offset(input, config.resync_window);
+                    self.position += dropped_bytes;
+                    self.report(DecodeDiagnostic::Resync { dropped_bytes });
+                }
+            }
+        };
+        self.frame_start = self.position;
+        self.position += length;
+        Some(result)
+    }
+
+    /// Same as `next_message()`, along with the bytes of the frame (e.g. to record them)
+    pub fn next_frame(&mut self) -> Option<DecodedFrame<'_>> {
+        let result = self.next_message()?;
+        Some(DecodedFrame {
+            frame: self.last_frame(),
+            result,
+        })
+    }
+
+    /// Bytes of the frame that was decoded last, until more bytes are pushed
+    pub(crate) fn last_frame(&self) -> &[u8] {
+        &self.buffer[self.frame_start..self.position]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::framing;
+    use crate::serializers::ToBytes;
+    use crate::structures::{DataSnapshot, Phase, StoppedMessage, SubPhase, VentilationMode};
+
+    fn stopped_message(systick: u64) -> TelemetryMessage {
+        let message = TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            ventilation_mode: VentilationMode::PC_CMV,
+            ..StoppedMessage::default()
+        });
+        // Optional fields are serialized with default values, so the message is compared once parsed
+        crate::parsers::parse_telemetry_message(&message.to_bytes())
+            .unwrap()
+            .1
+    }
+
+    #[test]
+    fn decodes_bytes_pushed_in_chunks() {
+        let first = stopped_message(1);
+        let second = stopped_message(2);
+        let mut corrupted = second.to_bytes();
+        // Last byte of the CRC, right before the footer
+        let crc_index = corrupted.len() - 3;
+        corrupted[crc_index] ^= 0xFF;
+        let mut stream = b"garbage".to_vec();
+        stream.extend(first.to_bytes());
+        stream.extend(&corrupted);
+        stream.extend(second.to_bytes());
+
+        let mut decoder = TelemetryDecoder::new();
+        let mut decoded = Vec::new();
+        for chunk in stream.chunks(5) {
+            decoder.push_bytes(chunk);
+            while let Some(result) = decoder.next_message() {
+                decoded.push(result);
+            }
+        }
+        assert_eq!(decoder.pending_bytes(), 0);
+        assert_eq!(decoded.len(), 3);
+        assert_eq!(decoded[0], Ok(first.clone()));
+        assert!(matches!(decoded[1], Err(HighLevelError::CrcError { .. })));
+        assert_eq!(decoded[2], Ok(second));
+
+        decoder.push_bytes(&first.to_bytes());
+        let frame = decoder.next_frame().unwrap();
+        assert_eq!(frame.frame, first.to_bytes());
+        assert!(framing::decode(frame.frame).is_ok());
+    }
+
+    #[test]
+    fn reports_diagnostics() {
+        let message = stopped_message(1);
+        let mut corrupted = message.to_bytes();
+        let crc_index = corrupted.len() - 3;
+        corrupted[crc_index] ^= 0xFF;
+        let mut stream = b"garbage".to_vec();
+        stream.extend(&corrupted);
+        stream.extend(message.to_bytes());
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut decoder = TelemetryDecoder::new().diagnostics(Some(tx));
+        decoder.push_bytes(&stream);
+        while decoder.next_message().is_some() {}
+
+        let diagnostics: Vec<DecodeDiagnostic> = rx.try_iter().collect();
+        assert_eq!(
+            diagnostics[0],
+            DecodeDiagnostic::Resync {
+                dropped_bytes: b"garbage".len()
+            }
+        );
+        assert!(matches!(diagnostics[1], DecodeDiagnostic::CrcError { .. }));
+        assert_eq!(diagnostics.len(), 2);
+    }
+
+    #[test]
+    fn reports_field_warnings_of_each_frame() {
+        let snapshot = |pressure| {
+            TelemetryMessage::DataSnapshot(DataSnapshot {
+                telemetry_version: 1,
+                version: "test".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 0,
+                centile: 0,
+                pressure,
+                phase: Phase::Exhalation,
+                subphase: Some(SubPhase::Exhale),
+                blower_valve_position: 0,
+                patient_valve_position: 0,
+                blower_rpm: 0,
+                battery_level: 0,
+                inspiratory_flow: None,
+                expiratory_flow: None,
+            })
+            .to_bytes_v1()
+        };
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut decoder = TelemetryDecoder::new().diagnostics(Some(tx));
+        decoder.push_bytes(&snapshot(-2));
+        decoder.push_bytes(&snapshot(100));
+        while decoder.next_message().is_some() {}
+
+        assert_eq!(
+            rx.try_iter().collect::<Vec<_>>(),
+            vec![DecodeDiagnostic::Field(
+                crate::diagnostics::FieldWarning::ClampedPressure(0xFFFE)
+            )]
+        );
+    }
+}
@@ -0,0 +1,62 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::cell::RefCell;
+
+/// A field of a telemetry message that could not be decoded as expected
+///
+/// The message is still delivered; the field is set to a fallback value (usually `None`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum FieldWarning {
+    /// The locale sent by the firmware is not a language code; the field was set to `None`
+    #[error("unknown locale {0:#06x}")]
+    UnknownLocale(u16),
+    /// [protocol v1] The pressure sent by the firmware does not fit in a signed field; it was clamped to `i16::MAX`
+    #[error("pressure {0} out of range, clamped")]
+    ClampedPressure(u16),
+}
+
+/// A non-fatal problem found while decoding a stream of bytes (see `decoder::TelemetryDecoder::diagnostics()`)
+///
+/// Unlike errors sent with messages, these are meant to measure the quality of a link without being part of the data.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum DecodeDiagnostic {
+    /// A frame was dropped because its CRC did not match
+    #[error("CRC error (expected={expected}, computed={computed})")]
+    CrcError {
+        /// CRC found in the frame
+        expected: u32,
+        /// CRC computed from the frame
+        computed: u32,
+    },
+    /// A frame was dropped because it was built using a newer protocol version
+    #[error("unsupported protocol version {found} (maximum supported is {maximum_supported})")]
+    UnsupportedProtocolVersion {
+        /// Latest protocol version supported by this library
+        maximum_supported: u8,
+        /// Protocol version of the frame
+        found: u8,
+    },
+    /// Bytes that did not belong to a frame were dropped to get to the next frame header
+    #[error("dropped {dropped_bytes} bytes to resynchronize")]
+    Resync {
+        /// Number of dropped bytes
+        dropped_bytes: usize,
+    },
+    /// A field of a decoded message was set to a fallback value
+    #[error("{0}")]
+    Field(FieldWarning),
+}
+
+/// Warnings found by the parsers of a frame, which are only returned once its CRC was checked
+pub(crate) type FieldWarnings = RefCell<Vec<FieldWarning>>;
@@ -0,0 +1,368 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::adapter::MessageAdapter;
+use crate::control::ControlSetting;
+use crate::structures::{EolTestSnapshot, EolTestSnapshotContent, EolTestStep, TelemetryMessage};
+
+/// Outcome of an end of line test step or session
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum EolOutcome {
+    /// The test moved on to the next step, or the whole test succeeded
+    Passed,
+    /// The MCU reported an error
+    Failed,
+    /// The recording ended (or the MCU restarted) before the end of the step or session
+    Incomplete,
+}
+
+impl EolOutcome {
+    /// Name used in exports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Passed => "passed",
+            Self::Failed => "failed",
+            Self::Incomplete => "incomplete",
+        }
+    }
+}
+
+/// Result of one step of an end of line test
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct EolStepResult {
+    /// Step
+    pub step: EolTestStep,
+    /// Outcome of the step
+    pub outcome: EolOutcome,
+    /// Systick of the first snapshot of the step
+    pub started_at: u64,
+    /// Systick of the last snapshot of the step
+    pub ended_at: u64,
+    /// Last message displayed by the MCU during the step (it holds measured values of some steps)
+    pub message: String,
+    /// Systick of the operator confirmation ACKed during the step, if any
+    pub confirmed_at: Option<u64>,
+    /// Lowest pressure of data snapshots received during the step, in mmH2O
+    pub min_pressure: Option<i16>,
+    /// Highest pressure of data snapshots received during the step, in mmH2O
+    pub max_pressure: Option<i16>,
+    /// Highest inspiratory flow of data snapshots received during the step, in cL/min
+    pub max_inspiratory_flow: Option<i16>,
+}
+
+impl EolStepResult {
+    fn new(snapshot: &EolTestSnapshot) -> Self {
+        Self {
+            step: snapshot.current_step,
+            outcome: EolOutcome::Incomplete,
+            started_at: snapshot.systick,
+            ended_at: snapshot.systick,
+            message: String::new(),
+            confirmed_at: None,
+            min_pressure: None,
+            max_pressure: None,
+            max_inspiratory_flow: None,
+        }
+    }
+}
+
+/// Evidence of one end of line test of a device, e.g. to archive it in a manufacturing execution system (MES)
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct EolSession {
+    /// Internal ID of the MCU, used as serial number of the device
+    pub device_id: String,
+    /// Version of the MCU firmware
+    pub firmware_version: String,
+    /// Outcome of the whole test
+    pub outcome: EolOutcome,
+    /// Steps, in the order they were run
+    pub steps: Vec<EolStepResult>,
+}
+
+impl EolSession {
+    /// Systick of the first snapshot of the test
+    pub fn started_at(&self) -> Option<u64> {
+        self.steps.first().map(|step| step.started_at)
+    }
+
+    /// Systick of the last snapshot of the test
+    pub fn ended_at(&self) -> Option<u64> {
+        self.steps.last().map(|step| step.ended_at)
+    }
+
+    /// Header line of `to_csv_lines()`
+    pub fn csv_header() -> &'static str {
+        "device_id,firmware_version,session_outcome,step,step_code,step_outcome,started_at,ended_at,confirmed_at,min_pressure,max_pressure,max_inspiratory_flow,message\n"
+    }
+
+    /// Render as CSV, one step per line, without header (see `csv_header()`)
+    pub fn to_csv_lines(&self) -> String {
+        let optional = |value: Option<String>| value.unwrap_or_default();
+        self.steps
+            .iter()
+            .map(|step| {
+                format!(
+                    "{},{},{},{:?},{},{},{},{},{},{},{},{},{}\n",
+                    csv_field(&self.device_id),
+                    csv_field(&self.firmware_version),
+                    self.outcome.name(),
+                    step.step,
+                    u8::from(&step.step),
+                    step.outcome.name(),
+                    step.started_at,
+                    step.ended_at,
+                    optional(step.confirmed_at.map(|systick| systick.to_string())),
+                    optional(step.min_pressure.map(|pressure| pressure.to_string())),
+                    optional(step.max_pressure.map(|pressure| pressure.to_string())),
+                    optional(step.max_inspiratory_flow.map(|flow| flow.to_string())),
+                    csv_field(&step.message),
+                )
+            })
+            .collect()
+    }
+}
+
+/// Quote a CSV field if needed
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Gather end of line test snapshots, operator confirmations and data snapshots into test sessions
+///
+/// A session ends when the MCU restarts or starts the test again; `finish()` returns the last session at the end of a recording.
+#[derive(Debug, Default)]
+pub struct EolSessions {
+    current: Option<EolSession>,
+}
+
+impl EolSessions {
+    /// Create an empty list of sessions
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Gather every session of a list of messages
+    pub fn from_messages<'a>(
+        messages: impl IntoIterator<Item = &'a TelemetryMessage>,
+    ) -> Vec<EolSession> {
+        let mut sessions = Self::new();
+        let mut result = Vec::new();
+        for message in messages {
+            result.extend(sessions.handle(message));
+        }
+        result.extend(sessions.finish());
+        result
+    }
+
+    /// End the current session, if any
+    pub fn finish(&mut self) -> Option<EolSession> {
+        self.current.take()
+    }
+
+    fn push_snapshot(&mut self, snapshot: &EolTestSnapshot) -> Option<EolSession> {
+        let restarted = snapshot.current_step == EolTestStep::START
+            && self
+                .current
+                .as_ref()
+                .and_then(|session| session.steps.last())
+                .is_some_and(|step| step.step != EolTestStep::START);
+        let finished = if restarted { self.finish() } else { None };
+
+        let session = self.current.get_or_insert_with(|| EolSession {
+            device_id: snapshot.device_id.clone(),
+            firmware_version: snapshot.version.clone(),
+            outcome: EolOutcome::Incomplete,
+            steps: Vec::new(),
+        });
+        if session.steps.last().map(|step| step.step) != Some(snapshot.current_step) {
+            if let Some(previous) = session.steps.last_mut() {
+                if previous.outcome == EolOutcome::Incomplete {
+                    previous.outcome = EolOutcome::Passed;
+                }
+            }
+            session.steps.push(EolStepResult::new(snapshot));
+        }
+        let step = session.steps.last_mut().expect("a step was just pushed");
+        step.ended_at = snapshot.systick;
+        let (message, outcome) = match &snapshot.content {
+            EolTestSnapshotContent::InProgress(message) => (message, EolOutcome::Incomplete),
+            EolTestSnapshotContent::Error(message) => (message, EolOutcome::Failed),
+            EolTestSnapshotContent::Success(message) => (message, EolOutcome::Passed),
+        };
+        step.message = message.clone();
+        step.outcome = outcome;
+        if outcome != EolOutcome::Incomplete {
+            session.outcome = outcome;
+        }
+        finished
+    }
+}
+
+impl MessageAdapter for EolSessions {
+    type Output = EolSession;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<EolSession> {
+        match message {
+            TelemetryMessage::EolTestSnapshot(snapshot) => {
+                self.push_snapshot(snapshot).into_iter().collect()
+            }
+            TelemetryMessage::ControlAck(ack) if ack.setting == ControlSetting::EolConfirm => {
+                if let Some(step) = self
+                    .current
+                    .as_mut()
+                    .and_then(|session| session.steps.last_mut())
+                {
+                    step.confirmed_at = Some(ack.systick);
+                }
+                Vec::new()
+            }
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                if let Some(step) = self
+                    .current
+                    .as_mut()
+                    .and_then(|session| session.steps.last_mut())
+                {
+                    let pressure = snapshot.pressure;
+                    step.min_pressure =
+                        Some(step.min_pressure.map_or(pressure, |min| min.min(pressure)));
+                    step.max_pressure =
+                        Some(step.max_pressure.map_or(pressure, |max| max.max(pressure)));
+                    if let Some(flow) = snapshot.inspiratory_flow {
+                        step.max_inspiratory_flow =
+                            Some(step.max_inspiratory_flow.map_or(flow, |max| max.max(flow)));
+                    }
+                }
+                Vec::new()
+            }
+            // The test does not survive a restart of the MCU
+            TelemetryMessage::BootMessage(_) => self.finish().into_iter().collect(),
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{ControlAck, DataSnapshot, Phase};
+
+    fn eol(systick: u64, step: EolTestStep, content: EolTestSnapshotContent) -> TelemetryMessage {
+        TelemetryMessage::EolTestSnapshot(EolTestSnapshot {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            current_step: step,
+            content,
+        })
+    }
+
+    fn in_progress(systick: u64, step: EolTestStep) -> TelemetryMessage {
+        eol(
+            systick,
+            step,
+            EolTestSnapshotContent::InProgress(format!("{:?}", step)),
+        )
+    }
+
+    fn data(systick: u64, pressure: i16, inspiratory_flow: Option<i16>) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            centile: 0,
+            pressure,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow,
+            expiratory_flow: None,
+        })
+    }
+
+    #[test]
+    fn gathers_steps_of_sessions() {
+        let messages = vec![
+            in_progress(1, EolTestStep::START),
+            in_progress(2, EolTestStep::START),
+            in_progress(3, EolTestStep::REACH_MAX_PRESSURE),
+            data(4, 650, Some(1200)),
+            data(5, 720, None),
+            in_progress(6, EolTestStep::USER_CONFIRMATION_BEFORE_O2_TEST),
+            TelemetryMessage::ControlAck(ControlAck {
+                telemetry_version: 2,
+                version: "test".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 7,
+                setting: ControlSetting::EolConfirm,
+                value: 0,
+            }),
+            eol(
+                8,
+                EolTestStep::O2_PRESSURE_NOT_REACH,
+                EolTestSnapshotContent::Error("O2 pressure, not reached".to_owned()),
+            ),
+            // The operator runs the test again
+            in_progress(9, EolTestStep::START),
+            eol(
+                10,
+                EolTestStep::END_SUCCESS,
+                EolTestSnapshotContent::Success("OK".to_owned()),
+            ),
+        ];
+        let sessions = EolSessions::from_messages(&messages);
+        assert_eq!(sessions.len(), 2);
+
+        let failed = &sessions[0];
+        assert_eq!(failed.device_id, "1-2-3");
+        assert_eq!(failed.outcome, EolOutcome::Failed);
+        assert_eq!((failed.started_at(), failed.ended_at()), (Some(1), Some(8)));
+        let outcomes: Vec<_> = failed.steps.iter().map(|step| step.outcome).collect();
+        assert_eq!(
+            outcomes,
+            vec![
+                EolOutcome::Passed,
+                EolOutcome::Passed,
+                EolOutcome::Passed,
+                EolOutcome::Failed
+            ]
+        );
+        assert_eq!(failed.steps[0].ended_at, 2);
+        assert_eq!(failed.steps[1].min_pressure, Some(650));
+        assert_eq!(failed.steps[1].max_pressure, Some(720));
+        assert_eq!(failed.steps[1].max_inspiratory_flow, Some(1200));
+        assert_eq!(failed.steps[2].confirmed_at, Some(7));
+
+        let csv = failed.to_csv_lines();
+        assert_eq!(csv.lines().count(), 4);
+        assert!(csv.ends_with(
+            "1-2-3,test,failed,O2_PRESSURE_NOT_REACH,20,failed,8,8,,,,,\"O2 pressure, not reached\"\n"
+        ));
+
+        assert_eq!(sessions[1].outcome, EolOutcome::Passed);
+        assert_eq!(sessions[1].steps.len(), 2);
+    }
+}
@@ -9,6 +9,10 @@ pub enum Error {
     #[error("Telemetry error: {0}")]
     TelemetryError(#[from] HighLevelError),
 
+    /// I/O error (e.g. on a connection to a telemetry source)
+    #[error("I/O error: {0}")]
+    IoError(#[from] std::io::Error),
+
     #[cfg(feature = "serial")]
     #[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
     /// Serial error
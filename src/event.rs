@@ -0,0 +1,523 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{BTreeSet, HashMap};
+use std::sync::mpsc::{Receiver, Sender};
+use std::time::SystemTime;
+
+use crate::adapter::MessageAdapter;
+use crate::anomaly::{AnomalyEvent, AnomalyMonitor};
+use crate::control::ControlSetting;
+use crate::diagnostics::{DecodeDiagnostic, FieldWarning};
+use crate::error::Error;
+use crate::sink::TelemetrySink;
+use crate::source::SourceInfo;
+use crate::structures::{AlarmPriority, FatalErrorDetails, HighLevelError, Mode, TelemetryMessage};
+use crate::TimedMessage;
+
+/// A change of the state of an alarm
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct AlarmChange {
+    /// Where the message that changed the alarm comes from
+    pub source: SourceInfo,
+    /// Internal ID of the MCU
+    pub device_id: String,
+    /// Systick of the message that changed the alarm
+    pub systick: u64,
+    /// Code of the alarm
+    pub alarm_code: u8,
+    /// Priority of the alarm, if it is known (only alarm traps contain it)
+    pub priority: Option<AlarmPriority>,
+    /// Whether the alarm is now active
+    pub active: bool,
+}
+
+/// State of the link with a telemetry source
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum LinkState {
+    /// Messages are received
+    Connected,
+    /// Bytes are received but cannot be read properly (e.g. wrong baud rate, or a break condition)
+    Degraded {
+        /// Description of the problem
+        reason: String,
+    },
+    /// The connection failed or was closed
+    Disconnected {
+        /// Description of the error
+        reason: String,
+    },
+}
+
+/// A change of the state of the link with a telemetry source
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct LinkChange {
+    /// Telemetry source
+    pub source: SourceInfo,
+    /// New state of the link
+    pub state: LinkState,
+}
+
+/// Something that happened to a device
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum DeviceEventKind {
+    /// The MCU booted
+    Booted {
+        /// Version of the MCU firmware
+        firmware_version: String,
+        /// Mode of the MCU
+        mode: Mode,
+    },
+    /// Ventilation started
+    VentilationStarted,
+    /// Ventilation stopped
+    VentilationStopped,
+    /// The MCU acknowledged a new value of a setting
+    SettingChanged {
+        /// Setting that was changed
+        setting: ControlSetting,
+        /// New value
+        value: u16,
+    },
+    /// A fatal error occurred
+    FatalError(FatalErrorDetails),
+}
+
+/// Something that happened to a device, with where and when it happened
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct DeviceEvent {
+    /// Where the message comes from
+    pub source: SourceInfo,
+    /// Internal ID of the MCU
+    pub device_id: String,
+    /// Systick of the message
+    pub systick: u64,
+    /// What happened
+    pub kind: DeviceEventKind,
+}
+
+/// A non-fatal problem found while decoding telemetry
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum Diagnostic {
+    /// A frame could not be decoded (e.g. invalid CRC); the telemetry source is given
+    Telemetry(SourceInfo, HighLevelError),
+    /// A field of a message could not be decoded as expected (see `diagnostics`)
+    Field(FieldWarning),
+}
+
+/// Everything a consumer of telemetry may want to know, as one stream
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum TelemetryEvent {
+    /// A decoded telemetry message
+    Message {
+        /// Where the message comes from
+        source: SourceInfo,
+        /// Host wall-clock time at which the message was received
+        received_at: SystemTime,
+        /// The message
+        message: TelemetryMessage,
+    },
+    /// An alarm was triggered or stopped
+    AlarmChange(AlarmChange),
+    /// The link with a telemetry source changed
+    LinkState(LinkChange),
+    /// Something happened to a device (boot, ventilation started or stopped, etc.)
+    DeviceEvent(DeviceEvent),
+    /// An anomaly detector found an unusual cycle
+    Anomaly(AnomalyEvent),
+    /// A non-fatal problem was found while decoding telemetry
+    Diagnostic(Diagnostic),
+}
+
+#[derive(Default)]
+struct SourceState {
+    link: Option<LinkState>,
+    running: Option<bool>,
+    active_alarms: BTreeSet<u8>,
+    anomalies: Option<AnomalyMonitor>,
+}
+
+type MonitorFactory = Box<dyn Fn() -> AnomalyMonitor + Send>;
+
+/// Derives a stream of `TelemetryEvent` from timed messages
+///
+/// Every decoded message gives a `Message` event, followed by the events derived from it; errors give `LinkState` or `Diagnostic` events.
+/// State (link, ventilation, active alarms, anomaly detectors) is kept separately for each source.
+#[derive(Default)]
+pub struct EventStream {
+    sources: HashMap<SourceInfo, SourceState>,
+    monitor_factory: Option<MonitorFactory>,
+    field_warnings: Option<Receiver<DecodeDiagnostic>>,
+}
+
+impl EventStream {
+    /// Create a stream without anomaly detection nor field warnings
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Run anomaly detection, with a monitor created by `factory` for each source
+    pub fn anomalies<F>(mut self, factory: F) -> Self
+    where
+        F: Fn() -> AnomalyMonitor + Send + 'static,
+    {
+        self.monitor_factory = Some(Box::new(factory));
+        self
+    }
+
+    /// Include field warnings as `Diagnostic` events
+    ///
+    /// * `rx` - Receiver of the diagnostics channel given to the gather function (its `diagnostics_tx`); other diagnostics are ignored, as frames that could not be decoded are already reported with the messages.
+    pub fn field_warnings(mut self, rx: Receiver<DecodeDiagnostic>) -> Self {
+        self.field_warnings = Some(rx);
+        self
+    }
+
+    /// Derive the events of a timed message
+    pub fn handle(&mut self, timed: &TimedMessage) -> Vec<TelemetryEvent> {
+        let state = self
+            .sources
+            .entry(timed.source.clone())
+            .or_insert_with(|| SourceState {
+                anomalies: self.monitor_factory.as_ref().map(|factory| factory()),
+                ..SourceState::default()
+            });
+        let mut events = Vec::new();
+        let set_link = |state: &mut SourceState, link: LinkState, events: &mut Vec<_>| {
+            if state.link.as_ref() != Some(&link) {
+                state.link = Some(link.clone());
+                events.push(TelemetryEvent::LinkState(LinkChange {
+                    source: timed.source.clone(),
+                    state: link,
+                }));
+            }
+        };
+
+        match &timed.message {
+            Ok(message) => {
+                set_link(state, LinkState::Connected, &mut events);
+                events.push(TelemetryEvent::Message {
+                    source: timed.source.clone(),
+                    received_at: timed.received_at,
+                    message: message.clone(),
+                });
+                derive(state, &timed.source, message, &mut events);
+            }
+            Err(Error::TelemetryError(
+                error @ (HighLevelError::LinkMisconfigured { .. }
+                | HighLevelError::BreakCondition { .. }),
+            )) => set_link(
+                state,
+                LinkState::Degraded {
+                    reason: error.to_string(),
+                },
+                &mut events,
+            ),
+            Err(Error::TelemetryError(error)) => events.push(TelemetryEvent::Diagnostic(
+                Diagnostic::Telemetry(timed.source.clone(), error.clone()),
+            )),
+            Err(error) => set_link(
+                state,
+                LinkState::Disconnected {
+                    reason: error.to_string(),
+                },
+                &mut events,
+            ),
+        }
+
+        if let Some(rx) = &self.field_warnings {
+            events.extend(rx.try_iter().filter_map(|diagnostic| match diagnostic {
+                DecodeDiagnostic::Field(warning) => {
+                    Some(TelemetryEvent::Diagnostic(Diagnostic::Field(warning)))
+                }
+                _ => None,
+            }));
+        }
+        events
+    }
+}
+
+fn derive(
+    state: &mut SourceState,
+    source: &SourceInfo,
+    message: &TelemetryMessage,
+    events: &mut Vec<TelemetryEvent>,
+) {
+    let device_event = |kind| {
+        TelemetryEvent::DeviceEvent(DeviceEvent {
+            source: source.clone(),
+            device_id: message.device_id(),
+            systick: message.systick(),
+            kind,
+        })
+    };
+    let alarm_change = |alarm_code, priority, active| {
+        TelemetryEvent::AlarmChange(AlarmChange {
+            source: source.clone(),
+            device_id: message.device_id(),
+            systick: message.systick(),
+            alarm_code,
+            priority,
+            active,
+        })
+    };
+
+    let running = match message {
+        TelemetryMessage::StoppedMessage(_) => Some(false),
+        TelemetryMessage::DataSnapshot(_) | TelemetryMessage::MachineStateSnapshot(_) => Some(true),
+        _ => None,
+    };
+    if let Some(running) = running {
+        if state.running.is_some_and(|previous| previous != running) {
+            events.push(device_event(if running {
+                DeviceEventKind::VentilationStarted
+            } else {
+                DeviceEventKind::VentilationStopped
+            }));
+        }
+        state.running = Some(running);
+    }
+
+    let current_alarms = match message {
+        TelemetryMessage::MachineStateSnapshot(snapshot) => Some(&snapshot.current_alarm_codes),
+        TelemetryMessage::StoppedMessage(stopped) => stopped.current_alarm_codes.as_ref(),
+        _ => None,
+    };
+    match message {
+        TelemetryMessage::BootMessage(boot) => {
+            state.running = None;
+            state.active_alarms.clear();
+            events.push(device_event(DeviceEventKind::Booted {
+                firmware_version: boot.version.clone(),
+                mode: boot.mode,
+            }));
+        }
+        TelemetryMessage::ControlAck(ack) => {
+            events.push(device_event(DeviceEventKind::SettingChanged {
+                setting: ack.setting,
+                value: ack.value,
+            }));
+        }
+        TelemetryMessage::FatalError(error) => {
+            events.push(device_event(DeviceEventKind::FatalError(
+                error.error.clone(),
+            )));
+        }
+        TelemetryMessage::AlarmTrap(trap) => {
+            let changed = if trap.triggered {
+                state.active_alarms.insert(trap.alarm_code)
+            } else {
+                state.active_alarms.remove(&trap.alarm_code)
+            };
+            if changed {
+                events.push(alarm_change(
+                    trap.alarm_code,
+                    Some(trap.alarm_priority),
+                    trap.triggered,
+                ));
+            }
+        }
+        _ => (),
+    }
+    // Snapshots list every active alarm, which also catches traps that were missed
+    if let Some(codes) = current_alarms {
+        let codes: BTreeSet<u8> = codes.iter().copied().collect();
+        for stopped in state.active_alarms.difference(&codes) {
+            events.push(alarm_change(*stopped, None, false));
+        }
+        for triggered in codes.difference(&state.active_alarms) {
+            events.push(alarm_change(*triggered, None, true));
+        }
+        state.active_alarms = codes;
+    }
+
+    if let Some(monitor) = state.anomalies.as_mut() {
+        events.extend(
+            monitor
+                .handle(message)
+                .into_iter()
+                .map(TelemetryEvent::Anomaly),
+        );
+    }
+}
+
+/// Sink that derives events from messages and sends them through a channel
+///
+/// Events are sent until the receiver is dropped.
+pub struct EventSink {
+    stream: EventStream,
+    tx: Option<Sender<TelemetryEvent>>,
+}
+
+impl EventSink {
+    /// Create a sink sending the events of a stream through a channel
+    pub fn new(stream: EventStream, tx: Sender<TelemetryEvent>) -> Self {
+        Self {
+            stream,
+            tx: Some(tx),
+        }
+    }
+}
+
+impl TelemetrySink for EventSink {
+    fn consume(&mut self, message: &TimedMessage) {
+        let Some(tx) = self.tx.as_ref() else {
+            return;
+        };
+        for event in self.stream.handle(message) {
+            if tx.send(event).is_err() {
+                self.tx = None;
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceKind;
+    use crate::structures::*;
+
+    fn timed(message: crate::TelemetryChannelType) -> TimedMessage {
+        TimedMessage::now(
+            message,
+            SourceInfo::new(SourceKind::Serial, Some("/dev/ttyUSB0".to_owned())),
+        )
+    }
+
+    fn snapshot(systick: u64, alarms: Vec<u8>) -> TimedMessage {
+        timed(Ok(TelemetryMessage::MachineStateSnapshot(
+            MachineStateSnapshot {
+                systick,
+                current_alarm_codes: alarms,
+                ..Default::default()
+            },
+        )))
+    }
+
+    #[test]
+    fn derives_events() {
+        let mut stream = EventStream::new();
+        let stopped = timed(Ok(TelemetryMessage::StoppedMessage(StoppedMessage {
+            systick: 1_000,
+            ..Default::default()
+        })));
+
+        let events = stream.handle(&stopped);
+        assert!(matches!(
+            &events[..],
+            [
+                TelemetryEvent::LinkState(LinkChange {
+                    state: LinkState::Connected,
+                    ..
+                }),
+                TelemetryEvent::Message { .. }
+            ]
+        ));
+
+        let events = stream.handle(&snapshot(2_000, vec![12]));
+        assert!(matches!(
+            &events[1..],
+            [
+                TelemetryEvent::DeviceEvent(DeviceEvent {
+                    kind: DeviceEventKind::VentilationStarted,
+                    ..
+                }),
+                TelemetryEvent::AlarmChange(AlarmChange {
+                    alarm_code: 12,
+                    active: true,
+                    ..
+                })
+            ]
+        ));
+        assert_eq!(stream.handle(&snapshot(3_000, vec![12])).len(), 1);
+
+        let events = stream.handle(&timed(Err(HighLevelError::CrcError {
+            expected: 1,
+            computed: 2,
+        }
+        .into())));
+        assert!(matches!(
+            &events[..],
+            [TelemetryEvent::Diagnostic(Diagnostic::Telemetry(
+                _,
+                HighLevelError::CrcError { .. }
+            ))]
+        ));
+
+        let events = stream.handle(&timed(Err(std::io::Error::other("unplugged").into())));
+        assert!(matches!(
+            &events[..],
+            [TelemetryEvent::LinkState(LinkChange {
+                state: LinkState::Disconnected { .. },
+                ..
+            })]
+        ));
+
+        let events = stream.handle(&snapshot(4_000, vec![]));
+        assert!(matches!(
+            &events[2..],
+            [TelemetryEvent::AlarmChange(AlarmChange {
+                alarm_code: 12,
+                active: false,
+                ..
+            })]
+        ));
+    }
+
+    #[test]
+    fn includes_field_warnings_of_the_diagnostics_channel() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let mut stream = EventStream::new().field_warnings(rx);
+        tx.send(DecodeDiagnostic::Resync { dropped_bytes: 3 })
+            .unwrap();
+        tx.send(DecodeDiagnostic::Field(FieldWarning::UnknownLocale(0xFFFF)))
+            .unwrap();
+
+        let events = stream.handle(&snapshot(1_000, vec![]));
+        assert_eq!(
+            events.last(),
+            Some(&TelemetryEvent::Diagnostic(Diagnostic::Field(
+                FieldWarning::UnknownLocale(0xFFFF)
+            )))
+        );
+        assert_eq!(
+            events
+                .iter()
+                .filter(|event| matches!(event, TelemetryEvent::Diagnostic(_)))
+                .count(),
+            1
+        );
+    }
+}
@@ -0,0 +1,451 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, RecvTimeoutError, SendError, Sender};
+use std::sync::Arc;
+use std::thread::JoinHandle;
+use std::time::Duration;
+
+use crate::control::ControlMessage;
+use crate::diagnostics::DecodeDiagnostic;
+use crate::event::{AlarmChange, EventStream, TelemetryEvent};
+use crate::recording::{FlushPolicy, RecordingWriter};
+use crate::sink::{FnSink, RecordingSink, SinkSet, TelemetrySink};
+use crate::structures::TelemetryMessage;
+use crate::watchdog::{HeartbeatWatchdog, RunningWatchdog, DEFAULT_HEARTBEAT_PERIOD};
+use crate::TimedMessage;
+
+/// How often the dispatching thread and WebSocket transports check whether they were stopped while no message is received
+const STOP_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Where telemetry is read from
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Source {
+    /// A serial port connected to the MCU
+    #[cfg(feature = "serial")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+    Serial(String),
+    /// A WebSocket server relaying telemetry
+    #[cfg(feature = "websocket")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+    WebSocket(url::Url),
+    /// A recorded file, read until its end
+    File(PathBuf),
+}
+
+impl Source {
+    fn is_live(&self) -> bool {
+        !matches!(self, Self::File(_))
+    }
+}
+
+/// Error returned when telemetry cannot be started
+#[derive(Debug, thiserror::Error)]
+pub enum StartError {
+    /// No source was given to the builder
+    #[error("no telemetry source was given")]
+    NoSource,
+    /// The source or the recording could not be opened
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+}
+
+type MessageCallback = Box<dyn FnMut(&TelemetryMessage) + Send>;
+type AlarmCallback = Box<dyn FnMut(&AlarmChange) + Send>;
+
+/// Sink calling a closure for every alarm that is triggered or stopped
+struct AlarmSink {
+    stream: EventStream,
+    callback: AlarmCallback,
+}
+
+impl TelemetrySink for AlarmSink {
+    fn consume(&mut self, message: &TimedMessage) {
+        for event in self.stream.handle(message) {
+            if let TelemetryEvent::AlarmChange(change) = event {
+                (self.callback)(&change);
+            }
+        }
+    }
+}
+
+/// Entry point of the all-in-one API, for applications that do not want to wire channels, sinks and threads themselves
+///
+/// ```no_run
+/// use makair_telemetry::facade::{Source, Telemetry};
+///
+/// let telemetry = Telemetry::builder()
+///     .source(Source::Serial("/dev/ttyUSB0".to_owned()))
+///     .record("out.record")
+///     .on_message(|message| println!("{:?}", message))
+///     .on_alarm(|alarm| println!("alarm {} active: {}", alarm.alarm_code, alarm.active))
+///     .start()
+///     .unwrap();
+/// telemetry.join();
+/// ```
+#[derive(Debug)]
+pub struct Telemetry;
+
+impl Telemetry {
+    /// Start describing what to read and what to do with it
+    pub fn builder() -> TelemetryBuilder {
+        TelemetryBuilder::default()
+    }
+}
+
+/// Builder of a running telemetry pipeline (source, recording, callbacks and heartbeats)
+#[derive(Default)]
+pub struct TelemetryBuilder {
+    source: Option<Source>,
+    #[cfg(feature = "serial")]
+    serial_config: crate::serial_config::SerialConfig,
+    #[cfg(feature = "websocket")]
+    ws_config: crate::websocket::WebSocketClientConfig,
+    record: Option<PathBuf>,
+    flush_policy: FlushPolicy,
+    time_simulation: bool,
+    heartbeat_period: Option<Option<Duration>>,
+    sinks: SinkSet,
+    on_message: Option<MessageCallback>,
+    on_alarm: Option<AlarmCallback>,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+}
+
+impl TelemetryBuilder {
+    /// Choose where telemetry is read from
+    pub fn source(mut self, source: Source) -> Self {
+        self.source = Some(source);
+        self
+    }
+
+    /// Choose the settings of the serial port, for a `Source::Serial`
+    #[cfg(feature = "serial")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+    pub fn serial_config(mut self, config: crate::serial_config::SerialConfig) -> Self {
+        self.serial_config = config;
+        self
+    }
+
+    /// Choose how to connect to the server, for a `Source::WebSocket`
+    ///
+    /// Without a `read_timeout`, reads time out every 100 ms so that the transport can be stopped.
+    #[cfg(feature = "websocket")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+    pub fn ws_config(mut self, config: crate::websocket::WebSocketClientConfig) -> Self {
+        self.ws_config = config;
+        self
+    }
+
+    /// Record telemetry to a file, which is created or truncated on start
+    ///
+    /// Live sources are recorded frame by frame as they are decoded: frames that could not be decoded (e.g. because of a CRC error) are left out.
+    /// Recorded files are recorded again, message by message.
+    pub fn record<P: Into<PathBuf>>(mut self, path: P) -> Self {
+        self.record = Some(path.into());
+        self
+    }
+
+    /// Choose when the recording is flushed (after every message by default)
+    pub fn flush_policy(mut self, policy: FlushPolicy) -> Self {
+        self.flush_policy = policy;
+        self
+    }
+
+    /// Deliver the messages of a `Source::File` at the pace they were recorded, instead of as fast as possible
+    pub fn time_simulation(mut self, enabled: bool) -> Self {
+        self.time_simulation = enabled;
+        self
+    }
+
+    /// Choose the period of heartbeats sent to live sources (`DEFAULT_HEARTBEAT_PERIOD` by default), or `None` to send none
+    ///
+    /// Heartbeats keep the RPi watchdog of the MCU happy, and stop when telemetry stalls (see `watchdog::HeartbeatWatchdog`).
+    pub fn heartbeat_period(mut self, period: Option<Duration>) -> Self {
+        self.heartbeat_period = Some(period);
+        self
+    }
+
+    /// Give every message (or error) to a sink, in addition to the callbacks
+    pub fn sink<S: TelemetrySink + Send + 'static>(mut self, name: &str, sink: S) -> Self {
+        self.sinks.add(name, sink);
+        self
+    }
+
+    /// Call a closure for every decoded message
+    pub fn on_message<F: FnMut(&TelemetryMessage) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_message = Some(Box::new(callback));
+        self
+    }
+
+    /// Call a closure every time an alarm is triggered or stopped
+    pub fn on_alarm<F: FnMut(&AlarmChange) + Send + 'static>(mut self, callback: F) -> Self {
+        self.on_alarm = Some(Box::new(callback));
+        self
+    }
+
+    /// Send non-fatal problems found while decoding (e.g. CRC errors) through a channel, to measure the quality of the link or of a recording
+    pub fn diagnostics(mut self, tx: Sender<DecodeDiagnostic>) -> Self {
+        self.diagnostics_tx = Some(tx);
+        self
+    }
+
+    /// Open the recording, and start reading telemetry and calling callbacks from dedicated threads
+    pub fn start(self) -> Result<TelemetryHandle, StartError> {
+        let source = self.source.ok_or(StartError::NoSource)?;
+        let mut sinks = self.sinks;
+        let mut recorder = match &self.record {
+            Some(path) => Some(RecordingWriter::new(File::create(path)?, self.flush_policy)),
+            None => None,
+        };
+        if !source.is_live() {
+            if let Some(recorder) = recorder.take() {
+                sinks.add("recording", RecordingSink::new(recorder));
+            }
+        }
+        if let Some(callback) = self.on_message {
+            sinks.add("on_message", FnSink::new(callback));
+        }
+        if let Some(callback) = self.on_alarm {
+            sinks.add(
+                "on_alarm",
+                AlarmSink {
+                    stream: EventStream::new(),
+                    callback,
+                },
+            );
+        }
+
+        let (tx, rx) = channel::<TimedMessage>();
+        let (control_tx, control_rx) = channel();
+        let watchdog = match self.heartbeat_period {
+            _ if !source.is_live() => None,
+            Some(None) => None,
+            period => {
+                let watchdog = HeartbeatWatchdog::new(control_tx.clone())
+                    .heartbeat_period(period.flatten().unwrap_or(DEFAULT_HEARTBEAT_PERIOD));
+                sinks.add("watchdog", watchdog.feeder());
+                Some(watchdog.spawn())
+            }
+        };
+
+        let stop = Arc::new(AtomicBool::new(false));
+        let transport = match source {
+            #[cfg(feature = "serial")]
+            Source::Serial(port_id) => {
+                let config = self.serial_config;
+                let diagnostics_tx = self.diagnostics_tx;
+                let stop = Arc::clone(&stop);
+                std::thread::spawn(move || {
+                    crate::gather_telemetry_until(
+                        &port_id,
+                        tx,
+                        recorder,
+                        Some(control_rx),
+                        &config,
+                        diagnostics_tx,
+                        Some(stop),
+                    )
+                })
+            }
+            #[cfg(feature = "websocket")]
+            Source::WebSocket(url) => {
+                let mut config = self.ws_config;
+                config.read_timeout = config.read_timeout.or(Some(STOP_CHECK_PERIOD));
+                let diagnostics_tx = self.diagnostics_tx;
+                let stop = Arc::clone(&stop);
+                std::thread::spawn(move || {
+                    crate::gather_telemetry_from_ws_until(
+                        &url,
+                        tx,
+                        recorder,
+                        Some(control_rx),
+                        &config,
+                        diagnostics_tx,
+                        Some(stop),
+                    )
+                })
+            }
+            Source::File(path) => {
+                // Control messages cannot be sent to a recording
+                drop(control_rx);
+                let file = File::open(path)?;
+                let time_simulation = self.time_simulation;
+                let diagnostics_tx = self.diagnostics_tx;
+                // Files are read without blocking: the transport returns as soon as the dispatcher drops its receiver
+                std::thread::spawn(move || {
+                    crate::gather_telemetry_from_file(file, tx, time_simulation, diagnostics_tx)
+                })
+            }
+        };
+
+        let thread_stop = Arc::clone(&stop);
+        let dispatcher = std::thread::spawn(move || {
+            while !thread_stop.load(Ordering::Relaxed) {
+                match rx.recv_timeout(STOP_CHECK_PERIOD) {
+                    Ok(message) => sinks.consume(&message),
+                    Err(RecvTimeoutError::Timeout) => {}
+                    Err(RecvTimeoutError::Disconnected) => break,
+                }
+            }
+            sinks.flush();
+        });
+
+        Ok(TelemetryHandle {
+            control_tx,
+            stop,
+            transport: Some(transport),
+            dispatcher: Some(dispatcher),
+            watchdog,
+        })
+    }
+}
+
+/// Running telemetry pipeline
+///
+/// Stopping it closes the source: serial ports within their timeout, WebSocket connections within their read timeout.
+/// Dropping the handle stops the pipeline, without disabling the RPi watchdog (as after a crash).
+pub struct TelemetryHandle {
+    control_tx: Sender<ControlMessage>,
+    stop: Arc<AtomicBool>,
+    transport: Option<JoinHandle<()>>,
+    dispatcher: Option<JoinHandle<()>>,
+    watchdog: Option<RunningWatchdog>,
+}
+
+impl TelemetryHandle {
+    /// Send a control message to the MCU (this fails for recorded files)
+    pub fn control(&self, message: ControlMessage) -> Result<(), SendError<ControlMessage>> {
+        self.control_tx.send(message)
+    }
+
+    /// Sender of control messages, e.g. to give to another thread
+    pub fn control_sender(&self) -> Sender<ControlMessage> {
+        self.control_tx.clone()
+    }
+
+    /// Whether callbacks are not called anymore, because the source ended
+    pub fn is_finished(&self) -> bool {
+        self.dispatcher
+            .as_ref()
+            .is_none_or(|dispatcher| dispatcher.is_finished())
+    }
+
+    /// Wait until a recorded file ends; live sources reconnect until they are stopped, so use `stop()` for them
+    pub fn join(mut self) {
+        self.join_threads();
+    }
+
+    /// Disable the RPi watchdog, close the source, stop calling callbacks and flush the recording
+    pub fn stop(mut self) {
+        if let Some(watchdog) = self.watchdog.take() {
+            watchdog.shutdown();
+        }
+        self.stop_threads();
+    }
+
+    fn stop_threads(&mut self) {
+        // The transport sends the control messages queued before, e.g. the heartbeat disabling the RPi watchdog
+        self.stop.store(true, Ordering::Release);
+        self.join_threads();
+    }
+
+    fn join_threads(&mut self) {
+        for thread in [self.dispatcher.take(), self.transport.take()]
+            .into_iter()
+            .flatten()
+        {
+            let _ = thread.join();
+        }
+    }
+}
+
+impl Drop for TelemetryHandle {
+    fn drop(&mut self) {
+        self.stop_threads();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::AtomicUsize;
+    use std::sync::Mutex;
+
+    #[test]
+    fn plays_a_recording() {
+        let path = std::env::temp_dir().join(format!("facade-{}.record", std::process::id()));
+        let messages = Arc::new(AtomicUsize::new(0));
+        let alarms = Arc::new(Mutex::new(Vec::new()));
+        let counted = Arc::clone(&messages);
+        let collected = Arc::clone(&alarms);
+
+        let telemetry = Telemetry::builder()
+            .source(Source::File(PathBuf::from("records/v2/short.record")))
+            .record(&path)
+            .on_message(move |_| {
+                counted.fetch_add(1, Ordering::Relaxed);
+            })
+            .on_alarm(move |alarm| collected.lock().unwrap().push(alarm.clone()))
+            .start()
+            .unwrap();
+        assert!(telemetry
+            .control(ControlMessage {
+                setting: crate::control::ControlSetting::PEEP,
+                value: 50,
+            })
+            .is_err());
+        telemetry.join();
+
+        let (tx, rx) = channel::<TimedMessage>();
+        crate::gather_telemetry_from_file(File::open(&path).unwrap(), tx, false, None);
+        let recorded = rx.iter().filter(|m| m.message.is_ok()).count();
+        std::fs::remove_file(&path).unwrap();
+        assert!(recorded > 0);
+        assert_eq!(messages.load(Ordering::Relaxed), recorded);
+        let alarms = alarms.lock().unwrap();
+        assert!(alarms.iter().any(|alarm| alarm.active));
+        assert!(alarms.iter().any(|alarm| !alarm.active));
+
+        assert!(matches!(
+            Telemetry::builder().start(),
+            Err(StartError::NoSource)
+        ));
+    }
+
+    #[cfg(feature = "websocket")]
+    #[test]
+    #[ntest::timeout(5000)]
+    fn stops_live_sources() {
+        use crate::control::{ControlSetting, DISABLE_RPI_WATCHDOG};
+        use crate::sink::WebSocketSink;
+
+        let (control_tx, control_rx) = channel();
+        let server = WebSocketSink::bind_with_control(
+            "127.0.0.1:0",
+            crate::websocket::WebSocketServerConfig::default(),
+            Some(control_tx),
+        )
+        .unwrap();
+        let url = url::Url::parse(&format!("ws://{}", server.local_addr())).unwrap();
+        let telemetry = Telemetry::builder()
+            .source(Source::WebSocket(url))
+            .start()
+            .unwrap();
+        while server.clients_count() == 0 {
+            std::thread::sleep(Duration::from_millis(1));
+        }
+
+        // The server never sends anything: the transport must not wait for a message to stop
+        telemetry.stop();
+        let disable = control_rx
+            .iter()
+            .find(|message| message.value == DISABLE_RPI_WATCHDOG)
+            .unwrap();
+        assert_eq!(disable.setting, ControlSetting::Heartbeat);
+    }
+}
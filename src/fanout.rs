@@ -0,0 +1,443 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard};
+use std::time::{Duration, Instant, SystemTime};
+
+/// Default number of messages that can wait in the queue of a client
+pub const DEFAULT_QUEUE_CAPACITY: usize = 512;
+
+/// Default time a client queue may stay full before the client is disconnected
+pub const DEFAULT_SLOW_CLIENT_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// What to do with a new message when the queue of a client is full
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum DropPolicy {
+    /// Drop the oldest queued message, so that the client gets the latest data once it catches up
+    DropOldest,
+    /// Drop the new message
+    DropNewest,
+    /// Disconnect the client right away
+    Disconnect,
+}
+
+impl std::str::FromStr for DropPolicy {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "drop-oldest" => Ok(Self::DropOldest),
+            "drop-newest" => Ok(Self::DropNewest),
+            "disconnect" => Ok(Self::Disconnect),
+            _ => Err("Supported drop policies are: drop-oldest, drop-newest, disconnect"),
+        }
+    }
+}
+
+/// How messages are queued for each client of a `FanOut`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct FanOutConfig {
+    /// Number of messages that can wait in the queue of a client
+    pub queue_capacity: usize,
+    /// What to do with a new message when the queue of a client is full
+    pub drop_policy: DropPolicy,
+    /// If set, clients whose queue stays full for this time are disconnected
+    pub slow_client_timeout: Option<Duration>,
+}
+
+impl Default for FanOutConfig {
+    fn default() -> Self {
+        Self {
+            queue_capacity: DEFAULT_QUEUE_CAPACITY,
+            drop_policy: DropPolicy::DropOldest,
+            slow_client_timeout: Some(DEFAULT_SLOW_CLIENT_TIMEOUT),
+        }
+    }
+}
+
+impl FanOutConfig {
+    /// Create the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Let at most this number of messages wait in the queue of a client (at least 1)
+    pub fn queue_capacity(mut self, capacity: usize) -> Self {
+        self.queue_capacity = capacity.max(1);
+        self
+    }
+
+    /// Choose what to do with a new message when the queue of a client is full
+    pub fn drop_policy(mut self, policy: DropPolicy) -> Self {
+        self.drop_policy = policy;
+        self
+    }
+
+    /// Disconnect clients whose queue stays full for this time, or never with `None`
+    pub fn slow_client_timeout(mut self, timeout: Option<Duration>) -> Self {
+        self.slow_client_timeout = timeout;
+        self
+    }
+}
+
+/// Delivery statistics of a connected client
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct ClientStats {
+    /// Name of the client (e.g. its address)
+    pub name: String,
+    /// Host wall-clock time at which the client connected
+    pub connected_at: SystemTime,
+    /// Number of messages sent to the client
+    pub sent: u64,
+    /// Number of messages dropped because its queue was full
+    pub dropped: u64,
+    /// Number of messages waiting in its queue
+    pub queued: usize,
+}
+
+/// Delivery statistics of a `FanOut`
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct FanOutStats {
+    /// Statistics of every connected client
+    pub clients: Vec<ClientStats>,
+    /// Number of clients that were disconnected because they could not keep up
+    pub slow_clients_disconnected: u64,
+    /// Number of clients that were disconnected because sending failed (e.g. they closed the connection)
+    pub failed_clients_disconnected: u64,
+}
+
+#[derive(Debug)]
+struct QueueState {
+    messages: VecDeque<Arc<[u8]>>,
+    closed: bool,
+    sent: u64,
+    dropped: u64,
+    full_since: Option<Instant>,
+}
+
+#[derive(Debug)]
+struct Queue {
+    state: Mutex<QueueState>,
+    ready: Condvar,
+}
+
+impl Queue {
+    fn lock(&self) -> MutexGuard<'_, QueueState> {
+        self.state
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    fn close(&self) {
+        self.lock().closed = true;
+        self.ready.notify_all();
+    }
+}
+
+struct Client {
+    name: String,
+    connected_at: SystemTime,
+    queue: Arc<Queue>,
+    interrupt: Box<dyn Fn() + Send>,
+}
+
+impl Drop for Client {
+    fn drop(&mut self) {
+        self.queue.close();
+    }
+}
+
+/// Fan-out of messages to clients, each with its own bounded queue and sending thread
+///
+/// A client that is slow (or stalled) only fills its own queue: messages are then dropped according to the `DropPolicy`,
+/// and the client is disconnected if its queue stays full for too long, while other clients keep receiving every message.
+pub struct FanOut {
+    config: FanOutConfig,
+    clients: Arc<Mutex<Vec<Client>>>,
+    slow_clients_disconnected: Arc<AtomicU64>,
+    failed_clients_disconnected: Arc<AtomicU64>,
+}
+
+impl FanOut {
+    /// Create a fan-out without any client
+    pub fn new(config: FanOutConfig) -> Self {
+        Self {
+            config,
+            clients: Arc::new(Mutex::new(Vec::new())),
+            slow_clients_disconnected: Arc::new(AtomicU64::new(0)),
+            failed_clients_disconnected: Arc::new(AtomicU64::new(0)),
+        }
+    }
+
+    fn clients(&self) -> MutexGuard<'_, Vec<Client>> {
+        self.clients
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Add a client; messages are given to `write` from a dedicated thread
+    ///
+    /// * `write` - Send a message to the client; the client is disconnected when it fails.
+    /// * `interrupt` - Unblock a pending `write` (e.g. by shutting down the socket), called when the client is disconnected for being too slow.
+    pub fn add_client<W, I>(&self, name: &str, mut write: W, interrupt: I)
+    where
+        W: FnMut(&[u8]) -> std::io::Result<()> + Send + 'static,
+        I: Fn() + Send + 'static,
+    {
+        let queue = Arc::new(Queue {
+            state: Mutex::new(QueueState {
+                messages: VecDeque::with_capacity(self.config.queue_capacity),
+                closed: false,
+                sent: 0,
+                dropped: 0,
+                full_since: None,
+            }),
+            ready: Condvar::new(),
+        });
+        let thread_queue = Arc::clone(&queue);
+        let capacity = self.config.queue_capacity;
+        let failed = Arc::clone(&self.failed_clients_disconnected);
+        let thread_name = name.to_owned();
+        std::thread::spawn(move || loop {
+            let message = {
+                let mut state = thread_queue.lock();
+                while state.messages.is_empty() && !state.closed {
+                    state = thread_queue
+                        .ready
+                        .wait(state)
+                        .unwrap_or_else(|poisoned| poisoned.into_inner());
+                }
+                if state.closed {
+                    return;
+                }
+                let message = state.messages.pop_front();
+                if state.messages.len() < capacity {
+                    state.full_since = None;
+                }
+                message
+            };
+            if let Some(message) = message {
+                if let Err(e) = write(&message) {
+                    let mut state = thread_queue.lock();
+                    // A failure caused by a disconnection of this client for being too slow was already counted
+                    if !state.closed {
+                        log::info!("[fan-out]\tdisconnecting {}: {}", thread_name, e);
+                        failed.fetch_add(1, Ordering::Relaxed);
+                        state.closed = true;
+                    }
+                    return;
+                }
+                thread_queue.lock().sent += 1;
+            }
+        });
+
+        self.clients().push(Client {
+            name: name.to_owned(),
+            connected_at: SystemTime::now(),
+            queue,
+            interrupt: Box::new(interrupt),
+        });
+    }
+
+    /// Queue a message for every client
+    pub fn send(&self, message: &[u8]) {
+        let message: Arc<[u8]> = Arc::from(message);
+        let now = Instant::now();
+        self.clients().retain(|client| {
+            let mut state = client.queue.lock();
+            if state.closed {
+                return false;
+            }
+            if state.messages.len() >= self.config.queue_capacity {
+                let full_since = *state.full_since.get_or_insert(now);
+                let too_slow = self.config.drop_policy == DropPolicy::Disconnect
+                    || self
+                        .config
+                        .slow_client_timeout
+                        .is_some_and(|timeout| now.duration_since(full_since) >= timeout);
+                if too_slow {
+                    log::warn!(
+                        "[fan-out]\tdisconnecting {}: it does not keep up with telemetry",
+                        client.name
+                    );
+                    state.closed = true;
+                    drop(state);
+                    client.queue.ready.notify_all();
+                    (client.interrupt)();
+                    self.slow_clients_disconnected
+                        .fetch_add(1, Ordering::Relaxed);
+                    return false;
+                }
+                state.dropped += 1;
+                match self.config.drop_policy {
+                    DropPolicy::DropOldest => {
+                        state.messages.pop_front();
+                        state.messages.push_back(Arc::clone(&message));
+                    }
+                    DropPolicy::DropNewest | DropPolicy::Disconnect => (),
+                }
+            } else {
+                state.messages.push_back(Arc::clone(&message));
+            }
+            drop(state);
+            client.queue.ready.notify_one();
+            true
+        });
+    }
+
+    /// Number of connected clients
+    pub fn clients_count(&self) -> usize {
+        self.clients()
+            .iter()
+            .filter(|client| !client.queue.lock().closed)
+            .count()
+    }
+
+    /// Delivery statistics of every connected client, and number of disconnected clients
+    pub fn stats(&self) -> FanOutStats {
+        let clients = self
+            .clients()
+            .iter()
+            .filter_map(|client| {
+                let state = client.queue.lock();
+                (!state.closed).then(|| ClientStats {
+                    name: client.name.clone(),
+                    connected_at: client.connected_at,
+                    sent: state.sent,
+                    dropped: state.dropped,
+                    queued: state.messages.len(),
+                })
+            })
+            .collect();
+        FanOutStats {
+            clients,
+            slow_clients_disconnected: self.slow_clients_disconnected.load(Ordering::Relaxed),
+            failed_clients_disconnected: self.failed_clients_disconnected.load(Ordering::Relaxed),
+        }
+    }
+}
+
+impl Clone for FanOut {
+    /// Clones share the same clients, so that one clone can accept clients while another one sends messages
+    fn clone(&self) -> Self {
+        Self {
+            config: self.config.clone(),
+            clients: Arc::clone(&self.clients),
+            slow_clients_disconnected: Arc::clone(&self.slow_clients_disconnected),
+            failed_clients_disconnected: Arc::clone(&self.failed_clients_disconnected),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::channel;
+
+    fn wait_until<F: Fn() -> bool>(condition: F) {
+        let start = Instant::now();
+        while !condition() {
+            assert!(start.elapsed() < Duration::from_secs(5), "timed out");
+            std::thread::sleep(Duration::from_millis(1));
+        }
+    }
+
+    #[test]
+    fn stalled_client_does_not_stall_others() {
+        let fan_out = FanOut::new(
+            FanOutConfig::new()
+                .queue_capacity(4)
+                .slow_client_timeout(None),
+        );
+        let (fast_tx, fast_rx) = channel();
+        fan_out.add_client(
+            "fast",
+            move |message| {
+                fast_tx.send(message.to_vec()).unwrap();
+                Ok(())
+            },
+            || (),
+        );
+        // The stalled client blocks on its first message until it is released
+        let (release_tx, release_rx) = channel::<()>();
+        let (stalled_tx, stalled_rx) = channel();
+        fan_out.add_client(
+            "stalled",
+            move |message| {
+                let _ = release_rx.recv();
+                stalled_tx.send(message[0]).unwrap();
+                Ok(())
+            },
+            || (),
+        );
+
+        fan_out.send(&[0]);
+        wait_until(|| fan_out.stats().clients[1].queued == 0);
+        for value in 1..10 {
+            fan_out.send(&[value]);
+            // The fast client has time to receive each message
+            wait_until(|| fan_out.stats().clients[0].sent == u64::from(value) + 1);
+        }
+        assert_eq!(fast_rx.try_iter().count(), 10);
+
+        let stats = fan_out.stats();
+        assert_eq!(stats.clients[1].queued, 4);
+        assert_eq!(stats.clients[1].dropped, 5);
+
+        drop(release_tx);
+        wait_until(|| fan_out.stats().clients[1].sent == 5);
+        // Oldest messages were dropped
+        assert_eq!(stalled_rx.try_iter().collect::<Vec<u8>>(), [0, 6, 7, 8, 9]);
+    }
+
+    #[test]
+    fn disconnects_slow_clients() {
+        let fan_out = FanOut::new(
+            FanOutConfig::new()
+                .queue_capacity(1)
+                .slow_client_timeout(Some(Duration::ZERO)),
+        );
+        let (release_tx, release_rx) = channel::<()>();
+        let release_tx = Mutex::new(Some(release_tx));
+        let (interrupted_tx, interrupted_rx) = channel();
+        fan_out.add_client(
+            "stalled",
+            move |_| {
+                let _ = release_rx.recv();
+                Err(std::io::Error::other("socket was shut down"))
+            },
+            move || {
+                // Like shutting down a socket, this unblocks the pending write
+                release_tx.lock().unwrap().take();
+                interrupted_tx.send(()).unwrap();
+            },
+        );
+
+        fan_out.send(&[0]);
+        wait_until(|| fan_out.stats().clients[0].queued == 0);
+        fan_out.send(&[1]);
+        fan_out.send(&[2]);
+        interrupted_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+
+        let stats = fan_out.stats();
+        assert!(stats.clients.is_empty());
+        assert_eq!(stats.slow_clients_disconnected, 1);
+        assert_eq!(stats.failed_clients_disconnected, 0);
+    }
+}
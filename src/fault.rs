@@ -0,0 +1,373 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::adapter::MessageAdapter;
+use crate::structures::{FatalError, FatalErrorDetails, TelemetryMessage};
+
+/// Interval at which the MCU repeats its fatal error message, in microseconds
+pub const FATAL_ERROR_PERIOD: u64 = 1_000_000;
+
+/// A fault that can happen on a device
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Fault {
+    /// The mass flow meter stops answering: the MCU stops ventilating and only sends `FatalErrorDetails::MassFlowMeterError` errors
+    MassFlowMeterFailure,
+    /// The battery voltage decreases linearly from its current level
+    BatterySag {
+        /// Battery level reached at the end, in centivolts
+        to: u16,
+        /// Time to reach this level
+        over: Duration,
+    },
+    /// Random noise is added to pressure readings
+    PressureNoise {
+        /// Highest absolute value of the noise, in mmH2O
+        amplitude: i16,
+        /// How long the noise lasts
+        duration: Duration,
+    },
+}
+
+/// A fault and when it starts
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TimedFault {
+    /// Time at which the fault starts, relative to the first message of the stream
+    pub at: Duration,
+    /// The fault
+    pub fault: Fault,
+}
+
+/// A fault description that could not be parsed
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid fault {0} (expected AT:mass-flow-meter-failure, AT:battery-sag:CENTIVOLTS:SECONDS or AT:pressure-noise:MMH2O:SECONDS, AT being in seconds)")]
+pub struct InvalidFault(pub String);
+
+impl FromStr for TimedFault {
+    type Err = InvalidFault;
+
+    /// Parse a fault such as `30:battery-sag:2200:60` (from 30 s, the battery goes down to 22 V in 60 s)
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || InvalidFault(s.to_owned());
+        let seconds = |part: &str| {
+            part.parse::<f64>()
+                .ok()
+                .filter(|seconds| seconds.is_finite() && *seconds >= 0.0)
+                .map(Duration::from_secs_f64)
+                .ok_or_else(invalid)
+        };
+        let parts: Vec<&str> = s.split(':').collect();
+        let fault = match parts.as_slice() {
+            [_, "mass-flow-meter-failure"] => Fault::MassFlowMeterFailure,
+            [_, "battery-sag", to, over] => Fault::BatterySag {
+                to: to.parse().map_err(|_| invalid())?,
+                over: seconds(over)?,
+            },
+            [_, "pressure-noise", amplitude, duration] => Fault::PressureNoise {
+                amplitude: amplitude.parse().map_err(|_| invalid())?,
+                duration: seconds(duration)?,
+            },
+            _ => return Err(invalid()),
+        };
+        Ok(Self {
+            at: seconds(parts[0])?,
+            fault,
+        })
+    }
+}
+
+/// Faults to inject into a stream of telemetry messages, e.g. to exercise how a UI handles fatal errors or a low battery
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FaultScenario {
+    /// Faults of the scenario, in any order
+    pub faults: Vec<TimedFault>,
+}
+
+impl FaultScenario {
+    /// Create a scenario without any fault
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a fault starting at the given time
+    pub fn fault(mut self, at: Duration, fault: Fault) -> Self {
+        self.faults.push(TimedFault { at, fault });
+        self
+    }
+}
+
+/// Alters telemetry messages (from a recording or a live device) according to a fault scenario
+///
+/// Time is measured with the systick of messages, from the first message handled; messages are expected to come from a single device boot.
+#[derive(Debug, Clone)]
+pub struct FaultInjector {
+    scenario: FaultScenario,
+    start_systick: Option<u64>,
+    sag_start_level: Option<u16>,
+    last_fatal_error: Option<u64>,
+    noise_state: u32,
+}
+
+impl FaultInjector {
+    /// Create an injector playing a scenario
+    pub fn new(scenario: FaultScenario) -> Self {
+        Self {
+            scenario,
+            start_systick: None,
+            sag_start_level: None,
+            last_fatal_error: None,
+            noise_state: 0x2545_F491,
+        }
+    }
+
+    /// Use another seed for the pressure noise, which is the same for every run by default
+    pub fn with_seed(mut self, seed: u32) -> Self {
+        self.noise_state = seed.max(1);
+        self
+    }
+
+    fn active(&self, elapsed: Duration) -> impl Iterator<Item = (Duration, Fault)> + '_ {
+        self.scenario
+            .faults
+            .iter()
+            .filter(move |timed| timed.at <= elapsed)
+            .map(move |timed| (elapsed - timed.at, timed.fault))
+    }
+
+    fn battery_level(&mut self, level: u16, elapsed: Duration) -> u16 {
+        let sag = self.active(elapsed).find_map(|(since, fault)| match fault {
+            Fault::BatterySag { to, over } => Some((since, to, over)),
+            _ => None,
+        });
+        match sag {
+            Some((since, to, over)) => {
+                let from = *self.sag_start_level.get_or_insert(level);
+                let progress = if over.is_zero() {
+                    1.0
+                } else {
+                    (since.as_secs_f64() / over.as_secs_f64()).min(1.0)
+                };
+                (f64::from(from) + (f64::from(to) - f64::from(from)) * progress).round() as u16
+            }
+            None => level,
+        }
+    }
+
+    fn pressure_noise(&mut self, elapsed: Duration) -> i16 {
+        let amplitude = self
+            .active(elapsed)
+            .filter_map(|(since, fault)| match fault {
+                Fault::PressureNoise {
+                    amplitude,
+                    duration,
+                } if since < duration => Some(amplitude.saturating_abs()),
+                _ => None,
+            })
+            .max();
+        match amplitude {
+            Some(amplitude) if amplitude > 0 => {
+                // xorshift32, so that runs are reproducible without depending on `rand`
+                self.noise_state ^= self.noise_state << 13;
+                self.noise_state ^= self.noise_state >> 17;
+                self.noise_state ^= self.noise_state << 5;
+                let span = 2 * i32::from(amplitude) + 1;
+                ((self.noise_state % span as u32) as i32 - i32::from(amplitude)) as i16
+            }
+            _ => 0,
+        }
+    }
+}
+
+impl MessageAdapter for FaultInjector {
+    type Output = TelemetryMessage;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<TelemetryMessage> {
+        if let TelemetryMessage::Unknown { .. } = message {
+            return vec![message.clone()];
+        }
+        let systick = message.systick();
+        let start = *self.start_systick.get_or_insert(systick);
+        let elapsed = Duration::from_micros(systick.saturating_sub(start));
+
+        if self
+            .active(elapsed)
+            .any(|(_, fault)| fault == Fault::MassFlowMeterFailure)
+        {
+            if self
+                .last_fatal_error
+                .is_some_and(|last| systick.saturating_sub(last) < FATAL_ERROR_PERIOD)
+            {
+                return Vec::new();
+            }
+            self.last_fatal_error = Some(systick);
+            return vec![TelemetryMessage::FatalError(FatalError {
+                telemetry_version: message.telemetry_version(),
+                version: message.version(),
+                device_id: message.device_id(),
+                systick,
+                error: FatalErrorDetails::MassFlowMeterError,
+            })];
+        }
+
+        let mut message = message.clone();
+        match &mut message {
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                let level = self.battery_level(u16::from(snapshot.battery_level) * 100, elapsed);
+                snapshot.battery_level = u8::try_from(level / 100).unwrap_or(u8::MAX);
+                snapshot.pressure = snapshot
+                    .pressure
+                    .saturating_add(self.pressure_noise(elapsed));
+            }
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                if let Some(level) = snapshot.battery_level {
+                    snapshot.battery_level = Some(self.battery_level(level, elapsed));
+                }
+            }
+            TelemetryMessage::StoppedMessage(message) => {
+                if let Some(level) = message.battery_level {
+                    message.battery_level = Some(self.battery_level(level, elapsed));
+                }
+            }
+            _ => (),
+        }
+        vec![message]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::DataSnapshot;
+    use crate::structures::{Phase, StoppedMessage};
+
+    fn stopped(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 2,
+            systick,
+            battery_level: Some(2_600),
+            ..Default::default()
+        })
+    }
+
+    fn data(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick,
+            centile: 0,
+            pressure: 100,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 26,
+            inspiratory_flow: Some(1_000),
+            expiratory_flow: Some(0),
+        })
+    }
+
+    fn battery_level(message: &TelemetryMessage) -> Option<u16> {
+        match message {
+            TelemetryMessage::StoppedMessage(message) => message.battery_level,
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn parses_faults() {
+        assert_eq!(
+            "30:battery-sag:2200:60".parse(),
+            Ok(TimedFault {
+                at: Duration::from_secs(30),
+                fault: Fault::BatterySag {
+                    to: 2_200,
+                    over: Duration::from_secs(60)
+                }
+            })
+        );
+        assert_eq!(
+            "0.5:mass-flow-meter-failure"
+                .parse::<TimedFault>()
+                .map(|f| f.at),
+            Ok(Duration::from_millis(500))
+        );
+        assert!("10:pressure-noise:20".parse::<TimedFault>().is_err());
+        assert!("-1:mass-flow-meter-failure".parse::<TimedFault>().is_err());
+    }
+
+    #[test]
+    fn ramps_battery_down() {
+        let scenario = FaultScenario::new().fault(
+            Duration::from_secs(10),
+            Fault::BatterySag {
+                to: 2_200,
+                over: Duration::from_secs(40),
+            },
+        );
+        let mut injector = FaultInjector::new(scenario);
+
+        let levels: Vec<Option<u16>> = [0, 10, 30, 50, 60]
+            .iter()
+            .flat_map(|seconds| injector.handle(&stopped(seconds * 1_000_000)))
+            .map(|message| battery_level(&message))
+            .collect();
+        assert_eq!(
+            levels,
+            vec![
+                Some(2_600),
+                Some(2_600),
+                Some(2_400),
+                Some(2_200),
+                Some(2_200)
+            ]
+        );
+    }
+
+    #[test]
+    fn adds_bounded_pressure_noise() {
+        let scenario = FaultScenario::new().fault(
+            Duration::ZERO,
+            Fault::PressureNoise {
+                amplitude: 20,
+                duration: Duration::from_secs(1),
+            },
+        );
+        let mut injector = FaultInjector::new(scenario);
+
+        let pressures: Vec<i16> = (0..200)
+            .flat_map(|i| injector.handle(&data(i * 10_000)))
+            .filter_map(|message| match message {
+                TelemetryMessage::DataSnapshot(snapshot) => Some(snapshot.pressure),
+                _ => None,
+            })
+            .collect();
+        assert!(pressures[..100].iter().all(|p| (80..=120).contains(p)));
+        assert!(pressures[..100].iter().any(|p| *p != 100));
+        assert!(pressures[100..].iter().all(|p| *p == 100));
+    }
+
+    #[test]
+    fn mass_flow_meter_failure_becomes_fatal_error() {
+        let scenario =
+            FaultScenario::new().fault(Duration::from_secs(1), Fault::MassFlowMeterFailure);
+        let mut injector = FaultInjector::new(scenario);
+
+        let messages: Vec<TelemetryMessage> = (0..300)
+            .flat_map(|i| injector.handle(&data(i * 10_000)))
+            .collect();
+        assert_eq!(messages.len(), 102);
+        assert!(messages[100..].iter().all(|message| matches!(
+            message,
+            TelemetryMessage::FatalError(FatalError {
+                error: FatalErrorDetails::MassFlowMeterError,
+                ..
+            })
+        )));
+    }
+}
@@ -0,0 +1,212 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::str::FromStr;
+
+use crate::sink::TelemetrySink;
+use crate::structures::TelemetryMessage;
+use crate::TimedMessage;
+
+/// Type of a telemetry message, without its content
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum MessageType {
+    /// See `TelemetryMessage::BootMessage`
+    Boot,
+    /// See `TelemetryMessage::StoppedMessage`
+    Stopped,
+    /// See `TelemetryMessage::DataSnapshot`
+    DataSnapshot,
+    /// See `TelemetryMessage::MachineStateSnapshot`
+    MachineStateSnapshot,
+    /// See `TelemetryMessage::AlarmTrap`
+    AlarmTrap,
+    /// See `TelemetryMessage::ControlAck`
+    ControlAck,
+    /// See `TelemetryMessage::FatalError`
+    FatalError,
+    /// See `TelemetryMessage::EolTestSnapshot`
+    EolTestSnapshot,
+    /// See `TelemetryMessage::Unknown`
+    Unknown,
+}
+
+impl MessageType {
+    /// Get the type of a telemetry message
+    pub fn of(message: &TelemetryMessage) -> Self {
+        match message {
+            TelemetryMessage::BootMessage(_) => Self::Boot,
+            TelemetryMessage::StoppedMessage(_) => Self::Stopped,
+            TelemetryMessage::DataSnapshot(_) => Self::DataSnapshot,
+            TelemetryMessage::MachineStateSnapshot(_) => Self::MachineStateSnapshot,
+            TelemetryMessage::AlarmTrap(_) => Self::AlarmTrap,
+            TelemetryMessage::ControlAck(_) => Self::ControlAck,
+            TelemetryMessage::FatalError(_) => Self::FatalError,
+            TelemetryMessage::EolTestSnapshot(_) => Self::EolTestSnapshot,
+            TelemetryMessage::Unknown { .. } => Self::Unknown,
+        }
+    }
+
+    /// Name of the type, as accepted by `from_str()` (e.g. `data-snapshot`)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Boot => "boot",
+            Self::Stopped => "stopped",
+            Self::DataSnapshot => "data-snapshot",
+            Self::MachineStateSnapshot => "machine-state-snapshot",
+            Self::AlarmTrap => "alarm-trap",
+            Self::ControlAck => "control-ack",
+            Self::FatalError => "fatal-error",
+            Self::EolTestSnapshot => "eol-test-snapshot",
+            Self::Unknown => "unknown",
+        }
+    }
+}
+
+impl FromStr for MessageType {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "boot" => Ok(Self::Boot),
+            "stopped" => Ok(Self::Stopped),
+            "data-snapshot" => Ok(Self::DataSnapshot),
+            "machine-state-snapshot" => Ok(Self::MachineStateSnapshot),
+            "alarm-trap" => Ok(Self::AlarmTrap),
+            "control-ack" => Ok(Self::ControlAck),
+            "fatal-error" => Ok(Self::FatalError),
+            "eol-test-snapshot" => Ok(Self::EolTestSnapshot),
+            "unknown" => Ok(Self::Unknown),
+            _ => Err("Supported message types are: boot, stopped, data-snapshot, machine-state-snapshot, alarm-trap, control-ack, fatal-error, eol-test-snapshot, unknown"),
+        }
+    }
+}
+
+/// Selection of telemetry messages based on their type
+///
+/// By default, every message matches; `only()` restricts the selection to some types and `exclude()` removes some types from it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MessageFilter {
+    /// If not empty, only messages of these types match
+    pub only: Vec<MessageType>,
+    /// Messages of these types never match
+    pub exclude: Vec<MessageType>,
+}
+
+impl MessageFilter {
+    /// Create a filter that matches every message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Only keep messages of the given types
+    pub fn only<I: IntoIterator<Item = MessageType>>(mut self, types: I) -> Self {
+        self.only.extend(types);
+        self
+    }
+
+    /// Drop messages of the given types
+    pub fn exclude<I: IntoIterator<Item = MessageType>>(mut self, types: I) -> Self {
+        self.exclude.extend(types);
+        self
+    }
+
+    /// Whether every message matches this filter
+    pub fn is_pass_through(&self) -> bool {
+        self.only.is_empty() && self.exclude.is_empty()
+    }
+
+    /// Whether a message of the given type matches this filter
+    pub fn matches_type(&self, message_type: MessageType) -> bool {
+        (self.only.is_empty() || self.only.contains(&message_type))
+            && !self.exclude.contains(&message_type)
+    }
+
+    /// Whether a message matches this filter
+    pub fn matches(&self, message: &TelemetryMessage) -> bool {
+        self.matches_type(MessageType::of(message))
+    }
+}
+
+/// Sink that only forwards messages matching a filter to another sink
+///
+/// Errors are always forwarded.
+pub struct FilteredSink<S> {
+    filter: MessageFilter,
+    inner: S,
+}
+
+impl<S: TelemetrySink> FilteredSink<S> {
+    /// Wrap a sink so that it only receives messages matching a filter
+    pub fn new(filter: MessageFilter, inner: S) -> Self {
+        Self { filter, inner }
+    }
+
+    /// Get back the wrapped sink
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: TelemetrySink> TelemetrySink for FilteredSink<S> {
+    fn consume(&mut self, message: &TimedMessage) {
+        match &message.message {
+            Ok(msg) if !self.filter.matches(msg) => (),
+            _ => self.inner.consume(message),
+        }
+    }
+
+    fn flush(&mut self) {
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::StoppedMessage;
+
+    const ALL_TYPES: [MessageType; 9] = [
+        MessageType::Boot,
+        MessageType::Stopped,
+        MessageType::DataSnapshot,
+        MessageType::MachineStateSnapshot,
+        MessageType::AlarmTrap,
+        MessageType::ControlAck,
+        MessageType::FatalError,
+        MessageType::EolTestSnapshot,
+        MessageType::Unknown,
+    ];
+
+    #[test]
+    fn names_roundtrip() {
+        for message_type in ALL_TYPES {
+            assert_eq!(MessageType::from_str(message_type.name()), Ok(message_type));
+        }
+        assert!(MessageType::from_str("DataSnapshot").is_err());
+    }
+
+    #[test]
+    fn only_and_exclude() {
+        let stopped = TelemetryMessage::StoppedMessage(StoppedMessage::default());
+        assert!(MessageFilter::new().matches(&stopped));
+        assert!(!MessageFilter::new()
+            .exclude([MessageType::Stopped])
+            .matches(&stopped));
+        assert!(!MessageFilter::new()
+            .only([MessageType::DataSnapshot, MessageType::AlarmTrap])
+            .matches(&stopped));
+
+        let filter = MessageFilter::new()
+            .only([MessageType::Stopped, MessageType::Boot])
+            .exclude([MessageType::Boot]);
+        assert!(filter.matches(&stopped));
+        assert!(!filter.matches_type(MessageType::Boot));
+        assert!(!filter.matches_type(MessageType::DataSnapshot));
+    }
+}
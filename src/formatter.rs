@@ -0,0 +1,252 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use log::{debug, error, info, warn};
+
+use crate::error::Error;
+use crate::structures::*;
+use crate::TelemetryChannelType;
+
+const ANSI_RESET: &str = "\x1b[0m";
+const ANSI_BOLD_RED: &str = "\x1b[1;31m";
+const ANSI_RED: &str = "\x1b[31m";
+const ANSI_GREEN: &str = "\x1b[32m";
+const ANSI_YELLOW: &str = "\x1b[33m";
+const ANSI_BLUE: &str = "\x1b[34m";
+const ANSI_MAGENTA: &str = "\x1b[35m";
+const ANSI_CYAN: &str = "\x1b[36m";
+const ANSI_DIM: &str = "\x1b[2m";
+
+/// A way to render telemetry messages for humans
+pub trait MessageFormatter {
+    /// Render a telemetry message (or an error) as a single line of text
+    fn format(&self, message: &TelemetryChannelType) -> String;
+
+    /// Output a telemetry message (or an error); defaults to printing the formatted line on stdout
+    fn display(&self, message: &TelemetryChannelType) {
+        println!("{}", self.format(message));
+    }
+}
+
+impl<F: MessageFormatter + ?Sized> MessageFormatter for Box<F> {
+    fn format(&self, message: &TelemetryChannelType) -> String {
+        (**self).format(message)
+    }
+
+    fn display(&self, message: &TelemetryChannelType) {
+        (**self).display(message)
+    }
+}
+
+/// Formatter that outputs messages through the `log` facade, with separators around important messages
+#[derive(Debug, Default, Clone, Copy)]
+pub struct LogFormatter;
+
+impl MessageFormatter for LogFormatter {
+    fn format(&self, message: &TelemetryChannelType) -> String {
+        match message {
+            Ok(TelemetryMessage::StoppedMessage(_)) => "stopped".to_owned(),
+            Ok(TelemetryMessage::DataSnapshot(_)) | Ok(TelemetryMessage::EolTestSnapshot(_)) => {
+                format!("    {:?}", message.as_ref().unwrap())
+            }
+            Ok(TelemetryMessage::AlarmTrap(AlarmTrap { triggered, .. })) => {
+                let prefix = if *triggered { "NEW ALARM" } else { "STOPPED" };
+                format!("{} {:?}", prefix, message.as_ref().unwrap())
+            }
+            Ok(TelemetryMessage::ControlAck(ControlAck { setting, value, .. })) => {
+                format!("← {:?} = {}", setting, value)
+            }
+            Ok(TelemetryMessage::FatalError(FatalError { error, .. })) => {
+                format!(
+                    "***** FATAL ERROR ***** {:?} ({:?}: {})",
+                    error,
+                    error.severity(),
+                    error
+                        .recommended_action()
+                        .description(&crate::locale::Locale::default())
+                )
+            }
+            Ok(msg) => format!("{:?}", msg),
+            Err(e) => format!("an error occurred: {:?}", e),
+        }
+    }
+
+    fn display(&self, message: &TelemetryChannelType) {
+        let line = self.format(message);
+        match message {
+            Ok(TelemetryMessage::BootMessage(_)) => {
+                debug!("####################################################################################");
+                debug!("######### CONTROLLER STARTED #########");
+                debug!("####################################################################################");
+                info!("{}", line);
+                debug!("####################################################################################");
+            }
+            Ok(TelemetryMessage::StoppedMessage(_)) => {
+                debug!("{}", line);
+            }
+            Ok(TelemetryMessage::MachineStateSnapshot(_)) => {
+                debug!("------------------------------------------------------------------------------------");
+                info!("{}", line);
+                debug!("------------------------------------------------------------------------------------");
+            }
+            Ok(TelemetryMessage::AlarmTrap(_)) => {
+                debug!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+                info!("{}", line);
+                debug!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
+            }
+            Ok(_) => {
+                info!("{}", line);
+            }
+            Err(Error::TelemetryError(
+                HighLevelError::LinkMisconfigured { .. } | HighLevelError::BreakCondition { .. },
+            )) => {
+                error!("{}", line);
+            }
+            Err(_) => {
+                warn!("{}", line);
+            }
+        }
+    }
+}
+
+/// Formatter that outputs one short line per message on stdout, only showing the most relevant fields
+#[derive(Debug, Default, Clone, Copy)]
+pub struct CompactFormatter;
+
+impl MessageFormatter for CompactFormatter {
+    fn format(&self, message: &TelemetryChannelType) -> String {
+        let message = match message {
+            Ok(message) => message,
+            Err(e) => return format!("{:>12} ERROR   {:?}", "-", e),
+        };
+
+        let details = match message {
+            TelemetryMessage::BootMessage(msg) => {
+                format!("version={} mode={:?}", msg.version, msg.mode)
+            }
+            TelemetryMessage::StoppedMessage(msg) => {
+                format!("ventilation_mode={:?}", msg.ventilation_mode)
+            }
+            TelemetryMessage::DataSnapshot(msg) => format!(
+                "phase={:?} pressure={} blower_rpm={}",
+                msg.phase, msg.pressure, msg.blower_rpm
+            ),
+            TelemetryMessage::MachineStateSnapshot(msg) => format!(
+                "cycle={} peak={} plateau={} peep={} alarms={:?}",
+                msg.cycle,
+                msg.previous_peak_pressure,
+                msg.previous_plateau_pressure,
+                msg.previous_peep_pressure,
+                msg.current_alarm_codes
+            ),
+            TelemetryMessage::AlarmTrap(msg) => format!(
+                "code={} priority={:?} triggered={}",
+                msg.alarm_code, msg.alarm_priority, msg.triggered
+            ),
+            TelemetryMessage::ControlAck(msg) => format!("{:?}={}", msg.setting, msg.value),
+            TelemetryMessage::FatalError(msg) => format!("{:?}", msg.error),
+            TelemetryMessage::EolTestSnapshot(msg) => {
+                format!("step={:?} {:?}", msg.current_step, msg.content)
+            }
+            TelemetryMessage::Unknown {
+                type_byte, payload, ..
+            } => format!("type={:#04x} length={}", type_byte, payload.len()),
+        };
+
+        format!(
+            "{:>12} {:<7} {}",
+            message.systick(),
+            short_name(message),
+            details
+        )
+    }
+}
+
+/// Formatter that outputs the same lines as `CompactFormatter`, colorized for ANSI terminals
+#[derive(Debug, Default, Clone, Copy)]
+pub struct ColorFormatter;
+
+impl MessageFormatter for ColorFormatter {
+    fn format(&self, message: &TelemetryChannelType) -> String {
+        let color = match message {
+            Ok(TelemetryMessage::BootMessage(_)) => ANSI_GREEN,
+            Ok(TelemetryMessage::StoppedMessage(_)) => ANSI_YELLOW,
+            Ok(TelemetryMessage::DataSnapshot(_)) => ANSI_DIM,
+            Ok(TelemetryMessage::MachineStateSnapshot(_)) => ANSI_CYAN,
+            Ok(TelemetryMessage::AlarmTrap(AlarmTrap { triggered, .. })) => {
+                if *triggered {
+                    ANSI_RED
+                } else {
+                    ANSI_YELLOW
+                }
+            }
+            Ok(TelemetryMessage::ControlAck(_)) => ANSI_BLUE,
+            Ok(TelemetryMessage::FatalError(_)) => ANSI_BOLD_RED,
+            Ok(TelemetryMessage::EolTestSnapshot(_)) => ANSI_MAGENTA,
+            Ok(TelemetryMessage::Unknown { .. }) => ANSI_DIM,
+            Err(_) => ANSI_RED,
+        };
+
+        format!(
+            "{}{}{}",
+            color,
+            CompactFormatter.format(message),
+            ANSI_RESET
+        )
+    }
+}
+
+fn short_name(message: &TelemetryMessage) -> &'static str {
+    match message {
+        TelemetryMessage::BootMessage(_) => "BOOT",
+        TelemetryMessage::StoppedMessage(_) => "STOPPED",
+        TelemetryMessage::DataSnapshot(_) => "DATA",
+        TelemetryMessage::MachineStateSnapshot(_) => "CYCLE",
+        TelemetryMessage::AlarmTrap(_) => "ALARM",
+        TelemetryMessage::ControlAck(_) => "ACK",
+        TelemetryMessage::FatalError(_) => "FATAL",
+        TelemetryMessage::EolTestSnapshot(_) => "EOL",
+        TelemetryMessage::Unknown { .. } => "UNKNOWN",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+
+    fn ack() -> TelemetryMessage {
+        TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "0-0-0".to_owned(),
+            systick: 42,
+            setting: ControlSetting::PEEP,
+            value: 80,
+        })
+    }
+
+    #[test]
+    fn log_formatter_format() {
+        assert_eq!(LogFormatter.format(&Ok(ack())), "← PEEP = 80");
+    }
+
+    #[test]
+    fn compact_formatter_is_single_line() {
+        let line = CompactFormatter.format(&Ok(ack()));
+
+        assert_eq!(line, "          42 ACK     PEEP=80");
+        assert!(!line.contains('\n'));
+    }
+
+    #[test]
+    fn color_formatter_wraps_compact_line() {
+        let line = ColorFormatter.format(&Ok(ack()));
+
+        assert!(line.starts_with(ANSI_BLUE));
+        assert!(line.ends_with(ANSI_RESET));
+        assert!(line.contains(&CompactFormatter.format(&Ok(ack()))));
+    }
+}
@@ -0,0 +1,223 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::control::{CONTROL_FRAME_FOOTER, CONTROL_FRAME_HEADER, PROTECTED_CONTROL_FRAME_HEADER};
+use crate::parsers::{FRAME_FOOTER, FRAME_HEADER};
+
+const CRC_LENGTH: usize = 4;
+
+/// Kind of frame, telling its header and footer
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum FrameKind {
+    /// Telemetry frame, sent by the MCU
+    Telemetry,
+    /// Control frame, sent to the MCU
+    Control,
+    /// Control frame protected against replays, sent to the MCU
+    ProtectedControl,
+}
+
+impl FrameKind {
+    /// Bytes starting frames of this kind
+    pub fn header(&self) -> &'static [u8; 2] {
+        match self {
+            Self::Telemetry => FRAME_HEADER,
+            Self::Control => CONTROL_FRAME_HEADER,
+            Self::ProtectedControl => PROTECTED_CONTROL_FRAME_HEADER,
+        }
+    }
+
+    /// Bytes ending frames of this kind
+    pub fn footer(&self) -> &'static [u8; 2] {
+        match self {
+            Self::Telemetry => FRAME_FOOTER,
+            Self::Control | Self::ProtectedControl => CONTROL_FRAME_FOOTER,
+        }
+    }
+
+    /// Kind of the frame starting with these bytes
+    pub fn of(frame: &[u8]) -> Option<Self> {
+        [Self::Telemetry, Self::Control, Self::ProtectedControl]
+            .into_iter()
+            .find(|kind| frame.starts_with(kind.header()))
+    }
+
+    pub(crate) fn overhead(&self) -> usize {
+        self.header().len() + CRC_LENGTH + self.footer().len()
+    }
+}
+
+/// Error returned when bytes are not a valid frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum FramingError {
+    /// Bytes do not start with the header of a known kind of frame
+    #[error("bytes do not start with a frame header")]
+    InvalidHeader,
+    /// Bytes are too short to be a whole frame, or no valid end of frame was found yet
+    #[error("frame is incomplete")]
+    Incomplete,
+    /// Bytes do not end with the footer matching their header
+    #[error("frame does not end with the expected footer")]
+    InvalidFooter,
+    /// CRC of the frame does not match its payload
+    #[error("invalid CRC: expected={expected} computed={computed}")]
+    CrcError {
+        /// CRC written in the frame
+        expected: u32,
+        /// CRC of the payload
+        computed: u32,
+    },
+}
+
+/// Payload extracted from a frame
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Unframed<'a> {
+    /// Kind of the frame
+    pub kind: FrameKind,
+    /// Bytes between the header and the CRC
+    pub payload: &'a [u8],
+}
+
+/// Wrap a payload into a telemetry frame (header, payload, CRC and footer)
+pub fn encode(payload: &[u8]) -> Vec<u8> {
+    encode_with(FrameKind::Telemetry, payload)
+}
+
+/// Wrap a payload into a frame of the given kind (header, payload, CRC and footer)
+pub fn encode_with(kind: FrameKind, payload: &[u8]) -> Vec<u8> {
+    let mut frame = Vec::with_capacity(payload.len() + kind.overhead());
+    frame.extend_from_slice(kind.header());
+    frame.extend_from_slice(payload);
+    frame.extend_from_slice(&crc32fast::hash(payload).to_be_bytes());
+    frame.extend_from_slice(kind.footer());
+    frame
+}
+
+/// Extract the payload of a whole frame, of any kind, and check its CRC
+///
+/// * `frame` - Bytes of exactly one frame, from its header to its footer.
+pub fn decode(frame: &[u8]) -> Result<Unframed<'_>, FramingError> {
+    let kind = kind_of(frame)?;
+    if frame.len() < kind.overhead() {
+        return Err(FramingError::Incomplete);
+    }
+    let footer_start = frame.len() - kind.footer().len();
+    if &frame[footer_start..] != kind.footer() {
+        return Err(FramingError::InvalidFooter);
+    }
+    let crc_start = footer_start - CRC_LENGTH;
+    let payload = &frame[kind.header().len()..crc_start];
+    let expected = read_crc(&frame[crc_start..footer_start]);
+    let computed = crc32fast::hash(payload);
+    if expected == computed {
+        Ok(Unframed { kind, payload })
+    } else {
+        Err(FramingError::CrcError { expected, computed })
+    }
+}
+
+/// Extract the payload of the frame at the start of a stream of bytes, and return the bytes following it
+///
+/// * `input` - Bytes starting with a frame header.
+///
+/// As payloads may contain footer bytes, the frame ends at the first footer preceded by a valid CRC.
+/// `FramingError::Incomplete` is returned until such a footer is received; callers should give up after a maximum frame size.
+pub fn decode_prefix(input: &[u8]) -> Result<(Unframed<'_>, &[u8]), FramingError> {
+    let kind = kind_of(input)?;
+    let footer = kind.footer();
+    let payload_start = kind.header().len();
+    for footer_start in (payload_start + CRC_LENGTH)..=input.len().saturating_sub(footer.len()) {
+        if &input[footer_start..footer_start + footer.len()] != footer {
+            continue;
+        }
+        let crc_start = footer_start - CRC_LENGTH;
+        let payload = &input[payload_start..crc_start];
+        if crc32fast::hash(payload) == read_crc(&input[crc_start..footer_start]) {
+            return Ok((
+                Unframed { kind, payload },
+                &input[footer_start + footer.len()..],
+            ));
+        }
+    }
+    Err(FramingError::Incomplete)
+}
+
+fn kind_of(input: &[u8]) -> Result<FrameKind, FramingError> {
+    FrameKind::of(input).ok_or_else(|| {
+        let partial_header = [FRAME_HEADER, CONTROL_FRAME_HEADER]
+            .iter()
+            .any(|header| input.len() < header.len() && header.starts_with(input));
+        if partial_header {
+            FramingError::Incomplete
+        } else {
+            FramingError::InvalidHeader
+        }
+    })
+}
+
+fn read_crc(bytes: &[u8]) -> u32 {
+    let mut crc = [0; CRC_LENGTH];
+    crc.copy_from_slice(bytes);
+    u32::from_be_bytes(crc)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlMessage, ControlSetting};
+
+    #[test]
+    #[cfg(feature = "runtime")]
+    fn decodes_serialized_messages() {
+        use crate::serializers::ToBytes;
+        use crate::simulator::{PatientPreset, SimulatedDevice};
+
+        let message = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .next()
+            .unwrap();
+        let frame = message.to_bytes();
+        let unframed = decode(&frame).unwrap();
+        assert_eq!(unframed.kind, FrameKind::Telemetry);
+        assert_eq!(encode(unframed.payload), frame);
+    }
+
+    #[test]
+    fn decodes_encoded_frames() {
+        let control = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        }
+        .to_control_frame();
+        let unframed = decode(&control).unwrap();
+        assert_eq!(unframed.kind, FrameKind::Control);
+        assert_eq!(unframed.payload, [3, 0, 80]);
+        assert_eq!(encode_with(FrameKind::Control, unframed.payload), control);
+    }
+
+    #[test]
+    fn decodes_frames_of_a_stream() {
+        // The footer bytes inside the payload must not end the frame
+        let payload = [1, 2, FRAME_FOOTER[0], FRAME_FOOTER[1], 3];
+        let mut stream = encode(&payload);
+        stream.extend_from_slice(&encode(b"next"));
+
+        let (unframed, rest) = decode_prefix(&stream).unwrap();
+        assert_eq!(unframed.payload, payload);
+        assert_eq!(decode(rest).unwrap().payload, b"next");
+        assert_eq!(
+            decode_prefix(&stream[..8]).err(),
+            Some(FramingError::Incomplete)
+        );
+        assert_eq!(decode(&stream[..1]).err(), Some(FramingError::Incomplete));
+        assert_eq!(decode(b"garbage").err(), Some(FramingError::InvalidHeader));
+
+        let mut corrupted = encode(&payload);
+        corrupted[3] ^= 0xFF;
+        assert!(matches!(
+            decode(&corrupted),
+            Err(FramingError::CrcError { .. })
+        ));
+    }
+}
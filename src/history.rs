@@ -0,0 +1,264 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::fs::{File, OpenOptions};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::parsers::parse_telemetry_message;
+use crate::structures::TelemetryMessage;
+
+/// Span of systick covered by each file of the cache
+pub const SEGMENT_DURATION: Duration = Duration::from_secs(10);
+
+#[derive(Debug)]
+struct Segment {
+    path: PathBuf,
+    first_systick: u64,
+    last_systick: u64,
+}
+
+/// Bounded on-disk cache of the latest telemetry messages of a device, so that live views can be paused and scrolled back
+///
+/// Messages are appended to files of `SEGMENT_DURATION` in a directory; the oldest files are deleted once they are older than the retention, and every file is deleted when the cache is dropped.
+/// The cache is cleared when the device reboots (its systick goes back).
+#[derive(Debug)]
+pub struct HistoryCache {
+    directory: PathBuf,
+    retention: u64,
+    segments: VecDeque<Segment>,
+    writer: Option<BufWriter<File>>,
+}
+
+impl HistoryCache {
+    /// Create a cache in a directory (created if needed), keeping messages for the given duration of systick
+    pub fn new<P: AsRef<Path>>(directory: P, retention: Duration) -> std::io::Result<Self> {
+        std::fs::create_dir_all(directory.as_ref())?;
+        Ok(Self {
+            directory: directory.as_ref().to_path_buf(),
+            retention: retention.as_micros() as u64,
+            segments: VecDeque::new(),
+            writer: None,
+        })
+    }
+
+    /// Add a message
+    pub fn push(&mut self, message: &TelemetryMessage) -> std::io::Result<()> {
+        let bytes = message
+            .to_bytes_for_version(message.telemetry_version())
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))?;
+        let systick = message.systick();
+        if self
+            .segments
+            .back()
+            .is_some_and(|segment| systick < segment.last_systick)
+        {
+            self.clear()?;
+        }
+
+        let segment_duration = SEGMENT_DURATION.as_micros() as u64;
+        let start_new_segment = self
+            .segments
+            .back()
+            .is_none_or(|segment| systick >= segment.first_systick + segment_duration);
+        if start_new_segment {
+            if let Some(mut writer) = self.writer.take() {
+                writer.flush()?;
+            }
+            let path = self.directory.join(format!("{:020}.frames", systick));
+            let file = OpenOptions::new()
+                .create(true)
+                .truncate(true)
+                .write(true)
+                .open(&path)?;
+            self.writer = Some(BufWriter::new(file));
+            self.segments.push_back(Segment {
+                path,
+                first_systick: systick,
+                last_systick: systick,
+            });
+        }
+
+        if let Some(writer) = self.writer.as_mut() {
+            writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+            writer.write_all(&bytes)?;
+        }
+        if let Some(segment) = self.segments.back_mut() {
+            segment.last_systick = systick;
+        }
+
+        while self.segments.len() > 1 && self.segments[1].first_systick + self.retention <= systick
+        {
+            if let Some(segment) = self.segments.pop_front() {
+                std::fs::remove_file(segment.path)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Systick of the oldest and latest cached messages, if any
+    pub fn bounds(&self) -> Option<(u64, u64)> {
+        match (self.segments.front(), self.segments.back()) {
+            (Some(first), Some(last)) => Some((first.first_systick, last.last_systick)),
+            _ => None,
+        }
+    }
+
+    /// Read back cached messages whose systick is within a range (inclusive)
+    pub fn range(&mut self, from: u64, to: u64) -> std::io::Result<Vec<TelemetryMessage>> {
+        if let Some(writer) = self.writer.as_mut() {
+            writer.flush()?;
+        }
+        let mut messages = Vec::new();
+        for segment in self
+            .segments
+            .iter()
+            .filter(|segment| segment.first_systick <= to && segment.last_systick >= from)
+        {
+            let mut reader = BufReader::new(File::open(&segment.path)?);
+            let mut length = [0; 4];
+            while reader.read_exact(&mut length).is_ok() {
+                let mut frame = vec![0; u32::from_be_bytes(length) as usize];
+                reader.read_exact(&mut frame)?;
+                if let Ok((_, message)) = parse_telemetry_message(&frame) {
+                    if (from..=to).contains(&message.systick()) {
+                        messages.push(message);
+                    }
+                }
+            }
+        }
+        Ok(messages)
+    }
+
+    /// Delete every cached message
+    pub fn clear(&mut self) -> std::io::Result<()> {
+        self.writer = None;
+        for segment in self.segments.drain(..) {
+            std::fs::remove_file(segment.path)?;
+        }
+        Ok(())
+    }
+}
+
+impl Drop for HistoryCache {
+    fn drop(&mut self) {
+        if let Err(e) = self.clear() {
+            log::warn!("failed deleting history cache: {:?}", e);
+        }
+    }
+}
+
+/// Position of a live view in the history: following the latest messages, or paused at some systick
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum Playhead {
+    /// Show the latest messages
+    #[default]
+    Live,
+    /// Show messages up to this systick
+    Paused(u64),
+}
+
+impl Playhead {
+    /// Stop following the latest messages, at the given systick
+    pub fn pause(&mut self, latest_systick: u64) {
+        if *self == Self::Live {
+            *self = Self::Paused(latest_systick);
+        }
+    }
+
+    /// Move back (negative offset) or forward in the history, pausing if needed, within the bounds of the cache
+    ///
+    /// Moving past the latest message resumes the live view.
+    pub fn scroll(&mut self, offset_us: i64, cache: &HistoryCache) {
+        let (oldest, latest) = match cache.bounds() {
+            Some(bounds) => bounds,
+            None => return,
+        };
+        let current = match *self {
+            Self::Live => latest,
+            Self::Paused(systick) => systick,
+        };
+        let target = current.saturating_add_signed(offset_us);
+        *self = if target >= latest && offset_us > 0 {
+            Self::Live
+        } else {
+            Self::Paused(target.clamp(oldest, latest))
+        };
+    }
+
+    /// Follow the latest messages again
+    pub fn resume(&mut self) {
+        *self = Self::Live;
+    }
+
+    /// Systick at the end of the view
+    pub fn end(&self, cache: &HistoryCache) -> Option<u64> {
+        match self {
+            Self::Live => cache.bounds().map(|(_, latest)| latest),
+            Self::Paused(systick) => Some(*systick),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::StoppedMessage;
+
+    fn stopped(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 2,
+            systick,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn keeps_recent_messages_on_disk() {
+        let directory = std::env::temp_dir().join("makair-telemetry-history-cache");
+        let mut cache = HistoryCache::new(&directory, Duration::from_secs(30)).unwrap();
+        for second in 0..=60 {
+            cache.push(&stopped(second * 1_000_000)).unwrap();
+        }
+
+        // Segments are only deleted once they are entirely out of the retention
+        assert_eq!(cache.bounds(), Some((30_000_000, 60_000_000)));
+        let messages = cache.range(45_000_000, 47_000_000).unwrap();
+        let systicks: Vec<u64> = messages.iter().map(TelemetryMessage::systick).collect();
+        assert_eq!(systicks, vec![45_000_000, 46_000_000, 47_000_000]);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 4);
+
+        cache.push(&stopped(1_000)).unwrap();
+        assert_eq!(cache.bounds(), Some((1_000, 1_000)));
+
+        drop(cache);
+        assert_eq!(std::fs::read_dir(&directory).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn scrolls_back_and_resumes() {
+        let directory = std::env::temp_dir().join("makair-telemetry-history-playhead");
+        let mut cache = HistoryCache::new(&directory, Duration::from_secs(60)).unwrap();
+        for second in 10..=20 {
+            cache.push(&stopped(second * 1_000_000)).unwrap();
+        }
+
+        let mut playhead = Playhead::default();
+        playhead.scroll(-5_000_000, &cache);
+        assert_eq!(playhead, Playhead::Paused(15_000_000));
+        playhead.scroll(-60_000_000, &cache);
+        assert_eq!(playhead.end(&cache), Some(10_000_000));
+        playhead.scroll(60_000_000, &cache);
+        assert_eq!(playhead, Playhead::Live);
+
+        playhead.pause(20_000_000);
+        cache.push(&stopped(21_000_000)).unwrap();
+        assert_eq!(playhead.end(&cache), Some(20_000_000));
+        playhead.resume();
+        assert_eq!(playhead.end(&cache), Some(21_000_000));
+    }
+}
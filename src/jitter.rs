@@ -0,0 +1,243 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::BTreeMap;
+use std::fmt;
+use std::time::Instant;
+
+use crate::structures::TelemetryMessage;
+
+/// Default width of histogram buckets, in microseconds
+pub const DEFAULT_BUCKET_WIDTH: u64 = 1_000;
+
+/// Width of the longest bar when a histogram is displayed
+const BAR_WIDTH: u64 = 40;
+
+/// Histogram of intervals, in microseconds
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct IntervalHistogram {
+    bucket_width: u64,
+    buckets: BTreeMap<u64, u64>,
+    count: u64,
+    sum: u64,
+    min: Option<u64>,
+    max: Option<u64>,
+}
+
+impl Default for IntervalHistogram {
+    fn default() -> Self {
+        Self::new(DEFAULT_BUCKET_WIDTH)
+    }
+}
+
+impl IntervalHistogram {
+    /// Create an empty histogram with buckets of `bucket_width` microseconds
+    pub fn new(bucket_width: u64) -> Self {
+        Self {
+            bucket_width: bucket_width.max(1),
+            buckets: BTreeMap::new(),
+            count: 0,
+            sum: 0,
+            min: None,
+            max: None,
+        }
+    }
+
+    /// Add an interval in microseconds
+    pub fn push(&mut self, interval: u64) {
+        let bucket = interval / self.bucket_width * self.bucket_width;
+        *self.buckets.entry(bucket).or_insert(0) += 1;
+        self.count += 1;
+        self.sum = self.sum.saturating_add(interval);
+        self.min = Some(self.min.map_or(interval, |min| min.min(interval)));
+        self.max = Some(self.max.map_or(interval, |max| max.max(interval)));
+    }
+
+    /// Number of intervals
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    /// Mean interval in microseconds
+    pub fn mean(&self) -> Option<f64> {
+        (self.count > 0).then(|| self.sum as f64 / self.count as f64)
+    }
+
+    /// Shortest interval in microseconds
+    pub fn min(&self) -> Option<u64> {
+        self.min
+    }
+
+    /// Longest interval in microseconds
+    pub fn max(&self) -> Option<u64> {
+        self.max
+    }
+
+    /// Upper bound (in microseconds) of the bucket containing the given percentile (between 0 and 100)
+    pub fn percentile(&self, percentile: f64) -> Option<u64> {
+        if self.count == 0 {
+            return None;
+        }
+        let rank =
+            ((percentile.clamp(0.0, 100.0) / 100.0 * self.count as f64).ceil() as u64).max(1);
+        let mut seen = 0;
+        self.buckets.iter().find_map(|(start, count)| {
+            seen += count;
+            (seen >= rank).then(|| start + self.bucket_width)
+        })
+    }
+
+    /// Non-empty buckets, as pairs of lower bound (in microseconds) and number of intervals
+    pub fn buckets(&self) -> impl Iterator<Item = (u64, u64)> + '_ {
+        self.buckets.iter().map(|(start, count)| (*start, *count))
+    }
+}
+
+impl fmt::Display for IntervalHistogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (mean, min, max) = match (self.mean(), self.min, self.max) {
+            (Some(mean), Some(min), Some(max)) => (mean, min, max),
+            _ => return writeln!(f, "no intervals"),
+        };
+        writeln!(
+            f,
+            "{} intervals: mean={:.0} µs, min={} µs, max={} µs, p50≤{} µs, p99≤{} µs",
+            self.count,
+            mean,
+            min,
+            max,
+            self.percentile(50.0).unwrap_or_default(),
+            self.percentile(99.0).unwrap_or_default()
+        )?;
+        let highest = self.buckets.values().copied().max().unwrap_or(1);
+        for (start, count) in self.buckets() {
+            let bar = (count * BAR_WIDTH).div_ceil(highest) as usize;
+            writeln!(
+                f,
+                "{:>7}-{:<7} µs {:>8} {}",
+                start,
+                start + self.bucket_width,
+                count,
+                "#".repeat(bar)
+            )?;
+        }
+        Ok(())
+    }
+}
+
+/// Measures intervals between consecutive data snapshots, by systick and by host receive time
+///
+/// Comparing both shows the jitter introduced by the serial link and host threads, as opposed to the one of the firmware.
+/// Intervals are not measured across boots and stops (they would only show the pause).
+#[derive(Debug, Clone, Default)]
+pub struct JitterAnalyzer {
+    /// Intervals between systicks of data snapshots
+    pub systick: IntervalHistogram,
+    /// Intervals between host receive times of data snapshots
+    pub host: IntervalHistogram,
+    last_systick: Option<u64>,
+    last_received: Option<Instant>,
+}
+
+impl JitterAnalyzer {
+    /// Create an analyzer with buckets of `bucket_width` microseconds
+    pub fn new(bucket_width: u64) -> Self {
+        Self {
+            systick: IntervalHistogram::new(bucket_width),
+            host: IntervalHistogram::new(bucket_width),
+            last_systick: None,
+            last_received: None,
+        }
+    }
+
+    /// Handle a message, along with the host time at which it was received if it is known (e.g. not for recordings)
+    pub fn push(&mut self, message: &TelemetryMessage, received_at: Option<Instant>) {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                if let Some(last) = self.last_systick {
+                    if snapshot.systick >= last {
+                        self.systick.push(snapshot.systick - last);
+                    }
+                }
+                self.last_systick = Some(snapshot.systick);
+
+                if let (Some(last), Some(received_at)) = (self.last_received, received_at) {
+                    self.host
+                        .push(received_at.saturating_duration_since(last).as_micros() as u64);
+                }
+                self.last_received = received_at;
+            }
+            TelemetryMessage::BootMessage(_) | TelemetryMessage::StoppedMessage(_) => {
+                self.last_systick = None;
+                self.last_received = None;
+            }
+            _ => (),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{DataSnapshot, Phase, StoppedMessage};
+    use std::time::Duration;
+
+    fn snapshot(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick,
+            centile: 0,
+            pressure: 0,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow: None,
+            expiratory_flow: None,
+        })
+    }
+
+    #[test]
+    fn histogram_statistics() {
+        let mut histogram = IntervalHistogram::new(1_000);
+        for interval in [9_500, 10_000, 10_200, 10_400, 25_000] {
+            histogram.push(interval);
+        }
+        assert_eq!(histogram.count(), 5);
+        assert_eq!(histogram.mean(), Some(13_020.0));
+        assert_eq!(histogram.min(), Some(9_500));
+        assert_eq!(histogram.max(), Some(25_000));
+        assert_eq!(histogram.percentile(50.0), Some(11_000));
+        assert_eq!(histogram.percentile(100.0), Some(26_000));
+        assert_eq!(
+            histogram.buckets().collect::<Vec<_>>(),
+            vec![(9_000, 1), (10_000, 3), (25_000, 1)]
+        );
+        assert_eq!(IntervalHistogram::default().percentile(50.0), None);
+    }
+
+    #[test]
+    fn measures_systick_and_host_intervals() {
+        let start = Instant::now();
+        let mut analyzer = JitterAnalyzer::default();
+        analyzer.push(&snapshot(0), Some(start));
+        analyzer.push(&snapshot(10_000), Some(start + Duration::from_millis(12)));
+        analyzer.push(
+            &TelemetryMessage::StoppedMessage(StoppedMessage::default()),
+            None,
+        );
+        analyzer.push(&snapshot(5_000_000), Some(start + Duration::from_secs(5)));
+        analyzer.push(&snapshot(5_010_000), None);
+
+        assert_eq!(analyzer.systick.count(), 2);
+        assert_eq!(analyzer.systick.max(), Some(10_000));
+        assert_eq!(analyzer.host.count(), 1);
+        assert_eq!(analyzer.host.max(), Some(12_000));
+    }
+}
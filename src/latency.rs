@@ -0,0 +1,287 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fmt;
+use std::time::{Duration, Instant};
+
+use crate::adapter::MessageAdapter;
+use crate::parsers::parse_telemetry_message;
+use crate::sink::TelemetrySink;
+use crate::source::{SourceInfo, SourceKind};
+use crate::structures::TelemetryMessage;
+use crate::TimedMessage;
+
+/// Name of the stage in which frames are parsed
+pub const PARSER_STAGE: &str = "parser";
+
+/// Name of the whole pipeline in reports
+pub const TOTAL_STAGE: &str = "total";
+
+/// Latencies measured for one stage of the pipeline
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StageLatency {
+    /// Name of the stage (`PARSER_STAGE`, the name of an adapter or sink, or `TOTAL_STAGE`)
+    pub name: String,
+    /// Number of measured messages
+    pub count: usize,
+    /// Mean latency
+    pub mean: Duration,
+    /// Median latency
+    pub p50: Duration,
+    /// 99th percentile of latencies
+    pub p99: Duration,
+    /// Highest latency
+    pub max: Duration,
+}
+
+impl StageLatency {
+    fn from_samples(name: &str, mut samples: Vec<Duration>) -> Self {
+        samples.sort_unstable();
+        let percentile = |p: usize| {
+            if samples.is_empty() {
+                Duration::ZERO
+            } else {
+                samples[((samples.len() * p).div_ceil(100)).clamp(1, samples.len()) - 1]
+            }
+        };
+        Self {
+            name: name.to_owned(),
+            count: samples.len(),
+            mean: if samples.is_empty() {
+                Duration::ZERO
+            } else {
+                samples.iter().sum::<Duration>() / samples.len() as u32
+            },
+            p50: percentile(50),
+            p99: percentile(99),
+            max: samples.last().copied().unwrap_or_default(),
+        }
+    }
+}
+
+/// Latencies of every stage of the pipeline, as measured by `LatencyHarness::run()`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct LatencyReport {
+    /// Parser, then adapters and sinks in the order they were added
+    pub stages: Vec<StageLatency>,
+    /// Whole pipeline, from the start of parsing to the end of the last sink
+    pub total: StageLatency,
+}
+
+impl LatencyReport {
+    /// Whether every message went through the whole pipeline within the budget
+    pub fn within_budget(&self, budget: Duration) -> bool {
+        self.total.max <= budget
+    }
+}
+
+impl fmt::Display for LatencyReport {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "{:<24} {:>8} {:>12} {:>12} {:>12} {:>12}",
+            "stage", "messages", "mean (µs)", "p50 (µs)", "p99 (µs)", "max (µs)"
+        )?;
+        for stage in self.stages.iter().chain(std::iter::once(&self.total)) {
+            writeln!(
+                f,
+                "{:<24} {:>8} {:>12.1} {:>12.1} {:>12.1} {:>12.1}",
+                stage.name,
+                stage.count,
+                stage.mean.as_secs_f64() * 1e6,
+                stage.p50.as_secs_f64() * 1e6,
+                stage.p99.as_secs_f64() * 1e6,
+                stage.max.as_secs_f64() * 1e6
+            )?;
+        }
+        Ok(())
+    }
+}
+
+type ErasedAdapter = Box<dyn FnMut(&TelemetryMessage)>;
+
+/// Measures how long each stage of the pipeline (parser → adapters → sinks) takes for every message
+///
+/// Messages are played as a simulated device would send them: each one is serialized to a frame, which is then parsed, handed to every adapter and consumed by every sink, one after the other.
+/// Serializing frames is not measured.
+#[derive(Default)]
+pub struct LatencyHarness {
+    adapters: Vec<(String, ErasedAdapter)>,
+    sinks: Vec<(String, Box<dyn TelemetrySink>)>,
+}
+
+impl LatencyHarness {
+    /// Create a harness with only the parser stage
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add an adapter stage; its outputs are dropped
+    pub fn adapter<A: MessageAdapter + 'static>(mut self, name: &str, mut adapter: A) -> Self {
+        self.adapters.push((
+            name.to_owned(),
+            Box::new(move |message| drop(adapter.handle(message))),
+        ));
+        self
+    }
+
+    /// Add a sink stage
+    pub fn sink<S: TelemetrySink + 'static>(mut self, name: &str, sink: S) -> Self {
+        self.sinks.push((name.to_owned(), Box::new(sink)));
+        self
+    }
+
+    /// Play messages through the pipeline and report latencies
+    ///
+    /// Messages that cannot be parsed back are skipped.
+    pub fn run<'a, I>(&mut self, messages: I) -> LatencyReport
+    where
+        I: IntoIterator<Item = &'a TelemetryMessage>,
+    {
+        let source = SourceInfo::new(SourceKind::Bytes, Some("latency harness".to_owned()));
+        let mut parser = Vec::new();
+        let mut adapters = vec![Vec::new(); self.adapters.len()];
+        let mut sinks = vec![Vec::new(); self.sinks.len()];
+        let mut total = Vec::new();
+
+        for message in messages {
+            let frame = match message.to_bytes_for_version(message.telemetry_version()) {
+                Ok(frame) => frame,
+                Err(_) => continue,
+            };
+
+            let start = Instant::now();
+            let parsed = match parse_telemetry_message(&frame) {
+                Ok((_, parsed)) => parsed,
+                Err(_) => continue,
+            };
+            parser.push(start.elapsed());
+
+            for ((_, adapter), samples) in self.adapters.iter_mut().zip(adapters.iter_mut()) {
+                let stage_start = Instant::now();
+                adapter(&parsed);
+                samples.push(stage_start.elapsed());
+            }
+
+            let timed_message = TimedMessage::now(Ok(parsed), source.clone());
+            for ((_, sink), samples) in self.sinks.iter_mut().zip(sinks.iter_mut()) {
+                let stage_start = Instant::now();
+                sink.consume(&timed_message);
+                samples.push(stage_start.elapsed());
+            }
+
+            total.push(start.elapsed());
+        }
+        for (_, sink) in self.sinks.iter_mut() {
+            sink.flush();
+        }
+
+        let mut stages = vec![StageLatency::from_samples(PARSER_STAGE, parser)];
+        stages.extend(
+            self.adapters
+                .iter()
+                .zip(adapters)
+                .map(|((name, _), samples)| StageLatency::from_samples(name, samples)),
+        );
+        stages.extend(
+            self.sinks
+                .iter()
+                .zip(sinks)
+                .map(|((name, _), samples)| StageLatency::from_samples(name, samples)),
+        );
+        LatencyReport {
+            stages,
+            total: StageLatency::from_samples(TOTAL_STAGE, total),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::StoppedMessage;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+
+    struct CountingSink(Arc<AtomicUsize>);
+
+    impl TelemetrySink for CountingSink {
+        fn consume(&mut self, _message: &TimedMessage) {
+            self.0.fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    #[test]
+    fn measures_every_stage() {
+        let messages = vec![
+            TelemetryMessage::StoppedMessage(StoppedMessage {
+                telemetry_version: 2,
+                ..Default::default()
+            });
+            10
+        ];
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let mut harness = LatencyHarness::new()
+            .adapter("slow", |_: &TelemetryMessage| {
+                std::thread::sleep(Duration::from_millis(1));
+                Vec::<()>::new()
+            })
+            .sink("count", CountingSink(Arc::clone(&consumed)));
+        let report = harness.run(&messages);
+
+        assert_eq!(consumed.load(Ordering::Relaxed), 10);
+        let names: Vec<&str> = report.stages.iter().map(|s| s.name.as_str()).collect();
+        assert_eq!(names, vec![PARSER_STAGE, "slow", "count"]);
+        assert!(report.stages.iter().all(|stage| stage.count == 10));
+        assert!(report.stages[1].p50 >= Duration::from_millis(1));
+        assert!(report.total.max >= report.stages[1].max);
+        assert!(!report.within_budget(Duration::from_micros(500)));
+        assert!(report.to_string().contains("slow"));
+    }
+
+    #[test]
+    fn computes_percentiles() {
+        let samples: Vec<Duration> = (1..=100).rev().map(Duration::from_millis).collect();
+        let stage = StageLatency::from_samples("stage", samples);
+        assert_eq!(stage.count, 100);
+        assert_eq!(stage.mean, Duration::from_micros(50_500));
+        assert_eq!(stage.p50, Duration::from_millis(50));
+        assert_eq!(stage.p99, Duration::from_millis(99));
+        assert_eq!(stage.max, Duration::from_millis(100));
+
+        let stage = StageLatency::from_samples("stage", vec![Duration::from_millis(3)]);
+        assert_eq!(stage.p50, Duration::from_millis(3));
+        assert_eq!(stage.p99, Duration::from_millis(3));
+    }
+
+    #[test]
+    fn reports_empty_and_unparsable_inputs() {
+        let consumed = Arc::new(AtomicUsize::new(0));
+        let mut harness = LatencyHarness::new().sink("count", CountingSink(Arc::clone(&consumed)));
+
+        let report = harness.run(&[]);
+        assert!(report
+            .stages
+            .iter()
+            .chain(std::iter::once(&report.total))
+            .all(|stage| stage.count == 0
+                && stage.mean == Duration::ZERO
+                && stage.max == Duration::ZERO));
+        assert!(report.within_budget(Duration::ZERO));
+
+        // Messages of unknown types are rejected by the default parser
+        let unknown = TelemetryMessage::Unknown {
+            telemetry_version: 2,
+            version: "v2".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 0,
+            type_byte: b'Z',
+            payload: vec![],
+        };
+        let report = harness.run(&[unknown]);
+        assert_eq!(report.total.count, 0);
+        assert_eq!(consumed.load(Ordering::Relaxed), 0);
+    }
+}
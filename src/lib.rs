@@ -12,20 +12,300 @@
 // Enable documentation of features
 #![cfg_attr(doc_cfg, feature(doc_cfg))]
 
+/// Adapters turning telemetry messages into higher-level outputs
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod adapter;
+/// Per-hour statistics across devices, exported only for hours shared by enough devices
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod aggregate;
 /// Utilities related to alarms
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
 pub mod alarm;
+/// Conversion of telemetry messages to polars DataFrames for analysis
+#[cfg(feature = "analytics")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "analytics")))]
+pub mod analytics;
+/// Operator annotations stored in recordings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod annotation;
+/// Pluggable detection of unusual breathing cycles
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod anomaly;
+/// Arbitration between several controllers sending control messages to the same MCU
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod arbitration;
+/// Tokio-based async equivalents of the `gather_telemetry` functions
+#[cfg(feature = "async")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "async")))]
+pub mod asynchronous;
+/// Tamper-evident log of control messages sent to the MCU
+#[cfg(feature = "audit")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "audit")))]
+pub mod audit;
+/// Breakdown of serial traffic by message type and overhead, projected against the capacity of the UART
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod bandwidth;
+/// Export of waveforms and events to standard biosignal formats (EDF+, WFDB)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod biosignal;
+/// Telemetry from Bluetooth serial bridges (RFCOMM)
+#[cfg(feature = "bluetooth")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "bluetooth")))]
+pub mod bluetooth;
+/// Estimation of respiratory rate and I:E ratio from pressure and flow waveforms
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod breathing;
+/// Generation of a C header with frame constants, shared with the firmware's unit tests
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod c_header;
+/// In-memory capture of the last raw frames, for post-mortem analysis
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod capture;
+/// Cycle-aligned comparison of the metrics of two devices (splitter bench tests, A/B firmware tests)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod compare;
 /// Structures to represent control messages
 pub mod control;
+/// Requests for fewer data snapshots, with detection of firmware support
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod decimation;
+/// Streaming decoder of telemetry frames, independent of any I/O
+pub mod decoder;
+/// Non-fatal problems found while decoding telemetry messages (e.g. unknown locales)
+pub mod diagnostics;
+/// End of line test sessions (per-step results, measurements and operator confirmations) gathered from telemetry
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod eol;
 /// Error-related entities
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
 pub mod error;
+/// Unified stream of telemetry events (messages, alarm changes, link state, device events, anomalies and diagnostics)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod event;
+/// All-in-one API to read, record and react to telemetry without wiring channels and threads
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod facade;
+/// Delivery of messages to many clients, each with its own bounded queue, so that a slow client cannot stall the others
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod fanout;
+/// Injection of device faults into telemetry streams
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod fault;
+/// Selection of telemetry messages based on their type
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod filter;
+/// Ways to display telemetry messages for humans
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod formatter;
+/// Wrapping of payloads into telemetry and control frames (header, CRC and footer), and the other way around
+pub mod framing;
+/// Bounded on-disk history of recent telemetry, to pause and scroll back live views
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod history;
+#[cfg(any(feature = "mes", feature = "s3", feature = "upload"))]
+mod http;
+/// Histograms of intervals between data snapshots, to quantify timing jitter
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod jitter;
+/// Measurement of per-stage latencies through the telemetry pipeline (parser, adapters, sinks)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod latency;
+/// Health of the links to sources of telemetry (connecting, connected, degraded, reconnecting, failed), reported by transports
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod link;
+/// Protocol compliance checks of captured firmware output (framing, CRC, field ranges, ordering, cadence)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod lint;
 /// Tools to manipulate ISO 639-1 language codes to be used in the control protocol
 pub mod locale;
+/// Provenance manifests (source recording hash, crate version, conversion parameters) embedded into exports
+#[cfg(feature = "manifest")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "manifest")))]
+pub mod manifest;
+/// Time-aligned merge of telemetry with CSV files of external sensors (e.g. reference flow analyzers)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod merge;
+/// Export of end of line test sessions to the REST endpoint of a manufacturing execution system (MES)
+#[cfg(feature = "mes")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "mes")))]
+pub mod mes;
+/// Mirroring of recordings to two destinations with independent failure handling
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod mirror;
+/// Publishing of telemetry to MQTT brokers, with control messages received on a topic
+#[cfg(feature = "mqtt")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "mqtt")))]
+pub mod mqtt;
+/// Classification of breathing cycle waveforms with ONNX models
+#[cfg(feature = "onnx")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "onnx")))]
+pub mod onnx;
+/// Quick-look overviews of recordings (message counts, duration, alarms, pressure range), cached in sidecar files
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod overview;
 /// Underlying parsers for telemetry messages
 pub mod parsers;
+/// Rendering of waveforms (pressure, flow, volume) to images
+#[cfg(feature = "plot")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "plot")))]
+pub mod plot;
+/// Platform-aware naming and discovery of serial ports
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub mod ports;
+/// Short listening of a device to report its protocol version, firmware, cadence and compliance warnings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod probe;
+/// Progress reporting for long-running operations on recordings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod progress;
+/// Telemetry metrics (message counters, alarms, CRC errors) in the Prometheus text format
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod prometheus;
+/// Selection of slices of recordings (systick and cycle ranges, message types, alarms)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod query;
+/// Rate limiting of control messages, to avoid overrunning the UART of the MCU
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod rate_limit;
+/// Lazy reading of recordings as iterators of messages, without threads nor channels
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod reader;
+/// Suggestion of alarm thresholds around the observed ventilation
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod recommendation;
+/// Reading and writing telemetry recordings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod recording;
+/// Protection of control frames against replays
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod replay;
+/// Standalone HTML reports summarizing recorded sessions
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod report;
+/// Retention rules (age, total size, annotations) applied to directories of recordings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod retention;
+/// Rolling statistics (mean, min, max, EWMA) over windows of time, cycles or samples
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod rolling;
+/// Recording straight to S3-compatible object storage (multipart upload)
+#[cfg(feature = "s3")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "s3")))]
+pub mod s3;
+/// Detection of patient session boundaries, to split recordings by session
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod segmentation;
+/// Configuration of serial ports (timeouts, modem control lines, break detection)
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub mod serial_config;
 /// Binary representation of telemtry messages
 pub mod serializers;
+/// Helpers to follow the outcome of control messages sent to the MCU
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod session;
+/// Simulated devices ventilating patient models
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod simulator;
+/// Consumers of telemetry messages (recording, display, WebSocket fan-out, etc.)
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod sink;
+/// Passthrough between the MCU and a control UI, to observe a running unit
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub mod sniffer;
+/// Information about where telemetry messages come from
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod source;
+/// Reproducible random control frames and bytes to stress the firmware
+#[cfg(feature = "rand")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "rand")))]
+pub mod storm;
 /// Structures to represent telemetry messages
 pub mod structures;
+/// Compact per-cycle summaries (settings, measures, alarms and derived metrics), e.g. for dashboards
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod summary;
+/// Helpers to test adapters and state machines against recordings
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod testing;
+/// Helpers to synchronize the host clock with the MCU clock
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod time_sync;
+/// Step-function timeline of every commanded setting (from snapshots and ACKs), exported as CSV or JSON
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod timeline;
+/// Conversions between the units used by the firmware (mmH2O, cL/min) and other common units
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod units;
+/// Resumable, checksum-verified upload of completed recordings
+#[cfg(feature = "upload")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "upload")))]
+pub mod upload;
+/// Per-breath volumes integrated from flows, with drift correction
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod volume;
+/// Heartbeats for the RPi watchdog coupled with systemd watchdog notifications, paused when telemetry stalls
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub mod watchdog;
+/// WebSocket client and server configuration, with permessage-deflate compression
+#[cfg(feature = "websocket")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+pub mod websocket;
 
 #[cfg(feature = "serial")]
 #[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
@@ -35,72 +315,321 @@ pub use serial;
 /// Re-export Url lib
 pub use url;
 
+#[cfg(feature = "runtime")]
 use log::{debug, error, info, warn};
 #[cfg(feature = "serial")]
 use serial::prelude::*;
+#[cfg(feature = "runtime")]
 use std::fs::File;
-use std::io::{BufRead, BufReader};
-#[cfg(feature = "serial")]
-use std::io::{BufWriter, Read, Write};
+#[cfg(any(feature = "serial", feature = "websocket"))]
+use std::io::BufWriter;
+#[cfg(feature = "runtime")]
+use std::io::{Read, Write};
+#[cfg(feature = "runtime")]
+use std::sync::atomic::{AtomicBool, Ordering};
+#[cfg(feature = "runtime")]
 use std::sync::mpsc::Receiver;
-use std::sync::mpsc::Sender;
+#[cfg(feature = "runtime")]
+use std::sync::mpsc::{SendError, Sender};
+#[cfg(feature = "runtime")]
+use std::sync::Arc;
 #[cfg(feature = "serial")]
-use std::sync::{Arc, Mutex};
-use std::time::Duration;
+use std::sync::Mutex;
+#[cfg(feature = "runtime")]
+use std::time::{Duration, Instant, SystemTime};
 #[cfg(feature = "websocket")]
 use url::Url;
 
+#[cfg(feature = "runtime")]
+use capture::{FrameCapture, FrameOutcome};
+#[cfg(feature = "runtime")]
 use control::*;
-use parsers::*;
+#[cfg(feature = "runtime")]
+use decoder::{DecodeConfig, DecodedFrame, TelemetryDecoder};
+#[cfg(feature = "runtime")]
+use diagnostics::DecodeDiagnostic;
+#[cfg(feature = "runtime")]
+use formatter::{LogFormatter, MessageFormatter};
+#[cfg(feature = "runtime")]
+use progress::{NoProgress, Progress, ProgressCallback};
+#[cfg(feature = "runtime")]
+use recording::RecordingWriter;
+#[cfg(feature = "runtime")]
+use replay::ControlFrameEncoder;
+#[cfg(feature = "runtime")]
+use source::{SourceInfo, SourceKind, TelemetrySource};
+#[cfg(feature = "runtime")]
 use structures::*;
 
+#[cfg(feature = "runtime")]
 use error::Error;
 
 /// A decoded telemetry message
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
 pub type TelemetryChannelType = Result<TelemetryMessage, Error>;
 
+/// A decoded telemetry message along with the host time at which it was received and its source
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+#[derive(Debug)]
+pub struct TimedMessage {
+    /// The decoded telemetry message (or an error)
+    pub message: TelemetryChannelType,
+    /// Host wall-clock time at which the message was received
+    pub received_at: SystemTime,
+    /// Host monotonic time at which the message was received
+    pub received_instant: Instant,
+    /// Where the message comes from
+    pub source: SourceInfo,
+}
+
+#[cfg(feature = "runtime")]
+impl TimedMessage {
+    /// Wrap a message that was just received
+    pub fn now(message: TelemetryChannelType, source: SourceInfo) -> Self {
+        Self {
+            message,
+            received_at: SystemTime::now(),
+            received_instant: Instant::now(),
+            source,
+        }
+    }
+}
+
+#[cfg(feature = "runtime")]
+impl From<TimedMessage> for TelemetryChannelType {
+    fn from(timed_message: TimedMessage) -> Self {
+        timed_message.message
+    }
+}
+
+/// Sender that timestamps and tags messages before sending them in a channel of `TimedMessage` or `TelemetryChannelType`
+#[cfg(feature = "runtime")]
+struct TimedSender<T> {
+    tx: Sender<T>,
+    source: SourceInfo,
+    frame_capture: Option<FrameCapture>,
+    stop: Option<Arc<AtomicBool>>,
+}
+
+#[cfg(feature = "runtime")]
+impl<T: From<TimedMessage>> TimedSender<T> {
+    fn new(tx: Sender<T>, kind: SourceKind, identifier: Option<String>) -> Self {
+        Self {
+            tx,
+            source: SourceInfo::new(kind, identifier),
+            frame_capture: None,
+            stop: None,
+        }
+    }
+
+    /// Keep the frames that go through this sender in a capture
+    fn frame_capture(mut self, capture: Option<FrameCapture>) -> Self {
+        self.frame_capture = capture;
+        self
+    }
+
+    /// Let the transport know it should return once this flag is set
+    fn stop_signal(mut self, stop: Option<Arc<AtomicBool>>) -> Self {
+        self.stop = stop;
+        self
+    }
+
+    /// Whether the transport was asked to return
+    fn is_stopped(&self) -> bool {
+        self.stop
+            .as_ref()
+            .is_some_and(|stop| stop.load(Ordering::Acquire))
+    }
+
+    /// Send a message, followed by a `LinkMisconfigured` error if it is a boot message showing that bytes are not read as expected
+    fn send(&self, message: TelemetryChannelType) -> Result<(), SendError<T>> {
+        let link_error = match &message {
+            Ok(TelemetryMessage::BootMessage(boot_message)) => boot_message.check_link().err(),
+            _ => None,
+        };
+        self.tx
+            .send(TimedMessage::now(message, self.source.clone()).into())?;
+        if let Some(e) = link_error {
+            self.tx
+                .send(TimedMessage::now(Err(e.into()), self.source.clone()).into())?;
+        }
+        Ok(())
+    }
+
+    fn capture(&self, frame: &[u8], outcome: FrameOutcome) {
+        if let Some(capture) = self.frame_capture.as_ref() {
+            capture.push(frame, &self.source, outcome);
+        }
+        if let Ok((_, version)) = parsers::protocol_version::<()>(frame) {
+            link::report_protocol_version(&self.source, version);
+        }
+        link::report_frame(&self.source, outcome);
+    }
+
+    fn connecting(&self) {
+        link::report_connecting(&self.source);
+    }
+
+    fn connected(&self) {
+        link::report_connected(&self.source);
+    }
+
+    /// Report a failure of the link, and the time after which the transport will try again, if it will
+    fn failed<E: std::fmt::Display>(&self, error: E, retry_in: Option<Duration>) {
+        link::report_failed(&self.source, error, retry_in);
+    }
+
+    /// Log that messages are not received anymore, before the transport returns
+    fn receiver_dropped(&self) {
+        info!(
+            "stopped reading {}: the receiver of messages was dropped",
+            &self.source
+        );
+    }
+
+    /// Log that the transport was asked to return, before it does
+    fn stopped(&self) {
+        info!("stopped reading {}", &self.source);
+    }
+}
+
+#[cfg(feature = "runtime")]
+const PROGRESS_REPORT_PERIOD: Duration = Duration::from_millis(100);
+#[cfg(feature = "runtime")]
+const FILE_CHUNK_SIZE: usize = 4096;
+
 /// Open a serial port, consume it endlessly and send parsed telemetry messages through a channel
 ///
 /// * `port_id` - Name or path to the serial port.
-/// * `tx` - Sender of a channel.
-/// * `file_buf` - Optional file buffer; if specified, messages will also be serialized and written in this file.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `file_buf` - Optional file buffer; if specified, messages will also be serialized and written in this file, and flushed after each message.
 /// * `control_rx` - Optional receiver of a channel used to send control messages through the serial port.
 ///
+/// Use `gather_telemetry_with_config()` to record with another `recording::FlushPolicy`.
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
 /// This is meant to be run in a dedicated thread.
 #[cfg(feature = "serial")]
 #[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
-pub fn gather_telemetry(
+pub fn gather_telemetry<T: From<TimedMessage>>(
     port_id: &str,
-    tx: Sender<TelemetryChannelType>,
-    mut file_buf: Option<BufWriter<File>>,
+    tx: Sender<T>,
+    file_buf: Option<BufWriter<File>>,
     control_rx: Option<Receiver<ControlMessage>>,
-) -> ! {
+) {
+    gather_telemetry_with_config(
+        port_id,
+        tx,
+        file_buf.map(RecordingWriter::from),
+        control_rx,
+        &serial_config::SerialConfig::default(),
+        None,
+    )
+}
+
+/// Same as `gather_telemetry()`, with a custom configuration of the serial port
+///
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `config` - Timeouts, modem control lines and break detection (see `serial_config::SerialConfig`).
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub fn gather_telemetry_with_config<T: From<TimedMessage>>(
+    port_id: &str,
+    tx: Sender<T>,
+    recorder: Option<RecordingWriter>,
+    control_rx: Option<Receiver<ControlMessage>>,
+    config: &serial_config::SerialConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    gather_telemetry_until(
+        port_id,
+        tx,
+        recorder,
+        control_rx,
+        config,
+        diagnostics_tx,
+        None,
+    )
+}
+
+/// Same as `gather_telemetry_with_config()`, but also return once `stop` is set
+///
+/// The flag is checked after every read, so the port is closed within its timeout; control messages that are still waiting are sent first (e.g. the heartbeat disabling the RPi watchdog).
+#[cfg(feature = "serial")]
+pub(crate) fn gather_telemetry_until<T: From<TimedMessage>>(
+    port_id: &str,
+    tx: Sender<T>,
+    mut recorder: Option<RecordingWriter>,
+    control_rx: Option<Receiver<ControlMessage>>,
+    config: &serial_config::SerialConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+    stop: Option<Arc<AtomicBool>>,
+) {
+    let port_id = ports::canonical_port_name(port_id);
+    let tx = TimedSender::new(tx, SourceKind::Serial, Some(port_id.clone()))
+        .frame_capture(config.decode.frame_capture.clone())
+        .stop_signal(stop);
+    // Kept across reconnections, as the firmware only advertises its session when it boots
+    let mut control_encoder = ControlFrameEncoder::default();
     loop {
+        if tx.is_stopped() {
+            tx.stopped();
+            return;
+        }
         info!("opening {}", &port_id);
+        tx.connecting();
         match serial::open(&port_id) {
             Err(e) => {
                 error!("{:?}", e);
-                tx.send(Err(e.into()))
-                    .expect("[tx channel] failed to send error");
-                std::thread::sleep(std::time::Duration::from_secs(1));
+                tx.failed(&e, Some(config.reconnect_delay));
+                if tx.send(Err(e.into())).is_err() {
+                    tx.receiver_dropped();
+                    return;
+                }
+                std::thread::sleep(config.reconnect_delay);
             }
             Ok(mut port) => {
-                match port.reconfigure(&|settings| {
-                    settings.set_char_size(serial::Bits8);
-                    settings.set_parity(serial::ParityNone);
-                    settings.set_stop_bits(serial::Stop1);
-                    settings.set_flow_control(serial::FlowNone);
-                    settings.set_baud_rate(serial::Baud115200)
-                }) {
+                match port
+                    .reconfigure(&|settings| {
+                        settings.set_char_size(serial::Bits8);
+                        settings.set_parity(serial::ParityNone);
+                        settings.set_stop_bits(serial::Stop1);
+                        settings.set_flow_control(serial::FlowNone);
+                        settings.set_baud_rate(serial::Baud115200)
+                    })
+                    .and_then(|_| config.apply(&mut port))
+                {
                     Err(e) => {
                         error!("{}", e);
-                        tx.send(Err(e.into()))
-                            .expect("[tx channel] failed setting up port");
-                        std::thread::sleep(std::time::Duration::from_secs(1));
+                        tx.failed(&e, Some(config.reconnect_delay));
+                        if tx.send(Err(e.into())).is_err() {
+                            tx.receiver_dropped();
+                            return;
+                        }
+                        std::thread::sleep(config.reconnect_delay);
                     }
                     Ok(_) => {
+                        tx.connected();
                         let port_handle = Arc::new(Mutex::new(port));
-                        let mut buffer = Vec::new();
+                        let mut decoder = TelemetryDecoder::with_config(config.decode.parser)
+                            .diagnostics(diagnostics_tx.clone());
+                        let mut break_detector = config.break_detector();
+                        let mut rate_limiter = config
+                            .control_rate_limit
+                            .map(rate_limit::ControlRateLimiter::new);
+                        let write_control =
+                            |message: &ControlMessage, encoder: &mut ControlFrameEncoder| {
+                                let write = port_handle
+                                .lock()
+                                .expect("[port] failed getting exclusive lock on serial port to write control message")
+                                .write_all(&encoder.encode(message));
+                                log_control_write(message, write);
+                            };
                         loop {
                             let mut tmp = [0; 1];
                             let b = port_handle
@@ -110,120 +639,87 @@ pub fn gather_telemetry(
                             match b {
                                 // We got a new byte
                                 Ok(byte) => {
-                                    // We add it to the buffer
-                                    buffer.push(byte);
-
-                                    // Let's try to parse the buffer
-                                    match parse_telemetry_message(&buffer) {
-                                        // It worked! Let's extract the message and replace the buffer with the rest of the bytes
-                                        Ok((rest, message)) => {
-                                            if let Some(file_buffer) = file_buf.as_mut() {
-                                                // Write a new line with the base64 value of the message
-                                                let base64 = base64::encode(&buffer);
-                                                file_buffer.write_all(base64.as_bytes()).expect(
-                                                    "[tx channel] failed flushing buffer to file",
-                                                );
-                                                file_buffer.write_all(b"\n").expect("[tx channel] failed ending buffer flush to file");
-                                                file_buffer.flush().expect("[tx channel] failed flushing buffer flush to file");
+                                    if let Some(detector) = break_detector.as_mut() {
+                                        if detector.push(byte) {
+                                            warn!("break condition detected on {}", &port_id);
+                                            let error = HighLevelError::BreakCondition {
+                                                zeros: config.break_threshold.unwrap_or_default(),
+                                            };
+                                            if tx.send(Err(error.into())).is_err() {
+                                                tx.receiver_dropped();
+                                                return;
                                             }
-
-                                            tx.send(Ok(message))
-                                                .expect("[tx channel] failed sending message");
-
-                                            buffer = Vec::from(rest);
                                         }
-                                        // Message was read but there was a CRC error
-                                        Err(nom::Err::Failure(TelemetryError(
-                                            msg_bytes,
-                                            TelemetryErrorKind::CrcError { expected, computed },
-                                        ))) => {
-                                            warn!(
-                                                "[CRC error]\texpected={}\tcomputed={}",
-                                                expected, computed
-                                            );
-
-                                            tx.send(Err(HighLevelError::CrcError {
-                                                expected,
-                                                computed,
-                                            }
-                                            .into()))
-                                                .expect("[tx channel] failed sending message");
+                                    }
 
-                                            buffer = buffer.clone().split_off(msg_bytes.len());
-                                        }
-                                        // Message was built using an unsupported protocol version
-                                        Err(nom::Err::Failure(TelemetryError(
-                                            msg_bytes,
-                                            TelemetryErrorKind::UnsupportedProtocolVersion {
-                                                maximum_supported,
-                                                found,
-                                            },
-                                        ))) => {
-                                            warn!(
-                                                "[unsupported protocol version]\tmaximum_supported={}\tfound={}",
-                                                maximum_supported, found
-                                            );
-
-                                            tx.send(Err(
-                                                HighLevelError::UnsupportedProtocolVersion {
-                                                    maximum_supported,
-                                                    found,
-                                                }
-                                                .into(),
-                                            ))
-                                            .expect("[tx channel] failed sending message");
-
-                                            buffer = buffer.clone().split_off(msg_bytes.len());
-                                        }
-                                        // There are not enough bytes, let's wait until we get more
-                                        Err(nom::Err::Incomplete(_)) => {
-                                            // Do nothing
-                                            if let Some(file_buffer) = file_buf.as_mut() {
-                                                file_buffer.flush().expect("[tx channel] failed flushing file buffer from incomplete parsing");
-                                            }
-                                        }
-                                        // We can't do anything with the begining of the buffer, let's drop its first byte
-                                        Err(e) => {
-                                            debug!("{:?}", &e);
-                                            if !buffer.is_empty() {
-                                                if let Some(file_buffer) = file_buf.as_mut() {
-                                                    file_buffer.flush().expect("[tx channel] failed flushing file buffer from parsing error");
-                                                }
-
-                                                buffer.remove(0);
-                                            }
-                                        }
+                                    decoder.push_bytes(&[byte]);
+                                    if forward_frames(
+                                        &mut decoder,
+                                        &tx,
+                                        recorder.as_mut(),
+                                        Some(&mut control_encoder),
+                                    )
+                                    .is_err()
+                                    {
+                                        tx.receiver_dropped();
+                                        return;
                                     }
                                 }
                                 // We failed to get a new byte from serial
                                 Err(e) => {
-                                    if let Some(file_buffer) = file_buf.as_mut() {
-                                        file_buffer.flush().expect("[tx channel] failed flushing file buffer from serial error");
+                                    if let Some(recorder) = recorder.as_mut() {
+                                        recorder.tick().expect("[tx channel] failed flushing recording from serial error");
                                     }
                                     if e.kind() == std::io::ErrorKind::TimedOut { // It's OK it's just a timeout; let's try again
                                          // Do nothing
                                     } else {
                                         // It's another error, let's print it and wait a bit before retrying the whole process
                                         error!("{:?}", &e);
-                                        std::thread::sleep(std::time::Duration::from_secs(1));
+                                        tx.failed(&e, Some(config.reconnect_delay));
+                                        std::thread::sleep(config.reconnect_delay);
                                         break;
                                     }
                                 }
                             };
                             if let Some(rx) = control_rx.as_ref() {
-                                if let Ok(message) = rx.try_recv() {
-                                    let write = port_handle
-                                        .lock()
-                                        .expect("[port] failed getting exclusive lock on serial port to write control message")
-                                        .write_all(&message.to_control_frame());
-                                    match write {
-                                        Ok(_) => debug!("→ {}", &message),
-                                        Err(e) => warn!(
-                                            "Could not send control message '{}': {:?}",
-                                            &message, &e
-                                        ),
+                                let messages: Vec<ControlMessage> = match rate_limiter.as_mut() {
+                                    Some(limiter) => {
+                                        while let Ok(message) = rx.try_recv() {
+                                            if !limiter.push(message) {
+                                                warn!("too many control messages are waiting to be sent, the oldest one was dropped");
+                                            }
+                                        }
+                                        std::iter::from_fn(|| limiter.pop_ready()).collect()
+                                    }
+                                    None => rx.try_recv().into_iter().collect(),
+                                };
+                                for message in messages {
+                                    write_control(&message, &mut control_encoder);
+                                }
+                            }
+                            if tx.is_stopped() {
+                                // Send the control messages that are still waiting, at the pace of the rate limit
+                                let waiting = control_rx.iter().flat_map(|rx| rx.try_iter());
+                                match rate_limiter.as_mut() {
+                                    Some(limiter) => {
+                                        for message in waiting {
+                                            if !limiter.push(message) {
+                                                warn!("too many control messages are waiting to be sent, the oldest one was dropped");
+                                            }
+                                        }
+                                        while let Some(delay) = limiter.next_ready_in() {
+                                            std::thread::sleep(delay);
+                                            if let Some(message) = limiter.pop_ready() {
+                                                write_control(&message, &mut control_encoder);
+                                            }
+                                        }
                                     }
+                                    None => waiting.for_each(|message| {
+                                        write_control(&message, &mut control_encoder)
+                                    }),
                                 }
+                                tx.stopped();
+                                return;
                             }
                         }
                     }
@@ -233,121 +729,514 @@ pub fn gather_telemetry(
     }
 }
 
-/// Helper to display telemetry messages
-pub fn display_message(message: TelemetryChannelType) {
-    match message {
-        Ok(TelemetryMessage::BootMessage(BootMessage { value128, .. })) => {
-            debug!("####################################################################################");
-            debug!("######### CONTROLLER STARTED #########");
-            debug!("####################################################################################");
-            info!(
-                "{:?}",
-                &message.expect("failed unwrapping message for boot")
-            );
-            debug!("####################################################################################");
-            if value128 != 128u8 {
-                error!("value128 should be equal to 128 (found {:b} = {}); check serial port configuration", &value128, &value128);
+/// Forward bytes between the MCU and a control UI connected to another serial port, and send the telemetry they exchange through a channel
+///
+/// * `mcu_port` - Name or path to the serial port the MCU is connected to.
+/// * `ui_port` - Name or path to the serial port the control UI is connected to.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `config` - Configuration of both ports; their timeout is replaced by `sniffer::PASSTHROUGH_TIMEOUT`.
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// Bytes are forwarded before being parsed, so the UI gets them as if it were directly connected; control messages sent by the UI are logged.
+/// When either port fails, both are opened again.
+///
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub fn sniff_telemetry<T: From<TimedMessage>>(
+    mcu_port: &str,
+    ui_port: &str,
+    tx: Sender<T>,
+    mut recorder: Option<RecordingWriter>,
+    config: &serial_config::SerialConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    use sniffer::{ControlTap, Direction, Passthrough};
+
+    let mcu_port = ports::canonical_port_name(mcu_port);
+    let ui_port = ports::canonical_port_name(ui_port);
+    let tx = TimedSender::new(tx, SourceKind::Serial, Some(mcu_port.clone()))
+        .frame_capture(config.decode.frame_capture.clone());
+    let config = config.clone().timeout(sniffer::PASSTHROUGH_TIMEOUT);
+    loop {
+        info!("forwarding {} to {}", &mcu_port, &ui_port);
+        tx.connecting();
+        let ports = open_serial_port(&mcu_port, &config)
+            .and_then(|mcu| Ok((mcu, open_serial_port(&ui_port, &config)?)));
+        let mut passthrough = match ports {
+            Ok((mcu, ui)) => {
+                tx.connected();
+                Passthrough::new(mcu, ui)
+            }
+            Err(e) => {
+                error!("{}", e);
+                tx.failed(&e, Some(config.reconnect_delay));
+                if tx.send(Err(e.into())).is_err() {
+                    tx.receiver_dropped();
+                    return;
+                }
+                std::thread::sleep(config.reconnect_delay);
+                continue;
+            }
+        };
+
+        let mut decoder =
+            TelemetryDecoder::with_config(config.decode.parser).diagnostics(diagnostics_tx.clone());
+        let mut control_tap = ControlTap::new();
+        let mut receiver_dropped = false;
+        loop {
+            let result = passthrough.poll(|direction, bytes| match direction {
+                Direction::McuToUi => {
+                    decoder.push_bytes(bytes);
+                    receiver_dropped |=
+                        forward_frames(&mut decoder, &tx, recorder.as_mut(), None).is_err();
+                }
+                Direction::UiToMcu => {
+                    for message in control_tap.push(bytes) {
+                        info!("UI → {}", &message);
+                    }
+                }
+            });
+            if receiver_dropped {
+                tx.receiver_dropped();
+                return;
+            }
+            match result {
+                Ok(0) => {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder
+                            .tick()
+                            .expect("[tx channel] failed flushing recording from sniffer timeout");
+                    }
+                }
+                Ok(_) => (),
+                Err(e) => {
+                    error!("{}", &e);
+                    tx.failed(&e, Some(config.reconnect_delay));
+                    if tx.send(Err(e.into())).is_err() {
+                        tx.receiver_dropped();
+                        return;
+                    }
+                    break;
+                }
             }
         }
-        Ok(TelemetryMessage::StoppedMessage(_)) => {
-            debug!("stopped");
-        }
-        Ok(TelemetryMessage::DataSnapshot(_)) => {
-            info!(
-                "    {:?}",
-                &message.expect("failed unwrapping message for data snapshot")
-            );
-        }
-        Ok(TelemetryMessage::MachineStateSnapshot(_)) => {
-            debug!("------------------------------------------------------------------------------------");
-            info!(
-                "{:?}",
-                &message.expect("failed unwrapping message for machine snapshot")
-            );
-            debug!("------------------------------------------------------------------------------------");
-        }
-        Ok(TelemetryMessage::AlarmTrap(AlarmTrap { triggered, .. })) => {
-            let prefix = if triggered { "NEW ALARM" } else { "STOPPED" };
-            debug!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-            info!(
-                "{} {:?}",
-                &prefix,
-                &message.expect("failed unwrapping message for alarm trap")
-            );
-            debug!("!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!!");
-        }
-        Ok(TelemetryMessage::ControlAck(ControlAck { setting, value, .. })) => {
-            info!("← {:?} = {}", &setting, &value);
-        }
-        Ok(TelemetryMessage::FatalError(FatalError { error, .. })) => {
-            info!("***** FATAL ERROR ***** {:?}", &error);
+        std::thread::sleep(config.reconnect_delay);
+    }
+}
+
+/// Open a serial port, consume it endlessly and re-broadcast parsed telemetry messages to the clients of a WebSocket server, forwarding their control messages to the MCU
+///
+/// * `port_id` - Name or path to the serial port.
+/// * `addr` - Address the WebSocket server listens on (e.g. `0.0.0.0:8080`).
+/// * `server_config` - How the server handles its clients, e.g. to send messages as JSON (see `websocket::WebSocketServerConfig`).
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `serial_config` - Timeouts, modem control lines and break detection (see `serial_config::SerialConfig`).
+/// * `accept_control` - Whether control frames sent by clients are forwarded to the MCU (see `sink::WebSocketSink::bind_with_control()`); if not, clients can only watch telemetry.
+///
+/// This replaces a separate relay between the MCU and WebSocket clients (e.g. dashboards, or `gather_telemetry_from_ws()` on another machine).
+/// It returns an error if the server cannot listen on the address; otherwise it runs as long as the serial port is read, and is meant to be run in a dedicated thread (or in the main thread of a CLI).
+#[cfg(all(feature = "serial", feature = "websocket"))]
+#[cfg_attr(doc_cfg, doc(cfg(all(feature = "serial", feature = "websocket"))))]
+pub fn serve_telemetry_ws<A: std::net::ToSocketAddrs>(
+    port_id: &str,
+    addr: A,
+    server_config: websocket::WebSocketServerConfig,
+    recorder: Option<RecordingWriter>,
+    serial_config: &serial_config::SerialConfig,
+    accept_control: bool,
+) -> std::io::Result<()> {
+    let (control_tx, control_rx) = if accept_control {
+        let (control_tx, control_rx) = std::sync::mpsc::channel();
+        (Some(control_tx), Some(control_rx))
+    } else {
+        (None, None)
+    };
+    let mut ws_sink = sink::WebSocketSink::bind_with_control(addr, server_config, control_tx)?;
+    info!(
+        "serving telemetry messages on ws://{}",
+        ws_sink.local_addr()
+    );
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    let port_id = port_id.to_owned();
+    let serial_config = serial_config.clone();
+    std::thread::spawn(move || {
+        gather_telemetry_with_config(&port_id, tx, recorder, control_rx, &serial_config, None)
+    });
+    sink::dispatch(rx, &mut ws_sink);
+    Ok(())
+}
+
+/// Open a serial port, consume it endlessly and publish parsed telemetry messages to an MQTT broker, forwarding control messages published to its control topic to the MCU
+///
+/// * `port_id` - Name or path to the serial port.
+/// * `config` - Broker, topic prefix and payload format (see `mqtt::MqttConfig`); messages are published to one topic per message type.
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `serial_config` - Timeouts, modem control lines and break detection (see `serial_config::SerialConfig`).
+/// * `accept_control` - Whether control messages published to the control topic are forwarded to the MCU; if not, the control topic is not subscribed to.
+///
+/// This runs as long as the serial port is read, and is meant to be run in a dedicated thread (or in the main thread of a CLI).
+#[cfg(all(feature = "serial", feature = "mqtt"))]
+#[cfg_attr(doc_cfg, doc(cfg(all(feature = "serial", feature = "mqtt"))))]
+pub fn gather_telemetry_to_mqtt(
+    port_id: &str,
+    config: &mqtt::MqttConfig,
+    recorder: Option<RecordingWriter>,
+    serial_config: &serial_config::SerialConfig,
+    accept_control: bool,
+) {
+    let (control_tx, control_rx) = if accept_control {
+        let (control_tx, control_rx) = std::sync::mpsc::channel();
+        (Some(control_tx), Some(control_rx))
+    } else {
+        (None, None)
+    };
+
+    let (tx, rx): (Sender<TimedMessage>, Receiver<TimedMessage>) = std::sync::mpsc::channel();
+    let port_id = port_id.to_owned();
+    let serial_config = serial_config.clone();
+    std::thread::spawn(move || {
+        gather_telemetry_with_config(&port_id, tx, recorder, control_rx, &serial_config, None)
+    });
+    mqtt::publish_telemetry(rx, config, control_tx);
+}
+
+#[cfg(feature = "serial")]
+pub(crate) fn open_serial_port(
+    port_id: &str,
+    config: &serial_config::SerialConfig,
+) -> serial::Result<serial::SystemPort> {
+    let mut port = serial::open(port_id)?;
+    port.reconfigure(&|settings| {
+        settings.set_char_size(serial::Bits8);
+        settings.set_parity(serial::ParityNone);
+        settings.set_stop_bits(serial::Stop1);
+        settings.set_flow_control(serial::FlowNone);
+        settings.set_baud_rate(serial::Baud115200)
+    })?;
+    config.apply(&mut port)?;
+    Ok(port)
+}
+
+/// Connect to a source, consume it endlessly and send parsed telemetry messages through a channel
+///
+/// * `source` - Source to read telemetry from (see `source::TelemetrySource`).
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `control_rx` - Optional receiver of a channel used to send control messages to the source.
+/// * `reconnect_delay` - Time to wait before connecting again after an error or a closed connection.
+/// * `decode` - How frames are decoded (see `decoder::DecodeConfig`).
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// Control messages are sent no faster than the default `rate_limit::RateLimit`.
+///
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn gather_telemetry_from_source<S: TelemetrySource, T: From<TimedMessage>>(
+    mut source: S,
+    tx: Sender<T>,
+    mut recorder: Option<RecordingWriter>,
+    control_rx: Option<Receiver<ControlMessage>>,
+    reconnect_delay: Duration,
+    decode: &DecodeConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    let tx = TimedSender {
+        tx,
+        source: source.info(),
+        frame_capture: decode.frame_capture.clone(),
+        stop: None,
+    };
+    // Kept across reconnections, as the firmware only advertises its session when it boots
+    let mut control_encoder = ControlFrameEncoder::default();
+    loop {
+        info!("opening {}", &tx.source);
+        tx.connecting();
+        let mut connection = match source.connect() {
+            Ok(connection) => {
+                tx.connected();
+                connection
+            }
+            Err(e) => {
+                error!("{}", e);
+                tx.failed(&e, Some(reconnect_delay));
+                if tx.send(Err(e.into())).is_err() {
+                    tx.receiver_dropped();
+                    return;
+                }
+                std::thread::sleep(reconnect_delay);
+                continue;
+            }
+        };
+
+        let mut decoder =
+            TelemetryDecoder::with_config(decode.parser).diagnostics(diagnostics_tx.clone());
+        let mut chunk = [0; FILE_CHUNK_SIZE];
+        let mut rate_limiter =
+            rate_limit::ControlRateLimiter::new(rate_limit::RateLimit::default());
+        loop {
+            match connection.read(&mut chunk) {
+                Ok(0) => {
+                    warn!("connection to {} was closed", &tx.source);
+                    tx.failed("connection was closed", Some(reconnect_delay));
+                    break;
+                }
+                Ok(read_bytes) => {
+                    decoder.push_bytes(&chunk[..read_bytes]);
+                    if forward_frames(
+                        &mut decoder,
+                        &tx,
+                        recorder.as_mut(),
+                        Some(&mut control_encoder),
+                    )
+                    .is_err()
+                    {
+                        tx.receiver_dropped();
+                        return;
+                    }
+                }
+                Err(e)
+                    if e.kind() == std::io::ErrorKind::TimedOut
+                        || e.kind() == std::io::ErrorKind::WouldBlock =>
+                {
+                    if let Some(recorder) = recorder.as_mut() {
+                        recorder
+                            .tick()
+                            .expect("[tx channel] failed flushing recording from source timeout");
+                    }
+                }
+                Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+                Err(e) => {
+                    error!("{}", &e);
+                    tx.failed(&e, Some(reconnect_delay));
+                    if tx.send(Err(e.into())).is_err() {
+                        tx.receiver_dropped();
+                        return;
+                    }
+                    break;
+                }
+            }
+
+            if let Some(rx) = control_rx.as_ref() {
+                while let Ok(message) = rx.try_recv() {
+                    if !rate_limiter.push(message) {
+                        warn!("too many control messages are waiting to be sent, the oldest one was dropped");
+                    }
+                }
+                while let Some(message) = rate_limiter.pop_ready() {
+                    let write = connection.write_all(&control_encoder.encode(&message));
+                    log_control_write(&message, write);
+                }
+            }
         }
-        Ok(TelemetryMessage::EolTestSnapshot(_)) => {
-            info!(
-                "    {:?}",
-                &message.expect("failed unwrapping message for EOL test snapshot")
-            );
+        std::thread::sleep(reconnect_delay);
+    }
+}
+
+/// Log the outcome of writing a control frame, and record it in the audit log
+#[cfg(feature = "runtime")]
+fn log_control_write<E: std::fmt::Debug>(message: &ControlMessage, write: Result<(), E>) {
+    #[cfg(feature = "audit")]
+    let result = match &write {
+        Ok(_) => audit::AuditResult::Sent,
+        Err(e) => audit::AuditResult::Failed(format!("{:?}", e)),
+    };
+    match write {
+        Ok(_) => debug!("→ {}", message),
+        Err(e) => warn!("Could not send control message '{}': {:?}", message, &e),
+    }
+    #[cfg(feature = "audit")]
+    audit::record(message, result);
+}
+
+/// Send every message or error decoded from the bytes pushed so far, and return how many were sent, or an error if the receiver was dropped
+///
+/// Boot messages are given to `control_encoder`, so that control frames are protected against replays once the firmware supports it.
+#[cfg(feature = "runtime")]
+fn forward_frames<T: From<TimedMessage>>(
+    decoder: &mut TelemetryDecoder,
+    tx: &TimedSender<T>,
+    mut recorder: Option<&mut RecordingWriter>,
+    mut control_encoder: Option<&mut ControlFrameEncoder>,
+) -> Result<usize, SendError<T>> {
+    let mut count = 0;
+    while let Some(DecodedFrame { frame, result }) = decoder.next_frame() {
+        tx.capture(frame, FrameOutcome::of(&result));
+        if let (Some(encoder), Ok(message)) = (control_encoder.as_mut(), &result) {
+            encoder.update(message);
         }
-        Err(e) => {
-            warn!("an error occurred: {:?}", e);
+        if let (Some(recorder), Ok(message)) = (recorder.as_mut(), &result) {
+            recorder
+                .write_frame(frame, Some(message))
+                .expect("[tx channel] failed writing message to recording");
         }
+        tx.send(result.map_err(Error::from))?;
+        count += 1;
     }
+    Ok(count)
+}
+
+/// Helper to display telemetry messages
+///
+/// This uses `LogFormatter`; see the `formatter` module for other ways to display messages.
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn display_message(message: TelemetryChannelType) {
+    LogFormatter.display(&message)
 }
 
 /// Open a file containing serialized telemetry data, read it and send back parsed telemetry messages through a channel
 ///
 /// * `file` - Handle to a file that contains telemetry data.
-/// * `tx` - Sender of a channel.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
 /// * `enable_time_simulation` - If `true`, telemetry messages will be sent in a realistic timing; if `false`, they will be read as fast as possible.
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
 ///
+/// Frames that cannot be decoded (e.g. because of a CRC error) are skipped, and only reported to `diagnostics_tx`.
+/// It returns at the end of the file, or once the receiver of `tx` is dropped.
 /// This is meant to be run in a dedicated thread.
-pub fn gather_telemetry_from_file(
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn gather_telemetry_from_file<T: From<TimedMessage>>(
     file: File,
-    tx: Sender<TelemetryChannelType>,
+    tx: Sender<T>,
     enable_time_simulation: bool,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
 ) {
-    let reader = BufReader::new(file);
-    let mut buffer = Vec::new();
+    gather_telemetry_from_file_with_progress(
+        file,
+        tx,
+        enable_time_simulation,
+        NoProgress,
+        &DecodeConfig::default(),
+        diagnostics_tx,
+    )
+}
+
+/// Same as `gather_telemetry_from_file`, but also report progress while reading the file, and choose how frames are decoded
+///
+/// * `file` - Handle to a file that contains telemetry data.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `enable_time_simulation` - If `true`, telemetry messages will be sent in a realistic timing; if `false`, they will be read as fast as possible.
+/// * `progress` - Callback that will regularly be notified of the progress.
+/// * `decode` - How frames are decoded (see `decoder::DecodeConfig`).
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// Frames that cannot be decoded (e.g. because of a CRC error) are skipped, and only reported to `diagnostics_tx` and to the frame capture.
+/// It returns at the end of the file, or once the receiver of `tx` is dropped.
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn gather_telemetry_from_file_with_progress<T: From<TimedMessage>, P: ProgressCallback>(
+    file: File,
+    tx: Sender<T>,
+    enable_time_simulation: bool,
+    mut progress: P,
+    decode: &DecodeConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    let tx =
+        TimedSender::new(tx, SourceKind::File, None).frame_capture(decode.frame_capture.clone());
+    let start = std::time::Instant::now();
+    let mut state = Progress {
+        total_bytes: file.metadata().ok().map(|metadata| metadata.len()),
+        ..Progress::default()
+    };
+    let mut last_report = start;
+
+    let mut reader = reader::TelemetryFileReader::new(file)
+        .parser_config(decode.parser)
+        .diagnostics(diagnostics_tx);
 
     let stopped_message_period = std::time::Duration::from_millis(100);
     let data_message_period = std::time::Duration::from_millis(10);
 
-    for line_str in reader.lines().flatten() {
-        if let Ok(mut bytes) = base64::decode(line_str) {
-            buffer.append(&mut bytes);
-
-            while !buffer.is_empty() {
-                // Let's try to parse the buffer
-                match parse_telemetry_message(&buffer) {
-                    // It worked! Let's extract the message and replace the buffer with the rest of the bytes
-                    Ok((rest, message)) => {
-                        if enable_time_simulation {
-                            match message {
-                                TelemetryMessage::StoppedMessage { .. } => {
-                                    std::thread::sleep(stopped_message_period);
-                                }
-                                TelemetryMessage::DataSnapshot { .. } => {
-                                    std::thread::sleep(data_message_period);
-                                }
-                                _ => (),
-                            }
-                        }
-                        tx.send(Ok(message))
-                            .expect("failed sending message to tx channel");
-                        buffer = Vec::from(rest);
-                    }
-                    // There are not enough bytes, let's wait until we get more
-                    Err(nom::Err::Incomplete(_)) => {
-                        break;
-                    }
-                    // We can't do anything with the begining of the buffer, let's drop its first byte
-                    Err(e) => {
-                        debug!("{:?}", &e);
-                        if !buffer.is_empty() {
-                            buffer.remove(0);
-                        }
-                    }
+    while let Some(frame) = reader.next_decoded_frame() {
+        let DecodedFrame { frame, result } = match frame {
+            Ok(frame) => frame,
+            Err(e) => {
+                warn!("failed to read file: {:?}", e);
+                break;
+            }
+        };
+        tx.capture(frame, FrameOutcome::of(&result));
+        let message = match result {
+            Ok(message) => message,
+            Err(_) => continue,
+        };
+        if enable_time_simulation {
+            match message {
+                TelemetryMessage::StoppedMessage { .. } => {
+                    std::thread::sleep(stopped_message_period);
                 }
+                TelemetryMessage::DataSnapshot { .. } => {
+                    std::thread::sleep(data_message_period);
+                }
+                _ => (),
+            }
+        }
+        if tx.send(Ok(message)).is_err() {
+            tx.receiver_dropped();
+            return;
+        }
+        state.messages += 1;
+
+        if last_report.elapsed() >= PROGRESS_REPORT_PERIOD {
+            last_report = std::time::Instant::now();
+            state.processed_bytes = reader.consumed_bytes();
+            state.elapsed = start.elapsed();
+            progress.on_progress(&state);
+        }
+    }
+
+    state.processed_bytes = state.total_bytes.unwrap_or(state.processed_bytes);
+    state.elapsed = start.elapsed();
+    progress.on_finish(&state);
+}
+
+/// Consume a stream of telemetry bytes (e.g. stdin or an accepted socket) until its end and send parsed telemetry messages through a channel
+///
+/// * `reader` - Stream of raw telemetry bytes, as sent by the MCU.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `identifier` - Optional name of the stream, attached to every message (e.g. `stdin`).
+/// * `decode` - How frames are decoded (see `decoder::DecodeConfig`).
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// Unlike `gather_telemetry_from_source()`, this returns as soon as the stream is closed, with an error if reading it failed; it also returns once the receiver of `tx` is dropped.
+///
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn gather_telemetry_from_reader<R: Read, T: From<TimedMessage>>(
+    mut reader: R,
+    tx: Sender<T>,
+    identifier: Option<String>,
+    decode: &DecodeConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) -> std::io::Result<()> {
+    let tx = TimedSender::new(tx, SourceKind::Bytes, identifier)
+        .frame_capture(decode.frame_capture.clone());
+    let mut decoder = TelemetryDecoder::with_config(decode.parser).diagnostics(diagnostics_tx);
+    let mut chunk = [0; FILE_CHUNK_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => return Ok(()),
+            Ok(read_bytes) => {
+                decoder.push_bytes(&chunk[..read_bytes]);
+                if forward_frames(&mut decoder, &tx, None, None).is_err() {
+                    tx.receiver_dropped();
+                    return Ok(());
+                }
+            }
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => {
+                tx.failed(&e, None);
+                return Err(e);
             }
         }
     }
@@ -356,106 +1245,128 @@ pub fn gather_telemetry_from_file(
 /// Connect to a WebSocket server, get binary messages endlessly and send parsed telemetry messages through a channel
 ///
 /// * `url` - URL to the WebSocket server.
-/// * `tx` - Sender of a channel.
-/// * `file_buf` - Optional file buffer; if specified, messages will also be serialized and written in this file.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `file_buf` - Optional file buffer; if specified, messages will also be serialized and written in this file, and flushed after each message.
 /// * `control_rx` - Optional receiver of a channel used to send control messages through the WS session.
 ///
+/// Use `gather_telemetry_from_ws_with_config()` to record with another `recording::FlushPolicy`.
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
 /// This is meant to be run in a dedicated thread.
 #[cfg(feature = "websocket")]
 #[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
-pub fn gather_telemetry_from_ws(
+pub fn gather_telemetry_from_ws<T: From<TimedMessage>>(
     url: &Url,
-    tx: Sender<TelemetryChannelType>,
-    mut file_buf: Option<BufWriter<File>>,
+    tx: Sender<T>,
+    file_buf: Option<BufWriter<File>>,
     control_rx: Option<Receiver<ControlMessage>>,
-) -> ! {
-    use tungstenite::client::connect;
-    use tungstenite::protocol::Message;
+) {
+    gather_telemetry_from_ws_with_config(
+        url,
+        tx,
+        file_buf.map(RecordingWriter::from),
+        control_rx,
+        &websocket::WebSocketClientConfig::default(),
+        None,
+    )
+}
+
+/// Same as `gather_telemetry_from_ws`, but with a custom configuration (e.g. to offer permessage-deflate compression)
+///
+/// * `url` - URL to the WebSocket server.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `recorder` - Optional recording writer; if specified, messages will also be serialized and written with it.
+/// * `control_rx` - Optional receiver of a channel used to send control messages through the WS session.
+/// * `config` - How to connect to the server.
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
+///
+/// It only returns once the receiver of `tx` is dropped, when it fails to send the next message or error.
+/// This is meant to be run in a dedicated thread.
+#[cfg(feature = "websocket")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+pub fn gather_telemetry_from_ws_with_config<T: From<TimedMessage>>(
+    url: &Url,
+    tx: Sender<T>,
+    recorder: Option<RecordingWriter>,
+    control_rx: Option<Receiver<ControlMessage>>,
+    config: &websocket::WebSocketClientConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    gather_telemetry_from_ws_until(url, tx, recorder, control_rx, config, diagnostics_tx, None)
+}
 
-    use serializers::ToBytes;
+/// Same as `gather_telemetry_from_ws_with_config()`, but also return once `stop` is set
+///
+/// The flag is checked after every read, so the connection is closed within the `read_timeout` of the configuration; control messages that are still waiting are sent first.
+#[cfg(feature = "websocket")]
+pub(crate) fn gather_telemetry_from_ws_until<T: From<TimedMessage>>(
+    url: &Url,
+    tx: Sender<T>,
+    mut recorder: Option<RecordingWriter>,
+    control_rx: Option<Receiver<ControlMessage>>,
+    config: &websocket::WebSocketClientConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+    stop: Option<Arc<AtomicBool>>,
+) {
+    use tungstenite::protocol::Message;
 
+    let tx = TimedSender::new(tx, SourceKind::WebSocket, Some(url.to_string()))
+        .frame_capture(config.decode.frame_capture.clone())
+        .stop_signal(stop);
+    // Kept across reconnections, as the firmware only advertises its session when it boots
+    let mut control_encoder = ControlFrameEncoder::default();
     loop {
+        if tx.is_stopped() {
+            tx.stopped();
+            return;
+        }
         info!("opening {}", &url);
+        tx.connecting();
 
-        match connect(url) {
+        match websocket::connect(url, config) {
             Err(e) => {
                 error!("{:?}", e);
-                tx.send(Err(e.into()))
-                    .expect("[tx channel] failed to send error");
+                tx.failed(&e, Some(Duration::from_secs(1)));
+                if tx.send(Err(e.into())).is_err() {
+                    tx.receiver_dropped();
+                    return;
+                }
                 std::thread::sleep(std::time::Duration::from_secs(1));
             }
-            Ok((mut socket, _response)) => {
+            Ok(mut socket) => {
                 info!("WebSocket connection was successfuly established");
+                tx.connected();
+                let mut decoder = TelemetryDecoder::with_config(config.decode.parser)
+                    .diagnostics(diagnostics_tx.clone());
                 'ws_session: loop {
                     match socket.read_message() {
                         Ok(Message::Binary(bytes)) => {
-                            // Let's try to parse the received message
-                            match parse_telemetry_message(&bytes) {
-                                // It worked!
-                                Ok((_rest, message)) => {
-                                    if let Some(file_buffer) = file_buf.as_mut() {
-                                        // Write a new line with the base64 value of the message
-                                        let base64 = base64::encode(&message.to_bytes());
-                                        file_buffer
-                                            .write_all(base64.as_bytes())
-                                            .expect("[tx channel] failed flushing buffer to file");
-                                        file_buffer.write_all(b"\n").expect(
-                                            "[tx channel] failed ending buffer flush to file",
-                                        );
-                                        file_buffer.flush().expect(
-                                            "[tx channel] failed flushing buffer flush to file",
-                                        );
-                                    }
-
-                                    tx.send(Ok(message))
-                                        .expect("[tx channel] failed sending message");
-                                }
-                                // Message was read but there was a CRC error
-                                Err(nom::Err::Failure(TelemetryError(
-                                    _msg_bytes,
-                                    TelemetryErrorKind::CrcError { expected, computed },
-                                ))) => {
-                                    warn!(
-                                        "[CRC error]\texpected={}\tcomputed={}",
-                                        expected, computed
-                                    );
-
-                                    tx.send(Err(
-                                        HighLevelError::CrcError { expected, computed }.into()
-                                    ))
-                                    .expect("[tx channel] failed sending message");
-                                }
-                                // Message was built using an unsupported protocol version
-                                Err(nom::Err::Failure(TelemetryError(
-                                    _msg_bytes,
-                                    TelemetryErrorKind::UnsupportedProtocolVersion {
-                                        maximum_supported,
-                                        found,
-                                    },
-                                ))) => {
-                                    warn!(
-                                        "[unsupported protocol version]\tmaximum_supported={}\tfound={}",
-                                        maximum_supported, found
-                                    );
-
-                                    tx.send(Err(HighLevelError::UnsupportedProtocolVersion {
-                                        maximum_supported,
-                                        found,
-                                    }
-                                    .into()))
-                                        .expect("[tx channel] failed sending message");
-                                }
-                                // We can't do anything with this message
-                                Err(e) => {
-                                    debug!("{:?}", &e);
-                                }
+                            // Every binary message holds a single frame
+                            decoder.push_bytes(&bytes);
+                            if forward_frames(
+                                &mut decoder,
+                                &tx,
+                                recorder.as_mut(),
+                                Some(&mut control_encoder),
+                            )
+                            .is_err()
+                            {
+                                tx.receiver_dropped();
+                                return;
                             }
+                            decoder.clear();
                         }
                         Ok(_) => {
                             // Do nothing
                         }
+                        // No message before the read timeout: let's send control messages
+                        Err(tungstenite::Error::Io(e))
+                            if matches!(
+                                e.kind(),
+                                std::io::ErrorKind::WouldBlock | std::io::ErrorKind::TimedOut
+                            ) => {}
                         Err(e) => {
                             error!("{:}", &e);
+                            tx.failed(&e, Some(Duration::from_secs(1)));
                             std::thread::sleep(std::time::Duration::from_secs(1));
                             break 'ws_session;
                         }
@@ -464,17 +1375,10 @@ pub fn gather_telemetry_from_ws(
                     'sending_control_messages: loop {
                         if let Some(rx) = control_rx.as_ref() {
                             if let Ok(message) = rx.try_recv() {
-                                let write = socket
-                                    .write_message(Message::Binary(message.to_control_frame()));
-                                match write {
-                                    Ok(_) => debug!("→ {}", &message),
-                                    Err(e) => {
-                                        warn!(
-                                            "Could not send control message '{}': {:?}",
-                                            &message, &e
-                                        )
-                                    }
-                                }
+                                let write = socket.write_message(Message::Binary(
+                                    control_encoder.encode(&message),
+                                ));
+                                log_control_write(&message, write);
                             } else {
                                 break 'sending_control_messages;
                             }
@@ -482,6 +1386,16 @@ pub fn gather_telemetry_from_ws(
                             break 'sending_control_messages;
                         }
                     }
+                    if tx.is_stopped() {
+                        // Send the control messages that are still waiting
+                        for message in control_rx.iter().flat_map(|rx| rx.try_iter()) {
+                            let write = socket
+                                .write_message(Message::Binary(control_encoder.encode(&message)));
+                            log_control_write(&message, write);
+                        }
+                        tx.stopped();
+                        return;
+                    }
                 }
             }
         }
@@ -495,16 +1409,26 @@ pub fn gather_telemetry_from_ws(
 /// * `control_rx` - Optional receiver of a channel used to transport structured control messages (input).
 /// * `control_bytes_tx` - Optional sender of a channel used to transport control bytes (output).
 /// * `sleep_duration` - Optional duration to wait when there are no more bytes to parse; if `None` then no sleep.
+/// * `decode` - How frames are decoded (see `decoder::DecodeConfig`).
+/// * `diagnostics_tx` - Optional sender of a channel of non-fatal problems found while decoding (see `diagnostics::DecodeDiagnostic`).
 ///
+/// It only returns once the receiver of `telemetry_tx` (or of `control_bytes_tx`) is dropped, when it fails to send the next message or frame.
 /// This is meant to be run in a dedicated thread.
-pub fn gather_telemetry_from_bytes(
+#[cfg(feature = "runtime")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "runtime")))]
+pub fn gather_telemetry_from_bytes<T: From<TimedMessage>>(
     telemetry_bytes_rx: Receiver<Vec<u8>>,
-    telemetry_tx: Sender<TelemetryChannelType>,
+    telemetry_tx: Sender<T>,
     control_rx: Option<Receiver<ControlMessage>>,
     control_bytes_tx: Option<Sender<Vec<u8>>>,
     sleep_duration: Option<Duration>,
-) -> ! {
-    let mut telemetry_buffer = Vec::new();
+    decode: &DecodeConfig,
+    diagnostics_tx: Option<Sender<DecodeDiagnostic>>,
+) {
+    let telemetry_tx = TimedSender::new(telemetry_tx, SourceKind::Bytes, None)
+        .frame_capture(decode.frame_capture.clone());
+    let mut decoder = TelemetryDecoder::with_config(decode.parser).diagnostics(diagnostics_tx);
+    let mut control_encoder = ControlFrameEncoder::default();
 
     if control_rx.is_none() || control_bytes_tx.is_none() {
         warn!("Control messages will not be handled (optional sender/receiver were not provided)");
@@ -512,85 +1436,45 @@ pub fn gather_telemetry_from_bytes(
 
     loop {
         // Check for new bytes from the telemetry bytes channel and handle them
-        if let Ok(mut new_telemetry_bytes) = telemetry_bytes_rx.try_recv() {
-            telemetry_buffer.append(&mut new_telemetry_bytes);
+        if let Ok(new_telemetry_bytes) = telemetry_bytes_rx.try_recv() {
+            decoder.push_bytes(&new_telemetry_bytes);
         }
 
-        if !telemetry_buffer.is_empty() {
-            match parse_telemetry_message(&telemetry_buffer) {
-                // It worked! Let's extract the message and replace the buffer with the rest of the bytes
-                Ok((rest, message)) => {
-                    telemetry_tx
-                        .send(Ok(message))
-                        .expect("[telemetry tx channel] failed sending message");
-
-                    telemetry_buffer = Vec::from(rest);
-                }
-                // Message was read but there was a CRC error
-                Err(nom::Err::Failure(TelemetryError(
-                    msg_bytes,
-                    TelemetryErrorKind::CrcError { expected, computed },
-                ))) => {
-                    warn!("[CRC error]\texpected={}\tcomputed={}", expected, computed);
-
-                    telemetry_tx
-                        .send(Err(HighLevelError::CrcError { expected, computed }.into()))
-                        .expect("[telemetry tx channel] failed sending message");
-
-                    telemetry_buffer = telemetry_buffer.clone().split_off(msg_bytes.len());
-                }
-                // Message was built using an unsupported protocol version
-                Err(nom::Err::Failure(TelemetryError(
-                    msg_bytes,
-                    TelemetryErrorKind::UnsupportedProtocolVersion {
-                        maximum_supported,
-                        found,
-                    },
-                ))) => {
-                    warn!(
-                        "[unsupported protocol version]\tmaximum_supported={}\tfound={}",
-                        maximum_supported, found
-                    );
-
-                    telemetry_tx
-                        .send(Err(HighLevelError::UnsupportedProtocolVersion {
-                            maximum_supported,
-                            found,
-                        }
-                        .into()))
-                        .expect("[telemetry tx channel] failed sending message");
-
-                    telemetry_buffer = telemetry_buffer.clone().split_off(msg_bytes.len());
-                }
-                // There are not enough bytes, let's wait until we get more
-                Err(nom::Err::Incomplete(_)) => {
-                    // Do nothing
-                    if let Some(duration) = sleep_duration {
-                        std::thread::sleep(duration);
-                    }
-                }
-                // We can't do anything with the begining of the buffer, let's drop its first byte
-                Err(e) => {
-                    debug!("{:?}", &e);
-                    telemetry_buffer.remove(0);
+        // Wait a bit if there are not enough bytes to decode a message
+        match forward_frames(
+            &mut decoder,
+            &telemetry_tx,
+            None,
+            Some(&mut control_encoder),
+        ) {
+            Ok(0) => {
+                if let Some(duration) = sleep_duration {
+                    std::thread::sleep(duration);
                 }
             }
-        } else if let Some(duration) = sleep_duration {
-            std::thread::sleep(duration);
+            Ok(_) => (),
+            Err(_) => {
+                telemetry_tx.receiver_dropped();
+                return;
+            }
         }
 
         // Check for a new message from the structured control message channel and handle it
         if let (Some(rx), Some(tx)) = (control_rx.as_ref(), control_bytes_tx.as_ref()) {
             if let Ok(new_control_message) = rx.try_recv() {
-                let new_control_bytes = new_control_message.to_control_frame();
-                tx.send(new_control_bytes)
-                    .expect("[control tx channel] failed sending bytes");
+                let new_control_bytes = control_encoder.encode(&new_control_message);
+                if tx.send(new_control_bytes).is_err() {
+                    info!("stopped reading bytes: the receiver of control bytes was dropped");
+                    return;
+                }
+                #[cfg(feature = "audit")]
+                audit::record(&new_control_message, audit::AuditResult::Sent);
             }
         }
     }
 }
 
-#[cfg(test)]
+#[cfg(all(test, feature = "runtime"))]
 mod tests {
     use super::*;
     use crate::serializers::*;
@@ -611,6 +1495,8 @@ mod tests {
                 systick: 10,
                 mode: Mode::Production,
                 value128: 128,
+                control_capabilities: None,
+                session_nonce: None,
             }),
             TelemetryMessage::ControlAck(ControlAck {
                 telemetry_version: TELEMETRY_VERSION,
@@ -668,6 +1554,105 @@ mod tests {
         ]
     }
 
+    #[test]
+    #[timeout(10000)]
+    fn gather_telemetry_from_file_timestamps_messages() {
+        let file = File::open("records/v2/short.record").expect("failed to open record");
+        let (tx, rx) = channel::<TimedMessage>();
+        let before = SystemTime::now();
+
+        gather_telemetry_from_file(file, tx, false, None);
+
+        let messages: Vec<TimedMessage> = rx.iter().collect();
+        assert!(!messages.is_empty());
+        assert!(messages.iter().all(|m| m.message.is_ok()));
+        assert!(messages.iter().all(|m| m.source.kind == SourceKind::File));
+        assert!(messages[0].received_at >= before);
+        assert!(messages
+            .windows(2)
+            .all(|w| w[0].received_instant <= w[1].received_instant));
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn gather_telemetry_from_file_reports_frames_it_skips() {
+        let telemetry_messages = gen_fake_telemetry_messages();
+        let mut corrupted = telemetry_messages[1].to_bytes();
+        // Last byte of the CRC, right before the footer
+        let crc_index = corrupted.len() - 3;
+        corrupted[crc_index] ^= 0xFF;
+        let recording = [
+            telemetry_messages[0].to_bytes(),
+            corrupted,
+            telemetry_messages[1].to_bytes(),
+        ]
+        .iter()
+        .map(|frame| base64::encode(frame) + "\n")
+        .collect::<String>();
+        let path = std::env::temp_dir().join(format!("skipped-{}.record", std::process::id()));
+        std::fs::write(&path, recording).unwrap();
+
+        let capture = FrameCapture::new(3);
+        let (tx, rx) = channel::<TelemetryChannelType>();
+        let (diagnostics_tx, diagnostics_rx) = channel();
+        gather_telemetry_from_file_with_progress(
+            File::open(&path).unwrap(),
+            tx,
+            false,
+            NoProgress,
+            &DecodeConfig::new().frame_capture(capture.clone()),
+            Some(diagnostics_tx),
+        );
+        std::fs::remove_file(&path).unwrap();
+
+        assert_eq!(rx.iter().filter(|m| m.is_ok()).count(), 2);
+        assert!(matches!(
+            diagnostics_rx.try_iter().collect::<Vec<_>>()[..],
+            [DecodeDiagnostic::CrcError { .. }]
+        ));
+        assert_eq!(
+            capture
+                .dump_recent_frames()
+                .iter()
+                .map(|frame| frame.outcome)
+                .collect::<Vec<_>>(),
+            vec![
+                FrameOutcome::Parsed,
+                FrameOutcome::CrcError,
+                FrameOutcome::Parsed
+            ]
+        );
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn transports_return_once_the_receiver_is_dropped() {
+        let (tx, rx) = channel::<TelemetryChannelType>();
+        drop(rx);
+        gather_telemetry_from_file(
+            File::open("records/v2/short.record").expect("failed to open record"),
+            tx,
+            false,
+            None,
+        );
+
+        let (telemetry_bytes_tx, telemetry_bytes_rx) = channel::<Vec<u8>>();
+        let (tx, rx) = channel::<TelemetryChannelType>();
+        drop(rx);
+        telemetry_bytes_tx
+            .send(gen_fake_telemetry_messages()[0].to_bytes())
+            .unwrap();
+        gather_telemetry_from_bytes(
+            telemetry_bytes_rx,
+            tx,
+            None,
+            None,
+            None,
+            &DecodeConfig::default(),
+            None,
+        );
+    }
+
     #[test]
     #[timeout(2000)]
     fn gather_telemetry_from_bytes_works() {
@@ -695,7 +1680,7 @@ mod tests {
         let (control_bytes_tx, control_bytes_rx) = channel::<Vec<u8>>();
         let (control_messages_tx, control_messages_rx) = channel::<ControlMessage>();
 
-        // Run the gather_telemetry* function in a thread (it will never terminate)
+        // Run the gather_telemetry* function in a thread (it only terminates once the receiver is dropped)
         std::thread::spawn(|| {
             gather_telemetry_from_bytes(
                 telemetry_bytes_rx,
@@ -703,6 +1688,8 @@ mod tests {
                 Some(control_messages_rx),
                 Some(control_bytes_tx),
                 None,
+                &DecodeConfig::default(),
+                None,
             )
         });
 
@@ -746,4 +1733,278 @@ mod tests {
             }
         }
     }
+
+    #[test]
+    #[timeout(2000)]
+    fn control_frames_are_protected_once_the_firmware_supports_it() {
+        use crate::replay::ControlCapabilities;
+
+        let (telemetry_bytes_tx, telemetry_bytes_rx) = channel::<Vec<u8>>();
+        let (telemetry_messages_tx, telemetry_messages_rx) = channel::<TelemetryChannelType>();
+        let (control_bytes_tx, control_bytes_rx) = channel::<Vec<u8>>();
+        let (control_messages_tx, control_messages_rx) = channel::<ControlMessage>();
+        std::thread::spawn(|| {
+            gather_telemetry_from_bytes(
+                telemetry_bytes_rx,
+                telemetry_messages_tx,
+                Some(control_messages_rx),
+                Some(control_bytes_tx),
+                None,
+                &DecodeConfig::default(),
+                None,
+            )
+        });
+        let control_messages = gen_fake_control_messages();
+
+        // The firmware did not advertise anything yet
+        control_messages_tx
+            .send(control_messages[0].clone())
+            .unwrap();
+        let frame = control_bytes_rx.recv().unwrap();
+        assert_eq!(
+            parse_control_message(&frame).unwrap().1,
+            control_messages[0]
+        );
+
+        let boot_message = TelemetryMessage::BootMessage(BootMessage {
+            telemetry_version: 3,
+            version: VERSION.to_owned(),
+            device_id: DEVICE_ID.to_owned(),
+            systick: 10,
+            mode: Mode::Production,
+            value128: 128,
+            control_capabilities: Some(ControlCapabilities::REPLAY_PROTECTION),
+            session_nonce: Some(42),
+        });
+        telemetry_bytes_tx.send(boot_message.to_bytes_v3()).unwrap();
+        assert_eq!(telemetry_messages_rx.recv().unwrap().unwrap(), boot_message);
+
+        for (sequence, message) in control_messages.iter().enumerate() {
+            control_messages_tx.send(message.clone()).unwrap();
+            let frame = control_bytes_rx.recv().unwrap();
+            let protected = parse_protected_control_message(&frame).unwrap().1;
+            assert_eq!(protected.message, *message);
+            assert_eq!(protected.nonce, 42);
+            assert_eq!(protected.sequence, sequence as u32);
+        }
+    }
+
+    /// Source whose connections yield some bytes, then get closed
+    struct FakeSource {
+        bytes: Vec<u8>,
+        connections: usize,
+        written: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    struct FakeConnection {
+        bytes: std::io::Cursor<Vec<u8>>,
+        written: std::sync::Arc<std::sync::Mutex<Vec<u8>>>,
+    }
+
+    impl Read for FakeConnection {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            // Give bytes in small chunks, so that frames are split
+            let len = buf.len().min(7);
+            self.bytes.read(&mut buf[..len])
+        }
+    }
+
+    impl Write for FakeConnection {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.written.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl TelemetrySource for FakeSource {
+        type Connection = FakeConnection;
+
+        fn info(&self) -> SourceInfo {
+            SourceInfo::new(SourceKind::Bytes, Some("fake".to_owned()))
+        }
+
+        fn connect(&mut self) -> std::io::Result<FakeConnection> {
+            self.connections += 1;
+            Ok(FakeConnection {
+                bytes: std::io::Cursor::new(self.bytes.clone()),
+                written: self.written.clone(),
+            })
+        }
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn gather_telemetry_from_source_reconnects() {
+        let telemetry_messages = gen_fake_telemetry_messages();
+        let written = std::sync::Arc::new(std::sync::Mutex::new(Vec::new()));
+        let source = FakeSource {
+            bytes: telemetry_messages
+                .iter()
+                .flat_map(|m| m.to_bytes())
+                .collect(),
+            connections: 0,
+            written: written.clone(),
+        };
+        let (tx, rx) = channel::<TimedMessage>();
+        let (control_tx, control_rx) = channel::<ControlMessage>();
+        let control_message = gen_fake_control_messages().remove(1);
+        control_tx.send(control_message.clone()).unwrap();
+        std::thread::spawn(move || {
+            gather_telemetry_from_source(
+                source,
+                tx,
+                None,
+                Some(control_rx),
+                Duration::from_millis(1),
+                &DecodeConfig::default(),
+                None,
+            )
+        });
+
+        // Messages are received once per connection
+        for expected in telemetry_messages.iter().chain(telemetry_messages.iter()) {
+            let message = rx.recv().unwrap();
+            assert_eq!(message.message.unwrap(), *expected);
+            assert_eq!(message.source.to_string(), "bytes:fake");
+        }
+        assert_eq!(*written.lock().unwrap(), control_message.to_control_frame());
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn gather_telemetry_from_reader_stops_at_end_of_stream() {
+        use sink::{FrameSink, TelemetrySink};
+
+        let telemetry_messages = gen_fake_telemetry_messages();
+        let bytes: Vec<u8> = telemetry_messages
+            .iter()
+            .flat_map(|m| m.to_bytes())
+            .collect();
+        let (tx, rx) = channel::<TimedMessage>();
+
+        gather_telemetry_from_reader(
+            bytes.as_slice(),
+            tx,
+            Some("stdin".to_owned()),
+            &DecodeConfig::default(),
+            None,
+        )
+        .unwrap();
+
+        // Frames are written back unchanged
+        let mut output = Vec::new();
+        let mut frames = FrameSink::new(&mut output);
+        for message in rx.iter() {
+            assert_eq!(message.source.to_string(), "bytes:stdin");
+            frames.consume(&message);
+        }
+        assert_eq!(output, bytes);
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn gather_telemetry_from_reader_captures_frames_on_demand() {
+        let telemetry_messages = gen_fake_telemetry_messages();
+        let bytes: Vec<u8> = telemetry_messages
+            .iter()
+            .flat_map(|m| m.to_bytes())
+            .collect();
+        let capture = FrameCapture::new(2);
+        let (tx, _rx) = channel::<TelemetryChannelType>();
+        gather_telemetry_from_reader(
+            bytes.as_slice(),
+            tx,
+            None,
+            &DecodeConfig::new().frame_capture(capture.clone()),
+            None,
+        )
+        .unwrap();
+
+        // Only the last frames are kept
+        let frames = capture.dump_recent_frames();
+        assert_eq!(
+            frames
+                .iter()
+                .map(|frame| frame.bytes.clone())
+                .collect::<Vec<_>>(),
+            telemetry_messages[telemetry_messages.len() - 2..]
+                .iter()
+                .map(|m| m.to_bytes())
+                .collect::<Vec<_>>()
+        );
+        assert!(frames
+            .iter()
+            .all(|frame| frame.outcome == FrameOutcome::Parsed));
+
+        let mut dump = Vec::new();
+        capture.write_recent_frames(&mut dump).unwrap();
+        assert_eq!(String::from_utf8(dump).unwrap().lines().count(), 2);
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn gather_telemetry_from_reader_uses_its_parser_config() {
+        let message = TelemetryMessage::EolTestSnapshot(EolTestSnapshot {
+            telemetry_version: TELEMETRY_VERSION,
+            version: VERSION.to_owned(),
+            device_id: DEVICE_ID.to_owned(),
+            systick: 10,
+            current_step: EolTestStep::Unknown(200),
+            content: EolTestSnapshotContent::InProgress("new step".to_owned()),
+        });
+        let bytes = message.to_bytes();
+        let gather = |decode: &DecodeConfig| {
+            let (tx, rx) = channel::<TelemetryChannelType>();
+            gather_telemetry_from_reader(bytes.as_slice(), tx, None, decode, None).unwrap();
+            rx.iter().filter_map(Result::ok).collect::<Vec<_>>()
+        };
+
+        // Unknown values are rejected by default, and kept by a lenient parser
+        assert!(gather(&DecodeConfig::default()).is_empty());
+        let lenient = DecodeConfig::new()
+            .parser(parsers::ParserConfig::new().mode(parsers::ParsingMode::Lenient));
+        assert_eq!(gather(&lenient), vec![message]);
+    }
+
+    #[test]
+    #[timeout(2000)]
+    fn misconfigured_link_is_reported() {
+        let boot_message = TelemetryMessage::BootMessage(BootMessage {
+            telemetry_version: TELEMETRY_VERSION,
+            version: VERSION.to_owned(),
+            device_id: DEVICE_ID.to_owned(),
+            systick: 10,
+            mode: Mode::Production,
+            value128: 1,
+            control_capabilities: None,
+            session_nonce: None,
+        });
+        let (telemetry_bytes_tx, telemetry_bytes_rx) = channel::<Vec<u8>>();
+        let (telemetry_messages_tx, telemetry_messages_rx) = channel::<TelemetryChannelType>();
+        std::thread::spawn(|| {
+            gather_telemetry_from_bytes(
+                telemetry_bytes_rx,
+                telemetry_messages_tx,
+                None,
+                None,
+                None,
+                &DecodeConfig::default(),
+                None,
+            )
+        });
+
+        telemetry_bytes_tx.send(boot_message.to_bytes()).unwrap();
+
+        assert_eq!(telemetry_messages_rx.recv().unwrap().unwrap(), boot_message);
+        assert!(matches!(
+            telemetry_messages_rx.recv().unwrap(),
+            Err(Error::TelemetryError(HighLevelError::LinkMisconfigured {
+                value128: 1
+            }))
+        ));
+    }
 }
@@ -0,0 +1,446 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Mutex, MutexGuard};
+use std::time::{Duration, SystemTime};
+
+use crate::capture::FrameOutcome;
+use crate::source::SourceInfo;
+
+/// Number of recent frames used to compute the CRC error rate of a link
+pub const CRC_WINDOW: usize = 100;
+
+/// Minimum number of frames in the window before a link can be considered degraded
+pub const MIN_FRAMES_FOR_DEGRADATION: usize = 20;
+
+/// Share of frames with a CRC error above which a link is considered degraded
+pub const DEGRADED_CRC_RATE: f64 = 0.05;
+
+/// Health of the link to a source of telemetry
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum LinkStatus {
+    /// The transport is opening the link
+    Connecting,
+    /// Frames are received
+    Connected {
+        /// Host wall-clock time at which the link was established
+        since: SystemTime,
+    },
+    /// Frames are received, but too many of them have a CRC error (bad cable, wrong baud rate, interference)
+    Degraded {
+        /// Share of the recent frames that had a CRC error (between 0 and 1)
+        crc_rate: f64,
+    },
+    /// The link failed and the transport will try to open it again
+    Reconnecting {
+        /// Number of consecutive failures, starting at 1
+        attempt: u32,
+        /// Host wall-clock time of the next try
+        next_retry: SystemTime,
+    },
+    /// The link failed; unless it is followed by `Reconnecting`, the transport gave up
+    Failed {
+        /// Description of the failure
+        error: String,
+    },
+}
+
+impl std::fmt::Display for LinkStatus {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::Connecting => write!(f, "connecting"),
+            Self::Connected { since } => {
+                let elapsed = since.elapsed().unwrap_or_default();
+                write!(f, "connected for {}s", elapsed.as_secs())
+            }
+            Self::Degraded { crc_rate } => {
+                write!(f, "degraded ({:.1}% of CRC errors)", crc_rate * 100.0)
+            }
+            Self::Reconnecting {
+                attempt,
+                next_retry,
+            } => {
+                let delay = next_retry
+                    .duration_since(SystemTime::now())
+                    .unwrap_or_default();
+                write!(
+                    f,
+                    "reconnecting (attempt {}, in {}ms)",
+                    attempt,
+                    delay.as_millis()
+                )
+            }
+            Self::Failed { error } => write!(f, "failed: {}", error),
+        }
+    }
+}
+
+/// A change of the status of a link
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct LinkUpdate {
+    /// Source the link leads to
+    pub source: SourceInfo,
+    /// New status of the link
+    pub status: LinkStatus,
+}
+
+#[derive(Debug)]
+struct LinkTracker {
+    source: SourceInfo,
+    status: Option<LinkStatus>,
+    connected_since: Option<SystemTime>,
+    failures: u32,
+    recent_crc_errors: VecDeque<bool>,
+    protocol_version: Option<u8>,
+}
+
+impl LinkTracker {
+    fn crc_rate(&self) -> f64 {
+        let errors = self
+            .recent_crc_errors
+            .iter()
+            .filter(|error| **error)
+            .count();
+        errors as f64 / self.recent_crc_errors.len().max(1) as f64
+    }
+}
+
+struct Links {
+    trackers: Vec<LinkTracker>,
+    subscribers: Vec<Sender<LinkUpdate>>,
+}
+
+static LINKS: Mutex<Links> = Mutex::new(Links {
+    trackers: Vec::new(),
+    subscribers: Vec::new(),
+});
+
+fn links() -> MutexGuard<'static, Links> {
+    // A panic while holding the lock cannot leave statuses in an inconsistent state
+    LINKS
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+impl Links {
+    fn tracker(&mut self, source: &SourceInfo) -> &mut LinkTracker {
+        let index = match self.trackers.iter().position(|t| &t.source == source) {
+            Some(index) => index,
+            None => {
+                self.trackers.push(LinkTracker {
+                    source: source.clone(),
+                    status: None,
+                    connected_since: None,
+                    failures: 0,
+                    recent_crc_errors: VecDeque::with_capacity(CRC_WINDOW),
+                    protocol_version: None,
+                });
+                self.trackers.len() - 1
+            }
+        };
+        &mut self.trackers[index]
+    }
+
+    /// Change the status of a link, and notify subscribers unless only the CRC rate of a degraded link changed
+    fn set(&mut self, source: &SourceInfo, status: LinkStatus) {
+        let tracker = self.tracker(source);
+        let changed = tracker.status.as_ref().map(std::mem::discriminant)
+            != Some(std::mem::discriminant(&status))
+            || matches!(
+                status,
+                LinkStatus::Reconnecting { .. } | LinkStatus::Failed { .. }
+            );
+        tracker.status = Some(status.clone());
+        if changed {
+            let update = LinkUpdate {
+                source: source.clone(),
+                status,
+            };
+            self.subscribers
+                .retain(|subscriber| subscriber.send(update.clone()).is_ok());
+        }
+    }
+}
+
+/// Get the current status of the link to a source, if a transport reported it
+pub fn link_status(source: &SourceInfo) -> Option<LinkStatus> {
+    links()
+        .trackers
+        .iter()
+        .find(|tracker| &tracker.source == source)
+        .and_then(|tracker| tracker.status.clone())
+}
+
+/// Get the current status of the links to every source of the process
+pub fn link_statuses() -> Vec<(SourceInfo, LinkStatus)> {
+    links()
+        .trackers
+        .iter()
+        .filter_map(|tracker| Some((tracker.source.clone(), tracker.status.clone()?)))
+        .collect()
+}
+
+/// Get the version of the telemetry protocol last detected on the link to a source, if a frame was received
+pub fn protocol_version(source: &SourceInfo) -> Option<u8> {
+    links()
+        .trackers
+        .iter()
+        .find(|tracker| &tracker.source == source)
+        .and_then(|tracker| tracker.protocol_version)
+}
+
+/// Get notified of every change of the status of a link
+///
+/// Updates are sent by the threads of transports; dropping the receiver unsubscribes.
+pub fn subscribe_link_status() -> Receiver<LinkUpdate> {
+    let (tx, rx) = channel();
+    links().subscribers.push(tx);
+    rx
+}
+
+pub(crate) fn report_connecting(source: &SourceInfo) {
+    links().set(source, LinkStatus::Connecting);
+}
+
+pub(crate) fn report_connected(source: &SourceInfo) {
+    let mut links = links();
+    let tracker = links.tracker(source);
+    tracker.failures = 0;
+    tracker.recent_crc_errors.clear();
+    // The device may have been updated while disconnected
+    tracker.protocol_version = None;
+    let since = *tracker.connected_since.insert(SystemTime::now());
+    links.set(source, LinkStatus::Connected { since });
+}
+
+/// Report a failure of the link; `retry_in` is the time after which the transport will try again, if it will
+pub(crate) fn report_failed<E: std::fmt::Display>(
+    source: &SourceInfo,
+    error: E,
+    retry_in: Option<Duration>,
+) {
+    let mut links = links();
+    let tracker = links.tracker(source);
+    tracker.connected_since = None;
+    tracker.failures += 1;
+    let attempt = tracker.failures;
+    links.set(
+        source,
+        LinkStatus::Failed {
+            error: error.to_string(),
+        },
+    );
+    if let Some(retry_in) = retry_in {
+        links.set(
+            source,
+            LinkStatus::Reconnecting {
+                attempt,
+                next_retry: SystemTime::now() + retry_in,
+            },
+        );
+    }
+}
+
+/// Report the protocol version of a received frame, which is logged on the first frame and whenever it changes
+pub(crate) fn report_protocol_version(source: &SourceInfo, version: u8) {
+    let mut links = links();
+    let tracker = links.tracker(source);
+    if tracker.protocol_version.replace(version) != Some(version) {
+        log::info!("telemetry protocol v{} detected on {}", version, source);
+    }
+}
+
+pub(crate) fn report_frame(source: &SourceInfo, outcome: FrameOutcome) {
+    let mut links = links();
+    let tracker = links.tracker(source);
+    if tracker.recent_crc_errors.len() >= CRC_WINDOW {
+        tracker.recent_crc_errors.pop_front();
+    }
+    tracker
+        .recent_crc_errors
+        .push_back(outcome == FrameOutcome::CrcError);
+
+    let crc_rate = tracker.crc_rate();
+    let degraded = tracker.recent_crc_errors.len() >= MIN_FRAMES_FOR_DEGRADATION
+        && crc_rate >= DEGRADED_CRC_RATE;
+    // Sources without an explicit connection (files, byte channels) are connected from their first frame
+    let since = *tracker.connected_since.get_or_insert_with(SystemTime::now);
+    let status = if degraded {
+        LinkStatus::Degraded { crc_rate }
+    } else {
+        LinkStatus::Connected { since }
+    };
+    if tracker.status.as_ref() != Some(&status) {
+        links.set(source, status);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::SourceKind;
+
+    #[test]
+    fn tracks_link_status() {
+        let source = SourceInfo::new(SourceKind::Serial, Some("/dev/link-test".to_owned()));
+        let updates = subscribe_link_status();
+
+        report_connecting(&source);
+        report_failed(&source, "no such device", Some(Duration::from_secs(1)));
+        report_connected(&source);
+        assert_eq!(protocol_version(&source), None);
+        report_protocol_version(&source, 2);
+        assert_eq!(protocol_version(&source), Some(2));
+        for i in 0..40 {
+            let outcome = if i % 10 == 0 {
+                FrameOutcome::CrcError
+            } else {
+                FrameOutcome::Parsed
+            };
+            report_frame(&source, outcome);
+        }
+        assert_eq!(
+            link_status(&source),
+            Some(LinkStatus::Degraded { crc_rate: 0.1 })
+        );
+
+        let statuses: Vec<LinkStatus> = updates
+            .try_iter()
+            .filter(|update| update.source == source)
+            .map(|update| update.status)
+            .collect();
+        assert_eq!(statuses.len(), 5);
+        assert_eq!(statuses[0], LinkStatus::Connecting);
+        assert_eq!(
+            statuses[1],
+            LinkStatus::Failed {
+                error: "no such device".to_owned()
+            }
+        );
+        assert!(matches!(
+            statuses[2],
+            LinkStatus::Reconnecting { attempt: 1, .. }
+        ));
+        assert!(matches!(statuses[3], LinkStatus::Connected { .. }));
+        // Only the first change to degraded is notified, not every change of the CRC rate
+        assert!(matches!(statuses[4], LinkStatus::Degraded { .. }));
+    }
+
+    #[test]
+    fn counts_consecutive_failures() {
+        let source = SourceInfo::new(SourceKind::Serial, Some("/dev/link-failures".to_owned()));
+        let updates = subscribe_link_status();
+
+        report_failed(&source, "timeout", Some(Duration::from_secs(1)));
+        report_failed(&source, "timeout", Some(Duration::from_secs(1)));
+        report_connected(&source);
+        report_failed(&source, "timeout", Some(Duration::from_secs(1)));
+        // The transport gives up
+        report_failed(&source, "gone", None);
+        assert_eq!(
+            link_status(&source),
+            Some(LinkStatus::Failed {
+                error: "gone".to_owned()
+            })
+        );
+
+        let attempts: Vec<u32> = updates
+            .try_iter()
+            .filter(|update| update.source == source)
+            .filter_map(|update| match update.status {
+                LinkStatus::Reconnecting { attempt, .. } => Some(attempt),
+                _ => None,
+            })
+            .collect();
+        // Attempts start over once connected
+        assert_eq!(attempts, vec![1, 2, 1]);
+    }
+
+    #[test]
+    fn recovers_from_degradation() {
+        let source = SourceInfo::new(SourceKind::Bytes, Some("link-recovery".to_owned()));
+
+        // Sources without an explicit connection are connected from their first frame, and a few bad frames are not enough to degrade a link
+        for _ in 0..MIN_FRAMES_FOR_DEGRADATION - 1 {
+            report_frame(&source, FrameOutcome::CrcError);
+        }
+        assert!(matches!(
+            link_status(&source),
+            Some(LinkStatus::Connected { .. })
+        ));
+        report_frame(&source, FrameOutcome::CrcError);
+        assert_eq!(
+            link_status(&source),
+            Some(LinkStatus::Degraded { crc_rate: 1.0 })
+        );
+
+        // Errors leave the window as good frames come in, until there are too few of them
+        for _ in 0..CRC_WINDOW - 5 {
+            report_frame(&source, FrameOutcome::Parsed);
+        }
+        assert_eq!(
+            link_status(&source),
+            Some(LinkStatus::Degraded { crc_rate: 0.05 })
+        );
+        report_frame(&source, FrameOutcome::Parsed);
+        assert!(matches!(
+            link_status(&source),
+            Some(LinkStatus::Connected { .. })
+        ));
+    }
+
+    #[test]
+    fn forgets_protocol_version_and_subscribers() {
+        let source = SourceInfo::new(SourceKind::Serial, Some("/dev/link-reconnect".to_owned()));
+        drop(subscribe_link_status());
+
+        report_connected(&source);
+        report_protocol_version(&source, 1);
+        // The device may have been updated while disconnected
+        report_failed(&source, "unplugged", Some(Duration::from_secs(1)));
+        report_connected(&source);
+        assert_eq!(protocol_version(&source), None);
+        report_protocol_version(&source, 2);
+        assert_eq!(protocol_version(&source), Some(2));
+        assert!(link_statuses()
+            .iter()
+            .any(|(s, status)| s == &source && matches!(status, LinkStatus::Connected { .. })));
+    }
+
+    #[test]
+    fn displays_statuses() {
+        assert_eq!(LinkStatus::Connecting.to_string(), "connecting");
+        assert_eq!(
+            LinkStatus::Degraded { crc_rate: 0.125 }.to_string(),
+            "degraded (12.5% of CRC errors)"
+        );
+        // Retries that are already due are not displayed with a negative delay
+        assert_eq!(
+            LinkStatus::Reconnecting {
+                attempt: 3,
+                next_retry: SystemTime::UNIX_EPOCH,
+            }
+            .to_string(),
+            "reconnecting (attempt 3, in 0ms)"
+        );
+        assert_eq!(
+            LinkStatus::Failed {
+                error: "gone".to_owned()
+            }
+            .to_string(),
+            "failed: gone"
+        );
+    }
+}
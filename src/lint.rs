@@ -0,0 +1,580 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::BTreeMap;
+use std::io::Read;
+
+use crate::alarm::{AlarmCodeDescription, AlarmThresholds};
+use crate::control::ControlSetting;
+use crate::parsers::{
+    parse_telemetry_message_with_config, resync_offset, ParserConfig, ParsingMode,
+    DEFAULT_RESYNC_WINDOW,
+};
+use crate::structures::{TelemetryError, TelemetryErrorKind, TelemetryMessage};
+
+/// Maximum interval between two data snapshots while the device is running, in µs
+pub const MAX_DATA_SNAPSHOT_INTERVAL: u64 = 100_000;
+
+/// Maximum interval between two stopped messages while the device is stopped, in µs
+pub const MAX_STOPPED_INTERVAL: u64 = 1_000_000;
+
+const CHUNK_SIZE: usize = 4096;
+
+/// Kind of protocol compliance issue found in a capture
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum LintCategory {
+    /// Bytes that are not part of any frame, or a truncated frame
+    Framing,
+    /// Frame with an invalid CRC
+    Crc,
+    /// Unsupported or inconsistent protocol version
+    ProtocolVersion,
+    /// Message type or enum value unknown to this version of the library
+    UnknownValue,
+    /// Field value out of its allowed range
+    FieldRange,
+    /// Messages out of order (systick or cycle going backwards, missing boot message)
+    Ordering,
+    /// Messages sent too rarely
+    Cadence,
+}
+
+impl LintCategory {
+    /// Name of the category, e.g. `field-range`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Framing => "framing",
+            Self::Crc => "crc",
+            Self::ProtocolVersion => "protocol-version",
+            Self::UnknownValue => "unknown-value",
+            Self::FieldRange => "field-range",
+            Self::Ordering => "ordering",
+            Self::Cadence => "cadence",
+        }
+    }
+}
+
+/// A protocol compliance issue found in a capture
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct LintIssue {
+    /// Kind of issue
+    pub category: LintCategory,
+    /// Offset of the frame (or bytes) in the decoded capture
+    pub offset: u64,
+    /// Systick of the message, if the frame could be decoded
+    pub systick: Option<u64>,
+    /// Description of the issue
+    pub description: String,
+}
+
+impl std::fmt::Display for LintIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "[{}] at byte {}", self.category.name(), self.offset)?;
+        if let Some(systick) = self.systick {
+            write!(f, " (systick {})", systick)?;
+        }
+        write!(f, ": {}", self.description)
+    }
+}
+
+/// Result of the linting of a capture
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct LintReport {
+    /// Number of frames that could be decoded
+    pub frames: usize,
+    /// Issues in the order they were found
+    pub issues: Vec<LintIssue>,
+}
+
+impl LintReport {
+    /// Whether no issue was found
+    pub fn is_clean(&self) -> bool {
+        self.issues.is_empty()
+    }
+
+    /// Issues grouped by category
+    pub fn by_category(&self) -> BTreeMap<LintCategory, Vec<&LintIssue>> {
+        let mut categories: BTreeMap<LintCategory, Vec<&LintIssue>> = BTreeMap::new();
+        for issue in &self.issues {
+            categories.entry(issue.category).or_default().push(issue);
+        }
+        categories
+    }
+}
+
+/// Checker of the protocol compliance of captured firmware output (frame after frame, in order)
+///
+/// Frames are parsed leniently so that unknown values are reported instead of being dropped as garbage.
+pub struct CaptureLinter {
+    buffer: Vec<u8>,
+    offset: u64,
+    garbage: Option<(u64, usize)>,
+    after_bad_frame: bool,
+    report: LintReport,
+    mid_stream: bool,
+    booted: bool,
+    telemetry_version: Option<u8>,
+    last_systick: Option<u64>,
+    last_data_snapshot: Option<u64>,
+    last_stopped: Option<u64>,
+    last_cycle: Option<u32>,
+}
+
+impl Default for CaptureLinter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl CaptureLinter {
+    /// Create a linter for a capture starting at power-on
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            offset: 0,
+            garbage: None,
+            after_bad_frame: false,
+            report: LintReport::default(),
+            mid_stream: false,
+            booted: false,
+            telemetry_version: None,
+            last_systick: None,
+            last_data_snapshot: None,
+            last_stopped: None,
+            last_cycle: None,
+        }
+    }
+
+    /// Create a linter for a capture starting while the device is already running
+    ///
+    /// The missing boot message, and the partial frames at the start and at the end of the capture, are not reported.
+    pub fn mid_stream() -> Self {
+        Self {
+            mid_stream: true,
+            booted: true,
+            ..Self::new()
+        }
+    }
+
+    fn issue(&mut self, category: LintCategory, systick: Option<u64>, description: String) {
+        self.report.issues.push(LintIssue {
+            category,
+            offset: self.offset,
+            systick,
+            description,
+        });
+    }
+
+    fn skip(&mut self, count: usize, garbage: bool) {
+        if garbage {
+            let (_, length) = self.garbage.get_or_insert((self.offset, 0));
+            *length += count;
+        }
+        self.buffer.drain(..count);
+        self.offset += count as u64;
+    }
+
+    fn end_garbage(&mut self) {
+        if let Some((offset, length)) = self.garbage.take() {
+            if self.mid_stream && offset == 0 {
+                return;
+            }
+            self.report.issues.push(LintIssue {
+                category: LintCategory::Framing,
+                offset,
+                systick: None,
+                description: format!("{} bytes outside of any frame", length),
+            });
+        }
+    }
+
+    /// Check the next bytes of the capture
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.push_with(bytes, |_| ())
+    }
+
+    /// Check the next bytes of the capture, and call `on_message` with each decoded message
+    pub fn push_with<F: FnMut(&TelemetryMessage)>(&mut self, bytes: &[u8], mut on_message: F) {
+        self.buffer.extend_from_slice(bytes);
+        let config = ParserConfig::new().mode(ParsingMode::Lenient);
+        while !self.buffer.is_empty() {
+            match parse_telemetry_message_with_config(&self.buffer, &config) {
+                Ok((rest, message)) => {
+                    let length = self.buffer.len() - rest.len();
+                    self.end_garbage();
+                    self.after_bad_frame = false;
+                    self.report.frames += 1;
+                    self.check(&message);
+                    on_message(&message);
+                    self.skip(length, false);
+                }
+                Err(nom::Err::Failure(TelemetryError(_, kind))) => {
+                    self.end_garbage();
+                    match kind {
+                        TelemetryErrorKind::CrcError { expected, computed } => self.issue(
+                            LintCategory::Crc,
+                            None,
+                            format!("invalid CRC: expected={} computed={}", expected, computed),
+                        ),
+                        TelemetryErrorKind::UnsupportedProtocolVersion {
+                            maximum_supported,
+                            found,
+                        } => self.issue(
+                            LintCategory::ProtocolVersion,
+                            None,
+                            format!(
+                                "unsupported protocol version {} (maximum supported is {})",
+                                found, maximum_supported
+                            ),
+                        ),
+                        TelemetryErrorKind::ParserError(_) => (),
+                    }
+                    // The rest of the bad frame is skipped without being reported as garbage
+                    self.after_bad_frame = true;
+                    let count = resync_offset(&self.buffer, DEFAULT_RESYNC_WINDOW);
+                    self.skip(count, false);
+                }
+                Err(nom::Err::Incomplete(_)) => return,
+                Err(nom::Err::Error(_)) => {
+                    let count = resync_offset(&self.buffer, DEFAULT_RESYNC_WINDOW);
+                    let garbage = !self.after_bad_frame;
+                    self.skip(count, garbage);
+                }
+            }
+        }
+    }
+
+    /// Report issues at the end of the capture
+    pub fn finish(mut self) -> LintReport {
+        self.end_garbage();
+        if !self.buffer.is_empty() && !self.mid_stream {
+            let length = self.buffer.len();
+            self.issue(
+                LintCategory::Framing,
+                None,
+                format!("capture ends with a truncated frame ({} bytes)", length),
+            );
+        }
+        if self.report.frames == 0 {
+            self.issue(
+                LintCategory::Framing,
+                None,
+                "no valid frame was found".to_owned(),
+            );
+        }
+        self.report
+    }
+
+    fn check(&mut self, message: &TelemetryMessage) {
+        if let TelemetryMessage::Unknown {
+            type_byte,
+            telemetry_version,
+            ..
+        } = message
+        {
+            self.issue(
+                LintCategory::UnknownValue,
+                None,
+                format!(
+                    "unknown message type {:?} (protocol version {})",
+                    char::from(*type_byte),
+                    telemetry_version
+                ),
+            );
+            return;
+        }
+
+        let systick = message.systick();
+        let at = Some(systick);
+        if message.has_unknown_values() {
+            self.issue(
+                LintCategory::UnknownValue,
+                at,
+                format!("{:?} contains unknown enum values", message),
+            );
+        }
+
+        let version = message.telemetry_version();
+        if let Some(previous) = self
+            .telemetry_version
+            .filter(|previous| *previous != version)
+        {
+            self.issue(
+                LintCategory::ProtocolVersion,
+                at,
+                format!("protocol version changed from {} to {}", previous, version),
+            );
+        }
+        self.telemetry_version = Some(version);
+
+        self.check_ordering(message, systick);
+        self.check_cadence(message, systick);
+        self.check_ranges(message, systick);
+    }
+
+    fn check_ordering(&mut self, message: &TelemetryMessage, systick: u64) {
+        let at = Some(systick);
+        if let TelemetryMessage::BootMessage(_) = message {
+            // The MCU rebooted: systicks and cycles start over
+            self.booted = true;
+            self.last_systick = None;
+            self.last_data_snapshot = None;
+            self.last_stopped = None;
+            self.last_cycle = None;
+        } else if !self.booted {
+            self.booted = true;
+            self.issue(
+                LintCategory::Ordering,
+                at,
+                "capture does not start with a boot message".to_owned(),
+            );
+        }
+
+        if let Some(previous) = self.last_systick.filter(|previous| *previous > systick) {
+            self.issue(
+                LintCategory::Ordering,
+                at,
+                format!("systick went backwards from {} to {}", previous, systick),
+            );
+        }
+        self.last_systick = Some(systick);
+
+        if let TelemetryMessage::MachineStateSnapshot(snapshot) = message {
+            match self.last_cycle {
+                Some(previous) if snapshot.cycle <= previous => self.issue(
+                    LintCategory::Ordering,
+                    at,
+                    format!(
+                        "cycle number did not increase ({} after {})",
+                        snapshot.cycle, previous
+                    ),
+                ),
+                Some(previous) if snapshot.cycle > previous + 1 => self.issue(
+                    LintCategory::Ordering,
+                    at,
+                    format!(
+                        "cycles {} to {} have no machine state snapshot",
+                        previous + 1,
+                        snapshot.cycle - 1
+                    ),
+                ),
+                _ => (),
+            }
+            self.last_cycle = Some(snapshot.cycle);
+        }
+    }
+
+    fn check_cadence(&mut self, message: &TelemetryMessage, systick: u64) {
+        let (last, max_interval, name) = match message {
+            TelemetryMessage::DataSnapshot(_) => {
+                self.last_stopped = None;
+                (
+                    &mut self.last_data_snapshot,
+                    MAX_DATA_SNAPSHOT_INTERVAL,
+                    "data snapshots",
+                )
+            }
+            TelemetryMessage::StoppedMessage(_) => {
+                self.last_data_snapshot = None;
+                (
+                    &mut self.last_stopped,
+                    MAX_STOPPED_INTERVAL,
+                    "stopped messages",
+                )
+            }
+            _ => return,
+        };
+        let interval = last.map(|last| systick.saturating_sub(last));
+        *last = Some(systick);
+        if let Some(interval) = interval.filter(|interval| *interval > max_interval) {
+            self.issue(
+                LintCategory::Cadence,
+                Some(systick),
+                format!(
+                    "{} µs between {} (maximum is {} µs)",
+                    interval, name, max_interval
+                ),
+            );
+        }
+    }
+
+    fn check_ranges(&mut self, message: &TelemetryMessage, systick: u64) {
+        let mut values: Vec<(ControlSetting, Option<u16>)> = Vec::new();
+        match message {
+            TelemetryMessage::BootMessage(boot) => {
+                if let Err(e) = boot.check_link() {
+                    self.issue(LintCategory::FieldRange, Some(systick), e.to_string());
+                }
+            }
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                values.extend(AlarmThresholds::from_machine_state(snapshot).values());
+                values.push((
+                    ControlSetting::CyclesPerMinute,
+                    Some(snapshot.cpm_command.into()),
+                ));
+                values.push((
+                    ControlSetting::PEEP,
+                    Some(u16::from(snapshot.peep_command) * 10),
+                ));
+            }
+            TelemetryMessage::StoppedMessage(stopped) => {
+                values.extend(AlarmThresholds::from_stopped_message(stopped).values());
+                values.push((
+                    ControlSetting::CyclesPerMinute,
+                    stopped.cpm_command.map(u16::from),
+                ));
+                values.push((
+                    ControlSetting::PEEP,
+                    stopped.peep_command.map(|peep| u16::from(peep) * 10),
+                ));
+            }
+            _ => (),
+        }
+
+        for (setting, value) in values {
+            let Some(value) = value else {
+                continue;
+            };
+            let bounds = setting.bounds();
+            if !bounds.contains(&usize::from(value)) {
+                self.issue(
+                    LintCategory::FieldRange,
+                    Some(systick),
+                    format!(
+                        "{:?} is {} (allowed range is {} to {})",
+                        setting,
+                        value,
+                        bounds.start(),
+                        bounds.end()
+                    ),
+                );
+            }
+        }
+        for code in message.alarm_codes() {
+            if let AlarmCodeDescription::Unknown(code) = code.description() {
+                self.issue(
+                    LintCategory::FieldRange,
+                    Some(systick),
+                    format!("unknown alarm code {}", code),
+                );
+            }
+        }
+    }
+}
+
+/// Check the protocol compliance of a whole capture
+///
+/// * `reader` - Decoded capture (raw bytes sent by the firmware; use `recording::Base64Decoder` for recordings).
+pub fn lint_capture<R: Read>(mut reader: R) -> std::io::Result<LintReport> {
+    let mut linter = CaptureLinter::new();
+    let mut chunk = [0; CHUNK_SIZE];
+    loop {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => linter.push(&chunk[..n]),
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => (),
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(linter.finish())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+
+    fn simulated_capture(messages: usize) -> Vec<u8> {
+        SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .take(messages)
+            .flat_map(|message| message.to_bytes())
+            .collect()
+    }
+
+    #[test]
+    fn simulated_firmware_is_compliant() {
+        let report = lint_capture(&simulated_capture(2_000)[..]).unwrap();
+        assert_eq!(report.frames, 2_000);
+        assert!(report.is_clean(), "{:?}", report.issues);
+    }
+
+    #[test]
+    fn reports_categorized_issues() {
+        let frames: Vec<Vec<u8>> = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .take(20)
+            .map(|message| message.to_bytes())
+            .collect();
+        let mut linter = CaptureLinter::new();
+        for frame in &frames[..5] {
+            linter.push(frame);
+        }
+        linter.push(b"garbage");
+        // Corrupt the CRC of the next frame (right before the footer)
+        let mut corrupted = frames[5].clone();
+        let crc_byte = corrupted.len() - 3;
+        corrupted[crc_byte] ^= 0xFF;
+        linter.push(&corrupted);
+        for frame in &frames[6..19] {
+            linter.push(frame);
+        }
+        linter.push(&frames[19][..10]);
+        let report = linter.finish();
+
+        assert_eq!(report.frames, 18);
+        let categories = report.by_category();
+        assert_eq!(categories[&LintCategory::Crc].len(), 1);
+        let framing: Vec<&str> = categories[&LintCategory::Framing]
+            .iter()
+            .map(|issue| issue.description.as_str())
+            .collect();
+        assert_eq!(
+            framing,
+            [
+                "7 bytes outside of any frame",
+                "capture ends with a truncated frame (10 bytes)"
+            ]
+        );
+        assert_eq!(categories.len(), 2);
+    }
+
+    #[test]
+    fn reports_out_of_range_and_missing_messages() {
+        let mut messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(1_000)
+                .collect();
+        let snapshot = messages
+            .iter()
+            .position(|message| matches!(message, TelemetryMessage::MachineStateSnapshot(_)))
+            .unwrap();
+        if let TelemetryMessage::MachineStateSnapshot(snapshot) = &mut messages[snapshot] {
+            snapshot.cpm_command = 200;
+        }
+        // Data snapshots stop for a while
+        messages.drain(snapshot + 1..snapshot + 11);
+        let capture: Vec<u8> = messages.iter().flat_map(|m| m.to_bytes()).collect();
+        let report = lint_capture(&capture[..]).unwrap();
+
+        let categories = report.by_category();
+        assert_eq!(categories.len(), 2, "{:?}", report.issues);
+        assert!(categories[&LintCategory::FieldRange][0]
+            .description
+            .starts_with("CyclesPerMinute is 200"));
+        assert_eq!(categories[&LintCategory::Cadence].len(), 1);
+    }
+}
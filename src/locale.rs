@@ -6,6 +6,8 @@
 use std::convert::TryFrom;
 use std::ops::RangeInclusive;
 
+use crate::control::{ControlMessage, ControlSetting};
+
 /// An ISO 639-1 language code to be used to choose language for the whole system
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(
@@ -17,10 +19,7 @@ pub struct Locale(u16);
 impl Locale {
     /// Create a locale from a u16
     pub fn try_from_u16(num: u16) -> Option<Self> {
-        match Self::try_from(Self(num).to_string().as_str()) {
-            Ok(locale) => Some(locale),
-            Err(_) => None,
-        }
+        Self::try_from(Self(num).to_string().as_str()).ok()
     }
 
     /// Language code as a u16
@@ -33,6 +32,14 @@ impl Locale {
         self.0.into()
     }
 
+    /// Control message asking the firmware to use this locale
+    pub fn to_control_message(&self) -> ControlMessage {
+        ControlMessage {
+            setting: ControlSetting::Locale,
+            value: self.as_u16(),
+        }
+    }
+
     /// Allowed value bounds (this is not really correct/useful)
     pub fn bounds() -> RangeInclusive<usize> {
         RangeInclusive::new(
@@ -42,6 +49,23 @@ impl Locale {
     }
 }
 
+/// Choose the locale the whole system should use
+///
+/// * `ui_preferred` - Locales wanted by the UI, most preferred first.
+/// * `firmware_supported` - Locales supported by the firmware.
+///
+/// This is the first preferred locale supported by the firmware; otherwise the default locale if it is supported, and then the first supported locale.
+/// The default locale is returned if the firmware does not tell which locales it supports.
+pub fn negotiate(ui_preferred: &[Locale], firmware_supported: &[Locale]) -> Locale {
+    ui_preferred
+        .iter()
+        .chain(std::iter::once(&Locale::default()))
+        .find(|locale| firmware_supported.contains(locale))
+        .or_else(|| firmware_supported.first())
+        .copied()
+        .unwrap_or_default()
+}
+
 impl TryFrom<&str> for Locale {
     type Error = &'static str;
 
@@ -72,7 +96,8 @@ impl std::fmt::Display for Locale {
 
 #[cfg(test)]
 mod tests {
-    use super::Locale;
+    use super::{negotiate, Locale};
+    use crate::control::{ControlMessage, ControlSetting};
 
     use proptest::prelude::*;
     use std::convert::TryFrom;
@@ -110,6 +135,23 @@ mod tests {
         assert_eq!(Locale(FR).to_string().as_str(), "fr")
     }
 
+    #[test]
+    fn negotiation() {
+        let [en, fr, de, it] = ["en", "fr", "de", "it"].map(|code| Locale::try_from(code).unwrap());
+
+        assert_eq!(negotiate(&[it, fr], &[en, fr, de]), fr);
+        assert_eq!(negotiate(&[it], &[de, en]), en);
+        assert_eq!(negotiate(&[it], &[de, fr]), de);
+        assert_eq!(negotiate(&[it], &[]), en);
+        assert_eq!(
+            negotiate(&[fr], &[fr]).to_control_message(),
+            ControlMessage {
+                setting: ControlSetting::Locale,
+                value: FR,
+            }
+        );
+    }
+
     proptest! {
         #[test]
         fn back_and_forth(ui_locale in ui_locale_strategy()) {
@@ -0,0 +1,129 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::Read;
+use std::path::Path;
+
+use sha2::{Digest, Sha256};
+
+/// Value of `message_type` of the manifest record of JSON exports
+pub const MANIFEST_MESSAGE_TYPE: &str = "Manifest";
+
+/// Provenance of an export, embedded into it so that a derived dataset can be traced back to the raw recording and tool version that produced it
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExportManifest {
+    /// File name of the source recording
+    pub source_file: String,
+    /// SHA-256 of the source recording, in hexadecimal
+    pub source_sha256: String,
+    /// Version of this crate
+    pub crate_version: String,
+    /// Conversion parameters (name and value), in the order they were added
+    pub parameters: Vec<(String, String)>,
+}
+
+impl ExportManifest {
+    /// Describe an export of a recording, hashing the recording
+    pub fn for_recording<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        let mut file = File::open(path)?;
+        let mut hasher = Sha256::new();
+        let mut buffer = [0; 64 * 1024];
+        loop {
+            let read = file.read(&mut buffer)?;
+            if read == 0 {
+                break;
+            }
+            hasher.update(&buffer[..read]);
+        }
+
+        Ok(Self {
+            source_file: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            source_sha256: hasher
+                .finalize()
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+            crate_version: env!("CARGO_PKG_VERSION").to_owned(),
+            parameters: Vec::new(),
+        })
+    }
+
+    /// Add a conversion parameter
+    pub fn parameter(mut self, name: &str, value: impl ToString) -> Self {
+        self.parameters.push((name.to_owned(), value.to_string()));
+        self
+    }
+
+    /// Every field as `name: value` lines, to be written as comments
+    pub fn lines(&self) -> Vec<String> {
+        let mut lines = vec![
+            format!("source_file: {}", self.source_file),
+            format!("source_sha256: {}", self.source_sha256),
+            format!("crate_version: {}", self.crate_version),
+        ];
+        lines.extend(
+            self.parameters
+                .iter()
+                .map(|(name, value)| format!("parameter.{}: {}", name, value)),
+        );
+        lines
+    }
+
+    /// Render as a JSON record (with a `Manifest` message type) to write before the messages of a JSON export
+    pub fn to_json_record(&self) -> String {
+        let parameters: serde_json::Map<String, serde_json::Value> = self
+            .parameters
+            .iter()
+            .map(|(name, value)| (name.clone(), value.clone().into()))
+            .collect();
+        let mut record = serde_json::json!({
+            "message_type": MANIFEST_MESSAGE_TYPE,
+            "source_file": self.source_file,
+            "source_sha256": self.source_sha256,
+            "crate_version": self.crate_version,
+            "parameters": parameters,
+        })
+        .to_string();
+        record.push('\n');
+        record
+    }
+
+    /// Render as comment lines to write before the data points of a GTS or InfluxDB line protocol export
+    pub fn to_comment_lines(&self) -> String {
+        self.lines()
+            .iter()
+            .map(|line| format!("# {}\n", line))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn describes_a_recording() {
+        let manifest = ExportManifest::for_recording("records/v2/short.record")
+            .unwrap()
+            .parameter("format", "json");
+        assert_eq!(manifest.source_file, "short.record");
+        assert_eq!(manifest.source_sha256.len(), 64);
+        assert_eq!(manifest.crate_version, env!("CARGO_PKG_VERSION"));
+
+        let record: serde_json::Value = serde_json::from_str(&manifest.to_json_record()).unwrap();
+        assert_eq!(record["message_type"], MANIFEST_MESSAGE_TYPE);
+        assert_eq!(record["source_sha256"], manifest.source_sha256.as_str());
+        assert_eq!(record["parameters"]["format"], "json");
+        assert!(manifest
+            .to_comment_lines()
+            .lines()
+            .all(|line| line.starts_with("# ")));
+    }
+}
@@ -0,0 +1,347 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::{BufRead, Write};
+
+use crate::structures::{DataSnapshot, Phase, TelemetryMessage};
+
+/// Default maximum distance between a data snapshot and the external sample it is merged with, in µs
+pub const DEFAULT_TOLERANCE: u64 = 20_000;
+
+/// Errors that can happen while merging telemetry with an external CSV
+#[derive(Debug, thiserror::Error)]
+pub enum MergeError {
+    /// Reading the CSV or writing the merged dataset failed
+    #[error("{0}")]
+    Io(#[from] std::io::Error),
+    /// The CSV has no header line
+    #[error("external CSV is empty")]
+    Empty,
+    /// The timestamp column is not in the header line
+    #[error("external CSV has no column named {0:?}")]
+    MissingColumn(String),
+    /// A timestamp is not a number of seconds since UNIX epoch
+    #[error("line {line}: invalid timestamp {value:?}")]
+    InvalidTimestamp {
+        /// Line of the CSV (starting at 1)
+        line: usize,
+        /// Content of the timestamp cell
+        value: String,
+    },
+    /// Timestamps of the CSV go backwards
+    #[error("line {line}: timestamps of the external CSV must be sorted")]
+    Unsorted {
+        /// Line of the CSV (starting at 1)
+        line: usize,
+    },
+}
+
+/// One row of an external CSV
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ExternalRow {
+    /// Wall-clock time of the row, in microseconds since UNIX epoch
+    pub timestamp: i64,
+    /// Values of the other columns, in the order of `ExternalCsv::columns()`
+    pub values: Vec<String>,
+}
+
+/// Parse a number of seconds since UNIX epoch (e.g. `1600000000.125`) into microseconds, without rounding errors
+fn parse_timestamp(value: &str) -> Option<i64> {
+    let (seconds, fraction) = value.split_once('.').unwrap_or((value, ""));
+    if fraction.len() > 6 || !fraction.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    let seconds: i64 = seconds.parse().ok()?;
+    let micros: i64 = format!("{:0<6}", fraction).parse().ok()?;
+    seconds.checked_mul(1_000_000)?.checked_add(micros)
+}
+
+fn split_cells(line: &str, delimiter: char) -> Vec<String> {
+    line.split(delimiter)
+        .map(|cell| cell.trim().trim_matches('"').to_owned())
+        .collect()
+}
+
+/// Streaming reader of a CSV written by an external device (e.g. a reference flow analyzer)
+///
+/// The first line must be a header. One column holds the wall-clock time of rows as a number of seconds since UNIX epoch (with an optional fraction), and rows must be sorted by time.
+/// Quoted cells cannot contain the delimiter.
+pub struct ExternalCsv<R> {
+    lines: std::io::Lines<R>,
+    line: usize,
+    delimiter: char,
+    timestamp_column: usize,
+    columns: Vec<String>,
+    last_timestamp: Option<i64>,
+}
+
+impl<R: BufRead> ExternalCsv<R> {
+    /// Read the header of a CSV
+    ///
+    /// * `timestamp_column` - Name of the column holding timestamps, or `None` to use the first column.
+    /// * `delimiter` - Character separating cells, usually `,` or `;`.
+    pub fn new(
+        reader: R,
+        timestamp_column: Option<&str>,
+        delimiter: char,
+    ) -> Result<Self, MergeError> {
+        let mut lines = reader.lines();
+        let header = lines.next().ok_or(MergeError::Empty)??;
+        let mut columns = split_cells(header.trim_start_matches('\u{feff}'), delimiter);
+        let timestamp_column = match timestamp_column {
+            Some(name) => columns
+                .iter()
+                .position(|column| column == name)
+                .ok_or_else(|| MergeError::MissingColumn(name.to_owned()))?,
+            None => 0,
+        };
+        columns.remove(timestamp_column);
+        Ok(Self {
+            lines,
+            line: 1,
+            delimiter,
+            timestamp_column,
+            columns,
+            last_timestamp: None,
+        })
+    }
+
+    /// Names of the columns other than the timestamp
+    pub fn columns(&self) -> &[String] {
+        &self.columns
+    }
+}
+
+impl<R: BufRead> Iterator for ExternalCsv<R> {
+    type Item = Result<ExternalRow, MergeError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        loop {
+            let line = match self.lines.next()? {
+                Ok(line) => line,
+                Err(e) => return Some(Err(e.into())),
+            };
+            self.line += 1;
+            if line.trim().is_empty() {
+                continue;
+            }
+            let mut values = split_cells(&line, self.delimiter);
+            values.resize(self.columns.len() + 1, String::new());
+            let value = values.remove(self.timestamp_column);
+            let Some(timestamp) = parse_timestamp(&value) else {
+                return Some(Err(MergeError::InvalidTimestamp {
+                    line: self.line,
+                    value,
+                }));
+            };
+            if self.last_timestamp.is_some_and(|last| timestamp < last) {
+                return Some(Err(MergeError::Unsorted { line: self.line }));
+            }
+            self.last_timestamp = Some(timestamp);
+            return Some(Ok(ExternalRow { timestamp, values }));
+        }
+    }
+}
+
+/// Counts of rows written by a `CsvMerger`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MergeSummary {
+    /// Number of data snapshots written
+    pub rows: usize,
+    /// Number of data snapshots that were matched with an external row
+    pub matched: usize,
+}
+
+/// Writer of a dataset merging data snapshots with the nearest row of an external CSV
+///
+/// Every data snapshot gives one CSV row with its wall-clock time (systick plus clock offset), systick, pressure, flows and phase,
+/// followed by the offset to the matched external row and its values (left empty when no external row is within the tolerance).
+/// Both inputs are read as a stream, so recordings and CSVs of any size can be merged.
+pub struct CsvMerger<R, W: Write> {
+    external: ExternalCsv<R>,
+    writer: W,
+    clock_offset: i64,
+    tolerance: u64,
+    previous: Option<ExternalRow>,
+    next: Option<ExternalRow>,
+    started: bool,
+    summary: MergeSummary,
+}
+
+impl<R: BufRead, W: Write> CsvMerger<R, W> {
+    /// Write the header of the merged dataset
+    ///
+    /// * `clock_offset` - Offset in microseconds to add to systicks to get wall-clock times (as logged by debug mode with time sync).
+    pub fn new(
+        external: ExternalCsv<R>,
+        mut writer: W,
+        clock_offset: i64,
+    ) -> Result<Self, MergeError> {
+        write!(
+            writer,
+            "timestamp,systick,pressure,inspiratory_flow,expiratory_flow,phase,external_offset"
+        )?;
+        for column in external.columns() {
+            write!(writer, ",{}", column)?;
+        }
+        writeln!(writer)?;
+        Ok(Self {
+            external,
+            writer,
+            clock_offset,
+            tolerance: DEFAULT_TOLERANCE,
+            previous: None,
+            next: None,
+            started: false,
+            summary: MergeSummary::default(),
+        })
+    }
+
+    /// Choose the maximum distance between a data snapshot and the external row it is merged with, in µs
+    pub fn tolerance(mut self, tolerance: u64) -> Self {
+        self.tolerance = tolerance;
+        self
+    }
+
+    /// Move forward in the external CSV so that `previous` is at or before `timestamp` and `next` is after it
+    fn advance(&mut self, timestamp: i64) -> Result<(), MergeError> {
+        if !self.started {
+            self.started = true;
+            self.next = self.external.next().transpose()?;
+        }
+        while self
+            .next
+            .as_ref()
+            .is_some_and(|next| next.timestamp <= timestamp)
+        {
+            self.previous = self.next.take();
+            self.next = self.external.next().transpose()?;
+        }
+        Ok(())
+    }
+
+    fn nearest(&self, timestamp: i64) -> Option<&ExternalRow> {
+        [self.previous.as_ref(), self.next.as_ref()]
+            .into_iter()
+            .flatten()
+            .filter(|row| row.timestamp.abs_diff(timestamp) <= self.tolerance)
+            .min_by_key(|row| row.timestamp.abs_diff(timestamp))
+    }
+
+    /// Merge a message; only data snapshots are written
+    pub fn push(&mut self, message: &TelemetryMessage) -> Result<(), MergeError> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => self.push_snapshot(snapshot),
+            _ => Ok(()),
+        }
+    }
+
+    fn push_snapshot(&mut self, snapshot: &DataSnapshot) -> Result<(), MergeError> {
+        let timestamp = snapshot.systick as i64 + self.clock_offset;
+        self.advance(timestamp)?;
+
+        let optional = |value: Option<i16>| value.map(|v| v.to_string()).unwrap_or_default();
+        let phase = match snapshot.phase {
+            Phase::Inhalation => "inhalation",
+            Phase::Exhalation => "exhalation",
+        };
+        let mut line = format!(
+            "{}.{:06},{},{},{},{},{}",
+            timestamp.div_euclid(1_000_000),
+            timestamp.rem_euclid(1_000_000),
+            snapshot.systick,
+            snapshot.pressure,
+            optional(snapshot.inspiratory_flow),
+            optional(snapshot.expiratory_flow),
+            phase
+        );
+        match self.nearest(timestamp) {
+            Some(row) => {
+                line.push_str(&format!(",{}", row.timestamp - timestamp));
+                for value in &row.values {
+                    line.push(',');
+                    line.push_str(value);
+                }
+                self.summary.matched += 1;
+            }
+            None => line.push_str(&",".repeat(self.external.columns().len() + 1)),
+        }
+        writeln!(self.writer, "{}", line)?;
+        self.summary.rows += 1;
+        Ok(())
+    }
+
+    /// Flush the merged dataset and count written rows
+    pub fn finish(mut self) -> Result<MergeSummary, MergeError> {
+        self.writer.flush()?;
+        Ok(self.summary)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn snapshot(systick: u64, pressure: i16) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            centile: 0,
+            pressure,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow: Some(12),
+            expiratory_flow: None,
+        })
+    }
+
+    #[test]
+    fn parses_timestamps() {
+        assert_eq!(parse_timestamp("1600000000"), Some(1_600_000_000_000_000));
+        assert_eq!(
+            parse_timestamp("1600000000.25"),
+            Some(1_600_000_000_250_000)
+        );
+        assert_eq!(parse_timestamp("1600000000.1234567"), None);
+        assert_eq!(parse_timestamp("12:00:00"), None);
+    }
+
+    #[test]
+    fn merges_nearest_external_row() {
+        let csv = "flow;time;volume\n10.5;1600000000.005;300\n11.0;1600000000.030;310\n";
+        let external = ExternalCsv::new(csv.as_bytes(), Some("time"), ';').unwrap();
+        assert_eq!(external.columns(), ["flow", "volume"]);
+
+        let mut output = Vec::new();
+        let offset = 1_600_000_000_000_000;
+        let mut merger = CsvMerger::new(external, &mut output, offset)
+            .unwrap()
+            .tolerance(10_000);
+        for systick in [0, 10_000, 20_000, 50_000] {
+            merger.push(&snapshot(systick, 50)).unwrap();
+        }
+        assert_eq!(
+            merger.finish().unwrap(),
+            MergeSummary {
+                rows: 4,
+                matched: 3
+            }
+        );
+        assert_eq!(
+            String::from_utf8(output).unwrap(),
+            "timestamp,systick,pressure,inspiratory_flow,expiratory_flow,phase,external_offset,flow,volume\n\
+             1600000000.000000,0,50,12,,inhalation,5000,10.5,300\n\
+             1600000000.010000,10000,50,12,,inhalation,-5000,10.5,300\n\
+             1600000000.020000,20000,50,12,,inhalation,10000,11.0,310\n\
+             1600000000.050000,50000,50,12,,inhalation,,,\n"
+        );
+    }
+}
@@ -0,0 +1,203 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use url::Url;
+
+use crate::eol::EolSession;
+use crate::http;
+
+/// Error that can happen while sending a test session to a MES endpoint
+#[derive(Debug, thiserror::Error)]
+pub enum MesError {
+    /// Talking to the endpoint failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The endpoint answered with a non-2xx HTTP status
+    #[error("unexpected HTTP status {status} from {url}: {body}")]
+    Http {
+        /// URL of the request
+        url: String,
+        /// Status of the response
+        status: u16,
+        /// Body of the response, which usually explains why the session was rejected
+        body: String,
+    },
+}
+
+/// Render an end of line test session as the JSON document sent to MES endpoints
+///
+/// Steps are identified by their name and their code in the telemetry protocol; absent values are `null`.
+pub fn session_to_json(session: &EolSession) -> String {
+    let steps: Vec<serde_json::Value> = session
+        .steps
+        .iter()
+        .map(|step| {
+            serde_json::json!({
+                "step": format!("{:?}", step.step),
+                "step_code": u8::from(&step.step),
+                "outcome": step.outcome.name(),
+                "started_at": step.started_at,
+                "ended_at": step.ended_at,
+                "confirmed_at": step.confirmed_at,
+                "message": step.message,
+                "measurements": {
+                    "min_pressure": step.min_pressure,
+                    "max_pressure": step.max_pressure,
+                    "max_inspiratory_flow": step.max_inspiratory_flow,
+                },
+            })
+        })
+        .collect();
+    serde_json::json!({
+        "device_id": session.device_id,
+        "firmware_version": session.firmware_version,
+        "outcome": session.outcome.name(),
+        "started_at": session.started_at(),
+        "ended_at": session.ended_at(),
+        "crate_version": env!("CARGO_PKG_VERSION"),
+        "steps": steps,
+    })
+    .to_string()
+}
+
+/// REST endpoint of a manufacturing execution system (MES), receiving each end of line test session as a JSON document (see `session_to_json()`)
+///
+/// The URL may contain `{device_id}`, replaced by the device ID of each session (e.g. `http://mes:8080/units/{device_id}/eol`).
+/// HTTPS is not supported: use a local reverse proxy to reach remote servers.
+#[derive(Debug, Clone)]
+pub struct MesEndpoint {
+    url: String,
+    method: &'static str,
+    headers: Vec<(String, String)>,
+}
+
+impl MesEndpoint {
+    /// Create an endpoint receiving sessions with `POST` requests
+    pub fn new(url: &str) -> Self {
+        Self {
+            url: url.to_owned(),
+            method: "POST",
+            headers: Vec::new(),
+        }
+    }
+
+    /// Send sessions with `PUT` requests instead, e.g. when the URL identifies the device
+    pub fn put(mut self) -> Self {
+        self.method = "PUT";
+        self
+    }
+
+    /// Add a header to every request (e.g. `Authorization`)
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    /// URL sessions of a device are sent to
+    pub fn url_for(&self, device_id: &str) -> Result<Url, url::ParseError> {
+        Url::parse(&self.url.replace("{device_id}", device_id))
+    }
+
+    /// Send a session; any 2xx status is a success
+    pub fn send(&self, session: &EolSession) -> Result<(), MesError> {
+        let url = self
+            .url_for(&session.device_id)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, format!("{}", e)))?;
+        let mut headers: Vec<(&str, String)> =
+            vec![("Content-Type", "application/json".to_owned())];
+        headers.extend(
+            self.headers
+                .iter()
+                .map(|(name, value)| (name.as_str(), value.clone())),
+        );
+        let response = http::request(
+            self.method,
+            &url,
+            &headers,
+            session_to_json(session).as_bytes(),
+        )?;
+        if (200..300).contains(&response.status) {
+            Ok(())
+        } else {
+            Err(MesError::Http {
+                url: url.to_string(),
+                status: response.status,
+                body: String::from_utf8_lossy(&response.body).into_owned(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::eol::{EolOutcome, EolStepResult};
+    use crate::structures::EolTestStep;
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+
+    #[test]
+    fn sends_sessions_to_endpoint() {
+        let session = EolSession {
+            device_id: "1-2-3".to_owned(),
+            firmware_version: "test".to_owned(),
+            outcome: EolOutcome::Passed,
+            steps: vec![EolStepResult {
+                step: EolTestStep::END_SUCCESS,
+                outcome: EolOutcome::Passed,
+                started_at: 10,
+                ended_at: 20,
+                message: "OK".to_owned(),
+                confirmed_at: None,
+                min_pressure: Some(0),
+                max_pressure: Some(700),
+                max_inspiratory_flow: None,
+            }],
+        };
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut reader = BufReader::new(stream);
+            let mut request_line = String::new();
+            reader.read_line(&mut request_line).unwrap();
+            let mut length = 0;
+            loop {
+                let mut line = String::new();
+                reader.read_line(&mut line).unwrap();
+                if line.trim().is_empty() {
+                    break;
+                }
+                if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                    length = value.trim().parse().unwrap();
+                }
+            }
+            let mut body = vec![0; length];
+            reader.read_exact(&mut body).unwrap();
+            reader
+                .get_mut()
+                .write_all(b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\n\r\n")
+                .unwrap();
+            (request_line, body)
+        });
+
+        MesEndpoint::new(&format!(
+            "http://127.0.0.1:{}/units/{{device_id}}/eol",
+            port
+        ))
+        .header("Authorization", "Bearer token")
+        .send(&session)
+        .unwrap();
+        let (request_line, body) = server.join().unwrap();
+        assert_eq!(request_line, "POST /units/1-2-3/eol HTTP/1.1\r\n");
+        let document: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert_eq!(document["outcome"], "passed");
+        assert_eq!(document["started_at"], 10);
+        assert_eq!(document["steps"][0]["step"], "END_SUCCESS");
+        assert_eq!(document["steps"][0]["measurements"]["max_pressure"], 700);
+        assert!(document["steps"][0]["confirmed_at"].is_null());
+    }
+}
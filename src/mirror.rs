@@ -0,0 +1,215 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::Write;
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Health of one copy written by a `MirrorWriter`
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct CopyStatus {
+    /// Name given to the copy (e.g. "sd" or "usb")
+    pub name: String,
+    /// Whether the copy still receives data
+    pub healthy: bool,
+    /// Number of bytes successfully written to the copy
+    pub bytes_written: u64,
+    /// Error that made the copy unhealthy
+    pub error: Option<String>,
+}
+
+impl CopyStatus {
+    fn new(name: &str) -> Self {
+        Self {
+            name: name.to_owned(),
+            healthy: true,
+            bytes_written: 0,
+            error: None,
+        }
+    }
+}
+
+fn lock(statuses: &Mutex<Vec<CopyStatus>>) -> MutexGuard<'_, Vec<CopyStatus>> {
+    statuses
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Handle reporting the health of the copies of a `MirrorWriter`, usable from any thread
+#[derive(Debug, Clone)]
+pub struct MirrorStatus {
+    statuses: Arc<Mutex<Vec<CopyStatus>>>,
+}
+
+impl MirrorStatus {
+    /// Status of every copy, in the order they were given to the writer
+    pub fn copies(&self) -> Vec<CopyStatus> {
+        lock(&self.statuses).clone()
+    }
+
+    /// Status of the copy with this name
+    pub fn copy(&self, name: &str) -> Option<CopyStatus> {
+        lock(&self.statuses)
+            .iter()
+            .find(|status| status.name == name)
+            .cloned()
+    }
+
+    /// Number of copies that still receive data
+    pub fn healthy_count(&self) -> usize {
+        lock(&self.statuses)
+            .iter()
+            .filter(|status| status.healthy)
+            .count()
+    }
+
+    /// Whether every copy still receives data
+    pub fn all_healthy(&self) -> bool {
+        lock(&self.statuses).iter().all(|status| status.healthy)
+    }
+}
+
+/// Writer duplicating everything to two destinations (e.g. an SD card and a USB stick), usually given to a `RecordingWriter`
+///
+/// Copies fail independently: after its first error, a copy stops receiving data and is reported as unhealthy, while the other one keeps going.
+/// Writing only fails once no copy is healthy anymore.
+pub struct MirrorWriter {
+    writers: Vec<Option<Box<dyn Write + Send>>>,
+    statuses: Arc<Mutex<Vec<CopyStatus>>>,
+}
+
+impl MirrorWriter {
+    /// Create a writer mirroring data to two named destinations
+    pub fn new<P, S>(primary_name: &str, primary: P, secondary_name: &str, secondary: S) -> Self
+    where
+        P: Write + Send + 'static,
+        S: Write + Send + 'static,
+    {
+        Self {
+            writers: vec![Some(Box::new(primary)), Some(Box::new(secondary))],
+            statuses: Arc::new(Mutex::new(vec![
+                CopyStatus::new(primary_name),
+                CopyStatus::new(secondary_name),
+            ])),
+        }
+    }
+
+    /// Handle reporting which copies are healthy; it stays valid once the writer was moved to another thread
+    pub fn status(&self) -> MirrorStatus {
+        MirrorStatus {
+            statuses: Arc::clone(&self.statuses),
+        }
+    }
+
+    fn apply<F>(&mut self, mut operation: F) -> std::io::Result<()>
+    where
+        F: FnMut(&mut dyn Write) -> std::io::Result<u64>,
+    {
+        let mut statuses = lock(&self.statuses);
+        for (slot, status) in self.writers.iter_mut().zip(statuses.iter_mut()) {
+            let Some(writer) = slot else {
+                continue;
+            };
+            match operation(writer.as_mut()) {
+                Ok(bytes) => status.bytes_written += bytes,
+                Err(e) => {
+                    log::error!("[mirror]\tcopy {} failed: {}", status.name, e);
+                    status.healthy = false;
+                    status.error = Some(e.to_string());
+                    *slot = None;
+                }
+            }
+        }
+        if statuses.iter().any(|status| status.healthy) {
+            Ok(())
+        } else {
+            Err(std::io::Error::other("every copy of the recording failed"))
+        }
+    }
+}
+
+impl Write for MirrorWriter {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // Copies always receive whole buffers so that a healthy copy is never missing part of the data
+        self.apply(|writer| writer.write_all(buf).map(|()| buf.len() as u64))?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.apply(|writer| writer.flush().map(|()| 0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct Storage {
+        data: Arc<Mutex<Vec<u8>>>,
+        capacity: Option<usize>,
+    }
+
+    impl Write for Storage {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            let mut data = self.data.lock().unwrap();
+            if self
+                .capacity
+                .is_some_and(|capacity| data.len() + buf.len() > capacity)
+            {
+                return Err(std::io::Error::other("no space left on device"));
+            }
+            data.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn keeps_writing_to_healthy_copy() {
+        let sd = Storage::default();
+        let usb = Storage {
+            capacity: Some(10),
+            ..Storage::default()
+        };
+        let mut writer = MirrorWriter::new("sd", sd.clone(), "usb", usb.clone());
+        let status = writer.status();
+
+        writer.write_all(b"first\n").unwrap();
+        assert!(status.all_healthy());
+        writer.write_all(b"second\n").unwrap();
+        writer.write_all(b"third\n").unwrap();
+        writer.flush().unwrap();
+
+        assert_eq!(
+            sd.data.lock().unwrap().as_slice(),
+            b"first\nsecond\nthird\n"
+        );
+        assert_eq!(usb.data.lock().unwrap().as_slice(), b"first\n");
+        assert_eq!(status.healthy_count(), 1);
+        let usb_status = status.copy("usb").unwrap();
+        assert!(!usb_status.healthy);
+        assert_eq!(usb_status.bytes_written, 6);
+        assert_eq!(usb_status.error.as_deref(), Some("no space left on device"));
+        assert_eq!(status.copy("sd").unwrap().bytes_written, 19);
+    }
+
+    #[test]
+    fn fails_once_every_copy_failed() {
+        let full = Storage {
+            capacity: Some(0),
+            ..Storage::default()
+        };
+        let mut writer = MirrorWriter::new("sd", full.clone(), "usb", full);
+        assert!(writer.write_all(b"data\n").is_err());
+        assert_eq!(writer.status().healthy_count(), 0);
+    }
+}
@@ -0,0 +1,595 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::time::{Duration, Instant};
+
+use url::Url;
+
+use crate::control::{parse_control_message, ControlMessage};
+use crate::filter::MessageType;
+use crate::serializers::ToBytes;
+use crate::structures::TelemetryMessage;
+use crate::TimedMessage;
+
+/// Default port of MQTT brokers
+pub const DEFAULT_PORT: u16 = 1883;
+
+/// How often incoming packets are checked while no telemetry message is published
+const POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// Time to wait for the broker to accept a connection
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+
+const CONNECT: u8 = 0x10;
+const CONNACK: u8 = 0x20;
+const PUBLISH: u8 = 0x30;
+const PUBACK: u8 = 0x40;
+const SUBSCRIBE: u8 = 0x82;
+const SUBACK: u8 = 0x90;
+const PINGREQ: u8 = 0xC0;
+const DISCONNECT: u8 = 0xE0;
+
+/// How telemetry messages and control messages are encoded in MQTT payloads
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum MqttPayload {
+    /// Telemetry and control frames, as sent on the serial port
+    #[default]
+    Frame,
+    /// Telemetry messages serialized as JSON, and control messages as `{"setting": "PEEP", "value": 80}`
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[cfg_attr(
+        doc_cfg,
+        doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+    )]
+    Json,
+}
+
+impl std::str::FromStr for MqttPayload {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "frame" => Ok(Self::Frame),
+            #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+            "json" => Ok(Self::Json),
+            _ if cfg!(all(feature = "serde-messages", feature = "serde_json")) => {
+                Err("Supported payloads are: frame, json")
+            }
+            _ => Err("Supported payloads are: frame"),
+        }
+    }
+}
+
+/// How to publish telemetry to an MQTT broker
+///
+/// Each telemetry message is published to `<topic prefix>/<message type>` (e.g. `makair/bed-12/data-snapshot`, see `filter::MessageType::name()`), and control messages are received on `<topic prefix>/control`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct MqttConfig {
+    /// URL of the broker (`mqtt://[user:password@]host[:port]`)
+    pub broker: Url,
+    /// Prefix of every topic, without trailing slash
+    pub topic_prefix: String,
+    /// Client identifier given to the broker
+    pub client_id: String,
+    /// How messages are encoded
+    pub payload: MqttPayload,
+    /// Keep-alive interval given to the broker; the connection is pinged when nothing was sent for half of it
+    pub keep_alive: Duration,
+    /// Time to wait before connecting again after an error
+    pub reconnect_delay: Duration,
+}
+
+impl MqttConfig {
+    /// Publish to a broker under a topic prefix, with frame payloads
+    pub fn new(broker: Url, topic_prefix: &str) -> Self {
+        Self {
+            broker,
+            topic_prefix: topic_prefix.trim_end_matches('/').to_owned(),
+            client_id: format!("makair-telemetry-{}", std::process::id()),
+            payload: MqttPayload::default(),
+            keep_alive: Duration::from_secs(30),
+            reconnect_delay: Duration::from_secs(1),
+        }
+    }
+
+    /// Set the client identifier given to the broker (it must be unique among the clients of the broker)
+    pub fn client_id(mut self, client_id: &str) -> Self {
+        self.client_id = client_id.to_owned();
+        self
+    }
+
+    /// Set how messages are encoded
+    pub fn payload(mut self, payload: MqttPayload) -> Self {
+        self.payload = payload;
+        self
+    }
+
+    /// Topic messages of a type are published to
+    pub fn topic(&self, message_type: MessageType) -> String {
+        format!("{}/{}", self.topic_prefix, message_type.name())
+    }
+
+    /// Topic control messages are received on
+    pub fn control_topic(&self) -> String {
+        format!("{}/control", self.topic_prefix)
+    }
+
+    /// Encode a telemetry message
+    pub fn encode(&self, message: &TelemetryMessage) -> Vec<u8> {
+        match self.payload {
+            MqttPayload::Frame => message.to_bytes(),
+            #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+            MqttPayload::Json => {
+                serde_json::to_vec(message).expect("telemetry messages are always serializable")
+            }
+        }
+    }
+
+    /// Decode a control message
+    pub fn decode_control(&self, payload: &[u8]) -> Result<ControlMessage, String> {
+        match self.payload {
+            MqttPayload::Frame => parse_control_message(payload)
+                .map(|(_, message)| message)
+                .map_err(|e| format!("invalid control frame: {:?}", e)),
+            #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+            MqttPayload::Json => {
+                #[derive(serde::Deserialize)]
+                struct JsonControl {
+                    setting: crate::control::ControlSetting,
+                    value: u16,
+                }
+
+                serde_json::from_slice::<JsonControl>(payload)
+                    .map(|control| ControlMessage {
+                        setting: control.setting,
+                        value: control.value,
+                    })
+                    .map_err(|e| format!("invalid control message: {}", e))
+            }
+        }
+    }
+}
+
+fn invalid(message: String) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+fn push_string(packet: &mut Vec<u8>, value: &[u8]) {
+    packet.extend_from_slice(&(value.len() as u16).to_be_bytes());
+    packet.extend_from_slice(value);
+}
+
+/// Prepend the fixed header of a packet (type and remaining length) to its body
+fn packet(header: u8, body: &[u8]) -> Vec<u8> {
+    let mut packet = vec![header];
+    let mut length = body.len();
+    loop {
+        let mut byte = (length % 128) as u8;
+        length /= 128;
+        if length > 0 {
+            byte |= 0x80;
+        }
+        packet.push(byte);
+        if length == 0 {
+            break;
+        }
+    }
+    packet.extend_from_slice(body);
+    packet
+}
+
+/// Split the first complete packet of a buffer into its header and body, if there is one
+fn take_packet(buffer: &mut Vec<u8>) -> io::Result<Option<(u8, Vec<u8>)>> {
+    let mut length = 0;
+    let mut index = 1;
+    loop {
+        let byte = match buffer.get(index) {
+            Some(byte) => *byte,
+            None => return Ok(None),
+        };
+        length += usize::from(byte & 0x7F) << (7 * (index - 1));
+        index += 1;
+        if byte & 0x80 == 0 {
+            break;
+        }
+        if index > 4 {
+            return Err(invalid("invalid MQTT remaining length".to_owned()));
+        }
+    }
+    if buffer.len() < index + length {
+        return Ok(None);
+    }
+    let header = buffer[0];
+    let body = buffer[index..index + length].to_vec();
+    buffer.drain(..index + length);
+    Ok(Some((header, body)))
+}
+
+/// Minimal MQTT 3.1.1 client over plain TCP, publishing and receiving with QoS 0
+///
+/// TLS is not supported: use a local bridge or proxy to reach remote brokers.
+pub struct MqttClient {
+    stream: TcpStream,
+    incoming: Vec<u8>,
+    keep_alive: Duration,
+    last_sent: Instant,
+    next_packet_id: u16,
+}
+
+impl MqttClient {
+    /// Connect to the broker of a configuration, with a clean session
+    pub fn connect(config: &MqttConfig) -> io::Result<Self> {
+        if config.broker.scheme() != "mqtt" {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!(
+                    "unsupported URL scheme {} (only mqtt is supported)",
+                    config.broker.scheme()
+                ),
+            ));
+        }
+        let host = config
+            .broker
+            .host_str()
+            .ok_or_else(|| invalid(format!("missing host in {}", config.broker)))?;
+        let stream = TcpStream::connect((host, config.broker.port().unwrap_or(DEFAULT_PORT)))?;
+        stream.set_nodelay(true)?;
+        stream.set_read_timeout(Some(CONNECT_TIMEOUT))?;
+        let mut client = Self {
+            stream,
+            incoming: Vec::new(),
+            keep_alive: config.keep_alive,
+            last_sent: Instant::now(),
+            next_packet_id: 1,
+        };
+
+        let username = (!config.broker.username().is_empty()).then(|| config.broker.username());
+        let password = config.broker.password();
+        // Clean session
+        let mut flags = 0x02;
+        if username.is_some() {
+            flags |= 0x80;
+        }
+        if password.is_some() {
+            flags |= 0x40;
+        }
+        let mut body = Vec::new();
+        push_string(&mut body, b"MQTT");
+        body.push(4);
+        body.push(flags);
+        body.extend_from_slice(
+            &(config.keep_alive.as_secs().min(u16::MAX.into()) as u16).to_be_bytes(),
+        );
+        push_string(&mut body, config.client_id.as_bytes());
+        if let Some(username) = username {
+            push_string(&mut body, username.as_bytes());
+        }
+        if let Some(password) = password {
+            push_string(&mut body, password.as_bytes());
+        }
+        client.send(&packet(CONNECT, &body))?;
+
+        let (header, body) = loop {
+            if let Some(packet) = take_packet(&mut client.incoming)? {
+                break packet;
+            }
+            client.read()?;
+        };
+        match (header, body.get(1)) {
+            (CONNACK, Some(0)) => (),
+            (CONNACK, Some(code)) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::ConnectionRefused,
+                    format!("MQTT broker refused the connection (return code {})", code),
+                ))
+            }
+            _ => {
+                return Err(invalid(format!(
+                    "expected CONNACK, got packet {:#x}",
+                    header
+                )))
+            }
+        }
+        Ok(client)
+    }
+
+    fn send(&mut self, packet: &[u8]) -> io::Result<()> {
+        self.stream.write_all(packet)?;
+        self.last_sent = Instant::now();
+        Ok(())
+    }
+
+    fn read(&mut self) -> io::Result<usize> {
+        let mut buffer = [0; 4096];
+        let read = self.stream.read(&mut buffer)?;
+        if read == 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "MQTT broker closed the connection",
+            ));
+        }
+        self.incoming.extend_from_slice(&buffer[..read]);
+        Ok(read)
+    }
+
+    /// Publish a message (QoS 0, not retained)
+    pub fn publish(&mut self, topic: &str, payload: &[u8]) -> io::Result<()> {
+        let mut body = Vec::with_capacity(topic.len() + payload.len() + 2);
+        push_string(&mut body, topic.as_bytes());
+        body.extend_from_slice(payload);
+        self.send(&packet(PUBLISH, &body))
+    }
+
+    /// Subscribe to a topic (QoS 0); messages are then returned by `poll()`
+    pub fn subscribe(&mut self, topic: &str) -> io::Result<()> {
+        let mut body = self.next_packet_id.to_be_bytes().to_vec();
+        self.next_packet_id = self.next_packet_id.wrapping_add(1).max(1);
+        push_string(&mut body, topic.as_bytes());
+        body.push(0);
+        self.send(&packet(SUBSCRIBE, &body))
+    }
+
+    /// Handle packets received from the broker without waiting, and return the next message published to a subscribed topic (topic and payload), if any
+    ///
+    /// This also pings the broker when nothing was sent for half of the keep-alive interval, so it must be called regularly.
+    pub fn poll(&mut self) -> io::Result<Option<(String, Vec<u8>)>> {
+        loop {
+            while let Some((header, body)) = take_packet(&mut self.incoming)? {
+                match header & 0xF0 {
+                    PUBLISH => {
+                        let qos = (header >> 1) & 0x03;
+                        let topic_length = body
+                            .get(..2)
+                            .map(|length| usize::from(u16::from_be_bytes([length[0], length[1]])))
+                            .ok_or_else(|| invalid("truncated MQTT PUBLISH".to_owned()))?;
+                        let mut offset = 2 + topic_length;
+                        let topic = body
+                            .get(2..offset)
+                            .map(|topic| String::from_utf8_lossy(topic).into_owned())
+                            .ok_or_else(|| invalid("truncated MQTT PUBLISH".to_owned()))?;
+                        if qos > 0 {
+                            let packet_id = body
+                                .get(offset..offset + 2)
+                                .ok_or_else(|| invalid("truncated MQTT PUBLISH".to_owned()))?
+                                .to_vec();
+                            offset += 2;
+                            if qos == 1 {
+                                self.send(&packet(PUBACK, &packet_id))?;
+                            }
+                        }
+                        return Ok(Some((topic, body[offset..].to_vec())));
+                    }
+                    SUBACK if body.get(2) == Some(&0x80) => {
+                        return Err(invalid("MQTT broker refused the subscription".to_owned()));
+                    }
+                    // Accepted subscriptions, PINGRESP, and acknowledgements of packets this client does not send
+                    _ => (),
+                }
+            }
+
+            self.stream.set_nonblocking(true)?;
+            let result = self.read();
+            self.stream.set_nonblocking(false)?;
+            match result {
+                Ok(_) => (),
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        if !self.keep_alive.is_zero() && self.last_sent.elapsed() >= self.keep_alive / 2 {
+            self.send(&packet(PINGREQ, &[]))?;
+        }
+        Ok(None)
+    }
+
+    /// Close the connection gracefully
+    pub fn disconnect(mut self) -> io::Result<()> {
+        self.send(&packet(DISCONNECT, &[]))
+    }
+}
+
+/// Publish every message received through a channel to an MQTT broker, and send control messages published to the control topic through another channel
+///
+/// * `rx` - Receiver of a channel given to a `gather_telemetry*` function.
+/// * `config` - Broker, topics and payload format.
+/// * `control_tx` - Optional sender of a channel of control messages (e.g. the one given to `gather_telemetry()`); if `None`, the control topic is not subscribed to.
+///
+/// The broker is connected to again after errors; messages received in the meantime are dropped.
+/// This returns when the channel of messages is closed.
+pub fn publish_telemetry(
+    rx: Receiver<TimedMessage>,
+    config: &MqttConfig,
+    control_tx: Option<Sender<ControlMessage>>,
+) {
+    let control_topic = config.control_topic();
+    let mut client: Option<MqttClient> = None;
+    let mut retry_at = Instant::now();
+    let mut last_poll = Instant::now();
+
+    loop {
+        if client.is_none() && Instant::now() >= retry_at {
+            log::info!("connecting to MQTT broker {}", config.broker);
+            let connected = MqttClient::connect(config).and_then(|mut client| {
+                if control_tx.is_some() {
+                    client.subscribe(&control_topic)?;
+                }
+                Ok(client)
+            });
+            match connected {
+                Ok(connected) => {
+                    log::info!("publishing telemetry to {}/#", config.topic_prefix);
+                    client = Some(connected);
+                }
+                Err(e) => {
+                    log::error!("failed to connect to MQTT broker: {}", e);
+                    retry_at = Instant::now() + config.reconnect_delay;
+                }
+            }
+        }
+
+        let message = match rx.recv_timeout(POLL_PERIOD) {
+            Ok(message) => Some(message),
+            Err(RecvTimeoutError::Timeout) => None,
+            Err(RecvTimeoutError::Disconnected) => break,
+        };
+        let connected = match client.as_mut() {
+            Some(connected) => connected,
+            None => continue,
+        };
+        let mut result = match message.as_ref().map(|message| &message.message) {
+            Some(Ok(message)) => connected.publish(
+                &config.topic(MessageType::of(message)),
+                &config.encode(message),
+            ),
+            _ => Ok(()),
+        };
+        if result.is_ok() && last_poll.elapsed() >= POLL_PERIOD {
+            last_poll = Instant::now();
+            result = (|| {
+                while let Some((topic, payload)) = connected.poll()? {
+                    if topic != control_topic {
+                        continue;
+                    }
+                    match config.decode_control(&payload) {
+                        Ok(control) => {
+                            log::info!("MQTT → {}", control);
+                            if let Some(control_tx) = control_tx.as_ref() {
+                                let _ = control_tx.send(control);
+                            }
+                        }
+                        Err(e) => log::warn!("dropping control message: {}", e),
+                    }
+                }
+                Ok(())
+            })();
+        }
+        if let Err(e) = result {
+            log::error!("lost connection to MQTT broker: {}", e);
+            client = None;
+            retry_at = Instant::now() + config.reconnect_delay;
+        }
+    }
+
+    if let Some(client) = client {
+        let _ = client.disconnect();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+    use crate::source::{SourceInfo, SourceKind};
+    use crate::structures::ControlAck;
+    use std::net::TcpListener;
+
+    /// Read the next packet sent by the client to a fake broker
+    fn read_packet(stream: &mut TcpStream, buffer: &mut Vec<u8>) -> (u8, Vec<u8>) {
+        loop {
+            if let Some(packet) = take_packet(buffer).unwrap() {
+                return packet;
+            }
+            let mut bytes = [0; 1024];
+            let read = stream.read(&mut bytes).unwrap();
+            assert!(read > 0, "client closed the connection");
+            buffer.extend_from_slice(&bytes[..read]);
+        }
+    }
+
+    #[test]
+    fn encodes_remaining_length() {
+        let body = vec![0; 321];
+        let encoded = packet(PUBLISH, &body);
+        assert_eq!(&encoded[..3], &[PUBLISH, 0xC1, 0x02]);
+        let mut buffer = encoded[..100].to_vec();
+        assert_eq!(take_packet(&mut buffer).unwrap(), None);
+        buffer = encoded;
+        assert_eq!(take_packet(&mut buffer).unwrap(), Some((PUBLISH, body)));
+        assert!(buffer.is_empty());
+    }
+
+    #[test]
+    fn publishes_telemetry_and_receives_control_messages() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let broker = Url::parse(&format!(
+            "mqtt://user:secret@{}",
+            listener.local_addr().unwrap()
+        ))
+        .unwrap();
+        let config = MqttConfig::new(broker, "makair/bed-12/").client_id("test");
+        let control = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+
+        let control_frame = control.to_control_frame();
+        let server = std::thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut buffer = Vec::new();
+            let (header, connect) = read_packet(&mut stream, &mut buffer);
+            assert_eq!(header, CONNECT);
+            // Username and password flags, clean session
+            assert_eq!(connect[7], 0xC2);
+            stream.write_all(&packet(CONNACK, &[0, 0])).unwrap();
+
+            let (header, subscribe) = read_packet(&mut stream, &mut buffer);
+            assert_eq!(header, SUBSCRIBE);
+            assert_eq!(&subscribe[4..subscribe.len() - 1], b"makair/bed-12/control");
+            stream.write_all(&packet(SUBACK, &[0, 1, 0])).unwrap();
+            let mut publish = Vec::new();
+            push_string(&mut publish, b"makair/bed-12/control");
+            publish.extend_from_slice(&control_frame);
+            stream.write_all(&packet(PUBLISH, &publish)).unwrap();
+
+            loop {
+                let (header, body) = read_packet(&mut stream, &mut buffer);
+                if header == DISCONNECT {
+                    break;
+                }
+                if header & 0xF0 == PUBLISH {
+                    return body;
+                }
+            }
+            panic!("no message was published");
+        });
+
+        let ack = TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 1000,
+            setting: control.setting,
+            value: control.value,
+        });
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (control_tx, control_rx) = std::sync::mpsc::channel();
+        let publisher = {
+            let config = config.clone();
+            std::thread::spawn(move || publish_telemetry(rx, &config, Some(control_tx)))
+        };
+
+        assert_eq!(
+            control_rx.recv_timeout(Duration::from_secs(5)).unwrap(),
+            control
+        );
+        tx.send(TimedMessage::now(
+            Ok(ack.clone()),
+            SourceInfo::new(SourceKind::Bytes, None),
+        ))
+        .unwrap();
+        let published = server.join().unwrap();
+        drop(tx);
+        publisher.join().unwrap();
+
+        let mut expected = Vec::new();
+        push_string(&mut expected, b"makair/bed-12/control-ack");
+        expected.extend_from_slice(&ack.to_bytes());
+        assert_eq!(published, expected);
+    }
+}
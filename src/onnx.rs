@@ -0,0 +1,311 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::Read;
+use std::path::Path;
+
+use tract_onnx::prelude::*;
+
+use crate::anomaly::{AnomalyDetector, AnomalyEvent, CycleMetrics, CycleWaveform, Metric};
+
+/// Number of points waveforms can be resampled to when the model does not require a specific one
+pub const DEFAULT_WAVEFORM_SAMPLES: usize = 256;
+
+/// Default probability above which a class is reported
+pub const DEFAULT_MIN_PROBABILITY: f32 = 0.5;
+
+/// Error that can happen when loading an ONNX model
+#[derive(Debug, thiserror::Error)]
+pub enum OnnxError {
+    /// The model could not be read or does not accept the waveform tensor
+    #[error("failed to load ONNX model: {0:#}")]
+    Model(TractError),
+    /// The model outputs a different number of classes than the labels given
+    #[error("the ONNX model outputs {classes} classes but {labels} labels were given")]
+    LabelMismatch {
+        /// Number of classes of the model
+        classes: usize,
+        /// Number of labels given
+        labels: usize,
+    },
+}
+
+/// Classifies the waveform of each cycle with an ONNX model (e.g. to find patient-ventilator asynchronies), and reports cycles that are not classified as normal
+///
+/// The model receives a `[1, 2, samples]` `f32` tensor: pressures in mmH2O then flows in cL/min (see `CycleWaveform`), linearly resampled to `samples` points.
+/// It must output a `[1, labels]` tensor of probabilities (models ending with logits need a final `Softmax`).
+/// Events use `Metric::Waveform`, with the index of the class as value, its probability as score and its label.
+pub struct OnnxClassifier {
+    name: String,
+    labels: Vec<String>,
+    normal: usize,
+    samples: usize,
+    min_probability: f32,
+    model: TypedSimplePlan<TypedModel>,
+}
+
+impl std::fmt::Debug for OnnxClassifier {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("OnnxClassifier")
+            .field("name", &self.name)
+            .field("labels", &self.labels)
+            .field("normal", &self.normal)
+            .field("samples", &self.samples)
+            .field("min_probability", &self.min_probability)
+            .finish_non_exhaustive()
+    }
+}
+
+impl OnnxClassifier {
+    /// Load a model from a `.onnx` file, with the labels of its classes; the first label is the normal class
+    pub fn load<P: AsRef<Path>>(
+        name: &str,
+        path: P,
+        labels: &[&str],
+        samples: usize,
+    ) -> Result<Self, OnnxError> {
+        let model = tract_onnx::onnx()
+            .model_for_path(path)
+            .map_err(OnnxError::Model)?;
+        Self::from_model(name, model, labels, samples)
+    }
+
+    /// Load a model from a reader, with the labels of its classes; the first label is the normal class
+    pub fn from_reader(
+        name: &str,
+        reader: &mut dyn Read,
+        labels: &[&str],
+        samples: usize,
+    ) -> Result<Self, OnnxError> {
+        let model = tract_onnx::onnx()
+            .model_for_read(reader)
+            .map_err(OnnxError::Model)?;
+        Self::from_model(name, model, labels, samples)
+    }
+
+    fn from_model(
+        name: &str,
+        model: InferenceModel,
+        labels: &[&str],
+        samples: usize,
+    ) -> Result<Self, OnnxError> {
+        let samples = samples.max(2);
+        let model = model
+            .with_input_fact(0, f32::fact([1, 2, samples]).into())
+            .and_then(|model| model.into_optimized())
+            .map_err(OnnxError::Model)?;
+        let classes = model
+            .output_fact(0)
+            .map_err(OnnxError::Model)?
+            .shape
+            .as_concrete()
+            .and_then(|shape| shape.last().copied());
+        if let Some(classes) = classes.filter(|classes| *classes != labels.len()) {
+            return Err(OnnxError::LabelMismatch {
+                classes,
+                labels: labels.len(),
+            });
+        }
+        Ok(Self {
+            name: name.to_owned(),
+            labels: labels.iter().map(|label| (*label).to_owned()).collect(),
+            normal: 0,
+            samples,
+            min_probability: DEFAULT_MIN_PROBABILITY,
+            model: model.into_runnable().map_err(OnnxError::Model)?,
+        })
+    }
+
+    /// Do not report cycles of this class instead of the first one (labels that do not exist are ignored)
+    pub fn normal(mut self, label: &str) -> Self {
+        if let Some(index) = self.labels.iter().position(|l| l == label) {
+            self.normal = index;
+        }
+        self
+    }
+
+    /// Only report classes with at least this probability
+    pub fn min_probability(mut self, probability: f32) -> Self {
+        self.min_probability = probability;
+        self
+    }
+
+    /// Probability of each class for a waveform
+    pub fn classify(&self, waveform: &CycleWaveform) -> TractResult<Vec<f32>> {
+        let mut input = resample(&waveform.pressure, self.samples);
+        input.extend(resample(&waveform.flow, self.samples));
+        let input = tract_ndarray::Array3::from_shape_vec((1, 2, self.samples), input)?;
+        let outputs = self.model.run(tvec!(Tensor::from(input).into()))?;
+        Ok(outputs[0].to_array_view::<f32>()?.iter().copied().collect())
+    }
+}
+
+fn resample(values: &[f64], samples: usize) -> Vec<f32> {
+    if values.is_empty() {
+        return vec![0.0; samples];
+    }
+    (0..samples)
+        .map(|i| {
+            let position = i as f64 * (values.len() - 1) as f64 / (samples - 1) as f64;
+            let before = position.floor() as usize;
+            let after = (before + 1).min(values.len() - 1);
+            let weight = position - before as f64;
+            (values[before] * (1.0 - weight) + values[after] * weight) as f32
+        })
+        .collect()
+}
+
+impl AnomalyDetector for OnnxClassifier {
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// Waveforms are needed: without them nothing is reported
+    fn inspect(&mut self, _metrics: &CycleMetrics) -> Vec<AnomalyEvent> {
+        Vec::new()
+    }
+
+    fn inspect_cycle(
+        &mut self,
+        metrics: &CycleMetrics,
+        waveform: &CycleWaveform,
+    ) -> Vec<AnomalyEvent> {
+        if waveform.is_empty() {
+            return Vec::new();
+        }
+        let probabilities = match self.classify(waveform) {
+            Ok(probabilities) => probabilities,
+            Err(e) => {
+                log::warn!("[{}] failed to classify cycle: {:#}", self.name, e);
+                return Vec::new();
+            }
+        };
+        let best = probabilities
+            .iter()
+            .enumerate()
+            .max_by(|(_, a), (_, b)| a.total_cmp(b));
+        match best {
+            Some((class, probability))
+                if class != self.normal && *probability >= self.min_probability =>
+            {
+                vec![AnomalyEvent {
+                    detector: self.name.clone(),
+                    systick: metrics.systick,
+                    cycle: metrics.cycle,
+                    metric: Metric::Waveform,
+                    value: class as f64,
+                    score: f64::from(*probability),
+                    label: self.labels.get(class).cloned(),
+                }]
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::MachineStateSnapshot;
+    use tract_onnx::pb;
+
+    /// Model classifying waveforms by comparing the means of pressures and flows
+    fn mean_model() -> InferenceModel {
+        let ints = |name: &str, ints: Vec<i64>| pb::AttributeProto {
+            name: name.to_owned(),
+            r#type: pb::attribute_proto::AttributeType::Ints as i32,
+            ints,
+            ..Default::default()
+        };
+        let int = |name: &str, i: i64| pb::AttributeProto {
+            name: name.to_owned(),
+            r#type: pb::attribute_proto::AttributeType::Int as i32,
+            i,
+            ..Default::default()
+        };
+        let node = |op_type: &str, input: &str, output: &str, attribute| pb::NodeProto {
+            op_type: op_type.to_owned(),
+            input: vec![input.to_owned()],
+            output: vec![output.to_owned()],
+            attribute,
+            ..Default::default()
+        };
+        let value = |name: &str| pb::ValueInfoProto {
+            name: name.to_owned(),
+            r#type: Some(pb::TypeProto {
+                value: Some(pb::type_proto::Value::TensorType(pb::type_proto::Tensor {
+                    elem_type: pb::tensor_proto::DataType::Float as i32,
+                    shape: None,
+                })),
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        let proto = pb::ModelProto {
+            ir_version: 6,
+            opset_import: vec![pb::OperatorSetIdProto {
+                domain: String::new(),
+                version: 11,
+            }],
+            graph: Some(pb::GraphProto {
+                node: vec![
+                    node(
+                        "ReduceMean",
+                        "waveform",
+                        "means",
+                        vec![ints("axes", vec![2]), int("keepdims", 0)],
+                    ),
+                    node("Softmax", "means", "probabilities", vec![int("axis", 1)]),
+                ],
+                input: vec![value("waveform")],
+                output: vec![value("probabilities")],
+                ..Default::default()
+            }),
+            ..Default::default()
+        };
+        tract_onnx::onnx().model_for_proto_model(&proto).unwrap()
+    }
+
+    #[test]
+    fn reports_abnormal_waveforms() {
+        let mut classifier =
+            OnnxClassifier::from_model("asynchrony", mean_model(), &["normal", "flow"], 16)
+                .unwrap();
+        let metrics = CycleMetrics::new(
+            &MachineStateSnapshot {
+                cycle: 3,
+                ..Default::default()
+            },
+            None,
+        );
+
+        let normal = CycleWaveform {
+            pressure: vec![50.0, 150.0, 100.0],
+            flow: vec![0.0, 5.0, 0.0],
+        };
+        assert!(classifier.inspect_cycle(&metrics, &normal).is_empty());
+
+        let abnormal = CycleWaveform {
+            pressure: vec![0.0; 100],
+            flow: vec![10.0; 100],
+        };
+        let events = classifier.inspect_cycle(&metrics, &abnormal);
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].cycle, 3);
+        assert_eq!(events[0].label.as_deref(), Some("flow"));
+        assert!(events[0].score > 0.99);
+    }
+
+    #[test]
+    fn checks_labels() {
+        assert!(matches!(
+            OnnxClassifier::from_model("asynchrony", mean_model(), &["normal"], 16),
+            Err(OnnxError::LabelMismatch {
+                classes: 2,
+                labels: 1
+            })
+        ));
+    }
+}
@@ -0,0 +1,246 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{BTreeMap, HashMap};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::UNIX_EPOCH;
+
+use crate::filter::MessageType;
+use crate::reader::TelemetryFileReader;
+use crate::retention::sidecar;
+use crate::structures::TelemetryMessage;
+
+/// Extension added to a recording path for its cached overview, e.g. `night.record.overview`
+pub const OVERVIEW_EXTENSION: &str = "overview";
+
+/// Quick-look summary of a recording, e.g. for recording-browser UIs
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RecordingOverview {
+    /// Internal ID of the MCU that sent the first message
+    pub device_id: Option<String>,
+    /// Number of messages of each type
+    pub message_counts: HashMap<MessageType, usize>,
+    /// Systick of the first message
+    pub first_systick: Option<u64>,
+    /// Systick of the last message
+    pub last_systick: Option<u64>,
+    /// Number of times each alarm was triggered, by alarm code
+    pub alarm_triggers: BTreeMap<u8, usize>,
+    /// Lowest pressure of data snapshots, in mmH2O
+    pub min_pressure: Option<i16>,
+    /// Highest pressure of data snapshots, in mmH2O
+    pub max_pressure: Option<i16>,
+}
+
+/// Size and last modification (in seconds since UNIX epoch) of a recording, to tell whether a cached overview is still valid
+type Fingerprint = (u64, u64);
+
+impl RecordingOverview {
+    /// Summarize messages
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(messages: I) -> Self {
+        let mut overview = Self::default();
+        for message in messages {
+            overview.push(message);
+        }
+        overview
+    }
+
+    fn push(&mut self, message: &TelemetryMessage) {
+        if self.device_id.is_none() {
+            self.device_id = Some(message.device_id());
+        }
+        *self
+            .message_counts
+            .entry(MessageType::of(message))
+            .or_default() += 1;
+        let systick = message.systick();
+        self.first_systick.get_or_insert(systick);
+        self.last_systick = Some(systick);
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                self.min_pressure = Some(
+                    self.min_pressure
+                        .map_or(snapshot.pressure, |min| min.min(snapshot.pressure)),
+                );
+                self.max_pressure = Some(
+                    self.max_pressure
+                        .map_or(snapshot.pressure, |max| max.max(snapshot.pressure)),
+                );
+            }
+            TelemetryMessage::AlarmTrap(trap) if trap.triggered => {
+                *self.alarm_triggers.entry(trap.alarm_code).or_default() += 1;
+            }
+            _ => (),
+        }
+    }
+
+    /// Duration between the first and the last message, in microseconds
+    pub fn duration(&self) -> Option<u64> {
+        self.first_systick
+            .zip(self.last_systick)
+            .map(|(first, last)| last.saturating_sub(first))
+    }
+
+    /// Number of alarm triggers per hour of recording
+    pub fn alarms_per_hour(&self) -> Option<f64> {
+        let duration = self.duration().filter(|duration| *duration > 0)?;
+        let triggers: usize = self.alarm_triggers.values().sum();
+        Some(triggers as f64 * 3_600_000_000.0 / duration as f64)
+    }
+
+    /// Get the overview of a recording from its sidecar file, or read the whole recording and cache its overview in a sidecar file
+    ///
+    /// The sidecar is recomputed when the recording changed since it was written (size or modification time). Failing to write it is not an error, e.g. on read-only shares.
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        let path = path.as_ref();
+        if let Some(overview) = Self::read_cached(path)? {
+            return Ok(overview);
+        }
+
+        let fingerprint = fingerprint(path)?;
+        let mut overview = Self::default();
+        let mut reader = TelemetryFileReader::open(path)?;
+        while let Some(frame) = reader.next_frame() {
+            overview.push(&frame?.0);
+        }
+        if let Err(e) = std::fs::write(Self::sidecar_path(path), overview.to_sidecar(fingerprint)) {
+            log::warn!("failed caching overview of {}: {:?}", path.display(), e);
+        }
+        Ok(overview)
+    }
+
+    /// Read the cached overview of a recording without reading the recording, if there is a valid one
+    pub fn read_cached<P: AsRef<Path>>(path: P) -> std::io::Result<Option<Self>> {
+        let path = path.as_ref();
+        let cached = match std::fs::read_to_string(Self::sidecar_path(path)) {
+            Ok(cached) => cached,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        let fingerprint = fingerprint(path)?;
+        Ok(Self::from_sidecar(&cached)
+            .filter(|(cached_fingerprint, _)| *cached_fingerprint == fingerprint)
+            .map(|(_, overview)| overview))
+    }
+
+    /// Path of the cached overview of a recording
+    pub fn sidecar_path<P: AsRef<Path>>(path: P) -> PathBuf {
+        sidecar(path.as_ref(), OVERVIEW_EXTENSION)
+    }
+
+    /// Render as `key value` lines
+    fn to_sidecar(&self, (size, modified): Fingerprint) -> String {
+        let mut lines = vec![
+            format!("recording_size {}", size),
+            format!("recording_modified {}", modified),
+        ];
+        if let Some(device_id) = &self.device_id {
+            lines.push(format!("device_id {}", device_id));
+        }
+        let mut counts: Vec<_> = self.message_counts.iter().collect();
+        counts.sort_by_key(|(message_type, _)| message_type.name());
+        for (message_type, count) in counts {
+            lines.push(format!("messages {} {}", message_type.name(), count));
+        }
+        for (name, value) in [
+            ("first_systick", self.first_systick.map(i128::from)),
+            ("last_systick", self.last_systick.map(i128::from)),
+            ("min_pressure", self.min_pressure.map(i128::from)),
+            ("max_pressure", self.max_pressure.map(i128::from)),
+        ] {
+            if let Some(value) = value {
+                lines.push(format!("{} {}", name, value));
+            }
+        }
+        for (code, count) in &self.alarm_triggers {
+            lines.push(format!("alarm {} {}", code, count));
+        }
+        lines.iter().map(|line| format!("{}\n", line)).collect()
+    }
+
+    /// Parse the content of a sidecar file; `None` if it is invalid (it is then recomputed)
+    fn from_sidecar(content: &str) -> Option<(Fingerprint, Self)> {
+        let mut overview = Self::default();
+        let (mut size, mut modified) = (None, None);
+        for line in content.lines() {
+            let (key, value) = line.split_once(' ')?;
+            match key {
+                "recording_size" => size = Some(value.parse().ok()?),
+                "recording_modified" => modified = Some(value.parse().ok()?),
+                "device_id" => overview.device_id = Some(value.to_owned()),
+                "messages" => {
+                    let (message_type, count) = value.split_once(' ')?;
+                    overview.message_counts.insert(
+                        MessageType::from_str(message_type).ok()?,
+                        count.parse().ok()?,
+                    );
+                }
+                "first_systick" => overview.first_systick = Some(value.parse().ok()?),
+                "last_systick" => overview.last_systick = Some(value.parse().ok()?),
+                "min_pressure" => overview.min_pressure = Some(value.parse().ok()?),
+                "max_pressure" => overview.max_pressure = Some(value.parse().ok()?),
+                "alarm" => {
+                    let (code, count) = value.split_once(' ')?;
+                    overview
+                        .alarm_triggers
+                        .insert(code.parse().ok()?, count.parse().ok()?);
+                }
+                _ => return None,
+            }
+        }
+        Some(((size?, modified?), overview))
+    }
+}
+
+fn fingerprint(path: &Path) -> std::io::Result<Fingerprint> {
+    let metadata = std::fs::metadata(path)?;
+    let modified = metadata
+        .modified()?
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |modified| modified.as_secs());
+    Ok((metadata.len(), modified))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+
+    #[test]
+    fn caches_overview_next_to_recording() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(1_000)
+                .collect();
+        let recording: String = messages
+            .iter()
+            .map(|message| base64::encode(message.to_bytes()) + "\n")
+            .collect();
+        let path =
+            std::env::temp_dir().join(format!("makair-overview-{}.record", std::process::id()));
+        std::fs::write(&path, &recording).unwrap();
+
+        assert_eq!(RecordingOverview::read_cached(&path).unwrap(), None);
+        let overview = RecordingOverview::open(&path).unwrap();
+        assert_eq!(overview, RecordingOverview::from_messages(&messages));
+        assert_eq!(overview.device_id.as_deref(), Some("1-2-3"));
+        assert_eq!(overview.message_counts.values().sum::<usize>(), 1_000);
+        assert!(overview.duration().unwrap() > 0);
+        assert!(overview.min_pressure < overview.max_pressure);
+        assert_eq!(
+            RecordingOverview::read_cached(&path).unwrap(),
+            Some(overview)
+        );
+
+        // The cache is not used anymore once the recording changed
+        std::fs::write(&path, &recording[..recording.len() / 2]).unwrap();
+        assert_eq!(RecordingOverview::read_cached(&path).unwrap(), None);
+
+        std::fs::remove_file(RecordingOverview::sidecar_path(&path)).unwrap();
+        std::fs::remove_file(&path).unwrap();
+    }
+}
@@ -7,26 +7,60 @@
 pub mod v1;
 /// Parsers for the telemetry protocol version 2
 pub mod v2;
+/// Parsers for the telemetry protocol version 3
+pub mod v3;
 
 use nom::error::{FromExternalError, ParseError};
 use nom::IResult;
 
 use super::structures::*;
+use crate::diagnostics::{FieldWarning, FieldWarnings};
 
-const MAXIMUM_SUPPORTED_VERSION: u8 = 2;
+/// Latest version of the telemetry protocol supported by this version of the library
+pub const MAXIMUM_SUPPORTED_VERSION: u8 = 3;
 
-fn header<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
-    nom::bytes::streaming::tag(b"\x03\x0C")(input)
+/// Bytes starting a telemetry frame
+pub const FRAME_HEADER: &[u8; 2] = b"\x03\x0C";
+
+/// Bytes ending a telemetry frame
+pub const FRAME_FOOTER: &[u8; 2] = b"\x30\xC0";
+
+/// Bytes identifying the types of messages supported by this version of the library
+const KNOWN_MESSAGE_TYPES: &[u8] = b"BODSTAEL";
+
+/// Default maximum size of a frame; beyond this, bytes are considered garbage
+pub const DEFAULT_MAX_FRAME_SIZE: usize = 1024;
+
+/// Default number of bytes scanned at once for the next frame header after bytes that could not be parsed
+pub const DEFAULT_RESYNC_WINDOW: usize = 64;
+
+/// Environment variable choosing the parsing mode (`strict` or `lenient`)
+pub const PARSER_MODE_VAR: &str = "MAKAIR_PARSER_MODE";
+
+/// Environment variable choosing the maximum frame size in bytes
+pub const PARSER_MAX_FRAME_SIZE_VAR: &str = "MAKAIR_PARSER_MAX_FRAME_SIZE";
+
+/// Environment variable choosing the resync window in bytes
+pub const PARSER_RESYNC_WINDOW_VAR: &str = "MAKAIR_PARSER_RESYNC_WINDOW";
+
+fn header<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
+    nom::bytes::streaming::tag(FRAME_HEADER)(input)
 }
 
-fn footer<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
-    nom::bytes::streaming::tag(b"\x30\xC0")(input)
+fn footer<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
+    nom::bytes::streaming::tag(FRAME_FOOTER)(input)
 }
 
 fn message<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
-    nom::branch::alt((v2::message, v1::message))(input).map_err(nom::Err::convert)
+    nom::branch::alt((
+        |i| v3::message_with_warnings(i, warnings),
+        |i| v2::message_with_warnings(i, warnings),
+        |i| v1::message_with_warnings(i, warnings),
+    ))(input)
+    .map_err(nom::Err::convert)
 }
 
 /// Try to extract protocol version from message bytes
@@ -44,35 +78,226 @@ pub fn protocol_version<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult
     parser(input)
 }
 
+/// How to handle enum values that are unknown to this version of the library
+///
+/// Newer firmware versions may send ventilation modes or end of line test steps that this library does not know yet.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum ParsingMode {
+    /// Reject messages containing unknown values, as if they were invalid
+    #[default]
+    Strict,
+    /// Keep messages containing unknown values, which are represented by `Unknown` variants
+    ///
+    /// Valid frames of unknown message types are also kept as `TelemetryMessage::Unknown`.
+    Lenient,
+}
+
+impl std::str::FromStr for ParsingMode {
+    type Err = &'static str;
+
+    /// Parse a parsing mode from `strict` or `lenient`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "strict" => Ok(Self::Strict),
+            "lenient" => Ok(Self::Lenient),
+            _ => Err("Supported parsing modes are: strict, lenient"),
+        }
+    }
+}
+
+/// An environment variable of the parser configuration has an invalid value
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+#[error("invalid value {value:?} for {name}: {reason}")]
+pub struct ParserConfigError {
+    /// Name of the environment variable
+    pub name: &'static str,
+    /// Value of the environment variable
+    pub value: String,
+    /// Why the value is invalid
+    pub reason: &'static str,
+}
+
+/// Limits and strictness of the parsers used by the `gather_telemetry*()` functions
+///
+/// Each gather function is given its own configuration (see `decoder::DecodeConfig`); `from_env()` lets applications such as the CLI read it from the environment.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParserConfig {
+    /// Whether messages containing unknown values are rejected or kept
+    pub mode: ParsingMode,
+    /// Size in bytes beyond which an incomplete frame is considered garbage
+    pub max_frame_size: usize,
+    /// Number of bytes scanned at once for the next frame header after bytes that could not be parsed
+    pub resync_window: usize,
+}
+
+impl Default for ParserConfig {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl ParserConfig {
+    /// Create the default configuration (strict mode, default limits)
+    pub const fn new() -> Self {
+        Self {
+            mode: ParsingMode::Strict,
+            max_frame_size: DEFAULT_MAX_FRAME_SIZE,
+            resync_window: DEFAULT_RESYNC_WINDOW,
+        }
+    }
+
+    /// Choose how to handle unknown values
+    pub fn mode(mut self, mode: ParsingMode) -> Self {
+        self.mode = mode;
+        self
+    }
+
+    /// Choose the size in bytes beyond which an incomplete frame is considered garbage
+    pub fn max_frame_size(mut self, bytes: usize) -> Self {
+        self.max_frame_size = bytes;
+        self
+    }
+
+    /// Choose the number of bytes scanned at once for the next frame header (at least 1)
+    pub fn resync_window(mut self, bytes: usize) -> Self {
+        self.resync_window = bytes.max(1);
+        self
+    }
+
+    /// Create the default configuration, overridden by the `MAKAIR_PARSER_*` environment variables that are set
+    pub fn from_env() -> Result<Self, ParserConfigError> {
+        Self::from_vars(|name| std::env::var(name).ok())
+    }
+
+    fn from_vars<F: Fn(&str) -> Option<String>>(var: F) -> Result<Self, ParserConfigError> {
+        let invalid = |name, value: String, reason| ParserConfigError {
+            name,
+            value,
+            reason,
+        };
+        let size = |name| -> Result<Option<usize>, ParserConfigError> {
+            match var(name) {
+                None => Ok(None),
+                Some(value) => match value.trim().parse() {
+                    Ok(size) if size > 0 => Ok(Some(size)),
+                    _ => Err(invalid(name, value, "expected a positive number of bytes")),
+                },
+            }
+        };
+
+        let mut config = Self::new();
+        if let Some(value) = var(PARSER_MODE_VAR) {
+            config.mode = value
+                .parse()
+                .map_err(|reason| invalid(PARSER_MODE_VAR, value, reason))?;
+        }
+        if let Some(bytes) = size(PARSER_MAX_FRAME_SIZE_VAR)? {
+            config.max_frame_size = bytes;
+        }
+        if let Some(bytes) = size(PARSER_RESYNC_WINDOW_VAR)? {
+            config.resync_window = bytes;
+        }
+        Ok(config)
+    }
+}
+
+/// Number of bytes to drop from the beginning of a buffer that cannot be parsed, to get to the next frame header
+///
+/// * `input` - Bytes that could not be parsed.
+/// * `window` - Maximum number of bytes to drop (see `ParserConfig::resync_window`).
+///
+/// At least one byte is dropped. A header byte at the end of the buffer is kept, as the rest of the header may not be received yet.
+pub fn resync_offset(input: &[u8], window: usize) -> usize {
+    let window = window.max(1);
+    for offset in 1..input.len().min(window + 1) {
+        let rest = &input[offset..];
+        if rest.starts_with(FRAME_HEADER) || rest == &FRAME_HEADER[..1] {
+            return offset;
+        }
+    }
+    input.len().min(window)
+}
+
 /// Transform bytes into a structured telemetry message
 ///
 /// * `input` - Bytes to parse.
 ///
 /// This requires every bytes of the message, including header, CRC and footer.
 /// CRC will be checked.
+/// Messages containing unknown values are rejected (see `parse_telemetry_message_with_mode()`).
 pub fn parse_telemetry_message(
     input: &[u8],
 ) -> IResult<&[u8], TelemetryMessage, TelemetryError<&[u8]>> {
+    parse_telemetry_message_with_mode(input, ParsingMode::Strict)
+}
+
+/// Same as `parse_telemetry_message()`, but choose how to handle unknown values
+///
+/// * `input` - Bytes to parse.
+/// * `mode` - Whether messages containing unknown values should be rejected or kept.
+pub fn parse_telemetry_message_with_mode(
+    input: &[u8],
+    mode: ParsingMode,
+) -> IResult<&[u8], TelemetryMessage, TelemetryError<&[u8]>> {
+    parse_telemetry_message_with_config(input, &ParserConfig::new().mode(mode))
+}
+
+/// Same as `parse_telemetry_message()`, but with custom limits and strictness
+///
+/// * `input` - Bytes to parse.
+/// * `config` - How to handle unknown values, and when to give up on an incomplete frame.
+pub fn parse_telemetry_message_with_config<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> IResult<&'a [u8], TelemetryMessage, TelemetryError<&'a [u8]>> {
+    parse_telemetry_message_with_warnings(input, config).map(|(rest, (msg, _))| (rest, msg))
+}
+
+/// A decoded message, along with warnings about its fields that were set to a fallback value
+pub type MessageWithWarnings = (TelemetryMessage, Vec<FieldWarning>);
+
+/// Same as `parse_telemetry_message_with_config()`, along with warnings about fields of the message that were set to a fallback value (e.g. unknown locales)
+///
+/// * `input` - Bytes to parse.
+/// * `config` - How to handle unknown values, and when to give up on an incomplete frame.
+///
+/// Warnings are only returned for frames with a valid CRC.
+pub fn parse_telemetry_message_with_warnings<'a>(
+    input: &'a [u8],
+    config: &ParserConfig,
+) -> IResult<&'a [u8], MessageWithWarnings, TelemetryError<&'a [u8]>> {
     use nom::combinator::consumed;
     use nom::number::streaming::be_u32;
     use nom::sequence::{pair, preceded, terminated};
 
-    let mut parser = preceded(header, terminated(pair(consumed(message), be_u32), footer));
-    parser(input)
+    let warnings = FieldWarnings::default();
+    let mut parser = preceded(
+        header,
+        terminated(pair(consumed(|i| message(i, &warnings)), be_u32), footer),
+    );
+    let result = parser(input)
         .and_then(|(rest, ((msg_bytes, msg), expected_crc))| {
             let mut crc = crc32fast::Hasher::new();
             crc.update(msg_bytes);
             let computed_crc = crc.finalize();
-            if expected_crc == computed_crc {
-                Ok((rest, msg))
-            } else {
+            if expected_crc != computed_crc {
+                // Only the bytes of the frame are returned, so that callers can skip it
                 Err(nom::Err::Failure(TelemetryError(
-                    input,
+                    &input[..input.len() - rest.len()],
                     TelemetryErrorKind::CrcError {
                         expected: expected_crc,
                         computed: computed_crc,
                     },
                 )))
+            } else if config.mode == ParsingMode::Strict && msg.has_unknown_values() {
+                Err(nom::Err::Error(TelemetryError(
+                    input,
+                    TelemetryErrorKind::ParserError(nom::error::VerboseErrorKind::Nom(
+                        nom::error::ErrorKind::MapRes,
+                    )),
+                )))
+            } else {
+                Ok((rest, msg))
             }
         })
         .or_else(|e| match e {
@@ -90,12 +315,82 @@ pub fn parse_telemetry_message(
                             found: version,
                         },
                     )))
+                } else if config.mode == ParsingMode::Lenient
+                    && !KNOWN_MESSAGE_TYPES.contains(&input[2])
+                {
+                    match unknown_message(input, config.max_frame_size) {
+                        Err(nom::Err::Error(_)) => Err(e),
+                        result => result,
+                    }
                 } else {
                     Err(e)
                 }
             }),
             _ => Err(e),
         })
+        .map_err(|e| match e {
+            nom::Err::Incomplete(_) if input.len() >= config.max_frame_size => too_large(input),
+            _ => e,
+        });
+
+    result.map(|(rest, msg)| (rest, (msg, warnings.take())))
+}
+
+/// Extract a message of unknown type, by looking for a footer preceded by a valid CRC and a body starting with the common header of messages
+fn unknown_message(
+    input: &[u8],
+    max_frame_size: usize,
+) -> IResult<&[u8], TelemetryMessage, TelemetryError<&[u8]>> {
+    // Body starts after the header and must at least contain the message type, ':' and the protocol version
+    const BODY_START: usize = 2;
+    const MIN_BODY_LENGTH: usize = 3;
+
+    for footer_start in (BODY_START + MIN_BODY_LENGTH + 4)..input.len().saturating_sub(1) {
+        if &input[footer_start..footer_start + 2] != FRAME_FOOTER {
+            continue;
+        }
+        let crc_start = footer_start - 4;
+        let body = &input[BODY_START..crc_start];
+        let mut expected_crc = [0u8; 4];
+        expected_crc.copy_from_slice(&input[crc_start..footer_start]);
+        if crc32fast::hash(body) == u32::from_be_bytes(expected_crc) {
+            let (payload, (version, device_id, systick)) =
+                v2::common_header::<TelemetryError<&[u8]>>(&body[MIN_BODY_LENGTH..]).map_err(
+                    |_| {
+                        nom::Err::Error(TelemetryError::from_error_kind(
+                            input,
+                            nom::error::ErrorKind::Verify,
+                        ))
+                    },
+                )?;
+            return Ok((
+                &input[footer_start + 2..],
+                TelemetryMessage::Unknown {
+                    telemetry_version: body[2],
+                    version,
+                    device_id,
+                    systick,
+                    type_byte: body[0],
+                    payload: payload.to_vec(),
+                },
+            ));
+        }
+    }
+
+    if input.len() < max_frame_size {
+        Err(nom::Err::Incomplete(nom::Needed::Unknown))
+    } else {
+        Err(too_large(input))
+    }
+}
+
+fn too_large(input: &[u8]) -> nom::Err<TelemetryError<&[u8]>> {
+    nom::Err::Error(TelemetryError(
+        input,
+        TelemetryErrorKind::ParserError(nom::error::VerboseErrorKind::Nom(
+            nom::error::ErrorKind::TooLarge,
+        )),
+    ))
 }
 
 #[cfg(test)]
@@ -143,6 +438,8 @@ mod tests {
                 systick,
                 mode,
                 value128,
+                control_capabilities: None,
+                session_nonce: None,
             };
 
             // This needs to be consistent with sendBootMessage() defined in src/software/firmware/srcs/telemetry.cpp
@@ -214,6 +511,8 @@ mod tests {
                 systick,
                 mode,
                 value128,
+                control_capabilities: None,
+                session_nonce: None,
             };
             let expected = TelemetryMessage::BootMessage(msg);
             let input = &expected.to_bytes_v2();
@@ -238,4 +537,171 @@ mod tests {
             Err(nom::Err::Failure(expected))
         );
     }
+
+    #[test]
+    fn unknown_values_in_strict_and_lenient_modes() {
+        let expected = TelemetryMessage::EolTestSnapshot(EolTestSnapshot {
+            telemetry_version: 2,
+            version: "v9.9.9".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            current_step: EolTestStep::Unknown(200),
+            content: EolTestSnapshotContent::InProgress("new step".to_owned()),
+        });
+        let input = &expected.to_bytes_v2();
+
+        assert!(matches!(
+            parse_telemetry_message_with_mode(input, ParsingMode::Strict),
+            Err(nom::Err::Error(_))
+        ));
+        assert_eq!(
+            parse_telemetry_message_with_mode(input, ParsingMode::Lenient),
+            Ok((&[][..], expected))
+        );
+    }
+
+    #[test]
+    fn unknown_message_type() {
+        let body = b"Z:\x02\x04v2.0\x00\x00\x00\x01\x00\x00\x00\x02\x00\x00\x00\x03\t\x00\x00\x00\x00\x00\x00\x00\x2A\tfuture payload";
+        let input = &flat(&[
+            b"\x03\x0C",
+            body,
+            &crc32fast::hash(body).to_be_bytes(),
+            b"\x30\xC0",
+            b"rest",
+        ]);
+        let expected = TelemetryMessage::Unknown {
+            telemetry_version: 2,
+            version: "v2.0".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            type_byte: b'Z',
+            payload: b"future payload".to_vec(),
+        };
+
+        assert!(matches!(
+            parse_telemetry_message(input),
+            Err(nom::Err::Error(_))
+        ));
+        assert_eq!(
+            parse_telemetry_message_with_mode(input, ParsingMode::Lenient),
+            Ok((&b"rest"[..], expected.clone()))
+        );
+        assert_eq!(
+            &expected.to_bytes_v2()[..],
+            &input[..input.len() - b"rest".len()]
+        );
+        assert!(matches!(
+            parse_telemetry_message_with_mode(&input[..input.len() - 8], ParsingMode::Lenient),
+            Err(nom::Err::Incomplete(_))
+        ));
+
+        // Frames without the common header of messages are not messages
+        let body = b"Z:\x02future payload";
+        let input = &flat(&[
+            b"\x03\x0C",
+            body,
+            &crc32fast::hash(body).to_be_bytes(),
+            b"\x30\xC0",
+        ]);
+        assert!(matches!(
+            parse_telemetry_message_with_mode(input, ParsingMode::Lenient),
+            Err(nom::Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn field_warnings_of_valid_frames() {
+        let snapshot = DataSnapshot {
+            telemetry_version: 1,
+            version: "v1".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            centile: 10,
+            // Sent as 0xFFFF, which does not fit in the signed field
+            pressure: -1,
+            phase: Phase::Inhalation,
+            subphase: Some(SubPhase::Inspiration),
+            blower_valve_position: 1,
+            patient_valve_position: 2,
+            blower_rpm: 3,
+            battery_level: 4,
+            inspiratory_flow: None,
+            expiratory_flow: None,
+        };
+        let input = TelemetryMessage::DataSnapshot(snapshot.clone()).to_bytes_v1();
+        let (_, (message, warnings)) =
+            parse_telemetry_message_with_warnings(&input, &ParserConfig::new()).unwrap();
+        assert_eq!(
+            message,
+            TelemetryMessage::DataSnapshot(DataSnapshot {
+                pressure: i16::MAX,
+                ..snapshot
+            })
+        );
+        assert_eq!(warnings, vec![FieldWarning::ClampedPressure(0xFFFF)]);
+
+        // Warnings of a corrupted frame are dropped with it
+        let mut corrupted = input.clone();
+        let crc_index = corrupted.len() - 3;
+        corrupted[crc_index] ^= 0xFF;
+        assert!(matches!(
+            parse_telemetry_message_with_warnings(&corrupted, &ParserConfig::new()),
+            Err(nom::Err::Failure(TelemetryError(
+                _,
+                TelemetryErrorKind::CrcError { .. }
+            )))
+        ));
+    }
+
+    #[test]
+    fn parser_config_from_environment() {
+        let vars = |mode: &'static str, size: &'static str| {
+            move |name: &str| match name {
+                PARSER_MODE_VAR => Some(mode.to_owned()),
+                PARSER_MAX_FRAME_SIZE_VAR => Some(size.to_owned()),
+                _ => None,
+            }
+        };
+        assert_eq!(
+            ParserConfig::from_vars(vars("Lenient", "4096")),
+            Ok(ParserConfig::new()
+                .mode(ParsingMode::Lenient)
+                .max_frame_size(4096))
+        );
+        assert_eq!(
+            ParserConfig::from_vars(vars("lenient", "0"))
+                .unwrap_err()
+                .name,
+            PARSER_MAX_FRAME_SIZE_VAR
+        );
+        assert!(ParserConfig::from_vars(vars("relaxed", "4096")).is_err());
+        assert_eq!(ParserConfig::from_vars(|_| None), Ok(ParserConfig::new()));
+    }
+
+    #[test]
+    fn incomplete_frames_are_limited_in_size() {
+        // Header and start of a boot message whose version string is never terminated
+        let mut input = b"\x03\x0CB:\x02\xFF".to_vec();
+        input.extend(std::iter::repeat_n(b'v', 100));
+        assert!(matches!(
+            parse_telemetry_message(&input),
+            Err(nom::Err::Incomplete(_))
+        ));
+        assert!(matches!(
+            parse_telemetry_message_with_config(&input, &ParserConfig::new().max_frame_size(64)),
+            Err(nom::Err::Error(_))
+        ));
+    }
+
+    #[test]
+    fn resync_to_next_header() {
+        assert_eq!(resync_offset(b"garbage\x03\x0CB:", 64), 7);
+        assert_eq!(resync_offset(b"garbage\x03\x0CB:", 4), 4);
+        assert_eq!(resync_offset(b"garbage\x03\x0CB:", 1), 1);
+        assert_eq!(resync_offset(b"garbage\x03", 64), 7);
+        assert_eq!(resync_offset(b"garbage", 64), 7);
+        assert_eq!(resync_offset(b"\x03\x0C\x03\x0C", 64), 2);
+        assert_eq!(resync_offset(b"x", 64), 1);
+    }
 }
@@ -9,15 +9,26 @@ use nom::IResult;
 use std::convert::TryFrom;
 
 use crate::control::*;
+use crate::diagnostics::{FieldWarning, FieldWarnings};
 use crate::structures::*;
 
 const VERSION: u8 = 1;
 
-fn sep<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
+/// Pressures are unsigned in protocol v1; the ones that do not fit in the signed field are clamped
+fn pressure_or_warning(pressure: u16, warnings: &FieldWarnings) -> i16 {
+    i16::try_from(pressure).unwrap_or_else(|_| {
+        warnings
+            .borrow_mut()
+            .push(FieldWarning::ClampedPressure(pressure));
+        i16::MAX
+    })
+}
+
+fn sep<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
     tag("\t")(input)
 }
 
-fn end<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
+fn end<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
     tag("\n")(input)
 }
 
@@ -76,7 +87,7 @@ fn triggered<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8],
 
 fn software_version<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
-) -> IResult<&'a [u8], &str, E> {
+) -> IResult<&'a [u8], &'a str, E> {
     let (rest, len) = be_u8(input)?;
     let mut parser = map_res(take(len), |bytes| {
         std::str::from_utf8(bytes)
@@ -117,6 +128,8 @@ fn boot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
                 systick,
                 mode,
                 value128,
+                control_capabilities: None,
+                session_nonce: None,
             })
         },
     );
@@ -183,6 +196,7 @@ fn stopped<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn data_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
@@ -237,7 +251,7 @@ fn data_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
                 device_id,
                 systick,
                 centile,
-                pressure: i16::try_from(pressure).unwrap_or(i16::MAX),
+                pressure: pressure_or_warning(pressure, warnings),
                 phase: phase_and_subphase.0,
                 subphase: Some(phase_and_subphase.1),
                 blower_valve_position,
@@ -380,6 +394,7 @@ fn machine_state_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u
 
 fn alarm_trap<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
@@ -440,7 +455,7 @@ fn alarm_trap<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
                 device_id,
                 systick,
                 centile,
-                pressure: i16::try_from(pressure).unwrap_or(i16::MAX),
+                pressure: pressure_or_warning(pressure, warnings),
                 phase: phase_and_subphase.0,
                 subphase: Some(phase_and_subphase.1),
                 cycle,
@@ -494,13 +509,24 @@ fn control_ack<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 /// This only decodes the message body: header, CRC and footer must be stripped beforehand.
 pub fn message<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+) -> IResult<&'a [u8], TelemetryMessage, E> {
+    message_with_warnings(input, &FieldWarnings::default())
+}
+
+/// Same as `message()`, collecting warnings about fields that were set to a fallback value
+pub(crate) fn message_with_warnings<
+    'a,
+    E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>,
+>(
+    input: &'a [u8],
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     nom::branch::alt((
         boot,
         stopped,
-        data_snapshot,
+        |i| data_snapshot(i, warnings),
         machine_state_snapshot,
-        alarm_trap,
+        |i| alarm_trap(i, warnings),
         control_ack,
     ))(input)
 }
@@ -556,6 +582,8 @@ mod tests {
                 systick,
                 mode,
                 value128,
+                control_capabilities: None,
+                session_nonce: None,
             };
             let input = &msg.to_bytes_v1();
             let expected = TelemetryMessage::BootMessage(msg);
@@ -641,7 +669,7 @@ mod tests {
                 device_id: format!("{}-{}-{}", device_id1, device_id2, device_id3),
                 systick,
                 centile,
-                pressure: i16::try_from(pressure).unwrap_or(i16::MAX),
+                pressure: pressure_or_warning(pressure, &FieldWarnings::default()),
                 phase: phase_subphase.0,
                 subphase: Some(phase_subphase.1),
                 blower_valve_position,
@@ -654,7 +682,7 @@ mod tests {
             let input = &msg.to_bytes_v1();
             let expected = TelemetryMessage::DataSnapshot(msg);
 
-            assert_eq!(nom::error::dbg_dmp(data_snapshot::<VerboseError<&[u8]>>, "data_snapshot")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| data_snapshot::<VerboseError<&[u8]>>(i, &FieldWarnings::default()), "data_snapshot")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -758,7 +786,7 @@ mod tests {
                 device_id: format!("{}-{}-{}", device_id1, device_id2, device_id3),
                 systick,
                 centile,
-                pressure: i16::try_from(pressure).unwrap_or(i16::MAX),
+                pressure: pressure_or_warning(pressure, &FieldWarnings::default()),
                 phase: phase_subphase.0,
                 subphase: Some(phase_subphase.1),
                 cycle,
@@ -772,7 +800,7 @@ mod tests {
             let input = &msg.to_bytes_v1();
             let expected = TelemetryMessage::AlarmTrap(msg);
 
-            assert_eq!(nom::error::dbg_dmp(alarm_trap::<VerboseError<&[u8]>>, "alarm_trap")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| alarm_trap::<VerboseError<&[u8]>>(i, &FieldWarnings::default()), "alarm_trap")(input), Ok((&[][..], expected)));
         }
     }
 
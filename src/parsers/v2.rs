@@ -1,6 +1,6 @@
 use nom::branch::alt;
 use nom::bytes::streaming::{tag, take};
-use nom::combinator::{map, map_res};
+use nom::combinator::{cond, map, map_res};
 use nom::error::{FromExternalError, ParseError};
 use nom::multi::length_data;
 use nom::number::streaming::{be_i16, be_u16, be_u32, be_u64, be_u8};
@@ -9,16 +9,17 @@ use nom::IResult;
 use std::convert::TryFrom;
 
 use crate::control::*;
+use crate::diagnostics::{FieldWarning, FieldWarnings};
 use crate::locale::Locale;
 use crate::structures::*;
 
 const VERSION: u8 = 2;
 
-fn sep<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
+fn sep<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
     tag("\t")(input)
 }
 
-fn end<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &[u8], E> {
+fn end<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8], &'a [u8], E> {
     tag("\n")(input)
 }
 
@@ -72,7 +73,7 @@ fn triggered<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8],
 
 fn software_version<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
-) -> IResult<&'a [u8], &str, E> {
+) -> IResult<&'a [u8], &'a str, E> {
     let (rest, len) = be_u8(input)?;
     let mut parser = map_res(take(len), |bytes| {
         std::str::from_utf8(bytes)
@@ -88,12 +89,25 @@ fn device_id<'a, E: ParseError<&'a [u8]>>(input: &'a [u8]) -> IResult<&'a [u8],
     parser(input)
 }
 
-fn ventilation_mode<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
+/// Header following the protocol version in every message: firmware version, device ID and systick (the same in every protocol version)
+pub(crate) fn common_header<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
+    input: &'a [u8],
+) -> IResult<&'a [u8], (String, String, u64), E> {
+    let mut parser = map(
+        tuple((software_version, device_id, sep, be_u64, sep)),
+        |(software_version, device_id, _, systick, _)| {
+            (software_version.to_owned(), device_id, systick)
+        },
+    );
+    parser(input)
+}
+
+// Unknown values are kept as is; strict parsing rejects them afterwards (see `parsers::ParsingMode`)
+fn ventilation_mode<'a, E: ParseError<&'a [u8]>>(
     input: &'a [u8],
 ) -> IResult<&'a [u8], VentilationMode, E> {
-    let mut parser = map_res(be_u8, |b| {
-        VentilationMode::try_from(b)
-            .map_err(|_e| E::from_error_kind(input, nom::error::ErrorKind::Fail))
+    let mut parser = map(be_u8, |b| {
+        VentilationMode::try_from(b).unwrap_or(VentilationMode::Unknown(b))
     });
     parser(input)
 }
@@ -161,12 +175,12 @@ fn fatal_error_details<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8],
     }
 }
 
-fn eol_test_step<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
+// Unknown values are kept as is; strict parsing rejects them afterwards (see `parsers::ParsingMode`)
+fn eol_test_step<'a, E: ParseError<&'a [u8]>>(
     input: &'a [u8],
 ) -> IResult<&'a [u8], EolTestStep, E> {
-    let mut parser = map_res(be_u8, |b| {
-        EolTestStep::try_from(b)
-            .map_err(|_e| E::from_error_kind(input, nom::error::ErrorKind::Fail))
+    let mut parser = map(be_u8, |b| {
+        EolTestStep::try_from(b).unwrap_or(EolTestStep::Unknown(b))
     });
     parser(input)
 }
@@ -212,13 +226,25 @@ fn patient_gender<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     parser(input)
 }
 
+/// Decode a locale, and warn about unknown locales (they are probably sent by a misconfigured firmware)
+fn locale_or_warning(locale: u16, warnings: &FieldWarnings) -> Option<Locale> {
+    let decoded = Locale::try_from_u16(locale);
+    if decoded.is_none() {
+        warnings
+            .borrow_mut()
+            .push(FieldWarning::UnknownLocale(locale));
+    }
+    decoded
+}
+
 fn boot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tag("B:"),
-            tag([VERSION]),
+            tag([version]),
             software_version,
             device_id,
             sep,
@@ -227,16 +253,20 @@ fn boot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
             mode,
             sep,
             be_u8,
+            // Protocol v3 adds the control capabilities and the session nonce
+            cond(version >= 3, tuple((sep, be_u16, sep, be_u32))),
             end,
         )),
-        |(_, _, software_version, device_id, _, systick, _, mode, _, value128, _)| {
+        |(_, _, software_version, device_id, _, systick, _, mode, _, value128, session, _)| {
             TelemetryMessage::BootMessage(BootMessage {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
                 mode,
                 value128,
+                control_capabilities: session.map(|(_, capabilities, _, _)| capabilities),
+                session_nonce: session.map(|(_, _, _, nonce)| nonce),
             })
         },
     );
@@ -245,12 +275,14 @@ fn boot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn stopped<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tuple((
                 tag("O:"),
-                tag([VERSION]),
+                tag([version]),
                 software_version,
                 device_id,
                 sep,
@@ -395,7 +427,7 @@ fn stopped<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
             ),
         )| {
             TelemetryMessage::StoppedMessage(StoppedMessage {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -436,7 +468,7 @@ fn stopped<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
                 inspiratory_duration_command: Some(inspiratory_duration_command),
                 battery_level: Some(battery_level),
                 current_alarm_codes: Some(current_alarm_codes),
-                locale: Locale::try_from_u16(locale),
+                locale: locale_or_warning(locale, warnings),
                 patient_height: Some(patient_height),
                 patient_gender: Some(patient_gender),
                 peak_pressure_alarm_threshold: Some(peak_pressure_alarm_threshold),
@@ -448,12 +480,13 @@ fn stopped<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn data_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tuple((
                 tag("D:"),
-                tag([VERSION]),
+                tag([version]),
                 software_version,
                 device_id,
                 sep,
@@ -503,7 +536,7 @@ fn data_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
             (inspiratory_flow, _, expiratory_flow, _),
         )| {
             TelemetryMessage::DataSnapshot(DataSnapshot {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -525,12 +558,14 @@ fn data_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn machine_state_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tuple((
                 tag("S:"),
-                tag([VERSION]),
+                tag([version]),
                 software_version,
                 device_id,
                 sep,
@@ -699,7 +734,7 @@ fn machine_state_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u
             (_, peak_pressure_alarm_threshold, _),
         )| {
             TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -751,7 +786,7 @@ fn machine_state_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u
                 inspiratory_duration_command: Some(inspiratory_duration_command),
                 previous_inspiratory_duration: Some(previous_inspiratory_duration),
                 battery_level: Some(battery_level),
-                locale: Locale::try_from_u16(locale),
+                locale: locale_or_warning(locale, warnings),
                 patient_height: Some(patient_height),
                 patient_gender: Some(patient_gender),
                 peak_pressure_alarm_threshold: Some(peak_pressure_alarm_threshold),
@@ -763,12 +798,13 @@ fn machine_state_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u
 
 fn alarm_trap<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tuple((
                 tag("T:"),
-                tag([VERSION]),
+                tag([version]),
                 software_version,
                 device_id,
                 sep,
@@ -818,7 +854,7 @@ fn alarm_trap<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
             (expected, _, measured, _, cycles_since_trigger, _),
         )| {
             TelemetryMessage::AlarmTrap(AlarmTrap {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -841,11 +877,12 @@ fn alarm_trap<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn control_ack<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tag("A:"),
-            tag([VERSION]),
+            tag([version]),
             software_version,
             device_id,
             sep,
@@ -858,7 +895,7 @@ fn control_ack<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
         )),
         |(_, _, software_version, device_id, _, systick, _, setting, _, value, _)| {
             TelemetryMessage::ControlAck(ControlAck {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -872,11 +909,12 @@ fn control_ack<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn fatal_error<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tag("E:"),
-            tag([VERSION]),
+            tag([version]),
             software_version,
             device_id,
             sep,
@@ -887,7 +925,7 @@ fn fatal_error<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
         )),
         |(_, _, software_version, device_id, _, systick, _, error, _)| {
             TelemetryMessage::FatalError(FatalError {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -900,11 +938,12 @@ fn fatal_error<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
 
 fn eol_test_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+    version: u8,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     let mut parser = map(
         tuple((
             tag("L:"),
-            tag([VERSION]),
+            tag([version]),
             software_version,
             device_id,
             sep,
@@ -917,7 +956,7 @@ fn eol_test_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E
         )),
         |(_, _, software_version, device_id, _, systick, _, current_step, _, content, _)| {
             TelemetryMessage::EolTestSnapshot(EolTestSnapshot {
-                telemetry_version: VERSION,
+                telemetry_version: version,
                 version: software_version.to_owned(),
                 device_id,
                 systick,
@@ -936,16 +975,36 @@ fn eol_test_snapshot<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E
 /// This only decodes the message body: header, CRC and footer must be stripped beforehand.
 pub fn message<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
     input: &'a [u8],
+) -> IResult<&'a [u8], TelemetryMessage, E> {
+    message_with_warnings(input, &FieldWarnings::default())
+}
+
+/// Same as `message()`, collecting warnings about fields that were set to a fallback value
+pub(crate) fn message_with_warnings<
+    'a,
+    E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>,
+>(
+    input: &'a [u8],
+    warnings: &FieldWarnings,
+) -> IResult<&'a [u8], TelemetryMessage, E> {
+    message_with_version(input, VERSION, warnings)
+}
+
+/// Same as `message_with_warnings()`, for a later protocol version that kept the layouts of protocol v2 (see `parsers::v3`)
+pub(crate) fn message_with_version<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
+    input: &'a [u8],
+    version: u8,
+    warnings: &FieldWarnings,
 ) -> IResult<&'a [u8], TelemetryMessage, E> {
     nom::branch::alt((
-        boot,
-        stopped,
-        data_snapshot,
-        machine_state_snapshot,
-        alarm_trap,
-        control_ack,
-        fatal_error,
-        eol_test_snapshot,
+        |i| boot(i, version),
+        |i| stopped(i, version, warnings),
+        |i| data_snapshot(i, version),
+        |i| machine_state_snapshot(i, version, warnings),
+        |i| alarm_trap(i, version),
+        |i| control_ack(i, version),
+        |i| fatal_error(i, version),
+        |i| eol_test_snapshot(i, version),
     ))(input)
 }
 
@@ -961,6 +1020,17 @@ mod tests {
     use proptest::option;
     use proptest::prelude::*;
 
+    #[test]
+    fn unknown_locales_become_none() {
+        let warnings = FieldWarnings::default();
+        assert_eq!(
+            locale_or_warning(0x6672, &warnings),
+            Locale::try_from("fr").ok()
+        );
+        assert_eq!(locale_or_warning(0xFFFF, &warnings), None);
+        assert_eq!(warnings.take(), vec![FieldWarning::UnknownLocale(0xFFFF)]);
+    }
+
     fn phase_strategy() -> impl Strategy<Value = Phase> {
         prop_oneof![Just(Phase::Inhalation), Just(Phase::Exhalation)]
     }
@@ -1078,11 +1148,13 @@ mod tests {
                 systick,
                 mode,
                 value128,
+                control_capabilities: None,
+                session_nonce: None,
             };
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::BootMessage(msg);
 
-            assert_eq!(nom::error::dbg_dmp(boot::<VerboseError<&[u8]>>, "boot")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| boot::<VerboseError<&[u8]>>(i, VERSION), "boot")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1169,7 +1241,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::StoppedMessage(msg);
 
-            assert_eq!(nom::error::dbg_dmp(stopped::<VerboseError<&[u8]>>, "stopped")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| stopped::<VerboseError<&[u8]>>(i, VERSION, &FieldWarnings::default()), "stopped")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1210,7 +1282,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::DataSnapshot(msg);
 
-            assert_eq!(nom::error::dbg_dmp(data_snapshot::<VerboseError<&[u8]>>, "data_snapshot")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| data_snapshot::<VerboseError<&[u8]>>(i, VERSION), "data_snapshot")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1311,7 +1383,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::MachineStateSnapshot(msg);
 
-            assert_eq!(nom::error::dbg_dmp(machine_state_snapshot::<VerboseError<&[u8]>>, "machine_state_snapshot")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| machine_state_snapshot::<VerboseError<&[u8]>>(i, VERSION, &FieldWarnings::default()), "machine_state_snapshot")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1354,7 +1426,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::AlarmTrap(msg);
 
-            assert_eq!(nom::error::dbg_dmp(alarm_trap::<VerboseError<&[u8]>>, "alarm_trap")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| alarm_trap::<VerboseError<&[u8]>>(i, VERSION), "alarm_trap")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1380,7 +1452,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::ControlAck(msg);
 
-            assert_eq!(nom::error::dbg_dmp(control_ack::<VerboseError<&[u8]>>, "control_ack")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| control_ack::<VerboseError<&[u8]>>(i, VERSION), "control_ack")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1404,7 +1476,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::FatalError(msg);
 
-            assert_eq!(nom::error::dbg_dmp(fatal_error::<VerboseError<&[u8]>>, "fatal_error")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| fatal_error::<VerboseError<&[u8]>>(i, VERSION), "fatal_error")(input), Ok((&[][..], expected)));
         }
     }
 
@@ -1430,7 +1502,7 @@ mod tests {
             let input = &msg.to_bytes_v2();
             let expected = TelemetryMessage::EolTestSnapshot(msg);
 
-            assert_eq!(nom::error::dbg_dmp(eol_test_snapshot::<VerboseError<&[u8]>>, "eol_test_snapshot")(input), Ok((&[][..], expected)));
+            assert_eq!(nom::error::dbg_dmp(|i| eol_test_snapshot::<VerboseError<&[u8]>>(i, VERSION), "eol_test_snapshot")(input), Ok((&[][..], expected)));
         }
     }
 }
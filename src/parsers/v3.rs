@@ -0,0 +1,102 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use nom::error::{FromExternalError, ParseError};
+use nom::IResult;
+
+use super::v2;
+use crate::diagnostics::FieldWarnings;
+use crate::structures::*;
+
+const VERSION: u8 = 3;
+
+/// Transform bytes into a structured telemetry message
+///
+/// * `input` - Bytes to parse.
+///
+/// This only decodes the message body: header, CRC and footer must be stripped beforehand.
+/// Messages of protocol v3 have the same layouts as in protocol v2, except boot messages which end with the control capabilities of the firmware and the nonce of its session.
+pub fn message<'a, E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>>(
+    input: &'a [u8],
+) -> IResult<&'a [u8], TelemetryMessage, E> {
+    message_with_warnings(input, &FieldWarnings::default())
+}
+
+/// Same as `message()`, collecting warnings about fields that were set to a fallback value
+pub(crate) fn message_with_warnings<
+    'a,
+    E: ParseError<&'a [u8]> + FromExternalError<&'a [u8], E>,
+>(
+    input: &'a [u8],
+    warnings: &FieldWarnings,
+) -> IResult<&'a [u8], TelemetryMessage, E> {
+    v2::message_with_version(input, VERSION, warnings)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+    use crate::parsers::parse_telemetry_message;
+    use crate::serializers::ToBytes;
+
+    #[test]
+    fn parses_v3_messages() {
+        let ack = ControlAck {
+            telemetry_version: VERSION,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+        let expected = TelemetryMessage::ControlAck(ack.clone());
+
+        let payload = ack.to_bytes_v3();
+        assert_eq!(payload[2], VERSION);
+        assert_eq!(
+            message::<nom::error::VerboseError<&[u8]>>(&payload),
+            Ok((&[][..], expected.clone()))
+        );
+        assert_eq!(
+            parse_telemetry_message(&expected.to_bytes_v3()),
+            Ok((&[][..], expected))
+        );
+    }
+
+    #[test]
+    fn parses_control_capabilities_of_v3_boot_messages() {
+        let boot = BootMessage {
+            telemetry_version: VERSION,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            mode: Mode::Production,
+            value128: 128,
+            control_capabilities: Some(1),
+            session_nonce: Some(0xCAFE),
+        };
+        let expected = TelemetryMessage::BootMessage(boot.clone());
+        assert_eq!(
+            message::<nom::error::VerboseError<&[u8]>>(&boot.to_bytes_v3()),
+            Ok((&[][..], expected.clone()))
+        );
+
+        // Older versions do not advertise anything
+        let downgraded = expected.downgrade_to(2).unwrap();
+        assert!(matches!(
+            &downgraded,
+            TelemetryMessage::BootMessage(BootMessage {
+                control_capabilities: None,
+                session_nonce: None,
+                ..
+            })
+        ));
+        assert_eq!(
+            parse_telemetry_message(&downgraded.to_bytes_v2()),
+            Ok((&[][..], downgraded))
+        );
+    }
+}
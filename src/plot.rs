@@ -0,0 +1,312 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fmt::Display;
+use std::ops::Range;
+use std::path::Path;
+
+use plotters::coord::Shift;
+use plotters::prelude::*;
+
+use crate::structures::TelemetryMessage;
+use crate::units;
+
+/// Error that can happen when rendering waveforms
+#[derive(Debug, thiserror::Error)]
+pub enum PlotError {
+    /// File extension does not match a supported image format
+    #[error("unsupported image format for {0} (supported extensions are: png, svg)")]
+    UnsupportedFormat(String),
+    /// There is no data snapshot to plot
+    #[error("no data to plot")]
+    NoData,
+    /// Something went wrong while drawing or writing the image
+    #[error("failed to draw waveforms: {0}")]
+    Drawing(String),
+}
+
+fn drawing_error<E: Display>(error: E) -> PlotError {
+    PlotError::Drawing(error.to_string())
+}
+
+/// Options to render waveforms
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PlotOptions {
+    /// Width of the image in pixels
+    pub width: u32,
+    /// Height of the image in pixels
+    pub height: u32,
+    /// Title displayed above the curves
+    pub title: Option<String>,
+}
+
+impl Default for PlotOptions {
+    fn default() -> Self {
+        Self {
+            width: 1200,
+            height: 900,
+            title: None,
+        }
+    }
+}
+
+/// Pressure, flow and volume curves extracted from data snapshots
+///
+/// Flow and volume are only available with protocol v2; volume is the integral of the flow since the beginning of the current cycle.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct Waveforms {
+    /// Time in seconds since the first data snapshot
+    pub time: Vec<f64>,
+    /// Pressure in cmH2O
+    pub pressure: Vec<f64>,
+    /// Net flow (inspiratory minus expiratory) in L/min
+    pub flow: Vec<Option<f64>>,
+    /// Volume in mL
+    pub volume: Vec<Option<f64>>,
+}
+
+impl Waveforms {
+    /// Extract curves from the data snapshots of a sequence of messages (other messages are ignored)
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(messages: I) -> Self {
+        let mut waveforms = Self::default();
+        let mut first_systick = None;
+        let mut previous: Option<(u64, u16)> = None;
+        let mut volume = 0.0;
+
+        for message in messages {
+            let snapshot = match message {
+                TelemetryMessage::DataSnapshot(snapshot) => snapshot,
+                _ => continue,
+            };
+            let first_systick = *first_systick.get_or_insert(snapshot.systick);
+
+            let flow = match (snapshot.inspiratory_flow, snapshot.expiratory_flow) {
+                (Some(inspiratory), Some(expiratory)) => {
+                    Some(f64::from(inspiratory) - f64::from(expiratory))
+                }
+                (Some(inspiratory), None) => Some(f64::from(inspiratory)),
+                _ => None,
+            };
+            // A new cycle starts when the centile goes back to a lower value
+            match previous {
+                Some((_, centile)) if snapshot.centile < centile => volume = 0.0,
+                Some((systick, _)) => {
+                    volume += units::cl_per_min_volume(
+                        flow.unwrap_or(0.0),
+                        snapshot.systick.saturating_sub(systick),
+                    );
+                }
+                None => (),
+            }
+            previous = Some((snapshot.systick, snapshot.centile));
+
+            waveforms
+                .time
+                .push(snapshot.systick.saturating_sub(first_systick) as f64 / 1_000_000.0);
+            waveforms
+                .pressure
+                .push(units::mmh2o_to_cmh2o(f64::from(snapshot.pressure)));
+            waveforms
+                .flow
+                .push(flow.map(units::cl_per_min_to_l_per_min));
+            waveforms.volume.push(flow.map(|_| volume));
+        }
+
+        waveforms
+    }
+
+    /// Number of points of each curve
+    pub fn len(&self) -> usize {
+        self.time.len()
+    }
+
+    /// Whether there is no point to plot
+    pub fn is_empty(&self) -> bool {
+        self.time.is_empty()
+    }
+
+    /// Render curves to an image; its format (PNG or SVG) is deduced from the extension of the path
+    pub fn render<P: AsRef<Path>>(&self, path: P, options: &PlotOptions) -> Result<(), PlotError> {
+        let path = path.as_ref();
+        if self.is_empty() {
+            return Err(PlotError::NoData);
+        }
+
+        let size = (options.width, options.height);
+        match path
+            .extension()
+            .and_then(|ext| ext.to_str())
+            .map(|ext| ext.to_lowercase())
+            .as_deref()
+        {
+            Some("png") => self.draw(BitMapBackend::new(path, size).into_drawing_area(), options),
+            Some("svg") => self.draw(SVGBackend::new(path, size).into_drawing_area(), options),
+            _ => Err(PlotError::UnsupportedFormat(path.display().to_string())),
+        }
+    }
+
+    /// Render curves to an SVG document held in memory (e.g. to embed it in an HTML page)
+    pub fn render_svg(&self, options: &PlotOptions) -> Result<String, PlotError> {
+        if self.is_empty() {
+            return Err(PlotError::NoData);
+        }
+
+        let mut svg = String::new();
+        self.draw(
+            SVGBackend::with_string(&mut svg, (options.width, options.height)).into_drawing_area(),
+            options,
+        )?;
+        Ok(svg)
+    }
+
+    fn draw<DB: DrawingBackend>(
+        &self,
+        root: DrawingArea<DB, Shift>,
+        options: &PlotOptions,
+    ) -> Result<(), PlotError>
+    where
+        DB::ErrorType: 'static,
+    {
+        root.fill(&WHITE).map_err(drawing_error)?;
+        let root = match &options.title {
+            Some(title) => root
+                .titled(title, ("sans-serif", 24))
+                .map_err(drawing_error)?,
+            None => root,
+        };
+
+        let panels = root.split_evenly((3, 1));
+        let x_range = 0.0..self.time.last().copied().unwrap_or(0.0).max(0.01);
+        let flow: Vec<(f64, f64)> = Self::points(&self.time, &self.flow);
+        let volume: Vec<(f64, f64)> = Self::points(&self.time, &self.volume);
+        let pressure: Vec<(f64, f64)> = self
+            .time
+            .iter()
+            .copied()
+            .zip(self.pressure.iter().copied())
+            .collect();
+
+        Self::draw_curve(
+            &panels[0],
+            x_range.clone(),
+            "Pressure (cmH2O)",
+            &pressure,
+            &BLUE,
+        )?;
+        Self::draw_curve(&panels[1], x_range.clone(), "Flow (L/min)", &flow, &GREEN)?;
+        Self::draw_curve(&panels[2], x_range, "Volume (mL)", &volume, &RED)?;
+
+        root.present().map_err(drawing_error)
+    }
+
+    fn points(time: &[f64], values: &[Option<f64>]) -> Vec<(f64, f64)> {
+        time.iter()
+            .zip(values)
+            .filter_map(|(time, value)| value.map(|value| (*time, value)))
+            .collect()
+    }
+
+    fn draw_curve<DB: DrawingBackend>(
+        area: &DrawingArea<DB, Shift>,
+        x_range: Range<f64>,
+        label: &str,
+        points: &[(f64, f64)],
+        color: &RGBColor,
+    ) -> Result<(), PlotError>
+    where
+        DB::ErrorType: 'static,
+    {
+        let (min, max) = points
+            .iter()
+            .fold((0.0_f64, 0.0_f64), |(min, max), (_, value)| {
+                (min.min(*value), max.max(*value))
+            });
+        // Add a margin so that curves do not touch the borders, and avoid empty ranges
+        let margin = ((max - min) * 0.1).max(1.0);
+
+        let mut chart = ChartBuilder::on(area)
+            .margin(10)
+            .x_label_area_size(30)
+            .y_label_area_size(60)
+            .build_cartesian_2d(x_range, (min - margin)..(max + margin))
+            .map_err(drawing_error)?;
+        chart
+            .configure_mesh()
+            .x_desc("Time (s)")
+            .y_desc(label)
+            .draw()
+            .map_err(drawing_error)?;
+        chart
+            .draw_series(LineSeries::new(points.iter().copied(), color))
+            .map_err(drawing_error)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{DataSnapshot, Phase};
+
+    fn snapshot(systick: u64, centile: u16, flow: i16) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick,
+            centile,
+            pressure: 100,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow: Some(flow),
+            expiratory_flow: Some(0),
+        })
+    }
+
+    #[test]
+    fn integrates_volume_per_cycle() {
+        // 60 L/min during one second gives 1 L
+        let messages = vec![
+            snapshot(0, 0, 6000),
+            snapshot(500_000, 50, 6000),
+            snapshot(1_000_000, 100, 6000),
+            snapshot(1_010_000, 0, 6000),
+        ];
+        let waveforms = Waveforms::from_messages(&messages);
+
+        assert_eq!(waveforms.time, vec![0.0, 0.5, 1.0, 1.01]);
+        assert_eq!(waveforms.pressure, vec![10.0; 4]);
+        assert_eq!(waveforms.flow, vec![Some(60.0); 4]);
+        assert_eq!(
+            waveforms.volume,
+            vec![Some(0.0), Some(500.0), Some(1000.0), Some(0.0)]
+        );
+    }
+
+    #[test]
+    fn renders_svg() {
+        let messages = vec![snapshot(0, 0, 100), snapshot(10_000, 1, -100)];
+        let path = std::env::temp_dir().join("makair-telemetry-waveforms.svg");
+        Waveforms::from_messages(&messages)
+            .render(&path, &PlotOptions::default())
+            .unwrap();
+        let svg = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert!(svg.contains("<svg"));
+
+        assert!(matches!(
+            Waveforms::default().render(&path, &PlotOptions::default()),
+            Err(PlotError::NoData)
+        ));
+        assert!(matches!(
+            Waveforms::from_messages(&messages).render("waveforms.gif", &PlotOptions::default()),
+            Err(PlotError::UnsupportedFormat(_))
+        ));
+    }
+}
@@ -0,0 +1,142 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+/// Operating system family, as far as serial port names are concerned
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Platform {
+    /// Windows: ports are named `COM1`, `COM2`, etc.
+    Windows,
+    /// macOS: each port has a `/dev/tty.*` (dial-in) and a `/dev/cu.*` (call-out) device
+    MacOs,
+    /// Linux and other Unix systems: ports are `/dev/tty*` devices
+    Unix,
+}
+
+impl Platform {
+    /// Platform the program was built for
+    pub fn current() -> Self {
+        if cfg!(windows) {
+            Self::Windows
+        } else if cfg!(target_os = "macos") {
+            Self::MacOs
+        } else {
+            Self::Unix
+        }
+    }
+}
+
+/// Turn a port name typed by a user into the name expected by the serial library on the current platform
+///
+/// See `canonical_port_name_for()` for the rules.
+pub fn canonical_port_name(port: &str) -> String {
+    canonical_port_name_for(port, Platform::current())
+}
+
+/// Turn a port name typed by a user into the name expected by the serial library on a platform
+///
+/// * On Windows, `com3`, `COM3:` and `\\.\COM3` all become `COM3` (the serial library adds the `\\.\` prefix itself, which is required from `COM10`).
+/// * On macOS, `/dev/tty.*` devices become `/dev/cu.*`: opening the former blocks until the carrier is detected, which USB bridges never signal.
+/// * On Unix systems, bare device names (e.g. `ttyUSB0`) are looked up in `/dev`.
+pub fn canonical_port_name_for(port: &str, platform: Platform) -> String {
+    let port = port.trim();
+    match platform {
+        Platform::Windows => {
+            let name = port
+                .strip_prefix(r"\\.\")
+                .or_else(|| port.strip_prefix(r"\\?\"))
+                .unwrap_or(port);
+            let name = name.strip_suffix(':').unwrap_or(name);
+            let is_com_port = name.len() > 3
+                && name
+                    .get(..3)
+                    .is_some_and(|prefix| prefix.eq_ignore_ascii_case("com"))
+                && name[3..].chars().all(|c| c.is_ascii_digit());
+            if is_com_port {
+                format!("COM{}", &name[3..])
+            } else {
+                name.to_owned()
+            }
+        }
+        Platform::MacOs => {
+            let path = with_dev_prefix(port);
+            match path.strip_prefix("/dev/tty.") {
+                Some(name) => format!("/dev/cu.{}", name),
+                None => path,
+            }
+        }
+        Platform::Unix => with_dev_prefix(port),
+    }
+}
+
+fn with_dev_prefix(port: &str) -> String {
+    if port.contains('/') {
+        port.to_owned()
+    } else {
+        format!("/dev/{}", port)
+    }
+}
+
+/// Whether a device name (in `/dev`) looks like a serial port a MakAir could be connected to on a platform
+///
+/// Built-in UARTs of PCs (`ttyS*`) always exist, so they are not considered.
+pub fn is_candidate_device(name: &str, platform: Platform) -> bool {
+    match platform {
+        Platform::Windows => false,
+        Platform::MacOs => name.starts_with("cu.") && name != "cu.Bluetooth-Incoming-Port",
+        Platform::Unix => {
+            ["ttyUSB", "ttyACM", "ttyAMA"]
+                .iter()
+                .any(|prefix| name.starts_with(prefix))
+                || name == "serial0"
+        }
+    }
+}
+
+/// List serial ports a MakAir could be connected to, sorted by name
+///
+/// On Unix systems, this looks for USB and Raspberry Pi serial devices in `/dev`; on Windows, it tries to open `COM1` to `COM32`.
+pub fn list_ports() -> std::io::Result<Vec<String>> {
+    let platform = Platform::current();
+    let mut ports = Vec::new();
+
+    if platform == Platform::Windows {
+        for n in 1..=32 {
+            let name = format!("COM{}", n);
+            if serial::open(&name).is_ok() {
+                ports.push(name);
+            }
+        }
+    } else {
+        for entry in std::fs::read_dir("/dev")? {
+            let name = entry?.file_name();
+            if let Some(name) = name.to_str() {
+                if is_candidate_device(name, platform) {
+                    ports.push(format!("/dev/{}", name));
+                }
+            }
+        }
+    }
+
+    ports.sort();
+    Ok(ports)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn candidate_devices() {
+        assert!(is_candidate_device("ttyUSB0", Platform::Unix));
+        assert!(is_candidate_device("serial0", Platform::Unix));
+        assert!(!is_candidate_device("ttyS0", Platform::Unix));
+        assert!(is_candidate_device("cu.usbserial-1410", Platform::MacOs));
+        assert!(!is_candidate_device("tty.usbserial-1410", Platform::MacOs));
+        assert!(!is_candidate_device(
+            "cu.Bluetooth-Incoming-Port",
+            Platform::MacOs
+        ));
+    }
+}
@@ -0,0 +1,385 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::BTreeSet;
+use std::io::{ErrorKind, Read};
+use std::time::{Duration, Instant};
+
+use crate::filter::MessageType;
+use crate::lint::{CaptureLinter, LintCategory, LintIssue};
+
+/// Default time during which a device is listened to
+pub const DEFAULT_PROBE_DURATION: Duration = Duration::from_secs(5);
+
+const CHUNK_SIZE: usize = 1024;
+
+/// Number and pace of the messages of one type received while probing
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct MessageCadence {
+    /// Type of the messages
+    pub message_type: MessageType,
+    /// Number of messages received
+    pub count: usize,
+    /// Mean interval between two messages according to their systick, in µs (if at least two were received)
+    pub mean_interval: Option<u64>,
+}
+
+/// What was learnt about a device by listening to it for a short time
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct ProbeReport {
+    /// Time spent listening
+    pub duration: Duration,
+    /// Number of bytes received
+    pub bytes: u64,
+    /// Number of frames that could be decoded
+    pub frames: usize,
+    /// Versions of the telemetry protocol used by the decoded frames (more than one is a firmware bug)
+    pub protocol_versions: BTreeSet<u8>,
+    /// Version of the firmware, according to the last decoded message
+    pub firmware_version: Option<String>,
+    /// Internal ID of the MCU, according to the last decoded message
+    pub device_id: Option<String>,
+    /// Messages received, by type, in the order their types were first seen
+    pub cadence: Vec<MessageCadence>,
+    /// Protocol compliance issues found in the received bytes
+    pub warnings: Vec<LintIssue>,
+}
+
+impl ProbeReport {
+    /// Number of frames with an invalid CRC
+    pub fn crc_errors(&self) -> usize {
+        self.warnings
+            .iter()
+            .filter(|warning| warning.category == LintCategory::Crc)
+            .count()
+    }
+}
+
+impl std::fmt::Display for ProbeReport {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(
+            f,
+            "listened for {:.1}s: {} bytes, {} frames, {} CRC errors",
+            self.duration.as_secs_f64(),
+            self.bytes,
+            self.frames,
+            self.crc_errors()
+        )?;
+        let versions: Vec<String> = self
+            .protocol_versions
+            .iter()
+            .map(|version| version.to_string())
+            .collect();
+        writeln!(
+            f,
+            "protocol version: {}",
+            if versions.is_empty() {
+                "unknown".to_owned()
+            } else {
+                versions.join(", ")
+            }
+        )?;
+        writeln!(
+            f,
+            "firmware version: {}",
+            self.firmware_version.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(
+            f,
+            "device ID: {}",
+            self.device_id.as_deref().unwrap_or("unknown")
+        )?;
+        writeln!(f, "message cadence:")?;
+        for cadence in &self.cadence {
+            write!(
+                f,
+                "  {}: {} message(s)",
+                cadence.message_type.name(),
+                cadence.count
+            )?;
+            match cadence.mean_interval {
+                Some(interval) => writeln!(f, ", every {:.1} ms", interval as f64 / 1000.0)?,
+                None => writeln!(f)?,
+            }
+        }
+        if self.warnings.is_empty() {
+            writeln!(f, "no compliance warning")
+        } else {
+            writeln!(f, "{} compliance warning(s):", self.warnings.len())?;
+            for warning in &self.warnings {
+                writeln!(f, "  {}", warning)?;
+            }
+            Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+struct TypeTracker {
+    message_type: MessageType,
+    count: usize,
+    first_systick: u64,
+    last_systick: u64,
+}
+
+/// Builder of a `ProbeReport` from the bytes received from a device
+///
+/// Bytes are expected to start anywhere in the output of a running device: the missing boot message and partial frames are not reported.
+pub struct Probe {
+    started_at: Instant,
+    bytes: u64,
+    linter: CaptureLinter,
+    protocol_versions: BTreeSet<u8>,
+    firmware_version: Option<String>,
+    device_id: Option<String>,
+    types: Vec<TypeTracker>,
+}
+
+impl Default for Probe {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Probe {
+    /// Start probing a device
+    pub fn new() -> Self {
+        Self {
+            started_at: Instant::now(),
+            bytes: 0,
+            linter: CaptureLinter::mid_stream(),
+            protocol_versions: BTreeSet::new(),
+            firmware_version: None,
+            device_id: None,
+            types: Vec::new(),
+        }
+    }
+
+    /// Handle the next bytes received from the device
+    pub fn push(&mut self, bytes: &[u8]) {
+        self.bytes += bytes.len() as u64;
+        let Self {
+            linter,
+            protocol_versions,
+            firmware_version,
+            device_id,
+            types,
+            ..
+        } = self;
+        linter.push_with(bytes, |message| {
+            protocol_versions.insert(message.telemetry_version());
+            *firmware_version = Some(message.version());
+            *device_id = Some(message.device_id());
+
+            let message_type = MessageType::of(message);
+            let systick = message.systick();
+            match types.iter_mut().find(|t| t.message_type == message_type) {
+                Some(tracker) => {
+                    tracker.count += 1;
+                    tracker.last_systick = systick;
+                }
+                None => types.push(TypeTracker {
+                    message_type,
+                    count: 1,
+                    first_systick: systick,
+                    last_systick: systick,
+                }),
+            }
+        });
+    }
+
+    /// Stop probing and build the report
+    pub fn finish(self) -> ProbeReport {
+        let cadence = self
+            .types
+            .iter()
+            .map(|tracker| MessageCadence {
+                message_type: tracker.message_type,
+                count: tracker.count,
+                // Reboots restart systicks
+                mean_interval: (tracker.count > 1 && tracker.last_systick > tracker.first_systick)
+                    .then(|| {
+                        (tracker.last_systick - tracker.first_systick) / (tracker.count as u64 - 1)
+                    }),
+            })
+            .collect();
+        let lint = self.linter.finish();
+        ProbeReport {
+            duration: self.started_at.elapsed(),
+            bytes: self.bytes,
+            frames: lint.frames,
+            protocol_versions: self.protocol_versions,
+            firmware_version: self.firmware_version,
+            device_id: self.device_id,
+            cadence,
+            warnings: lint.issues,
+        }
+    }
+}
+
+/// Listen to a device through a reader for some time, or until the end of the stream
+///
+/// * `reader` - Reader of the bytes sent by the device; timeouts of the reader (e.g. of a serial port) are ignored.
+/// * `duration` - Maximum time to listen; the deadline is only checked between reads.
+pub fn probe_reader<R: Read>(mut reader: R, duration: Duration) -> std::io::Result<ProbeReport> {
+    let mut probe = Probe::new();
+    let mut chunk = [0; CHUNK_SIZE];
+    while probe.started_at.elapsed() < duration {
+        match reader.read(&mut chunk) {
+            Ok(0) => break,
+            Ok(n) => probe.push(&chunk[..n]),
+            Err(e)
+                if matches!(
+                    e.kind(),
+                    ErrorKind::Interrupted | ErrorKind::TimedOut | ErrorKind::WouldBlock
+                ) => {}
+            Err(e) => return Err(e),
+        }
+    }
+    Ok(probe.finish())
+}
+
+/// Open a serial port and listen to the device connected to it for some time
+///
+/// * `port_id` - Name of the serial port.
+/// * `config` - Settings of the serial port; its timeout should be shorter than `duration`.
+/// * `duration` - Time to listen.
+#[cfg(feature = "serial")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "serial")))]
+pub fn probe_serial_port(
+    port_id: &str,
+    config: &crate::serial_config::SerialConfig,
+    duration: Duration,
+) -> std::io::Result<ProbeReport> {
+    let port = crate::open_serial_port(port_id, config)?;
+    probe_reader(port, duration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+    use crate::structures::TelemetryMessage;
+
+    #[test]
+    fn probes_a_running_device() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(1_000)
+                .collect();
+        // Start in the middle of a frame, long after boot
+        let capture: Vec<u8> = messages[500..].iter().flat_map(|m| m.to_bytes()).collect();
+        let mut probe = Probe::new();
+        probe.push(&capture[7..]);
+        let report = probe.finish();
+
+        assert!(report.warnings.is_empty(), "{:?}", report.warnings);
+        assert_eq!(report.frames, 499);
+        assert_eq!(report.protocol_versions, BTreeSet::from([2]));
+        assert_eq!(report.firmware_version, Some(messages[0].version()));
+        assert_eq!(report.device_id, Some(messages[0].device_id()));
+        let snapshots = report
+            .cadence
+            .iter()
+            .find(|cadence| cadence.message_type == MessageType::DataSnapshot)
+            .unwrap();
+        assert!(snapshots.count > 400);
+        assert!(snapshots.mean_interval.unwrap() <= crate::lint::MAX_DATA_SNAPSHOT_INTERVAL);
+    }
+
+    #[test]
+    fn reports_silent_devices() {
+        let report = probe_reader(std::io::empty(), DEFAULT_PROBE_DURATION).unwrap();
+
+        assert_eq!(report.bytes, 0);
+        assert_eq!(report.frames, 0);
+        assert!(report.cadence.is_empty());
+        // Silence is a warning in itself: the device may be stuck or the port wrong
+        assert_eq!(report.warnings.len(), 1);
+        assert_eq!(report.warnings[0].category, LintCategory::Framing);
+        assert_eq!(report.crc_errors(), 0);
+        let text = report.to_string();
+        assert!(text.contains("protocol version: unknown"));
+        assert!(text.contains("device ID: unknown"));
+    }
+
+    #[test]
+    fn reports_crc_errors() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .skip(1)
+                .take(3)
+                .collect();
+        let mut probe = Probe::new();
+        let mut corrupted = messages[1].to_bytes();
+        let middle = corrupted.len() / 2;
+        corrupted[middle] ^= 0xFF;
+        probe.push(&messages[0].to_bytes());
+        probe.push(&corrupted);
+        probe.push(&messages[2].to_bytes());
+        let report = probe.finish();
+
+        assert_eq!(report.crc_errors(), 1);
+        assert_eq!(report.frames, 2);
+        // A single message of a type has no cadence
+        assert!(report
+            .cadence
+            .iter()
+            .all(|cadence| cadence.count > 1 || cadence.mean_interval.is_none()));
+        assert!(report.to_string().contains("compliance warning(s):"));
+    }
+
+    struct FlakyReader {
+        reads: Vec<std::io::Result<Vec<u8>>>,
+    }
+
+    impl Read for FlakyReader {
+        fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+            if self.reads.is_empty() {
+                return Ok(0);
+            }
+            let bytes = self.reads.remove(0)?;
+            buf[..bytes.len()].copy_from_slice(&bytes);
+            Ok(bytes.len())
+        }
+    }
+
+    #[test]
+    fn ignores_timeouts_but_not_other_errors() {
+        let frame = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .next()
+            .unwrap()
+            .to_bytes();
+        let reader = FlakyReader {
+            reads: vec![
+                Err(ErrorKind::TimedOut.into()),
+                Err(ErrorKind::Interrupted.into()),
+                Ok(frame.clone()),
+            ],
+        };
+        let report = probe_reader(reader, DEFAULT_PROBE_DURATION).unwrap();
+        assert_eq!(report.bytes, frame.len() as u64);
+        assert_eq!(report.frames, 1);
+
+        let reader = FlakyReader {
+            reads: vec![Ok(frame), Err(ErrorKind::BrokenPipe.into())],
+        };
+        assert_eq!(
+            probe_reader(reader, DEFAULT_PROBE_DURATION)
+                .unwrap_err()
+                .kind(),
+            ErrorKind::BrokenPipe
+        );
+    }
+}
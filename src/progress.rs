@@ -0,0 +1,99 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::time::Duration;
+
+/// State of a long-running operation on a recording
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Progress {
+    /// Number of bytes of the recording that were processed
+    pub processed_bytes: u64,
+    /// Total size of the recording in bytes, if known
+    pub total_bytes: Option<u64>,
+    /// Number of telemetry messages that were decoded
+    pub messages: u64,
+    /// Time spent since the beginning of the operation
+    pub elapsed: Duration,
+}
+
+impl Progress {
+    /// Part of the recording that was processed, between 0 and 1
+    pub fn ratio(&self) -> Option<f64> {
+        self.total_bytes.map(|total| {
+            if total == 0 {
+                1.0
+            } else {
+                (self.processed_bytes as f64 / total as f64).min(1.0)
+            }
+        })
+    }
+
+    /// Estimated remaining time, based on the average speed since the beginning of the operation
+    pub fn eta(&self) -> Option<Duration> {
+        let total = self.total_bytes?;
+        if self.processed_bytes == 0 {
+            return None;
+        }
+        let remaining = total.saturating_sub(self.processed_bytes);
+        Some(
+            self.elapsed
+                .mul_f64(remaining as f64 / self.processed_bytes as f64),
+        )
+    }
+}
+
+/// Something that gets notified of the progress of a long-running operation
+pub trait ProgressCallback {
+    /// Called regularly while the operation is running
+    fn on_progress(&mut self, progress: &Progress);
+
+    /// Called once when the operation is over
+    fn on_finish(&mut self, _progress: &Progress) {}
+}
+
+impl<F: FnMut(&Progress)> ProgressCallback for F {
+    fn on_progress(&mut self, progress: &Progress) {
+        self(progress)
+    }
+}
+
+/// Progress callback that ignores every notification
+#[derive(Debug, Default, Clone, Copy)]
+pub struct NoProgress;
+
+impl ProgressCallback for NoProgress {
+    fn on_progress(&mut self, _progress: &Progress) {}
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ratio_and_eta() {
+        let progress = Progress {
+            processed_bytes: 250,
+            total_bytes: Some(1000),
+            messages: 10,
+            elapsed: Duration::from_secs(10),
+        };
+
+        assert_eq!(progress.ratio(), Some(0.25));
+        assert_eq!(progress.eta(), Some(Duration::from_secs(30)));
+    }
+
+    #[test]
+    fn unknown_total() {
+        let progress = Progress {
+            processed_bytes: 250,
+            total_bytes: None,
+            messages: 10,
+            elapsed: Duration::from_secs(10),
+        };
+
+        assert_eq!(progress.ratio(), None);
+        assert_eq!(progress.eta(), None);
+    }
+}
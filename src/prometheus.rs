@@ -0,0 +1,310 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write as _;
+use std::io::{BufRead, BufReader, Write};
+use std::net::{TcpListener, ToSocketAddrs};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::thread::JoinHandle;
+
+use crate::error::Error;
+use crate::filter::MessageType;
+use crate::sink::TelemetrySink;
+use crate::structures::{HighLevelError, TelemetryMessage};
+use crate::TimedMessage;
+
+/// Value of the `device_id` label for errors received before any message of their source
+pub const UNKNOWN_DEVICE: &str = "unknown";
+
+#[derive(Debug, Default)]
+struct DeviceMetrics {
+    messages: BTreeMap<&'static str, u64>,
+    last_systick: Option<u64>,
+    alarms: BTreeMap<u8, bool>,
+    crc_errors: u64,
+}
+
+#[derive(Debug, Default)]
+struct Metrics {
+    devices: BTreeMap<String, DeviceMetrics>,
+    /// Last device seen on each source, to label errors (which do not contain a device ID)
+    source_devices: HashMap<String, String>,
+}
+
+impl Metrics {
+    fn device(&mut self, device_id: &str) -> &mut DeviceMetrics {
+        self.devices.entry(device_id.to_owned()).or_default()
+    }
+
+    fn handle(&mut self, message: &TimedMessage) {
+        let source = message.source.to_string();
+        match &message.message {
+            Ok(message) => {
+                let device_id = message.device_id();
+                self.source_devices.insert(source, device_id.clone());
+                let device = self.device(&device_id);
+                *device
+                    .messages
+                    .entry(MessageType::of(message).name())
+                    .or_default() += 1;
+                device.last_systick = Some(message.systick());
+                match message {
+                    TelemetryMessage::AlarmTrap(trap) => {
+                        device.alarms.insert(trap.alarm_code, trap.triggered);
+                    }
+                    // Snapshots list every active alarm, which also catches traps that were missed
+                    TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                        device
+                            .alarms
+                            .values_mut()
+                            .for_each(|active| *active = false);
+                        for code in &snapshot.current_alarm_codes {
+                            device.alarms.insert(*code, true);
+                        }
+                    }
+                    TelemetryMessage::StoppedMessage(stopped) => {
+                        if let Some(codes) = &stopped.current_alarm_codes {
+                            device
+                                .alarms
+                                .values_mut()
+                                .for_each(|active| *active = false);
+                            for code in codes {
+                                device.alarms.insert(*code, true);
+                            }
+                        }
+                    }
+                    _ => (),
+                }
+            }
+            Err(Error::TelemetryError(HighLevelError::CrcError { .. })) => {
+                let device_id = self
+                    .source_devices
+                    .get(&source)
+                    .cloned()
+                    .unwrap_or_else(|| UNKNOWN_DEVICE.to_owned());
+                self.device(&device_id).crc_errors += 1;
+            }
+            Err(_) => (),
+        }
+    }
+
+    fn render(&self) -> String {
+        let mut output = String::new();
+        header(
+            &mut output,
+            "makair_telemetry_messages_total",
+            "counter",
+            "Number of telemetry messages received, by message type",
+        );
+        for (device_id, device) in &self.devices {
+            for (message_type, count) in &device.messages {
+                let _ = writeln!(
+                    output,
+                    "makair_telemetry_messages_total{{device_id=\"{}\",type=\"{}\"}} {}",
+                    escape(device_id),
+                    message_type,
+                    count
+                );
+            }
+        }
+        header(
+            &mut output,
+            "makair_telemetry_last_systick",
+            "gauge",
+            "Systick of the last message received (µs since the MCU booted)",
+        );
+        for (device_id, device) in &self.devices {
+            if let Some(systick) = device.last_systick {
+                let _ = writeln!(
+                    output,
+                    "makair_telemetry_last_systick{{device_id=\"{}\"}} {}",
+                    escape(device_id),
+                    systick
+                );
+            }
+        }
+        header(
+            &mut output,
+            "makair_telemetry_alarm_active",
+            "gauge",
+            "Whether an alarm is currently active (1) or not (0), for every alarm code seen so far",
+        );
+        for (device_id, device) in &self.devices {
+            for (code, active) in &device.alarms {
+                let _ = writeln!(
+                    output,
+                    "makair_telemetry_alarm_active{{device_id=\"{}\",alarm_code=\"{}\"}} {}",
+                    escape(device_id),
+                    code,
+                    u8::from(*active)
+                );
+            }
+        }
+        header(
+            &mut output,
+            "makair_telemetry_crc_errors_total",
+            "counter",
+            "Number of frames with an invalid CRC",
+        );
+        for (device_id, device) in &self.devices {
+            let _ = writeln!(
+                output,
+                "makair_telemetry_crc_errors_total{{device_id=\"{}\"}} {}",
+                escape(device_id),
+                device.crc_errors
+            );
+        }
+        output
+    }
+}
+
+fn header(output: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(output, "# HELP {} {}", name, help);
+    let _ = writeln!(output, "# TYPE {} {}", name, kind);
+}
+
+/// Escape a label value of the Prometheus text format
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// Sink computing telemetry metrics in the Prometheus text format, labeled by device ID
+///
+/// Clones share the same metrics, so one clone can be added to a `SinkSet` while another one serves them (see `serve()`).
+#[derive(Debug, Clone, Default)]
+pub struct PrometheusExporter {
+    metrics: Arc<Mutex<Metrics>>,
+}
+
+impl PrometheusExporter {
+    /// Create an exporter without any metric
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn metrics(&self) -> MutexGuard<'_, Metrics> {
+        self.metrics
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Current metrics in the Prometheus text format
+    pub fn render(&self) -> String {
+        self.metrics().render()
+    }
+
+    /// Serve metrics over HTTP (on any path, e.g. `/metrics`) from a dedicated thread
+    ///
+    /// * `address` - Address to listen on, e.g. `0.0.0.0:9100`.
+    pub fn serve<A: ToSocketAddrs>(&self, address: A) -> std::io::Result<JoinHandle<()>> {
+        let listener = TcpListener::bind(address)?;
+        let exporter = self.clone();
+        Ok(std::thread::spawn(move || {
+            for stream in listener.incoming() {
+                let result = stream.and_then(|mut stream| {
+                    // Only the request line matters; the rest of the request is ignored
+                    let mut request_line = String::new();
+                    BufReader::new(&mut stream).read_line(&mut request_line)?;
+                    let body = exporter.render();
+                    write!(
+                        stream,
+                        "HTTP/1.1 200 OK\r\nContent-Type: text/plain; version=0.0.4\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+                        body.len(),
+                        body
+                    )
+                });
+                if let Err(e) = result {
+                    log::warn!("failed to serve metrics: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+impl TelemetrySink for PrometheusExporter {
+    fn consume(&mut self, message: &TimedMessage) {
+        self.metrics().handle(message);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::source::{SourceInfo, SourceKind};
+    use crate::structures::*;
+
+    fn timed(message: crate::TelemetryChannelType) -> TimedMessage {
+        TimedMessage::now(
+            message,
+            SourceInfo::new(SourceKind::Serial, Some("/dev/ttyUSB0".to_owned())),
+        )
+    }
+
+    #[test]
+    fn renders_metrics_by_device() {
+        let mut exporter = PrometheusExporter::new();
+        exporter.consume(&timed(Err(HighLevelError::CrcError {
+            expected: 1,
+            computed: 2,
+        }
+        .into())));
+        let boot = BootMessage {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 1_000,
+            mode: Mode::Production,
+            value128: 128,
+            control_capabilities: None,
+            session_nonce: None,
+        };
+        exporter.consume(&timed(Ok(TelemetryMessage::BootMessage(boot))));
+        let trap = AlarmTrap {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 2_000,
+            centile: 0,
+            pressure: 0,
+            phase: Phase::Inhalation,
+            subphase: None,
+            cycle: 1,
+            alarm_code: 12,
+            alarm_priority: AlarmPriority::High,
+            triggered: true,
+            expected: 0,
+            measured: 0,
+            cycles_since_trigger: 1,
+        };
+        exporter.consume(&timed(Ok(TelemetryMessage::AlarmTrap(trap.clone()))));
+        exporter.consume(&timed(Ok(TelemetryMessage::AlarmTrap(AlarmTrap {
+            systick: 3_000,
+            triggered: false,
+            ..trap
+        }))));
+        exporter.consume(&timed(Err(HighLevelError::CrcError {
+            expected: 1,
+            computed: 2,
+        }
+        .into())));
+
+        let output = exporter.render();
+        for line in [
+            "makair_telemetry_messages_total{device_id=\"1-2-3\",type=\"boot\"} 1",
+            "makair_telemetry_messages_total{device_id=\"1-2-3\",type=\"alarm-trap\"} 2",
+            "makair_telemetry_last_systick{device_id=\"1-2-3\"} 3000",
+            "makair_telemetry_alarm_active{device_id=\"1-2-3\",alarm_code=\"12\"} 0",
+            "makair_telemetry_crc_errors_total{device_id=\"1-2-3\"} 1",
+            "makair_telemetry_crc_errors_total{device_id=\"unknown\"} 1",
+            "# TYPE makair_telemetry_alarm_active gauge",
+        ] {
+            assert!(output.lines().any(|l| l == line), "missing {}", line);
+        }
+    }
+}
@@ -0,0 +1,385 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::mem;
+
+use crate::filter::MessageFilter;
+use crate::sink::TelemetrySink;
+use crate::structures::TelemetryMessage;
+use crate::{TelemetryChannelType, TimedMessage};
+
+/// Error returned when a query cannot match anything
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum InvalidQuery {
+    /// Start of the systick range is after its end
+    #[error("systick range start cannot be greater than its end")]
+    SystickRange,
+    /// Start of the cycle range is after its end
+    #[error("cycle range start cannot be greater than its end")]
+    CycleRange,
+}
+
+/// Criteria to select a slice of a recording
+///
+/// Every criterion is optional; an empty query matches every message.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Query {
+    /// Only messages with a greater or equal systick match
+    pub from_systick: Option<u64>,
+    /// Only messages with a smaller or equal systick match
+    pub to_systick: Option<u64>,
+    /// Only messages of cycles with a greater or equal number match
+    pub from_cycle: Option<u32>,
+    /// Only messages of cycles with a smaller or equal number match
+    pub to_cycle: Option<u32>,
+    /// Types of messages that match
+    pub types: MessageFilter,
+    /// Only messages of cycles during which an alarm was active match
+    pub only_cycles_with_alarm: bool,
+}
+
+impl Query {
+    /// Create a query that matches every message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Restrict the query to an inclusive range of systicks
+    pub fn systicks(mut self, from: Option<u64>, to: Option<u64>) -> Self {
+        self.from_systick = from;
+        self.to_systick = to;
+        self
+    }
+
+    /// Restrict the query to an inclusive range of cycles
+    pub fn cycles(mut self, from: Option<u32>, to: Option<u32>) -> Self {
+        self.from_cycle = from;
+        self.to_cycle = to;
+        self
+    }
+
+    /// Restrict the query to some types of messages
+    pub fn types(mut self, types: MessageFilter) -> Self {
+        self.types = types;
+        self
+    }
+
+    /// Restrict the query to cycles during which an alarm was active
+    pub fn only_cycles_with_alarm(mut self) -> Self {
+        self.only_cycles_with_alarm = true;
+        self
+    }
+
+    /// Check that the query can match something
+    pub fn validate(&self) -> Result<(), InvalidQuery> {
+        if let (Some(from), Some(to)) = (self.from_systick, self.to_systick) {
+            if from > to {
+                return Err(InvalidQuery::SystickRange);
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from_cycle, self.to_cycle) {
+            if from > to {
+                return Err(InvalidQuery::CycleRange);
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether the query needs to know the cycle of messages, which requires buffering them until the end of their cycle
+    pub fn needs_cycles(&self) -> bool {
+        self.from_cycle.is_some() || self.to_cycle.is_some() || self.only_cycles_with_alarm
+    }
+
+    /// Whether a systick is in the systick range of the query
+    pub fn matches_systick(&self, systick: u64) -> bool {
+        self.from_systick.is_none_or(|from| systick >= from)
+            && self.to_systick.is_none_or(|to| systick <= to)
+    }
+
+    /// Whether a message matches the criteria that do not depend on its cycle (systick range and types)
+    pub fn matches_message(&self, message: &TelemetryMessage) -> bool {
+        self.matches_systick(message.systick()) && self.types.matches(message)
+    }
+
+    /// Whether a cycle matches the criteria that depend on cycles
+    pub fn matches_cycle(&self, cycle: u32, had_alarm: bool) -> bool {
+        self.from_cycle.is_none_or(|from| cycle >= from)
+            && self.to_cycle.is_none_or(|to| cycle <= to)
+            && (had_alarm || !self.only_cycles_with_alarm)
+    }
+}
+
+/// Something that can go through a `QueryRunner`
+pub trait QueryItem {
+    /// The telemetry message, if this is not an error
+    fn telemetry_message(&self) -> Option<&TelemetryMessage>;
+}
+
+impl QueryItem for TelemetryMessage {
+    fn telemetry_message(&self) -> Option<&TelemetryMessage> {
+        Some(self)
+    }
+}
+
+impl QueryItem for TelemetryChannelType {
+    fn telemetry_message(&self) -> Option<&TelemetryMessage> {
+        self.as_ref().ok()
+    }
+}
+
+impl QueryItem for TimedMessage {
+    fn telemetry_message(&self) -> Option<&TelemetryMessage> {
+        self.message.as_ref().ok()
+    }
+}
+
+/// Applies a query to a stream of messages
+///
+/// A cycle ends with its `MachineStateSnapshot`; when the query needs cycles, messages are held back until then.
+/// Messages that follow the last `MachineStateSnapshot` of a stream belong to an unfinished cycle and never match such queries.
+/// Errors are never filtered out.
+#[derive(Debug)]
+pub struct QueryRunner<T> {
+    query: Query,
+    pending: Vec<T>,
+    pending_has_alarm: bool,
+    skipped: u64,
+}
+
+impl<T: QueryItem> QueryRunner<T> {
+    /// Create a runner for a query
+    pub fn new(query: Query) -> Self {
+        Self {
+            query,
+            pending: Vec::new(),
+            pending_has_alarm: false,
+            skipped: 0,
+        }
+    }
+
+    /// Number of messages that did not match the query so far
+    pub fn skipped(&self) -> u64 {
+        self.skipped
+    }
+
+    /// Handle a new item and return the items that are now known to match the query
+    pub fn push(&mut self, item: T) -> Vec<T> {
+        let message = match item.telemetry_message() {
+            Some(message) => message,
+            None => return vec![item],
+        };
+
+        if !self.query.needs_cycles() {
+            return if self.query.matches_message(message) {
+                vec![item]
+            } else {
+                self.skipped += 1;
+                Vec::new()
+            };
+        }
+
+        let end_of_cycle = match message {
+            TelemetryMessage::AlarmTrap(trap) => {
+                self.pending_has_alarm |= trap.triggered;
+                None
+            }
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                self.pending_has_alarm |= !snapshot.current_alarm_codes.is_empty();
+                Some(snapshot.cycle)
+            }
+            _ => None,
+        };
+        self.pending.push(item);
+
+        match end_of_cycle {
+            Some(cycle) => {
+                let had_alarm = mem::replace(&mut self.pending_has_alarm, false);
+                let cycle_items = mem::take(&mut self.pending);
+                if !self.query.matches_cycle(cycle, had_alarm) {
+                    self.skipped += cycle_items.len() as u64;
+                    return Vec::new();
+                }
+                let total = cycle_items.len();
+                let matching: Vec<T> = cycle_items
+                    .into_iter()
+                    .filter(|item| {
+                        item.telemetry_message()
+                            .is_none_or(|message| self.query.matches_message(message))
+                    })
+                    .collect();
+                self.skipped += (total - matching.len()) as u64;
+                matching
+            }
+            None => Vec::new(),
+        }
+    }
+
+    /// Signal the end of the stream and return the items that still match the query
+    pub fn finish(&mut self) -> Vec<T> {
+        self.skipped += self.pending.len() as u64;
+        self.pending.clear();
+        self.pending_has_alarm = false;
+        Vec::new()
+    }
+}
+
+/// Sink that only forwards messages matching a query to another sink
+pub struct QuerySink<S> {
+    runner: QueryRunner<TimedMessage>,
+    inner: S,
+}
+
+impl<S: TelemetrySink> QuerySink<S> {
+    /// Wrap a sink so that it only receives messages matching a query
+    pub fn new(query: Query, inner: S) -> Self {
+        Self {
+            runner: QueryRunner::new(query),
+            inner,
+        }
+    }
+
+    /// Number of messages that did not match the query so far
+    pub fn skipped(&self) -> u64 {
+        self.runner.skipped()
+    }
+
+    /// Get back the wrapped sink
+    pub fn into_inner(self) -> S {
+        self.inner
+    }
+}
+
+impl<S: TelemetrySink> TelemetrySink for QuerySink<S> {
+    fn consume(&mut self, message: &TimedMessage) {
+        let item = match &message.message {
+            Ok(msg) => TimedMessage {
+                message: Ok(msg.clone()),
+                received_at: message.received_at,
+                received_instant: message.received_instant,
+                source: message.source.clone(),
+            },
+            Err(_) => {
+                // Errors are not buffered, so there is no need to own them
+                self.inner.consume(message);
+                return;
+            }
+        };
+        for matching in self.runner.push(item) {
+            self.inner.consume(&matching);
+        }
+    }
+
+    fn flush(&mut self) {
+        for matching in self.runner.finish() {
+            self.inner.consume(&matching);
+        }
+        self.inner.flush();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::filter::MessageType;
+    use crate::structures::{AlarmPriority, AlarmTrap, MachineStateSnapshot, StoppedMessage};
+
+    fn stopped(systick: u64) -> TelemetryMessage {
+        TelemetryMessage::StoppedMessage(StoppedMessage {
+            systick,
+            ..Default::default()
+        })
+    }
+
+    fn end_of_cycle(cycle: u32, systick: u64) -> TelemetryMessage {
+        TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+            cycle,
+            systick,
+            ..Default::default()
+        })
+    }
+
+    fn alarm(cycle: u32, systick: u64) -> TelemetryMessage {
+        TelemetryMessage::AlarmTrap(AlarmTrap {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: String::new(),
+            systick,
+            centile: 0,
+            pressure: 0,
+            phase: crate::structures::Phase::Inhalation,
+            subphase: None,
+            cycle,
+            alarm_code: 12,
+            alarm_priority: AlarmPriority::High,
+            triggered: true,
+            expected: 0,
+            measured: 0,
+            cycles_since_trigger: 0,
+        })
+    }
+
+    fn run(query: Query, messages: Vec<TelemetryMessage>) -> (Vec<u64>, u64) {
+        let mut runner = QueryRunner::new(query);
+        let mut output: Vec<u64> = Vec::new();
+        for message in messages {
+            output.extend(runner.push(message).iter().map(|m| m.systick()));
+        }
+        output.extend(runner.finish().iter().map(|m| m.systick()));
+        (output, runner.skipped())
+    }
+
+    fn recording() -> Vec<TelemetryMessage> {
+        vec![
+            stopped(1),
+            end_of_cycle(1, 2),
+            stopped(3),
+            alarm(2, 4),
+            end_of_cycle(2, 5),
+            stopped(6),
+            end_of_cycle(3, 7),
+            stopped(8),
+        ]
+    }
+
+    #[test]
+    fn empty_query_matches_everything() {
+        assert_eq!(
+            run(Query::new(), recording()),
+            (vec![1, 2, 3, 4, 5, 6, 7, 8], 0)
+        );
+    }
+
+    #[test]
+    fn systick_and_type_criteria() {
+        let query = Query::new()
+            .systicks(Some(2), Some(6))
+            .types(MessageFilter::new().exclude([MessageType::AlarmTrap]));
+        assert_eq!(run(query, recording()), (vec![2, 3, 5, 6], 4));
+    }
+
+    #[test]
+    fn cycle_criteria() {
+        let query = Query::new().cycles(Some(2), None);
+        assert_eq!(run(query, recording()), (vec![3, 4, 5, 6, 7], 3));
+
+        let query = Query::new()
+            .only_cycles_with_alarm()
+            .types(MessageFilter::new().only([MessageType::Stopped]));
+        assert_eq!(run(query, recording()), (vec![3], 7));
+    }
+
+    #[test]
+    fn invalid_ranges() {
+        assert_eq!(
+            Query::new().systicks(Some(2), Some(1)).validate(),
+            Err(InvalidQuery::SystickRange)
+        );
+        assert_eq!(
+            Query::new().cycles(Some(2), Some(1)).validate(),
+            Err(InvalidQuery::CycleRange)
+        );
+        assert_eq!(Query::new().cycles(Some(1), Some(1)).validate(), Ok(()));
+    }
+}
@@ -0,0 +1,211 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use crate::control::ControlMessage;
+
+/// Default maximum number of control messages waiting to be sent; beyond this, the oldest ones are dropped
+pub const DEFAULT_MAX_QUEUED: usize = 64;
+
+/// Maximum pace at which control frames can be sent to the MCU
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RateLimit {
+    /// Sustained number of frames per second
+    pub frames_per_second: u32,
+    /// Number of frames that can be sent at once after a quiet period
+    pub burst: u32,
+}
+
+impl Default for RateLimit {
+    /// 10 frames per second with bursts of 5 frames, which the UART RX buffer of the firmware can absorb
+    fn default() -> Self {
+        Self {
+            frames_per_second: 10,
+            burst: 5,
+        }
+    }
+}
+
+/// What happened to control messages that went through a `ControlRateLimiter`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RateLimiterMetrics {
+    /// Messages that were released to be sent
+    pub sent: u64,
+    /// Messages that had to wait in the queue before being released
+    pub delayed: u64,
+    /// Messages that were dropped because the queue was full
+    pub dropped: u64,
+    /// Longest queue seen
+    pub max_queued: usize,
+}
+
+/// Queue that releases control messages no faster than a rate limit (token bucket)
+///
+/// Naive UIs can send control frames faster than the firmware reads its UART, which overruns its RX buffer and loses frames.
+/// Messages are released in the order they were pushed.
+#[derive(Debug, Clone)]
+pub struct ControlRateLimiter {
+    limit: RateLimit,
+    tokens: f64,
+    refilled_at: Instant,
+    queue: VecDeque<ControlMessage>,
+    max_queued: usize,
+    metrics: RateLimiterMetrics,
+}
+
+impl ControlRateLimiter {
+    /// Create a limiter with a full burst budget
+    pub fn new(limit: RateLimit) -> Self {
+        Self::new_at(limit, Instant::now())
+    }
+
+    fn new_at(limit: RateLimit, now: Instant) -> Self {
+        Self {
+            limit,
+            tokens: f64::from(limit.burst.max(1)),
+            refilled_at: now,
+            queue: VecDeque::new(),
+            max_queued: DEFAULT_MAX_QUEUED,
+            metrics: RateLimiterMetrics::default(),
+        }
+    }
+
+    /// Keep at most this number of messages waiting to be sent
+    pub fn with_max_queued(mut self, max_queued: usize) -> Self {
+        self.max_queued = max_queued.max(1);
+        self
+    }
+
+    /// Add a message to send
+    ///
+    /// Returns `false` if the queue was full and its oldest message was dropped to make room.
+    pub fn push(&mut self, message: ControlMessage) -> bool {
+        self.push_at(message, Instant::now())
+    }
+
+    fn push_at(&mut self, message: ControlMessage, now: Instant) -> bool {
+        self.refill(now);
+        if self.queue.len() as f64 + 1.0 > self.tokens {
+            self.metrics.delayed += 1;
+        }
+        let dropped = self.queue.len() >= self.max_queued;
+        if dropped {
+            self.queue.pop_front();
+            self.metrics.dropped += 1;
+        }
+        self.queue.push_back(message);
+        self.metrics.max_queued = self.metrics.max_queued.max(self.queue.len());
+        !dropped
+    }
+
+    /// Take the next message if it can be sent now
+    pub fn pop_ready(&mut self) -> Option<ControlMessage> {
+        self.pop_ready_at(Instant::now())
+    }
+
+    fn pop_ready_at(&mut self, now: Instant) -> Option<ControlMessage> {
+        self.refill(now);
+        if self.queue.is_empty() || self.tokens < 1.0 {
+            return None;
+        }
+        self.tokens -= 1.0;
+        self.metrics.sent += 1;
+        self.queue.pop_front()
+    }
+
+    /// Time to wait before the next queued message can be sent, if any message is queued
+    pub fn next_ready_in(&mut self) -> Option<Duration> {
+        self.next_ready_in_at(Instant::now())
+    }
+
+    fn next_ready_in_at(&mut self, now: Instant) -> Option<Duration> {
+        if self.queue.is_empty() {
+            return None;
+        }
+        self.refill(now);
+        let missing = (1.0 - self.tokens).max(0.0);
+        Some(Duration::from_secs_f64(
+            missing / f64::from(self.limit.frames_per_second.max(1)),
+        ))
+    }
+
+    /// Number of messages waiting to be sent
+    pub fn queued(&self) -> usize {
+        self.queue.len()
+    }
+
+    /// What happened to messages so far
+    pub fn metrics(&self) -> RateLimiterMetrics {
+        self.metrics
+    }
+
+    fn refill(&mut self, now: Instant) {
+        let elapsed = now
+            .saturating_duration_since(self.refilled_at)
+            .as_secs_f64();
+        self.tokens = (self.tokens + elapsed * f64::from(self.limit.frames_per_second))
+            .min(f64::from(self.limit.burst.max(1)));
+        self.refilled_at = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+
+    fn message(value: u16) -> ControlMessage {
+        ControlMessage {
+            setting: ControlSetting::PEEP,
+            value,
+        }
+    }
+
+    #[test]
+    fn releases_bursts_then_sustained_rate() {
+        let start = Instant::now();
+        let mut limiter = ControlRateLimiter::new_at(
+            RateLimit {
+                frames_per_second: 10,
+                burst: 2,
+            },
+            start,
+        );
+        for value in 0..4 {
+            assert!(limiter.push_at(message(value), start));
+        }
+
+        assert_eq!(limiter.pop_ready_at(start), Some(message(0)));
+        assert_eq!(limiter.pop_ready_at(start), Some(message(1)));
+        assert_eq!(limiter.pop_ready_at(start), None);
+        assert_eq!(
+            limiter.next_ready_in_at(start),
+            Some(Duration::from_millis(100))
+        );
+
+        let later = start + Duration::from_millis(100);
+        assert_eq!(limiter.pop_ready_at(later), Some(message(2)));
+        assert_eq!(limiter.pop_ready_at(later), None);
+        assert_eq!(limiter.queued(), 1);
+
+        let metrics = limiter.metrics();
+        assert_eq!(metrics.sent, 3);
+        assert_eq!(metrics.delayed, 2);
+        assert_eq!(metrics.max_queued, 4);
+    }
+
+    #[test]
+    fn drops_oldest_messages_when_full() {
+        let mut limiter = ControlRateLimiter::new(RateLimit::default()).with_max_queued(2);
+        assert!(limiter.push(message(0)));
+        assert!(limiter.push(message(1)));
+        assert!(!limiter.push(message(2)));
+
+        assert_eq!(limiter.metrics().dropped, 1);
+        assert_eq!(limiter.pop_ready(), Some(message(1)));
+    }
+}
@@ -0,0 +1,198 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::{ErrorKind, Read, Seek};
+use std::path::Path;
+use std::sync::mpsc::Sender;
+
+use crate::decoder::{DecodedFrame, TelemetryDecoder};
+use crate::diagnostics::DecodeDiagnostic;
+use crate::parsers::ParserConfig;
+use crate::recording::Base64Decoder;
+use crate::structures::{HighLevelError, TelemetryMessage};
+use crate::TelemetryChannelType;
+
+const CHUNK_SIZE: usize = 4096;
+
+/// Lazy reader of the messages of a recording, without any thread nor channel
+///
+/// Like `gather_telemetry_from_file()`, bytes that cannot be parsed (e.g. frames with a CRC error) are skipped.
+/// If reading the recording fails, the error is returned once and the iteration ends.
+pub struct TelemetryFileReader<R = File> {
+    reader: Base64Decoder<R>,
+    decoder: TelemetryDecoder,
+    finished: bool,
+}
+
+impl TelemetryFileReader<File> {
+    /// Open a recording
+    pub fn open<P: AsRef<Path>>(path: P) -> std::io::Result<Self> {
+        Ok(Self::new(File::open(path)?))
+    }
+}
+
+impl<R: Read> TelemetryFileReader<R> {
+    /// Read a recording (base64 frames, one per line) from any reader
+    pub fn new(reader: R) -> Self {
+        Self {
+            reader: Base64Decoder::new(reader),
+            decoder: TelemetryDecoder::new(),
+            finished: false,
+        }
+    }
+
+    /// Choose the limits and strictness of the parser (strict mode and default limits otherwise)
+    pub fn parser_config(mut self, parser: ParserConfig) -> Self {
+        self.decoder = self.decoder.parser_config(parser);
+        self
+    }
+
+    /// Also send non-fatal problems (CRC errors, dropped bytes, field warnings) through a channel, until its receiver is dropped
+    pub fn diagnostics(mut self, tx: Option<Sender<DecodeDiagnostic>>) -> Self {
+        self.decoder = self.decoder.diagnostics(tx);
+        self
+    }
+
+    /// Number of bytes of the recording that were consumed so far, to report progress
+    pub fn consumed_bytes(&self) -> u64 {
+        self.reader.consumed_bytes()
+    }
+
+    /// Read the next message along with the bytes of its frame, skipping frames that could not be decoded
+    pub(crate) fn next_frame(&mut self) -> Option<std::io::Result<(TelemetryMessage, &[u8])>> {
+        loop {
+            match self.next_result()? {
+                Ok(Ok(message)) => return Some(Ok((message, self.decoder.last_frame()))),
+                Ok(Err(_)) => (),
+                Err(e) => return Some(Err(e)),
+            }
+        }
+    }
+
+    /// Read the next frame, including frames that could not be decoded (e.g. because of a CRC error)
+    pub(crate) fn next_decoded_frame(&mut self) -> Option<std::io::Result<DecodedFrame<'_>>> {
+        match self.next_result()? {
+            Ok(result) => Some(Ok(DecodedFrame {
+                frame: self.decoder.last_frame(),
+                result,
+            })),
+            Err(e) => Some(Err(e)),
+        }
+    }
+
+    fn next_result(&mut self) -> Option<std::io::Result<Result<TelemetryMessage, HighLevelError>>> {
+        loop {
+            if let Some(result) = self.decoder.next_message() {
+                return Some(Ok(result));
+            }
+            if self.finished {
+                return None;
+            }
+            let mut chunk = [0; CHUNK_SIZE];
+            match self.reader.read(&mut chunk) {
+                Ok(0) => self.finished = true,
+                Ok(n) => self.decoder.push_bytes(&chunk[..n]),
+                Err(e) if e.kind() == ErrorKind::Interrupted => (),
+                Err(e) => {
+                    self.finished = true;
+                    return Some(Err(e));
+                }
+            }
+        }
+    }
+}
+
+impl<R: Read + Seek> TelemetryFileReader<R> {
+    /// Read `n` messages evenly spread across the recording without reading all of it, e.g. to preview a multi-hour recording (duration, modes used, alarm density) before loading it
+    ///
+    /// The first message following each of `n` evenly spaced offsets of the file is read, so that only about `n` lines are decoded; fewer messages are returned if the recording has fewer lines.
+    /// Iterating the reader afterwards continues after the last sampled message.
+    pub fn sample(&mut self, n: usize) -> std::io::Result<Vec<TelemetryMessage>> {
+        let len = self.reader.inner_len()?;
+        let mut messages: Vec<TelemetryMessage> = Vec::with_capacity(n);
+        for index in 0..n as u64 {
+            self.reader.seek_to_line(len * index / n as u64)?;
+            self.decoder.clear();
+            self.finished = false;
+            match self.next_frame() {
+                Some(Ok((message, _))) => {
+                    // Offsets closer than a line lead to the same message
+                    if messages.last() != Some(&message) {
+                        messages.push(message);
+                    }
+                }
+                Some(Err(e)) => return Err(e),
+                None => break,
+            }
+        }
+        Ok(messages)
+    }
+}
+
+impl<R: Read> Iterator for TelemetryFileReader<R> {
+    type Item = TelemetryChannelType;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        match self.next_frame()? {
+            Ok((message, _)) => Some(Ok(message)),
+            Err(e) => Some(Err(e.into())),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::serializers::ToBytes;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+
+    #[test]
+    fn reads_messages_lazily() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(200)
+                .collect();
+        let mut frames: Vec<Vec<u8>> = messages.iter().map(|m| m.to_bytes()).collect();
+        // A frame with a CRC error is skipped
+        let mut corrupted = frames[0].clone();
+        corrupted[5] ^= 0xFF;
+        frames.insert(100, corrupted);
+        let recording: String = frames
+            .iter()
+            .map(|frame| base64::encode(frame) + "\n")
+            .collect();
+
+        let mut reader = TelemetryFileReader::new(recording.as_bytes());
+        assert_eq!(reader.next().unwrap().unwrap(), messages[0]);
+        assert!(reader.consumed_bytes() > 0);
+        let rest: Vec<TelemetryMessage> = reader.map(Result::unwrap).collect();
+        assert_eq!(rest, messages[1..]);
+    }
+
+    #[test]
+    fn samples_messages_across_the_recording() {
+        let recording: String = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .take(2_000)
+            .map(|message| base64::encode(message.to_bytes()) + "\n")
+            .collect();
+        // Optional fields are serialized with default values, so messages are compared once parsed
+        let messages: Vec<TelemetryMessage> = TelemetryFileReader::new(recording.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+
+        let mut reader = TelemetryFileReader::new(std::io::Cursor::new(recording));
+        let sample = reader.sample(10).unwrap();
+        assert_eq!(sample.len(), 10);
+        assert_eq!(sample[0], messages[0]);
+        assert!(sample.iter().all(|message| messages.contains(message)));
+        assert!(sample
+            .windows(2)
+            .all(|pair| pair[0].systick() < pair[1].systick()));
+        assert!(sample[9].systick() > messages[1_700].systick());
+
+        assert_eq!(reader.sample(5_000).unwrap().len(), messages.len());
+    }
+}
@@ -0,0 +1,220 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::control::{ControlMessage, ControlMessageGroup, ControlSetting};
+use crate::structures::TelemetryMessage;
+
+/// Default relative margin around the baseline (±20%)
+pub const DEFAULT_MARGIN: f64 = 0.2;
+
+/// Ventilation observed over a set of cycles
+///
+/// Values are medians of the cycles that reported them, which makes them robust to a few abnormal cycles.
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct Baseline {
+    /// Number of cycles the baseline is computed from
+    pub cycles: usize,
+    /// Minute volume in L/min (protocol v2 only)
+    pub minute_volume: Option<f64>,
+    /// Respiratory rate in cycles per minute (protocol v2 only)
+    pub respiratory_rate: Option<f64>,
+    /// Tidal volume in mL (protocol v2 only)
+    pub tidal_volume: Option<f64>,
+    /// Peak pressure in mmH2O
+    pub peak_pressure: f64,
+}
+
+fn median(mut values: Vec<f64>) -> Option<f64> {
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_by(|a, b| a.total_cmp(b));
+    let middle = values.len() / 2;
+    Some(if values.len().is_multiple_of(2) {
+        (values[middle - 1] + values[middle]) / 2.0
+    } else {
+        values[middle]
+    })
+}
+
+impl Baseline {
+    /// Compute the baseline from the machine state snapshots of a recording or of a live window (other messages are ignored)
+    ///
+    /// Returns `None` if there is no machine state snapshot.
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(
+        messages: I,
+    ) -> Option<Self> {
+        let mut cycles = 0;
+        let mut minute_volumes = Vec::new();
+        let mut respiratory_rates = Vec::new();
+        let mut tidal_volumes = Vec::new();
+        let mut peak_pressures = Vec::new();
+
+        for message in messages {
+            let snapshot = match message {
+                TelemetryMessage::MachineStateSnapshot(snapshot) => snapshot,
+                _ => continue,
+            };
+            cycles += 1;
+            peak_pressures.push(f64::from(snapshot.previous_peak_pressure));
+            // Cycles without breathing (e.g. right after the start) do not tell anything
+            let volume = snapshot.previous_volume.filter(|volume| *volume > 0);
+            let rate = snapshot.previous_cpm.filter(|rate| *rate > 0);
+            if let Some(volume) = volume {
+                tidal_volumes.push(f64::from(volume));
+            }
+            if let Some(rate) = rate {
+                respiratory_rates.push(f64::from(rate));
+            }
+            if let (Some(volume), Some(rate)) = (volume, rate) {
+                minute_volumes.push(f64::from(volume) * f64::from(rate) / 1000.0);
+            }
+        }
+
+        Some(Self {
+            cycles,
+            minute_volume: median(minute_volumes),
+            respiratory_rate: median(respiratory_rates),
+            tidal_volume: median(tidal_volumes),
+            peak_pressure: median(peak_pressures)?,
+        })
+    }
+
+    /// Suggest alarm thresholds around the baseline
+    ///
+    /// * `margin` - Relative margin (e.g. `0.2` for ±20%); low thresholds are rounded down and high thresholds up.
+    ///
+    /// Values are clamped like the firmware would, so that the proposal can be reviewed as it will be applied.
+    /// Thresholds that depend on values missing from the baseline are not proposed.
+    pub fn recommend_thresholds(&self, margin: f64) -> ControlMessageGroup {
+        let mut group = ControlMessageGroup::new(format!(
+            "Alarm thresholds at ±{:.0}% of the baseline of {} cycles",
+            margin * 100.0,
+            self.cycles
+        ));
+        let mut propose = |setting: ControlSetting, value: f64| {
+            let value = value.clamp(0.0, f64::from(u16::MAX)) as u16;
+            group.push(ControlMessage {
+                setting,
+                value: setting.clamp(value),
+            });
+        };
+        // Round first so that floating point errors do not move thresholds (e.g. 10 × 1.2 = 12.000000000000002)
+        let scaled = |value: f64, factor: f64| (value * factor * 1e6).round() / 1e6;
+        let low = |value: f64| scaled(value, 1.0 - margin).floor();
+        let high = |value: f64| scaled(value, 1.0 + margin).ceil();
+
+        if let Some(minute_volume) = self.minute_volume {
+            propose(
+                ControlSetting::LowInspiratoryMinuteVolumeAlarmThreshold,
+                low(minute_volume),
+            );
+            propose(
+                ControlSetting::HighInspiratoryMinuteVolumeAlarmThreshold,
+                high(minute_volume),
+            );
+            propose(
+                ControlSetting::LowExpiratoryMinuteVolumeAlarmThreshold,
+                low(minute_volume),
+            );
+            propose(
+                ControlSetting::HighExpiratoryMinuteVolumeAlarmThreshold,
+                high(minute_volume),
+            );
+        }
+        if let Some(rate) = self.respiratory_rate {
+            propose(ControlSetting::LowRespiratoryRateAlarmThreshold, low(rate));
+            propose(
+                ControlSetting::HighRespiratoryRateAlarmThreshold,
+                high(rate),
+            );
+        }
+        if let Some(volume) = self.tidal_volume {
+            propose(ControlSetting::LowTidalVolumeAlarmThreshold, low(volume));
+            propose(ControlSetting::HighTidalVolumeAlarmThreshold, high(volume));
+        }
+        propose(
+            ControlSetting::PeakPressureAlarmThreshold,
+            high(self.peak_pressure),
+        );
+
+        group
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::MachineStateSnapshot;
+
+    fn cycle(volume: u16, cpm: u8, peak: u16) -> TelemetryMessage {
+        TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+            previous_volume: Some(volume),
+            previous_cpm: Some(cpm),
+            previous_peak_pressure: peak,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn baseline_uses_medians() {
+        let messages = vec![
+            cycle(500, 20, 300),
+            cycle(0, 0, 0),
+            cycle(500, 20, 300),
+            cycle(2000, 30, 600),
+        ];
+        let baseline = Baseline::from_messages(&messages).unwrap();
+
+        assert_eq!(baseline.cycles, 4);
+        assert_eq!(baseline.tidal_volume, Some(500.0));
+        assert_eq!(baseline.respiratory_rate, Some(20.0));
+        assert_eq!(baseline.minute_volume, Some(10.0));
+        assert_eq!(baseline.peak_pressure, 300.0);
+        assert_eq!(Baseline::from_messages(&[]), None);
+    }
+
+    #[test]
+    fn recommends_clamped_thresholds() {
+        let baseline = Baseline {
+            cycles: 10,
+            minute_volume: Some(10.0),
+            respiratory_rate: Some(30.0),
+            tidal_volume: None,
+            peak_pressure: 300.0,
+        };
+        let group = baseline.recommend_thresholds(DEFAULT_MARGIN);
+
+        assert_eq!(
+            group.get(ControlSetting::LowInspiratoryMinuteVolumeAlarmThreshold),
+            Some(8)
+        );
+        assert_eq!(
+            group.get(ControlSetting::HighExpiratoryMinuteVolumeAlarmThreshold),
+            Some(12)
+        );
+        assert_eq!(
+            group.get(ControlSetting::LowRespiratoryRateAlarmThreshold),
+            Some(24)
+        );
+        // 36 is above the maximum the firmware accepts
+        assert_eq!(
+            group.get(ControlSetting::HighRespiratoryRateAlarmThreshold),
+            Some(35)
+        );
+        assert_eq!(
+            group.get(ControlSetting::PeakPressureAlarmThreshold),
+            Some(360)
+        );
+        assert_eq!(
+            group.get(ControlSetting::LowTidalVolumeAlarmThreshold),
+            None
+        );
+    }
+}
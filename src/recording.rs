@@ -0,0 +1,787 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::{BufWriter, Read, Seek, SeekFrom, Write};
+use std::sync::mpsc::{Receiver, RecvTimeoutError, Sender};
+use std::sync::{Arc, Mutex, MutexGuard, Weak};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::annotation::Annotation;
+use crate::segmentation::{SessionDetector, SessionFiles};
+use crate::structures::TelemetryMessage;
+
+const INPUT_BUFFER_SIZE: usize = 8 * 1024;
+const OUTPUT_BUFFER_SIZE: usize = 8 * 1024;
+const IDLE_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// When a `RecordingWriter` should flush recorded messages to the underlying writer
+///
+/// Messages that were not flushed are lost if the process crashes or the device loses power.
+/// In every policy, the internal buffer is also flushed when it is full (8 KiB, roughly 100 messages).
+///
+/// Flushing hands messages to the underlying writer, i.e. to the OS for files: they then survive a crash of the process, but not a power loss.
+/// Only alarms can be synced to storage, with `RecordingWriter::sync_alarms()`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum FlushPolicy {
+    /// Flush after every message; at most the message being written can be lost
+    #[default]
+    EveryMessage,
+    /// Flush once the given number of messages were written; at most this number of messages can be lost
+    EveryMessages(usize),
+    /// Flush when the given duration elapsed since the last flush; at most this duration of messages can be lost
+    ///
+    /// The duration is only checked when writing a message or when calling `RecordingWriter::tick()`.
+    Every(Duration),
+    /// Flush after every alarm trap or fatal error; every message since the last flush can be lost, but alarms are not lost if the process crashes
+    ///
+    /// Use `RecordingWriter::sync_alarms()` so that alarms are not lost after a power loss either.
+    OnAlarm,
+}
+
+impl std::str::FromStr for FlushPolicy {
+    type Err = &'static str;
+
+    /// Parse a flush policy from `message`, `messages:<count>`, `ms:<milliseconds>` or `alarm`
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        const ERROR: &str =
+            "Supported flush policies are: message, messages:<count>, ms:<milliseconds>, alarm";
+
+        let s = s.trim().to_lowercase();
+        match s.split_once(':') {
+            None if s == "message" => Ok(Self::EveryMessage),
+            None if s == "alarm" => Ok(Self::OnAlarm),
+            Some(("messages", count)) => match count.parse() {
+                Ok(count) if count > 0 => Ok(Self::EveryMessages(count)),
+                _ => Err(ERROR),
+            },
+            Some(("ms", ms)) => ms
+                .parse()
+                .map(|ms| Self::Every(Duration::from_millis(ms)))
+                .map_err(|_| ERROR),
+            _ => Err(ERROR),
+        }
+    }
+}
+
+impl FlushPolicy {
+    fn should_flush(&self, is_alarm: bool, pending_messages: usize, last_flush: Instant) -> bool {
+        match self {
+            Self::EveryMessage => pending_messages > 0,
+            Self::EveryMessages(n) => pending_messages >= *n,
+            Self::Every(duration) => pending_messages > 0 && last_flush.elapsed() >= *duration,
+            Self::OnAlarm => is_alarm,
+        }
+    }
+}
+
+struct Recorder {
+    writer: BufWriter<Box<dyn Write + Send>>,
+    /// File to sync to storage after alarms
+    sync: Option<File>,
+    policy: FlushPolicy,
+    pending_messages: usize,
+    last_flush: Instant,
+}
+
+impl Recorder {
+    fn write_frame(&mut self, frame: &[u8], is_alarm: bool) -> std::io::Result<()> {
+        // Write a new line with the base64 value of the message
+        self.writer.write_all(base64::encode(frame).as_bytes())?;
+        self.writer.write_all(b"\n")?;
+        self.pending_messages += 1;
+        self.flush_if_needed(is_alarm)
+    }
+
+    fn flush_if_needed(&mut self, is_alarm: bool) -> std::io::Result<()> {
+        // Alarms are synced whatever the policy, so they must be flushed first
+        let sync = is_alarm && self.sync.is_some();
+        if sync
+            || self
+                .policy
+                .should_flush(is_alarm, self.pending_messages, self.last_flush)
+        {
+            self.flush()?;
+        }
+        match &self.sync {
+            Some(file) if sync => file.sync_data(),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.writer.flush()?;
+        self.pending_messages = 0;
+        self.last_flush = Instant::now();
+        Ok(())
+    }
+
+    fn replace(
+        &mut self,
+        writer: Box<dyn Write + Send>,
+        sync: Option<File>,
+    ) -> std::io::Result<()> {
+        self.flush()?;
+        self.writer = BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, writer);
+        if self.sync.is_some() {
+            self.sync = sync;
+        }
+        Ok(())
+    }
+}
+
+enum Command {
+    Write(Vec<u8>, bool),
+    Flush,
+    Replace(Box<dyn Write + Send>, Option<File>),
+    Sync(File),
+}
+
+type SharedSender = Arc<Mutex<Option<Sender<Command>>>>;
+
+enum Target {
+    Direct(Arc<Mutex<Recorder>>),
+    Background {
+        tx: SharedSender,
+        handle: Option<JoinHandle<()>>,
+    },
+}
+
+fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    // A panic while holding the lock cannot leave a recorder in an inconsistent state
+    mutex
+        .lock()
+        .unwrap_or_else(|poisoned| poisoned.into_inner())
+}
+
+/// Writer of telemetry recordings (one base64-encoded message per line) that follows a `FlushPolicy`
+///
+/// In async mode, writes and flushes happen in a dedicated thread so that reading telemetry never waits for the storage;
+/// in addition to what the flush policy allows, messages that are still queued for this thread can then be lost.
+/// Remaining messages are flushed when the writer is dropped.
+pub struct RecordingWriter {
+    target: Target,
+    sessions: Option<(SessionDetector, SessionFiles)>,
+}
+
+impl From<BufWriter<File>> for RecordingWriter {
+    /// Create a writer that flushes every message in the current thread, like recordings were written before flush policies existed
+    fn from(writer: BufWriter<File>) -> Self {
+        Self::new(writer, FlushPolicy::EveryMessage)
+    }
+}
+
+impl RecordingWriter {
+    /// Create a writer that writes and flushes in the current thread
+    ///
+    /// * `writer` - Where to write the recording (usually a file).
+    /// * `policy` - When to flush written messages.
+    pub fn new<W: Write + Send + 'static>(writer: W, policy: FlushPolicy) -> Self {
+        Self {
+            target: Target::Direct(Arc::new(Mutex::new(Self::recorder(writer, policy)))),
+            sessions: None,
+        }
+    }
+
+    /// Create a writer that writes and flushes in a dedicated thread
+    ///
+    /// * `writer` - Where to write the recording (usually a file).
+    /// * `policy` - When to flush written messages.
+    ///
+    /// Write errors are not reported in this mode; the thread stops recording after the first one.
+    pub fn new_async<W: Write + Send + 'static>(writer: W, policy: FlushPolicy) -> Self {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let recorder = Self::recorder(writer, policy);
+        let handle = std::thread::spawn(move || Self::run_background(recorder, rx));
+
+        Self {
+            target: Target::Background {
+                tx: Arc::new(Mutex::new(Some(tx))),
+                handle: Some(handle),
+            },
+            sessions: None,
+        }
+    }
+
+    /// Sync alarm traps and fatal errors to storage as soon as they are written, so that they are not lost after a power loss
+    ///
+    /// * `file` - File the recording is written to (e.g. a clone obtained with `File::try_clone()`).
+    ///
+    /// Alarms are then flushed whatever the flush policy, and so are the messages written before them.
+    /// When sessions are split, the files of the next sessions are synced too.
+    pub fn sync_alarms(mut self, file: File) -> Self {
+        match &mut self.target {
+            Target::Direct(recorder) => lock(recorder).sync = Some(file),
+            // The thread was just started and cannot have stopped yet
+            Target::Background { tx, .. } => {
+                let _ = Self::send(tx, Command::Sync(file));
+            }
+        }
+        self
+    }
+
+    /// Continue the recording in a new file whenever a new session starts, so that a file never mixes two patients
+    ///
+    /// The writer given at creation is used for the first session; it should be the first file of `files`.
+    pub fn split_sessions(mut self, detector: SessionDetector, files: SessionFiles) -> Self {
+        self.sessions = Some((detector, files));
+        self
+    }
+
+    /// Record the bytes of a telemetry message
+    ///
+    /// * `frame` - Bytes of the message, including header, CRC and footer.
+    /// * `message` - Parsed message, if available; it is used by `FlushPolicy::OnAlarm`.
+    pub fn write_frame(
+        &mut self,
+        frame: &[u8],
+        message: Option<&TelemetryMessage>,
+    ) -> std::io::Result<()> {
+        let is_alarm = matches!(
+            message,
+            Some(TelemetryMessage::AlarmTrap(_)) | Some(TelemetryMessage::FatalError(_))
+        );
+        if let (Some((detector, files)), Some(message)) = (self.sessions.as_mut(), message) {
+            if let Some(boundary) = detector.handle(message) {
+                let file = files.create_next()?;
+                log::info!(
+                    "{}, recording session {} to {}",
+                    boundary,
+                    files.session(),
+                    files.path(files.session()).display()
+                );
+                let sync = file.try_clone()?;
+                let writer: Box<dyn Write + Send> = Box::new(file);
+                match &mut self.target {
+                    Target::Direct(recorder) => lock(recorder).replace(writer, Some(sync))?,
+                    Target::Background { tx, .. } => {
+                        Self::send(tx, Command::Replace(writer, Some(sync)))?
+                    }
+                }
+            }
+        }
+        match &mut self.target {
+            Target::Direct(recorder) => lock(recorder).write_frame(frame, is_alarm),
+            Target::Background { tx, .. } => {
+                Self::send(tx, Command::Write(frame.to_vec(), is_alarm))
+            }
+        }
+    }
+
+    /// Record an annotation; it is flushed right away, whatever the flush policy
+    pub fn write_annotation(&mut self, annotation: &Annotation) -> std::io::Result<()> {
+        self.annotator().write(annotation)
+    }
+
+    /// Get a handle that can write annotations from another thread while this writer is used to record messages
+    pub fn annotator(&self) -> Annotator {
+        Annotator {
+            target: match &self.target {
+                Target::Direct(recorder) => AnnotatorTarget::Direct(Arc::downgrade(recorder)),
+                Target::Background { tx, .. } => AnnotatorTarget::Background(Arc::downgrade(tx)),
+            },
+        }
+    }
+
+    /// Flush if the flush policy requires it; this should be called regularly when no message is written
+    ///
+    /// This is useful for `FlushPolicy::Every` which is otherwise only checked when a message is written.
+    pub fn tick(&mut self) -> std::io::Result<()> {
+        match &mut self.target {
+            Target::Direct(recorder) => lock(recorder).flush_if_needed(false),
+            // The background thread checks this by itself
+            Target::Background { .. } => Ok(()),
+        }
+    }
+
+    /// Flush every written message to the underlying writer
+    pub fn flush(&mut self) -> std::io::Result<()> {
+        match &mut self.target {
+            Target::Direct(recorder) => lock(recorder).flush(),
+            Target::Background { tx, .. } => Self::send(tx, Command::Flush),
+        }
+    }
+
+    fn recorder<W: Write + Send + 'static>(writer: W, policy: FlushPolicy) -> Recorder {
+        Recorder {
+            writer: BufWriter::with_capacity(OUTPUT_BUFFER_SIZE, Box::new(writer)),
+            sync: None,
+            policy,
+            pending_messages: 0,
+            last_flush: Instant::now(),
+        }
+    }
+
+    fn send(tx: &Mutex<Option<Sender<Command>>>, command: Command) -> std::io::Result<()> {
+        lock(tx)
+            .as_ref()
+            .and_then(|tx| tx.send(command).ok())
+            .ok_or_else(|| {
+                std::io::Error::new(
+                    std::io::ErrorKind::BrokenPipe,
+                    "recording thread is not running anymore",
+                )
+            })
+    }
+
+    fn run_background(mut recorder: Recorder, rx: Receiver<Command>) {
+        loop {
+            let result = match rx.recv_timeout(IDLE_CHECK_PERIOD) {
+                Ok(Command::Write(frame, is_alarm)) => recorder.write_frame(&frame, is_alarm),
+                Ok(Command::Flush) => recorder.flush(),
+                Ok(Command::Replace(writer, sync)) => recorder.replace(writer, sync),
+                Ok(Command::Sync(file)) => {
+                    recorder.sync = Some(file);
+                    Ok(())
+                }
+                Err(RecvTimeoutError::Timeout) => recorder.flush_if_needed(false),
+                Err(RecvTimeoutError::Disconnected) => {
+                    let _ = recorder.flush();
+                    break;
+                }
+            };
+            if result.is_err() {
+                break;
+            }
+        }
+    }
+}
+
+impl Drop for RecordingWriter {
+    fn drop(&mut self) {
+        match &mut self.target {
+            Target::Direct(recorder) => {
+                let _ = lock(recorder).flush();
+            }
+            Target::Background { tx, handle } => {
+                // Closing the channel stops the thread once every queued message is written
+                lock(tx).take();
+                if let Some(handle) = handle.take() {
+                    let _ = handle.join();
+                }
+            }
+        }
+    }
+}
+
+enum AnnotatorTarget {
+    Direct(Weak<Mutex<Recorder>>),
+    Background(Weak<Mutex<Option<Sender<Command>>>>),
+}
+
+/// Handle to write annotations in a recording that is being written by a `RecordingWriter`
+///
+/// It does not keep the recording open: writing fails once the `RecordingWriter` is dropped.
+pub struct Annotator {
+    target: AnnotatorTarget,
+}
+
+impl Annotator {
+    /// Record an annotation; it is flushed right away, whatever the flush policy
+    pub fn write(&self, annotation: &Annotation) -> std::io::Result<()> {
+        let closed = || {
+            std::io::Error::new(
+                std::io::ErrorKind::BrokenPipe,
+                "recording is not open anymore",
+            )
+        };
+        let frame = annotation.to_frame();
+        match &self.target {
+            AnnotatorTarget::Direct(recorder) => {
+                let recorder = recorder.upgrade().ok_or_else(closed)?;
+                let mut recorder = lock(&recorder);
+                recorder.write_frame(&frame, false)?;
+                recorder.flush()
+            }
+            AnnotatorTarget::Background(tx) => {
+                let tx = tx.upgrade().ok_or_else(closed)?;
+                RecordingWriter::send(&tx, Command::Write(frame, false))?;
+                RecordingWriter::send(&tx, Command::Flush)
+            }
+        }
+    }
+}
+
+/// Incremental decoder for base64-encoded recordings
+///
+/// Recordings contain base64 data, usually one telemetry message per line, but this decoder does not rely on lines:
+/// whitespaces are ignored wherever they are, padding is optional at the end of the stream and invalid characters are skipped (along with the partially read group of 4 characters they belong to).
+/// It decodes data as it is read, so it never needs to hold a whole line in memory.
+pub struct Base64Decoder<R> {
+    inner: R,
+    input: Box<[u8]>,
+    input_pos: usize,
+    input_len: usize,
+    quantum: [u8; 4],
+    quantum_len: usize,
+    output: [u8; 3],
+    output_pos: usize,
+    output_len: usize,
+    consumed_bytes: u64,
+    eof: bool,
+}
+
+impl<R: Read> Base64Decoder<R> {
+    /// Wrap a reader of base64 data
+    pub fn new(inner: R) -> Self {
+        Base64Decoder {
+            inner,
+            input: vec![0; INPUT_BUFFER_SIZE].into_boxed_slice(),
+            input_pos: 0,
+            input_len: 0,
+            quantum: [0; 4],
+            quantum_len: 0,
+            output: [0; 3],
+            output_pos: 0,
+            output_len: 0,
+            consumed_bytes: 0,
+            eof: false,
+        }
+    }
+
+    /// Number of base64 bytes that were consumed from the underlying reader so far
+    pub fn consumed_bytes(&self) -> u64 {
+        self.consumed_bytes
+    }
+
+    /// Get back the underlying reader
+    pub fn into_inner(self) -> R {
+        self.inner
+    }
+
+    fn flush_quantum(&mut self) {
+        let q = &self.quantum;
+        let bytes = [
+            (q[0] << 2) | (q[1] >> 4),
+            (q[1] << 4) | (q[2] >> 2),
+            (q[2] << 6) | q[3],
+        ];
+        // 2 characters hold 1 byte, 3 characters hold 2 bytes and 4 characters hold 3 bytes
+        self.output_len = self.quantum_len.saturating_sub(1);
+        self.output[..self.output_len].copy_from_slice(&bytes[..self.output_len]);
+        self.output_pos = 0;
+        self.quantum = [0; 4];
+        self.quantum_len = 0;
+    }
+}
+
+impl<R: Read + Seek> Base64Decoder<R> {
+    /// Size of the underlying data, in bytes
+    pub(crate) fn inner_len(&mut self) -> std::io::Result<u64> {
+        let position = self.inner.stream_position()?;
+        let len = self.inner.seek(SeekFrom::End(0))?;
+        self.inner.seek(SeekFrom::Start(position))?;
+        Ok(len)
+    }
+
+    /// Continue decoding from the first line that starts at or after `offset` in the underlying data
+    ///
+    /// Lines of recordings are encoded separately, so decoding is aligned again at the start of a line.
+    pub(crate) fn seek_to_line(&mut self, offset: u64) -> std::io::Result<()> {
+        // The byte before `offset` tells whether a line starts at `offset`
+        let start = offset.saturating_sub(1);
+        self.inner.seek(SeekFrom::Start(start))?;
+        self.input_pos = 0;
+        self.input_len = 0;
+        self.quantum_len = 0;
+        self.output_pos = 0;
+        self.output_len = 0;
+        self.consumed_bytes = start;
+        self.eof = false;
+        if offset == 0 {
+            return Ok(());
+        }
+
+        loop {
+            self.input_len = self.inner.read(&mut self.input)?;
+            if self.input_len == 0 {
+                self.eof = true;
+                return Ok(());
+            }
+            match self.input[..self.input_len]
+                .iter()
+                .position(|c| *c == b'\n')
+            {
+                Some(newline) => {
+                    self.input_pos = newline + 1;
+                    self.consumed_bytes += self.input_pos as u64;
+                    return Ok(());
+                }
+                None => self.consumed_bytes += self.input_len as u64,
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for Base64Decoder<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let mut written = 0;
+
+        while written < buf.len() {
+            if self.output_pos < self.output_len {
+                let available = &self.output[self.output_pos..self.output_len];
+                let count = available.len().min(buf.len() - written);
+                buf[written..written + count].copy_from_slice(&available[..count]);
+                self.output_pos += count;
+                written += count;
+                continue;
+            }
+
+            if self.input_pos == self.input_len {
+                // Do not block waiting for more data if we already have something to return
+                if written > 0 || self.eof {
+                    break;
+                }
+                self.input_len = self.inner.read(&mut self.input)?;
+                self.input_pos = 0;
+                if self.input_len == 0 {
+                    // Tolerate missing padding at the end of the stream
+                    self.eof = true;
+                    self.flush_quantum();
+                }
+                continue;
+            }
+
+            let c = self.input[self.input_pos];
+            self.input_pos += 1;
+            self.consumed_bytes += 1;
+
+            match c {
+                b'=' => self.flush_quantum(),
+                c if c.is_ascii_whitespace() => (),
+                c => match decode_char(c) {
+                    Some(value) => {
+                        self.quantum[self.quantum_len] = value;
+                        self.quantum_len += 1;
+                        if self.quantum_len == 4 {
+                            self.flush_quantum();
+                        }
+                    }
+                    None => {
+                        self.quantum = [0; 4];
+                        self.quantum_len = 0;
+                    }
+                },
+            }
+        }
+
+        Ok(written)
+    }
+}
+
+fn decode_char(c: u8) -> Option<u8> {
+    match c {
+        b'A'..=b'Z' => Some(c - b'A'),
+        b'a'..=b'z' => Some(c - b'a' + 26),
+        b'0'..=b'9' => Some(c - b'0' + 52),
+        b'+' => Some(62),
+        b'/' => Some(63),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use proptest::prelude::*;
+    use std::sync::{Arc, Mutex};
+
+    fn decode_all(input: &[u8]) -> Vec<u8> {
+        let mut output = Vec::new();
+        Base64Decoder::new(input)
+            .read_to_end(&mut output)
+            .expect("failed to decode");
+        output
+    }
+
+    #[test]
+    fn tolerates_missing_padding_and_newline() {
+        assert_eq!(decode_all(b"aGVsbG8"), b"hello");
+        assert_eq!(decode_all(b"aGVsbG8=\nd29ybGQ="), b"helloworld");
+    }
+
+    #[test]
+    fn skips_invalid_groups() {
+        assert_eq!(decode_all(b"aGVs\naG*=\nbG8=\n"), b"hello");
+    }
+
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+
+    fn shared_buffer() -> (SharedBuffer, Arc<Mutex<Vec<u8>>>) {
+        let output = Arc::new(Mutex::new(Vec::new()));
+        (SharedBuffer(Arc::clone(&output)), output)
+    }
+
+    fn flushed_lines(output: &Arc<Mutex<Vec<u8>>>) -> usize {
+        output
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|b| **b == b'\n')
+            .count()
+    }
+
+    #[test]
+    fn parse_flush_policy() {
+        assert_eq!("message".parse(), Ok(FlushPolicy::EveryMessage));
+        assert_eq!("messages:10".parse(), Ok(FlushPolicy::EveryMessages(10)));
+        assert_eq!(
+            "ms:500".parse(),
+            Ok(FlushPolicy::Every(Duration::from_millis(500)))
+        );
+        assert_eq!("alarm".parse(), Ok(FlushPolicy::OnAlarm));
+        assert!("messages:0".parse::<FlushPolicy>().is_err());
+        assert!("sometimes".parse::<FlushPolicy>().is_err());
+    }
+
+    #[test]
+    fn flush_every_messages() {
+        let (writer, output) = shared_buffer();
+        let mut recorder = RecordingWriter::new(writer, FlushPolicy::EveryMessages(3));
+
+        recorder.write_frame(b"a", None).unwrap();
+        recorder.write_frame(b"b", None).unwrap();
+        assert_eq!(flushed_lines(&output), 0);
+        recorder.write_frame(b"c", None).unwrap();
+        assert_eq!(flushed_lines(&output), 3);
+
+        recorder.write_frame(b"d", None).unwrap();
+        drop(recorder);
+        assert_eq!(flushed_lines(&output), 4);
+    }
+
+    #[test]
+    fn async_writer_flushes_on_drop() {
+        let (writer, output) = shared_buffer();
+        let mut recorder = RecordingWriter::new_async(writer, FlushPolicy::OnAlarm);
+
+        for frame in [&b"hello"[..], b"world"] {
+            recorder.write_frame(frame, None).unwrap();
+        }
+        drop(recorder);
+
+        let recorded = output.lock().unwrap().clone();
+        assert_eq!(decode_all(&recorded), b"helloworld");
+    }
+
+    #[test]
+    fn splits_sessions_into_files() {
+        use crate::structures::StoppedMessage;
+
+        let directory = std::env::temp_dir().join("makair-telemetry-split-sessions");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let mut files = SessionFiles::new(directory.join("night.record"));
+        let first = files.create_next().unwrap();
+        let mut recorder = RecordingWriter::new_async(first, FlushPolicy::EveryMessage)
+            .split_sessions(SessionDetector::default(), files);
+
+        for (device_id, frame) in [("a", b"a1"), ("a", b"a2"), ("b", b"b1")] {
+            let message = TelemetryMessage::StoppedMessage(StoppedMessage {
+                telemetry_version: 2,
+                device_id: device_id.to_owned(),
+                ..Default::default()
+            });
+            recorder.write_frame(frame, Some(&message)).unwrap();
+        }
+        drop(recorder);
+
+        let read = |name: &str| decode_all(&std::fs::read(directory.join(name)).unwrap());
+        assert_eq!(read("night-session-1.record"), b"a1a2");
+        assert_eq!(read("night-session-2.record"), b"b1");
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn syncs_alarms_to_the_file() {
+        use crate::structures::{FatalError, FatalErrorDetails};
+
+        let path = std::env::temp_dir().join("makair-telemetry-sync-alarms.record");
+        let file = File::create(&path).unwrap();
+        let alarm = TelemetryMessage::FatalError(FatalError {
+            telemetry_version: 2,
+            version: "v2".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 0,
+            error: FatalErrorDetails::WatchdogRestart,
+        });
+        let read = || decode_all(&std::fs::read(&path).unwrap());
+
+        let mut recorder =
+            RecordingWriter::new(file.try_clone().unwrap(), FlushPolicy::EveryMessages(100))
+                .sync_alarms(file);
+        recorder.write_frame(b"a", None).unwrap();
+        assert_eq!(read(), b"");
+        recorder.write_frame(b"b", Some(&alarm)).unwrap();
+        assert_eq!(read(), b"ab");
+        drop(recorder);
+
+        // Files given as a buffer are flushed after every message
+        let mut recorder = RecordingWriter::from(BufWriter::new(File::create(&path).unwrap()));
+        recorder.write_frame(b"c", None).unwrap();
+        assert_eq!(read(), b"c");
+        drop(recorder);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn annotator_writes_while_recording() {
+        for asynchronous in [false, true] {
+            let (writer, output) = shared_buffer();
+            let mut recorder = if asynchronous {
+                RecordingWriter::new_async(writer, FlushPolicy::EveryMessages(100))
+            } else {
+                RecordingWriter::new(writer, FlushPolicy::EveryMessages(100))
+            };
+            let annotator = recorder.annotator();
+
+            recorder.write_frame(b"a", None).unwrap();
+            std::thread::spawn(move || annotator.write(&Annotation::new(1, "note")))
+                .join()
+                .unwrap()
+                .unwrap();
+            recorder.write_frame(b"b", None).unwrap();
+            let annotator = recorder.annotator();
+            drop(recorder);
+            assert!(annotator.write(&Annotation::new(2, "late")).is_err());
+
+            let recorded = output.lock().unwrap().clone();
+            let expected = [&b"a"[..], &Annotation::new(1, "note").to_frame(), b"b"].concat();
+            assert_eq!(decode_all(&recorded), expected);
+        }
+    }
+
+    proptest! {
+        #[test]
+        fn decodes_like_base64_crate(
+            messages in proptest::collection::vec(proptest::collection::vec(any::<u8>(), 0..300), 0..20),
+            line_length in 1usize..100,
+        ) {
+            // Re-wrap the usual one-message-per-line format at an arbitrary line length
+            let encoded: String = messages.iter().map(base64::encode).collect::<Vec<String>>().join("\n");
+            let wrapped = encoded
+                .as_bytes()
+                .chunks(line_length)
+                .collect::<Vec<&[u8]>>()
+                .join(&b"\r\n"[..]);
+
+            let decoded = decode_all(&wrapped);
+            let expected: Vec<u8> = messages.concat();
+            prop_assert_eq!(decoded, expected);
+        }
+    }
+}
@@ -0,0 +1,241 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::control::{ControlMessage, ProtectedControlMessage};
+use crate::structures::TelemetryMessage;
+
+/// Control protocol extensions supported by a firmware, as a bit field
+///
+/// Firmwares advertise them in their boot messages (`BootMessage::control_capabilities`, from protocol v3); firmwares that do not advertise any capability only understand legacy control frames.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct ControlCapabilities(u16);
+
+impl ControlCapabilities {
+    /// Control frames can carry a session nonce and a sequence number (see `ControlMessage::to_protected_control_frame()`)
+    pub const REPLAY_PROTECTION: u16 = 0b0000_0001;
+    /// Data snapshots can be decimated with the `DataSnapshotDecimation` setting
+    pub const DATA_SNAPSHOT_DECIMATION: u16 = 0b0000_0010;
+
+    /// Read capabilities from the bit field advertised by the firmware
+    pub fn from_bits(bits: u16) -> Self {
+        Self(bits)
+    }
+
+    /// Bit field of capabilities
+    pub fn bits(&self) -> u16 {
+        self.0
+    }
+
+    /// Whether the firmware accepts control frames protected against replays
+    pub fn supports_replay_protection(&self) -> bool {
+        self.0 & Self::REPLAY_PROTECTION != 0
+    }
+
+    /// Whether the firmware can send fewer data snapshots when asked to (see `decimation::DecimationRequest`)
+    pub fn supports_data_snapshot_decimation(&self) -> bool {
+        self.0 & Self::DATA_SNAPSHOT_DECIMATION != 0
+    }
+}
+
+/// Why a protected control frame was rejected
+#[derive(Debug, Clone, Copy, PartialEq, Eq, thiserror::Error)]
+pub enum ReplayError {
+    /// The frame was meant for another session of the device (e.g. it was recorded before a reboot)
+    #[error("frame was meant for session {found:#010x}, current session is {expected:#010x}")]
+    WrongNonce {
+        /// Nonce of the current session
+        expected: u32,
+        /// Nonce found in the frame
+        found: u32,
+    },
+    /// The sequence number was already used in this session
+    #[error("sequence number {found} is not after the last accepted one ({last})")]
+    StaleSequence {
+        /// Last accepted sequence number
+        last: u32,
+        /// Sequence number found in the frame
+        found: u32,
+    },
+}
+
+/// Creates control frames, protected against replays when the firmware supports it
+///
+/// The `gather_telemetry*()` functions keep one for each device, and give it every boot message they receive: control frames are protected once the firmware advertised replay protection, with the nonce of its current session.
+#[derive(Debug, Clone, Default)]
+pub struct ControlFrameEncoder {
+    session: Option<(u32, u32)>,
+}
+
+impl ControlFrameEncoder {
+    /// Create an encoder for a device
+    ///
+    /// * `capabilities` - Capabilities advertised by the firmware.
+    /// * `nonce` - Nonce of the current session of the device, if known.
+    ///
+    /// Legacy frames are created unless the firmware supports replay protection and the nonce is known.
+    pub fn new(capabilities: ControlCapabilities, nonce: Option<u32>) -> Self {
+        Self {
+            session: nonce
+                .filter(|_| capabilities.supports_replay_protection())
+                .map(|nonce| (nonce, 0)),
+        }
+    }
+
+    /// Start a new session when the device boots, using the capabilities and the nonce advertised in its boot message
+    ///
+    /// Other messages are ignored.
+    pub fn update(&mut self, message: &TelemetryMessage) {
+        if let TelemetryMessage::BootMessage(boot) = message {
+            let capabilities =
+                ControlCapabilities::from_bits(boot.control_capabilities.unwrap_or_default());
+            *self = Self::new(capabilities, boot.session_nonce);
+        }
+    }
+
+    /// Whether created frames are protected against replays
+    pub fn is_protected(&self) -> bool {
+        self.session.is_some()
+    }
+
+    /// Create the frame of a message, using the next sequence number if it is protected
+    pub fn encode(&mut self, message: &ControlMessage) -> Vec<u8> {
+        match self.session.as_mut() {
+            Some((nonce, next_sequence)) => {
+                let frame = message.to_protected_control_frame(*nonce, *next_sequence);
+                *next_sequence = next_sequence.wrapping_add(1);
+                frame
+            }
+            None => message.to_control_frame(),
+        }
+    }
+}
+
+/// Rejects protected control frames that were already received or that were meant for another session
+///
+/// This is what a firmware (or a simulator) supporting replay protection does with every frame it receives.
+#[derive(Debug, Clone)]
+pub struct ReplayValidator {
+    nonce: u32,
+    last_sequence: Option<u32>,
+}
+
+impl ReplayValidator {
+    /// Start a session with a fresh nonce
+    pub fn new(nonce: u32) -> Self {
+        Self {
+            nonce,
+            last_sequence: None,
+        }
+    }
+
+    /// Accept a frame if it belongs to this session and its sequence number is after the last accepted one
+    pub fn check(&mut self, frame: &ProtectedControlMessage) -> Result<(), ReplayError> {
+        if frame.nonce != self.nonce {
+            return Err(ReplayError::WrongNonce {
+                expected: self.nonce,
+                found: frame.nonce,
+            });
+        }
+        if let Some(last) = self.last_sequence.filter(|last| frame.sequence <= *last) {
+            return Err(ReplayError::StaleSequence {
+                last,
+                found: frame.sequence,
+            });
+        }
+        self.last_sequence = Some(frame.sequence);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{parse_control_message, parse_protected_control_message, ControlSetting};
+    use crate::structures::{BootMessage, Mode};
+
+    fn peep(value: u16) -> ControlMessage {
+        ControlMessage {
+            setting: ControlSetting::PEEP,
+            value,
+        }
+    }
+
+    #[test]
+    fn falls_back_to_legacy_frames() {
+        let mut encoder = ControlFrameEncoder::new(ControlCapabilities::default(), Some(42));
+        assert!(!encoder.is_protected());
+        assert!(parse_control_message(&encoder.encode(&peep(50))).is_ok());
+
+        let capabilities = ControlCapabilities::from_bits(ControlCapabilities::REPLAY_PROTECTION);
+        assert!(!ControlFrameEncoder::new(capabilities, None).is_protected());
+    }
+
+    #[test]
+    fn starts_a_session_when_the_device_boots() {
+        let boot = |control_capabilities, session_nonce| {
+            TelemetryMessage::BootMessage(BootMessage {
+                telemetry_version: 3,
+                version: "test".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 0,
+                mode: Mode::Production,
+                value128: 128,
+                control_capabilities,
+                session_nonce,
+            })
+        };
+        let mut encoder = ControlFrameEncoder::default();
+        assert!(!encoder.is_protected());
+
+        encoder.update(&boot(
+            Some(ControlCapabilities::REPLAY_PROTECTION),
+            Some(42),
+        ));
+        encoder.encode(&peep(50));
+        let frame = encoder.encode(&peep(80));
+        let (_, frame) = parse_protected_control_message(&frame).unwrap();
+        assert_eq!((frame.nonce, frame.sequence), (42, 1));
+
+        // Sequence numbers start over with the session of the next boot
+        encoder.update(&boot(
+            Some(ControlCapabilities::REPLAY_PROTECTION),
+            Some(43),
+        ));
+        let (_, frame) = parse_protected_control_message(&encoder.encode(&peep(50))).unwrap();
+        assert_eq!((frame.nonce, frame.sequence), (43, 0));
+
+        // e.g. after flashing an older firmware
+        encoder.update(&boot(None, None));
+        assert!(!encoder.is_protected());
+    }
+
+    #[test]
+    fn rejects_replayed_frames() {
+        let capabilities = ControlCapabilities::from_bits(ControlCapabilities::REPLAY_PROTECTION);
+        let mut encoder = ControlFrameEncoder::new(capabilities, Some(42));
+        let mut validator = ReplayValidator::new(42);
+
+        let first = encoder.encode(&peep(50));
+        let second = encoder.encode(&peep(80));
+        let (_, first) = parse_protected_control_message(&first).unwrap();
+        let (_, second) = parse_protected_control_message(&second).unwrap();
+
+        assert_eq!(validator.check(&first), Ok(()));
+        assert_eq!(validator.check(&second), Ok(()));
+        assert_eq!(
+            validator.check(&first),
+            Err(ReplayError::StaleSequence { last: 1, found: 0 })
+        );
+
+        let mut rebooted = ReplayValidator::new(43);
+        assert_eq!(
+            rebooted.check(&second),
+            Err(ReplayError::WrongNonce {
+                expected: 43,
+                found: 42
+            })
+        );
+    }
+}
@@ -0,0 +1,404 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fmt::Write as _;
+
+use crate::alarm::AlarmCode;
+use crate::annotation::Annotation;
+use crate::control::ControlSetting;
+use crate::filter::MessageType;
+use crate::structures::{AlarmPriority, TelemetryMessage};
+
+/// Maximum number of waveform thumbnails in a report
+pub const MAX_THUMBNAILS: usize = 12;
+/// Duration of recording shown before and after an alarm in a thumbnail, in microseconds
+pub const THUMBNAIL_HALF_WINDOW: u64 = 5_000_000;
+
+/// A setting that was acknowledged by the MCU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SettingChange {
+    /// Systick of the acknowledgment
+    pub systick: u64,
+    /// Setting that was changed
+    pub setting: ControlSetting,
+    /// New value
+    pub value: u16,
+}
+
+/// An alarm that was triggered or stopped
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AlarmEvent {
+    /// Systick of the alarm trap
+    pub systick: u64,
+    /// Cycle during which the alarm was triggered or stopped
+    pub cycle: u32,
+    /// Code of the alarm
+    pub code: u8,
+    /// Priority of the alarm
+    pub priority: AlarmPriority,
+    /// Whether the alarm was triggered (`true`) or stopped (`false`)
+    pub triggered: bool,
+}
+
+/// An image embedded in a report
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Thumbnail {
+    /// Text displayed under the image
+    pub caption: String,
+    /// SVG document
+    pub svg: String,
+}
+
+/// Summary of a recorded session, that can be rendered as a standalone HTML page
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SessionReport {
+    /// Title of the report (usually the name of the recording)
+    pub title: String,
+    /// Internal ID of the MCU, if any message contained it
+    pub device_id: Option<String>,
+    /// Version of the MCU firmware, if any message contained it
+    pub firmware_version: Option<String>,
+    /// Smallest systick of the session
+    pub first_systick: Option<u64>,
+    /// Greatest systick of the session
+    pub last_systick: Option<u64>,
+    /// Number of messages of each type
+    pub message_counts: Vec<(MessageType, usize)>,
+    /// Number of respiratory cycles (machine state snapshots)
+    pub cycles: usize,
+    /// Settings acknowledged by the MCU, in order
+    pub settings_history: Vec<SettingChange>,
+    /// Alarms triggered or stopped, in order
+    pub alarms: Vec<AlarmEvent>,
+    /// Operator annotations
+    pub annotations: Vec<Annotation>,
+    /// Waveform images
+    pub thumbnails: Vec<Thumbnail>,
+}
+
+impl SessionReport {
+    /// Compute a report (without annotations nor thumbnails) from the messages of a session
+    pub fn from_messages<'a, I: IntoIterator<Item = &'a TelemetryMessage>>(
+        title: &str,
+        messages: I,
+    ) -> Self {
+        let mut report = Self {
+            title: title.to_owned(),
+            ..Self::default()
+        };
+
+        for message in messages {
+            let message_type = MessageType::of(message);
+            match report
+                .message_counts
+                .iter_mut()
+                .find(|(t, _)| *t == message_type)
+            {
+                Some((_, count)) => *count += 1,
+                None => report.message_counts.push((message_type, 1)),
+            }
+            if message_type == MessageType::Unknown {
+                continue;
+            }
+
+            if report.device_id.is_none() && !message.device_id().is_empty() {
+                report.device_id = Some(message.device_id());
+                report.firmware_version = Some(message.version());
+            }
+            let systick = message.systick();
+            report.first_systick = Some(report.first_systick.map_or(systick, |s| s.min(systick)));
+            report.last_systick = Some(report.last_systick.map_or(systick, |s| s.max(systick)));
+
+            match message {
+                TelemetryMessage::MachineStateSnapshot(_) => report.cycles += 1,
+                TelemetryMessage::ControlAck(ack) => report.settings_history.push(SettingChange {
+                    systick,
+                    setting: ack.setting,
+                    value: ack.value,
+                }),
+                TelemetryMessage::AlarmTrap(trap) => report.alarms.push(AlarmEvent {
+                    systick,
+                    cycle: trap.cycle,
+                    code: trap.alarm_code,
+                    priority: trap.alarm_priority,
+                    triggered: trap.triggered,
+                }),
+                _ => (),
+            }
+        }
+
+        report
+    }
+
+    /// Render waveform thumbnails around triggered alarms (or at the beginning of the session if there is none)
+    ///
+    /// * `messages` - Messages of the session, sorted by systick.
+    #[cfg(feature = "plot")]
+    #[cfg_attr(doc_cfg, doc(cfg(feature = "plot")))]
+    pub fn add_thumbnails(
+        &mut self,
+        messages: &[TelemetryMessage],
+    ) -> Result<(), crate::plot::PlotError> {
+        use crate::plot::{PlotOptions, Waveforms};
+
+        let options = PlotOptions {
+            width: 480,
+            height: 360,
+            title: None,
+        };
+
+        let mut windows: Vec<(String, u64, u64)> = Vec::new();
+        for alarm in self.alarms.iter().filter(|alarm| alarm.triggered) {
+            if windows.len() >= MAX_THUMBNAILS {
+                break;
+            }
+            // Alarms that are visible in the previous thumbnail do not need another one
+            if windows
+                .last()
+                .is_some_and(|(_, _, end)| alarm.systick <= *end)
+            {
+                continue;
+            }
+            windows.push((
+                format!(
+                    "Alarm {} ({:?}) at {}",
+                    alarm.code,
+                    AlarmCode::from(alarm.code).description(),
+                    format_systick(alarm.systick)
+                ),
+                alarm.systick.saturating_sub(THUMBNAIL_HALF_WINDOW),
+                alarm.systick.saturating_add(THUMBNAIL_HALF_WINDOW),
+            ));
+        }
+        if windows.is_empty() {
+            if let Some(first) = self.first_systick {
+                windows.push((
+                    format!("Beginning of the session ({})", format_systick(first)),
+                    first,
+                    first.saturating_add(2 * THUMBNAIL_HALF_WINDOW),
+                ));
+            }
+        }
+
+        for (caption, start, end) in windows {
+            let waveforms = Waveforms::from_messages(
+                messages
+                    .iter()
+                    .filter(|message| (start..=end).contains(&message.systick())),
+            );
+            if waveforms.is_empty() {
+                continue;
+            }
+            self.thumbnails.push(Thumbnail {
+                caption,
+                svg: waveforms.render_svg(&options)?,
+            });
+        }
+        Ok(())
+    }
+
+    /// Render the report as a standalone HTML page
+    pub fn to_html(&self) -> String {
+        let mut html = String::new();
+        // Writing to a String cannot fail, so results are ignored below
+        let _ = write!(
+            html,
+            "<!DOCTYPE html>\n<html>\n<head>\n<meta charset=\"utf-8\">\n<title>{}</title>\n<style>{}</style>\n</head>\n<body>\n<h1>{}</h1>\n",
+            escape(&self.title),
+            STYLE,
+            escape(&self.title)
+        );
+
+        html.push_str("<h2>Summary</h2>\n<table>\n");
+        let duration = match (self.first_systick, self.last_systick) {
+            (Some(first), Some(last)) => format_systick(last - first),
+            _ => "-".to_owned(),
+        };
+        for (name, value) in [
+            ("Device ID", self.device_id.clone().unwrap_or_default()),
+            (
+                "Firmware version",
+                self.firmware_version.clone().unwrap_or_default(),
+            ),
+            ("Duration", duration),
+            ("Cycles", self.cycles.to_string()),
+            (
+                "Triggered alarms",
+                self.alarms
+                    .iter()
+                    .filter(|alarm| alarm.triggered)
+                    .count()
+                    .to_string(),
+            ),
+        ] {
+            let _ = writeln!(
+                html,
+                "<tr><th>{}</th><td>{}</td></tr>",
+                name,
+                escape(&value)
+            );
+        }
+        for (message_type, count) in &self.message_counts {
+            let _ = writeln!(
+                html,
+                "<tr><th>Messages: {}</th><td>{}</td></tr>",
+                message_type.name(),
+                count
+            );
+        }
+        html.push_str("</table>\n");
+
+        html.push_str("<h2>Settings history</h2>\n");
+        if self.settings_history.is_empty() {
+            html.push_str("<p>No setting was changed.</p>\n");
+        } else {
+            html.push_str("<table>\n<tr><th>Time</th><th>Setting</th><th>Value</th></tr>\n");
+            for change in &self.settings_history {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{:?}</td><td>{}</td></tr>",
+                    format_systick(change.systick),
+                    change.setting,
+                    change.value
+                );
+            }
+            html.push_str("</table>\n");
+        }
+
+        html.push_str("<h2>Alarm timeline</h2>\n");
+        if self.alarms.is_empty() {
+            html.push_str("<p>No alarm.</p>\n");
+        } else {
+            html.push_str("<table>\n<tr><th>Time</th><th>Cycle</th><th>Code</th><th>Description</th><th>Priority</th><th>Event</th></tr>\n");
+            for alarm in &self.alarms {
+                let _ = writeln!(
+                    html,
+                    "<tr class=\"{}\"><td>{}</td><td>{}</td><td>{}</td><td>{:?}</td><td>{:?}</td><td>{}</td></tr>",
+                    if alarm.triggered { "triggered" } else { "stopped" },
+                    format_systick(alarm.systick),
+                    alarm.cycle,
+                    alarm.code,
+                    AlarmCode::from(alarm.code).description(),
+                    alarm.priority,
+                    if alarm.triggered { "triggered" } else { "stopped" }
+                );
+            }
+            html.push_str("</table>\n");
+        }
+
+        if !self.annotations.is_empty() {
+            html.push_str("<h2>Annotations</h2>\n<table>\n<tr><th>Time</th><th>Text</th></tr>\n");
+            for annotation in &self.annotations {
+                let _ = writeln!(
+                    html,
+                    "<tr><td>{}</td><td>{}</td></tr>",
+                    format_systick(annotation.systick),
+                    escape(&annotation.text)
+                );
+            }
+            html.push_str("</table>\n");
+        }
+
+        if !self.thumbnails.is_empty() {
+            html.push_str("<h2>Waveforms</h2>\n<div class=\"thumbnails\">\n");
+            for thumbnail in &self.thumbnails {
+                let _ = writeln!(
+                    html,
+                    "<figure>{}<figcaption>{}</figcaption></figure>",
+                    thumbnail.svg,
+                    escape(&thumbnail.caption)
+                );
+            }
+            html.push_str("</div>\n");
+        }
+
+        html.push_str("</body>\n</html>\n");
+        html
+    }
+}
+
+const STYLE: &str = "body{font-family:sans-serif;margin:2em}table{border-collapse:collapse;margin-bottom:1em}th,td{border:1px solid #ccc;padding:4px 8px;text-align:left}tr.triggered{background:#fde0dc}.thumbnails{display:flex;flex-wrap:wrap}figure{margin:0 1em 1em 0}";
+
+/// Format a systick (microseconds) as `HH:MM:SS.mmm`
+fn format_systick(systick: u64) -> String {
+    let millis = systick / 1_000;
+    format!(
+        "{:02}:{:02}:{:02}.{:03}",
+        millis / 3_600_000,
+        millis / 60_000 % 60,
+        millis / 1_000 % 60,
+        millis % 1_000
+    )
+}
+
+fn escape(text: &str) -> String {
+    let mut escaped = String::with_capacity(text.len());
+    for c in text.chars() {
+        match c {
+            '<' => escaped.push_str("&lt;"),
+            '>' => escaped.push_str("&gt;"),
+            '&' => escaped.push_str("&amp;"),
+            '"' => escaped.push_str("&quot;"),
+            '\'' => escaped.push_str("&#39;"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{AlarmTrap, ControlAck, Phase};
+
+    #[test]
+    fn summarizes_session() {
+        let messages = vec![
+            TelemetryMessage::ControlAck(ControlAck {
+                telemetry_version: 2,
+                version: "v1".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 1_000_000,
+                setting: ControlSetting::PEEP,
+                value: 80,
+            }),
+            TelemetryMessage::AlarmTrap(AlarmTrap {
+                telemetry_version: 2,
+                version: "v1".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 3_723_004_000,
+                centile: 0,
+                pressure: 0,
+                phase: Phase::Exhalation,
+                subphase: None,
+                cycle: 7,
+                alarm_code: 12,
+                alarm_priority: AlarmPriority::High,
+                triggered: true,
+                expected: 0,
+                measured: 0,
+                cycles_since_trigger: 0,
+            }),
+        ];
+
+        let mut report = SessionReport::from_messages("<session>", &messages);
+        report
+            .annotations
+            .push(Annotation::new(2_000_000, "patient simulator disconnected"));
+        assert_eq!(report.device_id.as_deref(), Some("1-2-3"));
+        assert_eq!(report.settings_history.len(), 1);
+        assert_eq!(report.alarms[0].cycle, 7);
+        assert_eq!(
+            report.message_counts,
+            vec![(MessageType::ControlAck, 1), (MessageType::AlarmTrap, 1)]
+        );
+
+        let html = report.to_html();
+        assert!(html.contains("<title>&lt;session&gt;</title>"));
+        assert!(html.contains("01:02:03.004"));
+        assert!(html.contains("PEEP"));
+        assert!(html.contains("patient simulator disconnected"));
+    }
+}
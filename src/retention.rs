@@ -0,0 +1,274 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use crate::annotation::read_annotations;
+use crate::overview::OVERVIEW_EXTENSION;
+
+/// Extension of the files a retention policy applies to
+pub const RECORDING_EXTENSION: &str = "record";
+
+/// Extension added to a recording path once it was uploaded (see the `upload` module); the file contains the SHA-256 of the recording
+pub const UPLOADED_MARKER_EXTENSION: &str = "uploaded";
+
+/// Rules deciding which recordings of a directory to delete
+///
+/// Recordings are deleted when they are older than the maximum age, then the oldest ones are deleted until the directory fits in the maximum size.
+/// Age is measured from the last modification of files.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct RetentionPolicy {
+    max_age: Option<Duration>,
+    max_total_size: Option<u64>,
+    keep_annotated: bool,
+    uploaded_only: bool,
+}
+
+impl RetentionPolicy {
+    /// Create a policy that keeps everything
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Delete recordings last modified longer ago than this
+    pub fn max_age(mut self, max_age: Duration) -> Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Delete the oldest recordings until their total size is at most this number of bytes
+    pub fn max_total_size(mut self, bytes: u64) -> Self {
+        self.max_total_size = Some(bytes);
+        self
+    }
+
+    /// Never delete recordings that contain annotations (they can then exceed the maximum size)
+    pub fn keep_annotated(mut self, keep: bool) -> Self {
+        self.keep_annotated = keep;
+        self
+    }
+
+    /// Only delete recordings that were uploaded (they can then exceed the maximum size)
+    pub fn uploaded_only(mut self, uploaded_only: bool) -> Self {
+        self.uploaded_only = uploaded_only;
+        self
+    }
+}
+
+/// Recordings deleted (or that would be deleted in dry-run mode) by a retention policy
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RetentionReport {
+    /// Deleted recordings, oldest first
+    pub deleted: Vec<PathBuf>,
+    /// Number of bytes freed
+    pub freed_bytes: u64,
+    /// Number of recordings kept
+    pub kept: usize,
+    /// Total size of kept recordings in bytes
+    pub kept_bytes: u64,
+}
+
+#[derive(Debug)]
+struct Recording {
+    path: PathBuf,
+    size: u64,
+    modified: SystemTime,
+    protected: bool,
+}
+
+/// Apply a retention policy to the recordings (`.record` files) of a directory; subdirectories are left alone
+///
+/// * `directory` - Directory containing recordings.
+/// * `policy` - Which recordings to delete.
+/// * `dry_run` - Only report what would be deleted.
+pub fn apply_retention<P: AsRef<Path>>(
+    directory: P,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+) -> std::io::Result<RetentionReport> {
+    apply_retention_at(directory.as_ref(), policy, dry_run, SystemTime::now())
+}
+
+fn apply_retention_at(
+    directory: &Path,
+    policy: &RetentionPolicy,
+    dry_run: bool,
+    now: SystemTime,
+) -> std::io::Result<RetentionReport> {
+    let mut recordings = Vec::new();
+    for entry in std::fs::read_dir(directory)? {
+        let path = entry?.path();
+        if !path.is_file()
+            || path
+                .extension()
+                .is_none_or(|ext| ext != RECORDING_EXTENSION)
+        {
+            continue;
+        }
+        let metadata = std::fs::metadata(&path)?;
+        let protected = (policy.uploaded_only && !is_uploaded(&path))
+            || (policy.keep_annotated && !read_annotations(File::open(&path)?)?.is_empty());
+        recordings.push(Recording {
+            path,
+            size: metadata.len(),
+            modified: metadata.modified()?,
+            protected,
+        });
+    }
+    recordings.sort_by_key(|recording| recording.modified);
+
+    let mut total_size: u64 = recordings.iter().map(|recording| recording.size).sum();
+    let mut report = RetentionReport::default();
+    for recording in recordings {
+        let too_old = policy.max_age.is_some_and(|max_age| {
+            now.duration_since(recording.modified)
+                .is_ok_and(|age| age > max_age)
+        });
+        let too_big = policy.max_total_size.is_some_and(|max| total_size > max);
+        if recording.protected || !(too_old || too_big) {
+            report.kept += 1;
+            report.kept_bytes += recording.size;
+            continue;
+        }
+        if !dry_run {
+            std::fs::remove_file(&recording.path)?;
+            for extension in [UPLOADED_MARKER_EXTENSION, OVERVIEW_EXTENSION] {
+                let marker = sidecar(&recording.path, extension);
+                if marker.is_file() {
+                    std::fs::remove_file(marker)?;
+                }
+            }
+        }
+        total_size -= recording.size;
+        report.freed_bytes += recording.size;
+        report.deleted.push(recording.path);
+    }
+    Ok(report)
+}
+
+/// Whether a recording was uploaded (it has a `.uploaded` marker)
+pub fn is_uploaded<P: AsRef<Path>>(path: P) -> bool {
+    sidecar(path.as_ref(), UPLOADED_MARKER_EXTENSION).is_file()
+}
+
+/// Path of a file stored next to a recording, e.g. `night.record.uploaded`
+pub(crate) fn sidecar(path: &Path, extension: &str) -> PathBuf {
+    let mut name = path.as_os_str().to_owned();
+    name.push(".");
+    name.push(extension);
+    PathBuf::from(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::annotation::Annotation;
+
+    fn write(directory: &Path, name: &str, size: usize, age_hours: u64, now: SystemTime) {
+        let path = directory.join(name);
+        std::fs::write(&path, vec![b'A'; size]).unwrap();
+        File::options()
+            .write(true)
+            .open(&path)
+            .unwrap()
+            .set_modified(now - Duration::from_secs(age_hours * 3600))
+            .unwrap();
+    }
+
+    #[test]
+    fn deletes_old_and_oldest_recordings() {
+        let directory = std::env::temp_dir().join("makair-telemetry-retention");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let now = SystemTime::now();
+        write(&directory, "ancient.record", 100, 100, now);
+        write(&directory, "old.record", 100, 30, now);
+        write(&directory, "recent.record", 100, 20, now);
+        write(&directory, "latest.record", 100, 10, now);
+        write(&directory, "notes.txt", 1_000, 100, now);
+        let annotation = Annotation::new(0, "patient moved").to_frame();
+        std::fs::write(
+            directory.join("annotated.record"),
+            format!("{}\n", base64::encode(annotation)),
+        )
+        .unwrap();
+        File::options()
+            .write(true)
+            .open(directory.join("annotated.record"))
+            .unwrap()
+            .set_modified(now - Duration::from_secs(200 * 3600))
+            .unwrap();
+
+        let policy = RetentionPolicy::new()
+            .max_age(Duration::from_secs(48 * 3600))
+            .max_total_size(250)
+            .keep_annotated(true);
+        let report = apply_retention_at(&directory, &policy, true, now).unwrap();
+        let names: Vec<_> = report
+            .deleted
+            .iter()
+            .map(|path| path.file_name().unwrap().to_string_lossy().into_owned())
+            .collect();
+        assert_eq!(names, vec!["ancient.record", "old.record"]);
+        assert_eq!(report.kept, 3);
+        assert!(directory.join("ancient.record").exists());
+
+        apply_retention_at(&directory, &policy, false, now).unwrap();
+        assert!(!directory.join("ancient.record").exists());
+        assert!(directory.join("annotated.record").exists());
+        assert!(directory.join("notes.txt").exists());
+
+        // Recordings that were not uploaded are kept
+        std::fs::write(directory.join("latest.record.uploaded"), "").unwrap();
+        let policy = RetentionPolicy::new().max_total_size(0).uploaded_only(true);
+        let report = apply_retention_at(&directory, &policy, false, now).unwrap();
+        assert_eq!(report.deleted, vec![directory.join("latest.record")]);
+        assert!(!directory.join("latest.record.uploaded").exists());
+        assert!(directory.join("recent.record").exists());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn keeps_everything_it_cannot_judge() {
+        let directory = std::env::temp_dir().join("makair-telemetry-retention-edge");
+        let _ = std::fs::remove_dir_all(&directory);
+        assert_eq!(
+            apply_retention_at(&directory, &RetentionPolicy::new(), true, SystemTime::now())
+                .unwrap_err()
+                .kind(),
+            std::io::ErrorKind::NotFound
+        );
+
+        std::fs::create_dir_all(directory.join("nested.record")).unwrap();
+        let now = SystemTime::now();
+        write(&directory, "old.record", 100, 100, now);
+        // The clock went back since it was written
+        write(
+            &directory,
+            "future.record",
+            100,
+            0,
+            now + Duration::from_secs(3600),
+        );
+        std::fs::write(directory.join("old.record.overview"), "").unwrap();
+
+        let report = apply_retention_at(&directory, &RetentionPolicy::new(), false, now).unwrap();
+        assert!(report.deleted.is_empty());
+        assert_eq!(report.kept, 2);
+        assert_eq!(report.kept_bytes, 200);
+
+        let policy = RetentionPolicy::new().max_age(Duration::from_secs(3600));
+        let report = apply_retention_at(&directory, &policy, false, now).unwrap();
+        assert_eq!(report.deleted, vec![directory.join("old.record")]);
+        assert_eq!(report.freed_bytes, 100);
+        // Sidecars go with their recording, and directories are never deleted
+        assert!(!directory.join("old.record.overview").exists());
+        assert!(directory.join("future.record").exists());
+        assert!(directory.join("nested.record").is_dir());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
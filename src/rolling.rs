@@ -0,0 +1,390 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+
+use crate::adapter::MessageAdapter;
+use crate::structures::TelemetryMessage;
+
+/// Default smoothing factor of exponentially weighted moving averages
+pub const DEFAULT_EWMA_ALPHA: f64 = 0.1;
+
+/// Extent of a rolling window
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Window {
+    /// Values of the last N microseconds (based on systicks)
+    Duration(u64),
+    /// Values of the last N cycles, including the current one
+    Cycles(u32),
+    /// Last N values
+    Samples(usize),
+}
+
+/// Aggregates of the values currently in a window
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct RollingSummary {
+    /// Systick of the last value
+    pub systick: u64,
+    /// Number of values in the window
+    pub count: usize,
+    /// Mean of the values in the window
+    pub mean: f64,
+    /// Smallest value in the window
+    pub min: f64,
+    /// Greatest value in the window
+    pub max: f64,
+    /// Exponentially weighted moving average of every value so far (it does not depend on the window)
+    pub ewma: f64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    systick: u64,
+    cycle: u32,
+    value: f64,
+}
+
+/// Windowed aggregator of numeric values (mean, min, max and EWMA)
+#[derive(Debug, Clone)]
+pub struct RollingStats {
+    window: Window,
+    alpha: f64,
+    samples: VecDeque<Sample>,
+    sum: f64,
+    ewma: Option<f64>,
+}
+
+impl RollingStats {
+    /// Create an empty aggregator
+    pub fn new(window: Window) -> Self {
+        Self {
+            window,
+            alpha: DEFAULT_EWMA_ALPHA,
+            samples: VecDeque::new(),
+            sum: 0.0,
+            ewma: None,
+        }
+    }
+
+    /// Use another smoothing factor for the EWMA (between 0 and 1; the higher, the more reactive)
+    pub fn with_ewma_alpha(mut self, alpha: f64) -> Self {
+        self.alpha = alpha.clamp(0.0, 1.0);
+        self
+    }
+
+    /// Add a value and drop the ones that are now out of the window
+    ///
+    /// * `systick` - Systick of the message the value comes from.
+    /// * `cycle` - Cycle during which the value was measured (only used by `Window::Cycles`).
+    /// * `value` - The value.
+    pub fn push(&mut self, systick: u64, cycle: u32, value: f64) {
+        self.samples.push_back(Sample {
+            systick,
+            cycle,
+            value,
+        });
+        self.sum += value;
+        self.ewma = Some(match self.ewma {
+            Some(ewma) => ewma + self.alpha * (value - ewma),
+            None => value,
+        });
+
+        while let Some(oldest) = self.samples.front() {
+            let expired = match self.window {
+                Window::Duration(duration) => systick.saturating_sub(oldest.systick) >= duration,
+                Window::Cycles(cycles) => cycle.saturating_sub(oldest.cycle) >= cycles,
+                Window::Samples(samples) => self.samples.len() > samples,
+            };
+            if !expired {
+                break;
+            }
+            self.sum -= oldest.value;
+            self.samples.pop_front();
+        }
+    }
+
+    /// Forget every value
+    pub fn clear(&mut self) {
+        self.samples.clear();
+        self.sum = 0.0;
+        self.ewma = None;
+    }
+
+    /// Number of values in the window
+    pub fn count(&self) -> usize {
+        self.samples.len()
+    }
+
+    /// Mean of the values in the window
+    pub fn mean(&self) -> Option<f64> {
+        if self.samples.is_empty() {
+            None
+        } else {
+            Some(self.sum / self.samples.len() as f64)
+        }
+    }
+
+    /// Standard deviation of the values in the window (population standard deviation)
+    pub fn std_dev(&self) -> Option<f64> {
+        let mean = self.mean()?;
+        let variance = self
+            .samples
+            .iter()
+            .map(|sample| (sample.value - mean).powi(2))
+            .sum::<f64>()
+            / self.samples.len() as f64;
+        Some(variance.sqrt())
+    }
+
+    /// Smallest value in the window
+    pub fn min(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .map(|sample| sample.value)
+            .reduce(f64::min)
+    }
+
+    /// Greatest value in the window
+    pub fn max(&self) -> Option<f64> {
+        self.samples
+            .iter()
+            .map(|sample| sample.value)
+            .reduce(f64::max)
+    }
+
+    /// Exponentially weighted moving average of every value so far
+    pub fn ewma(&self) -> Option<f64> {
+        self.ewma
+    }
+
+    /// All aggregates at once, if there is at least one value in the window
+    pub fn summary(&self) -> Option<RollingSummary> {
+        Some(RollingSummary {
+            systick: self.samples.back()?.systick,
+            count: self.count(),
+            mean: self.mean()?,
+            min: self.min()?,
+            max: self.max()?,
+            ewma: self.ewma?,
+        })
+    }
+}
+
+/// Rolling statistics of a numeric field of telemetry messages
+///
+/// The field is picked by a selector (see the `selectors` module for common ones); messages for which it returns `None` are ignored.
+/// Cycles are followed thanks to machine state snapshots, so that `Window::Cycles` can be used with any field.
+pub struct RollingMetric<F> {
+    selector: F,
+    stats: RollingStats,
+    cycle: u32,
+}
+
+impl<F> RollingMetric<F>
+where
+    F: FnMut(&TelemetryMessage) -> Option<f64>,
+{
+    /// Create a rolling metric over a window
+    pub fn new(selector: F, window: Window) -> Self {
+        Self::with_stats(selector, RollingStats::new(window))
+    }
+
+    /// Create a rolling metric with a custom aggregator (e.g. to change its EWMA smoothing factor)
+    pub fn with_stats(selector: F, stats: RollingStats) -> Self {
+        Self {
+            selector,
+            stats,
+            cycle: 0,
+        }
+    }
+
+    /// Underlying aggregator
+    pub fn stats(&self) -> &RollingStats {
+        &self.stats
+    }
+}
+
+impl<F> MessageAdapter for RollingMetric<F>
+where
+    F: FnMut(&TelemetryMessage) -> Option<f64>,
+{
+    type Output = RollingSummary;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<RollingSummary> {
+        let value = (self.selector)(message);
+        match message {
+            // A machine state snapshot ends its cycle; following messages belong to the next one
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                if let Some(value) = value {
+                    self.stats.push(message.systick(), snapshot.cycle, value);
+                }
+                self.cycle = snapshot.cycle.saturating_add(1);
+            }
+            TelemetryMessage::AlarmTrap(trap) => {
+                self.cycle = trap.cycle;
+                if let Some(value) = value {
+                    self.stats.push(message.systick(), trap.cycle, value);
+                }
+            }
+            _ => {
+                if let Some(value) = value {
+                    self.stats.push(message.systick(), self.cycle, value);
+                }
+            }
+        }
+
+        match value {
+            Some(_) => self.stats.summary().into_iter().collect(),
+            None => Vec::new(),
+        }
+    }
+}
+
+/// Selectors of common numeric fields, to be used with `RollingMetric`
+pub mod selectors {
+    use crate::structures::TelemetryMessage;
+
+    /// Pressure of data snapshots, in mmH2O
+    pub fn pressure(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => Some(f64::from(snapshot.pressure)),
+            _ => None,
+        }
+    }
+
+    /// Net flow (inspiratory minus expiratory) of data snapshots, in cL/min (protocol v2 only)
+    pub fn flow(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                let inspiratory = f64::from(snapshot.inspiratory_flow?);
+                Some(inspiratory - f64::from(snapshot.expiratory_flow.unwrap_or(0)))
+            }
+            _ => None,
+        }
+    }
+
+    /// Blower speed of data snapshots, in RPM/100
+    pub fn blower_rpm(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => Some(f64::from(snapshot.blower_rpm)),
+            _ => None,
+        }
+    }
+
+    /// Peak pressure of the previous cycle, in mmH2O
+    pub fn peak_pressure(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                Some(f64::from(snapshot.previous_peak_pressure))
+            }
+            _ => None,
+        }
+    }
+
+    /// Plateau pressure of the previous cycle, in mmH2O
+    pub fn plateau_pressure(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                Some(f64::from(snapshot.previous_plateau_pressure))
+            }
+            _ => None,
+        }
+    }
+
+    /// PEEP of the previous cycle, in mmH2O
+    pub fn peep(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                Some(f64::from(snapshot.previous_peep_pressure))
+            }
+            _ => None,
+        }
+    }
+
+    /// Tidal volume of the previous cycle, in mL (protocol v2 only)
+    pub fn tidal_volume(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                snapshot.previous_volume.map(f64::from)
+            }
+            _ => None,
+        }
+    }
+
+    /// CPU load of the MCU, in percent (protocol v2 only)
+    pub fn cpu_load(message: &TelemetryMessage) -> Option<f64> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => snapshot.cpu_load.map(f64::from),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::MachineStateSnapshot;
+
+    #[test]
+    fn duration_window() {
+        let mut stats = RollingStats::new(Window::Duration(1_000));
+        stats.push(0, 0, 1.0);
+        stats.push(500, 0, 3.0);
+        assert_eq!(stats.mean(), Some(2.0));
+        stats.push(1_200, 0, 8.0);
+        assert_eq!(stats.count(), 2);
+        assert_eq!(stats.min(), Some(3.0));
+        assert_eq!(stats.max(), Some(8.0));
+        assert_eq!(stats.mean(), Some(5.5));
+        assert_eq!(stats.std_dev(), Some(2.5));
+    }
+
+    #[test]
+    fn samples_window_and_ewma() {
+        let mut stats = RollingStats::new(Window::Samples(2)).with_ewma_alpha(0.5);
+        assert_eq!(stats.summary(), None);
+        for (systick, value) in [(1, 4.0), (2, 0.0), (3, 2.0)] {
+            stats.push(systick, 0, value);
+        }
+        assert_eq!(
+            stats.summary(),
+            Some(RollingSummary {
+                systick: 3,
+                count: 2,
+                mean: 1.0,
+                min: 0.0,
+                max: 2.0,
+                ewma: 2.0,
+            })
+        );
+    }
+
+    #[test]
+    fn cycles_window_from_machine_states() {
+        let mss = |cycle: u32, peak: u16| {
+            TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+                cycle,
+                systick: u64::from(cycle),
+                previous_peak_pressure: peak,
+                ..Default::default()
+            })
+        };
+        let mut metric = RollingMetric::new(selectors::peak_pressure, Window::Cycles(2));
+        metric.handle(&mss(1, 100));
+        metric.handle(&mss(2, 200));
+        let summary = metric.handle(&mss(3, 300));
+
+        assert_eq!(summary.len(), 1);
+        assert_eq!(summary[0].count, 2);
+        assert_eq!(summary[0].mean, 250.0);
+        assert!(metric
+            .handle(&TelemetryMessage::StoppedMessage(Default::default()))
+            .is_empty());
+    }
+}
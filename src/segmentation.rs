@@ -0,0 +1,261 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::{File, OpenOptions};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+use crate::structures::TelemetryMessage;
+
+/// Default duration after which a stopped device is considered to be done with its patient
+pub const DEFAULT_STOP_TIMEOUT: Duration = Duration::from_secs(5 * 60);
+
+/// Why a new session started
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SessionBoundary {
+    /// The device stayed stopped for longer than the timeout
+    LongStop,
+    /// The device rebooted
+    Reboot,
+    /// Messages come from another device
+    DeviceChange,
+}
+
+impl std::fmt::Display for SessionBoundary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Self::LongStop => write!(f, "device stopped for a long time"),
+            Self::Reboot => write!(f, "device rebooted"),
+            Self::DeviceChange => write!(f, "device changed"),
+        }
+    }
+}
+
+/// Finds where a stream of telemetry messages goes from one patient session to the next
+///
+/// A session ends when the device stays stopped for longer than a timeout, when it reboots (boot message or systick going back), or when the device ID changes.
+/// Durations are measured with the systick of messages.
+#[derive(Debug, Clone)]
+pub struct SessionDetector {
+    stop_timeout: u64,
+    device_id: Option<String>,
+    last_systick: Option<u64>,
+    last_was_boot: bool,
+    stopped_since: Option<u64>,
+    idle: bool,
+}
+
+impl Default for SessionDetector {
+    fn default() -> Self {
+        Self::new(DEFAULT_STOP_TIMEOUT)
+    }
+}
+
+impl SessionDetector {
+    /// Create a detector ending sessions after the device is stopped for `stop_timeout`
+    pub fn new(stop_timeout: Duration) -> Self {
+        Self {
+            stop_timeout: stop_timeout.as_micros() as u64,
+            device_id: None,
+            last_systick: None,
+            last_was_boot: false,
+            stopped_since: None,
+            idle: false,
+        }
+    }
+
+    /// Handle a message, and tell whether it is the first one of a new session
+    ///
+    /// The first message ever handled starts the first session and is not a boundary.
+    /// A stop only ends one session: the device must ventilate again before another one can end.
+    pub fn handle(&mut self, message: &TelemetryMessage) -> Option<SessionBoundary> {
+        let systick = message.systick();
+        let device_id = message.device_id();
+        let is_boot = matches!(message, TelemetryMessage::BootMessage(_));
+
+        let boundary = match self.last_systick {
+            None => None,
+            Some(_)
+                if !device_id.is_empty()
+                    && self.device_id.as_ref().is_some_and(|id| *id != device_id) =>
+            {
+                Some(SessionBoundary::DeviceChange)
+            }
+            Some(last) if systick < last || (is_boot && !self.last_was_boot) => {
+                Some(SessionBoundary::Reboot)
+            }
+            Some(_)
+                if self
+                    .stopped_since
+                    .is_some_and(|since| systick.saturating_sub(since) > self.stop_timeout) =>
+            {
+                Some(SessionBoundary::LongStop)
+            }
+            Some(_) => None,
+        };
+
+        match boundary {
+            Some(SessionBoundary::LongStop) => {
+                self.stopped_since = None;
+                self.idle = true;
+            }
+            Some(_) => {
+                self.stopped_since = None;
+                self.idle = false;
+            }
+            None => (),
+        }
+        match message {
+            TelemetryMessage::StoppedMessage(_) if !self.idle => {
+                self.stopped_since.get_or_insert(systick);
+            }
+            TelemetryMessage::DataSnapshot(_) | TelemetryMessage::MachineStateSnapshot(_) => {
+                self.stopped_since = None;
+                self.idle = false;
+            }
+            _ => (),
+        }
+        if !device_id.is_empty() {
+            self.device_id = Some(device_id);
+        }
+        self.last_systick = Some(systick);
+        self.last_was_boot = is_boot;
+        boundary
+    }
+}
+
+/// Numbered files of a recording split by session: `<stem>-session-<number>.<extension>`, starting at 1
+#[derive(Debug, Clone)]
+pub struct SessionFiles {
+    path: PathBuf,
+    session: u32,
+}
+
+impl SessionFiles {
+    /// Name files after a path, e.g. `night.record` gives `night-session-1.record`, `night-session-2.record`…
+    pub fn new<P: AsRef<Path>>(path: P) -> Self {
+        Self {
+            path: path.as_ref().to_path_buf(),
+            session: 0,
+        }
+    }
+
+    /// Number of the last created file (0 before the first one)
+    pub fn session(&self) -> u32 {
+        self.session
+    }
+
+    /// Path of a session file
+    pub fn path(&self, session: u32) -> PathBuf {
+        let stem = self
+            .path
+            .file_stem()
+            .map(|stem| stem.to_string_lossy().into_owned())
+            .unwrap_or_default();
+        let name = match self.path.extension() {
+            Some(extension) => format!(
+                "{}-session-{}.{}",
+                stem,
+                session,
+                extension.to_string_lossy()
+            ),
+            None => format!("{}-session-{}", stem, session),
+        };
+        self.path.with_file_name(name)
+    }
+
+    /// Create the file of the next session; it fails if the file already exists
+    pub fn create_next(&mut self) -> std::io::Result<File> {
+        let file = OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(self.path(self.session + 1))?;
+        self.session += 1;
+        Ok(file)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::{DataSnapshot, Phase, StoppedMessage};
+
+    fn stopped(device_id: &str, systick: u64) -> TelemetryMessage {
+        TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 2,
+            device_id: device_id.to_owned(),
+            systick,
+            ..Default::default()
+        })
+    }
+
+    fn data(device_id: &str, systick: u64) -> TelemetryMessage {
+        TelemetryMessage::DataSnapshot(DataSnapshot {
+            telemetry_version: 2,
+            version: String::new(),
+            device_id: device_id.to_owned(),
+            systick,
+            centile: 0,
+            pressure: 0,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 0,
+            patient_valve_position: 0,
+            blower_rpm: 0,
+            battery_level: 0,
+            inspiratory_flow: None,
+            expiratory_flow: None,
+        })
+    }
+
+    #[test]
+    fn detects_boundaries() {
+        let minute = 60_000_000;
+        let mut detector = SessionDetector::new(Duration::from_secs(5 * 60));
+        let boundaries: Vec<Option<SessionBoundary>> = [
+            data("a", 0),
+            stopped("a", minute),
+            stopped("a", 3 * minute),
+            data("a", 4 * minute),
+            stopped("a", 5 * minute),
+            stopped("a", 11 * minute),
+            stopped("a", 30 * minute),
+            data("a", 31 * minute),
+            data("a", minute),
+            data("b", 2 * minute),
+        ]
+        .iter()
+        .map(|message| detector.handle(message))
+        .collect();
+        assert_eq!(
+            boundaries,
+            vec![
+                None,
+                None,
+                None,
+                None,
+                None,
+                Some(SessionBoundary::LongStop),
+                None,
+                None,
+                Some(SessionBoundary::Reboot),
+                Some(SessionBoundary::DeviceChange),
+            ]
+        );
+    }
+
+    #[test]
+    fn names_session_files() {
+        let files = SessionFiles::new("records/night.record");
+        assert_eq!(
+            files.path(2),
+            PathBuf::from("records/night-session-2.record")
+        );
+        assert_eq!(
+            SessionFiles::new("night").path(1),
+            PathBuf::from("night-session-1")
+        );
+    }
+}
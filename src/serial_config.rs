@@ -0,0 +1,193 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::time::Duration;
+
+use serial::SerialPort;
+
+use crate::decoder::DecodeConfig;
+use crate::rate_limit::RateLimit;
+
+/// Default time to wait for a byte before giving the hand back (e.g. to send control messages)
+pub const DEFAULT_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// Default time to wait before opening the port again after an error
+pub const DEFAULT_RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// How to open and watch a serial port
+///
+/// USB-to-serial bridges behave differently on connect: some reset the MCU when DTR or RTS change, others need them toggled to start forwarding bytes.
+/// By default, modem control lines are left as the driver sets them.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SerialConfig {
+    /// Time to wait for a byte before giving the hand back
+    pub timeout: Duration,
+    /// Time to wait before opening the port again after an error
+    pub reconnect_delay: Duration,
+    /// Level to set the DTR line to once the port is open
+    pub dtr: Option<bool>,
+    /// Level to set the RTS line to once the port is open
+    pub rts: Option<bool>,
+    /// If set, the lines configured above are first driven to the opposite level during this time (to reset bridges that need it)
+    pub toggle_duration: Option<Duration>,
+    /// If set, a break condition is reported after this number of consecutive NUL bytes
+    pub break_threshold: Option<usize>,
+    /// If set, control frames are sent no faster than this limit (the others wait in a queue)
+    pub control_rate_limit: Option<RateLimit>,
+    /// How frames read from the port are decoded
+    pub decode: DecodeConfig,
+}
+
+impl Default for SerialConfig {
+    fn default() -> Self {
+        Self {
+            timeout: DEFAULT_TIMEOUT,
+            reconnect_delay: DEFAULT_RECONNECT_DELAY,
+            dtr: None,
+            rts: None,
+            toggle_duration: None,
+            break_threshold: None,
+            control_rate_limit: Some(RateLimit::default()),
+            decode: DecodeConfig::default(),
+        }
+    }
+}
+
+impl SerialConfig {
+    /// Create the default configuration
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Wait at most this time for a byte before giving the hand back
+    pub fn timeout(mut self, timeout: Duration) -> Self {
+        self.timeout = timeout;
+        self
+    }
+
+    /// Wait this time before opening the port again after an error
+    pub fn reconnect_delay(mut self, delay: Duration) -> Self {
+        self.reconnect_delay = delay;
+        self
+    }
+
+    /// Set the DTR line to this level once the port is open
+    pub fn dtr(mut self, level: bool) -> Self {
+        self.dtr = Some(level);
+        self
+    }
+
+    /// Set the RTS line to this level once the port is open
+    pub fn rts(mut self, level: bool) -> Self {
+        self.rts = Some(level);
+        self
+    }
+
+    /// Drive the configured lines to the opposite level during this time before setting them
+    pub fn toggle_on_open(mut self, duration: Duration) -> Self {
+        self.toggle_duration = Some(duration);
+        self
+    }
+
+    /// Report a break condition after this number of consecutive NUL bytes
+    pub fn detect_break(mut self, threshold: usize) -> Self {
+        self.break_threshold = Some(threshold);
+        self
+    }
+
+    /// Send control frames no faster than this limit, or as soon as they are received with `None`
+    pub fn control_rate_limit(mut self, limit: Option<RateLimit>) -> Self {
+        self.control_rate_limit = limit;
+        self
+    }
+
+    /// Decode frames read from the port with this configuration
+    pub fn decode(mut self, decode: DecodeConfig) -> Self {
+        self.decode = decode;
+        self
+    }
+
+    /// Apply timeout and modem control lines to a port that was just opened
+    pub fn apply<P: SerialPort + ?Sized>(&self, port: &mut P) -> serial::Result<()> {
+        port.set_timeout(self.timeout)?;
+
+        if let Some(duration) = self.toggle_duration {
+            if let Some(level) = self.dtr {
+                port.set_dtr(!level)?;
+            }
+            if let Some(level) = self.rts {
+                port.set_rts(!level)?;
+            }
+            std::thread::sleep(duration);
+        }
+        if let Some(level) = self.dtr {
+            port.set_dtr(level)?;
+        }
+        if let Some(level) = self.rts {
+            port.set_rts(level)?;
+        }
+        Ok(())
+    }
+
+    /// Create a break detector matching this configuration, if break detection is enabled
+    pub fn break_detector(&self) -> Option<BreakDetector> {
+        self.break_threshold.map(BreakDetector::new)
+    }
+}
+
+/// Detects break conditions in a stream of bytes
+///
+/// UART drivers report a break (the line held low, e.g. when the MCU is powered off or the cable is cut) as NUL bytes with framing errors.
+/// Framing errors are not exposed by the serial library, so a break is assumed when too many consecutive NUL bytes are received; telemetry frames never contain that many.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BreakDetector {
+    threshold: usize,
+    zeros: usize,
+}
+
+impl BreakDetector {
+    /// Create a detector reporting breaks after `threshold` consecutive NUL bytes
+    pub fn new(threshold: usize) -> Self {
+        Self {
+            threshold: threshold.max(1),
+            zeros: 0,
+        }
+    }
+
+    /// Handle a received byte and tell whether a break condition just started
+    ///
+    /// A break is only reported once; the detector is armed again when a non-NUL byte is received.
+    pub fn push(&mut self, byte: u8) -> bool {
+        if byte == 0 {
+            self.zeros = self.zeros.saturating_add(1);
+            self.zeros == self.threshold
+        } else {
+            self.zeros = 0;
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reports_breaks_once() {
+        let mut detector = SerialConfig::new()
+            .detect_break(3)
+            .break_detector()
+            .unwrap();
+        let reports: Vec<bool> = [0, 0, 1, 0, 0, 0, 0, 0, 2, 0, 0, 0]
+            .iter()
+            .map(|byte| detector.push(*byte))
+            .collect();
+        assert_eq!(
+            reports,
+            vec![false, false, false, false, false, true, false, false, false, false, false, true]
+        );
+        assert_eq!(SerialConfig::default().break_detector(), None);
+    }
+}
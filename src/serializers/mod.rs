@@ -3,13 +3,14 @@
 // Copyright: 2020, Makers For Life
 // License: Public Domain License
 
+#[cfg(feature = "log")]
 use log::warn;
 
 use crate::structures::*;
 
 /// Serialize to binary using the telemetry protocol
 pub trait ToBytes {
-    /// Serialize to binary using the latest telemetry protocol
+    /// Serialize to binary using the telemetry protocol of released firmware (v2)
     fn to_bytes(&self) -> Vec<u8> {
         self.to_bytes_v2()
     }
@@ -19,12 +20,23 @@ pub trait ToBytes {
 
     /// Serialize to binary using the telemetry protocol v2
     fn to_bytes_v2(&self) -> Vec<u8>;
+
+    /// Serialize to binary using the telemetry protocol v3
+    fn to_bytes_v3(&self) -> Vec<u8>;
 }
 
 fn flat(v: &[&[u8]]) -> Vec<u8> {
     v.iter().flat_map(|a| a.iter()).copied().collect()
 }
 
+/// Messages of protocol v3 have the same layouts as in protocol v2; only their version byte differs
+fn v2_payload_as_v3(mut payload: Vec<u8>) -> Vec<u8> {
+    if let Some(version) = payload.get_mut(2) {
+        *version = 3;
+    }
+    payload
+}
+
 fn split_device_id(device_id: &str) -> (u32, u32, u32) {
     use std::str::FromStr;
 
@@ -115,6 +127,31 @@ impl ToBytes for BootMessage {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        let (device_id1, device_id2, device_id3) = split_device_id(&self.device_id);
+
+        flat(&[
+            b"B:",
+            &[3],
+            &[self.version.len() as u8],
+            self.version.as_bytes(),
+            &device_id1.to_be_bytes(),
+            &device_id2.to_be_bytes(),
+            &device_id3.to_be_bytes(),
+            b"\t",
+            &self.systick.to_be_bytes(),
+            b"\t",
+            &[self.mode as u8],
+            b"\t",
+            &[self.value128],
+            b"\t",
+            &self.control_capabilities.unwrap_or_default().to_be_bytes(),
+            b"\t",
+            &self.session_nonce.unwrap_or_default().to_be_bytes(),
+            b"\n",
+        ])
+    }
 }
 
 impl ToBytes for StoppedMessage {
@@ -175,7 +212,7 @@ impl ToBytes for StoppedMessage {
             b"\t",
             &self.cpu_load.unwrap_or_default().to_be_bytes(),
             b"\t",
-            &[self.ventilation_mode as u8],
+            &[u8::from(&self.ventilation_mode)],
             b"\t",
             &self
                 .inspiratory_trigger_flow
@@ -265,6 +302,10 @@ impl ToBytes for StoppedMessage {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for DataSnapshot {
@@ -333,6 +374,10 @@ impl ToBytes for DataSnapshot {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for MachineStateSnapshot {
@@ -439,7 +484,7 @@ impl ToBytes for MachineStateSnapshot {
             b"\t",
             &self.cpu_load.unwrap_or_default().to_be_bytes(),
             b"\t",
-            &[self.ventilation_mode as u8],
+            &[u8::from(&self.ventilation_mode)],
             b"\t",
             &self
                 .inspiratory_trigger_flow
@@ -531,6 +576,10 @@ impl ToBytes for MachineStateSnapshot {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for AlarmTrap {
@@ -607,6 +656,10 @@ impl ToBytes for AlarmTrap {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for ControlAck {
@@ -651,10 +704,15 @@ impl ToBytes for ControlAck {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for FatalError {
     fn to_bytes_v1(&self) -> Vec<u8> {
+        #[cfg(feature = "log")]
         warn!(
             "trying to serialize a FatalError message that did not exist in telemetry protocol v1"
         );
@@ -709,10 +767,15 @@ impl ToBytes for FatalError {
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
 impl ToBytes for EolTestSnapshot {
     fn to_bytes_v1(&self) -> Vec<u8> {
+        #[cfg(feature = "log")]
         warn!("trying to serialize a EolTestSnapshot message that did not exist in telemetry protocol v1");
         vec![]
     }
@@ -743,24 +806,47 @@ impl ToBytes for EolTestSnapshot {
             b"\t",
             &self.systick.to_be_bytes(),
             b"\t",
-            &[self.current_step as u8],
+            &[u8::from(&self.current_step)],
             b"\t",
             &eol_test_snapshot_content,
             b"\n",
         ])
     }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        v2_payload_as_v3(self.to_bytes_v2())
+    }
 }
 
-/// Wrap a binary payload into a CRC-aware binary frame
+/// Wrap a binary payload into a CRC-aware binary frame (see `framing::encode()`)
 pub fn mk_frame(payload: &[u8]) -> Vec<u8> {
-    let mut crc = crc32fast::Hasher::new();
-    crc.update(payload);
+    crate::framing::encode(payload)
+}
+
+/// Messages of unknown types are serialized back with their header followed by their raw payload
+fn unknown_message_payload(
+    telemetry_version: u8,
+    version: &str,
+    device_id: &str,
+    systick: u64,
+    type_byte: u8,
+    payload: &[u8],
+) -> Vec<u8> {
+    let (device_id1, device_id2, device_id3) = split_device_id(device_id);
 
     flat(&[
-        b"\x03\x0C",
+        &[type_byte],
+        b":",
+        &[telemetry_version],
+        &[version.len() as u8],
+        version.as_bytes(),
+        &device_id1.to_be_bytes(),
+        &device_id2.to_be_bytes(),
+        &device_id3.to_be_bytes(),
+        b"\t",
+        &systick.to_be_bytes(),
+        b"\t",
         payload,
-        &crc.finalize().to_be_bytes(),
-        b"\x30\xC0",
     ])
 }
 
@@ -775,6 +861,21 @@ impl ToBytes for TelemetryMessage {
             Self::ControlAck(m) => m.to_bytes_v1(),
             Self::FatalError(m) => m.to_bytes_v1(),
             Self::EolTestSnapshot(m) => m.to_bytes_v1(),
+            Self::Unknown {
+                telemetry_version,
+                version,
+                device_id,
+                systick,
+                type_byte,
+                payload,
+            } => unknown_message_payload(
+                *telemetry_version,
+                version,
+                device_id,
+                *systick,
+                *type_byte,
+                payload,
+            ),
         };
         mk_frame(&payload)
     }
@@ -789,11 +890,72 @@ impl ToBytes for TelemetryMessage {
             Self::ControlAck(m) => m.to_bytes_v2(),
             Self::FatalError(m) => m.to_bytes_v2(),
             Self::EolTestSnapshot(m) => m.to_bytes_v2(),
+            Self::Unknown {
+                telemetry_version,
+                version,
+                device_id,
+                systick,
+                type_byte,
+                payload,
+            } => unknown_message_payload(
+                *telemetry_version,
+                version,
+                device_id,
+                *systick,
+                *type_byte,
+                payload,
+            ),
+        };
+        mk_frame(&payload)
+    }
+
+    fn to_bytes_v3(&self) -> Vec<u8> {
+        let payload = match self {
+            Self::BootMessage(m) => m.to_bytes_v3(),
+            Self::StoppedMessage(m) => m.to_bytes_v3(),
+            Self::DataSnapshot(m) => m.to_bytes_v3(),
+            Self::MachineStateSnapshot(m) => m.to_bytes_v3(),
+            Self::AlarmTrap(m) => m.to_bytes_v3(),
+            Self::ControlAck(m) => m.to_bytes_v3(),
+            Self::FatalError(m) => m.to_bytes_v3(),
+            Self::EolTestSnapshot(m) => m.to_bytes_v3(),
+            Self::Unknown {
+                telemetry_version,
+                version,
+                device_id,
+                systick,
+                type_byte,
+                payload,
+            } => unknown_message_payload(
+                *telemetry_version,
+                version,
+                device_id,
+                *systick,
+                *type_byte,
+                payload,
+            ),
         };
         mk_frame(&payload)
     }
 }
 
+impl TelemetryMessage {
+    /// Serialize to binary using the given version of the telemetry protocol, e.g. `self.telemetry_version()` to keep the version a message was received with
+    ///
+    /// Like the parsers, this fails with `HighLevelError::UnsupportedProtocolVersion` for versions that do not exist (0, or newer than the latest one known by this library).
+    pub fn to_bytes_for_version(&self, version: u8) -> Result<Vec<u8>, HighLevelError> {
+        match version {
+            1 => Ok(self.to_bytes_v1()),
+            2 => Ok(self.to_bytes_v2()),
+            3 => Ok(self.to_bytes_v3()),
+            _ => Err(HighLevelError::UnsupportedProtocolVersion {
+                maximum_supported: crate::parsers::MAXIMUM_SUPPORTED_VERSION,
+                found: version,
+            }),
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
@@ -807,4 +969,25 @@ mod tests {
     fn split_invalid_device_id() {
         assert_eq!(split_device_id("123-456789"), (123, 456789, 0))
     }
+
+    #[test]
+    fn serialize_for_supported_versions_only() {
+        let message = TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 3,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            ..StoppedMessage::default()
+        });
+        assert_eq!(message.to_bytes_for_version(2), Ok(message.to_bytes_v2()));
+        assert_eq!(message.to_bytes_for_version(3), Ok(message.to_bytes_v3()));
+        for version in [0, 4] {
+            assert_eq!(
+                message.to_bytes_for_version(version),
+                Err(HighLevelError::UnsupportedProtocolVersion {
+                    maximum_supported: 3,
+                    found: version
+                })
+            );
+        }
+    }
 }
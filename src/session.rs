@@ -0,0 +1,402 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::control::{ControlMessage, ControlSetting};
+use crate::structures::ControlAck;
+
+/// Maximum number of control messages waiting for their ACK
+const MAX_PENDING_MESSAGES: usize = 64;
+
+/// First heartbeat value reserved to open a batch (the firmware ignores heartbeat values, but acknowledges them)
+const BATCH_START_MARKER: u16 = 128;
+/// First heartbeat value reserved to close a batch
+const BATCH_END_MARKER: u16 = 192;
+/// Number of correlation IDs that fit in the reserved heartbeat values; IDs wrap around
+const BATCH_IDS: u8 = 64;
+
+/// Identifier of a batch of settings sent with `ControlSession::batch()`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct CorrelationId(pub u8);
+
+impl CorrelationId {
+    fn from_marker(value: u16) -> Option<(Self, bool)> {
+        let id = |base: u16| Self(u8::try_from(value - base).unwrap_or_default());
+        match value {
+            _ if (BATCH_START_MARKER..BATCH_END_MARKER).contains(&value) => {
+                Some((id(BATCH_START_MARKER), true))
+            }
+            _ if (BATCH_END_MARKER..BATCH_END_MARKER + u16::from(BATCH_IDS)).contains(&value) => {
+                Some((id(BATCH_END_MARKER), false))
+            }
+            _ => None,
+        }
+    }
+
+    fn marker(self, start: bool) -> ControlMessage {
+        let base = if start {
+            BATCH_START_MARKER
+        } else {
+            BATCH_END_MARKER
+        };
+        ControlMessage {
+            setting: ControlSetting::Heartbeat,
+            value: base + u16::from(self.0),
+        }
+    }
+}
+
+/// ACKs received for a batch that is not closed yet
+#[derive(Debug)]
+struct OpenBatch {
+    id: CorrelationId,
+    events: Vec<ControlEvent>,
+}
+
+/// Outcome of a control message that was sent to the MCU
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum ControlEvent {
+    /// The MCU applied the value that was sent
+    Acknowledged {
+        /// Setting that was changed
+        setting: ControlSetting,
+        /// Value that was sent and applied
+        value: u16,
+    },
+    /// The MCU applied a different value than the one that was sent (out of bounds, rounded, etc.)
+    ///
+    /// `ControlSetting::clamp()` can be used to predict the applied value before sending it.
+    ValueClampedByFirmware {
+        /// Setting that was changed
+        setting: ControlSetting,
+        /// Value that was sent
+        sent: u16,
+        /// Value that the MCU actually applied
+        applied: u16,
+    },
+    /// The setting was changed by someone else (e.g. with the buttons of the device, or by another controller)
+    ExternalSettingChange {
+        /// Setting that was changed
+        setting: ControlSetting,
+        /// Value that the MCU applied
+        value: u16,
+        /// Value of the setting according to the previous ACK, if any
+        previous: Option<u16>,
+    },
+    /// The MCU acknowledged again the current value of a setting, while no message was pending
+    DuplicateAck {
+        /// Setting that was acknowledged
+        setting: ControlSetting,
+        /// Current value of the setting
+        value: u16,
+    },
+    /// The MCU acknowledged the end of a batch sent with `ControlSession::batch()`
+    ///
+    /// The events of the batch were also returned one by one when their ACK was handled.
+    BatchCompleted {
+        /// Identifier returned by `ControlSession::batch()`
+        id: CorrelationId,
+        /// `Acknowledged` and `ValueClampedByFirmware` events of the settings of the batch
+        events: Vec<ControlEvent>,
+        /// Settings of the batch that were not acknowledged (e.g. the control frame was corrupted)
+        unacknowledged: Vec<ControlSetting>,
+    },
+}
+
+/// Helper to check that control messages sent to the MCU were applied as expected
+///
+/// Call `sent()` for every control message sent to the MCU, then pass every `ControlAck` to `handle_ack()`.
+/// ACKs are matched with the oldest pending message of the same setting; the others reveal changes made outside of this session.
+///
+/// Multi-setting updates can be correlated to their ACK burst with `batch()`, which brackets them with heartbeats of reserved values.
+/// This works with the existing firmware, as long as it acknowledges control messages in the order they are received.
+#[derive(Debug, Default)]
+pub struct ControlSession {
+    pending: Vec<ControlMessage>,
+    acknowledged_values: HashMap<ControlSetting, u16>,
+    next_batch_id: u8,
+    batches: VecDeque<(CorrelationId, Vec<ControlSetting>)>,
+    open_batch: Option<OpenBatch>,
+}
+
+impl ControlSession {
+    /// Create a session without any pending message
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Register a control message that was sent to the MCU
+    ///
+    /// Heartbeats are ignored as their value is meaningless.
+    pub fn sent(&mut self, message: &ControlMessage) {
+        if message.setting == ControlSetting::Heartbeat {
+            return;
+        }
+        if self.pending.len() >= MAX_PENDING_MESSAGES {
+            self.pending.remove(0);
+        }
+        self.pending.push(message.clone());
+    }
+
+    /// Prepare a batch of settings to be correlated with their ACKs, and register them as sent
+    ///
+    /// Returns the identifier of the batch, and the messages to send in order (the settings, bracketed by two heartbeats).
+    /// A `ControlEvent::BatchCompleted` is returned by `handle_ack()` once the MCU acknowledged the end of the batch.
+    pub fn batch(&mut self, messages: &[ControlMessage]) -> (CorrelationId, Vec<ControlMessage>) {
+        let id = CorrelationId(self.next_batch_id);
+        self.next_batch_id = (self.next_batch_id + 1) % BATCH_IDS;
+        if self.batches.len() >= usize::from(BATCH_IDS) {
+            self.batches.pop_front();
+        }
+        self.batches.retain(|(batch_id, _)| *batch_id != id);
+        self.batches
+            .push_back((id, messages.iter().map(|m| m.setting).collect()));
+
+        let mut bracketed = Vec::with_capacity(messages.len() + 2);
+        bracketed.push(id.marker(true));
+        for message in messages {
+            self.sent(message);
+            bracketed.push(message.clone());
+        }
+        bracketed.push(id.marker(false));
+        (id, bracketed)
+    }
+
+    /// Handle the ACK of a heartbeat, which may open or close a batch
+    fn handle_marker(&mut self, value: u16) -> Option<ControlEvent> {
+        let (id, start) = CorrelationId::from_marker(value)?;
+        if start {
+            self.open_batch = Some(OpenBatch {
+                id,
+                events: Vec::new(),
+            });
+            return None;
+        }
+
+        let index = self
+            .batches
+            .iter()
+            .position(|(batch_id, _)| *batch_id == id)?;
+        let (_, settings) = self.batches.remove(index)?;
+        let events = match self.open_batch.take() {
+            Some(open_batch) if open_batch.id == id => open_batch.events,
+            // The start of the batch was not acknowledged
+            _ => Vec::new(),
+        };
+        let mut unacknowledged = settings;
+        for event in &events {
+            let setting = match event {
+                ControlEvent::Acknowledged { setting, .. }
+                | ControlEvent::ValueClampedByFirmware { setting, .. } => setting,
+                _ => continue,
+            };
+            if let Some(index) = unacknowledged.iter().position(|s| s == setting) {
+                unacknowledged.remove(index);
+            }
+        }
+        Some(ControlEvent::BatchCompleted {
+            id,
+            events,
+            unacknowledged,
+        })
+    }
+
+    /// Handle a `ControlAck` received from the MCU
+    ///
+    /// Returns `None` for heartbeats and time synchronization messages, whose values are meaningless or handled elsewhere, except for the end of a batch.
+    pub fn handle_ack(&mut self, ack: &ControlAck) -> Option<ControlEvent> {
+        match ack.setting {
+            ControlSetting::Heartbeat => return self.handle_marker(ack.value),
+            ControlSetting::TimeSync
+            | ControlSetting::WallClockHigh
+            | ControlSetting::WallClockLow => return None,
+            _ => (),
+        }
+        let event = self.handle_setting_ack(ack)?;
+        if let Some(open_batch) = self.open_batch.as_mut() {
+            if matches!(
+                event,
+                ControlEvent::Acknowledged { .. } | ControlEvent::ValueClampedByFirmware { .. }
+            ) {
+                open_batch.events.push(event.clone());
+            }
+        }
+        Some(event)
+    }
+
+    fn handle_setting_ack(&mut self, ack: &ControlAck) -> Option<ControlEvent> {
+        let previous = self.acknowledged_values.insert(ack.setting, ack.value);
+
+        let index = match self
+            .pending
+            .iter()
+            .position(|message| message.setting == ack.setting)
+        {
+            Some(index) => index,
+            None if previous == Some(ack.value) => {
+                return Some(ControlEvent::DuplicateAck {
+                    setting: ack.setting,
+                    value: ack.value,
+                })
+            }
+            None => {
+                return Some(ControlEvent::ExternalSettingChange {
+                    setting: ack.setting,
+                    value: ack.value,
+                    previous,
+                })
+            }
+        };
+        let message = self.pending.remove(index);
+
+        if message.value == ack.value {
+            Some(ControlEvent::Acknowledged {
+                setting: message.setting,
+                value: message.value,
+            })
+        } else {
+            Some(ControlEvent::ValueClampedByFirmware {
+                setting: message.setting,
+                sent: message.value,
+                applied: ack.value,
+            })
+        }
+    }
+
+    /// Control messages that were sent but not acknowledged yet
+    pub fn pending(&self) -> &[ControlMessage] {
+        &self.pending
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(setting: ControlSetting, value: u16) -> ControlAck {
+        ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "0-0-0".to_owned(),
+            systick: 0,
+            setting,
+            value,
+        }
+    }
+
+    #[test]
+    fn acknowledged_and_clamped_values() {
+        let mut session = ControlSession::new();
+        session.sent(&ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        });
+        session.sent(&ControlMessage {
+            setting: ControlSetting::CyclesPerMinute,
+            value: 50,
+        });
+
+        assert_eq!(
+            session.handle_ack(&ack(ControlSetting::CyclesPerMinute, 35)),
+            Some(ControlEvent::ValueClampedByFirmware {
+                setting: ControlSetting::CyclesPerMinute,
+                sent: 50,
+                applied: 35,
+            })
+        );
+        assert_eq!(
+            session.handle_ack(&ack(ControlSetting::PEEP, 80)),
+            Some(ControlEvent::Acknowledged {
+                setting: ControlSetting::PEEP,
+                value: 80,
+            })
+        );
+        assert!(session.pending().is_empty());
+    }
+
+    #[test]
+    fn ignores_heartbeats() {
+        let mut session = ControlSession::new();
+        session.sent(&ControlMessage {
+            setting: ControlSetting::Heartbeat,
+            value: 0,
+        });
+
+        assert!(session.pending().is_empty());
+        assert_eq!(session.handle_ack(&ack(ControlSetting::Heartbeat, 0)), None);
+    }
+
+    #[test]
+    fn correlates_batches_with_their_acks() {
+        let mut session = ControlSession::new();
+        let (id, messages) = session.batch(&[
+            ControlMessage {
+                setting: ControlSetting::PEEP,
+                value: 80,
+            },
+            ControlMessage {
+                setting: ControlSetting::CyclesPerMinute,
+                value: 20,
+            },
+        ]);
+        assert_eq!(messages.len(), 4);
+        assert_eq!(messages[0].setting, ControlSetting::Heartbeat);
+        assert_eq!(session.pending().len(), 2);
+
+        // The firmware echoes every message, and only the PEEP was received
+        let acks: Vec<ControlAck> = messages
+            .iter()
+            .filter(|m| m.setting != ControlSetting::CyclesPerMinute)
+            .map(|m| ack(m.setting, m.value))
+            .collect();
+        assert_eq!(session.handle_ack(&acks[0]), None);
+        assert!(session.handle_ack(&acks[1]).is_some());
+        assert_eq!(
+            session.handle_ack(&acks[2]),
+            Some(ControlEvent::BatchCompleted {
+                id,
+                events: vec![ControlEvent::Acknowledged {
+                    setting: ControlSetting::PEEP,
+                    value: 80,
+                }],
+                unacknowledged: vec![ControlSetting::CyclesPerMinute],
+            })
+        );
+
+        // Regular heartbeats are still ignored
+        assert_eq!(session.handle_ack(&ack(ControlSetting::Heartbeat, 0)), None);
+        assert_ne!(session.batch(&[]).0, id);
+    }
+
+    #[test]
+    fn detects_external_changes_and_duplicate_acks() {
+        let mut session = ControlSession::new();
+
+        assert_eq!(
+            session.handle_ack(&ack(ControlSetting::PEEP, 80)),
+            Some(ControlEvent::ExternalSettingChange {
+                setting: ControlSetting::PEEP,
+                value: 80,
+                previous: None,
+            })
+        );
+        assert_eq!(
+            session.handle_ack(&ack(ControlSetting::PEEP, 80)),
+            Some(ControlEvent::DuplicateAck {
+                setting: ControlSetting::PEEP,
+                value: 80,
+            })
+        );
+        assert_eq!(
+            session.handle_ack(&ack(ControlSetting::PEEP, 100)),
+            Some(ControlEvent::ExternalSettingChange {
+                setting: ControlSetting::PEEP,
+                value: 100,
+                previous: Some(80),
+            })
+        );
+    }
+}
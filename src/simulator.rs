@@ -0,0 +1,516 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::VecDeque;
+use std::str::FromStr;
+use std::sync::mpsc::Sender;
+use std::time::{Duration, Instant};
+
+use crate::control::ControlSetting;
+use crate::source::{SourceInfo, SourceKind};
+use crate::structures::{
+    BootMessage, DataSnapshot, MachineStateSnapshot, Mode, Phase, TelemetryMessage, VentilationMode,
+};
+use crate::TimedMessage;
+
+/// Interval between two data snapshots, like the control loop of the firmware
+pub const DATA_SNAPSHOT_PERIOD: Duration = Duration::from_millis(10);
+
+/// Firmware version reported by simulated devices
+pub const SIMULATOR_VERSION: &str = "simulator";
+
+/// Spontaneous breathing of a patient, as a pressure generated by respiratory muscles
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BreathingEffort {
+    /// Highest muscular pressure in cmH2O
+    pub amplitude: f64,
+    /// Number of spontaneous breaths per minute
+    pub rate: f64,
+}
+
+/// Single-compartment model of the respiratory system of a patient
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct PatientModel {
+    /// Compliance in mL/cmH2O
+    pub compliance: f64,
+    /// Airway resistance in cmH2O/(L/s)
+    pub resistance: f64,
+    /// Spontaneous breathing, if the patient is not sedated
+    pub effort: Option<BreathingEffort>,
+}
+
+/// Patient models with clinically distinct waveforms, for demos and training sessions
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PatientPreset {
+    /// Sedated adult with healthy lungs
+    Healthy,
+    /// Acute respiratory distress syndrome: stiff lungs (low compliance), small tidal volumes
+    Ards,
+    /// Chronic obstructive pulmonary disease: high resistance, slow and incomplete exhalation, spontaneous efforts
+    Copd,
+    /// Child around 20 kg: low compliance, high resistance and fast spontaneous breathing
+    Pediatric,
+}
+
+impl PatientPreset {
+    /// Name of the preset, as parsed by `from_str()`
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Healthy => "healthy",
+            Self::Ards => "ards",
+            Self::Copd => "copd",
+            Self::Pediatric => "pediatric",
+        }
+    }
+
+    /// Model of the patient
+    pub fn model(&self) -> PatientModel {
+        match self {
+            Self::Healthy => PatientModel {
+                compliance: 50.0,
+                resistance: 5.0,
+                effort: None,
+            },
+            Self::Ards => PatientModel {
+                compliance: 20.0,
+                resistance: 10.0,
+                effort: None,
+            },
+            Self::Copd => PatientModel {
+                compliance: 60.0,
+                resistance: 25.0,
+                effort: Some(BreathingEffort {
+                    amplitude: 3.0,
+                    rate: 14.0,
+                }),
+            },
+            Self::Pediatric => PatientModel {
+                compliance: 15.0,
+                resistance: 30.0,
+                effort: Some(BreathingEffort {
+                    amplitude: 2.0,
+                    rate: 28.0,
+                }),
+            },
+        }
+    }
+
+    /// Ventilator settings a clinician would typically use for this patient
+    pub fn settings(&self) -> SimulatorSettings {
+        match self {
+            Self::Healthy => SimulatorSettings::default(),
+            Self::Ards => SimulatorSettings {
+                plateau_pressure: 280,
+                peep: 120,
+                cycles_per_minute: 26,
+                expiratory_term: 15,
+            },
+            Self::Copd => SimulatorSettings {
+                plateau_pressure: 220,
+                peep: 50,
+                cycles_per_minute: 12,
+                expiratory_term: 40,
+            },
+            Self::Pediatric => SimulatorSettings {
+                plateau_pressure: 180,
+                peep: 50,
+                cycles_per_minute: 30,
+                expiratory_term: 20,
+            },
+        }
+    }
+}
+
+impl FromStr for PatientPreset {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "healthy" => Ok(Self::Healthy),
+            "ards" => Ok(Self::Ards),
+            "copd" => Ok(Self::Copd),
+            "pediatric" => Ok(Self::Pediatric),
+            _ => Err("Supported patient presets are: healthy, ards, copd, pediatric"),
+        }
+    }
+}
+
+/// Settings of a simulated device, which ventilates in PC-CMV
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct SimulatorSettings {
+    /// Plateau pressure in mmH2O
+    pub plateau_pressure: u16,
+    /// PEEP in mmH2O
+    pub peep: u16,
+    /// Number of cycles per minute
+    pub cycles_per_minute: u8,
+    /// Expiration term in the "Inspiration/Expiration" ratio given that Inspiration = 10
+    pub expiratory_term: u8,
+}
+
+impl Default for SimulatorSettings {
+    fn default() -> Self {
+        Self {
+            plateau_pressure: 200,
+            peep: 50,
+            cycles_per_minute: 20,
+            expiratory_term: 20,
+        }
+    }
+}
+
+/// Measurements of the current breathing cycle
+#[derive(Debug, Clone, Copy, Default)]
+struct CycleMeasurements {
+    peak_pressure: f64,
+    plateau_pressure: f64,
+    min_volume: f64,
+    max_volume: f64,
+}
+
+/// A virtual MakAir ventilating a patient model, producing the telemetry messages of a real device
+///
+/// This is an infinite iterator: it yields a `BootMessage`, then a `DataSnapshot` every `DATA_SNAPSHOT_PERIOD` (of systick) and a `MachineStateSnapshot` at the end of every cycle.
+#[derive(Debug, Clone)]
+pub struct SimulatedDevice {
+    device_id: String,
+    patient: PatientModel,
+    settings: SimulatorSettings,
+    systick: u64,
+    cycle: u32,
+    cycle_start: u64,
+    /// Volume in the lungs above the volume at PEEP, in mL
+    volume: f64,
+    airway_pressure: f64,
+    measurements: CycleMeasurements,
+    pending: VecDeque<TelemetryMessage>,
+}
+
+impl SimulatedDevice {
+    /// Create a device that is about to boot
+    pub fn new(device_id: &str, patient: PatientModel, settings: SimulatorSettings) -> Self {
+        let boot = TelemetryMessage::BootMessage(BootMessage {
+            telemetry_version: 2,
+            version: SIMULATOR_VERSION.to_owned(),
+            device_id: device_id.to_owned(),
+            systick: 0,
+            mode: Mode::Production,
+            value128: 128,
+            control_capabilities: None,
+            session_nonce: None,
+        });
+        Self {
+            device_id: device_id.to_owned(),
+            patient,
+            settings,
+            systick: 0,
+            cycle: 0,
+            cycle_start: 0,
+            volume: 0.0,
+            airway_pressure: f64::from(settings.peep) / 10.0,
+            measurements: CycleMeasurements::default(),
+            pending: VecDeque::from(vec![boot]),
+        }
+    }
+
+    /// Create a device ventilating a preset patient with the settings typically used for it
+    pub fn with_preset(device_id: &str, preset: PatientPreset) -> Self {
+        Self::new(device_id, preset.model(), preset.settings())
+    }
+
+    /// Internal ID of the simulated MCU
+    pub fn device_id(&self) -> &str {
+        &self.device_id
+    }
+
+    /// Number of microseconds since the simulated MCU booted
+    pub fn systick(&self) -> u64 {
+        self.systick
+    }
+
+    fn cycle_duration(&self) -> u64 {
+        60_000_000 / u64::from(self.settings.cycles_per_minute.max(1))
+    }
+
+    fn inspiration_duration(&self) -> u64 {
+        self.cycle_duration() * 10 / (10 + u64::from(self.settings.expiratory_term))
+    }
+
+    /// Muscular pressure of spontaneous breathing in cmH2O: a half sine wave during the first third of each spontaneous breath
+    fn muscular_pressure(&self) -> f64 {
+        match self.patient.effort {
+            Some(effort) if effort.rate > 0.0 => {
+                let period = 60.0 / effort.rate;
+                let phase = (self.systick as f64 / 1e6 % period) / period;
+                if phase < 1.0 / 3.0 {
+                    effort.amplitude * (phase * 3.0 * std::f64::consts::PI).sin()
+                } else {
+                    0.0
+                }
+            }
+            _ => 0.0,
+        }
+    }
+
+    fn step(&mut self) {
+        let dt = DATA_SNAPSHOT_PERIOD.as_secs_f64();
+        let peep = f64::from(self.settings.peep) / 10.0;
+        let plateau = f64::from(self.settings.plateau_pressure.max(self.settings.peep)) / 10.0;
+        let in_cycle = self.systick - self.cycle_start;
+        let phase = if in_cycle < self.inspiration_duration() {
+            Phase::Inhalation
+        } else {
+            Phase::Exhalation
+        };
+
+        // The blower builds up pressure with a time constant of 50 ms; the patient valve opens immediately
+        let target = match phase {
+            Phase::Inhalation => plateau,
+            Phase::Exhalation => peep,
+        };
+        self.airway_pressure = match phase {
+            Phase::Inhalation => {
+                self.airway_pressure + (target - self.airway_pressure) * (dt / 0.05).min(1.0)
+            }
+            Phase::Exhalation => target,
+        };
+        let alveolar_pressure = peep + self.volume / self.patient.compliance;
+        let flow = (self.airway_pressure - alveolar_pressure + self.muscular_pressure())
+            / self.patient.resistance.max(0.1);
+        self.volume = (self.volume + flow * 1_000.0 * dt).max(0.0);
+
+        let measurements = &mut self.measurements;
+        measurements.peak_pressure = measurements.peak_pressure.max(self.airway_pressure);
+        if phase == Phase::Inhalation {
+            measurements.plateau_pressure = alveolar_pressure;
+        }
+        measurements.min_volume = measurements.min_volume.min(self.volume);
+        measurements.max_volume = measurements.max_volume.max(self.volume);
+
+        let flow = flow * 6_000.0;
+        self.pending
+            .push_back(TelemetryMessage::DataSnapshot(DataSnapshot {
+                telemetry_version: 2,
+                version: SIMULATOR_VERSION.to_owned(),
+                device_id: self.device_id.clone(),
+                systick: self.systick,
+                centile: u16::try_from(in_cycle / 10_000).unwrap_or(u16::MAX),
+                pressure: (self.airway_pressure * 10.0).round() as i16,
+                phase,
+                subphase: None,
+                blower_valve_position: if phase == Phase::Inhalation { 40 } else { 125 },
+                patient_valve_position: if phase == Phase::Inhalation { 125 } else { 40 },
+                blower_rpm: 150,
+                battery_level: 27,
+                inspiratory_flow: Some(flow.max(0.0).round() as i16),
+                expiratory_flow: Some((-flow).max(0.0).round() as i16),
+            }));
+
+        self.systick += DATA_SNAPSHOT_PERIOD.as_micros() as u64;
+        if self.systick - self.cycle_start >= self.cycle_duration() {
+            self.end_cycle(peep);
+        }
+    }
+
+    fn end_cycle(&mut self, peep: f64) {
+        let measurements = self.measurements;
+        self.pending
+            .push_back(TelemetryMessage::MachineStateSnapshot(
+                MachineStateSnapshot {
+                    telemetry_version: 2,
+                    version: SIMULATOR_VERSION.to_owned(),
+                    device_id: self.device_id.clone(),
+                    systick: self.systick,
+                    cycle: self.cycle,
+                    peak_command: (self.settings.plateau_pressure / 10) as u8,
+                    plateau_command: (self.settings.plateau_pressure / 10) as u8,
+                    peep_command: (self.settings.peep / 10) as u8,
+                    cpm_command: self.settings.cycles_per_minute,
+                    previous_peak_pressure: (measurements.peak_pressure * 10.0).round() as u16,
+                    previous_plateau_pressure: (measurements.plateau_pressure * 10.0).round()
+                        as u16,
+                    previous_peep_pressure: ((peep + self.volume / self.patient.compliance) * 10.0)
+                        .round() as u16,
+                    previous_volume: Some(
+                        (measurements.max_volume - measurements.min_volume).round() as u16,
+                    ),
+                    expiratory_term: self.settings.expiratory_term,
+                    previous_cpm: Some(self.settings.cycles_per_minute),
+                    alarm_snoozed: Some(false),
+                    ventilation_mode: VentilationMode::PC_CMV,
+                    battery_level: Some(2_700),
+                    low_inspiratory_minute_volume_alarm_threshold: Some(
+                        ControlSetting::LowInspiratoryMinuteVolumeAlarmThreshold.default() as u8,
+                    ),
+                    high_inspiratory_minute_volume_alarm_threshold: Some(
+                        ControlSetting::HighInspiratoryMinuteVolumeAlarmThreshold.default() as u8,
+                    ),
+                    low_expiratory_minute_volume_alarm_threshold: Some(
+                        ControlSetting::LowExpiratoryMinuteVolumeAlarmThreshold.default() as u8,
+                    ),
+                    high_expiratory_minute_volume_alarm_threshold: Some(
+                        ControlSetting::HighExpiratoryMinuteVolumeAlarmThreshold.default() as u8,
+                    ),
+                    low_respiratory_rate_alarm_threshold: Some(
+                        ControlSetting::LowRespiratoryRateAlarmThreshold.default() as u8,
+                    ),
+                    high_respiratory_rate_alarm_threshold: Some(
+                        ControlSetting::HighRespiratoryRateAlarmThreshold.default() as u8,
+                    ),
+                    low_tidal_volume_alarm_threshold: Some(
+                        ControlSetting::LowTidalVolumeAlarmThreshold.default() as u16,
+                    ),
+                    high_tidal_volume_alarm_threshold: Some(
+                        ControlSetting::HighTidalVolumeAlarmThreshold.default() as u16,
+                    ),
+                    leak_alarm_threshold: Some(ControlSetting::LeakAlarmThreshold.default() as u16),
+                    peak_pressure_alarm_threshold: Some(
+                        ControlSetting::PeakPressureAlarmThreshold.default() as u16,
+                    ),
+                    ..Default::default()
+                },
+            ));
+        self.cycle += 1;
+        self.cycle_start = self.systick;
+        self.measurements = CycleMeasurements {
+            min_volume: self.volume,
+            max_volume: self.volume,
+            ..Default::default()
+        };
+    }
+}
+
+impl Iterator for SimulatedDevice {
+    type Item = TelemetryMessage;
+
+    fn next(&mut self) -> Option<TelemetryMessage> {
+        if self.pending.is_empty() {
+            self.step();
+        }
+        self.pending.pop_front()
+    }
+}
+
+/// Create devices with distinct IDs (`<prefix>-1`, `<prefix>-2`, etc.) ventilating preset patients, e.g. to load-test dashboards
+///
+/// Devices start ventilating at different times after booting (spread over a cycle), so that their breaths are not synchronized.
+pub fn swarm(prefix: &str, count: usize, preset: PatientPreset) -> Vec<SimulatedDevice> {
+    (0..count)
+        .map(|index| {
+            let mut device =
+                SimulatedDevice::with_preset(&format!("{}-{}", prefix, index + 1), preset);
+            let offset = device.cycle_duration() * index as u64 / count as u64;
+            let offset = offset - offset % DATA_SNAPSHOT_PERIOD.as_micros() as u64;
+            device.systick = offset;
+            device.cycle_start = offset;
+            device
+        })
+        .collect()
+}
+
+/// Send the telemetry of a simulated device through a channel
+///
+/// * `device` - Simulated device.
+/// * `tx` - Sender of a channel of `TelemetryChannelType` or `TimedMessage`.
+/// * `duration` - How long to simulate (in device time), or `None` to simulate until the receiver is dropped.
+/// * `enable_time_simulation` - If `true`, telemetry messages will be sent in a realistic timing; if `false`, they will be sent as fast as possible.
+pub fn simulate_telemetry<T: From<TimedMessage>>(
+    device: SimulatedDevice,
+    tx: Sender<T>,
+    duration: Option<Duration>,
+    enable_time_simulation: bool,
+) {
+    let source = SourceInfo::new(SourceKind::Simulator, Some(device.device_id.clone()));
+    let end = duration.map(|duration| duration.as_micros() as u64);
+    let start = Instant::now();
+    for message in device {
+        let systick = message.systick();
+        if end.is_some_and(|end| systick > end) {
+            break;
+        }
+        if enable_time_simulation {
+            let due = start + Duration::from_micros(systick);
+            if let Some(wait) = due.checked_duration_since(Instant::now()) {
+                std::thread::sleep(wait);
+            }
+        }
+        if tx
+            .send(TimedMessage::now(Ok(message), source.clone()).into())
+            .is_err()
+        {
+            break;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cycles(preset: PatientPreset, count: usize) -> Vec<MachineStateSnapshot> {
+        SimulatedDevice::with_preset("sim", preset)
+            .filter_map(|message| match message {
+                TelemetryMessage::MachineStateSnapshot(snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .take(count)
+            .collect()
+    }
+
+    #[test]
+    fn produces_device_telemetry() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("sim", PatientPreset::Healthy)
+                .take(400)
+                .collect();
+
+        assert!(matches!(messages[0], TelemetryMessage::BootMessage(_)));
+        assert_eq!(messages[2].systick() - messages[1].systick(), 10_000);
+        assert!(messages[1..]
+            .iter()
+            .all(|message| message.device_id() == "sim"));
+        // 20 cycles per minute: a cycle lasts 3 s, i.e. 300 snapshots
+        let cycle_ends: Vec<usize> = messages
+            .iter()
+            .enumerate()
+            .filter(|(_, message)| matches!(message, TelemetryMessage::MachineStateSnapshot(_)))
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(cycle_ends, vec![301]);
+    }
+
+    #[test]
+    fn swarm_devices_are_distinct() {
+        let devices = swarm("sim", 3, PatientPreset::Ards);
+        let ids: Vec<&str> = devices.iter().map(SimulatedDevice::device_id).collect();
+        assert_eq!(ids, vec!["sim-1", "sim-2", "sim-3"]);
+
+        let first_snapshots: Vec<u64> = devices
+            .into_iter()
+            .map(|mut device| device.nth(1).unwrap().systick())
+            .collect();
+        // 26 cycles per minute: a cycle lasts 2307 ms
+        assert_eq!(first_snapshots, vec![0, 760_000, 1_530_000]);
+    }
+
+    #[test]
+    fn presets_have_distinct_waveforms() {
+        let healthy = cycles(PatientPreset::Healthy, 5).pop().unwrap();
+        let ards = cycles(PatientPreset::Ards, 5).pop().unwrap();
+        let copd = cycles(PatientPreset::Copd, 5).pop().unwrap();
+
+        // Healthy lungs: (20 - 5) cmH2O * 50 mL/cmH2O, almost fully delivered
+        let healthy_volume = healthy.previous_volume.unwrap();
+        assert!((650..=750).contains(&healthy_volume), "{}", healthy_volume);
+        // Stiff lungs get small tidal volumes despite higher pressures
+        assert!(ards.previous_volume.unwrap() < 400);
+        // Obstructed airways do not empty in time: measured PEEP is above the command (auto-PEEP)
+        assert!(copd.previous_peep_pressure > u16::from(copd.peep_command) * 10);
+        assert!(healthy.previous_peep_pressure <= u16::from(healthy.peep_command) * 10 + 5);
+
+        assert_eq!("copd".parse(), Ok(PatientPreset::Copd));
+        assert!("unknown".parse::<PatientPreset>().is_err());
+    }
+}
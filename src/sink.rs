@@ -0,0 +1,762 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::sync::mpsc::{Receiver, RecvTimeoutError, TryRecvError};
+use std::time::{Duration, Instant};
+
+use crate::formatter::MessageFormatter;
+use crate::recording::RecordingWriter;
+use crate::structures::TelemetryMessage;
+use crate::TimedMessage;
+
+/// Something that consumes telemetry messages (recording, display, export, etc.)
+pub trait TelemetrySink {
+    /// Handle a telemetry message (or an error)
+    fn consume(&mut self, message: &TimedMessage);
+
+    /// Flush buffered data, if any; this is called when no more message will be consumed
+    fn flush(&mut self) {}
+}
+
+impl<S: TelemetrySink + ?Sized> TelemetrySink for Box<S> {
+    fn consume(&mut self, message: &TimedMessage) {
+        (**self).consume(message);
+    }
+
+    fn flush(&mut self) {
+        (**self).flush();
+    }
+}
+
+/// A set of named sinks that receive every message, which can be changed at runtime
+#[derive(Default)]
+pub struct SinkSet {
+    sinks: Vec<(String, Box<dyn TelemetrySink + Send>)>,
+}
+
+impl SinkSet {
+    /// Create an empty set of sinks
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Add a sink; if a sink with the same name already exists, it is flushed and replaced
+    pub fn add<S: TelemetrySink + Send + 'static>(&mut self, name: &str, sink: S) {
+        self.add_boxed(name, Box::new(sink));
+    }
+
+    /// Same as `add()`, for a sink that is already boxed
+    pub fn add_boxed(&mut self, name: &str, sink: Box<dyn TelemetrySink + Send>) {
+        self.remove(name);
+        self.sinks.push((name.to_owned(), sink));
+    }
+
+    /// Flush and remove a sink, returning whether it existed
+    pub fn remove(&mut self, name: &str) -> bool {
+        match self.sinks.iter().position(|(n, _)| n == name) {
+            Some(index) => {
+                let (_, mut sink) = self.sinks.remove(index);
+                sink.flush();
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// Names of the sinks in the set
+    pub fn names(&self) -> Vec<&str> {
+        self.sinks.iter().map(|(name, _)| name.as_str()).collect()
+    }
+
+    /// Number of sinks in the set
+    pub fn len(&self) -> usize {
+        self.sinks.len()
+    }
+
+    /// Whether the set contains no sink
+    pub fn is_empty(&self) -> bool {
+        self.sinks.is_empty()
+    }
+}
+
+impl TelemetrySink for SinkSet {
+    fn consume(&mut self, message: &TimedMessage) {
+        for (_, sink) in self.sinks.iter_mut() {
+            sink.consume(message);
+        }
+    }
+
+    fn flush(&mut self) {
+        for (_, sink) in self.sinks.iter_mut() {
+            sink.flush();
+        }
+    }
+}
+
+/// Give every message received through a channel to a sink, until the channel is closed
+///
+/// * `rx` - Receiver of a channel given to a `gather_telemetry*` function.
+/// * `sink` - Sink that will consume messages (use a `SinkSet` to fan out to several sinks).
+///
+/// This is meant to be run in a dedicated thread (or in the main thread of a CLI).
+pub fn dispatch<S: TelemetrySink + ?Sized>(rx: Receiver<TimedMessage>, sink: &mut S) {
+    for message in rx.iter() {
+        sink.consume(&message);
+    }
+    sink.flush();
+}
+
+/// How often pending commands are checked while no message is received
+const COMMAND_CHECK_PERIOD: Duration = Duration::from_millis(100);
+
+/// Change to apply to a running dispatcher (see `dispatch_with_commands()`)
+pub enum SinkCommand {
+    /// Add a sink, or replace the sink with the same name
+    Add(String, Box<dyn TelemetrySink + Send>),
+    /// Flush and remove a sink
+    Remove(String),
+    /// Minimum interval between two `DataSnapshot` messages given to sinks (`None` to keep every snapshot)
+    ///
+    /// Other kinds of messages are never throttled.
+    Throttle(Option<Duration>),
+}
+
+/// Same as `dispatch()`, but sinks and throttling can be changed while messages are flowing
+///
+/// * `rx` - Receiver of a channel given to a `gather_telemetry*` function.
+/// * `commands` - Receiver of changes to apply; they are applied between two messages, so the source is never interrupted.
+/// * `sinks` - Initial set of sinks.
+///
+/// This returns when the channel of messages is closed.
+pub fn dispatch_with_commands(
+    rx: Receiver<TimedMessage>,
+    commands: Receiver<SinkCommand>,
+    sinks: &mut SinkSet,
+) {
+    let mut throttle: Option<Duration> = None;
+    let mut last_snapshot: Option<Instant> = None;
+    let mut commands_open = true;
+
+    loop {
+        while commands_open {
+            match commands.try_recv() {
+                Ok(SinkCommand::Add(name, sink)) => sinks.add_boxed(&name, sink),
+                Ok(SinkCommand::Remove(name)) => {
+                    sinks.remove(&name);
+                }
+                Ok(SinkCommand::Throttle(interval)) => throttle = interval,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => commands_open = false,
+            }
+        }
+
+        match rx.recv_timeout(COMMAND_CHECK_PERIOD) {
+            Ok(message) => {
+                if let (Ok(TelemetryMessage::DataSnapshot(_)), Some(interval)) =
+                    (&message.message, throttle)
+                {
+                    if last_snapshot
+                        .map(|last| message.received_instant.duration_since(last) < interval)
+                        .unwrap_or(false)
+                    {
+                        continue;
+                    }
+                    last_snapshot = Some(message.received_instant);
+                }
+                sinks.consume(&message);
+            }
+            Err(RecvTimeoutError::Timeout) => {}
+            Err(RecvTimeoutError::Disconnected) => break,
+        }
+    }
+    sinks.flush();
+}
+
+/// Sink that displays messages using a formatter
+pub struct DisplaySink<F> {
+    formatter: F,
+}
+
+impl<F: MessageFormatter> DisplaySink<F> {
+    /// Create a sink that displays messages using the given formatter
+    pub fn new(formatter: F) -> Self {
+        Self { formatter }
+    }
+}
+
+impl<F: MessageFormatter> TelemetrySink for DisplaySink<F> {
+    fn consume(&mut self, message: &TimedMessage) {
+        self.formatter.display(&message.message);
+    }
+}
+
+/// Sink that records messages in a recording
+///
+/// Messages are serialized again using the protocol version they were received with; errors are not recorded.
+pub struct RecordingSink {
+    writer: RecordingWriter,
+}
+
+impl RecordingSink {
+    /// Create a sink that records messages using the given writer
+    pub fn new(writer: RecordingWriter) -> Self {
+        Self { writer }
+    }
+}
+
+impl TelemetrySink for RecordingSink {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(message) = &message.message {
+            let result = message
+                .to_bytes_for_version(message.telemetry_version())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+                .and_then(|bytes| self.writer.write_frame(&bytes, Some(message)));
+            if let Err(e) = result {
+                log::error!("failed writing message to recording: {:?}", e);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::error!("failed flushing recording: {:?}", e);
+        }
+    }
+}
+
+/// Sink that writes messages as raw telemetry frames, e.g. to pass a cleaned-up stream to another program
+///
+/// Messages are serialized again using the protocol version they were received with, and the writer is flushed after every frame; errors are not written.
+pub struct FrameSink<W: std::io::Write> {
+    writer: W,
+}
+
+impl<W: std::io::Write> FrameSink<W> {
+    /// Create a sink that writes frames with the given writer
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+impl<W: std::io::Write> TelemetrySink for FrameSink<W> {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(message) = &message.message {
+            let result = message
+                .to_bytes_for_version(message.telemetry_version())
+                .map_err(|e| std::io::Error::new(std::io::ErrorKind::InvalidInput, e))
+                .and_then(|bytes| self.writer.write_all(&bytes))
+                .and_then(|_| self.writer.flush());
+            if let Err(e) = result {
+                log::error!("failed writing frame: {:?}", e);
+            }
+        }
+    }
+}
+
+/// Sink that writes messages as JSON, one message per line (NDJSON); errors are not written
+///
+/// This is the same format as the one produced by the JSON export of the CLI.
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+#[cfg_attr(
+    doc_cfg,
+    doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+)]
+pub struct JsonSink<W: std::io::Write> {
+    writer: W,
+}
+
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+impl<W: std::io::Write> JsonSink<W> {
+    /// Create a sink that writes messages with the given writer (use a `LineWriter` to flush every line)
+    pub fn new(writer: W) -> Self {
+        Self { writer }
+    }
+}
+
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+impl<W: std::io::Write> TelemetrySink for JsonSink<W> {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(message) = &message.message {
+            let result = serde_json::to_writer(&mut self.writer, message)
+                .map_err(std::io::Error::from)
+                .and_then(|_| self.writer.write_all(b"\n"));
+            if let Err(e) = result {
+                log::error!("failed writing message as JSON: {:?}", e);
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::error!("failed flushing JSON output: {:?}", e);
+        }
+    }
+}
+
+/// Sink that calls a closure for every successfully decoded message
+pub struct FnSink<F> {
+    f: F,
+}
+
+impl<F: FnMut(&TelemetryMessage)> FnSink<F> {
+    /// Create a sink from a closure
+    pub fn new(f: F) -> Self {
+        Self { f }
+    }
+}
+
+impl<F: FnMut(&TelemetryMessage)> TelemetrySink for FnSink<F> {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(message) = &message.message {
+            (self.f)(message);
+        }
+    }
+}
+
+/// Sink that forwards messages to every client connected to a WebSocket server
+///
+/// Messages are sent as binary WebSocket messages by default, like the MakAir WebSocket relay does, so this can be consumed by `gather_telemetry_from_ws()`; they can be sent as JSON instead (see `websocket::WebSocketMessageFormat`).
+/// Each client has its own bounded queue and sending thread (see `FanOut`), so a stalled client cannot delay the others.
+/// Messages are compressed for clients that negotiate permessage-deflate, if enabled in the configuration.
+/// Clients that fail to receive a message, or that do not keep up, are disconnected.
+#[cfg(feature = "websocket")]
+#[cfg_attr(doc_cfg, doc(cfg(feature = "websocket")))]
+pub struct WebSocketSink {
+    fan_out: crate::fanout::FanOut,
+    local_addr: std::net::SocketAddr,
+    format: crate::websocket::WebSocketMessageFormat,
+}
+
+/// How long a WebSocket client is waited for control messages at once, during which messages cannot be sent to it
+#[cfg(feature = "websocket")]
+const CONTROL_POLL_PERIOD: Duration = Duration::from_millis(10);
+
+/// How long a new WebSocket client is waited for to complete its handshake
+#[cfg(feature = "websocket")]
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[cfg(feature = "websocket")]
+impl WebSocketSink {
+    /// Start a WebSocket server on the given address with the default configuration; clients are accepted in a dedicated thread
+    pub fn bind<A: std::net::ToSocketAddrs>(addr: A) -> std::io::Result<Self> {
+        Self::bind_with_config(addr, crate::websocket::WebSocketServerConfig::default())
+    }
+
+    /// Start a WebSocket server on the given address; clients are accepted in a dedicated thread
+    pub fn bind_with_config<A: std::net::ToSocketAddrs>(
+        addr: A,
+        config: crate::websocket::WebSocketServerConfig,
+    ) -> std::io::Result<Self> {
+        Self::bind_with_control(addr, config, None)
+    }
+
+    /// Same as `bind_with_config()`, also accepting control messages from clients
+    ///
+    /// * `control_tx` - Optional sender of a channel of control messages (e.g. the one given to `gather_telemetry()`); clients send them as binary WebSocket messages holding a control frame each, and frames that cannot be parsed are logged and dropped.
+    #[allow(clippy::result_large_err)]
+    pub fn bind_with_control<A: std::net::ToSocketAddrs>(
+        addr: A,
+        config: crate::websocket::WebSocketServerConfig,
+        control_tx: Option<std::sync::mpsc::Sender<crate::control::ControlMessage>>,
+    ) -> std::io::Result<Self> {
+        use crate::websocket::{
+            DeflateParameters, MessageDeflater, WebSocketMessageFormat, EXTENSIONS_HEADER,
+        };
+        use std::sync::{Arc, Mutex};
+        use tungstenite::handshake::server::{ErrorResponse, Request, Response};
+        use tungstenite::http::HeaderValue;
+        use tungstenite::protocol::frame::coding::{Data, OpCode};
+        use tungstenite::protocol::frame::Frame;
+        use tungstenite::protocol::Message;
+
+        let listener = std::net::TcpListener::bind(addr)?;
+        let local_addr = listener.local_addr()?;
+        let fan_out = crate::fanout::FanOut::new(config.fan_out);
+        let accepting_fan_out = fan_out.clone();
+        let permessage_deflate = config.permessage_deflate;
+        let format = config.format;
+
+        std::thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                let fan_out = accepting_fan_out.clone();
+                let control_tx = control_tx.clone();
+                // Handshakes happen in their own thread, so that a client that does not complete it cannot delay the others
+                std::thread::spawn(move || {
+                    let name = stream
+                        .peer_addr()
+                        .map(|addr| addr.to_string())
+                        .unwrap_or_else(|_| "unknown".to_owned());
+                    let interrupted_stream = match stream.try_clone() {
+                        Ok(stream) => stream,
+                        Err(e) => {
+                            log::warn!("failed to accept WebSocket client: {:?}", e);
+                            return;
+                        }
+                    };
+                    let mut deflate = None;
+                    let negotiate = |request: &Request, mut response: Response| {
+                        if permessage_deflate {
+                            deflate = request
+                                .headers()
+                                .get_all(EXTENSIONS_HEADER)
+                                .iter()
+                                .filter_map(|offer| offer.to_str().ok())
+                                .find_map(DeflateParameters::accept_offer);
+                            if let Some(parameters) = deflate {
+                                if let Ok(header) = HeaderValue::from_str(&parameters.to_header()) {
+                                    response.headers_mut().insert(EXTENSIONS_HEADER, header);
+                                }
+                            }
+                        }
+                        Ok::<Response, ErrorResponse>(response)
+                    };
+                    if let Err(e) = interrupted_stream.set_read_timeout(Some(HANDSHAKE_TIMEOUT)) {
+                        log::warn!("failed to accept WebSocket client: {:?}", e);
+                        return;
+                    }
+                    let handshake = tungstenite::accept_hdr(stream, negotiate);
+                    if let Err(e) = interrupted_stream.set_read_timeout(None) {
+                        log::warn!("failed to accept WebSocket client: {:?}", e);
+                        return;
+                    }
+                    match handshake {
+                        Ok(socket) => {
+                            log::info!(
+                                "new WebSocket client {}{}",
+                                name,
+                                if deflate.is_some() {
+                                    " (compressed)"
+                                } else {
+                                    ""
+                                }
+                            );
+                            let socket = Arc::new(Mutex::new(socket));
+                            if let Some(control_tx) = control_tx.clone() {
+                                if let Err(e) =
+                                    interrupted_stream.set_read_timeout(Some(CONTROL_POLL_PERIOD))
+                                {
+                                    log::warn!("failed to accept WebSocket client: {:?}", e);
+                                    return;
+                                }
+                                let socket = Arc::clone(&socket);
+                                let name = name.clone();
+                                std::thread::spawn(move || loop {
+                                    let result = socket
+                                        .lock()
+                                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                        .read_message();
+                                    match result {
+                                        Ok(Message::Binary(bytes)) => {
+                                            match crate::control::parse_control_message(&bytes) {
+                                                Ok((_, message)) => {
+                                                    log::info!("{} → {}", name, message);
+                                                    if control_tx.send(message).is_err() {
+                                                        return;
+                                                    }
+                                                }
+                                                Err(e) => log::warn!(
+                                                    "dropping invalid control frame from WebSocket client {}: {:?}",
+                                                    name,
+                                                    e
+                                                ),
+                                            }
+                                        }
+                                        Ok(_) => (),
+                                        Err(tungstenite::Error::Io(e))
+                                            if e.kind() == std::io::ErrorKind::WouldBlock
+                                                || e.kind() == std::io::ErrorKind::TimedOut =>
+                                        {
+                                            // Let messages be sent to the client
+                                            std::thread::sleep(CONTROL_POLL_PERIOD);
+                                        }
+                                        // The client is gone: sending it the next message will fail and disconnect it
+                                        Err(_) => return,
+                                    }
+                                });
+                            }
+                            let mut deflater = deflate.map(MessageDeflater::new);
+                            fan_out.add_client(
+                                &name,
+                                move |bytes| {
+                                    let data = match format {
+                                        WebSocketMessageFormat::Binary => Data::Binary,
+                                        #[cfg(all(
+                                            feature = "serde-messages",
+                                            feature = "serde_json"
+                                        ))]
+                                        WebSocketMessageFormat::Json => Data::Text,
+                                    };
+                                    let message = match deflater.as_mut() {
+                                        Some(deflater) => {
+                                            let mut frame = Frame::message(
+                                                deflater.compress(bytes)?,
+                                                OpCode::Data(data),
+                                                true,
+                                            );
+                                            frame.header_mut().rsv1 = true;
+                                            Message::Frame(frame)
+                                        }
+                                        None if data == Data::Text => Message::Text(
+                                            String::from_utf8_lossy(bytes).into_owned(),
+                                        ),
+                                        None => Message::Binary(bytes.to_vec()),
+                                    };
+                                    socket
+                                        .lock()
+                                        .unwrap_or_else(|poisoned| poisoned.into_inner())
+                                        .write_message(message)
+                                        .map_err(std::io::Error::other)
+                                },
+                                move || {
+                                    let _ = interrupted_stream.shutdown(std::net::Shutdown::Both);
+                                },
+                            );
+                        }
+                        Err(e) => log::warn!("failed to accept WebSocket client: {:?}", e),
+                    }
+                });
+            }
+        });
+
+        Ok(Self {
+            fan_out,
+            local_addr,
+            format,
+        })
+    }
+
+    /// Address the server listens on (useful when binding to port 0)
+    pub fn local_addr(&self) -> std::net::SocketAddr {
+        self.local_addr
+    }
+
+    /// Number of connected clients
+    pub fn clients_count(&self) -> usize {
+        self.fan_out.clients_count()
+    }
+
+    /// Delivery statistics of every connected client, and number of disconnected clients
+    pub fn stats(&self) -> crate::fanout::FanOutStats {
+        self.fan_out.stats()
+    }
+}
+
+#[cfg(feature = "websocket")]
+impl TelemetrySink for WebSocketSink {
+    fn consume(&mut self, message: &TimedMessage) {
+        use crate::websocket::WebSocketMessageFormat;
+
+        if let Ok(message) = &message.message {
+            match self.format {
+                WebSocketMessageFormat::Binary => {
+                    match message.to_bytes_for_version(message.telemetry_version()) {
+                        Ok(bytes) => self.fan_out.send(&bytes),
+                        Err(e) => log::error!("failed serializing message: {:?}", e),
+                    }
+                }
+                #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+                WebSocketMessageFormat::Json => match serde_json::to_vec(message) {
+                    Ok(json) => self.fan_out.send(&json),
+                    Err(e) => log::error!("failed serializing message as JSON: {:?}", e),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+    use crate::source::{SourceInfo, SourceKind};
+    use crate::structures::ControlAck;
+    use std::sync::{Arc, Mutex};
+
+    fn timed_ack(value: u16) -> TimedMessage {
+        TimedMessage::now(
+            Ok(TelemetryMessage::ControlAck(ControlAck {
+                telemetry_version: 2,
+                version: "test".to_owned(),
+                device_id: "0-0-0".to_owned(),
+                systick: 0,
+                setting: ControlSetting::PEEP,
+                value,
+            })),
+            SourceInfo::new(SourceKind::Bytes, None),
+        )
+    }
+
+    fn counting_sink(counter: &Arc<Mutex<usize>>) -> impl TelemetrySink + Send {
+        let counter = Arc::clone(counter);
+        FnSink::new(move |_: &TelemetryMessage| *counter.lock().unwrap() += 1)
+    }
+
+    #[test]
+    fn sink_set_fans_out() {
+        let first = Arc::new(Mutex::new(0));
+        let second = Arc::new(Mutex::new(0));
+        let mut sinks = SinkSet::new();
+        sinks.add("first", counting_sink(&first));
+        sinks.add("second", counting_sink(&second));
+
+        sinks.consume(&timed_ack(1));
+        assert!(sinks.remove("second"));
+        sinks.consume(&timed_ack(2));
+
+        assert_eq!(sinks.names(), vec!["first"]);
+        assert_eq!(*first.lock().unwrap(), 2);
+        assert_eq!(*second.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn dispatch_until_channel_is_closed() {
+        let counter = Arc::new(Mutex::new(0));
+        let mut sinks = SinkSet::new();
+        sinks.add("counter", counting_sink(&counter));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        for value in 0..3 {
+            tx.send(timed_ack(value)).unwrap();
+        }
+        drop(tx);
+        dispatch(rx, &mut sinks);
+
+        assert_eq!(*counter.lock().unwrap(), 3);
+    }
+
+    #[test]
+    fn dispatch_applies_commands() {
+        let counter = Arc::new(Mutex::new(0));
+        let mut sinks = SinkSet::new();
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+        commands_tx
+            .send(SinkCommand::Add(
+                "counter".to_owned(),
+                Box::new(counting_sink(&counter)),
+            ))
+            .unwrap();
+        tx.send(timed_ack(1)).unwrap();
+        drop(tx);
+        dispatch_with_commands(rx, commands_rx, &mut sinks);
+
+        assert_eq!(sinks.names(), vec!["counter"]);
+        assert_eq!(*counter.lock().unwrap(), 1);
+    }
+
+    #[test]
+    fn dispatch_removes_sinks() {
+        let kept = Arc::new(Mutex::new(0));
+        let removed = Arc::new(Mutex::new(0));
+        let mut sinks = SinkSet::new();
+        sinks.add("kept", counting_sink(&kept));
+        sinks.add("removed", counting_sink(&removed));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+        commands_tx
+            .send(SinkCommand::Remove("removed".to_owned()))
+            .unwrap();
+        // Removing a sink that does not exist is not an error
+        commands_tx
+            .send(SinkCommand::Remove("unknown".to_owned()))
+            .unwrap();
+        tx.send(timed_ack(1)).unwrap();
+        drop(tx);
+        dispatch_with_commands(rx, commands_rx, &mut sinks);
+
+        assert_eq!(sinks.names(), vec!["kept"]);
+        assert_eq!(*kept.lock().unwrap(), 1);
+        assert_eq!(*removed.lock().unwrap(), 0);
+    }
+
+    #[test]
+    fn dispatch_throttles_data_snapshots() {
+        use crate::simulator::{PatientPreset, SimulatedDevice};
+
+        let counter = Arc::new(Mutex::new(0));
+        let mut sinks = SinkSet::new();
+        sinks.add("counter", counting_sink(&counter));
+
+        let (tx, rx) = std::sync::mpsc::channel();
+        let (commands_tx, commands_rx) = std::sync::mpsc::channel();
+        commands_tx
+            .send(SinkCommand::Throttle(Some(Duration::from_millis(100))))
+            .unwrap();
+        let data_snapshot = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .find(|message| matches!(message, TelemetryMessage::DataSnapshot(_)))
+            .unwrap();
+        let start = Instant::now();
+        let snapshot = |ms: u64| TimedMessage {
+            received_instant: start + Duration::from_millis(ms),
+            ..TimedMessage::now(
+                Ok(data_snapshot.clone()),
+                SourceInfo::new(SourceKind::Bytes, None),
+            )
+        };
+        for ms in [0, 50, 100, 150, 250] {
+            tx.send(snapshot(ms)).unwrap();
+            // Other messages are never throttled
+            tx.send(timed_ack(1)).unwrap();
+        }
+        drop(tx);
+        dispatch_with_commands(rx, commands_rx, &mut sinks);
+
+        // Snapshots received at 0, 100 and 250 ms, and every ACK
+        assert_eq!(*counter.lock().unwrap(), 3 + 5);
+    }
+
+    #[test]
+    fn recording_sink_keeps_protocol_version() {
+        let messages: Vec<TelemetryMessage> = [1, 2, 3]
+            .into_iter()
+            .map(|telemetry_version| {
+                TelemetryMessage::ControlAck(ControlAck {
+                    telemetry_version,
+                    version: "test".to_owned(),
+                    device_id: "1-2-3".to_owned(),
+                    systick: u64::from(telemetry_version),
+                    setting: ControlSetting::PEEP,
+                    value: 80,
+                })
+            })
+            .collect();
+        let path = std::env::temp_dir().join(format!("makair-sink-{}.record", std::process::id()));
+
+        let mut sink = RecordingSink::new(RecordingWriter::new(
+            std::fs::File::create(&path).unwrap(),
+            crate::recording::FlushPolicy::EveryMessage,
+        ));
+        for message in &messages {
+            sink.consume(&TimedMessage::now(
+                Ok(message.clone()),
+                SourceInfo::new(SourceKind::Bytes, None),
+            ));
+        }
+        sink.flush();
+        drop(sink);
+
+        let recorded: Vec<TelemetryMessage> = crate::reader::TelemetryFileReader::open(&path)
+            .unwrap()
+            .map(|message| message.unwrap())
+            .collect();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(recorded, messages);
+    }
+
+    #[test]
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    fn json_sink_writes_one_line_per_message() {
+        let mut sink = JsonSink::new(Vec::new());
+        sink.consume(&timed_ack(1));
+        sink.consume(&timed_ack(2));
+
+        let output = String::from_utf8(sink.writer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert!(lines[1].contains("\"message_type\":\"ControlAck\""));
+    }
+}
@@ -0,0 +1,208 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use crate::control::{parse_control_message, ControlMessage};
+
+/// Time to wait for bytes on one side of a passthrough before polling the other side
+pub const PASSTHROUGH_TIMEOUT: Duration = Duration::from_millis(1);
+
+const CHUNK_SIZE: usize = 1024;
+
+/// Direction of bytes going through a passthrough
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    /// Telemetry sent by the MCU to the UI
+    McuToUi,
+    /// Control messages sent by the UI to the MCU
+    UiToMcu,
+}
+
+/// Forwards bytes between the MCU and the control UI, and shows them to an observer once they were forwarded
+///
+/// Bytes are written to the other side as soon as they are read, before the observer gets them, so parsing or recording them does not delay the traffic.
+/// Both sides are polled in turn: they must have a short read timeout (see `PASSTHROUGH_TIMEOUT`).
+#[derive(Debug)]
+pub struct Passthrough<M, U> {
+    mcu: M,
+    ui: U,
+    chunk: [u8; CHUNK_SIZE],
+    mcu_to_ui_bytes: u64,
+    ui_to_mcu_bytes: u64,
+}
+
+impl<M: Read + Write, U: Read + Write> Passthrough<M, U> {
+    /// Create a passthrough between the port connected to the MCU and the one connected to the UI
+    pub fn new(mcu: M, ui: U) -> Self {
+        Self {
+            mcu,
+            ui,
+            chunk: [0; CHUNK_SIZE],
+            mcu_to_ui_bytes: 0,
+            ui_to_mcu_bytes: 0,
+        }
+    }
+
+    /// Forward the bytes available on each side to the other side, then hand them to `tap`
+    ///
+    /// Returns the number of forwarded bytes; timeouts are not errors and only mean that nothing was forwarded.
+    pub fn poll<F: FnMut(Direction, &[u8])>(&mut self, mut tap: F) -> io::Result<usize> {
+        let telemetry_len = forward(&mut self.mcu, &mut self.ui, &mut self.chunk)?;
+        self.mcu_to_ui_bytes += telemetry_len as u64;
+        if telemetry_len > 0 {
+            tap(Direction::McuToUi, &self.chunk[..telemetry_len]);
+        }
+
+        let control_len = forward(&mut self.ui, &mut self.mcu, &mut self.chunk)?;
+        self.ui_to_mcu_bytes += control_len as u64;
+        if control_len > 0 {
+            tap(Direction::UiToMcu, &self.chunk[..control_len]);
+        }
+
+        Ok(telemetry_len + control_len)
+    }
+
+    /// Number of bytes forwarded in a direction so far
+    pub fn forwarded_bytes(&self, direction: Direction) -> u64 {
+        match direction {
+            Direction::McuToUi => self.mcu_to_ui_bytes,
+            Direction::UiToMcu => self.ui_to_mcu_bytes,
+        }
+    }
+
+    /// Get back the ports
+    pub fn into_inner(self) -> (M, U) {
+        (self.mcu, self.ui)
+    }
+}
+
+fn forward<R: Read, W: Write>(from: &mut R, to: &mut W, chunk: &mut [u8]) -> io::Result<usize> {
+    match from.read(chunk) {
+        Ok(len) => {
+            to.write_all(&chunk[..len])?;
+            Ok(len)
+        }
+        Err(e)
+            if matches!(
+                e.kind(),
+                io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock | io::ErrorKind::Interrupted
+            ) =>
+        {
+            Ok(0)
+        }
+        Err(e) => Err(e),
+    }
+}
+
+/// Decodes control messages from the bytes sent by the UI
+///
+/// Bytes that are not part of a valid control frame (including frames with a wrong CRC) are skipped.
+#[derive(Debug, Default)]
+pub struct ControlTap {
+    buffer: Vec<u8>,
+}
+
+impl ControlTap {
+    /// Create a decoder with an empty buffer
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Handle bytes sent by the UI and return the control messages they complete
+    pub fn push(&mut self, bytes: &[u8]) -> Vec<ControlMessage> {
+        self.buffer.extend_from_slice(bytes);
+        let mut messages = Vec::new();
+        while !self.buffer.is_empty() {
+            let consumed = match parse_control_message(&self.buffer) {
+                Ok((rest, message)) => {
+                    messages.push(message);
+                    self.buffer.len() - rest.len()
+                }
+                // There are not enough bytes, let's wait until we get more
+                Err(nom::Err::Incomplete(_)) => break,
+                Err(_) => 1,
+            };
+            self.buffer.drain(..consumed);
+        }
+        messages
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::ControlSetting;
+    use std::io::Cursor;
+
+    struct FakePort {
+        input: Cursor<Vec<u8>>,
+        output: Vec<u8>,
+    }
+
+    impl FakePort {
+        fn new(input: &[u8]) -> Self {
+            Self {
+                input: Cursor::new(input.to_vec()),
+                output: Vec::new(),
+            }
+        }
+    }
+
+    impl Read for FakePort {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.input.read(buf)
+        }
+    }
+
+    impl Write for FakePort {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.output.write(buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn forwards_then_taps_both_directions() {
+        let mut passthrough =
+            Passthrough::new(FakePort::new(b"telemetry"), FakePort::new(b"control"));
+        let mut tapped = Vec::new();
+        let forwarded = passthrough
+            .poll(|direction, bytes| tapped.push((direction, bytes.to_vec())))
+            .unwrap();
+
+        assert_eq!(forwarded, 16);
+        assert_eq!(
+            tapped,
+            vec![
+                (Direction::McuToUi, b"telemetry".to_vec()),
+                (Direction::UiToMcu, b"control".to_vec())
+            ]
+        );
+        assert_eq!(passthrough.forwarded_bytes(Direction::McuToUi), 9);
+        let (mcu, ui) = passthrough.into_inner();
+        assert_eq!(mcu.output, b"control");
+        assert_eq!(ui.output, b"telemetry");
+    }
+
+    #[test]
+    fn decodes_control_messages_across_chunks() {
+        let message = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+        let mut bytes = vec![0xFF, 0x05];
+        bytes.extend(message.to_control_frame());
+        let (first, second) = bytes.split_at(5);
+
+        let mut tap = ControlTap::new();
+        assert_eq!(tap.push(first), vec![]);
+        assert_eq!(tap.push(second), vec![message]);
+    }
+}
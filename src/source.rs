@@ -0,0 +1,98 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::{self, Read, Write};
+
+/// Kind of source telemetry messages come from
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum SourceKind {
+    /// A serial port connected to the MCU
+    Serial,
+    /// A WebSocket server relaying telemetry
+    WebSocket,
+    /// A recorded file
+    File,
+    /// A channel of raw bytes
+    Bytes,
+    /// A simulated device
+    Simulator,
+    /// A Bluetooth serial link (RFCOMM)
+    Bluetooth,
+}
+
+impl std::fmt::Display for SourceKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Self::Serial => "serial",
+            Self::WebSocket => "ws",
+            Self::File => "file",
+            Self::Bytes => "bytes",
+            Self::Simulator => "simulator",
+            Self::Bluetooth => "bt",
+        };
+        f.write_str(kind)
+    }
+}
+
+/// Where telemetry messages come from
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct SourceInfo {
+    /// Kind of source
+    pub kind: SourceKind,
+    /// Identifier of the source within its kind (serial port, URL, file path, etc.), if known
+    pub identifier: Option<String>,
+}
+
+impl SourceInfo {
+    /// Create source information
+    pub fn new(kind: SourceKind, identifier: Option<String>) -> Self {
+        Self { kind, identifier }
+    }
+}
+
+impl std::fmt::Display for SourceInfo {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.identifier {
+            Some(identifier) => write!(f, "{}:{}", self.kind, identifier),
+            None => write!(f, "{}", self.kind),
+        }
+    }
+}
+
+/// Something telemetry can be read from, and connected to again after errors
+///
+/// See `gather_telemetry_from_source()`, which handles parsing, control messages and reconnections for any source.
+pub trait TelemetrySource {
+    /// An open connection; reads should time out regularly, so that control messages can be sent in between
+    type Connection: Read + Write;
+
+    /// Information attached to every message read from this source
+    fn info(&self) -> SourceInfo;
+
+    /// Open a new connection to the source
+    fn connect(&mut self) -> io::Result<Self::Connection>;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn display_source_info() {
+        assert_eq!(
+            SourceInfo::new(SourceKind::Serial, Some("/dev/ttyUSB0".to_owned())).to_string(),
+            "serial:/dev/ttyUSB0"
+        );
+        assert_eq!(SourceInfo::new(SourceKind::File, None).to_string(), "file");
+    }
+}
@@ -0,0 +1,242 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use rand::distributions::{Distribution, WeightedIndex};
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+
+use crate::control::{ControlMessage, ControlSetting};
+
+/// Maximum number of bytes sent at once by the `Bytes` generator
+pub const MAX_RANDOM_BYTES: usize = 10;
+
+/// Kind of data sent to the MCU during a storm
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum StormGenerator {
+    /// Valid control frames
+    Valid,
+    /// Random bytes, not framed
+    Bytes,
+    /// Control frames with a wrong CRC
+    WrongCrc,
+}
+
+impl StormGenerator {
+    /// Name of the generator, as accepted by `from_str()` (e.g. `wrong-crc`)
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Valid => "valid",
+            Self::Bytes => "bytes",
+            Self::WrongCrc => "wrong-crc",
+        }
+    }
+}
+
+impl std::str::FromStr for StormGenerator {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "valid" => Ok(Self::Valid),
+            "bytes" => Ok(Self::Bytes),
+            "wrong-crc" | "wrong_crc" => Ok(Self::WrongCrc),
+            _ => Err("Supported generators are: valid, bytes, wrong-crc"),
+        }
+    }
+}
+
+/// Error returned when a storm cannot generate anything with its configuration
+#[derive(Debug, Clone, PartialEq, Eq, thiserror::Error)]
+pub enum StormConfigError {
+    /// Every generator has a weight of 0
+    #[error("at least one generator must have a positive weight")]
+    NoGenerator,
+    /// Every setting has a weight of 0
+    #[error("at least one setting must have a positive weight")]
+    NoSetting,
+}
+
+/// What a storm sends, and how often
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StormConfig {
+    /// Generators with their relative frequency
+    pub generators: Vec<(StormGenerator, u32)>,
+    /// Relative frequency of settings in control frames, overriding `ControlSetting::random_weight()` (0 never sends the setting)
+    pub setting_weights: Vec<(ControlSetting, u32)>,
+    /// Seed of the random number generator; a random one is picked if not set
+    pub seed: Option<u64>,
+}
+
+impl Default for StormConfig {
+    fn default() -> Self {
+        Self {
+            generators: vec![(StormGenerator::Valid, 1)],
+            setting_weights: Vec::new(),
+            seed: None,
+        }
+    }
+}
+
+impl StormConfig {
+    /// Create a config without any generator
+    pub fn new() -> Self {
+        Self {
+            generators: Vec::new(),
+            ..Default::default()
+        }
+    }
+
+    /// Set the relative frequency of a generator
+    pub fn generator(mut self, generator: StormGenerator, weight: u32) -> Self {
+        self.generators
+            .retain(|(existing, _)| *existing != generator);
+        self.generators.push((generator, weight));
+        self
+    }
+
+    /// Set the relative frequency of a setting in control frames
+    pub fn setting_weight(mut self, setting: ControlSetting, weight: u32) -> Self {
+        self.setting_weights
+            .retain(|(existing, _)| *existing != setting);
+        self.setting_weights.push((setting, weight));
+        self
+    }
+
+    /// Set the seed of the random number generator, e.g. to replay a storm that crashed a firmware
+    pub fn seed(mut self, seed: u64) -> Self {
+        self.seed = Some(seed);
+        self
+    }
+}
+
+/// Endless and reproducible sequence of random data to send to a MCU
+///
+/// Two storms with the same config (including the seed) generate the same data, with the same version of this library.
+#[derive(Debug)]
+pub struct Storm {
+    seed: u64,
+    rng: StdRng,
+    generators: Vec<StormGenerator>,
+    generator_index: WeightedIndex<u32>,
+    settings: Vec<ControlSetting>,
+    setting_index: WeightedIndex<u32>,
+}
+
+impl Storm {
+    /// Create a storm
+    pub fn new(config: &StormConfig) -> Result<Self, StormConfigError> {
+        let (generators, generator_weights): (Vec<StormGenerator>, Vec<u32>) =
+            config.generators.iter().copied().unzip();
+        let generator_index =
+            WeightedIndex::new(generator_weights).map_err(|_| StormConfigError::NoGenerator)?;
+
+        let (settings, setting_weights): (Vec<ControlSetting>, Vec<u32>) = ControlSetting::iter()
+            .map(|setting| {
+                let weight = config
+                    .setting_weights
+                    .iter()
+                    .find(|(candidate, _)| *candidate == setting)
+                    .map_or_else(|| setting.random_weight(), |(_, weight)| *weight);
+                (setting, weight)
+            })
+            .unzip();
+        let setting_index =
+            WeightedIndex::new(setting_weights).map_err(|_| StormConfigError::NoSetting)?;
+
+        let seed = config.seed.unwrap_or_else(rand::random);
+        Ok(Self {
+            seed,
+            rng: StdRng::seed_from_u64(seed),
+            generators,
+            generator_index,
+            settings,
+            setting_index,
+        })
+    }
+
+    /// Seed of the storm, to be reported along with a crash to replay it
+    pub fn seed(&self) -> u64 {
+        self.seed
+    }
+
+    /// Generate a valid control message
+    pub fn next_message(&mut self) -> ControlMessage {
+        let setting = self.settings[self.setting_index.sample(&mut self.rng)];
+        let value = setting.random_value(&mut self.rng);
+        ControlMessage { setting, value }
+    }
+
+    /// Generate the next bytes to send, with the generator that made them
+    pub fn next_bytes(&mut self) -> (StormGenerator, Vec<u8>) {
+        let generator = self.generators[self.generator_index.sample(&mut self.rng)];
+        let bytes = match generator {
+            StormGenerator::Valid => self.next_message().to_control_frame(),
+            StormGenerator::Bytes => {
+                let length = self.rng.gen_range(1..=MAX_RANDOM_BYTES);
+                (0..length).map(|_| self.rng.gen()).collect()
+            }
+            StormGenerator::WrongCrc => {
+                let message = self.next_message();
+                message.to_control_frame_with(Some(self.rng.gen()))
+            }
+        };
+        (generator, bytes)
+    }
+}
+
+impl Iterator for Storm {
+    type Item = Vec<u8>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        Some(self.next_bytes().1)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn storms_are_reproducible() {
+        let config = StormConfig::new()
+            .generator(StormGenerator::Valid, 3)
+            .generator(StormGenerator::Bytes, 1)
+            .generator(StormGenerator::WrongCrc, 1)
+            .setting_weight(ControlSetting::Heartbeat, 0)
+            .seed(42);
+        let first: Vec<Vec<u8>> = Storm::new(&config).unwrap().take(1_000).collect();
+        let second: Vec<Vec<u8>> = Storm::new(&config).unwrap().take(1_000).collect();
+        assert_eq!(first, second);
+
+        let other: Vec<Vec<u8>> = Storm::new(&config.clone().seed(43))
+            .unwrap()
+            .take(1_000)
+            .collect();
+        assert_ne!(first, other);
+
+        let mut storm = Storm::new(&config).unwrap();
+        assert_eq!(storm.seed(), 42);
+        assert!((0..1_000).all(|_| storm.next_message().setting != ControlSetting::Heartbeat));
+    }
+
+    #[test]
+    fn rejects_empty_configs() {
+        assert_eq!(
+            Storm::new(&StormConfig::new()).err(),
+            Some(StormConfigError::NoGenerator)
+        );
+        let no_setting = ControlSetting::iter().fold(StormConfig::default(), |config, setting| {
+            config.setting_weight(setting, 0)
+        });
+        assert_eq!(
+            Storm::new(&no_setting).err(),
+            Some(StormConfigError::NoSetting)
+        );
+    }
+}
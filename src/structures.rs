@@ -106,16 +106,19 @@ impl TryFrom<u8> for AlarmPriority {
 }
 
 /// Supported ventilation modes
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(
     feature = "serde-messages",
     derive(serde::Serialize, serde::Deserialize)
 )]
 #[allow(non_camel_case_types)]
+#[repr(u8)]
+#[non_exhaustive]
 pub enum VentilationMode {
     /// PC-CMV
     PC_CMV = 1,
     /// PC-AC (default)
+    #[default]
     PC_AC = 2,
     /// VC-CMV
     VC_CMV = 3,
@@ -123,6 +126,8 @@ pub enum VentilationMode {
     PC_VSAI = 4,
     /// VC-AC
     VC_AC = 5,
+    /// Mode unknown to this version of the library (only produced by lenient parsing)
+    Unknown(u8),
 }
 
 /// Ventilation mode class
@@ -132,6 +137,8 @@ pub enum VentilationModeClass {
     Pressure,
     /// VC
     Volume,
+    /// Class of an unknown ventilation mode
+    Unknown,
 }
 
 /// Ventilation mode kind
@@ -143,6 +150,8 @@ pub enum VentilationModeKind {
     Ac,
     /// VSAI
     Vsai,
+    /// Kind of an unknown ventilation mode
+    Unknown,
 }
 
 impl TryFrom<u8> for VentilationMode {
@@ -163,15 +172,16 @@ impl TryFrom<u8> for VentilationMode {
     }
 }
 
-impl Default for VentilationMode {
-    fn default() -> Self {
-        Self::PC_AC
-    }
-}
-
 impl From<&VentilationMode> for u8 {
     fn from(mode: &VentilationMode) -> u8 {
-        *mode as u8
+        match mode {
+            VentilationMode::PC_CMV => 1,
+            VentilationMode::PC_AC => 2,
+            VentilationMode::VC_CMV => 3,
+            VentilationMode::PC_VSAI => 4,
+            VentilationMode::VC_AC => 5,
+            VentilationMode::Unknown(value) => *value,
+        }
     }
 }
 
@@ -181,6 +191,7 @@ impl VentilationMode {
         match self {
             Self::PC_CMV | Self::PC_AC | Self::PC_VSAI => VentilationModeClass::Pressure,
             Self::VC_CMV | Self::VC_AC => VentilationModeClass::Volume,
+            Self::Unknown(_) => VentilationModeClass::Unknown,
         }
     }
 
@@ -190,8 +201,68 @@ impl VentilationMode {
             Self::PC_CMV | Self::VC_CMV => VentilationModeKind::Cmv,
             Self::PC_AC | Self::VC_AC => VentilationModeKind::Ac,
             Self::PC_VSAI => VentilationModeKind::Vsai,
+            Self::Unknown(_) => VentilationModeKind::Unknown,
+        }
+    }
+
+    /// Iterate over every known ventilation mode
+    pub fn iter() -> impl Iterator<Item = Self> {
+        [
+            Self::PC_CMV,
+            Self::PC_AC,
+            Self::VC_CMV,
+            Self::PC_VSAI,
+            Self::VC_AC,
+        ]
+        .into_iter()
+    }
+
+    /// Short name of the ventilation mode, as displayed to users
+    ///
+    /// Supported languages are English and French; other locales fall back to English.
+    pub fn display_name(&self, locale: &Locale) -> &'static str {
+        match (locale.to_string().as_str(), self) {
+            ("fr", Self::PC_CMV) => "VPC",
+            ("fr", Self::PC_AC) => "VPAC",
+            ("fr", Self::VC_CMV) => "VC",
+            ("fr", Self::PC_VSAI) => "VS-AI",
+            ("fr", Self::VC_AC) => "VAC",
+            (_, Self::PC_CMV) => "PC-CMV",
+            (_, Self::PC_AC) => "PC-AC",
+            (_, Self::VC_CMV) => "VC-CMV",
+            (_, Self::PC_VSAI) => "PC-VSAI",
+            (_, Self::VC_AC) => "VC-AC",
+            ("fr", Self::Unknown(_)) => "Inconnu",
+            (_, Self::Unknown(_)) => "Unknown",
+        }
+    }
+
+    /// Clinical description of the ventilation mode
+    ///
+    /// Supported languages are English and French; other locales fall back to English.
+    pub fn description(&self, locale: &Locale) -> &'static str {
+        match (locale.to_string().as_str(), self) {
+            ("fr", Self::PC_CMV) => "Ventilation contrôlée en pression : chaque cycle est déclenché par le ventilateur et délivré à la pression de plateau réglée.",
+            ("fr", Self::PC_AC) => "Ventilation assistée contrôlée en pression : les cycles sont délivrés à la pression de plateau réglée et le patient peut déclencher des cycles supplémentaires.",
+            ("fr", Self::VC_CMV) => "Ventilation contrôlée en volume : chaque cycle est déclenché par le ventilateur et délivre le volume courant réglé.",
+            ("fr", Self::PC_VSAI) => "Ventilation spontanée avec aide inspiratoire : le patient déclenche chaque cycle et reçoit une aide en pression jusqu'à la baisse du débit inspiratoire ; la fréquence réglée sert de secours.",
+            ("fr", Self::VC_AC) => "Ventilation assistée contrôlée en volume : les cycles délivrent le volume courant réglé et le patient peut déclencher des cycles supplémentaires.",
+            (_, Self::PC_CMV) => "Pressure-controlled continuous mandatory ventilation: every breath is triggered by the ventilator and delivered at the set plateau pressure.",
+            (_, Self::PC_AC) => "Pressure-controlled assist-control ventilation: breaths are delivered at the set plateau pressure, and the patient can trigger additional breaths.",
+            (_, Self::VC_CMV) => "Volume-controlled continuous mandatory ventilation: every breath is triggered by the ventilator and delivers the set tidal volume.",
+            (_, Self::PC_VSAI) => "Pressure support ventilation: the patient triggers every breath and receives pressure support until the inspiratory flow decreases; the set rate is used as a backup.",
+            (_, Self::VC_AC) => "Volume-controlled assist-control ventilation: breaths deliver the set tidal volume, and the patient can trigger additional breaths.",
+            ("fr", Self::Unknown(_)) => "Mode de ventilation inconnu de cette version du logiciel.",
+            (_, Self::Unknown(_)) => "Ventilation mode unknown to this version of the software.",
         }
     }
+
+    /// Get the settings that are relevant in this ventilation mode
+    pub fn settings(&self) -> Vec<ControlSetting> {
+        ControlSetting::iter()
+            .filter(|setting| setting.applicable_in(*self))
+            .collect()
+    }
 }
 
 /// Details of fatal errors
@@ -230,6 +301,102 @@ pub enum FatalErrorDetails {
     },
 }
 
+impl FatalErrorDetails {
+    /// How serious the error is
+    ///
+    /// | Error | Severity |
+    /// | --- | --- |
+    /// | `WatchdogRestart` | `Recoverable` |
+    /// | `CalibrationError` | `ServiceRequired` |
+    /// | `BatteryDeeplyDischarged` | `ServiceRequired` |
+    /// | `MassFlowMeterError` | `OutOfService` |
+    /// | `InconsistentPressure` | `OutOfService` |
+    pub fn severity(&self) -> FatalErrorSeverity {
+        match self {
+            Self::WatchdogRestart => FatalErrorSeverity::Recoverable,
+            Self::CalibrationError { .. } | Self::BatteryDeeplyDischarged { .. } => {
+                FatalErrorSeverity::ServiceRequired
+            }
+            Self::MassFlowMeterError | Self::InconsistentPressure { .. } => {
+                FatalErrorSeverity::OutOfService
+            }
+        }
+    }
+
+    /// What users should do about the error
+    ///
+    /// | Error | Action |
+    /// | --- | --- |
+    /// | `WatchdogRestart` | `RestartAndMonitor` |
+    /// | `CalibrationError` | `Recalibrate` |
+    /// | `BatteryDeeplyDischarged` | `ChargeOrReplaceBattery` |
+    /// | `MassFlowMeterError` | `ReplaceFlowMeter` |
+    /// | `InconsistentPressure` | `CheckPressureSensor` |
+    pub fn recommended_action(&self) -> RecommendedAction {
+        match self {
+            Self::WatchdogRestart => RecommendedAction::RestartAndMonitor,
+            Self::CalibrationError { .. } => RecommendedAction::Recalibrate,
+            Self::BatteryDeeplyDischarged { .. } => RecommendedAction::ChargeOrReplaceBattery,
+            Self::MassFlowMeterError => RecommendedAction::ReplaceFlowMeter,
+            Self::InconsistentPressure { .. } => RecommendedAction::CheckPressureSensor,
+        }
+    }
+}
+
+/// How serious a fatal error is, from the least to the most serious
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum FatalErrorSeverity {
+    /// The MCU restarted; the machine can be used again once restarted
+    Recoverable,
+    /// The machine must not be used until an operator fixed the cause (calibration, battery)
+    ServiceRequired,
+    /// The machine is faulty and must be taken out of service until repaired
+    OutOfService,
+}
+
+/// Action recommended to users after a fatal error
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub enum RecommendedAction {
+    /// Restart ventilation and watch for other restarts
+    RestartAndMonitor,
+    /// Restart the machine to calibrate sensors again
+    Recalibrate,
+    /// Plug the machine to mains, and replace the battery if it does not charge
+    ChargeOrReplaceBattery,
+    /// Have the mass flow meter replaced
+    ReplaceFlowMeter,
+    /// Have the pressure sensor and its tubing checked
+    CheckPressureSensor,
+}
+
+impl RecommendedAction {
+    /// Instructions for users
+    ///
+    /// Supported languages are English and French; other locales fall back to English.
+    pub fn description(&self, locale: &Locale) -> &'static str {
+        match (locale.to_string().as_str(), self) {
+            ("fr", Self::RestartAndMonitor) => "Redémarrer la ventilation et surveiller le patient ; signaler le problème s'il se reproduit.",
+            ("fr", Self::Recalibrate) => "Redémarrer la machine pour recalibrer les capteurs, patient débranché et circuit ouvert à l'air libre.",
+            ("fr", Self::ChargeOrReplaceBattery) => "Brancher la machine sur le secteur ; remplacer la batterie si elle ne se recharge pas.",
+            ("fr", Self::ReplaceFlowMeter) => "Mettre la machine hors service et faire remplacer le débitmètre massique.",
+            ("fr", Self::CheckPressureSensor) => "Mettre la machine hors service et faire vérifier le capteur de pression et sa tubulure.",
+            (_, Self::RestartAndMonitor) => "Restart ventilation and monitor the patient; report the issue if it happens again.",
+            (_, Self::Recalibrate) => "Restart the machine to recalibrate sensors, with the patient disconnected and the circuit open to the air.",
+            (_, Self::ChargeOrReplaceBattery) => "Plug the machine to mains; replace the battery if it does not charge.",
+            (_, Self::ReplaceFlowMeter) => "Take the machine out of service and have the mass flow meter replaced.",
+            (_, Self::CheckPressureSensor) => "Take the machine out of service and have the pressure sensor and its tubing checked.",
+        }
+    }
+}
+
 /// Step of the end of line test
 #[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
 #[cfg_attr(
@@ -237,6 +404,7 @@ pub enum FatalErrorDetails {
     derive(serde::Serialize, serde::Deserialize)
 )]
 #[allow(non_camel_case_types, missing_docs)]
+#[non_exhaustive]
 pub enum EolTestStep {
     START,
     SUPPLY_TO_EXPANDER_NOT_CONNECTED,
@@ -266,6 +434,8 @@ pub enum EolTestStep {
     END_SUCCESS,
     DISPLAY_PRESSURE,
     DISPLAY_FLOW,
+    /// Step unknown to this version of the library (only produced by lenient parsing)
+    Unknown(u8),
 }
 
 impl TryFrom<u8> for EolTestStep {
@@ -309,6 +479,42 @@ impl TryFrom<u8> for EolTestStep {
     }
 }
 
+impl From<&EolTestStep> for u8 {
+    fn from(step: &EolTestStep) -> u8 {
+        match step {
+            EolTestStep::START => 0,
+            EolTestStep::SUPPLY_TO_EXPANDER_NOT_CONNECTED => 1,
+            EolTestStep::CHECK_FAN => 2,
+            EolTestStep::TEST_BAT_DEAD => 3,
+            EolTestStep::BATTERY_DEEP_DISCHARGE => 4,
+            EolTestStep::DISCONNECT_MAINS => 5,
+            EolTestStep::CONNECT_MAINS => 6,
+            EolTestStep::CHECK_BUZZER => 7,
+            EolTestStep::CHECK_ALL_BUTTONS => 8,
+            EolTestStep::CHECK_UI_SCREEN => 9,
+            EolTestStep::PLUG_AIR_TEST_SYTEM => 10,
+            EolTestStep::REACH_MAX_PRESSURE => 11,
+            EolTestStep::MAX_PRESSURE_REACHED_OK => 12,
+            EolTestStep::MAX_PRESSURE_NOT_REACHED => 13,
+            EolTestStep::START_LEAK_MESURE => 14,
+            EolTestStep::LEAK_IS_TOO_HIGH => 15,
+            EolTestStep::REACH_NULL_PRESSURE => 16,
+            EolTestStep::MIN_PRESSURE_NOT_REACHED => 17,
+            EolTestStep::USER_CONFIRMATION_BEFORE_O2_TEST => 18,
+            EolTestStep::START_O2_TEST => 19,
+            EolTestStep::O2_PRESSURE_NOT_REACH => 20,
+            EolTestStep::WAIT_USER_BEFORE_LONG_RUN => 21,
+            EolTestStep::START_LONG_RUN_BLOWER => 22,
+            EolTestStep::PRESSURE_NOT_STABLE => 23,
+            EolTestStep::FLOW_NOT_STABLE => 24,
+            EolTestStep::END_SUCCESS => 25,
+            EolTestStep::DISPLAY_PRESSURE => 26,
+            EolTestStep::DISPLAY_FLOW => 27,
+            EolTestStep::Unknown(value) => *value,
+        }
+    }
+}
+
 /// Content of end of line test snapshots
 #[derive(Debug, Clone, PartialEq, Eq)]
 #[cfg_attr(
@@ -325,13 +531,14 @@ pub enum EolTestSnapshotContent {
 }
 
 /// Patient gender
-#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
 #[cfg_attr(
     feature = "serde-messages",
     derive(serde::Serialize, serde::Deserialize)
 )]
 pub enum PatientGender {
     /// Male
+    #[default]
     Male = 0,
     /// Female
     Female = 1,
@@ -352,12 +559,6 @@ impl TryFrom<u8> for PatientGender {
     }
 }
 
-impl Default for PatientGender {
-    fn default() -> Self {
-        Self::Male
-    }
-}
-
 impl From<&PatientGender> for u8 {
     fn from(gender: &PatientGender) -> u8 {
         *gender as u8
@@ -385,10 +586,14 @@ pub struct BootMessage {
     ///
     /// This is only used to make sure that serial port was correctly opened and that there is no endianness problem.
     pub value128: u8,
+    /// [protocol v3] Control protocol extensions supported by the firmware, as a bit field (see `replay::ControlCapabilities`)
+    pub control_capabilities: Option<u16>,
+    /// [protocol v3] Nonce of the session started by this boot, to protect control frames against replays
+    pub session_nonce: Option<u32>,
 }
 
 /// A telemetry message that is sent every 100 ms when the MCU is in "stop" mode
-#[derive(Debug, Clone, PartialEq, Eq)]
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
 #[cfg_attr(
     feature = "serde-messages",
     derive(serde::Serialize, serde::Deserialize)
@@ -715,6 +920,7 @@ pub struct EolTestSnapshot {
     derive(serde::Serialize, serde::Deserialize)
 )]
 #[cfg_attr(feature = "serde-messages", serde(tag = "message_type"))]
+#[non_exhaustive]
 pub enum TelemetryMessage {
     /// A telemetry message that is sent once every time the MCU boots
     BootMessage(BootMessage),
@@ -732,6 +938,23 @@ pub enum TelemetryMessage {
     FatalError(FatalError),
     /// [protocol v2] A message sent during end of line tests
     EolTestSnapshot(EolTestSnapshot),
+    /// A valid frame containing a message type unknown to this version of the library (only produced by lenient parsing)
+    ///
+    /// Such messages are kept so that they can be recorded and reprocessed later; only their header, common to all message types, is decoded.
+    Unknown {
+        /// Version of the telemetry protocol
+        telemetry_version: u8,
+        /// Version of the MCU firmware
+        version: String,
+        /// Internal ID of the MCU
+        device_id: String,
+        /// Number of microseconds since the MCU booted
+        systick: u64,
+        /// Byte identifying the type of message
+        type_byte: u8,
+        /// Raw bytes of the message that follow its header, without CRC and footer
+        payload: Vec<u8>,
+    },
 }
 
 impl TelemetryMessage {
@@ -762,6 +985,9 @@ impl TelemetryMessage {
             Self::EolTestSnapshot(EolTestSnapshot {
                 telemetry_version, ..
             }) => telemetry_version,
+            Self::Unknown {
+                telemetry_version, ..
+            } => telemetry_version,
         };
         *val
     }
@@ -777,6 +1003,7 @@ impl TelemetryMessage {
             Self::ControlAck(ControlAck { version, .. }) => version,
             Self::FatalError(FatalError { version, .. }) => version,
             Self::EolTestSnapshot(EolTestSnapshot { version, .. }) => version,
+            Self::Unknown { version, .. } => version,
         };
         val.clone()
     }
@@ -792,6 +1019,7 @@ impl TelemetryMessage {
             Self::ControlAck(ControlAck { device_id, .. }) => device_id,
             Self::FatalError(FatalError { device_id, .. }) => device_id,
             Self::EolTestSnapshot(EolTestSnapshot { device_id, .. }) => device_id,
+            Self::Unknown { device_id, .. } => device_id,
         };
         val.clone()
     }
@@ -807,9 +1035,157 @@ impl TelemetryMessage {
             Self::ControlAck(ControlAck { systick, .. }) => systick,
             Self::FatalError(FatalError { systick, .. }) => systick,
             Self::EolTestSnapshot(EolTestSnapshot { systick, .. }) => systick,
+            Self::Unknown { systick, .. } => systick,
         };
         *val
     }
+
+    /// Get the same message as it would have been sent using an older version of the telemetry protocol
+    ///
+    /// * `version` - Version of the telemetry protocol to downgrade to.
+    ///
+    /// Fields that do not exist in the target version are set to `None` (or to their default value if they are not optional).
+    /// Returns `None` if this kind of message does not exist in the target version.
+    /// Messages are returned as is if they already use the target version (or an older one).
+    /// Messages of protocol v3 have the same layouts as in protocol v2 except boot messages, so downgrading them to v2 only changes their version (and drops the capabilities and nonce of boot messages); messages of unknown types are not downgraded, as they may not exist in older versions.
+    pub fn downgrade_to(&self, version: u8) -> Option<TelemetryMessage> {
+        if version >= self.telemetry_version() {
+            return Some(self.clone());
+        }
+        match version {
+            1 => self.downgrade_to_v1(),
+            2 => self.with_telemetry_version(2),
+            _ => None,
+        }
+    }
+
+    fn with_telemetry_version(&self, telemetry_version: u8) -> Option<TelemetryMessage> {
+        let message = match self {
+            Self::BootMessage(msg) => Self::BootMessage(BootMessage {
+                telemetry_version,
+                control_capabilities: msg.control_capabilities.filter(|_| telemetry_version >= 3),
+                session_nonce: msg.session_nonce.filter(|_| telemetry_version >= 3),
+                ..msg.clone()
+            }),
+            Self::StoppedMessage(msg) => Self::StoppedMessage(StoppedMessage {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::DataSnapshot(msg) => Self::DataSnapshot(DataSnapshot {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::MachineStateSnapshot(msg) => Self::MachineStateSnapshot(MachineStateSnapshot {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::AlarmTrap(msg) => Self::AlarmTrap(AlarmTrap {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::ControlAck(msg) => Self::ControlAck(ControlAck {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::FatalError(msg) => Self::FatalError(FatalError {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::EolTestSnapshot(msg) => Self::EolTestSnapshot(EolTestSnapshot {
+                telemetry_version,
+                ..msg.clone()
+            }),
+            Self::Unknown { .. } => return None,
+        };
+        Some(message)
+    }
+
+    fn downgrade_to_v1(&self) -> Option<TelemetryMessage> {
+        match self {
+            Self::BootMessage(msg) => Some(Self::BootMessage(BootMessage {
+                telemetry_version: 1,
+                control_capabilities: None,
+                session_nonce: None,
+                ..msg.clone()
+            })),
+            Self::StoppedMessage(msg) => Some(Self::StoppedMessage(StoppedMessage {
+                telemetry_version: 1,
+                version: msg.version.clone(),
+                device_id: msg.device_id.clone(),
+                systick: msg.systick,
+                ..StoppedMessage::default()
+            })),
+            Self::DataSnapshot(msg) => Some(Self::DataSnapshot(DataSnapshot {
+                telemetry_version: 1,
+                inspiratory_flow: None,
+                expiratory_flow: None,
+                ..msg.clone()
+            })),
+            Self::MachineStateSnapshot(msg) => {
+                Some(Self::MachineStateSnapshot(MachineStateSnapshot {
+                    telemetry_version: 1,
+                    version: msg.version.clone(),
+                    device_id: msg.device_id.clone(),
+                    systick: msg.systick,
+                    cycle: msg.cycle,
+                    peak_command: msg.peak_command,
+                    plateau_command: msg.plateau_command,
+                    peep_command: msg.peep_command,
+                    cpm_command: msg.cpm_command,
+                    previous_peak_pressure: msg.previous_peak_pressure,
+                    previous_plateau_pressure: msg.previous_plateau_pressure,
+                    previous_peep_pressure: msg.previous_peep_pressure,
+                    current_alarm_codes: msg.current_alarm_codes.clone(),
+                    previous_volume: msg.previous_volume,
+                    expiratory_term: msg.expiratory_term,
+                    trigger_enabled: msg.trigger_enabled,
+                    trigger_offset: msg.trigger_offset,
+                    ..MachineStateSnapshot::default()
+                }))
+            }
+            Self::AlarmTrap(msg) => Some(Self::AlarmTrap(AlarmTrap {
+                telemetry_version: 1,
+                ..msg.clone()
+            })),
+            Self::ControlAck(msg) => Some(Self::ControlAck(ControlAck {
+                telemetry_version: 1,
+                ..msg.clone()
+            })),
+            Self::FatalError(_) | Self::EolTestSnapshot(_) | Self::Unknown { .. } => None,
+        }
+    }
+
+    /// Whether the message contains values that are unknown to this version of the library
+    ///
+    /// This can only happen with messages parsed in lenient mode (see `parsers::ParsingMode`).
+    pub fn has_unknown_values(&self) -> bool {
+        match self {
+            Self::StoppedMessage(StoppedMessage {
+                ventilation_mode, ..
+            })
+            | Self::MachineStateSnapshot(MachineStateSnapshot {
+                ventilation_mode, ..
+            }) => matches!(ventilation_mode, VentilationMode::Unknown(_)),
+            Self::EolTestSnapshot(EolTestSnapshot { current_step, .. }) => {
+                matches!(current_step, EolTestStep::Unknown(_))
+            }
+            Self::Unknown { .. } => true,
+            _ => false,
+        }
+    }
+}
+
+impl BootMessage {
+    /// Check that the link with the MCU is properly configured, using the known value of `value128`
+    pub fn check_link(&self) -> Result<(), HighLevelError> {
+        if self.value128 == 128 {
+            Ok(())
+        } else {
+            Err(HighLevelError::LinkMisconfigured {
+                value128: self.value128,
+            })
+        }
+    }
 }
 
 /// Extension of Nom's `ErrorKind` to be able to represent CRC errors
@@ -889,12 +1265,26 @@ pub enum HighLevelError {
         /// Found version of the telemetry protocol
         found: u8,
     },
+    /// Boot message contains an unexpected value in `value128`, which means bytes are not read as expected (endianness, baud rate, etc.)
+    #[error("value128 should be equal to 128 (found {value128:b} = {value128}); check serial port configuration")]
+    LinkMisconfigured {
+        /// Found value (128 is expected)
+        value128: u8,
+    },
+    /// The serial line seems to be held low (break condition), e.g. because the MCU is powered off or the cable is cut
+    #[error("break condition detected on the serial line ({zeros} consecutive NUL bytes); check the cable and the MCU")]
+    BreakCondition {
+        /// Number of consecutive NUL bytes that were received
+        zeros: usize,
+    },
 }
 
 #[cfg(test)]
 mod tests {
-    use crate::structures::AlarmPriority;
+    use crate::locale::Locale;
+    use crate::structures::*;
     use std::cmp::Ordering;
+    use std::convert::TryFrom;
 
     #[test]
     fn order_alarm_priority() {
@@ -917,4 +1307,141 @@ mod tests {
         assert_eq!(high.cmp(&low), Ordering::Greater);
         assert_eq!(medium.cmp(&low), Ordering::Greater);
     }
+
+    #[test]
+    fn ventilation_mode_names() {
+        let fr = Locale::try_from("fr").unwrap();
+        let de = Locale::try_from("de").unwrap();
+
+        assert_eq!(VentilationMode::iter().count(), 5);
+        assert_eq!(
+            VentilationMode::PC_AC.display_name(&Locale::default()),
+            "PC-AC"
+        );
+        assert_eq!(VentilationMode::PC_AC.display_name(&fr), "VPAC");
+        assert_eq!(
+            VentilationMode::VC_AC.description(&de),
+            VentilationMode::VC_AC.description(&Locale::default())
+        );
+    }
+
+    #[test]
+    fn downgrade_to_v1() {
+        let stopped = TelemetryMessage::StoppedMessage(StoppedMessage {
+            telemetry_version: 2,
+            version: "v2".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            peak_command: Some(30),
+            ventilation_mode: VentilationMode::VC_CMV,
+            ..StoppedMessage::default()
+        });
+        let fatal_error = TelemetryMessage::FatalError(FatalError {
+            telemetry_version: 2,
+            version: "v2".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            error: FatalErrorDetails::WatchdogRestart,
+        });
+
+        assert_eq!(
+            stopped.downgrade_to(1),
+            Some(TelemetryMessage::StoppedMessage(StoppedMessage {
+                telemetry_version: 1,
+                version: "v2".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 42,
+                ..StoppedMessage::default()
+            }))
+        );
+        assert_eq!(stopped.downgrade_to(2), Some(stopped.clone()));
+        assert_eq!(fatal_error.downgrade_to(1), None);
+    }
+
+    #[test]
+    fn downgrade_v3_messages() {
+        let snapshot = DataSnapshot {
+            telemetry_version: 3,
+            version: "v3".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            centile: 10,
+            pressure: 200,
+            phase: Phase::Inhalation,
+            subphase: None,
+            blower_valve_position: 50,
+            patient_valve_position: 60,
+            blower_rpm: 120,
+            battery_level: 25,
+            inspiratory_flow: Some(300),
+            expiratory_flow: Some(-100),
+        };
+        let fatal_error = TelemetryMessage::FatalError(FatalError {
+            telemetry_version: 3,
+            version: "v3".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            error: FatalErrorDetails::WatchdogRestart,
+        });
+        let unknown = TelemetryMessage::Unknown {
+            telemetry_version: 3,
+            version: "v3".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick: 42,
+            type_byte: b'Z',
+            payload: vec![1, 2, 3],
+        };
+
+        // Only the version changes between v3 and v2
+        assert_eq!(
+            TelemetryMessage::DataSnapshot(snapshot.clone()).downgrade_to(2),
+            Some(TelemetryMessage::DataSnapshot(DataSnapshot {
+                telemetry_version: 2,
+                ..snapshot.clone()
+            }))
+        );
+        assert_eq!(
+            fatal_error.downgrade_to(2),
+            Some(TelemetryMessage::FatalError(FatalError {
+                telemetry_version: 2,
+                version: "v3".to_owned(),
+                device_id: "1-2-3".to_owned(),
+                systick: 42,
+                error: FatalErrorDetails::WatchdogRestart,
+            }))
+        );
+        assert_eq!(unknown.downgrade_to(2), None);
+
+        // Fields and messages that appeared in v2 are dropped in v1
+        assert_eq!(
+            TelemetryMessage::DataSnapshot(snapshot.clone()).downgrade_to(1),
+            Some(TelemetryMessage::DataSnapshot(DataSnapshot {
+                telemetry_version: 1,
+                inspiratory_flow: None,
+                expiratory_flow: None,
+                ..snapshot
+            }))
+        );
+        assert_eq!(fatal_error.downgrade_to(1), None);
+        assert_eq!(fatal_error.downgrade_to(0), None);
+    }
+
+    #[test]
+    fn fatal_error_guidance() {
+        let battery = FatalErrorDetails::BatteryDeeplyDischarged {
+            battery_level: 2000,
+        };
+        assert_eq!(battery.severity(), FatalErrorSeverity::ServiceRequired);
+        assert_eq!(
+            battery.recommended_action(),
+            RecommendedAction::ChargeOrReplaceBattery
+        );
+        assert!(FatalErrorDetails::MassFlowMeterError.severity() > battery.severity());
+        assert_eq!(
+            FatalErrorDetails::WatchdogRestart
+                .recommended_action()
+                .description(&Locale::default()),
+            "Restart ventilation and monitor the patient; report the issue if it happens again."
+        );
+    }
 }
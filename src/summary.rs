@@ -0,0 +1,354 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::adapter::MessageAdapter;
+use crate::anomaly::CycleMetrics;
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+use crate::sink::TelemetrySink;
+use crate::structures::{MachineStateSnapshot, TelemetryMessage, VentilationMode};
+use crate::volume::{BreathVolumes, VolumeIntegrator};
+
+/// Settings commanded during a cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct CommandedSettings {
+    /// Requested peak pressure in cmH2O
+    pub peak_pressure: u8,
+    /// Requested plateau pressure in cmH2O
+    pub plateau_pressure: u8,
+    /// Requested PEEP in cmH2O
+    pub peep: u8,
+    /// Requested number of cycles per minute
+    pub cycles_per_minute: u8,
+    /// [protocol v2] Target tidal volume in mL
+    pub target_tidal_volume: Option<u16>,
+    /// [protocol v2] Target flow during inspiration in L/min
+    pub target_inspiratory_flow: Option<u8>,
+    /// [protocol v2] Requested duration of inspiration in ms
+    pub inspiratory_duration: Option<u16>,
+}
+
+impl From<&MachineStateSnapshot> for CommandedSettings {
+    fn from(snapshot: &MachineStateSnapshot) -> Self {
+        Self {
+            peak_pressure: snapshot.peak_command,
+            plateau_pressure: snapshot.plateau_command,
+            peep: snapshot.peep_command,
+            cycles_per_minute: snapshot.cpm_command,
+            target_tidal_volume: snapshot.target_tidal_volume,
+            target_inspiratory_flow: snapshot.target_inspiratory_flow,
+            inspiratory_duration: snapshot.inspiratory_duration_command,
+        }
+    }
+}
+
+/// Metrics computed from the messages of a cycle rather than reported by the firmware
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct DerivedMetrics {
+    /// Duration of the cycle in ms, since the previous machine state snapshot (unknown for the first cycle)
+    pub duration: Option<u64>,
+    /// Mean pressure of the data snapshots of the cycle, in mmH2O
+    pub mean_pressure: Option<f64>,
+    /// Tidal volume multiplied by the measured respiratory rate, in L/min
+    pub minute_volume: Option<f64>,
+    /// Expiration term of the I:E ratio given that inspiration = 1, from the measured inspiratory duration
+    pub ie_ratio: Option<f64>,
+}
+
+/// Compact summary of one breathing cycle, to be ingested by dashboards instead of waveforms
+#[derive(Debug, Clone, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct CycleSummary {
+    /// Internal ID of the MCU
+    pub device_id: String,
+    /// Ventilation mode during the cycle
+    pub ventilation_mode: VentilationMode,
+    /// Settings commanded during the cycle
+    pub settings: CommandedSettings,
+    /// Values measured by the firmware
+    pub measured: CycleMetrics,
+    /// Codes of the alarms that were triggered at the end of the cycle
+    pub active_alarms: Vec<u8>,
+    /// Metrics computed from the messages of the cycle
+    pub derived: DerivedMetrics,
+}
+
+impl CycleSummary {
+    /// Serialize the summary as a single line of JSON
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[cfg_attr(
+        doc_cfg,
+        doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+    )]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("cycle summaries are always serializable")
+    }
+}
+
+/// Produces a `CycleSummary` at the end of each cycle (on each machine state snapshot)
+#[derive(Debug, Default)]
+pub struct CycleSummarizer {
+    volumes: VolumeIntegrator,
+    last_systick: Option<u64>,
+    pressure_sum: f64,
+    pressure_count: usize,
+}
+
+impl CycleSummarizer {
+    /// Create a summarizer waiting for its first cycle
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn summarize(
+        &mut self,
+        snapshot: &MachineStateSnapshot,
+        volumes: Option<&BreathVolumes>,
+    ) -> CycleSummary {
+        let measured = CycleMetrics::new(snapshot, volumes);
+
+        let duration = self
+            .last_systick
+            .replace(snapshot.systick)
+            .filter(|last| *last < snapshot.systick)
+            .map(|last| (snapshot.systick - last) / 1_000);
+        let mean_pressure =
+            (self.pressure_count > 0).then(|| self.pressure_sum / self.pressure_count as f64);
+        self.pressure_sum = 0.0;
+        self.pressure_count = 0;
+        let minute_volume = measured
+            .tidal_volume
+            .zip(measured.respiratory_rate)
+            .map(|(volume, rate)| f64::from(volume) * f64::from(rate) / 1_000.0);
+        let ie_ratio = snapshot
+            .previous_inspiratory_duration
+            .zip(measured.respiratory_rate)
+            .filter(|(inspiration, rate)| *inspiration > 0 && *rate > 0)
+            .map(|(inspiration, rate)| {
+                let inspiration = f64::from(inspiration);
+                (60_000.0 / f64::from(rate) - inspiration) / inspiration
+            });
+
+        CycleSummary {
+            device_id: snapshot.device_id.clone(),
+            ventilation_mode: snapshot.ventilation_mode,
+            settings: CommandedSettings::from(snapshot),
+            measured,
+            active_alarms: snapshot.current_alarm_codes.clone(),
+            derived: DerivedMetrics {
+                duration,
+                mean_pressure,
+                minute_volume,
+                ie_ratio,
+            },
+        }
+    }
+
+    fn reset(&mut self) {
+        self.last_systick = None;
+        self.pressure_sum = 0.0;
+        self.pressure_count = 0;
+    }
+}
+
+impl MessageAdapter for CycleSummarizer {
+    type Output = CycleSummary;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<CycleSummary> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => {
+                self.volumes.handle(message);
+                self.pressure_sum += f64::from(snapshot.pressure);
+                self.pressure_count += 1;
+                Vec::new()
+            }
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                let volumes = self.volumes.handle(message);
+                vec![self.summarize(snapshot, volumes.first())]
+            }
+            TelemetryMessage::StoppedMessage(_) | TelemetryMessage::BootMessage(_) => {
+                self.volumes.handle(message);
+                self.reset();
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+/// Sink that writes a summary of each cycle as JSON, one summary per line (NDJSON)
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+#[cfg_attr(
+    doc_cfg,
+    doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+)]
+pub struct CycleSummarySink<W: std::io::Write> {
+    writer: W,
+    summarizers: std::collections::HashMap<String, CycleSummarizer>,
+}
+
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+impl<W: std::io::Write> CycleSummarySink<W> {
+    /// Create a sink that writes summaries with the given writer (use a `LineWriter` to flush every line)
+    pub fn new(writer: W) -> Self {
+        Self {
+            writer,
+            summarizers: std::collections::HashMap::new(),
+        }
+    }
+}
+
+#[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+impl<W: std::io::Write> TelemetrySink for CycleSummarySink<W> {
+    fn consume(&mut self, message: &crate::TimedMessage) {
+        if let Ok(message) = &message.message {
+            // Devices are summarized separately, as their cycles are interleaved
+            let summaries = self
+                .summarizers
+                .entry(message.device_id())
+                .or_default()
+                .handle(message);
+            for summary in summaries {
+                if let Err(e) = writeln!(self.writer, "{}", summary.to_json()) {
+                    log::error!("failed writing cycle summary: {:?}", e);
+                }
+            }
+        }
+    }
+
+    fn flush(&mut self) {
+        if let Err(e) = self.writer.flush() {
+            log::error!("failed flushing cycle summaries: {:?}", e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+
+    #[test]
+    fn summarizes_each_cycle() {
+        let mut summarizer = CycleSummarizer::new();
+        let summaries: Vec<CycleSummary> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(2_000)
+                .flat_map(|message| summarizer.handle(&message))
+                .collect();
+
+        assert!(summaries.len() > 3);
+        let summary = &summaries[2];
+        assert_eq!(summary.device_id, "1-2-3");
+        assert!(summary.derived.duration.unwrap() > 0);
+        assert!(summary.derived.mean_pressure.unwrap() > 0.0);
+        assert!(summary.derived.minute_volume.unwrap() > 0.0);
+        assert!(summary.measured.leak_flow.is_some());
+        assert!(summaries[0].derived.duration.is_none());
+    }
+
+    fn machine_state_snapshot() -> MachineStateSnapshot {
+        SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+            .find_map(|message| match message {
+                TelemetryMessage::MachineStateSnapshot(snapshot) => Some(snapshot),
+                _ => None,
+            })
+            .unwrap()
+    }
+
+    #[test]
+    fn starts_over_on_stops_and_reboots() {
+        let mut summarizer = CycleSummarizer::new();
+        let snapshot = machine_state_snapshot();
+        let later = MachineStateSnapshot {
+            systick: snapshot.systick + 3_000_000,
+            ..snapshot.clone()
+        };
+
+        summarizer.handle(&TelemetryMessage::MachineStateSnapshot(snapshot.clone()));
+        let summary = summarizer
+            .handle(&TelemetryMessage::MachineStateSnapshot(later.clone()))
+            .remove(0);
+        assert_eq!(summary.derived.duration, Some(3_000));
+        // No data snapshot was received during the cycle
+        assert_eq!(summary.derived.mean_pressure, None);
+
+        // Systicks restart after a reboot, even when its boot message was lost
+        let summary = summarizer
+            .handle(&TelemetryMessage::MachineStateSnapshot(snapshot.clone()))
+            .remove(0);
+        assert_eq!(summary.derived.duration, None);
+
+        summarizer.handle(&TelemetryMessage::MachineStateSnapshot(snapshot));
+        summarizer.handle(&TelemetryMessage::StoppedMessage(Default::default()));
+        let summary = summarizer
+            .handle(&TelemetryMessage::MachineStateSnapshot(later))
+            .remove(0);
+        assert_eq!(summary.derived.duration, None);
+    }
+
+    #[test]
+    fn skips_undefined_ratios() {
+        let mut summarizer = CycleSummarizer::new();
+        let snapshot = MachineStateSnapshot {
+            previous_inspiratory_duration: Some(0),
+            ..machine_state_snapshot()
+        };
+        let summary = summarizer
+            .handle(&TelemetryMessage::MachineStateSnapshot(snapshot.clone()))
+            .remove(0);
+        assert_eq!(summary.derived.ie_ratio, None);
+
+        // Protocol v1 does not report the inspiratory duration
+        let summary = summarizer
+            .handle(&TelemetryMessage::MachineStateSnapshot(
+                MachineStateSnapshot {
+                    previous_inspiratory_duration: None,
+                    ..snapshot
+                },
+            ))
+            .remove(0);
+        assert_eq!(summary.derived.ie_ratio, None);
+    }
+
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[test]
+    fn writes_summaries_of_each_device() {
+        use crate::source::{SourceInfo, SourceKind};
+        use crate::TimedMessage;
+
+        let mut sink = CycleSummarySink::new(Vec::new());
+        let source = SourceInfo::new(SourceKind::Bytes, None);
+        let snapshot = machine_state_snapshot();
+        for (device_id, delay) in [("1-2-3", 0), ("4-5-6", 1_000_000), ("1-2-3", 3_000_000)] {
+            let message = TelemetryMessage::MachineStateSnapshot(MachineStateSnapshot {
+                device_id: device_id.to_owned(),
+                systick: snapshot.systick + delay,
+                ..snapshot.clone()
+            });
+            sink.consume(&TimedMessage::now(Ok(message), source.clone()));
+        }
+        sink.flush();
+
+        let output = String::from_utf8(sink.writer).unwrap();
+        let lines: Vec<&str> = output.lines().collect();
+        assert_eq!(lines.len(), 3);
+        assert!(lines[1].contains(r#""device_id":"4-5-6""#));
+        // Cycles of other devices do not count in the duration
+        assert!(lines[0].contains(r#""duration":null"#));
+        assert!(lines[1].contains(r#""duration":null"#));
+        assert!(lines[2].contains(r#""duration":3000"#));
+    }
+}
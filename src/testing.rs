@@ -0,0 +1,148 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fmt::{Debug, Write as _};
+use std::path::Path;
+
+use crate::adapter::MessageAdapter;
+use crate::reader::TelemetryFileReader;
+use crate::structures::TelemetryMessage;
+
+/// Name of the environment variable that makes `assert_snapshot()` write golden files instead of comparing them
+pub const UPDATE_SNAPSHOTS_ENV: &str = "MAKAIR_UPDATE_SNAPSHOTS";
+
+/// Outputs produced by an adapter for one message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReplayStep<O> {
+    /// Position of the message in the replayed sequence
+    pub index: usize,
+    /// Systick of the message
+    pub systick: u64,
+    /// Outputs produced by the adapter (never empty)
+    pub outputs: Vec<O>,
+}
+
+/// Read every message of a recording, skipping errors
+///
+/// * `path` - Path to a recording.
+pub fn read_recording<P: AsRef<Path>>(path: P) -> std::io::Result<Vec<TelemetryMessage>> {
+    Ok(TelemetryFileReader::open(path)?
+        .filter_map(Result::ok)
+        .collect())
+}
+
+/// Feed messages to an adapter and keep track of what it produced
+///
+/// * `events` - Messages to replay, usually obtained with `read_recording()`.
+/// * `adapter` - Adapter (or state machine) under test.
+///
+/// Only messages that produced outputs are returned; replaying the same messages always gives the same steps for a deterministic adapter.
+pub fn replay<'a, I, A>(events: I, adapter: &mut A) -> Vec<ReplayStep<A::Output>>
+where
+    I: IntoIterator<Item = &'a TelemetryMessage>,
+    A: MessageAdapter,
+{
+    events
+        .into_iter()
+        .enumerate()
+        .filter_map(|(index, message)| {
+            let outputs = adapter.handle(message);
+            if outputs.is_empty() {
+                None
+            } else {
+                Some(ReplayStep {
+                    index,
+                    systick: message.systick(),
+                    outputs,
+                })
+            }
+        })
+        .collect()
+}
+
+/// Render replay steps as stable text, one output per line, suitable for golden files
+pub fn snapshot<O: Debug>(steps: &[ReplayStep<O>]) -> String {
+    let mut output = String::new();
+    for step in steps {
+        for item in &step.outputs {
+            // Writing to a String cannot fail
+            let _ = writeln!(output, "#{} @{}: {:?}", step.index, step.systick, item);
+        }
+    }
+    output
+}
+
+/// Compare a snapshot with the content of a golden file, and panic if they differ
+///
+/// * `actual` - Snapshot, usually obtained with `snapshot()`.
+/// * `golden_path` - Path to the expected snapshot.
+///
+/// If the `MAKAIR_UPDATE_SNAPSHOTS` environment variable is set, the golden file is written instead.
+pub fn assert_snapshot<P: AsRef<Path>>(actual: &str, golden_path: P) {
+    let golden_path = golden_path.as_ref();
+    if std::env::var_os(UPDATE_SNAPSHOTS_ENV).is_some() {
+        std::fs::write(golden_path, actual).expect("failed to write golden file");
+        return;
+    }
+
+    let expected = std::fs::read_to_string(golden_path).unwrap_or_else(|e| {
+        panic!(
+            "failed to read golden file {} ({}); set {} to create it",
+            golden_path.display(),
+            e,
+            UPDATE_SNAPSHOTS_ENV
+        )
+    });
+    if let Some((line, (expected_line, actual_line))) = expected
+        .lines()
+        .zip(actual.lines())
+        .enumerate()
+        .find(|(_, (e, a))| e != a)
+    {
+        panic!(
+            "snapshot differs from {} at line {}:\n  expected: {}\n  actual:   {}",
+            golden_path.display(),
+            line + 1,
+            expected_line,
+            actual_line
+        );
+    }
+    assert_eq!(
+        expected.lines().count(),
+        actual.lines().count(),
+        "snapshot and {} do not have the same number of lines",
+        golden_path.display()
+    );
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn replay_recording() {
+        let messages = read_recording("records/v2/short.record").unwrap();
+        let mut boots = 0;
+        let mut count_boots = |message: &TelemetryMessage| match message {
+            TelemetryMessage::BootMessage(_) => {
+                boots += 1;
+                vec![boots]
+            }
+            _ => vec![],
+        };
+
+        let steps = replay(&messages, &mut count_boots);
+        assert!(!messages.is_empty());
+        assert!(steps.iter().all(|step| step.outputs.len() == 1));
+
+        let text = snapshot(&steps);
+        assert_eq!(text.lines().count(), steps.len());
+
+        let golden_path = std::env::temp_dir().join("makair-telemetry-replay.snapshot");
+        std::fs::write(&golden_path, &text).unwrap();
+        assert_snapshot(&text, &golden_path);
+        std::fs::remove_file(&golden_path).unwrap();
+    }
+}
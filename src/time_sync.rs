@@ -0,0 +1,494 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::{HashMap, VecDeque};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::control::{ControlMessage, ControlSetting};
+use crate::sink::TelemetrySink;
+use crate::structures::{ControlAck, TelemetryMessage};
+use crate::TimedMessage;
+
+/// Maximum number of time synchronization requests waiting for their ACK
+const MAX_PENDING_REQUESTS: usize = 16;
+
+/// Default time during which received messages are used to estimate the clock offset of a device
+pub const DEFAULT_SKEW_WINDOW: Duration = Duration::from_secs(60);
+
+/// Mapping between the MCU clock (systick) and the host wall-clock
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ClockMapping {
+    /// Systick (in microseconds) of the reference point
+    pub systick: u64,
+    /// Host wall-clock time of the reference point
+    pub wall_clock: SystemTime,
+    /// Maximum error of this mapping (half of the round-trip time of the synchronization request)
+    pub uncertainty: Duration,
+}
+
+impl ClockMapping {
+    /// Create a mapping from a systick observed at a given host time
+    pub fn new(systick: u64, wall_clock: SystemTime, uncertainty: Duration) -> Self {
+        ClockMapping {
+            systick,
+            wall_clock,
+            uncertainty,
+        }
+    }
+
+    /// Offset in microseconds between the host wall-clock (since UNIX epoch) and the MCU systick
+    ///
+    /// Adding this offset to a systick gives the number of microseconds since UNIX epoch.
+    pub fn offset_micros(&self) -> i128 {
+        unix_micros(self.wall_clock) - i128::from(self.systick)
+    }
+
+    /// Convert a systick into an absolute wall-clock time
+    pub fn wall_clock(&self, systick: u64) -> SystemTime {
+        if systick >= self.systick {
+            self.wall_clock + Duration::from_micros(systick - self.systick)
+        } else {
+            self.wall_clock - Duration::from_micros(self.systick - systick)
+        }
+    }
+
+    /// Convert a systick into a number of microseconds since UNIX epoch
+    pub fn wall_clock_micros(&self, systick: u64) -> i128 {
+        i128::from(systick) + self.offset_micros()
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct PendingRequest {
+    token: u16,
+    sent_at: SystemTime,
+}
+
+/// Helper to synchronize the host wall-clock with the MCU clock using the `TimeSync` control setting
+///
+/// Send the control messages returned by `request()` to the MCU, then pass every `ControlAck` to `handle_ack()`.
+/// The MCU gets the host wall-clock (to the second) with every request, while the host computes a finer mapping from the round-trip time of requests; the mapping with the smallest uncertainty is kept.
+#[derive(Debug, Default)]
+pub struct ClockSynchronizer {
+    pending: Vec<PendingRequest>,
+    mapping: Option<ClockMapping>,
+    next_token: u16,
+}
+
+impl ClockSynchronizer {
+    /// Create a new synchronizer without any mapping
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Create the control messages of a time synchronization request, to be sent to the MCU in order
+    ///
+    /// * `now` - Host wall-clock at the time the messages are sent.
+    ///
+    /// The host wall-clock is split across the `WallClockHigh` and `WallClockLow` settings, followed by the `TimeSync` setting.
+    /// Every request gets a new token, so that ACKs are matched with the right request even if several requests are sent within the same second.
+    pub fn request(&mut self, now: SystemTime) -> Vec<ControlMessage> {
+        let token = self.next_token;
+        self.next_token = self.next_token.wrapping_add(1);
+
+        if self.pending.len() >= MAX_PENDING_REQUESTS {
+            self.pending.remove(0);
+        }
+        self.pending.push(PendingRequest {
+            token,
+            sent_at: now,
+        });
+
+        let seconds = u32::try_from(now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs())
+            .unwrap_or(u32::MAX);
+        vec![
+            ControlMessage {
+                setting: ControlSetting::WallClockHigh,
+                value: (seconds >> 16) as u16,
+            },
+            ControlMessage {
+                setting: ControlSetting::WallClockLow,
+                value: seconds as u16,
+            },
+            ControlMessage {
+                setting: ControlSetting::TimeSync,
+                value: token,
+            },
+        ]
+    }
+
+    /// Handle a `ControlAck` and update the mapping if it acknowledges a pending time synchronization request
+    ///
+    /// * `ack` - ACK received from the MCU.
+    /// * `received_at` - Host wall-clock at the time the ACK was received.
+    ///
+    /// Returns the new mapping if it is more accurate than the previous one.
+    pub fn handle_ack(
+        &mut self,
+        ack: &ControlAck,
+        received_at: SystemTime,
+    ) -> Option<ClockMapping> {
+        if ack.setting != ControlSetting::TimeSync {
+            return None;
+        }
+
+        let index = self
+            .pending
+            .iter()
+            .position(|request| request.token == ack.value)?;
+        let request = self.pending.remove(index);
+
+        // The MCU handled the request somewhere between sending and receiving; assume the middle
+        let round_trip = received_at
+            .duration_since(request.sent_at)
+            .unwrap_or_default();
+        let uncertainty = round_trip / 2;
+        let mapping = ClockMapping::new(ack.systick, request.sent_at + uncertainty, uncertainty);
+
+        match self.mapping {
+            Some(current) if current.uncertainty <= mapping.uncertainty => None,
+            _ => {
+                self.mapping = Some(mapping);
+                Some(mapping)
+            }
+        }
+    }
+
+    /// Best mapping computed so far
+    pub fn mapping(&self) -> Option<ClockMapping> {
+        self.mapping
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Observation {
+    systick: u64,
+    received_at: SystemTime,
+    offset: i128,
+}
+
+#[derive(Debug, Default)]
+struct DeviceClock {
+    last_systick: Option<u64>,
+    /// Observations of the window with increasing offsets; the first one has the smallest offset
+    minima: VecDeque<Observation>,
+    synchronized: Option<ClockMapping>,
+}
+
+impl DeviceClock {
+    fn observe(&mut self, systick: u64, received_at: SystemTime, window: Duration) {
+        if self.last_systick.is_some_and(|last| systick < last) {
+            // The MCU rebooted: its systick starts over
+            self.minima.clear();
+            self.synchronized = None;
+        }
+        self.last_systick = Some(systick);
+
+        let observation = Observation {
+            systick,
+            received_at,
+            offset: unix_micros(received_at) - i128::from(systick),
+        };
+        while self
+            .minima
+            .back()
+            .is_some_and(|last| last.offset >= observation.offset)
+        {
+            self.minima.pop_back();
+        }
+        self.minima.push_back(observation);
+        let window_start = received_at.checked_sub(window).unwrap_or(UNIX_EPOCH);
+        while self
+            .minima
+            .front()
+            .is_some_and(|first| first.received_at < window_start)
+        {
+            self.minima.pop_front();
+        }
+    }
+
+    fn mapping(&self) -> Option<ClockMapping> {
+        self.synchronized.or_else(|| {
+            self.minima.front().map(|observation| {
+                ClockMapping::new(observation.systick, observation.received_at, Duration::ZERO)
+            })
+        })
+    }
+}
+
+/// Estimation of the clock offset of many devices, to put their messages on a common timeline
+///
+/// Offsets are estimated passively from the host time at which messages are received: as a message cannot be received before it was sent, the smallest difference between host time and systick over a recent window is the best estimate.
+/// Aligned times are thus late by the smallest delivery latency, which is about the same for devices connected the same way.
+/// The window lets the estimate follow the drift of the MCU clock; mappings from active synchronization (see `ClockSynchronizer`) take precedence until the device reboots.
+#[derive(Debug)]
+pub struct FleetClock {
+    window: Duration,
+    devices: HashMap<String, DeviceClock>,
+}
+
+impl Default for FleetClock {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl FleetClock {
+    /// Create an estimator using the default window
+    pub fn new() -> Self {
+        Self::with_window(DEFAULT_SKEW_WINDOW)
+    }
+
+    /// Create an estimator using messages received during `window` to estimate offsets
+    pub fn with_window(window: Duration) -> Self {
+        Self {
+            window,
+            devices: HashMap::new(),
+        }
+    }
+
+    /// Use a message to refine the offset of its device
+    ///
+    /// * `message` - Message received from the device.
+    /// * `received_at` - Host wall-clock at the time the message was received.
+    pub fn observe(&mut self, message: &TelemetryMessage, received_at: SystemTime) {
+        self.devices
+            .entry(message.device_id())
+            .or_default()
+            .observe(message.systick(), received_at, self.window);
+    }
+
+    /// Use a mapping computed by active synchronization for a device, instead of the estimated one
+    pub fn set_mapping(&mut self, device_id: &str, mapping: ClockMapping) {
+        self.devices
+            .entry(device_id.to_owned())
+            .or_default()
+            .synchronized = Some(mapping);
+    }
+
+    /// Current mapping between the clock of a device and the host wall-clock
+    pub fn mapping(&self, device_id: &str) -> Option<ClockMapping> {
+        self.devices.get(device_id).and_then(DeviceClock::mapping)
+    }
+
+    /// Offsets in microseconds between the host wall-clock (since UNIX epoch) and the systick of every known device
+    pub fn offsets(&self) -> Vec<(String, i128)> {
+        let mut offsets: Vec<(String, i128)> = self
+            .devices
+            .iter()
+            .filter_map(|(device_id, clock)| {
+                Some((device_id.clone(), clock.mapping()?.offset_micros()))
+            })
+            .collect();
+        offsets.sort();
+        offsets
+    }
+
+    /// Host wall-clock time at which a message was sent, according to the mapping of its device
+    pub fn aligned_time(&self, message: &TelemetryMessage) -> Option<SystemTime> {
+        self.mapping(&message.device_id())
+            .map(|mapping| mapping.wall_clock(message.systick()))
+    }
+
+    /// Observe a received message, and get the time at which it was sent on the common timeline
+    pub fn align(&mut self, timed_message: &TimedMessage) -> Option<SystemTime> {
+        let message = timed_message.message.as_ref().ok()?;
+        self.observe(message, timed_message.received_at);
+        self.aligned_time(message)
+    }
+}
+
+impl TelemetrySink for FleetClock {
+    fn consume(&mut self, message: &TimedMessage) {
+        if let Ok(inner) = &message.message {
+            self.observe(inner, message.received_at);
+        }
+    }
+}
+
+fn unix_micros(time: SystemTime) -> i128 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(duration) => duration.as_micros() as i128,
+        Err(e) => -(e.duration().as_micros() as i128),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ack(systick: u64, setting: ControlSetting, value: u16) -> ControlAck {
+        ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "0-0-0".to_owned(),
+            systick,
+            setting,
+            value,
+        }
+    }
+
+    /// Token of a time synchronization request
+    fn token(request: &[ControlMessage]) -> u16 {
+        request.last().unwrap().value
+    }
+
+    #[test]
+    fn mapping_converts_systicks() {
+        let reference = UNIX_EPOCH + Duration::from_secs(1_000);
+        let mapping = ClockMapping::new(5_000_000, reference, Duration::ZERO);
+
+        assert_eq!(mapping.offset_micros(), 995_000_000);
+        assert_eq!(
+            mapping.wall_clock(6_000_000),
+            reference + Duration::from_secs(1)
+        );
+        assert_eq!(
+            mapping.wall_clock(4_000_000),
+            reference - Duration::from_secs(1)
+        );
+        assert_eq!(mapping.wall_clock_micros(0), 995_000_000);
+    }
+
+    #[test]
+    fn synchronizer_uses_round_trip_middle() {
+        let mut synchronizer = ClockSynchronizer::new();
+        let sent_at = UNIX_EPOCH + Duration::from_secs(70_000);
+        let request = synchronizer.request(sent_at);
+
+        // 70 000 s since UNIX epoch, then the token
+        assert_eq!(
+            request
+                .iter()
+                .map(|message| (message.setting, message.value))
+                .collect::<Vec<_>>(),
+            vec![
+                (ControlSetting::WallClockHigh, 1),
+                (ControlSetting::WallClockLow, 4_464),
+                (ControlSetting::TimeSync, token(&request)),
+            ]
+        );
+
+        let mapping = synchronizer
+            .handle_ack(
+                &ack(2_000_000, ControlSetting::TimeSync, token(&request)),
+                sent_at + Duration::from_millis(20),
+            )
+            .unwrap();
+
+        assert_eq!(mapping.uncertainty, Duration::from_millis(10));
+        assert_eq!(mapping.wall_clock, sent_at + Duration::from_millis(10));
+        assert_eq!(synchronizer.mapping(), Some(mapping));
+    }
+
+    #[test]
+    fn synchronizer_keeps_most_accurate_mapping() {
+        let mut synchronizer = ClockSynchronizer::new();
+        let first = UNIX_EPOCH + Duration::from_secs(1);
+        let second = UNIX_EPOCH + Duration::from_secs(2);
+        let first_request = synchronizer.request(first);
+        let second_request = synchronizer.request(second);
+
+        assert!(synchronizer
+            .handle_ack(
+                &ack(1_000, ControlSetting::TimeSync, token(&first_request)),
+                first + Duration::from_millis(10),
+            )
+            .is_some());
+        assert!(synchronizer
+            .handle_ack(
+                &ack(2_000, ControlSetting::TimeSync, token(&second_request)),
+                second + Duration::from_millis(50),
+            )
+            .is_none());
+        assert_eq!(
+            synchronizer.mapping().map(|mapping| mapping.systick),
+            Some(1_000)
+        );
+    }
+
+    #[test]
+    fn synchronizer_matches_requests_sent_within_a_second() {
+        let mut synchronizer = ClockSynchronizer::new();
+        let first = UNIX_EPOCH + Duration::from_millis(1_000);
+        let second = UNIX_EPOCH + Duration::from_millis(1_500);
+        let first_request = synchronizer.request(first);
+        let second_request = synchronizer.request(second);
+        assert_ne!(token(&first_request), token(&second_request));
+
+        // The ACK of the second request arrives first; it must not be matched with the first request
+        let mapping = synchronizer
+            .handle_ack(
+                &ack(2_000, ControlSetting::TimeSync, token(&second_request)),
+                second + Duration::from_millis(10),
+            )
+            .unwrap();
+        assert_eq!(mapping.wall_clock, second + Duration::from_millis(5));
+        assert!(synchronizer
+            .handle_ack(
+                &ack(1_000, ControlSetting::TimeSync, token(&first_request)),
+                first + Duration::from_millis(600),
+            )
+            .is_none());
+        // Each request is only matched once
+        assert!(synchronizer
+            .handle_ack(
+                &ack(2_000, ControlSetting::TimeSync, token(&second_request)),
+                second + Duration::from_millis(2),
+            )
+            .is_none());
+    }
+
+    fn snapshot(device_id: &str, systick: u64) -> TelemetryMessage {
+        TelemetryMessage::MachineStateSnapshot(crate::structures::MachineStateSnapshot {
+            device_id: device_id.to_owned(),
+            systick,
+            ..Default::default()
+        })
+    }
+
+    #[test]
+    fn fleet_clock_aligns_devices() {
+        let mut clock = FleetClock::with_window(Duration::from_secs(10));
+        let start = UNIX_EPOCH + Duration::from_secs(1_000);
+        // Device "a" booted 5 s before device "b"; deliveries take between 1 and 9 ms
+        for i in 0..100u64 {
+            let at = start + Duration::from_millis(i * 100);
+            let latency = Duration::from_millis(1 + i % 9);
+            clock.observe(&snapshot("a", 5_000_000 + i * 100_000), at + latency);
+            clock.observe(
+                &snapshot("b", i * 100_000),
+                at + Duration::from_millis(10) - latency,
+            );
+        }
+
+        let a = clock.aligned_time(&snapshot("a", 5_000_000)).unwrap();
+        let b = clock.aligned_time(&snapshot("b", 0)).unwrap();
+        assert_eq!(a, start + Duration::from_millis(1));
+        assert_eq!(b, start + Duration::from_millis(1));
+        assert_eq!(clock.offsets().len(), 2);
+
+        // After a reboot, the previous estimate is forgotten
+        let later = start + Duration::from_secs(20);
+        clock.observe(&snapshot("a", 1_000), later);
+        assert_eq!(clock.aligned_time(&snapshot("a", 1_000)), Some(later));
+    }
+
+    #[test]
+    fn synchronizer_ignores_unrelated_acks() {
+        let mut synchronizer = ClockSynchronizer::new();
+        let request = synchronizer.request(UNIX_EPOCH);
+
+        assert!(synchronizer
+            .handle_ack(&ack(0, ControlSetting::PEEP, token(&request)), UNIX_EPOCH)
+            .is_none());
+        assert!(synchronizer
+            .handle_ack(
+                &ack(0, ControlSetting::TimeSync, token(&request) + 1),
+                UNIX_EPOCH
+            )
+            .is_none());
+    }
+}
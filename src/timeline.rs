@@ -0,0 +1,451 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::collections::HashMap;
+
+use crate::adapter::MessageAdapter;
+use crate::alarm::AlarmThresholds;
+use crate::control::ControlSetting;
+use crate::structures::{MachineStateSnapshot, StoppedMessage, TelemetryMessage};
+
+/// Message a step was read from
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize),
+    serde(rename_all = "snake_case")
+)]
+pub enum StepSource {
+    /// Commands reported by a machine state snapshot
+    Snapshot,
+    /// Commands reported by a stopped message
+    Stopped,
+    /// A setting acknowledged by the MCU
+    Ack,
+}
+
+impl StepSource {
+    /// Name used in exports
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Snapshot => "snapshot",
+            Self::Stopped => "stopped",
+            Self::Ack => "ack",
+        }
+    }
+}
+
+/// A commanded setting that took a new value, which holds until the next step of the same setting
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct SettingStep {
+    /// Systick of the message the value was read from
+    pub systick: u64,
+    /// Setting that changed
+    pub setting: ControlSetting,
+    /// New value, in the unit of the control protocol
+    pub value: u16,
+    /// Message the value was read from
+    pub source: StepSource,
+}
+
+/// Step function of every setting commanded on a device over time, e.g. to plot prescription changes above waveforms
+///
+/// Values come from the commands reported by machine state snapshots and stopped messages, and from ACKs; a step is only added when a value differs from the previous one.
+/// Values are converted to the unit of the control protocol (e.g. pressures in mmH2O), so that steps read from snapshots and from ACKs can be compared.
+#[derive(Debug, Clone, Default)]
+pub struct SettingsTimeline {
+    steps: Vec<SettingStep>,
+    current: HashMap<ControlSetting, u16>,
+}
+
+impl SettingsTimeline {
+    /// Create an empty timeline
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Build the timeline of a list of messages
+    pub fn from_messages<'a>(messages: impl IntoIterator<Item = &'a TelemetryMessage>) -> Self {
+        let mut timeline = Self::new();
+        for message in messages {
+            timeline.handle(message);
+        }
+        timeline
+    }
+
+    /// Steps, in the order of messages
+    pub fn steps(&self) -> &[SettingStep] {
+        &self.steps
+    }
+
+    /// Value of a setting at a given systick, if it was known by then
+    pub fn value_at(&self, setting: ControlSetting, systick: u64) -> Option<u16> {
+        self.steps
+            .iter()
+            .rfind(|step| step.setting == setting && step.systick <= systick)
+            .map(|step| step.value)
+    }
+
+    /// Render as CSV, one step per line, with a header line
+    pub fn to_csv(&self) -> String {
+        let mut csv = String::from("systick,setting,value,source\n");
+        for step in &self.steps {
+            csv.push_str(&format!(
+                "{},{:?},{},{}\n",
+                step.systick,
+                step.setting,
+                step.value,
+                step.source.name()
+            ));
+        }
+        csv
+    }
+
+    /// Render as a JSON array of steps
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[cfg_attr(
+        doc_cfg,
+        doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+    )]
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.steps).expect("setting steps are always serializable")
+    }
+
+    fn record(
+        &mut self,
+        systick: u64,
+        values: impl IntoIterator<Item = (ControlSetting, Option<u16>)>,
+        source: StepSource,
+    ) -> Vec<SettingStep> {
+        let mut steps = Vec::new();
+        for (setting, value) in values {
+            let value = match value {
+                Some(value) => value,
+                None => continue,
+            };
+            if self.current.insert(setting, value) != Some(value) {
+                steps.push(SettingStep {
+                    systick,
+                    setting,
+                    value,
+                    source,
+                });
+            }
+        }
+        self.steps.extend(&steps);
+        steps
+    }
+}
+
+impl MessageAdapter for SettingsTimeline {
+    type Output = SettingStep;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<SettingStep> {
+        match message {
+            TelemetryMessage::MachineStateSnapshot(snapshot) => self.record(
+                snapshot.systick,
+                snapshot_values(snapshot),
+                StepSource::Snapshot,
+            ),
+            TelemetryMessage::StoppedMessage(stopped) => self.record(
+                stopped.systick,
+                stopped_values(stopped),
+                StepSource::Stopped,
+            ),
+            TelemetryMessage::ControlAck(ack) => match ack.setting {
+                // These are requests rather than settings
+                ControlSetting::Heartbeat
+                | ControlSetting::EolConfirm
+                | ControlSetting::TimeSync
+                | ControlSetting::WallClockHigh
+                | ControlSetting::WallClockLow => Vec::new(),
+                setting => self.record(ack.systick, [(setting, Some(ack.value))], StepSource::Ack),
+            },
+            // The MCU restarted with its default settings: every value will be a new step
+            TelemetryMessage::BootMessage(_) => {
+                self.current.clear();
+                Vec::new()
+            }
+            _ => Vec::new(),
+        }
+    }
+}
+
+fn snapshot_values(snapshot: &MachineStateSnapshot) -> Vec<(ControlSetting, Option<u16>)> {
+    let mut values = vec![
+        (
+            ControlSetting::VentilationMode,
+            Some(u8::from(&snapshot.ventilation_mode).into()),
+        ),
+        (
+            ControlSetting::PlateauPressure,
+            Some(u16::from(snapshot.plateau_command) * 10),
+        ),
+        (
+            ControlSetting::PEEP,
+            Some(u16::from(snapshot.peep_command) * 10),
+        ),
+        (
+            ControlSetting::CyclesPerMinute,
+            Some(snapshot.cpm_command.into()),
+        ),
+        (
+            ControlSetting::ExpiratoryTerm,
+            Some(snapshot.expiratory_term.into()),
+        ),
+        (
+            ControlSetting::TriggerEnabled,
+            Some(snapshot.trigger_enabled.into()),
+        ),
+        (
+            ControlSetting::TriggerOffset,
+            Some(snapshot.trigger_offset.into()),
+        ),
+        (ControlSetting::RespirationEnabled, Some(1)),
+        (
+            ControlSetting::AlarmSnooze,
+            snapshot.alarm_snoozed.map(u16::from),
+        ),
+        (
+            ControlSetting::InspiratoryTriggerFlow,
+            snapshot.inspiratory_trigger_flow.map(u16::from),
+        ),
+        (
+            ControlSetting::ExpiratoryTriggerFlow,
+            snapshot.expiratory_trigger_flow.map(u16::from),
+        ),
+        (ControlSetting::TiMin, snapshot.ti_min),
+        (ControlSetting::TiMax, snapshot.ti_max),
+        (
+            ControlSetting::TargetTidalVolume,
+            snapshot.target_tidal_volume,
+        ),
+        (ControlSetting::PlateauDuration, snapshot.plateau_duration),
+        (
+            ControlSetting::TargetInspiratoryFlow,
+            snapshot.target_inspiratory_flow.map(u16::from),
+        ),
+        (
+            ControlSetting::InspiratoryDuration,
+            snapshot.inspiratory_duration_command,
+        ),
+        (
+            ControlSetting::Locale,
+            snapshot.locale.as_ref().map(|locale| locale.as_u16()),
+        ),
+        (
+            ControlSetting::PatientHeight,
+            snapshot.patient_height.map(u16::from),
+        ),
+        (
+            ControlSetting::PatientGender,
+            snapshot
+                .patient_gender
+                .as_ref()
+                .map(|gender| u8::from(gender).into()),
+        ),
+    ];
+    values.extend(AlarmThresholds::from_machine_state(snapshot).values());
+    values
+}
+
+fn stopped_values(stopped: &StoppedMessage) -> Vec<(ControlSetting, Option<u16>)> {
+    let mut values = vec![
+        (
+            ControlSetting::VentilationMode,
+            Some(u8::from(&stopped.ventilation_mode).into()),
+        ),
+        (
+            ControlSetting::PlateauPressure,
+            stopped
+                .plateau_command
+                .map(|plateau| u16::from(plateau) * 10),
+        ),
+        (
+            ControlSetting::PEEP,
+            stopped.peep_command.map(|peep| u16::from(peep) * 10),
+        ),
+        (
+            ControlSetting::CyclesPerMinute,
+            stopped.cpm_command.map(u16::from),
+        ),
+        (
+            ControlSetting::ExpiratoryTerm,
+            stopped.expiratory_term.map(u16::from),
+        ),
+        (
+            ControlSetting::TriggerEnabled,
+            stopped.trigger_enabled.map(u16::from),
+        ),
+        (
+            ControlSetting::TriggerOffset,
+            stopped.trigger_offset.map(u16::from),
+        ),
+        (ControlSetting::RespirationEnabled, Some(0)),
+        (
+            ControlSetting::AlarmSnooze,
+            stopped.alarm_snoozed.map(u16::from),
+        ),
+        (
+            ControlSetting::InspiratoryTriggerFlow,
+            stopped.inspiratory_trigger_flow.map(u16::from),
+        ),
+        (
+            ControlSetting::ExpiratoryTriggerFlow,
+            stopped.expiratory_trigger_flow.map(u16::from),
+        ),
+        (ControlSetting::TiMin, stopped.ti_min),
+        (ControlSetting::TiMax, stopped.ti_max),
+        (
+            ControlSetting::TargetTidalVolume,
+            stopped.target_tidal_volume,
+        ),
+        (ControlSetting::PlateauDuration, stopped.plateau_duration),
+        (
+            ControlSetting::TargetInspiratoryFlow,
+            stopped.target_inspiratory_flow.map(u16::from),
+        ),
+        (
+            ControlSetting::InspiratoryDuration,
+            stopped.inspiratory_duration_command,
+        ),
+        (
+            ControlSetting::Locale,
+            stopped.locale.as_ref().map(|locale| locale.as_u16()),
+        ),
+        (
+            ControlSetting::PatientHeight,
+            stopped.patient_height.map(u16::from),
+        ),
+        (
+            ControlSetting::PatientGender,
+            stopped
+                .patient_gender
+                .as_ref()
+                .map(|gender| u8::from(gender).into()),
+        ),
+    ];
+    values.extend(AlarmThresholds::from_stopped_message(stopped).values());
+    values
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::simulator::{PatientPreset, SimulatedDevice};
+    use crate::structures::ControlAck;
+
+    #[test]
+    fn records_a_step_when_a_setting_changes() {
+        let messages: Vec<TelemetryMessage> =
+            SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy)
+                .take(2_000)
+                .collect();
+        let mut timeline = SettingsTimeline::from_messages(&messages);
+        let first_steps = timeline.steps().len();
+        assert!(first_steps > 0);
+        let peep = timeline.value_at(ControlSetting::PEEP, u64::MAX).unwrap();
+
+        let systick = messages.last().unwrap().systick() + 1;
+        let ack = TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            setting: ControlSetting::PEEP,
+            value: peep + 20,
+        });
+        assert_eq!(timeline.handle(&ack).len(), 1);
+        // The same value again is not a step
+        assert!(timeline.handle(&ack).is_empty());
+
+        assert_eq!(timeline.steps().len(), first_steps + 1);
+        assert_eq!(
+            timeline.value_at(ControlSetting::PEEP, systick - 1),
+            Some(peep)
+        );
+        assert_eq!(
+            timeline.value_at(ControlSetting::PEEP, systick),
+            Some(peep + 20)
+        );
+        assert!(timeline
+            .to_csv()
+            .ends_with(&format!("{},PEEP,{},ack\n", systick, peep + 20)));
+    }
+
+    fn ack(systick: u64, setting: ControlSetting, value: u16) -> TelemetryMessage {
+        TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "1-2-3".to_owned(),
+            systick,
+            setting,
+            value,
+        })
+    }
+
+    #[test]
+    fn ignores_requests_and_unknown_values() {
+        let mut timeline = SettingsTimeline::new();
+        assert_eq!(timeline.value_at(ControlSetting::PEEP, u64::MAX), None);
+        assert_eq!(timeline.to_csv(), "systick,setting,value,source\n");
+
+        for setting in [
+            ControlSetting::Heartbeat,
+            ControlSetting::EolConfirm,
+            ControlSetting::TimeSync,
+            ControlSetting::WallClockLow,
+        ] {
+            assert!(timeline.handle(&ack(1_000, setting, 1)).is_empty());
+        }
+        assert!(timeline.steps().is_empty());
+
+        timeline.handle(&ack(2_000, ControlSetting::PEEP, 50));
+        // The setting was not known yet
+        assert_eq!(timeline.value_at(ControlSetting::PEEP, 1_999), None);
+        assert_eq!(timeline.value_at(ControlSetting::PEEP, 2_000), Some(50));
+        assert_eq!(
+            timeline.value_at(ControlSetting::PlateauPressure, 2_000),
+            None
+        );
+    }
+
+    #[test]
+    fn starts_over_after_a_reboot() {
+        let mut messages = SimulatedDevice::with_preset("1-2-3", PatientPreset::Healthy);
+        let boot = messages.next().unwrap();
+        let mut timeline = SettingsTimeline::new();
+
+        timeline.handle(&ack(1_000, ControlSetting::PEEP, 50));
+        assert!(timeline
+            .handle(&ack(2_000, ControlSetting::PEEP, 50))
+            .is_empty());
+        // The MCU may have restarted with other defaults: the same value is a step again
+        timeline.handle(&boot);
+        assert_eq!(
+            timeline.handle(&ack(3_000, ControlSetting::PEEP, 50)).len(),
+            1
+        );
+        assert_eq!(timeline.steps().len(), 2);
+        // Steps of earlier runs are kept
+        assert_eq!(timeline.value_at(ControlSetting::PEEP, 1_500), Some(50));
+    }
+
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[test]
+    fn renders_json() {
+        let mut timeline = SettingsTimeline::new();
+        assert_eq!(timeline.to_json(), "[]");
+
+        timeline.handle(&ack(1_000, ControlSetting::PEEP, 50));
+        let steps: Vec<SettingStep> = serde_json::from_str(&timeline.to_json()).unwrap();
+        assert_eq!(steps, timeline.steps());
+        assert!(timeline.to_json().contains(r#""source":"ack""#));
+    }
+}
@@ -0,0 +1,98 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+/// Hectopascals in one centimeter of water
+pub const HPA_PER_CMH2O: f64 = 0.980665;
+
+/// Convert a pressure from mmH2O to cmH2O
+pub fn mmh2o_to_cmh2o(pressure: f64) -> f64 {
+    pressure / 10.0
+}
+
+/// Convert a pressure from cmH2O to mmH2O
+pub fn cmh2o_to_mmh2o(pressure: f64) -> f64 {
+    pressure * 10.0
+}
+
+/// Convert a pressure from cmH2O to hPa
+pub fn cmh2o_to_hpa(pressure: f64) -> f64 {
+    pressure * HPA_PER_CMH2O
+}
+
+/// Convert a pressure from hPa to cmH2O
+pub fn hpa_to_cmh2o(pressure: f64) -> f64 {
+    pressure / HPA_PER_CMH2O
+}
+
+/// Convert a pressure from mmH2O to hPa
+pub fn mmh2o_to_hpa(pressure: f64) -> f64 {
+    cmh2o_to_hpa(mmh2o_to_cmh2o(pressure))
+}
+
+/// Convert a pressure from hPa to mmH2O
+pub fn hpa_to_mmh2o(pressure: f64) -> f64 {
+    cmh2o_to_mmh2o(hpa_to_cmh2o(pressure))
+}
+
+/// Convert a flow from cL/min to L/min
+pub fn cl_per_min_to_l_per_min(flow: f64) -> f64 {
+    flow / 100.0
+}
+
+/// Convert a flow from L/min to cL/min
+pub fn l_per_min_to_cl_per_min(flow: f64) -> f64 {
+    flow * 100.0
+}
+
+/// Convert a flow from L/min to mL/s
+pub fn l_per_min_to_ml_per_s(flow: f64) -> f64 {
+    flow * 1000.0 / 60.0
+}
+
+/// Convert a flow from mL/s to L/min
+pub fn ml_per_s_to_l_per_min(flow: f64) -> f64 {
+    flow * 60.0 / 1000.0
+}
+
+/// Convert a flow from cL/min to mL/s
+pub fn cl_per_min_to_ml_per_s(flow: f64) -> f64 {
+    l_per_min_to_ml_per_s(cl_per_min_to_l_per_min(flow))
+}
+
+/// Convert a flow from mL/s to cL/min
+pub fn ml_per_s_to_cl_per_min(flow: f64) -> f64 {
+    l_per_min_to_cl_per_min(ml_per_s_to_l_per_min(flow))
+}
+
+/// Volume in mL that went through at a flow in cL/min during a duration in microseconds (e.g. to integrate flows between systicks)
+pub fn cl_per_min_volume(flow: f64, duration: u64) -> f64 {
+    cl_per_min_to_ml_per_s(flow) * duration as f64 / 1_000_000.0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn assert_close(a: f64, b: f64) {
+        assert!((a - b).abs() < 1e-9, "{} != {}", a, b);
+    }
+
+    #[test]
+    fn pressures() {
+        assert_close(mmh2o_to_cmh2o(123.0), 12.3);
+        assert_close(cmh2o_to_hpa(10.0), 9.80665);
+        assert_close(mmh2o_to_hpa(100.0), 9.80665);
+        assert_close(hpa_to_mmh2o(mmh2o_to_hpa(42.0)), 42.0);
+    }
+
+    #[test]
+    fn flows() {
+        assert_close(cl_per_min_to_l_per_min(3000.0), 30.0);
+        assert_close(l_per_min_to_ml_per_s(60.0), 1000.0);
+        assert_close(cl_per_min_to_ml_per_s(6000.0), 1000.0);
+        assert_close(ml_per_s_to_cl_per_min(cl_per_min_to_ml_per_s(123.0)), 123.0);
+        assert_close(cl_per_min_volume(6000.0, 500_000), 500.0);
+    }
+}
@@ -0,0 +1,462 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime};
+
+use sha2::{Digest, Sha256};
+use url::Url;
+
+use crate::http::{self, HttpResponse};
+use crate::retention::{is_uploaded, sidecar, RECORDING_EXTENSION, UPLOADED_MARKER_EXTENSION};
+
+/// Default size of the chunks sent to the target
+pub const DEFAULT_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Default time since its last modification after which a recording is considered complete
+pub const DEFAULT_MIN_IDLE: Duration = Duration::from_secs(60);
+
+/// Extension added to a recording path to store the state of its unfinished upload
+pub const UPLOAD_STATE_EXTENSION: &str = "upload";
+
+/// Error that can happen while uploading a recording
+#[derive(Debug, thiserror::Error)]
+pub enum UploadError {
+    /// Reading the recording or talking to the target failed
+    #[error("I/O error: {0}")]
+    Io(#[from] std::io::Error),
+    /// The target answered with an unexpected HTTP status
+    #[error("unexpected HTTP status {status} for {method} {url}")]
+    Http {
+        /// HTTP method of the request
+        method: &'static str,
+        /// URL of the request
+        url: String,
+        /// Status of the response
+        status: u16,
+    },
+    /// The target did not answer as expected (e.g. a missing header)
+    #[error("invalid answer from upload target: {0}")]
+    InvalidAnswer(String),
+    /// The target rejected a chunk because its checksum did not match
+    #[error("upload target rejected chunk at offset {0} (checksum mismatch)")]
+    ChecksumMismatch(u64),
+    /// The target does not hold the whole recording after the upload
+    #[error("upload target has {received} bytes instead of {expected}")]
+    Incomplete {
+        /// Size of the recording
+        expected: u64,
+        /// Number of bytes held by the target
+        received: u64,
+    },
+}
+
+/// Description of a recording to upload
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct UploadInfo {
+    /// File name of the recording
+    pub name: String,
+    /// Size of the recording in bytes
+    pub size: u64,
+    /// SHA-256 of the recording
+    pub sha256: [u8; 32],
+}
+
+/// Somewhere recordings can be uploaded in chunks, resuming interrupted uploads
+pub trait UploadTarget {
+    /// Start an upload, or resume it from its saved state; returns the state to save and how many bytes the target already has
+    fn begin(
+        &mut self,
+        info: &UploadInfo,
+        state: Option<&str>,
+    ) -> Result<(String, u64), UploadError>;
+
+    /// Send the chunk of the recording starting at `offset`
+    fn write_chunk(&mut self, state: &str, offset: u64, chunk: &[u8]) -> Result<(), UploadError>;
+
+    /// Finish the upload, checking that the target holds the whole recording
+    fn finish(&mut self, state: &str, info: &UploadInfo) -> Result<(), UploadError>;
+}
+
+/// Upload target speaking the tus resumable upload protocol (1.0, with the creation and checksum extensions) over plain HTTP
+///
+/// Every chunk is sent with its SHA-256, and the SHA-256 of the whole recording is given as `sha256` upload metadata so that the server can check it too.
+/// HTTPS is not supported: use a local reverse proxy to reach remote servers.
+#[derive(Debug, Clone)]
+pub struct TusTarget {
+    endpoint: Url,
+}
+
+impl TusTarget {
+    /// Create a target creating uploads at an endpoint (e.g. `http://collector:1080/files/`)
+    pub fn new(endpoint: Url) -> Self {
+        Self { endpoint }
+    }
+
+    fn offset(response: &HttpResponse) -> Result<u64, UploadError> {
+        response
+            .header("upload-offset")
+            .and_then(|offset| offset.parse().ok())
+            .ok_or_else(|| UploadError::InvalidAnswer("missing Upload-Offset header".to_owned()))
+    }
+
+    fn head(&self, url: &Url) -> Result<HttpResponse, UploadError> {
+        Ok(http::request(
+            "HEAD",
+            url,
+            &[("Tus-Resumable", "1.0.0".to_owned())],
+            &[],
+        )?)
+    }
+}
+
+impl UploadTarget for TusTarget {
+    fn begin(
+        &mut self,
+        info: &UploadInfo,
+        state: Option<&str>,
+    ) -> Result<(String, u64), UploadError> {
+        if let Some(location) = state {
+            let url = Url::parse(location)
+                .map_err(|e| UploadError::InvalidAnswer(format!("invalid saved URL: {}", e)))?;
+            let response = self.head(&url)?;
+            if response.status == 200 {
+                return Ok((location.to_owned(), Self::offset(&response)?));
+            }
+            // The server forgot the upload (404, 410): start again
+            log::warn!(
+                "upload {} is gone (HTTP {}), starting again",
+                location,
+                response.status
+            );
+        }
+
+        let metadata = format!(
+            "filename {},sha256 {}",
+            base64::encode(&info.name),
+            base64::encode(info.sha256)
+        );
+        let response = http::request(
+            "POST",
+            &self.endpoint,
+            &[
+                ("Tus-Resumable", "1.0.0".to_owned()),
+                ("Upload-Length", info.size.to_string()),
+                ("Upload-Metadata", metadata),
+            ],
+            &[],
+        )?;
+        let response = expect_status(response, "POST", &self.endpoint, 201)?;
+        let location = response
+            .header("location")
+            .ok_or_else(|| UploadError::InvalidAnswer("missing Location header".to_owned()))?;
+        let url = self
+            .endpoint
+            .join(location)
+            .map_err(|e| UploadError::InvalidAnswer(format!("invalid Location: {}", e)))?;
+        Ok((url.to_string(), 0))
+    }
+
+    fn write_chunk(&mut self, state: &str, offset: u64, chunk: &[u8]) -> Result<(), UploadError> {
+        let url = Url::parse(state)
+            .map_err(|e| UploadError::InvalidAnswer(format!("invalid saved URL: {}", e)))?;
+        let checksum = format!("sha256 {}", base64::encode(Sha256::digest(chunk)));
+        let response = http::request(
+            "PATCH",
+            &url,
+            &[
+                ("Tus-Resumable", "1.0.0".to_owned()),
+                ("Content-Type", "application/offset+octet-stream".to_owned()),
+                ("Upload-Offset", offset.to_string()),
+                ("Upload-Checksum", checksum),
+            ],
+            chunk,
+        )?;
+        if response.status == 460 {
+            return Err(UploadError::ChecksumMismatch(offset));
+        }
+        expect_status(response, "PATCH", &url, 204)?;
+        Ok(())
+    }
+
+    fn finish(&mut self, state: &str, info: &UploadInfo) -> Result<(), UploadError> {
+        let url = Url::parse(state)
+            .map_err(|e| UploadError::InvalidAnswer(format!("invalid saved URL: {}", e)))?;
+        let received = Self::offset(&expect_status(self.head(&url)?, "HEAD", &url, 200)?)?;
+        if received != info.size {
+            return Err(UploadError::Incomplete {
+                expected: info.size,
+                received,
+            });
+        }
+        Ok(())
+    }
+}
+
+/// What happened to a recording given to an `Uploader`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UploadOutcome {
+    /// The recording was uploaded (possibly resuming a previous attempt)
+    Uploaded,
+    /// The recording was uploaded before
+    AlreadyUploaded,
+    /// The recording was modified too recently: it is probably still being written
+    NotComplete,
+}
+
+/// Uploads completed recordings to a target, resuming interrupted uploads and marking uploaded recordings
+///
+/// The state of an unfinished upload is saved next to the recording (`.upload` file); once the target holds the whole recording, a `.uploaded` marker containing its SHA-256 replaces it.
+/// Uploaded recordings can then be deleted with `RetentionPolicy::uploaded_only()`.
+#[derive(Debug)]
+pub struct Uploader<T: UploadTarget> {
+    target: T,
+    chunk_size: usize,
+    min_idle: Duration,
+}
+
+impl<T: UploadTarget> Uploader<T> {
+    /// Create an uploader sending recordings to a target
+    pub fn new(target: T) -> Self {
+        Self {
+            target,
+            chunk_size: DEFAULT_CHUNK_SIZE,
+            min_idle: DEFAULT_MIN_IDLE,
+        }
+    }
+
+    /// Send chunks of this number of bytes
+    pub fn chunk_size(mut self, bytes: usize) -> Self {
+        self.chunk_size = bytes.max(1);
+        self
+    }
+
+    /// Only upload recordings that were not modified for this duration
+    pub fn min_idle(mut self, min_idle: Duration) -> Self {
+        self.min_idle = min_idle;
+        self
+    }
+
+    /// Upload a recording, unless it is still being written or was uploaded before
+    pub fn upload_file<P: AsRef<Path>>(&mut self, path: P) -> Result<UploadOutcome, UploadError> {
+        self.upload_file_at(path.as_ref(), SystemTime::now())
+    }
+
+    /// Upload every completed recording (`.record` file) of a directory, and return those that were uploaded now
+    ///
+    /// It stops at the first error; uploads are resumed on the next call.
+    pub fn upload_directory<P: AsRef<Path>>(
+        &mut self,
+        directory: P,
+    ) -> Result<Vec<PathBuf>, UploadError> {
+        let mut paths: Vec<PathBuf> = std::fs::read_dir(directory)?
+            .map(|entry| entry.map(|entry| entry.path()))
+            .collect::<Result<_, _>>()?;
+        paths.retain(|path| {
+            path.is_file()
+                && path
+                    .extension()
+                    .is_some_and(|extension| extension == RECORDING_EXTENSION)
+        });
+        paths.sort();
+
+        let now = SystemTime::now();
+        let mut uploaded = Vec::new();
+        for path in paths {
+            if self.upload_file_at(&path, now)? == UploadOutcome::Uploaded {
+                uploaded.push(path);
+            }
+        }
+        Ok(uploaded)
+    }
+
+    fn upload_file_at(
+        &mut self,
+        path: &Path,
+        now: SystemTime,
+    ) -> Result<UploadOutcome, UploadError> {
+        if is_uploaded(path) {
+            return Ok(UploadOutcome::AlreadyUploaded);
+        }
+        let modified = std::fs::metadata(path)?.modified()?;
+        if now
+            .duration_since(modified)
+            .map_or(true, |idle| idle < self.min_idle)
+        {
+            return Ok(UploadOutcome::NotComplete);
+        }
+
+        let info = UploadInfo {
+            name: path
+                .file_name()
+                .map(|name| name.to_string_lossy().into_owned())
+                .unwrap_or_default(),
+            size: std::fs::metadata(path)?.len(),
+            sha256: sha256_file(path)?,
+        };
+        let state_path = sidecar(path, UPLOAD_STATE_EXTENSION);
+        let saved_state = std::fs::read_to_string(&state_path).ok();
+        let (state, mut offset) = self.target.begin(&info, saved_state.as_deref())?;
+        if saved_state.as_deref() != Some(state.as_str()) {
+            std::fs::write(&state_path, &state)?;
+        }
+        if offset > 0 {
+            log::info!("resuming upload of {} at byte {}", info.name, offset);
+        }
+
+        let mut file = File::open(path)?;
+        file.seek(SeekFrom::Start(offset))?;
+        let mut chunk = vec![0; self.chunk_size];
+        while offset < info.size {
+            let length = ((info.size - offset) as usize).min(self.chunk_size);
+            file.read_exact(&mut chunk[..length])?;
+            self.target.write_chunk(&state, offset, &chunk[..length])?;
+            offset += length as u64;
+        }
+        self.target.finish(&state, &info)?;
+
+        std::fs::write(
+            sidecar(path, UPLOADED_MARKER_EXTENSION),
+            to_hex(&info.sha256),
+        )?;
+        std::fs::remove_file(&state_path)?;
+        Ok(UploadOutcome::Uploaded)
+    }
+}
+
+fn sha256_file(path: &Path) -> std::io::Result<[u8; 32]> {
+    let mut hasher = Sha256::new();
+    std::io::copy(&mut File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().into())
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+fn expect_status(
+    response: HttpResponse,
+    method: &'static str,
+    url: &Url,
+    status: u16,
+) -> Result<HttpResponse, UploadError> {
+    if response.status == status {
+        Ok(response)
+    } else {
+        Err(UploadError::Http {
+            method,
+            url: url.to_string(),
+            status: response.status,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Target keeping uploads in memory, that can fail after a number of chunks
+    #[derive(Default)]
+    struct MemoryTarget {
+        uploads: Vec<Vec<u8>>,
+        chunks_before_failure: Option<usize>,
+    }
+
+    impl UploadTarget for MemoryTarget {
+        fn begin(
+            &mut self,
+            _info: &UploadInfo,
+            state: Option<&str>,
+        ) -> Result<(String, u64), UploadError> {
+            match state.and_then(|state| state.parse::<usize>().ok()) {
+                Some(index) => Ok((state.unwrap().to_owned(), self.uploads[index].len() as u64)),
+                None => {
+                    self.uploads.push(Vec::new());
+                    Ok(((self.uploads.len() - 1).to_string(), 0))
+                }
+            }
+        }
+
+        fn write_chunk(
+            &mut self,
+            state: &str,
+            offset: u64,
+            chunk: &[u8],
+        ) -> Result<(), UploadError> {
+            if let Some(remaining) = self.chunks_before_failure.as_mut() {
+                if *remaining == 0 {
+                    return Err(UploadError::Io(std::io::ErrorKind::ConnectionReset.into()));
+                }
+                *remaining -= 1;
+            }
+            let upload = &mut self.uploads[state.parse::<usize>().unwrap()];
+            assert_eq!(upload.len() as u64, offset);
+            upload.extend_from_slice(chunk);
+            Ok(())
+        }
+
+        fn finish(&mut self, state: &str, info: &UploadInfo) -> Result<(), UploadError> {
+            let upload = &self.uploads[state.parse::<usize>().unwrap()];
+            if <[u8; 32]>::from(Sha256::digest(upload)) != info.sha256 {
+                return Err(UploadError::ChecksumMismatch(0));
+            }
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn resumes_interrupted_uploads() {
+        let directory = std::env::temp_dir().join("makair-telemetry-upload");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let recording = directory.join("night.record");
+        let content: Vec<u8> = (0..=255).cycle().take(1000).collect();
+        std::fs::write(&recording, &content).unwrap();
+
+        let target = MemoryTarget {
+            chunks_before_failure: Some(3),
+            ..Default::default()
+        };
+        let mut uploader = Uploader::new(target)
+            .chunk_size(100)
+            .min_idle(Duration::ZERO);
+        assert!(uploader.upload_directory(&directory).is_err());
+        assert!(sidecar(&recording, UPLOAD_STATE_EXTENSION).is_file());
+        assert!(!is_uploaded(&recording));
+
+        uploader.target.chunks_before_failure = None;
+        assert_eq!(
+            uploader.upload_directory(&directory).unwrap(),
+            vec![recording.clone()]
+        );
+        assert_eq!(uploader.target.uploads, vec![content]);
+        assert!(is_uploaded(&recording));
+        assert!(!sidecar(&recording, UPLOAD_STATE_EXTENSION).exists());
+        assert_eq!(
+            uploader.upload_file(&recording).unwrap(),
+            UploadOutcome::AlreadyUploaded
+        );
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+
+    #[test]
+    fn skips_recordings_being_written() {
+        let directory = std::env::temp_dir().join("makair-telemetry-upload-recent");
+        let _ = std::fs::remove_dir_all(&directory);
+        std::fs::create_dir_all(&directory).unwrap();
+        let recording = directory.join("live.record");
+        std::fs::write(&recording, b"data").unwrap();
+
+        let mut uploader = Uploader::new(MemoryTarget::default());
+        assert_eq!(
+            uploader.upload_file(&recording).unwrap(),
+            UploadOutcome::NotComplete
+        );
+        assert!(uploader.target.uploads.is_empty());
+        std::fs::remove_dir_all(&directory).unwrap();
+    }
+}
@@ -0,0 +1,276 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use crate::adapter::MessageAdapter;
+use crate::structures::{DataSnapshot, Phase, TelemetryMessage};
+use crate::units;
+
+/// Part of the expiration, at its end, during which flows are expected to be null and are used as baseline
+pub const END_EXPIRATORY_FRACTION: f64 = 0.2;
+
+/// Absolute tolerance in mL between computed inspiratory volumes and volumes reported by the firmware
+pub const FIRMWARE_TOLERANCE_ML: f64 = 20.0;
+
+/// Relative tolerance between computed inspiratory volumes and volumes reported by the firmware
+pub const FIRMWARE_TOLERANCE_RATIO: f64 = 0.1;
+
+/// Volumes of one breath, integrated from the flows of data snapshots (protocol v2 only)
+#[derive(Debug, Clone, Copy, PartialEq)]
+#[cfg_attr(
+    feature = "serde-messages",
+    derive(serde::Serialize, serde::Deserialize)
+)]
+pub struct BreathVolumes {
+    /// Systick of the first data snapshot of the breath
+    pub systick: u64,
+    /// Duration of the breath in microseconds
+    pub duration: u64,
+    /// Volume that went through the inspiratory branch during inhalation, in mL
+    pub inspiratory_volume: f64,
+    /// Volume that went through the expiratory branch during exhalation, in mL
+    pub expiratory_volume: f64,
+    /// Baseline subtracted from the inspiratory flow, in cL/min
+    pub inspiratory_offset: f64,
+    /// Baseline subtracted from the expiratory flow, in cL/min
+    pub expiratory_offset: f64,
+    /// Volume reported by the firmware at the end of the breath, in mL (if its machine state snapshot was received)
+    pub reported_volume: Option<u16>,
+}
+
+impl BreathVolumes {
+    /// Volume lost during the breath (inspiratory minus expiratory), in mL
+    pub fn leak(&self) -> f64 {
+        self.inspiratory_volume - self.expiratory_volume
+    }
+
+    /// Mean leak flow during the breath, in cL/min (the unit of the leak alarm threshold)
+    pub fn leak_flow(&self) -> f64 {
+        if self.duration == 0 {
+            0.0
+        } else {
+            units::ml_per_s_to_cl_per_min(self.leak() * 1_000_000.0 / self.duration as f64)
+        }
+    }
+
+    /// Whether the inspiratory volume agrees with the one reported by the firmware
+    ///
+    /// Volumes agree when they differ by at most `FIRMWARE_TOLERANCE_ML` or `FIRMWARE_TOLERANCE_RATIO` of the reported volume, whichever is greater.
+    /// Returns `None` when the firmware did not report any volume.
+    pub fn agrees_with_firmware(&self) -> Option<bool> {
+        self.reported_volume.map(|reported| {
+            let reported = f64::from(reported);
+            let tolerance = FIRMWARE_TOLERANCE_ML.max(reported * FIRMWARE_TOLERANCE_RATIO);
+            (self.inspiratory_volume - reported).abs() <= tolerance
+        })
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    systick: u64,
+    centile: u16,
+    phase: Phase,
+    inspiratory_flow: f64,
+    expiratory_flow: f64,
+}
+
+/// Computes the volumes of each breath from the flows of data snapshots
+///
+/// A breath ends with its machine state snapshot, or when the centile of data snapshots goes back to a lower value if that snapshot was dropped.
+/// Flows are integrated with a baseline correction: the mean flows of the end of the expiration, during which both branches should be still, are considered as sensor drift and subtracted from the whole breath.
+/// Breaths with data snapshots lacking flows (protocol v1) are ignored.
+#[derive(Debug, Default)]
+pub struct VolumeIntegrator {
+    samples: Vec<Sample>,
+    missing_flow: bool,
+}
+
+impl VolumeIntegrator {
+    /// Create an integrator waiting for its first breath
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn push_snapshot(&mut self, snapshot: &DataSnapshot) -> Option<BreathVolumes> {
+        let ended = match self.samples.last() {
+            Some(last) if snapshot.centile < last.centile => self.finish_breath(None),
+            _ => None,
+        };
+
+        match (snapshot.inspiratory_flow, snapshot.expiratory_flow) {
+            (Some(inspiratory), Some(expiratory)) => self.samples.push(Sample {
+                systick: snapshot.systick,
+                centile: snapshot.centile,
+                phase: snapshot.phase,
+                inspiratory_flow: f64::from(inspiratory),
+                expiratory_flow: f64::from(expiratory),
+            }),
+            _ => self.missing_flow = true,
+        }
+
+        ended
+    }
+
+    fn finish_breath(&mut self, reported_volume: Option<u16>) -> Option<BreathVolumes> {
+        let samples = std::mem::take(&mut self.samples);
+        let missing_flow = std::mem::replace(&mut self.missing_flow, false);
+        if missing_flow || samples.len() < 2 {
+            return None;
+        }
+
+        let expiration: Vec<&Sample> = samples
+            .iter()
+            .filter(|sample| sample.phase == Phase::Exhalation)
+            .collect();
+        let end_len = ((expiration.len() as f64 * END_EXPIRATORY_FRACTION).ceil() as usize).max(1);
+        let end_expiration = &expiration[expiration.len().saturating_sub(end_len)..];
+        let mean = |flow: fn(&Sample) -> f64| {
+            if end_expiration.is_empty() {
+                0.0
+            } else {
+                end_expiration
+                    .iter()
+                    .map(|sample| flow(sample))
+                    .sum::<f64>()
+                    / end_expiration.len() as f64
+            }
+        };
+        let inspiratory_offset = mean(|sample| sample.inspiratory_flow);
+        let expiratory_offset = mean(|sample| sample.expiratory_flow);
+
+        // Each sample lasts until the next one; the last one is assumed to last as long as the previous one
+        let mut inspiratory_volume = 0.0;
+        let mut expiratory_volume = 0.0;
+        for (i, sample) in samples.iter().enumerate() {
+            let duration = match samples.get(i + 1) {
+                Some(next) => next.systick.saturating_sub(sample.systick),
+                None => sample.systick.saturating_sub(samples[i - 1].systick),
+            };
+            match sample.phase {
+                Phase::Inhalation => {
+                    inspiratory_volume += units::cl_per_min_volume(
+                        sample.inspiratory_flow - inspiratory_offset,
+                        duration,
+                    )
+                }
+                Phase::Exhalation => {
+                    expiratory_volume += units::cl_per_min_volume(
+                        sample.expiratory_flow - expiratory_offset,
+                        duration,
+                    )
+                }
+            }
+        }
+
+        let first = samples[0].systick;
+        let last = samples[samples.len() - 1].systick;
+        Some(BreathVolumes {
+            systick: first,
+            duration: (last - first) * samples.len() as u64 / (samples.len() as u64 - 1),
+            inspiratory_volume: inspiratory_volume.max(0.0),
+            expiratory_volume: expiratory_volume.max(0.0),
+            inspiratory_offset,
+            expiratory_offset,
+            reported_volume,
+        })
+    }
+}
+
+impl MessageAdapter for VolumeIntegrator {
+    type Output = BreathVolumes;
+
+    fn handle(&mut self, message: &TelemetryMessage) -> Vec<BreathVolumes> {
+        match message {
+            TelemetryMessage::DataSnapshot(snapshot) => self.push_snapshot(snapshot),
+            TelemetryMessage::MachineStateSnapshot(snapshot) => {
+                self.finish_breath(snapshot.previous_volume)
+            }
+            // The breath is interrupted, it will not get any meaningful volume
+            TelemetryMessage::StoppedMessage(_) | TelemetryMessage::BootMessage(_) => {
+                self.samples.clear();
+                self.missing_flow = false;
+                None
+            }
+            _ => None,
+        }
+        .into_iter()
+        .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::structures::MachineStateSnapshot;
+
+    /// One 3 s breath sampled every 100 ms: 30 L/min during 1 s of inhalation, then 60 L/min during 0.5 s of exhalation
+    fn breath(start: u64, inspiratory_drift: i16, expiratory_drift: i16) -> Vec<TelemetryMessage> {
+        (0..30u16)
+            .map(|i| {
+                let inhalation = i < 10;
+                TelemetryMessage::DataSnapshot(DataSnapshot {
+                    telemetry_version: 2,
+                    version: String::new(),
+                    device_id: String::new(),
+                    systick: start + u64::from(i) * 100_000,
+                    centile: i * 10,
+                    pressure: 0,
+                    phase: if inhalation {
+                        Phase::Inhalation
+                    } else {
+                        Phase::Exhalation
+                    },
+                    subphase: None,
+                    blower_valve_position: 0,
+                    patient_valve_position: 0,
+                    blower_rpm: 0,
+                    battery_level: 0,
+                    inspiratory_flow: Some(if inhalation { 3000 } else { 0 } + inspiratory_drift),
+                    expiratory_flow: Some(
+                        if (10..15).contains(&i) { 6000 } else { 0 } + expiratory_drift,
+                    ),
+                })
+            })
+            .collect()
+    }
+
+    #[test]
+    fn integrates_with_drift_correction() {
+        let mut integrator = VolumeIntegrator::new();
+        let mut volumes = Vec::new();
+        for message in breath(0, 100, 50) {
+            volumes.extend(integrator.handle(&message));
+        }
+        volumes.extend(integrator.handle(&TelemetryMessage::MachineStateSnapshot(
+            MachineStateSnapshot {
+                previous_volume: Some(520),
+                ..Default::default()
+            },
+        )));
+
+        assert_eq!(volumes.len(), 1);
+        let breath_volumes = volumes[0];
+        assert_eq!(breath_volumes.duration, 3_000_000);
+        assert_eq!(breath_volumes.inspiratory_offset, 100.0);
+        assert_eq!(breath_volumes.expiratory_offset, 50.0);
+        assert!((breath_volumes.inspiratory_volume - 500.0).abs() < 1e-9);
+        assert!((breath_volumes.expiratory_volume - 500.0).abs() < 1e-9);
+        assert!(breath_volumes.leak().abs() < 1e-9);
+        assert_eq!(breath_volumes.agrees_with_firmware(), Some(true));
+    }
+
+    #[test]
+    fn ends_breaths_without_machine_state() {
+        let mut integrator = VolumeIntegrator::new();
+        let mut volumes = Vec::new();
+        for message in breath(0, 0, 0).into_iter().chain(breath(3_000_000, 0, 0)) {
+            volumes.extend(integrator.handle(&message));
+        }
+
+        assert_eq!(volumes.len(), 1);
+        assert_eq!(volumes[0].reported_volume, None);
+        assert_eq!(volumes[0].agrees_with_firmware(), None);
+    }
+}
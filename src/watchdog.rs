@@ -0,0 +1,336 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::Sender;
+use std::sync::{Arc, Mutex};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+use crate::control::{ControlMessage, ControlSetting, DISABLE_RPI_WATCHDOG};
+use crate::sink::TelemetrySink;
+use crate::TimedMessage;
+
+/// Default period of heartbeats sent to the MCU
+pub const DEFAULT_HEARTBEAT_PERIOD: Duration = Duration::from_secs(30);
+
+/// Default time without telemetry after which the pipeline is considered stalled
+pub const DEFAULT_STALL_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How often the watchdog thread checks whether it has something to do
+const TICK_PERIOD: Duration = Duration::from_millis(500);
+
+/// Send a notification to systemd (e.g. `READY=1` or `WATCHDOG=1`)
+///
+/// This returns `Ok(false)` when the process was not started by systemd with a notification socket (`NOTIFY_SOCKET` is not set).
+pub fn sd_notify(state: &str) -> std::io::Result<bool> {
+    match std::env::var_os("NOTIFY_SOCKET") {
+        Some(path) => send_notification(&path, state).map(|()| true),
+        None => Ok(false),
+    }
+}
+
+#[cfg(unix)]
+fn send_notification(path: &std::ffi::OsStr, state: &str) -> std::io::Result<()> {
+    use std::os::unix::ffi::OsStrExt;
+    use std::os::unix::net::UnixDatagram;
+
+    let socket = UnixDatagram::unbound()?;
+    match path.as_bytes().strip_prefix(b"@") {
+        // Abstract socket (Linux only)
+        #[cfg(target_os = "linux")]
+        Some(name) => {
+            use std::os::linux::net::SocketAddrExt;
+
+            let address = std::os::unix::net::SocketAddr::from_abstract_name(name)?;
+            socket.send_to_addr(state.as_bytes(), &address)?;
+        }
+        _ => {
+            socket.send_to(state.as_bytes(), path)?;
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_notification(_path: &std::ffi::OsStr, _state: &str) -> std::io::Result<()> {
+    Err(std::io::Error::new(
+        std::io::ErrorKind::Unsupported,
+        "systemd notifications are only supported on Unix",
+    ))
+}
+
+/// Interval at which systemd expects watchdog pings, if its watchdog is enabled for this process (`WATCHDOG_USEC`)
+pub fn systemd_watchdog_interval() -> Option<Duration> {
+    if let Ok(pid) = std::env::var("WATCHDOG_PID") {
+        if pid.trim() != std::process::id().to_string() {
+            return None;
+        }
+    }
+    std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.trim().parse().ok())
+        .filter(|usec| *usec > 0)
+        .map(Duration::from_micros)
+}
+
+/// What the watchdog thread has to do at a given time
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+struct Actions {
+    heartbeat: bool,
+    systemd_ping: bool,
+}
+
+/// Timing of heartbeats and systemd pings, which both stop once telemetry stalls
+#[derive(Debug)]
+struct Schedule {
+    heartbeat_period: Duration,
+    systemd_period: Option<Duration>,
+    stall_timeout: Duration,
+    last_heartbeat: Option<Instant>,
+    last_systemd_ping: Option<Instant>,
+}
+
+impl Schedule {
+    fn actions(&mut self, now: Instant, last_feed: Instant) -> Actions {
+        if now.duration_since(last_feed) >= self.stall_timeout {
+            return Actions::default();
+        }
+        let due = |last: Option<Instant>, period: Duration| {
+            last.is_none_or(|last| now.duration_since(last) >= period)
+        };
+        let actions = Actions {
+            heartbeat: due(self.last_heartbeat, self.heartbeat_period),
+            systemd_ping: self
+                .systemd_period
+                .is_some_and(|period| due(self.last_systemd_ping, period)),
+        };
+        if actions.heartbeat {
+            self.last_heartbeat = Some(now);
+        }
+        if actions.systemd_ping {
+            self.last_systemd_ping = Some(now);
+        }
+        actions
+    }
+}
+
+/// Handle telling the watchdog that the telemetry pipeline is making progress
+///
+/// It is also a sink that feeds the watchdog on every decoded message, so it can be added to the sinks of a CLI.
+#[derive(Debug, Clone)]
+pub struct WatchdogFeeder {
+    last_feed: Arc<Mutex<Instant>>,
+}
+
+impl WatchdogFeeder {
+    /// Tell the watchdog that the pipeline is alive
+    pub fn feed(&self) {
+        *self
+            .last_feed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = Instant::now();
+    }
+
+    fn last_feed(&self) -> Instant {
+        *self
+            .last_feed
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl TelemetrySink for WatchdogFeeder {
+    fn consume(&mut self, message: &TimedMessage) {
+        if message.message.is_ok() {
+            self.feed();
+        }
+    }
+}
+
+/// Sender of heartbeats (for the RPi watchdog of the MCU) coupled with systemd watchdog pings
+///
+/// Both are only sent while the pipeline is fed (see `WatchdogFeeder`): when telemetry stalls, the MCU and systemd both stop hearing from this process and their watchdogs engage.
+/// The systemd watchdog is used if the process was started with `WatchdogSec=` (pings are sent twice per interval).
+pub struct HeartbeatWatchdog {
+    control_tx: Sender<ControlMessage>,
+    schedule: Schedule,
+    feeder: WatchdogFeeder,
+}
+
+impl HeartbeatWatchdog {
+    /// Create a watchdog sending heartbeats through a channel of control messages (given to a `gather_telemetry*` function)
+    pub fn new(control_tx: Sender<ControlMessage>) -> Self {
+        Self {
+            control_tx,
+            schedule: Schedule {
+                heartbeat_period: DEFAULT_HEARTBEAT_PERIOD,
+                systemd_period: systemd_watchdog_interval().map(|interval| interval / 2),
+                stall_timeout: DEFAULT_STALL_TIMEOUT,
+                last_heartbeat: None,
+                last_systemd_ping: None,
+            },
+            feeder: WatchdogFeeder {
+                last_feed: Arc::new(Mutex::new(Instant::now())),
+            },
+        }
+    }
+
+    /// Choose the period of heartbeats; it must be shorter than the RPi watchdog timeout of the firmware
+    pub fn heartbeat_period(mut self, period: Duration) -> Self {
+        self.schedule.heartbeat_period = period;
+        self
+    }
+
+    /// Choose the time without telemetry after which heartbeats and systemd pings stop
+    pub fn stall_timeout(mut self, timeout: Duration) -> Self {
+        self.schedule.stall_timeout = timeout;
+        self
+    }
+
+    /// Handle to feed the watchdog
+    pub fn feeder(&self) -> WatchdogFeeder {
+        self.feeder.clone()
+    }
+
+    /// Notify systemd that the service is ready, and send heartbeats and pings from a dedicated thread
+    pub fn spawn(self) -> RunningWatchdog {
+        if let Err(e) = sd_notify("READY=1") {
+            log::warn!("[watchdog]\tfailed to notify systemd: {}", e);
+        }
+        let stop = Arc::new(AtomicBool::new(false));
+        let thread_stop = Arc::clone(&stop);
+        let control_tx = self.control_tx.clone();
+        let handle = std::thread::spawn(move || self.run(&thread_stop));
+        RunningWatchdog {
+            control_tx,
+            stop,
+            handle: Some(handle),
+        }
+    }
+
+    fn run(mut self, stop: &AtomicBool) {
+        let mut stalled = false;
+        while !stop.load(Ordering::Relaxed) {
+            let last_feed = self.feeder.last_feed();
+            let now = Instant::now();
+            let actions = self.schedule.actions(now, last_feed);
+            let is_stalled = now.duration_since(last_feed) >= self.schedule.stall_timeout;
+            if is_stalled != stalled {
+                stalled = is_stalled;
+                if stalled {
+                    log::error!("[watchdog]\ttelemetry stalled, heartbeats are not sent anymore");
+                } else {
+                    log::info!("[watchdog]\ttelemetry resumed, sending heartbeats again");
+                }
+            }
+            if actions.heartbeat {
+                let heartbeat = ControlMessage {
+                    setting: ControlSetting::Heartbeat,
+                    value: 0,
+                };
+                if self.control_tx.send(heartbeat).is_err() {
+                    log::error!("[watchdog]\tcontrol channel was closed");
+                    return;
+                }
+            }
+            if actions.systemd_ping {
+                if let Err(e) = sd_notify("WATCHDOG=1") {
+                    log::warn!("[watchdog]\tfailed to ping systemd: {}", e);
+                }
+            }
+            std::thread::sleep(TICK_PERIOD);
+        }
+    }
+}
+
+/// Watchdog running in a dedicated thread
+///
+/// Dropping it stops heartbeats without disabling the RPi watchdog, as after a crash; use `shutdown()` on a clean shutdown.
+pub struct RunningWatchdog {
+    control_tx: Sender<ControlMessage>,
+    stop: Arc<AtomicBool>,
+    handle: Option<JoinHandle<()>>,
+}
+
+impl RunningWatchdog {
+    fn stop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(handle) = self.handle.take() {
+            let _ = handle.join();
+        }
+    }
+
+    /// Stop heartbeats, disable the RPi watchdog (`DISABLE_RPI_WATCHDOG`) and tell systemd the service is stopping
+    ///
+    /// The disabling heartbeat is only queued in the control channel; the `gather_telemetry*` function must keep running long enough to send it.
+    pub fn shutdown(mut self) {
+        self.stop();
+        let disable = ControlMessage {
+            setting: ControlSetting::Heartbeat,
+            value: DISABLE_RPI_WATCHDOG,
+        };
+        if self.control_tx.send(disable).is_err() {
+            log::warn!(
+                "[watchdog]\tcould not disable the RPi watchdog: control channel was closed"
+            );
+        }
+        if let Err(e) = sd_notify("STOPPING=1") {
+            log::warn!("[watchdog]\tfailed to notify systemd: {}", e);
+        }
+    }
+}
+
+impl Drop for RunningWatchdog {
+    fn drop(&mut self) {
+        self.stop();
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pauses_while_telemetry_is_stalled() {
+        let start = Instant::now();
+        let mut schedule = Schedule {
+            heartbeat_period: Duration::from_secs(30),
+            systemd_period: Some(Duration::from_secs(10)),
+            stall_timeout: Duration::from_secs(60),
+            last_heartbeat: None,
+            last_systemd_ping: None,
+        };
+        let at = |seconds| start + Duration::from_secs(seconds);
+
+        let both = Actions {
+            heartbeat: true,
+            systemd_ping: true,
+        };
+        assert_eq!(schedule.actions(at(0), at(0)), both);
+        assert_eq!(schedule.actions(at(5), at(0)), Actions::default());
+        assert_eq!(
+            schedule.actions(at(10), at(0)),
+            Actions {
+                heartbeat: false,
+                systemd_ping: true,
+            }
+        );
+        assert_eq!(schedule.actions(at(30), at(0)), both);
+        // No telemetry for a minute: the pipeline is stalled
+        assert_eq!(schedule.actions(at(60), at(0)), Actions::default());
+        assert_eq!(schedule.actions(at(90), at(0)), Actions::default());
+        assert_eq!(schedule.actions(at(91), at(91)), both);
+    }
+
+    #[test]
+    fn shutdown_disables_rpi_watchdog() {
+        let (tx, rx) = std::sync::mpsc::channel();
+        let watchdog = HeartbeatWatchdog::new(tx).spawn();
+        watchdog.shutdown();
+        let messages: Vec<_> = rx.try_iter().collect();
+        assert_eq!(messages.last().unwrap().value, DISABLE_RPI_WATCHDOG);
+    }
+}
@@ -0,0 +1,742 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+use std::io::{self, Read, Write};
+
+use flate2::{Compress, Compression, Decompress, FlushCompress, FlushDecompress, Status};
+use tungstenite::protocol::WebSocket;
+use url::Url;
+
+use crate::decoder::DecodeConfig;
+use crate::fanout::FanOutConfig;
+
+/// Name of the WebSocket extension compressing every message with DEFLATE (RFC 7692)
+pub const PERMESSAGE_DEFLATE: &str = "permessage-deflate";
+
+/// Header used to negotiate WebSocket extensions
+pub const EXTENSIONS_HEADER: &str = "Sec-WebSocket-Extensions";
+
+/// Largest decompressed message accepted from a server, like the default of tungstenite
+pub const MAX_MESSAGE_SIZE: usize = 64 << 20;
+
+/// Bytes that end a DEFLATE block flushed with a sync flush, which permessage-deflate leaves out of messages
+const DEFLATE_TRAILER: [u8; 4] = [0x00, 0x00, 0xff, 0xff];
+
+/// Largest HTTP response header accepted during the handshake
+const MAX_HANDSHAKE_SIZE: usize = 64 << 10;
+
+/// How the telemetry WebSocket server encodes messages for its clients
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum WebSocketMessageFormat {
+    /// Binary WebSocket messages holding a telemetry frame each, like the MakAir WebSocket relay
+    #[default]
+    Binary,
+    /// Text WebSocket messages holding a message serialized as JSON each, e.g. for web dashboards
+    #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+    #[cfg_attr(
+        doc_cfg,
+        doc(cfg(all(feature = "serde-messages", feature = "serde_json")))
+    )]
+    Json,
+}
+
+impl std::str::FromStr for WebSocketMessageFormat {
+    type Err = &'static str;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.trim().to_lowercase().as_str() {
+            "binary" => Ok(Self::Binary),
+            #[cfg(all(feature = "serde-messages", feature = "serde_json"))]
+            "json" => Ok(Self::Json),
+            _ if cfg!(all(feature = "serde-messages", feature = "serde_json")) => {
+                Err("Supported formats are: binary, json")
+            }
+            _ => Err("Supported formats are: binary"),
+        }
+    }
+}
+
+/// How the telemetry WebSocket server handles its clients
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WebSocketServerConfig {
+    /// How messages are queued for each client
+    pub fan_out: FanOutConfig,
+    /// Compress messages for clients that offer permessage-deflate
+    pub permessage_deflate: bool,
+    /// How messages are encoded
+    pub format: WebSocketMessageFormat,
+}
+
+/// How to connect to a telemetry WebSocket server
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct WebSocketClientConfig {
+    /// Offer permessage-deflate to the server, so that it can compress messages
+    pub permessage_deflate: bool,
+    /// How frames received from the server are decoded
+    pub decode: DecodeConfig,
+    /// Time to wait for a message before giving the hand back (e.g. to send control messages), once connected; `None` waits as long as needed
+    pub read_timeout: Option<std::time::Duration>,
+}
+
+/// Parameters of a negotiated permessage-deflate extension
+///
+/// Window sizes are always the maximum (15 bits): offers asking the server to use a smaller window are declined.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct DeflateParameters {
+    /// The server starts every message with an empty compression context
+    pub server_no_context_takeover: bool,
+    /// The client starts every message with an empty compression context
+    pub client_no_context_takeover: bool,
+}
+
+/// Parameters of a WebSocket extension, with their optional value
+type ExtensionParameters = Vec<(String, Option<String>)>;
+
+/// Split a `Sec-WebSocket-Extensions` header into extensions, each with its name and its parameters
+fn parse_extensions(header: &str) -> Vec<(String, ExtensionParameters)> {
+    header
+        .split(',')
+        .filter_map(|extension| {
+            let mut parts = extension.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let parameters = parts
+                .filter(|parameter| !parameter.is_empty())
+                .map(|parameter| match parameter.split_once('=') {
+                    Some((key, value)) => (
+                        key.trim().to_lowercase(),
+                        Some(value.trim().trim_matches('"').to_owned()),
+                    ),
+                    None => (parameter.to_lowercase(), None),
+                })
+                .collect();
+            Some((name.to_lowercase(), parameters))
+        })
+        .collect()
+}
+
+impl DeflateParameters {
+    /// Choose the first permessage-deflate offer of a client's `Sec-WebSocket-Extensions` header that a server can accept
+    pub fn accept_offer(header: &str) -> Option<Self> {
+        parse_extensions(header)
+            .into_iter()
+            .filter(|(name, _)| name == PERMESSAGE_DEFLATE)
+            .find_map(|(_, parameters)| {
+                let mut accepted = Self::default();
+                for (key, value) in parameters {
+                    match (key.as_str(), value.as_deref()) {
+                        ("server_no_context_takeover", None) => {
+                            accepted.server_no_context_takeover = true
+                        }
+                        ("client_no_context_takeover", None) => {
+                            accepted.client_no_context_takeover = true
+                        }
+                        ("server_max_window_bits", Some("15")) => (),
+                        // Clients only tell the window size they can use; they do not send compressed messages to this server anyway
+                        ("client_max_window_bits", _) => (),
+                        _ => return None,
+                    }
+                }
+                Some(accepted)
+            })
+    }
+
+    /// Read the permessage-deflate extension accepted by a server in its `Sec-WebSocket-Extensions` header, if any
+    pub fn from_response(header: &str) -> Option<Self> {
+        let (_, parameters) = parse_extensions(header)
+            .into_iter()
+            .find(|(name, _)| name == PERMESSAGE_DEFLATE)?;
+        let mut accepted = Self::default();
+        for (key, _) in parameters {
+            match key.as_str() {
+                "server_no_context_takeover" => accepted.server_no_context_takeover = true,
+                "client_no_context_takeover" => accepted.client_no_context_takeover = true,
+                // Messages are decompressed with the largest window, which also handles smaller ones
+                _ => (),
+            }
+        }
+        Some(accepted)
+    }
+
+    /// Value of the `Sec-WebSocket-Extensions` header a server answers with to accept these parameters
+    pub fn to_header(&self) -> String {
+        let mut header = PERMESSAGE_DEFLATE.to_owned();
+        if self.server_no_context_takeover {
+            header.push_str("; server_no_context_takeover");
+        }
+        if self.client_no_context_takeover {
+            header.push_str("; client_no_context_takeover");
+        }
+        header
+    }
+}
+
+/// Compressor of the messages a server sends with permessage-deflate
+pub struct MessageDeflater {
+    compress: Compress,
+    no_context_takeover: bool,
+}
+
+impl MessageDeflater {
+    /// Create a compressor for the negotiated parameters
+    pub fn new(parameters: DeflateParameters) -> Self {
+        Self {
+            compress: Compress::new(Compression::default(), false),
+            no_context_takeover: parameters.server_no_context_takeover,
+        }
+    }
+
+    /// Compress the payload of a message, which must then be sent with the RSV1 bit set
+    pub fn compress(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if self.no_context_takeover {
+            self.compress.reset();
+        }
+        let start = self.compress.total_in();
+        let mut output = Vec::with_capacity(payload.len() / 2 + 64);
+        loop {
+            if output.capacity() - output.len() < 64 {
+                output.reserve(output.capacity());
+            }
+            let consumed = (self.compress.total_in() - start) as usize;
+            self.compress
+                .compress_vec(&payload[consumed..], &mut output, FlushCompress::Sync)
+                .map_err(io::Error::other)?;
+            // The flush is complete once everything was consumed without filling the output
+            if self.compress.total_in() - start == payload.len() as u64
+                && output.len() < output.capacity()
+            {
+                break;
+            }
+        }
+        if output.ends_with(&DEFLATE_TRAILER) {
+            output.truncate(output.len() - DEFLATE_TRAILER.len());
+        }
+        Ok(output)
+    }
+}
+
+/// Decompressor of the messages a client receives with permessage-deflate
+pub struct MessageInflater {
+    decompress: Decompress,
+    no_context_takeover: bool,
+}
+
+impl MessageInflater {
+    /// Create a decompressor for the negotiated parameters
+    pub fn new(parameters: DeflateParameters) -> Self {
+        Self {
+            decompress: Decompress::new(false),
+            no_context_takeover: parameters.server_no_context_takeover,
+        }
+    }
+
+    /// Decompress the payload of a message that was sent with the RSV1 bit set
+    pub fn decompress(&mut self, payload: &[u8]) -> io::Result<Vec<u8>> {
+        if self.no_context_takeover {
+            self.decompress.reset(false);
+        }
+        let input = [payload, &DEFLATE_TRAILER[..]].concat();
+        let start = self.decompress.total_in();
+        let mut output = Vec::with_capacity(input.len() * 4);
+        loop {
+            if output.capacity() - output.len() < 64 {
+                output.reserve(output.capacity());
+            }
+            let consumed = (self.decompress.total_in() - start) as usize;
+            let status = self
+                .decompress
+                .decompress_vec(&input[consumed..], &mut output, FlushDecompress::Sync)
+                .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+            if output.len() > MAX_MESSAGE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "decompressed WebSocket message is too large",
+                ));
+            }
+            let consumed_all = self.decompress.total_in() - start == input.len() as u64;
+            if status == Status::StreamEnd || (consumed_all && output.len() < output.capacity()) {
+                break;
+            }
+        }
+        Ok(output)
+    }
+}
+
+/// Something a WebSocket connection can run over
+pub trait ReadWrite: Read + Write {}
+
+impl<S: Read + Write> ReadWrite for S {}
+
+/// Client stream that decompresses the messages of a server using permessage-deflate
+///
+/// It sits between the connection (plain or TLS) and tungstenite, which does not support WebSocket extensions:
+/// it reads the handshake response to know whether the server accepted permessage-deflate,
+/// then rewrites every compressed message into a single uncompressed frame. Writes are left unchanged.
+pub struct InflatingStream<S> {
+    inner: S,
+    handshake_done: bool,
+    inflater: Option<MessageInflater>,
+    raw: Vec<u8>,
+    output: Vec<u8>,
+    position: usize,
+    message: Option<(u8, Vec<u8>)>,
+}
+
+impl<S: Read + Write> InflatingStream<S> {
+    /// Wrap a connection on which the handshake was not done yet
+    pub fn new(inner: S) -> Self {
+        Self {
+            inner,
+            handshake_done: false,
+            inflater: None,
+            raw: Vec::new(),
+            output: Vec::new(),
+            position: 0,
+            message: None,
+        }
+    }
+
+    /// Whether the server accepted to compress messages
+    pub fn is_compressed(&self) -> bool {
+        self.inflater.is_some()
+    }
+
+    /// Underlying connection
+    pub fn get_ref(&self) -> &S {
+        &self.inner
+    }
+
+    /// Read more bytes from the connection; returns `false` at the end of the stream
+    fn fill(&mut self) -> io::Result<bool> {
+        let mut chunk = [0; 4096];
+        let read_bytes = self.inner.read(&mut chunk)?;
+        self.raw.extend_from_slice(&chunk[..read_bytes]);
+        Ok(read_bytes > 0)
+    }
+
+    /// Pass the HTTP response header through, and see whether the server accepted permessage-deflate
+    fn read_handshake(&mut self) -> io::Result<bool> {
+        let end = loop {
+            if let Some(position) = self.raw.windows(4).position(|w| w == b"\r\n\r\n") {
+                break position + 4;
+            }
+            if self.raw.len() > MAX_HANDSHAKE_SIZE {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "WebSocket handshake response is too large",
+                ));
+            }
+            if !self.fill()? {
+                // Let tungstenite report the incomplete response
+                self.handshake_done = true;
+                self.output = std::mem::take(&mut self.raw);
+                return Ok(!self.output.is_empty());
+            }
+        };
+        let header: Vec<u8> = self.raw.drain(..end).collect();
+        let parameters = String::from_utf8_lossy(&header)
+            .lines()
+            .filter_map(|line| line.split_once(':'))
+            .filter(|(name, _)| name.trim().eq_ignore_ascii_case(EXTENSIONS_HEADER))
+            .find_map(|(_, value)| DeflateParameters::from_response(value));
+        self.inflater = parameters.map(MessageInflater::new);
+        self.handshake_done = true;
+        self.output = header;
+        Ok(true)
+    }
+
+    /// Handle the next frame if it was fully received; returns `false` if more bytes are needed
+    fn read_frame(&mut self) -> io::Result<bool> {
+        let inflater = match self.inflater.as_mut() {
+            Some(inflater) => inflater,
+            None => {
+                // Nothing to rewrite
+                self.output = std::mem::take(&mut self.raw);
+                return Ok(!self.output.is_empty());
+            }
+        };
+        if self.raw.len() < 2 {
+            return Ok(false);
+        }
+        let (first, second) = (self.raw[0], self.raw[1]);
+        let length_size = match second & 0x7f {
+            126 => 2,
+            127 => 8,
+            _ => 0,
+        };
+        let mask_size = if second & 0x80 != 0 { 4 } else { 0 };
+        let header_size = 2 + length_size + mask_size;
+        if self.raw.len() < header_size {
+            return Ok(false);
+        }
+        let payload_size = match length_size {
+            0 => u64::from(second & 0x7f),
+            _ => self.raw[2..2 + length_size]
+                .iter()
+                .fold(0, |size, byte| size << 8 | u64::from(*byte)),
+        };
+        if payload_size > MAX_MESSAGE_SIZE as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                "WebSocket frame is too large",
+            ));
+        }
+        let frame_size = header_size + payload_size as usize;
+        if self.raw.len() < frame_size {
+            return Ok(false);
+        }
+        let frame: Vec<u8> = self.raw.drain(..frame_size).collect();
+
+        let (is_final, compressed, opcode) = (first & 0x80 != 0, first & 0x40 != 0, first & 0x0f);
+        let mut payload = frame[header_size..].to_vec();
+        if mask_size > 0 {
+            let mask = &frame[2 + length_size..header_size];
+            for (i, byte) in payload.iter_mut().enumerate() {
+                *byte ^= mask[i % 4];
+            }
+        }
+        match (&mut self.message, opcode) {
+            // Control frames can come in the middle of fragmented messages, and are never compressed
+            (_, 0x8..=0xf) => self.output = frame,
+            (Some((_, message)), 0x0) => message.extend_from_slice(&payload),
+            (Some(_), _) => {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidData,
+                    "WebSocket message started before the end of the previous one",
+                ))
+            }
+            (None, 0x1 | 0x2) if compressed => self.message = Some((opcode, payload)),
+            (None, _) => self.output = frame,
+        }
+
+        if is_final {
+            if let Some((opcode, message)) = self.message.take() {
+                let message = inflater.decompress(&message)?;
+                self.output = Vec::with_capacity(message.len() + 10);
+                self.output.push(0x80 | opcode);
+                match message.len() {
+                    0..=125 => self.output.push(message.len() as u8),
+                    126..=0xffff => {
+                        self.output.push(126);
+                        self.output
+                            .extend_from_slice(&(message.len() as u16).to_be_bytes());
+                    }
+                    _ => {
+                        self.output.push(127);
+                        self.output
+                            .extend_from_slice(&(message.len() as u64).to_be_bytes());
+                    }
+                }
+                self.output.extend_from_slice(&message);
+            }
+        }
+        Ok(true)
+    }
+}
+
+impl<S: Read + Write> Read for InflatingStream<S> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            if self.position < self.output.len() {
+                let size = buf.len().min(self.output.len() - self.position);
+                buf[..size].copy_from_slice(&self.output[self.position..self.position + size]);
+                self.position += size;
+                return Ok(size);
+            }
+            self.output.clear();
+            self.position = 0;
+
+            let ready = if self.handshake_done {
+                self.read_frame()?
+            } else {
+                self.read_handshake()?
+            };
+            if !ready && !self.fill()? {
+                return Ok(0);
+            }
+        }
+    }
+}
+
+impl<S: Read + Write> Write for InflatingStream<S> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.inner.write(buf)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+/// Open a TCP connection to the server of a WebSocket URL, with TLS for `wss://` URLs
+#[allow(clippy::result_large_err)]
+fn open_connection(
+    url: &Url,
+) -> tungstenite::Result<(Box<dyn ReadWrite + Send>, std::net::TcpStream)> {
+    use std::sync::Arc;
+    use tungstenite::error::{TlsError, UrlError};
+
+    let host = url.host_str().ok_or(UrlError::NoHostName)?;
+    let port = url
+        .port_or_known_default()
+        .ok_or(UrlError::UnsupportedUrlScheme)?;
+    let stream = std::net::TcpStream::connect((host, port))?;
+    stream.set_nodelay(true)?;
+    let handle = stream.try_clone()?;
+
+    match url.scheme() {
+        "ws" => Ok((Box::new(stream), handle)),
+        "wss" => {
+            let mut roots = rustls::RootCertStore::empty();
+            roots.add_server_trust_anchors(webpki_roots::TLS_SERVER_ROOTS.0.iter().map(|ta| {
+                rustls::OwnedTrustAnchor::from_subject_spki_name_constraints(
+                    ta.subject,
+                    ta.spki,
+                    ta.name_constraints,
+                )
+            }));
+            let config = rustls::ClientConfig::builder()
+                .with_safe_defaults()
+                .with_root_certificates(roots)
+                .with_no_client_auth();
+            let name = rustls::ServerName::try_from(host).map_err(|_| TlsError::InvalidDnsName)?;
+            let connection =
+                rustls::ClientConnection::new(Arc::new(config), name).map_err(TlsError::Rustls)?;
+            Ok((
+                Box::new(rustls::StreamOwned::new(connection, stream)),
+                handle,
+            ))
+        }
+        _ => Err(UrlError::UnsupportedUrlScheme.into()),
+    }
+}
+
+/// A WebSocket connection to a server
+pub type ClientSocket = WebSocket<InflatingStream<Box<dyn ReadWrite + Send>>>;
+
+/// Connect to a WebSocket server (`ws://` or `wss://`), offering permessage-deflate if configured
+///
+/// With a `read_timeout`, reading a message fails with an `io::ErrorKind::WouldBlock` or `TimedOut` error when none was received in time; reading can then go on.
+#[allow(clippy::result_large_err)]
+pub fn connect(url: &Url, config: &WebSocketClientConfig) -> tungstenite::Result<ClientSocket> {
+    use tungstenite::client::IntoClientRequest;
+    use tungstenite::handshake::HandshakeError;
+    use tungstenite::http::HeaderValue;
+
+    let mut request = url.into_client_request()?;
+    if config.permessage_deflate {
+        request.headers_mut().insert(
+            EXTENSIONS_HEADER,
+            HeaderValue::from_static(PERMESSAGE_DEFLATE),
+        );
+    }
+    let (stream, handle) = open_connection(url)?;
+    match tungstenite::client(request, InflatingStream::new(stream)) {
+        Ok((socket, _response)) => {
+            if config.permessage_deflate && !socket.get_ref().is_compressed() {
+                log::warn!("[websocket]\tserver did not accept permessage-deflate");
+            }
+            // The handshake is not interrupted by the timeout
+            handle.set_read_timeout(config.read_timeout)?;
+            Ok(socket)
+        }
+        Err(HandshakeError::Failure(e)) => Err(e),
+        Err(HandshakeError::Interrupted(_)) => {
+            Err(io::Error::from(io::ErrorKind::WouldBlock).into())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::control::{ControlMessage, ControlSetting};
+    use crate::sink::{TelemetrySink, WebSocketSink};
+    use crate::source::{SourceInfo, SourceKind};
+    use crate::structures::{ControlAck, TelemetryMessage};
+    use crate::TimedMessage;
+    use tungstenite::protocol::Message;
+
+    #[test]
+    fn negotiates_parameters() {
+        // What browsers offer
+        assert_eq!(
+            DeflateParameters::accept_offer("permessage-deflate; client_max_window_bits"),
+            Some(DeflateParameters::default())
+        );
+        // A smaller server window cannot be used, but the next offer can
+        let parameters = DeflateParameters::accept_offer(
+            "permessage-deflate; server_max_window_bits=10, permessage-deflate; server_no_context_takeover",
+        )
+        .unwrap();
+        assert!(parameters.server_no_context_takeover);
+        assert_eq!(
+            parameters.to_header(),
+            "permessage-deflate; server_no_context_takeover"
+        );
+        assert_eq!(
+            DeflateParameters::accept_offer("x-webkit-deflate-frame"),
+            None
+        );
+        assert_eq!(
+            DeflateParameters::from_response(&parameters.to_header()),
+            Some(parameters)
+        );
+    }
+
+    #[test]
+    fn compressed_messages_round_trip() {
+        let mut deflater = MessageDeflater::new(DeflateParameters::default());
+        let mut inflater = MessageInflater::new(DeflateParameters::default());
+        let payload = b"telemetry telemetry telemetry telemetry".repeat(10);
+        let first = deflater.compress(&payload).unwrap();
+        let second = deflater.compress(&payload).unwrap();
+        assert!(first.len() < payload.len() / 4);
+        // The second message refers to the first one
+        assert!(second.len() < first.len());
+        assert_eq!(inflater.decompress(&first).unwrap(), payload);
+        assert_eq!(inflater.decompress(&second).unwrap(), payload);
+    }
+
+    #[test]
+    fn client_receives_compressed_messages_from_sink() {
+        let config = WebSocketServerConfig {
+            permessage_deflate: true,
+            ..Default::default()
+        };
+        let mut sink = WebSocketSink::bind_with_config("127.0.0.1:0", config).unwrap();
+        let url = Url::parse(&format!("ws://{}", sink.local_addr())).unwrap();
+        let mut socket = connect(
+            &url,
+            &WebSocketClientConfig {
+                permessage_deflate: true,
+                ..WebSocketClientConfig::default()
+            },
+        )
+        .unwrap();
+        assert!(socket.get_ref().is_compressed());
+        while sink.clients_count() == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let messages: Vec<TelemetryMessage> = (0..20)
+            .map(|value| {
+                TelemetryMessage::ControlAck(ControlAck {
+                    telemetry_version: 2,
+                    version: "test".to_owned(),
+                    device_id: "0-0-0".to_owned(),
+                    systick: value * 1000,
+                    setting: ControlSetting::PEEP,
+                    value: value as u16,
+                })
+            })
+            .collect();
+        for message in &messages {
+            sink.consume(&TimedMessage::now(
+                Ok(message.clone()),
+                SourceInfo::new(SourceKind::Bytes, None),
+            ));
+        }
+        for message in &messages {
+            match socket.read_message().unwrap() {
+                Message::Binary(bytes) => {
+                    let (_, received): (_, TelemetryMessage) =
+                        crate::parsers::parse_telemetry_message(&bytes).unwrap();
+                    assert_eq!(&received, message);
+                }
+                other => panic!("unexpected message {:?}", other),
+            }
+        }
+    }
+
+    #[test]
+    #[ntest::timeout(5000)]
+    fn pending_handshake_does_not_block_other_clients() {
+        let mut sink =
+            WebSocketSink::bind_with_config("127.0.0.1:0", WebSocketServerConfig::default())
+                .unwrap();
+        // Connects but never sends its handshake
+        let _idle = std::net::TcpStream::connect(sink.local_addr()).unwrap();
+        let url = Url::parse(&format!("ws://{}", sink.local_addr())).unwrap();
+        let mut socket = connect(&url, &WebSocketClientConfig::default()).unwrap();
+        while sink.clients_count() == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let ack = TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "0-0-0".to_owned(),
+            systick: 1000,
+            setting: ControlSetting::PEEP,
+            value: 80,
+        });
+        sink.consume(&TimedMessage::now(
+            Ok(ack.clone()),
+            SourceInfo::new(SourceKind::Bytes, None),
+        ));
+        match socket.read_message().unwrap() {
+            Message::Binary(bytes) => {
+                let (_, received) = crate::parsers::parse_telemetry_message(&bytes).unwrap();
+                assert_eq!(received, ack);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+    }
+
+    #[test]
+    fn clients_send_control_messages_through_sink() {
+        let (control_tx, control_rx) = std::sync::mpsc::channel();
+        let mut sink = WebSocketSink::bind_with_control(
+            "127.0.0.1:0",
+            WebSocketServerConfig::default(),
+            Some(control_tx),
+        )
+        .unwrap();
+        let url = Url::parse(&format!("ws://{}", sink.local_addr())).unwrap();
+        let mut socket = connect(&url, &WebSocketClientConfig::default()).unwrap();
+        while sink.clients_count() == 0 {
+            std::thread::sleep(std::time::Duration::from_millis(1));
+        }
+
+        let control = ControlMessage {
+            setting: ControlSetting::PEEP,
+            value: 80,
+        };
+        socket
+            .write_message(Message::Binary(control.to_control_frame()))
+            .unwrap();
+        socket
+            .write_message(Message::Binary(b"garbage".to_vec()))
+            .unwrap();
+        assert_eq!(
+            control_rx
+                .recv_timeout(std::time::Duration::from_secs(5))
+                .unwrap(),
+            control
+        );
+
+        // Telemetry still flows while the server waits for control messages
+        let ack = TelemetryMessage::ControlAck(ControlAck {
+            telemetry_version: 2,
+            version: "test".to_owned(),
+            device_id: "0-0-0".to_owned(),
+            systick: 1000,
+            setting: control.setting,
+            value: control.value,
+        });
+        sink.consume(&TimedMessage::now(
+            Ok(ack.clone()),
+            SourceInfo::new(SourceKind::Bytes, None),
+        ));
+        match socket.read_message().unwrap() {
+            Message::Binary(bytes) => {
+                let (_, received) = crate::parsers::parse_telemetry_message(&bytes).unwrap();
+                assert_eq!(received, ack);
+            }
+            other => panic!("unexpected message {:?}", other),
+        }
+        assert!(control_rx.try_recv().is_err());
+    }
+}
@@ -0,0 +1,61 @@
+// MakAir Telemetry
+//
+// Copyright: 2020, Makers For Life
+// License: Public Domain License
+
+#![cfg(feature = "serial")]
+
+use makair_telemetry::ports::{canonical_port_name_for, list_ports, Platform};
+
+#[test]
+fn windows_port_names() {
+    for name in ["COM3", "com3", "COM3:", r"\\.\COM3", r"\\?\com3", " COM3 "] {
+        assert_eq!(canonical_port_name_for(name, Platform::Windows), "COM3");
+    }
+    assert_eq!(
+        canonical_port_name_for(r"\\.\COM12", Platform::Windows),
+        "COM12"
+    );
+    assert_eq!(canonical_port_name_for("COMé", Platform::Windows), "COMé");
+}
+
+#[test]
+fn macos_port_names() {
+    assert_eq!(
+        canonical_port_name_for("/dev/tty.usbserial-1410", Platform::MacOs),
+        "/dev/cu.usbserial-1410"
+    );
+    assert_eq!(
+        canonical_port_name_for("tty.usbserial-1410", Platform::MacOs),
+        "/dev/cu.usbserial-1410"
+    );
+    assert_eq!(
+        canonical_port_name_for("/dev/cu.usbmodem1", Platform::MacOs),
+        "/dev/cu.usbmodem1"
+    );
+}
+
+#[test]
+fn unix_port_names() {
+    assert_eq!(
+        canonical_port_name_for("ttyUSB0", Platform::Unix),
+        "/dev/ttyUSB0"
+    );
+    assert_eq!(
+        canonical_port_name_for("/dev/serial0", Platform::Unix),
+        "/dev/serial0"
+    );
+    assert_eq!(canonical_port_name_for("./pty", Platform::Unix), "./pty");
+}
+
+#[cfg(unix)]
+#[test]
+fn lists_existing_devices_only() {
+    for port in list_ports().unwrap() {
+        assert!(
+            std::path::Path::new(&port).exists(),
+            "{} does not exist",
+            port
+        );
+    }
+}